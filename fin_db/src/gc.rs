@@ -0,0 +1,177 @@
+/// Garbage collection of the tree nodes of a closed FinDB
+///
+/// Merk keeps a node per key of the state in the default column family, keyed by the key,
+/// and fmerk can't delete a node which isn't in its tree anymore, e.g. one left by an
+/// interrupted write. The pass opens the data directory with rocksdb directly, marks the nodes
+/// reachable from the retained roots and deletes the others.
+///
+/// Each retained tree is walked in key order while the column family is scanned in the same
+/// order, so the memory of a pass is bounded by the depth of the trees, not by their size.
+///
+use crate::{SecondaryFinDB, TryStatusIter, MERK_CF_AUX, MERK_CF_INTERNAL, MERK_ROOT_KEY};
+use fmerk::{rocksdb, tree::Tree, Merk};
+use ruc::*;
+use std::{collections::BTreeSet, path::Path};
+use storage::{layout::DataLayout, state::ChainState};
+
+// deeper trees than an AVL tree of 2^64 nodes can be have a cycle in their links
+const MAX_DEPTH: usize = 128;
+
+/// Outcome of `FinDB::collect_garbage`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// retained roots whose root node is in the db
+    pub roots: usize,
+    /// retained roots whose root node was overwritten, merk updates the nodes in place
+    pub missing_roots: usize,
+    /// nodes reachable from the retained roots
+    pub reachable: u64,
+    /// unreachable nodes deleted
+    pub deleted: u64,
+}
+
+/// Deletes the nodes of the closed db at `path` which no retained root reaches, `batch_size`
+/// nodes per write
+pub(crate) fn collect_garbage(path: &Path, batch_size: usize) -> Result<GcReport> {
+    let main_dir = DataLayout::open(path).c(d!())?.main_dir();
+    let roots = {
+        let db = SecondaryFinDB::open_dir(&main_dir).c(d!())?;
+        ChainState::retained_roots(&db).c(d!())?
+    };
+
+    let db = rocksdb::DB::open_cf(
+        &Merk::default_db_opts(),
+        &main_dir,
+        [MERK_CF_AUX, MERK_CF_INTERNAL],
+    )
+    .c(d!("Failed to open db"))?;
+    let mut report = GcReport::default();
+    let mut walks = root_keys(&db, &roots, &mut report)
+        .c(d!())?
+        .into_iter()
+        .map(|key| Walk::new(&db, key))
+        .collect::<Result<Vec<_>>>()
+        .c(d!())?;
+    let mut heads = walks
+        .iter_mut()
+        .map(Walk::next)
+        .collect::<Result<Vec<_>>>()
+        .c(d!())?;
+
+    let mut batch = rocksdb::WriteBatch::default();
+    for kv in TryStatusIter::new(db.iterator(rocksdb::IteratorMode::Start)) {
+        let (key, _) = kv.c(d!())?;
+        let mut reached = false;
+        for (walk, head) in walks.iter_mut().zip(heads.iter_mut()) {
+            while head.as_deref().map_or(false, |k| k < &key[..]) {
+                *head = walk.next().c(d!())?;
+            }
+            reached |= head.as_deref() == Some(&key[..]);
+        }
+        if reached {
+            report.reachable += 1;
+            continue;
+        }
+        batch.delete(&key);
+        report.deleted += 1;
+        if batch.len() >= batch_size.max(1) {
+            db.write(std::mem::take(&mut batch)).c(d!())?;
+        }
+    }
+    if !batch.is_empty() {
+        db.write(batch).c(d!())?;
+    }
+    Ok(report)
+}
+
+// Keys of the root nodes of `roots`, the current root is read from the internal data and the
+// others are looked up by hash, scanning the nodes once if a retained root isn't the current one
+fn root_keys(db: &rocksdb::DB, roots: &[Vec<u8>], report: &mut GcReport) -> Result<Vec<Vec<u8>>> {
+    let internal = db
+        .cf_handle(MERK_CF_INTERNAL)
+        .c(d!(format!("missing column family {}", MERK_CF_INTERNAL)))?;
+    let mut pending = roots.iter().cloned().collect::<BTreeSet<_>>();
+    let mut keys = vec![];
+    if let Some(key) = db.get_cf(internal, MERK_ROOT_KEY).c(d!())? {
+        pending.remove(&read_node(db, &key).c(d!())?.hash().to_vec());
+        keys.push(key);
+    }
+    if !pending.is_empty() {
+        for kv in TryStatusIter::new(db.iterator(rocksdb::IteratorMode::Start)) {
+            let (key, node) = kv.c(d!())?;
+            if pending.remove(&decode(&key, &node).c(d!())?.hash().to_vec()) {
+                keys.push(key.to_vec());
+                if pending.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+    report.roots = keys.len();
+    report.missing_roots = pending.len();
+    Ok(keys)
+}
+
+// `Tree::decode` panics on malformed nodes
+fn decode(key: &[u8], node: &[u8]) -> Result<Tree> {
+    std::panic::catch_unwind(|| Tree::decode(key.to_vec(), node))
+        .map_err(|_| eg!("Failed to decode tree node"))
+}
+
+fn read_node(db: &rocksdb::DB, key: &[u8]) -> Result<Tree> {
+    let node = db.get(key).c(d!())?.c(d!("missing tree node"))?;
+    decode(key, &node).c(d!())
+}
+
+// In-order walk of the tree under a root node, reading the nodes as it goes
+struct Walk<'a> {
+    db: &'a rocksdb::DB,
+    // nodes whose key and right subtree are left to visit, with the key of their right child
+    stack: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    last: Option<Vec<u8>>,
+}
+
+impl<'a> Walk<'a> {
+    fn new(db: &'a rocksdb::DB, root: Vec<u8>) -> Result<Self> {
+        let mut walk = Walk {
+            db,
+            stack: vec![],
+            last: None,
+        };
+        walk.descend(root).c(d!())?;
+        Ok(walk)
+    }
+
+    // pushes the node of `key` and its leftmost descendants
+    fn descend(&mut self, mut key: Vec<u8>) -> Result<()> {
+        loop {
+            if self.stack.len() >= MAX_DEPTH {
+                return Err(eg!("tree links form a cycle"));
+            }
+            let tree = read_node(self.db, &key).c(d!())?;
+            let left = tree.link(true).map(|link| link.key().to_vec());
+            let right = tree.link(false).map(|link| link.key().to_vec());
+            self.stack.push((key, right));
+            match left {
+                Some(left) => key = left,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // next key of the tree, they must ascend
+    fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        let (key, right) = match self.stack.pop() {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        if let Some(right) = right {
+            self.descend(right).c(d!())?;
+        }
+        if self.last.as_ref().map_or(false, |last| *last >= key) {
+            return Err(eg!("tree nodes out of order"));
+        }
+        self.last = Some(key.clone());
+        Ok(Some(key))
+    }
+}
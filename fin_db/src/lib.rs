@@ -1,14 +1,62 @@
 use fmerk::{
+    proofs::Query,
     rocksdb::{self},
-    tree::Tree,
+    tree::{kv_hash, Tree},
     BatchEntry, Merk, Op,
 };
 use ruc::*;
-use std::path::{Path, PathBuf};
-use storage::db::{DbIter, IterOrder, KVBatch, KValue, MerkleDB};
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::Bound,
+    panic,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+};
+use storage::{
+    config::{Backend, OpenWithConfig, StorageConfig},
+    db::{
+        key_range, CfOpts, Compression, DbIter, DbTryIter, IterOpts, IterOrder, KVBatch,
+        KVEntryRef, KValue, MerkleDB, PrefixBloomOpts, PrefixExtractor, ScanControl, ValueLogOpts,
+        WalOpts,
+    },
+    layout::DataLayout,
+    merge::MergeOp,
+    portable::{export_portable_file, import_portable_file},
+    proof::{ProofMemo, ProofMemoStats},
+    remote::ProofVerifier,
+    snapshot::{
+        open_container_dir, seal_container_dir, PrefixFilter, SnapshotCodec, SnapshotOptions,
+        CONTAINER_FILE,
+    },
+    state::ChainStateOpts,
+    upgrade::{plan_upgrade, UpgradeReport},
+};
+
+mod gc;
+mod secondary;
+#[cfg(feature = "test-hooks")]
+mod stall;
+
+pub use gc::GcReport;
+pub use secondary::SecondaryFinDB;
+#[cfg(feature = "test-hooks")]
+pub use stall::{StallHook, StallOp};
 
 const CF_STATE: &str = "state";
 
+/// column family of the aux data of a fmerk db
+pub const MERK_CF_AUX: &str = "aux";
+/// column family of the internal data of a fmerk db
+pub const MERK_CF_INTERNAL: &str = "internal";
+/// key of the key of the root node of a fmerk db in `MERK_CF_INTERNAL`
+pub const MERK_ROOT_KEY: &[u8] = b"root";
+
+// caches of `BlockCache::named`, by name
+static NAMED_CACHES: Mutex<BTreeMap<String, Weak<rocksdb::Cache>>> = Mutex::new(BTreeMap::new());
+// backend recorded in the snapshot containers
+const SNAPSHOT_BACKEND: &str = "findb";
+
 /// Converts KVEntry to BatchEntry
 pub fn to_batch<I: IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>>(items: I) -> Vec<BatchEntry> {
     let mut batch = Vec::new();
@@ -21,26 +69,435 @@ pub fn to_batch<I: IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>>(items: I) ->
     batch
 }
 
+/// Builds read options for a range iteration with the given read hints
+fn range_readopts(lower: &[u8], upper: &[u8], opts: &IterOpts) -> rocksdb::ReadOptions {
+    let mut readopts = rocksdb::ReadOptions::default();
+    readopts.set_iterate_lower_bound(lower.to_vec());
+    readopts.set_iterate_upper_bound(upper.to_vec());
+    if let Some(size) = opts.readahead_size {
+        readopts.set_readahead_size(size);
+    }
+    readopts.fill_cache(opts.fill_cache);
+    readopts.set_pin_data(opts.pin_data);
+    readopts
+}
+
+/// Raw iterator yielding owned pairs, then the error which stopped it if any
+///
+/// The iterators of rocksdb end at a read error as if the range was done, the error is only
+/// reported by the status of a raw iterator.
+struct TryRawIter<'a> {
+    iter: rocksdb::DBRawIterator<'a>,
+    order: IterOrder,
+    done: bool,
+}
+
+impl<'a> TryRawIter<'a> {
+    fn new(mut iter: rocksdb::DBRawIterator<'a>, order: IterOrder) -> Self {
+        match order {
+            IterOrder::Desc => iter.seek_to_last(),
+            _ => iter.seek_to_first(),
+        }
+        TryRawIter {
+            iter,
+            order,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for TryRawIter<'_> {
+    type Item = Result<(Box<[u8]>, Box<[u8]>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let pair = match (self.iter.key(), self.iter.value()) {
+            (Some(k), Some(v)) => Some((Box::from(k), Box::from(v))),
+            _ => None,
+        };
+        match pair {
+            Some(pair) => {
+                match self.order {
+                    IterOrder::Desc => self.iter.prev(),
+                    _ => self.iter.next(),
+                }
+                Some(Ok(pair))
+            }
+            None => {
+                self.done = true;
+                let status = self.iter.status();
+                status
+                    .err()
+                    .map(|e| Err(eg!("Failed to iterate db: {}", e)))
+            }
+        }
+    }
+}
+
+/// Iterator yielding the owned pairs of a rocksdb iterator, then the error which stopped it
+/// if any, see `TryRawIter`
+struct TryStatusIter<'a> {
+    iter: rocksdb::DBIterator<'a>,
+    done: bool,
+}
+
+impl<'a> TryStatusIter<'a> {
+    fn new(iter: rocksdb::DBIterator<'a>) -> Self {
+        TryStatusIter { iter, done: false }
+    }
+}
+
+impl Iterator for TryStatusIter<'_> {
+    type Item = Result<(Box<[u8]>, Box<[u8]>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.iter.next() {
+            Some(pair) => Some(Ok(pair)),
+            None => {
+                self.done = true;
+                let status = self.iter.status();
+                status
+                    .err()
+                    .map(|e| Err(eg!("Failed to iterate db: {}", e)))
+            }
+        }
+    }
+}
+
+/// Block cache shared by the dbs of a process
+///
+/// Every `FinDB` opened with the same cache reads the blocks of its tree through it, so a
+/// process hosting several stores caps their memory with one capacity instead of one cache
+/// per store. Clones share the cache.
+#[derive(Clone)]
+pub struct BlockCache(Arc<rocksdb::Cache>);
+
+impl BlockCache {
+    /// Creates an LRU cache of `capacity` bytes
+    pub fn new(capacity: usize) -> Result<Self> {
+        rocksdb::Cache::new_lru_cache(capacity)
+            .map(|cache| BlockCache(Arc::new(cache)))
+            .c(d!())
+    }
+
+    /// The cache named `name` in the process, e.g. the `shared_block_cache` of the configs
+    /// of its dbs
+    ///
+    /// It's created with `capacity` bytes if no db holds it anymore, the capacity of an
+    /// existing cache is kept.
+    pub fn named(name: &str, capacity: usize) -> Result<Self> {
+        let mut caches = NAMED_CACHES
+            .lock()
+            .map_err(|_e| eg!("block cache registry poisoned"))?;
+        caches.retain(|_, cache| cache.strong_count() > 0);
+        if let Some(cache) = caches.get(name).and_then(Weak::upgrade) {
+            return Ok(BlockCache(cache));
+        }
+        let cache = Self::new(capacity).c(d!())?;
+        caches.insert(name.to_owned(), Arc::downgrade(&cache.0));
+        Ok(cache)
+    }
+
+    /// The cache named in `cfg`, if any, see `RocksDbConfig::shared_block_cache`
+    fn from_config(cfg: &StorageConfig) -> Result<Option<Self>> {
+        let name = match cfg.rocksdb.shared_block_cache.as_deref() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let size = cfg
+            .rocksdb
+            .block_cache_size
+            .c(d!("a shared block cache needs a block_cache_size"))?;
+        Self::named(name, size).map(Some)
+    }
+
+    /// Bytes used by the cached blocks of all the dbs sharing the cache
+    pub fn usage(&self) -> usize {
+        self.0.get_usage()
+    }
+}
+
+/// Options of `FinDB::open_with_opts` and `RocksDB::open_with_opts`
+#[derive(Clone, Default)]
+pub struct FinDBOpts {
+    /// Keeps large values in blob files, see `FinDB::open_with_value_log`
+    pub value_log: Option<ValueLogOpts>,
+    /// Cache shared with other dbs, rocksdb's default cache of this db alone if `None`
+    pub block_cache: Option<BlockCache>,
+    /// Capacity in bytes of a cache of this db alone, ignored with a shared `block_cache`
+    pub block_cache_size: Option<usize>,
+    /// Tuning of the column family of the state, the aux data isn't tuned apart: fmerk fixes
+    /// the options of its aux column family and `RocksDB` keeps the aux data with the state
+    pub data: CfOpts,
+}
+
+impl FinDBOpts {
+    pub fn with_value_log(mut self, opts: ValueLogOpts) -> Self {
+        self.value_log = Some(opts);
+        self
+    }
+
+    pub fn with_block_cache(mut self, cache: &BlockCache) -> Self {
+        self.block_cache = Some(cache.clone());
+        self
+    }
+
+    pub fn with_block_cache_size(mut self, size: usize) -> Self {
+        self.block_cache_size = Some(size);
+        self
+    }
+
+    pub fn with_data_cf(mut self, opts: CfOpts) -> Self {
+        self.data = opts;
+        self
+    }
+
+    /// The shared cache, or a new one of `block_cache_size` bytes
+    fn cache(&self) -> Result<Option<BlockCache>> {
+        match (self.block_cache.as_ref(), self.block_cache_size) {
+            (Some(cache), _) => Ok(Some(cache.clone())),
+            (None, Some(size)) => BlockCache::new(size).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Applies `cf` to the options of a column family, its blocks are read through `cache`
+fn tune_cf(
+    opts: &mut rocksdb::Options,
+    table_opts: &mut rocksdb::BlockBasedOptions,
+    cf: &CfOpts,
+    cache: Option<&BlockCache>,
+) {
+    if let Some(compression) = cf.compression {
+        opts.set_compression_type(match compression {
+            Compression::Snappy => rocksdb::DBCompressionType::Snappy,
+            Compression::Lz4 => rocksdb::DBCompressionType::Lz4,
+            Compression::Zstd => rocksdb::DBCompressionType::Zstd,
+            _ => rocksdb::DBCompressionType::None,
+        });
+    }
+    if let Some(size) = cf.write_buffer_size {
+        opts.set_write_buffer_size(size);
+    }
+    if let Some(bits) = cf.bloom_bits_per_key.filter(|bits| *bits != 0) {
+        table_opts.set_bloom_filter(bits as _, false);
+    }
+    if let Some(cache) = cache {
+        table_opts.set_block_cache(&cache.0);
+    }
+}
+
+/// Keeps the values of a column family of at least `min_value_size` bytes in blob files
+///
+/// RocksDB separates them from the keys below the tree, so compactions don't rewrite them
+/// and the tree, its root hash and its proofs are those of a db without a value log.
+fn set_value_log(opts: &mut rocksdb::Options, vlog: &ValueLogOpts) {
+    opts.set_enable_blob_files(true);
+    opts.set_min_blob_size(vlog.min_value_size as u64);
+    opts.set_blob_file_size(vlog.segment_size);
+    opts.set_enable_blob_gc(true);
+    // blob files of the oldest ones with this share of garbage are rewritten by compactions
+    opts.set_blob_gc_force_threshold(1.0 - vlog.gc_live_ratio);
+}
+
+// copies the files under `src` but the snapshot container to `dst`
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst).c(d!())?;
+    for entry in fs::read_dir(src).c(d!())? {
+        let entry = entry.c(d!())?;
+        let (from, to) = (entry.path(), dst.join(entry.file_name()));
+        if from.is_dir() {
+            copy_dir(&from, &to).c(d!())?;
+        } else if entry.file_name() != CONTAINER_FILE {
+            fs::copy(&from, &to).c(d!())?;
+        }
+    }
+    Ok(())
+}
+
 /// Findora db
 
 pub struct FinDB {
     db: Merk,
+    root: PathBuf,
+    proof_memo: ProofMemo,
+    // keeps a named cache registered while the db reads through it
+    _block_cache: Option<BlockCache>,
+    #[cfg(feature = "test-hooks")]
+    stall_hook: Option<StallHook>,
 }
 
 impl FinDB {
     /// Opens a db with the specified file path. If no db exists at that
     ///
-    /// path, one will be created.
+    /// path, one will be created. A data directory in an older layout is migrated first.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<FinDB> {
-        let db = Merk::open(path).map_err(|e| eg!("Failed to open db {}", e))?;
-        Ok(Self { db })
+        Self::open_with_opts(path, &FinDBOpts::default())
+    }
+
+    /// Opens a db like `open`, keeping the tree nodes holding values of at least
+    /// `min_value_size` bytes in blob files
+    ///
+    /// The tree and its root hash are the same as without a value log, the mode can be
+    /// switched on an existing db, the values already written move to blob files as they're
+    /// compacted.
+    pub fn open_with_value_log<P: AsRef<Path>>(path: P, opts: &ValueLogOpts) -> Result<FinDB> {
+        Self::open_with_opts(path, &FinDBOpts::default().with_value_log(opts.clone()))
+    }
+
+    /// Opens a db like `open` with the given options, e.g. a block cache shared with the other
+    /// dbs of the process
+    ///
+    /// The tuning of the data column family applies to the tree, the aux column family keeps
+    /// the options of fmerk.
+    pub fn open_with_opts<P: AsRef<Path>>(path: P, opts: &FinDBOpts) -> Result<FinDB> {
+        let layout = DataLayout::open(path).c(d!())?;
+        let cache = opts.cache().c(d!())?;
+        let db = if cache.is_none() && opts.data.is_default() && opts.value_log.is_none() {
+            Merk::open(layout.main_dir())
+        } else {
+            // the tree nodes are in the default column family
+            let mut db_opts = Merk::default_db_opts();
+            let mut table_opts = rocksdb::BlockBasedOptions::default();
+            tune_cf(&mut db_opts, &mut table_opts, &opts.data, cache.as_ref());
+            db_opts.set_block_based_table_factory(&table_opts);
+            if let Some(vlog) = opts.value_log.as_ref() {
+                set_value_log(&mut db_opts, vlog);
+            }
+            Merk::open_opt(layout.main_dir(), db_opts)
+        }
+        .map_err(|e| eg!("Failed to open db {}", e))?;
+        Ok(Self {
+            db,
+            root: layout.root().to_path_buf(),
+            proof_memo: ProofMemo::default(),
+            _block_cache: cache,
+            #[cfg(feature = "test-hooks")]
+            stall_hook: None,
+        })
+    }
+
+    /// Lists the layout migrations, reindexes and prunings opening the data directory at
+    /// `path` and a chain state with `opts` on it would run, see `storage::upgrade`
+    ///
+    /// Nothing is written, the db is opened read-only.
+    pub fn plan_upgrade<P: AsRef<Path>>(path: P, opts: &ChainStateOpts) -> Result<UpgradeReport> {
+        let mut report = plan_upgrade(path).c(d!())?;
+        if report.db_dir.join("CURRENT").is_file() {
+            let db = SecondaryFinDB::open_dir(&report.db_dir).c(d!())?;
+            report.plan_chain_state(&db, opts).c(d!())?;
+        }
+        Ok(report)
+    }
+
+    /// Restores the snapshot taken by `snapshot` at `snapshot` into the empty data directory
+    /// `path` and opens it with `opts`
+    ///
+    /// A portable snapshot, a file written by `snapshot_portable` or by another backend, is
+    /// restored as well, see `storage::portable`, it's streamed into the db and the partial
+    /// db is removed if it fails. The files of any other snapshot are checked against its
+    /// container first, a truncated or altered snapshot, or one of another backend, fails
+    /// before anything is written.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(
+        snapshot: P,
+        path: Q,
+        opts: &FinDBOpts,
+    ) -> Result<FinDB> {
+        let snapshot = snapshot.as_ref();
+        if snapshot.is_file() {
+            return Self::restore_portable(snapshot, path, opts);
+        }
+        open_container_dir(SNAPSHOT_BACKEND, snapshot).c(d!())?;
+        let main_dir = DataLayout::open(path.as_ref()).c(d!())?.main_dir();
+        if fs::read_dir(&main_dir).c(d!())?.next().is_some() {
+            return Err(eg!("can't restore a snapshot into a non-empty db"));
+        }
+        copy_dir(snapshot, &main_dir).c(d!())?;
+        Self::open_with_opts(path, opts)
+    }
+
+    // restores a portable snapshot, the root hash is checked if it was taken from a FinDB;
+    // the snapshot is streamed into the db, which is removed if the restore fails
+    fn restore_portable<Q: AsRef<Path>>(
+        snapshot: &Path,
+        path: Q,
+        opts: &FinDBOpts,
+    ) -> Result<FinDB> {
+        let path = path.as_ref();
+        if path.exists() && fs::read_dir(path).c(d!())?.next().is_some() {
+            return Err(eg!("can't restore a snapshot into a non-empty db"));
+        }
+        let restored = Self::open_with_opts(path, opts).and_then(|mut db| {
+            let restored = import_portable_file(&mut db, snapshot).c(d!())?;
+            db.commit(vec![], true).c(d!())?;
+            if restored.backend == SNAPSHOT_BACKEND && restored.root_hash != db.root_hash() {
+                return Err(eg!("restored root doesn't match the snapshot root"));
+            }
+            Ok(db)
+        });
+        if restored.is_err() {
+            let _ = fs::remove_dir_all(path);
+        }
+        restored.c(d!())
+    }
+
+    /// Takes a portable snapshot at `path`, restored into any backend, see `storage::portable`
+    ///
+    /// Every entry is read and written again, it's much slower than `snapshot`.
+    pub fn snapshot_portable<P: AsRef<Path>>(&self, path: P, opts: &SnapshotOptions) -> Result<()> {
+        let filter = PrefixFilter::new();
+        export_portable_file(self, SNAPSHOT_BACKEND, &filter, opts, path.as_ref()).c(d!())?;
+        Ok(())
+    }
+
+    /// Delays or stalls the writes and compactions of this db as told by `hook`, for tests
+    #[cfg(feature = "test-hooks")]
+    pub fn set_stall_hook(&mut self, hook: StallHook) {
+        self.stall_hook = Some(hook);
+    }
+
+    #[cfg(feature = "test-hooks")]
+    fn stall(&self, op: StallOp) {
+        if let Some(hook) = self.stall_hook.as_ref() {
+            hook.wait(op);
+        }
+    }
+
+    /// Sets how many proofs of the current root are kept in memory, zero disables the memo
+    pub fn set_proof_memo_size(&mut self, size: usize) {
+        self.proof_memo = ProofMemo::new(size);
+    }
+
+    /// Returns hit/miss counters of the proof memo
+    pub fn proof_memo_stats(&self) -> ProofMemoStats {
+        self.proof_memo.stats()
+    }
+
+    /// Deletes the tree nodes of the closed db at `path` which no retained root reaches,
+    /// writing `batch_size` deletes at a time
+    ///
+    /// fmerk has no way to delete a node which isn't in its tree, so the db must be closed
+    /// and is opened with rocksdb directly. The retained roots are the current root and the
+    /// roots of the chain state's version window and pruning policy, see
+    /// `ChainState::retained_roots`. Read errors, nodes which can't be decoded and links to
+    /// missing nodes fail the pass, the nodes deleted by then were unreachable and an
+    /// interrupted pass is run again.
+    pub fn collect_garbage<P: AsRef<Path>>(path: P, batch_size: usize) -> Result<GcReport> {
+        gc::collect_garbage(path.as_ref(), batch_size)
     }
 
     /// Closes db and deletes all data from disk.
     pub fn destroy(self) -> Result<()> {
         self.db
             .destroy()
-            .map_err(|e| eg!("Failed to destory db {}", e))
+            .map_err(|e| eg!("Failed to destory db {}", e))?;
+        fs::remove_dir_all(&self.root).c(d!())
     }
 }
 
@@ -61,6 +518,26 @@ impl MerkleDB for FinDB {
             .map_err(|e| eg!("Failed to get data from db {}", e))
     }
 
+    /// Reads a value into `buf`
+    ///
+    /// fmerk returns values it owns, the value replaces `buf` unless `buf` can hold it, so a
+    /// read allocates for fmerk's value only and never for `buf`.
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        buf.clear();
+        let value = self
+            .db
+            .get(key)
+            .map_err(|e| eg!("Failed to get data from db {}", e))?;
+        Ok(value.map(|value| {
+            if buf.capacity() >= value.len() {
+                buf.extend_from_slice(&value);
+            } else {
+                *buf = value;
+            }
+            buf.len()
+        }))
+    }
+
     /// Gets an auxiliary value.
     fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         self.db
@@ -70,6 +547,8 @@ impl MerkleDB for FinDB {
 
     /// Puts a batch of KVs
     fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
+        #[cfg(feature = "test-hooks")]
+        self.stall(StallOp::Write);
         let batch = to_batch(kvs);
         self.db
             .apply(batch.as_ref())
@@ -82,8 +561,47 @@ impl MerkleDB for FinDB {
         readopts.set_iterate_lower_bound(lower.to_vec());
         readopts.set_iterate_upper_bound(upper.to_vec());
         match order {
-            IterOrder::Asc => Box::new(self.db.iter_opt(rocksdb::IteratorMode::Start, readopts)),
             IterOrder::Desc => Box::new(self.db.iter_opt(rocksdb::IteratorMode::End, readopts)),
+            _ => Box::new(self.db.iter_opt(rocksdb::IteratorMode::Start, readopts)),
+        }
+    }
+
+    /// Gets range iterator with read hints
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbIter<'_> {
+        let readopts = range_readopts(lower, upper, opts);
+        match order {
+            IterOrder::Desc => Box::new(self.db.iter_opt(rocksdb::IteratorMode::End, readopts)),
+            _ => Box::new(self.db.iter_opt(rocksdb::IteratorMode::Start, readopts)),
+        }
+    }
+
+    /// Gets range iterator over any bounds, an unbounded end reaches the last key
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        let (start, end) = key_range(lower, upper);
+        match end {
+            Some(end) if end <= start => Box::new(std::iter::empty()),
+            Some(end) => self.iter(&start, &end, order),
+            None => {
+                let mut readopts = rocksdb::ReadOptions::default();
+                readopts.set_iterate_lower_bound(start);
+                match order {
+                    IterOrder::Desc => {
+                        Box::new(self.db.iter_opt(rocksdb::IteratorMode::End, readopts))
+                    }
+                    _ => Box::new(self.db.iter_opt(rocksdb::IteratorMode::Start, readopts)),
+                }
+            }
         }
     }
 
@@ -93,29 +611,124 @@ impl MerkleDB for FinDB {
         readopts.set_iterate_lower_bound(lower.to_vec());
         readopts.set_iterate_upper_bound(upper.to_vec());
         match order {
-            IterOrder::Asc => {
-                Box::new(self.db.iter_opt_aux(rocksdb::IteratorMode::Start, readopts))
-            }
             IterOrder::Desc => Box::new(self.db.iter_opt_aux(rocksdb::IteratorMode::End, readopts)),
+            _ => Box::new(self.db.iter_opt_aux(rocksdb::IteratorMode::Start, readopts)),
         }
     }
-    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_>
-    {
+
+    /// Gets range iterator checking the status of the rocksdb iterator, read errors are yielded
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        let mut readopts = rocksdb::ReadOptions::default();
+        readopts.set_iterate_lower_bound(lower.to_vec());
+        readopts.set_iterate_upper_bound(upper.to_vec());
+        let mode = match order {
+            IterOrder::Desc => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        Box::new(TryStatusIter::new(self.db.iter_opt(mode, readopts)))
+    }
+
+    /// Gets range iterator for aux, read errors are yielded
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        let mut readopts = rocksdb::ReadOptions::default();
+        readopts.set_iterate_lower_bound(lower.to_vec());
+        readopts.set_iterate_upper_bound(upper.to_vec());
+        let mode = match order {
+            IterOrder::Desc => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        Box::new(TryStatusIter::new(self.db.iter_opt_aux(mode, readopts)))
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
         let readopts = rocksdb::ReadOptions::default();
         match order {
-            IterOrder::Asc => Box::new(self.db.iter_opt(rocksdb::IteratorMode::Start, readopts)),
             IterOrder::Desc => Box::new(self.db.iter_opt(rocksdb::IteratorMode::End, readopts)),
+            _ => Box::new(self.db.iter_opt(rocksdb::IteratorMode::Start, readopts)),
         }
     }
 
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        let readopts = rocksdb::ReadOptions::default();
+        match order {
+            IterOrder::Desc => Box::new(self.db.iter_opt_aux(rocksdb::IteratorMode::End, readopts)),
+            _ => Box::new(self.db.iter_opt_aux(rocksdb::IteratorMode::Start, readopts)),
+        }
+    }
+
+    /// Gets all pairs, read errors are yielded
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        let readopts = rocksdb::ReadOptions::default();
+        let mode = match order {
+            IterOrder::Desc => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        Box::new(TryStatusIter::new(self.db.iter_opt(mode, readopts)))
+    }
+
+    /// Gets all aux, read errors are yielded
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        let readopts = rocksdb::ReadOptions::default();
+        let mode = match order {
+            IterOrder::Desc => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        Box::new(TryStatusIter::new(self.db.iter_opt_aux(mode, readopts)))
+    }
+
+    fn has_proofs(&self) -> bool {
+        true
+    }
+
+    /// Generates a merk proof of `keys` against the current root hash
+    ///
+    /// Proofs are memoized until the next commit, so hot keys don't walk the tree again within
+    /// a block, see `ProofMemo`.
+    fn prove_keys(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let root = self.db.root_hash();
+        self.proof_memo.get_or_prove(&root, keys, || {
+            let mut query = Query::new();
+            for key in keys {
+                query.insert_key(key.clone());
+            }
+            self.db
+                .prove(query)
+                .map_err(|e| eg!("Failed to generate proof {}", e))
+        })
+    }
+
+    /// Generates a merk proof of the pairs in `[lower, upper)` against the current root hash
+    fn prove_range(&self, lower: &[u8], upper: &[u8]) -> Result<Vec<u8>> {
+        let mut query = Query::new();
+        query.insert_range(lower.to_vec()..upper.to_vec());
+        self.db
+            .prove(query)
+            .map_err(|e| eg!("Failed to generate proof {}", e))
+    }
+
+    /// Re-computes the kv hash of a tree node and compares it with the stored one
+    fn verify_entry(&self, kv_pair: &(Box<[u8]>, Box<[u8]>)) -> Result<()> {
+        // a node which doesn't even decode is corrupted as well
+        let tree = panic::catch_unwind(|| Tree::decode(kv_pair.0.to_vec(), &kv_pair.1))
+            .map_err(|_| eg!("Failed to decode tree node"))?;
+        if tree.kv_hash() != &kv_hash(tree.key(), tree.value()) {
+            return Err(eg!("kv hash mismatch"));
+        }
+        Ok(())
+    }
 
     /// Commits changes.
     fn commit(&mut self, aux: KVBatch, flush: bool) -> Result<()> {
+        #[cfg(feature = "test-hooks")]
+        self.stall(StallOp::Write);
         let batch_aux = to_batch(aux);
         self.db
             .commit(batch_aux.as_ref())
             .map_err(|e| eg!("Failed to commit to db {}", e))?;
+        self.proof_memo.clear();
         if flush {
+            #[cfg(feature = "test-hooks")]
+            self.stall(StallOp::Compaction);
             self.db
                 .flush()
                 .map_err(|e| eg!("Failed to flush memtables {}", e))?;
@@ -123,12 +736,23 @@ impl MerkleDB for FinDB {
         Ok(())
     }
 
-    /// Takes a snapshot using checkpoint
+    /// Takes a snapshot using checkpoint, sealed in a container, see `FinDB::restore`
     fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         self.db
-            .snapshot(path)
+            .snapshot(path.as_ref())
             .map_err(|e| eg!("Failed to take snapshot {}", e))?;
-        Ok(())
+        seal_container_dir(SNAPSHOT_BACKEND, path.as_ref()).c(d!())
+    }
+
+    /// Takes a checkpoint like `snapshot` without codec, a compressed snapshot is a portable
+    /// snapshot file, see `snapshot_portable`
+    ///
+    /// `FinDB::restore` restores both.
+    fn snapshot_with_opts<P: AsRef<Path>>(&self, path: P, opts: &SnapshotOptions) -> Result<()> {
+        match opts.codec {
+            SnapshotCodec::None => self.snapshot(path),
+            _ => self.snapshot_portable(path, opts),
+        }
     }
 
     /// Decode key value pair
@@ -137,15 +761,106 @@ impl MerkleDB for FinDB {
         (kv.key().to_vec(), kv.value().to_vec())
     }
 
+    /// Cleans aux
     fn clean_aux(&mut self) -> Result<()> {
         self.db.clean_aux().map_err(|e| eg!(e))
     }
 }
 
+impl OpenWithConfig for FinDB {
+    fn open_with_config(cfg: &StorageConfig) -> Result<Self> {
+        if cfg.backend != Backend::FinDB {
+            return Err(eg!(format!("config is for the {:?} backend", cfg.backend)));
+        }
+        let opts = FinDBOpts {
+            value_log: cfg.value_log_opts(),
+            block_cache: BlockCache::from_config(cfg).c(d!())?,
+            block_cache_size: cfg.rocksdb.block_cache_size,
+            data: cfg.data_cf_opts(),
+        };
+        let mut db = Self::open_with_opts(&cfg.path, &opts).c(d!())?;
+        if let Some(size) = cfg.cache.proof_memo_size {
+            db.set_proof_memo_size(size);
+        }
+        Ok(db)
+    }
+}
+
+/// Verifies merk proofs generated by `FinDB`, e.g. for a `RemoteDB` over a FinDB node
+pub struct MerkVerifier;
+
+impl ProofVerifier for MerkVerifier {
+    fn verify(&self, root: &[u8], keys: &[Vec<u8>], proof: &[u8]) -> Result<Vec<Option<Vec<u8>>>> {
+        let root = root
+            .try_into()
+            .map_err(|_| eg!("Invalid root hash length {}", root.len()))?;
+        let map = fmerk::verify(proof, root).map_err(|e| eg!("Failed to verify proof {}", e))?;
+        keys.iter()
+            .map(|k| {
+                map.get(k)
+                    .map(|v| v.map(<[u8]>::to_vec))
+                    .map_err(|e| eg!("Key is not covered by the proof {}", e))
+            })
+            .collect()
+    }
+
+    fn verify_range(
+        &self,
+        root: &[u8],
+        lower: &[u8],
+        upper: &[u8],
+        proof: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let root = root
+            .try_into()
+            .map_err(|_| eg!("Invalid root hash length {}", root.len()))?;
+        let map = fmerk::verify(proof, root).map_err(|e| eg!("Failed to verify proof {}", e))?;
+        map.range(lower..upper)
+            .map(|kv| {
+                kv.map(|(k, v)| (k.to_vec(), v.to_vec()))
+                    .map_err(|e| eg!("Range is not covered by the proof {}", e))
+            })
+            .collect()
+    }
+}
+
+fn module_prefix(key: &[u8]) -> &[u8] {
+    PrefixExtractor::Module.prefix(key).unwrap_or(key)
+}
+
+fn in_module_domain(key: &[u8]) -> bool {
+    PrefixExtractor::Module.prefix(key).is_some()
+}
+
+/// Full merge of the state column family, operands are tagged with their `MergeOp`
+///
+/// Returning `None` fails the read or compaction, which only happens on a corrupted operand.
+fn full_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut rocksdb::MergeOperands,
+) -> Option<Vec<u8>> {
+    MergeOp::merge_operands(existing, operands).ok().flatten()
+}
+
+/// Operands of different merge functions can't be combined without the value, they are
+/// kept as is until the full merge
+fn partial_merge(
+    _key: &[u8],
+    _existing: Option<&[u8]>,
+    _operands: &mut rocksdb::MergeOperands,
+) -> Option<Vec<u8>> {
+    None
+}
+
 /// Rocks db
 pub struct RocksDB {
     db: rocksdb::DB,
     path: PathBuf,
+    root: PathBuf,
+    prefix: Option<PrefixExtractor>,
+    // keeps a named cache registered while the db reads through it
+    _block_cache: Option<BlockCache>,
 }
 
 impl RocksDB {
@@ -153,33 +868,135 @@ impl RocksDB {
     /// path, one will be created.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         let db_opts = Self::default_db_opts();
-        Self::open_opt(path, db_opts)
+        Self::open_opt(path, db_opts, None, &FinDBOpts::default())
+    }
+
+    /// Opens a store like `open` with the given block cache, column family tuning and value
+    /// log of the state
+    ///
+    /// The aux data is kept in the column family of the state, its tuning applies to both.
+    pub fn open_with_opts<P: AsRef<Path>>(path: P, opts: &FinDBOpts) -> Result<Self> {
+        Self::open_opt(path, Self::default_db_opts(), None, opts)
+    }
+
+    /// Opens a store like `open`, keeping its write-ahead log within the given limits
+    pub fn open_with_wal<P: AsRef<Path>>(path: P, wal: &WalOpts) -> Result<Self> {
+        let mut db_opts = Self::default_db_opts();
+        Self::set_wal(&mut db_opts, wal);
+        Self::open_opt(path, db_opts, None, &FinDBOpts::default())
+    }
+
+    /// Opens a store like `open`, with prefix bloom filters so scans within a prefix read
+    /// fewer blocks
+    ///
+    /// The extractor is part of the table format, reopening a store with a different one
+    /// leaves existing filters unused until their files are compacted.
+    pub fn open_with_prefix_bloom<P: AsRef<Path>>(
+        path: P,
+        bloom: &PrefixBloomOpts,
+    ) -> Result<Self> {
+        Self::open_opt(
+            path,
+            Self::default_db_opts(),
+            Some(bloom),
+            &FinDBOpts::default(),
+        )
     }
 
     /// Closes the store and deletes all data from disk.
     pub fn destroy(self) -> Result<()> {
         let opts = Self::default_db_opts();
         let path = self.path.clone();
+        let root = self.root.clone();
         drop(self);
         rocksdb::DB::destroy(&opts, path).c(d!())?;
-        Ok(())
+        fs::remove_dir_all(root).c(d!())
     }
 
     /// Opens a store with the specified file path and the given options. If no
-    /// store exists at that path, one will be created.
-    fn open_opt<P>(path: P, db_opts: rocksdb::Options) -> Result<Self>
+    /// store exists at that path, one will be created. A data directory in an
+    /// older layout is migrated first.
+    fn open_opt<P>(
+        path: P,
+        db_opts: rocksdb::Options,
+        bloom: Option<&PrefixBloomOpts>,
+        opts: &FinDBOpts,
+    ) -> Result<Self>
     where
         P: AsRef<Path>,
     {
-        let mut path_buf = PathBuf::new();
-        path_buf.push(path);
-        let cfs = vec![rocksdb::ColumnFamilyDescriptor::new(
-            CF_STATE,
-            Self::default_db_opts(),
-        )];
+        let layout = DataLayout::open(path).c(d!())?;
+        let path_buf = layout.main_dir();
+        let cache = opts.cache().c(d!())?;
+
+        let mut cf_opts = Self::default_db_opts();
+        let mut table_opts = rocksdb::BlockBasedOptions::default();
+        cf_opts.set_merge_operator("storage_merge", full_merge, partial_merge);
+        if let Some(bloom) = bloom {
+            Self::set_prefix_bloom(&mut cf_opts, &mut table_opts, bloom);
+        }
+        tune_cf(&mut cf_opts, &mut table_opts, &opts.data, cache.as_ref());
+        cf_opts.set_block_based_table_factory(&table_opts);
+        if let Some(vlog) = opts.value_log.as_ref() {
+            set_value_log(&mut cf_opts, vlog);
+        }
+        let cfs = vec![rocksdb::ColumnFamilyDescriptor::new(CF_STATE, cf_opts)];
         let db = rocksdb::DB::open_cf_descriptors(&db_opts, &path_buf, cfs).c(d!())?;
 
-        Ok(Self { db, path: path_buf })
+        Ok(Self {
+            db,
+            path: path_buf,
+            root: layout.root().to_path_buf(),
+            prefix: bloom.map(|b| b.extractor),
+            _block_cache: cache,
+        })
+    }
+
+    fn set_wal(opts: &mut rocksdb::Options, wal: &WalOpts) {
+        if wal.size_limit_mb != 0 {
+            opts.set_wal_size_limit_mb(wal.size_limit_mb);
+        }
+        if wal.ttl_seconds != 0 {
+            opts.set_wal_ttl_seconds(wal.ttl_seconds);
+        }
+        if wal.max_total_size != 0 {
+            opts.set_max_total_wal_size(wal.max_total_size);
+        }
+    }
+
+    fn set_prefix_bloom(
+        opts: &mut rocksdb::Options,
+        table_opts: &mut rocksdb::BlockBasedOptions,
+        bloom: &PrefixBloomOpts,
+    ) {
+        let transform = match bloom.extractor {
+            PrefixExtractor::Fixed(n) => rocksdb::SliceTransform::create_fixed_prefix(n),
+            PrefixExtractor::Module => rocksdb::SliceTransform::create(
+                "module_prefix",
+                module_prefix,
+                Some(in_module_domain),
+            ),
+            _ => return,
+        };
+        opts.set_prefix_extractor(transform);
+        if bloom.memtable_bloom_ratio > 0.0 {
+            opts.set_memtable_prefix_bloom_ratio(bloom.memtable_bloom_ratio);
+        }
+        table_opts.set_bloom_filter(bloom.bits_per_key as _, false);
+        table_opts.set_whole_key_filtering(true);
+    }
+
+    /// Builds read options of a range scan, the prefix bloom filters are only used when
+    /// every key of the range has the same prefix, other scans are done in total order
+    fn range_readopts(&self, lower: &[u8], upper: &[u8], opts: &IterOpts) -> rocksdb::ReadOptions {
+        let mut readopts = range_readopts(lower, upper, opts);
+        if let Some(extractor) = self.prefix {
+            match extractor.range_prefix(lower, upper) {
+                Some(_) => readopts.set_prefix_same_as_start(true),
+                None => readopts.set_total_order_seek(true),
+            }
+        }
+        readopts
     }
 
     fn default_db_opts() -> rocksdb::Options {
@@ -200,11 +1017,34 @@ impl RocksDB {
         let state_cf = self.db.cf_handle(CF_STATE).unwrap();
         self.db.iterator_cf_opt(state_cf, readopts, mode)
     }
+
+    fn write(&self, batch: rocksdb::WriteBatch) -> Result<()> {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(false);
+        self.db.write_opt(batch, &opts).c(d!())
+    }
+}
+
+impl OpenWithConfig for RocksDB {
+    fn open_with_config(cfg: &StorageConfig) -> Result<Self> {
+        if cfg.backend != Backend::RocksDB {
+            return Err(eg!(format!("config is for the {:?} backend", cfg.backend)));
+        }
+        let mut db_opts = Self::default_db_opts();
+        Self::set_wal(&mut db_opts, &cfg.wal_opts());
+        let opts = FinDBOpts {
+            value_log: cfg.value_log_opts(),
+            block_cache: BlockCache::from_config(cfg).c(d!())?,
+            block_cache_size: cfg.rocksdb.block_cache_size,
+            data: cfg.data_cf_opts(),
+        };
+        Self::open_opt(&cfg.path, db_opts, cfg.prefix_bloom_opts().as_ref(), &opts)
+    }
 }
 
 impl Clone for RocksDB {
     fn clone(&self) -> Self {
-        RocksDB::open(self.path.clone()).unwrap()
+        RocksDB::open(self.root.clone()).unwrap()
     }
 }
 
@@ -223,6 +1063,20 @@ impl MerkleDB for RocksDB {
         }
     }
 
+    /// Copies the value out of the pinned block, without an allocation per read
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        buf.clear();
+        let cf = match self.db.cf_handle(CF_STATE) {
+            Some(cf) => cf,
+            None => return Ok(None),
+        };
+        let value = self.db.get_pinned_cf(cf, key).c(d!("get data failed"))?;
+        Ok(value.map(|value| {
+            buf.extend_from_slice(&value);
+            value.len()
+        }))
+    }
+
     /// Gets an auxiliary value.
     fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         self.get(key)
@@ -242,22 +1096,120 @@ impl MerkleDB for RocksDB {
         }
 
         // write to db
-        let mut opts = rocksdb::WriteOptions::default();
-        opts.set_sync(false);
-        self.db.write_opt(batch, &opts).c(d!())?;
+        self.write(batch)
+    }
 
-        Ok(())
+    /// Puts a batch of borrowed KVs without copying them
+    fn put_batch_ref(&mut self, kvs: &[KVEntryRef<'_>]) -> Result<()> {
+        let state_cf = self.db.cf_handle(CF_STATE).unwrap();
+        let mut batch = rocksdb::WriteBatch::default();
+        for (key, value) in kvs {
+            match value {
+                Some(value) => batch.put_cf(state_cf, key, value),
+                None => batch.delete_cf(state_cf, key),
+            };
+        }
+
+        // write to db
+        self.write(batch)
     }
 
     /// Gets range iterator
     fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
-        let mut readopts = rocksdb::ReadOptions::default();
-        readopts.set_iterate_lower_bound(lower.to_vec());
-        readopts.set_iterate_upper_bound(upper.to_vec());
+        let readopts = self.range_readopts(lower, upper, &IterOpts::default());
+        match order {
+            IterOrder::Desc => Box::new(self.iter_opt(rocksdb::IteratorMode::End, readopts)),
+            _ => Box::new(self.iter_opt(rocksdb::IteratorMode::Start, readopts)),
+        }
+    }
+
+    /// Gets range iterator with read hints
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbIter<'_> {
+        let readopts = self.range_readopts(lower, upper, opts);
         match order {
-            IterOrder::Asc => Box::new(self.iter_opt(rocksdb::IteratorMode::Start, readopts)),
             IterOrder::Desc => Box::new(self.iter_opt(rocksdb::IteratorMode::End, readopts)),
+            _ => Box::new(self.iter_opt(rocksdb::IteratorMode::Start, readopts)),
+        }
+    }
+
+    /// Gets range iterator over any bounds, an unbounded end reaches the last key
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        let (start, end) = key_range(lower, upper);
+        match end {
+            Some(end) if end <= start => Box::new(std::iter::empty()),
+            Some(end) => self.iter(&start, &end, order),
+            None => {
+                let mut readopts = rocksdb::ReadOptions::default();
+                readopts.set_iterate_lower_bound(start);
+                // a prefix seek would stop at the end of the prefix of the start
+                if self.prefix.is_some() {
+                    readopts.set_total_order_seek(true);
+                }
+                match order {
+                    IterOrder::Desc => {
+                        Box::new(self.iter_opt(rocksdb::IteratorMode::End, readopts))
+                    }
+                    _ => Box::new(self.iter_opt(rocksdb::IteratorMode::Start, readopts)),
+                }
+            }
+        }
+    }
+
+    /// Walks the range on a raw iterator, keys and values are borrowed from rocksdb
+    fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        let readopts = self.range_readopts(lower, upper, &IterOpts::default());
+        let state_cf = self.db.cf_handle(CF_STATE).unwrap();
+        let mut iter = self.db.raw_iterator_cf_opt(state_cf, readopts);
+        match order {
+            IterOrder::Desc => iter.seek_to_last(),
+            _ => iter.seek_to_first(),
+        }
+
+        let mut visited = 0;
+        while let (Some(k), Some(v)) = (iter.key(), iter.value()) {
+            visited += 1;
+            if f(k, v) == ScanControl::Stop {
+                break;
+            }
+            match order {
+                IterOrder::Desc => iter.prev(),
+                _ => iter.next(),
+            }
         }
+        visited
+    }
+
+    /// Writes the patch as a merge operand, it's combined with the value on read
+    fn merge(&mut self, key: &[u8], patch: &[u8], op: MergeOp) -> Result<()> {
+        let state_cf = self.db.cf_handle(CF_STATE).c(d!())?;
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.merge_cf(state_cf, key, op.encode_operand(patch));
+        self.write(batch)
+    }
+
+    /// Deletes the range with a single range tombstone
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        let state_cf = self.db.cf_handle(CF_STATE).c(d!())?;
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.delete_range_cf(state_cf, lower, upper);
+        self.write(batch)
     }
 
     /// Gets range iterator for aux
@@ -265,15 +1217,62 @@ impl MerkleDB for RocksDB {
         self.iter(lower, upper, order)
     }
 
-    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_>
-    {
-        let readopts = rocksdb::ReadOptions::default();
+    /// Gets range iterator on a raw iterator, read errors are yielded
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        let readopts = self.range_readopts(lower, upper, &IterOpts::default());
+        match self.db.cf_handle(CF_STATE) {
+            Some(state_cf) => Box::new(TryRawIter::new(
+                self.db.raw_iterator_cf_opt(state_cf, readopts),
+                order,
+            )),
+            None => Box::new(std::iter::once(Err(eg!("missing state column family")))),
+        }
+    }
+
+    /// Gets range iterator for aux, read errors are yielded
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.try_iter(lower, upper, order)
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        let mut readopts = rocksdb::ReadOptions::default();
+        readopts.set_total_order_seek(true);
         match order {
-            IterOrder::Asc => Box::new(self.iter_opt(rocksdb::IteratorMode::Start, readopts)),
             IterOrder::Desc => Box::new(self.iter_opt(rocksdb::IteratorMode::End, readopts)),
+            _ => Box::new(self.iter_opt(rocksdb::IteratorMode::Start, readopts)),
+        }
+    }
+
+    /// Gets all aux, stored with the data like `iter_aux`
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        self.db_all_iterator(order)
+    }
+
+    /// Gets all pairs on a raw iterator, read errors are yielded
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        let mut readopts = rocksdb::ReadOptions::default();
+        readopts.set_total_order_seek(true);
+        match self.db.cf_handle(CF_STATE) {
+            Some(state_cf) => Box::new(TryRawIter::new(
+                self.db.raw_iterator_cf_opt(state_cf, readopts),
+                order,
+            )),
+            None => Box::new(std::iter::once(Err(eg!("missing state column family")))),
         }
     }
 
+    /// Gets all aux, stored with the data like `try_iter_aux`
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.try_db_all_iterator(order)
+    }
+
+    /// Compacts the range in the state column family, dropping tombstones of deleted keys
+    fn compact_range(&self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        let state_cf = self.db.cf_handle(CF_STATE).c(d!())?;
+        self.db.compact_range_cf(state_cf, Some(lower), Some(upper));
+        Ok(())
+    }
+
     /// Commits changes.
     fn commit(&mut self, kvs: KVBatch, flush: bool) -> Result<()> {
         // write batch
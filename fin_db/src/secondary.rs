@@ -0,0 +1,292 @@
+/// Read-only secondary instance of a FinDB
+///
+/// A follower process, e.g. an indexer, opens the data directory of a primary `FinDB` as a
+/// RocksDB secondary instance. It never writes the primary's files and keeps its own logs in a
+/// separate directory. Its view is fixed until `try_catch_up_with_primary` replays the writes
+/// the primary made since.
+///
+/// Values are read from the tree nodes directly, the tree isn't loaded. The iterators of the
+/// state decode the nodes as they go, `try_iter` yields the nodes which can't be decoded as
+/// errors and the other iterators end at them.
+///
+use crate::{range_readopts, TryStatusIter, MERK_CF_AUX, MERK_CF_INTERNAL, MERK_ROOT_KEY};
+use fmerk::{rocksdb, tree::Tree};
+use ruc::*;
+use std::{
+    ops::Bound,
+    path::{Path, PathBuf},
+};
+use storage::{
+    db::{key_range, DbIter, DbTryIter, IterOpts, IterOrder, KVBatch, KValue, MerkleDB},
+    layout::DataLayout,
+};
+
+/// Read-only follower of a `FinDB` opened by another process
+pub struct SecondaryFinDB {
+    db: rocksdb::DB,
+    primary: PathBuf,
+}
+
+impl SecondaryFinDB {
+    /// Opens the db at `primary`, the data directory of a `FinDB`, keeping the logs of the
+    /// secondary instance in `secondary`
+    pub fn open<P: AsRef<Path>, S: AsRef<Path>>(primary: P, secondary: S) -> Result<Self> {
+        let layout = DataLayout::open_read_only(primary).c(d!())?;
+        let main_dir = layout.main_dir();
+        let mut opts = rocksdb::Options::default();
+        // a secondary instance keeps every file of the primary open
+        opts.set_max_open_files(-1);
+        let db = rocksdb::DB::open_cf_as_secondary(
+            &opts,
+            &main_dir,
+            secondary.as_ref(),
+            [MERK_CF_AUX, MERK_CF_INTERNAL],
+        )
+        .c(d!("Failed to open secondary instance"))?;
+        Ok(SecondaryFinDB {
+            db,
+            primary: layout.root().to_path_buf(),
+        })
+    }
+
+    /// Opens the backend db in `dir` read-only, without a secondary instance, e.g. to plan the
+    /// upgrade of a data directory in an older layout
+    pub(crate) fn open_dir(dir: &Path) -> Result<Self> {
+        let opts = rocksdb::Options::default();
+        let cfs = [MERK_CF_AUX, MERK_CF_INTERNAL];
+        let db = rocksdb::DB::open_cf_for_read_only(&opts, dir, cfs, false)
+            .c(d!("Failed to open db read-only"))?;
+        Ok(SecondaryFinDB {
+            db,
+            primary: dir.to_path_buf(),
+        })
+    }
+
+    /// Data directory of the primary
+    pub fn primary(&self) -> &Path {
+        &self.primary
+    }
+
+    /// Refreshes the view with the writes the primary made since the last refresh
+    ///
+    /// The writes of a commit are replayed together, so a commit of the primary is seen as a
+    /// whole or not at all. Call it periodically to follow the primary.
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        self.db
+            .try_catch_up_with_primary()
+            .c(d!("Failed to catch up with primary"))
+    }
+
+    fn cf(&self, name: &str) -> Result<&rocksdb::ColumnFamily> {
+        self.db
+            .cf_handle(name)
+            .c(d!(format!("missing column family {}", name)))
+    }
+
+    /// Hash of the root node the primary committed last, the null hash for an empty tree
+    pub fn try_root_hash(&self) -> Result<Vec<u8>> {
+        let internal = self.cf(MERK_CF_INTERNAL).c(d!())?;
+        let key = match self.db.get_cf(internal, MERK_ROOT_KEY).c(d!())? {
+            Some(key) => key,
+            None => return Ok(vec![0; 32]),
+        };
+        let node = self.db.get(&key).c(d!())?.c(d!("missing root node"))?;
+        Ok(Self::decode(&key, &node).c(d!())?.hash().to_vec())
+    }
+
+    // `Tree::decode` panics on malformed nodes
+    fn decode(key: &[u8], node: &[u8]) -> Result<Tree> {
+        std::panic::catch_unwind(|| Tree::decode(key.to_vec(), node))
+            .map_err(|_| eg!("Failed to decode tree node"))
+    }
+
+    // raw pairs of the state, or of the column family `cf`, then the error which stopped them
+    fn try_range_iter(
+        &self,
+        cf: Option<&str>,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> Result<TryStatusIter<'_>> {
+        let readopts = range_readopts(lower, upper, opts);
+        let mode = match order {
+            IterOrder::Desc => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        let iter = match cf {
+            Some(name) => {
+                let cf = self.cf(name).c(d!())?;
+                self.db.iterator_cf_opt(cf, readopts, mode)
+            }
+            None => self.db.iterator_opt(mode, readopts),
+        };
+        Ok(TryStatusIter::new(iter))
+    }
+
+    // decoded pairs of the state, then the error which stopped them
+    fn try_state_iter(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbTryIter<'_> {
+        match self.try_range_iter(None, lower, upper, order, opts) {
+            Ok(iter) => Box::new(iter.map(|kv| {
+                let (key, node) = kv?;
+                let value = Self::decode(&key, &node).c(d!())?.value().into();
+                Ok((key, value))
+            })),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn try_aux_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        let opts = IterOpts::default();
+        match self.try_range_iter(Some(MERK_CF_AUX), lower, upper, order, &opts) {
+            Ok(iter) => Box::new(iter),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+}
+
+impl MerkleDB for SecondaryFinDB {
+    /// Hash of the root node the primary committed last, see `try_root_hash`
+    ///
+    /// Panics if the root node can't be read, rather than reporting a root the primary never
+    /// committed.
+    fn root_hash(&self) -> Vec<u8> {
+        self.try_root_hash()
+            .unwrap_or_else(|e| panic!("Failed to read the root hash: {}", e))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.db.get(key).c(d!("Failed to get data from db"))? {
+            Some(node) => Ok(Some(Self::decode(key, &node)?.value().to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db
+            .get_cf(self.cf(MERK_CF_AUX)?, key)
+            .c(d!("Failed to get aux from db"))
+    }
+
+    fn put_batch(&mut self, _kvs: KVBatch) -> Result<()> {
+        Err(eg!("secondary instance is read-only"))
+    }
+
+    fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.iter_with_opts(lower, upper, order, &IterOpts::default())
+    }
+
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbIter<'_> {
+        let iter = self.try_state_iter(lower, upper, order, opts);
+        Box::new(iter.map_while(|kv| kv.ok()))
+    }
+
+    /// Gets range iterator over any bounds, an unbounded end reaches the last key
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        let (start, end) = key_range(lower, upper);
+        match end {
+            Some(end) if end <= start => Box::new(std::iter::empty()),
+            Some(end) => self.iter(&start, &end, order),
+            None => {
+                let mut readopts = rocksdb::ReadOptions::default();
+                readopts.set_iterate_lower_bound(start);
+                let mode = match order {
+                    IterOrder::Desc => rocksdb::IteratorMode::End,
+                    _ => rocksdb::IteratorMode::Start,
+                };
+                let nodes = TryStatusIter::new(self.db.iterator_opt(mode, readopts));
+                Box::new(nodes.map_while(|kv| {
+                    let (key, node) = kv.ok()?;
+                    let value = Self::decode(&key, &node).ok()?.value().into();
+                    Some((key, value))
+                }))
+            }
+        }
+    }
+
+    fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        let iter = self.try_aux_iter(lower, upper, order);
+        Box::new(iter.map_while(|kv| kv.ok()))
+    }
+
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.try_state_iter(lower, upper, order, &IterOpts::default())
+    }
+
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.try_aux_iter(lower, upper, order)
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        Box::new(self.try_db_all_iterator(order).map_while(|kv| kv.ok()))
+    }
+
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        let mode = match order {
+            IterOrder::Desc => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        match self.db.cf_handle(MERK_CF_AUX) {
+            Some(cf) => Box::new(self.db.iterator_cf(cf, mode)),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        let mode = match order {
+            IterOrder::Desc => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        let nodes = TryStatusIter::new(self.db.iterator(mode));
+        Box::new(nodes.map(|kv| {
+            let (key, node) = kv?;
+            let value = Self::decode(&key, &node).c(d!())?.value().into();
+            Ok((key, value))
+        }))
+    }
+
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        let mode = match order {
+            IterOrder::Desc => rocksdb::IteratorMode::End,
+            _ => rocksdb::IteratorMode::Start,
+        };
+        match self.cf(MERK_CF_AUX) {
+            Ok(cf) => Box::new(TryStatusIter::new(self.db.iterator_cf(cf, mode))),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn commit(&mut self, _aux: KVBatch, _flush: bool) -> Result<()> {
+        Err(eg!("secondary instance is read-only"))
+    }
+
+    fn snapshot<P: AsRef<Path>>(&self, _path: P) -> Result<()> {
+        Err(eg!("snapshots are taken by the primary"))
+    }
+
+    /// The iterators of the state yield decoded values already
+    fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue {
+        (kv_pair.0.to_vec(), kv_pair.1.to_vec())
+    }
+
+    fn clean_aux(&mut self) -> Result<()> {
+        Err(eg!("secondary instance is read-only"))
+    }
+}
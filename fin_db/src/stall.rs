@@ -0,0 +1,100 @@
+/// Write stall simulation for tests
+///
+/// A `StallHook` set on a `FinDB` delays its writes and compactions, or stalls them until they
+/// are released, so consumers can verify their back-pressure and timeout handling against a
+/// slow disk in CI. It is only built with the `test-hooks` feature.
+///
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+/// Operations slowed down by a `StallHook`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StallOp {
+    /// `put_batch` and `commit`
+    Write,
+    /// memtable flushes
+    Compaction,
+}
+
+#[derive(Debug, Default)]
+struct OpStall {
+    delay: Duration,
+    stalled: bool,
+    waited: u64,
+}
+
+#[derive(Debug, Default)]
+struct Stalls {
+    write: OpStall,
+    compaction: OpStall,
+}
+
+impl Stalls {
+    fn op(&mut self, op: StallOp) -> &mut OpStall {
+        match op {
+            StallOp::Write => &mut self.write,
+            StallOp::Compaction => &mut self.compaction,
+        }
+    }
+}
+
+/// Delays or stalls the operations of the dbs it is set on, clones share their settings
+#[derive(Clone, Debug, Default)]
+pub struct StallHook {
+    inner: Arc<(Mutex<Stalls>, Condvar)>,
+}
+
+impl StallHook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Stalls> {
+        self.inner.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Delays every `op` by `delay`, zero removes the delay
+    pub fn set_delay(&self, op: StallOp, delay: Duration) {
+        self.lock().op(op).delay = delay;
+    }
+
+    /// Blocks every `op` until `release` is called
+    pub fn stall(&self, op: StallOp) {
+        self.lock().op(op).stalled = true;
+    }
+
+    /// Unblocks the `op` stalled so far and the later ones
+    pub fn release(&self, op: StallOp) {
+        self.lock().op(op).stalled = false;
+        self.inner.1.notify_all();
+    }
+
+    /// Number of `op` which were delayed or stalled
+    pub fn waited(&self, op: StallOp) -> u64 {
+        self.lock().op(op).waited
+    }
+
+    pub(crate) fn wait(&self, op: StallOp) {
+        let mut stalls = self.lock();
+        let delay = {
+            let stall = stalls.op(op);
+            if stall.delay.is_zero() && !stall.stalled {
+                return;
+            }
+            stall.waited = stall.waited.saturating_add(1);
+            stall.delay
+        };
+        while stalls.op(op).stalled {
+            stalls = self
+                .inner
+                .1
+                .wait(stalls)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        drop(stalls);
+        thread::sleep(delay);
+    }
+}
@@ -0,0 +1,503 @@
+/// LMDB backed db
+///
+/// `LmdbDB` implements `MerkleDB` over LMDB through heed, for read-heavy workloads where the
+/// mmap based reads of LMDB outperform RocksDB. The state and the auxiliary data are two named
+/// databases of one environment. The root hash is the binary merkle root of `storage::merkle`,
+/// its leaves are hashed when the db is opened and rehashed by the writes.
+///
+/// The writes of `put_batch` are kept in memory, where reads see them, and written with the
+/// aux data of the next `commit` in one write transaction, see `storage::pending`.
+///
+/// A range iterator holds a read transaction of its own and reads the pairs as it goes, so it
+/// sees a consistent view without loading the range into memory. `try_iter` yields the read
+/// errors, the other iterators end at them and panic if no read transaction can be opened,
+/// rather than passing for an empty db.
+///
+use heed::{types::ByteSlice, CompactionOption, Database, Env, EnvOpenOptions, RoTxn};
+use ruc::*;
+use std::{
+    fs,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use storage::{
+    config::{Backend, OpenWithConfig, StorageConfig},
+    db::{DbIter, DbTryIter, IterOrder, KVBatch, KValue, MerkleDB},
+    layout::DataLayout,
+    merkle::MerkleLeaves,
+    pending::PendingWrites,
+};
+
+type Db = Database<ByteSlice, ByteSlice>;
+
+// cursor of a read transaction, over pairs borrowed from the memory map
+type Cursor<'t> = Box<dyn Iterator<Item = heed::Result<(&'t [u8], &'t [u8])>> + 't>;
+
+const DB_STATE: &str = "state";
+const DB_AUX: &str = "aux";
+const DATA_FILE: &str = "data.mdb";
+/// max size of the memory map, only the pages in use take up disk space, 1TB
+const MAP_SIZE: usize = 0x0100_0000_0000;
+
+/// LMDB db
+pub struct LmdbDB {
+    env: Env,
+    state: Db,
+    aux: Db,
+    root: PathBuf,
+    // leaf hashes of `state` with the pending writes
+    merkle: Mutex<MerkleLeaves>,
+    pending: PendingWrites,
+}
+
+impl LmdbDB {
+    /// Opens a db with the specified file path. If no db exists at that path, one will be
+    /// created. A data directory in an older layout is migrated first.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<LmdbDB> {
+        let layout = DataLayout::open(path).c(d!())?;
+        fs::create_dir_all(layout.main_dir()).c(d!())?;
+        let env = EnvOpenOptions::new()
+            .map_size(MAP_SIZE)
+            .max_dbs(2)
+            .open(layout.main_dir())
+            .c(d!("Failed to open db"))?;
+        let state: Db = env.create_database(Some(DB_STATE)).c(d!())?;
+        let aux = env.create_database(Some(DB_AUX)).c(d!())?;
+        let merkle = {
+            let txn = env.read_txn().c(d!())?;
+            let pairs = state
+                .iter(&txn)
+                .c(d!())?
+                .collect::<heed::Result<Vec<_>>>()
+                .c(d!("Failed to hash db"))?;
+            MerkleLeaves::from_pairs(pairs)
+        };
+        Ok(LmdbDB {
+            env,
+            state,
+            aux,
+            root: layout.root().to_path_buf(),
+            merkle: Mutex::new(merkle),
+            pending: PendingWrites::new(),
+        })
+    }
+
+    /// Closes db and deletes all data from disk.
+    pub fn destroy(self) -> Result<()> {
+        let root = self.root.clone();
+        drop(self);
+        fs::remove_dir_all(root).c(d!())
+    }
+
+    fn merkle(&mut self) -> &mut MerkleLeaves {
+        self.merkle.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn read(&self, db: Db, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let txn = self.env.read_txn().c(d!())?;
+        db.get(&txn, key)
+            .map(|v| v.map(|v| v.to_vec()))
+            .c(d!("Failed to get data from db"))
+    }
+
+    fn try_range(
+        &self,
+        db: Db,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> Result<TxnIter<'_>> {
+        let range = (lower, upper);
+        TxnIter::new(&self.env, |txn| match order {
+            IterOrder::Desc => db
+                .rev_range(txn, &range)
+                .map(|iter| Box::new(iter) as Cursor<'_>),
+            _ => db
+                .range(txn, &range)
+                .map(|iter| Box::new(iter) as Cursor<'_>),
+        })
+    }
+
+    // pairs of the state as of the pending writes
+    fn try_state(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> Result<DbTryIter<'_>> {
+        let stored = self.try_range(self.state, lower, upper, order).c(d!())?;
+        Ok(self
+            .pending
+            .overlay(Box::new(stored), (lower, upper), order))
+    }
+
+    fn try_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> Result<DbTryIter<'_>> {
+        let (lower, upper) = (Bound::Included(lower), Bound::Excluded(upper));
+        let stored = self.try_range(self.aux, lower, upper, order).c(d!())?;
+        Ok(Box::new(stored))
+    }
+}
+
+// pairs up to the first read error
+fn range_iter(iter: Result<DbTryIter<'_>>) -> DbIter<'_> {
+    match iter {
+        Ok(iter) => Box::new(iter.map_while(|kv| kv.ok())),
+        Err(e) => panic!("Failed to iterate db: {}", e),
+    }
+}
+
+// like `range_iter`, the errors of the transaction and of the cursor are yielded
+fn try_range_iter(iter: Result<DbTryIter<'_>>) -> DbTryIter<'_> {
+    match iter {
+        Ok(iter) => iter,
+        Err(e) => Box::new(std::iter::once(Err(e))),
+    }
+}
+
+// Pairs of a cursor, read lazily in the read transaction owned by the iterator
+struct TxnIter<'e> {
+    // borrows `_txn`, declared first to be dropped first
+    cursor: Cursor<'e>,
+    // boxed, its address doesn't change when the iterator moves
+    _txn: Box<RoTxn<'e>>,
+    failed: bool,
+}
+
+impl<'e> TxnIter<'e> {
+    fn new(
+        env: &'e Env,
+        open: impl FnOnce(&'e RoTxn<'e>) -> heed::Result<Cursor<'e>>,
+    ) -> Result<Self> {
+        let txn = Box::new(env.read_txn().c(d!())?);
+        // SAFETY: the transaction is neither moved nor dropped while the cursor lives, the
+        // cursor is dropped first and the box keeps the transaction at one address
+        let txn_ref = unsafe { &*(&*txn as *const RoTxn<'e>) };
+        let cursor = open(txn_ref).c(d!())?;
+        Ok(TxnIter {
+            cursor,
+            _txn: txn,
+            failed: false,
+        })
+    }
+}
+
+impl Iterator for TxnIter<'_> {
+    type Item = Result<(Box<[u8]>, Box<[u8]>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let kv = self.cursor.next()?;
+        self.failed = kv.is_err();
+        Some(
+            kv.map(|(k, v)| (Box::from(k), Box::from(v)))
+                .c(d!("Failed to iterate db")),
+        )
+    }
+}
+
+impl MerkleDB for LmdbDB {
+    /// Root of a binary merkle tree over the live KV pairs in key order, see `storage::merkle`
+    fn root_hash(&self) -> Vec<u8> {
+        self.merkle.lock().unwrap_or_else(|e| e.into_inner()).root()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.clone());
+        }
+        self.read(self.state, key)
+    }
+
+    /// Copies the value out of the memory map, without an allocation per read
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        buf.clear();
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.as_ref().map(|v| {
+                buf.extend_from_slice(v);
+                v.len()
+            }));
+        }
+        let txn = self.env.read_txn().c(d!())?;
+        let value = self
+            .state
+            .get(&txn, key)
+            .c(d!("Failed to get data from db"))?;
+        Ok(value.map(|v| {
+            buf.extend_from_slice(v);
+            v.len()
+        }))
+    }
+
+    fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read(self.aux, key).c(d!("Failed to get aux from db"))
+    }
+
+    /// Keeps the writes in memory until the next `commit`
+    fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
+        let merkle = self.merkle();
+        for (k, v) in kvs.iter() {
+            merkle.update(k, v.as_deref());
+        }
+        self.pending.extend(kvs);
+        Ok(())
+    }
+
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        let keys = self
+            .try_iter(lower, upper, IterOrder::Asc)
+            .map(|kv| kv.map(|(k, _)| (k.to_vec(), None)))
+            .collect::<Result<Vec<_>>>()
+            .c(d!())?;
+        self.pending.extend(keys);
+        self.merkle().remove_range(lower, upper);
+        Ok(())
+    }
+
+    fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        let (lower, upper) = (Bound::Included(lower), Bound::Excluded(upper));
+        range_iter(self.try_state(lower, upper, order))
+    }
+
+    /// Native bounds, an unbounded end reaches the last key
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        range_iter(self.try_state(lower, upper, order))
+    }
+
+    fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        range_iter(self.try_aux(lower, upper, order))
+    }
+
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        let (lower, upper) = (Bound::Included(lower), Bound::Excluded(upper));
+        try_range_iter(self.try_state(lower, upper, order))
+    }
+
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        try_range_iter(self.try_aux(lower, upper, order))
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        range_iter(self.try_state(Bound::Unbounded, Bound::Unbounded, order))
+    }
+
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        let iter = self.try_range(self.aux, Bound::Unbounded, Bound::Unbounded, order);
+        range_iter(iter.map(|iter| Box::new(iter) as DbTryIter<'_>))
+    }
+
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        try_range_iter(self.try_state(Bound::Unbounded, Bound::Unbounded, order))
+    }
+
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        let iter = self.try_range(self.aux, Bound::Unbounded, Bound::Unbounded, order);
+        try_range_iter(iter.map(|iter| Box::new(iter) as DbTryIter<'_>))
+    }
+
+    /// Writes the pending writes of the state and `aux` in one write transaction
+    fn commit(&mut self, aux: KVBatch, flush: bool) -> Result<()> {
+        let mut txn = self.env.write_txn().c(d!())?;
+        let state = self.pending.iter().map(|(k, v)| (self.state, k, v));
+        let aux = aux.iter().map(|(k, v)| (self.aux, k, v));
+        for (db, k, v) in state.chain(aux) {
+            match v {
+                Some(v) => db.put(&mut txn, k, v).c(d!())?,
+                None => db.delete(&mut txn, k).c(d!()).map(|_| ())?,
+            }
+        }
+        txn.commit().c(d!("Failed to commit to db"))?;
+        self.pending.clear();
+        if flush {
+            self.env.force_sync().c(d!("Failed to flush db"))?;
+        }
+        Ok(())
+    }
+
+    /// Copies the environment into a new data directory at `path`
+    fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if path.as_ref().exists() {
+            return Err(eg!("snapshot path already exists"));
+        }
+        let layout = DataLayout::open(path).c(d!())?;
+        self.env
+            .copy_to_path(layout.main_dir().join(DATA_FILE), CompactionOption::Enabled)
+            .c(d!("Failed to copy db"))
+            .map(|_| ())
+    }
+
+    fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue {
+        (kv_pair.0.to_vec(), kv_pair.1.to_vec())
+    }
+
+    fn clean_aux(&mut self) -> Result<()> {
+        let mut txn = self.env.write_txn().c(d!())?;
+        self.aux.clear(&mut txn).c(d!())?;
+        txn.commit().c(d!())
+    }
+}
+
+impl OpenWithConfig for LmdbDB {
+    fn open_with_config(cfg: &StorageConfig) -> Result<Self> {
+        if cfg.backend != Backend::Lmdb {
+            return Err(eg!(format!("config is for the {:?} backend", cfg.backend)));
+        }
+        LmdbDB::open(&cfg.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LmdbDB;
+    use std::{env::temp_dir, path::PathBuf, time::SystemTime};
+    use storage::db::{IterOrder, MerkleDB};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        temp_dir().join(format!("lmdbdb-{}-{}", name, time))
+    }
+
+    #[test]
+    fn db_put_n_get() {
+        let path = temp_path("put_n_get");
+        let mut db = LmdbDB::open(&path).unwrap();
+        let empty = db.root_hash();
+        assert_eq!(empty, vec![0; 32]);
+
+        db.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k30".to_vec(), Some(b"v30".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"100".to_vec()))], true)
+            .unwrap();
+        let root = db.root_hash();
+        assert_ne!(root, empty);
+
+        assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"100".to_vec()));
+        let keys = |order| {
+            db.iter(b"k", b"k30", order)
+                .map(|(k, _)| k.to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(IterOrder::Asc), vec![b"k10".to_vec(), b"k20".to_vec()]);
+        assert_eq!(
+            keys(IterOrder::Desc),
+            vec![b"k20".to_vec(), b"k10".to_vec()]
+        );
+        let aux = db
+            .iter_aux(b"h", b"i", IterOrder::Asc)
+            .map(|kv| db.decode_kv(kv))
+            .collect::<Vec<_>>();
+        assert_eq!(aux, vec![(b"height".to_vec(), b"100".to_vec())]);
+        let pairs = db
+            .try_iter(b"k", b"l", IterOrder::Desc)
+            .collect::<ruc::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(&*pairs[0].0, b"k30");
+        assert_eq!(db.db_all_iterator(IterOrder::Asc).count(), 3);
+
+        // deletes change the root, the data survives a reopen
+        db.delete_range(b"k20", b"k30").unwrap();
+        db.commit(vec![], true).unwrap();
+        assert_ne!(db.root_hash(), root);
+        let root = db.root_hash();
+        drop(db);
+
+        let mut db = LmdbDB::open(&path).unwrap();
+        assert_eq!(db.root_hash(), root);
+        assert_eq!(db.get(b"k20").unwrap(), None);
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"100".to_vec()));
+
+        db.clean_aux().unwrap();
+        assert_eq!(db.get_aux(b"height").unwrap(), None);
+        assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn db_commit_state_with_aux() {
+        let path = temp_path("commit_state_with_aux");
+        let mut db = LmdbDB::open(&path).unwrap();
+        db.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+
+        // the pending writes are read before the commit
+        db.put_batch(vec![(b"k15".to_vec(), Some(b"v15".to_vec()))])
+            .unwrap();
+        db.delete_range(b"k10", b"k15").unwrap();
+        assert_eq!(db.get(b"k10").unwrap(), None);
+        assert_eq!(db.get(b"k15").unwrap(), Some(b"v15".to_vec()));
+        let keys = db
+            .try_iter(b"k", b"l", IterOrder::Desc)
+            .map(|kv| kv.unwrap().0.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![b"k20".to_vec(), b"k15".to_vec()]);
+        let root = db.root_hash();
+
+        // a crash before the commit loses the state writes with the aux data of the height
+        drop(db);
+        let mut db = LmdbDB::open(&path).unwrap();
+        assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(db.get(b"k15").unwrap(), None);
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"1".to_vec()));
+
+        db.put_batch(vec![
+            (b"k10".to_vec(), None),
+            (b"k15".to_vec(), Some(b"v15".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"2".to_vec()))], true)
+            .unwrap();
+        drop(db);
+        let db = LmdbDB::open(&path).unwrap();
+        assert_eq!(db.root_hash(), root);
+        assert_eq!(db.db_all_iterator(IterOrder::Asc).count(), 2);
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"2".to_vec()));
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn db_snapshot() {
+        let path = temp_path("snapshot");
+        let mut db = LmdbDB::open(&path).unwrap();
+        db.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+
+        let snap_path = temp_path("snapshot_copy");
+        db.snapshot(&snap_path).unwrap();
+        assert!(db.snapshot(&snap_path).is_err());
+        assert!(snap_path.join("LAYOUT").is_file());
+        db.put_batch(vec![(b"k20".to_vec(), Some(b"v20".to_vec()))])
+            .unwrap();
+        db.commit(vec![], true).unwrap();
+
+        let snap = LmdbDB::open(&snap_path).unwrap();
+        assert_eq!(snap.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(snap.get(b"k20").unwrap(), None);
+        assert_eq!(snap.get_aux(b"height").unwrap(), Some(b"1".to_vec()));
+        assert_ne!(snap.root_hash(), db.root_hash());
+        snap.destroy().unwrap();
+        db.destroy().unwrap();
+    }
+}
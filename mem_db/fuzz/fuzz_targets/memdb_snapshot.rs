@@ -0,0 +1,13 @@
+//! Decodes arbitrary bytes as a persisted `MemoryDB`
+//!
+//! Decoding must fail with an error rather than panic or allocate more than the input
+//! size, times the max ratio of its codec once compressed. Run from `mem_db/fuzz` with
+//! `cargo fuzz run memdb_snapshot corpus/memdb_snapshot -- -rss_limit_mb=256`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mem_db::MemoryDB;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = MemoryDB::from_bytes(data);
+});
@@ -1,57 +1,286 @@
+use bincode::Options;
 use ruc::*;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::env::temp_dir;
-use std::ops::Bound::{Excluded, Included};
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::SystemTime;
-use storage::db::{DbIter, IterOrder, KVBatch, KValue, MerkleDB};
+use storage::{
+    config::{Backend, OpenWithConfig, StorageConfig},
+    db::{key_range, DbIter, IterOrder, KVBatch, KValue, MerkleDB, ScanControl},
+    leaks::{track_temp_db, TempDbTicket},
+    merkle::MerkleLeaves,
+    portable::{export_portable_file, import_portable, is_portable},
+    snapshot::{decompress_snapshot, is_container, open_container, PrefixFilter, SnapshotOptions},
+};
+
+const MANIFEST_MAGIC: &str = "memorydb-manifest-v1";
+// backend recorded in the snapshots, and in the containers written by older versions
+const SNAPSHOT_BACKEND: &str = "memdb";
+// a data file may be swapped out between reading the manifest and opening it
+const OPEN_RETRIES: usize = 3;
+// a manifest is a few short lines, a longer file isn't one
+const MAX_MANIFEST_LEN: usize = 0x1000;
+
+type KVTree = BTreeMap<Box<[u8]>, Option<Box<[u8]>>>;
+
+// committed and aux maps loaded by `open_shared`, by data file
+static SHARED: Mutex<BTreeMap<PathBuf, (Weak<KVTree>, Weak<KVTree>)>> = Mutex::new(BTreeMap::new());
 
 /// Wraps a Findora db instance and deletes it from disk it once it goes out of scope.
 #[derive(Serialize, Deserialize)]
 pub struct MemoryDB {
     temp: PathBuf,
-    cache: BTreeMap<Box<[u8]>, Option<Box<[u8]>>>,
-    inner: BTreeMap<Box<[u8]>, Option<Box<[u8]>>>,
-    aux: BTreeMap<Box<[u8]>, Option<Box<[u8]>>>,
+    cache: KVTree,
+    // copy-on-write, instances opened with `open_shared` share them until they write
+    inner: Arc<KVTree>,
+    aux: Arc<KVTree>,
+    // leaf hashes of `inner`, loaded by the first `root_hash`
+    #[serde(skip)]
+    merkle: Mutex<Option<MerkleLeaves>>,
+    // recorded while leak tracking is enabled, see `storage::leaks`
+    #[serde(skip)]
+    ticket: Option<TempDbTicket>,
 }
 
 impl MemoryDB {
     pub fn new() -> MemoryDB {
+        let mut db = MemoryDB {
+            temp: Self::temp_path(),
+            cache: BTreeMap::new(),
+            inner: Arc::default(),
+            aux: Arc::default(),
+            merkle: Mutex::default(),
+            ticket: None,
+        };
+        db.track();
+        db
+    }
+
+    fn temp_path() -> PathBuf {
         let time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
+            .unwrap_or_default()
             .as_nanos();
         let mut path = temp_dir();
         path.push(format!("temp-memorydb–{}", time));
-        MemoryDB {
-            temp: path,
-            cache: BTreeMap::new(),
-            inner: BTreeMap::new(),
-            aux: BTreeMap::new(),
-        }
+        path
     }
 
     /// Opens a `MemoryDB` at an autogenerated, temporary file path.
+    ///
+    /// `path` is the manifest of the persisted db, a db written by an older version as a
+    /// single file is opened as well.
     pub fn open(path: PathBuf) -> Result<MemoryDB> {
-        if path.exists() {
-            let bytes = std::fs::read(path).map_err(|_e| eg!("file missing"))?;
-            bincode::deserialize(&bytes).map_err(|_e| eg!("deserialize failure"))
-        } else {
-            Ok(MemoryDB {
+        if !path.exists() {
+            let mut db = MemoryDB {
                 temp: path,
                 cache: BTreeMap::new(),
-                inner: BTreeMap::new(),
-                aux: BTreeMap::new(),
-            })
+                inner: Arc::default(),
+                aux: Arc::default(),
+                merkle: Mutex::default(),
+                ticket: None,
+            };
+            db.track();
+            return Ok(db);
+        }
+
+        let mut retries = 0;
+        let mut db: MemoryDB = loop {
+            let bytes = fs::read(&path).map_err(|_e| eg!("file missing"))?;
+            let data = match Self::read_manifest(&path, &bytes) {
+                Some((data_path, len)) => match fs::metadata(&data_path) {
+                    // checked before reading, the manifest may claim any length
+                    Ok(meta) if meta.len() != len => {
+                        return Err(eg!("data file size doesn't match the manifest"))
+                    }
+                    Ok(_) => fs::read(data_path).c(d!("data file unreadable"))?,
+                    Err(_) if retries < OPEN_RETRIES => {
+                        retries += 1;
+                        continue;
+                    }
+                    Err(_) => return Err(eg!("data file missing")),
+                },
+                None => bytes,
+            };
+            break Self::from_bytes(&data).c(d!())?;
+        };
+        db.temp = path;
+        db.track();
+        Ok(db)
+    }
+
+    /// Decodes a db persisted by `persist`, e.g. a snapshot received from elsewhere
+    ///
+    /// Portable snapshots of any backend are loaded, see `storage::portable`, the root hash
+    /// is checked if the snapshot was taken from a MemoryDB. Dbs persisted by older versions
+    /// are decoded from their internal structures, the container of those is verified and
+    /// they're decompressed first, a db persisted before containers existed is decoded as is.
+    /// Every length read from the input is checked against the bytes left before anything
+    /// is allocated, so a crafted file fails with an error instead of exhausting memory.
+    pub fn from_bytes(bytes: &[u8]) -> Result<MemoryDB> {
+        if is_portable(bytes) {
+            let mut db = MemoryDB::new();
+            let snapshot = import_portable(&mut db, bytes).c(d!())?;
+            if snapshot.backend == SNAPSHOT_BACKEND && snapshot.root_hash != db.root_hash() {
+                return Err(eg!("loaded root doesn't match the snapshot root"));
+            }
+            return Ok(db);
+        }
+
+        let bytes = if is_container(bytes) {
+            open_container(SNAPSHOT_BACKEND, bytes).c(d!())?
+        } else {
+            bytes
+        };
+        let bytes = decompress_snapshot(bytes).c(d!())?;
+        let mut db: MemoryDB = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(bytes.len() as u64)
+            .deserialize(&bytes)
+            .map_err(|e| eg!(format!("invalid db file: {}", e)))?;
+        // the recorded path is deleted on drop, it must not come from the input
+        db.temp = Self::temp_path();
+        db.track();
+        Ok(db)
+    }
+
+    // records the db at its current path, see `storage::leaks`
+    fn track(&mut self) {
+        if let Some(ticket) = self.ticket.take() {
+            ticket.release();
         }
+        self.ticket = track_temp_db("MemoryDB", &self.temp);
+    }
+
+    /// Opens the db persisted at `path` like `open`, loading it only once for all instances
+    ///
+    /// Instances opened from the same snapshot share its maps and copy them on their first
+    /// write, e.g. many test states spawned from one fixture. They persist to a temporary
+    /// path of their own, the snapshot is never modified nor deleted.
+    pub fn open_shared(path: PathBuf) -> Result<MemoryDB> {
+        let key = fs::read(&path)
+            .ok()
+            .and_then(|bytes| Self::read_manifest(&path, &bytes))
+            .map_or_else(|| path.clone(), |(data_path, _)| data_path);
+
+        let mut shared = SHARED
+            .lock()
+            .map_err(|_e| eg!("shared db registry poisoned"))?;
+        let loaded = shared
+            .get(&key)
+            .and_then(|(inner, aux)| Some((inner.upgrade()?, aux.upgrade()?)));
+        let (inner, aux) = match loaded {
+            Some(maps) => maps,
+            None => {
+                let mut db = Self::open(path)?;
+                // dropping the loaded instance must leave the snapshot on disk
+                db.temp = Self::temp_path();
+                let maps = (db.inner.clone(), db.aux.clone());
+                shared.insert(key, (Arc::downgrade(&maps.0), Arc::downgrade(&maps.1)));
+                maps
+            }
+        };
+        shared.retain(|_, (inner, _)| inner.strong_count() > 0);
+
+        // `MemoryDB` implements `Drop`, its fields can't be taken from `new`
+        let mut db = MemoryDB {
+            temp: Self::temp_path(),
+            cache: BTreeMap::new(),
+            inner,
+            aux,
+            merkle: Mutex::default(),
+            ticket: None,
+        };
+        db.track();
+        Ok(db)
     }
 
     /// Closes db and deletes all data from disk.
     pub fn destroy(&mut self) {
-        let _ = std::fs::remove_file(&self.temp);
+        if let Some((data_path, _)) = fs::read(&self.temp)
+            .ok()
+            .and_then(|bytes| Self::read_manifest(&self.temp, &bytes))
+        {
+            let _ = fs::remove_file(data_path);
+        }
+        let _ = fs::remove_file(&self.temp);
         self.cache.clear();
-        self.inner.clear();
+        // other instances may share the map
+        self.inner = Arc::default();
+        *self.merkle.get_mut().unwrap_or_else(|e| e.into_inner()) = None;
+        if let Some(ticket) = self.ticket.take() {
+            ticket.release();
+        }
+    }
+
+    /// Persists the db as an immutable data file and a manifest pointing at it
+    ///
+    /// The data file is a portable snapshot, it's restored by the other backends as well.
+    /// The manifest is replaced atomically once the data file is on disk, so a crash at any
+    /// point leaves the previous db readable and `open` never sees a partial write.
+    fn persist(&self, path: &Path, opts: &SnapshotOptions) -> Result<()> {
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .c(d!("invalid db path"))?;
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .c(d!())?
+            .as_nanos();
+        let data_name = format!("{}.{}.data", file_name, time);
+        let old_data = fs::read(path)
+            .ok()
+            .and_then(|old| Self::read_manifest(path, &old));
+
+        let data_path = path.with_file_name(&data_name);
+        let filter = PrefixFilter::new();
+        export_portable_file(self, SNAPSHOT_BACKEND, &filter, opts, &data_path).c(d!())?;
+        let len = fs::metadata(&data_path).c(d!())?.len();
+        let manifest = format!("{}\n{}\n{}\n", MANIFEST_MAGIC, data_name, len);
+        let tmp = path.with_file_name(format!("{}.tmp", file_name));
+        Self::write_synced(&tmp, manifest.as_bytes())?;
+        fs::rename(&tmp, path).map_err(|_e| eg!("write file failure"))?;
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            let _ = File::open(dir).and_then(|d| d.sync_all());
+        }
+
+        if let Some((old_path, _)) = old_data {
+            let _ = fs::remove_file(old_path);
+        }
+        Ok(())
+    }
+
+    // returns the data file and its length if `bytes` is a manifest
+    fn read_manifest(path: &Path, bytes: &[u8]) -> Option<(PathBuf, u64)> {
+        if bytes.len() > MAX_MANIFEST_LEN {
+            return None;
+        }
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.lines();
+        if lines.next()? != MANIFEST_MAGIC {
+            return None;
+        }
+        // the data file must be next to the manifest, it's also deleted on the next persist
+        let data_name = lines.next()?;
+        if matches!(data_name, "" | "." | "..") || data_name.contains(|c| c == '/' || c == '\\') {
+            return None;
+        }
+        let data_path = path.with_file_name(data_name);
+        let len = lines.next()?.parse().ok()?;
+        Some((data_path, len))
+    }
+
+    fn write_synced(path: &Path, bytes: &[u8]) -> Result<()> {
+        let mut file = File::create(path).map_err(|_e| eg!("write file failure"))?;
+        file.write_all(bytes)
+            .and_then(|_| file.sync_all())
+            .map_err(|_e| eg!("write file failure"))
     }
 }
 
@@ -62,8 +291,20 @@ impl Default for MemoryDB {
 }
 
 impl MerkleDB for MemoryDB {
+    /// Root of a binary merkle tree over the live KV pairs in key order, see `storage::merkle`
+    ///
+    /// The leaves are hashed on the first call, later writes rehash their own leaves only.
     fn root_hash(&self) -> Vec<u8> {
-        vec![]
+        let mut merkle = self.merkle.lock().unwrap_or_else(|e| e.into_inner());
+        merkle
+            .get_or_insert_with(|| {
+                MerkleLeaves::from_pairs(
+                    self.inner
+                        .iter()
+                        .filter_map(|(k, v)| v.as_ref().map(|v| (k, v))),
+                )
+            })
+            .root()
     }
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
@@ -71,39 +312,48 @@ impl MerkleDB for MemoryDB {
         Ok(self.inner.get(&k).cloned().flatten().map(|v| v.to_vec()))
     }
 
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        buf.clear();
+        Ok(self.inner.get(key).and_then(Option::as_deref).map(|v| {
+            buf.extend_from_slice(v);
+            v.len()
+        }))
+    }
+
     fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         let k = key.to_vec().into_boxed_slice();
         Ok(self.aux.get(&k).cloned().flatten().map(|v| v.to_vec()))
     }
 
     fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
+        let inner = Arc::make_mut(&mut self.inner);
+        let merkle = self.merkle.get_mut().unwrap_or_else(|e| e.into_inner());
         for (k, v) in kvs {
-            self.inner
-                .insert(k.into_boxed_slice(), v.map(|v| v.into_boxed_slice()));
+            if let Some(merkle) = merkle.as_mut() {
+                merkle.update(&k, v.as_deref());
+            }
+            inner.insert(k.into_boxed_slice(), v.map(|v| v.into_boxed_slice()));
         }
         Ok(())
     }
 
-    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_>
-    {
-        let lower_key: &[u8] = b"0";
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        let inner = Arc::make_mut(&mut self.inner);
+        let merkle = self.merkle.get_mut().unwrap_or_else(|e| e.into_inner());
+        for (k, v) in inner.range_mut::<[u8], _>((Included(lower), Excluded(upper))) {
+            if let (Some(_), Some(merkle)) = (v.take(), merkle.as_mut()) {
+                merkle.update(k, None);
+            }
+        }
+        Ok(())
+    }
 
-        let lower = lower_key.to_vec().into_boxed_slice();
-        let upper =  lower_key.to_vec().into_boxed_slice();
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        all_iter(&self.inner, order)
+    }
 
-        match order {
-            IterOrder::Asc => Box::new(
-                self.inner
-                    .range::<Box<[u8]>, _>((Included(&lower), Excluded(&upper)))
-                    .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone()))),
-            ),
-            IterOrder::Desc => Box::new(
-                self.inner
-                    .range::<Box<[u8]>, _>((Included(&lower), Excluded(&upper)))
-                    .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone())))
-                    .rev(),
-            ),
-        }
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        all_iter(&self.aux, order)
     }
 
     fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
@@ -111,54 +361,106 @@ impl MerkleDB for MemoryDB {
         let upper = upper.to_vec().into_boxed_slice();
 
         match order {
-            IterOrder::Asc => Box::new(
-                self.inner
-                    .range::<Box<[u8]>, _>((Included(&lower), Excluded(&upper)))
-                    .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone()))),
-            ),
             IterOrder::Desc => Box::new(
                 self.inner
                     .range::<Box<[u8]>, _>((Included(&lower), Excluded(&upper)))
                     .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone())))
                     .rev(),
             ),
+            _ => Box::new(
+                self.inner
+                    .range::<Box<[u8]>, _>((Included(&lower), Excluded(&upper)))
+                    .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone()))),
+            ),
         }
     }
 
+    /// Native unbounded ends, keys above 32 0xFF bytes included
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        let (start, end) = key_range(lower, upper);
+        if end.as_ref().is_some_and(|end| *end <= start) {
+            return Box::new(std::iter::empty());
+        }
+        let start = start.into_boxed_slice();
+        let end = end.map(Vec::into_boxed_slice);
+        let pairs = self
+            .inner
+            .range::<Box<[u8]>, _>((Included(&start), end.as_ref().map_or(Unbounded, Excluded)))
+            .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone())));
+        match order {
+            IterOrder::Desc => Box::new(pairs.rev()),
+            _ => Box::new(pairs),
+        }
+    }
+
+    fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        let range = self
+            .inner
+            .range::<[u8], _>((Included(lower), Excluded(upper)))
+            .filter_map(|(k, v)| v.as_deref().map(|v| (&**k, v)));
+        let entries: Box<dyn Iterator<Item = (&[u8], &[u8])>> = match order {
+            IterOrder::Desc => Box::new(range.rev()),
+            _ => Box::new(range),
+        };
+
+        let mut visited = 0;
+        for (k, v) in entries {
+            visited += 1;
+            if f(k, v) == ScanControl::Stop {
+                break;
+            }
+        }
+        visited
+    }
+
     fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
         let lower = lower.to_vec().into_boxed_slice();
         let upper = upper.to_vec().into_boxed_slice();
 
         match order {
-            IterOrder::Asc => Box::new(
-                self.aux
-                    .range::<Box<[u8]>, _>((Included(&lower), Excluded(&upper)))
-                    .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone()))),
-            ),
             IterOrder::Desc => Box::new(
                 self.aux
                     .range::<Box<[u8]>, _>((Included(&lower), Excluded(&upper)))
                     .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone())))
                     .rev(),
             ),
+            _ => Box::new(
+                self.aux
+                    .range::<Box<[u8]>, _>((Included(&lower), Excluded(&upper)))
+                    .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone()))),
+            ),
         }
     }
 
     fn commit(&mut self, aux: KVBatch, flush: bool) -> Result<()> {
+        let aux_map = Arc::make_mut(&mut self.aux);
         for (k, v) in aux {
-            self.aux
-                .insert(k.into_boxed_slice(), v.map(|v| v.into_boxed_slice()));
+            aux_map.insert(k.into_boxed_slice(), v.map(|v| v.into_boxed_slice()));
         }
         if flush {
-            let bytes = bincode::serialize(self).map_err(|_e| eg!("serialize failure"))?;
-            std::fs::write(&self.temp, bytes).map_err(|_e| eg!("write file failure"))?;
+            self.persist(&self.temp, &SnapshotOptions::default())?;
         }
         Ok(())
     }
 
     fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let bytes = bincode::serialize(self).map_err(|_e| eg!("serialize failure"))?;
-        std::fs::write(path, bytes).map_err(|_e| eg!("write file failure"))
+        self.persist(path.as_ref(), &SnapshotOptions::default())
+    }
+
+    /// Compressed snapshots are opened like uncompressed ones
+    fn snapshot_with_opts<P: AsRef<Path>>(&self, path: P, opts: &SnapshotOptions) -> Result<()> {
+        self.persist(path.as_ref(), opts)
     }
 
     fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue {
@@ -166,11 +468,31 @@ impl MerkleDB for MemoryDB {
     }
 
     fn clean_aux(&mut self) -> Result<()> {
-        self.aux.clear();
+        self.aux = Arc::default();
         Ok(())
     }
 }
 
+// live pairs of a whole map, deleted keys are skipped
+fn all_iter(tree: &KVTree, order: IterOrder) -> DbIter<'_> {
+    let pairs = tree
+        .iter()
+        .filter_map(|(k, v)| v.as_ref().map(|v| (k.clone(), v.clone())));
+    match order {
+        IterOrder::Desc => Box::new(pairs.rev()),
+        _ => Box::new(pairs),
+    }
+}
+
+impl OpenWithConfig for MemoryDB {
+    fn open_with_config(cfg: &StorageConfig) -> Result<Self> {
+        if cfg.backend != Backend::Memory {
+            return Err(eg!(format!("config is for the {:?} backend", cfg.backend)));
+        }
+        Ok(MemoryDB::new())
+    }
+}
+
 impl Drop for MemoryDB {
     fn drop(&mut self) {
         self.destroy();
@@ -180,9 +502,19 @@ impl Drop for MemoryDB {
 #[cfg(test)]
 mod tests {
     use super::MemoryDB;
+    use sha2::{Digest, Sha256};
     use std::env::temp_dir;
+    use std::ops::Bound::{self, Excluded, Included, Unbounded};
+    use std::sync::Arc;
     use std::time::SystemTime;
-    use storage::db::{IterOrder, MerkleDB};
+    use storage::{
+        db::{prefix_successor, DbIter, IterOrder, MerkleDB, ScanControl},
+        portable::PORTABLE_BACKEND,
+        snapshot::{
+            compress_snapshot, decompress_snapshot, is_container, open_container, seal_container,
+            SnapshotCodec, SnapshotOptions,
+        },
+    };
 
     #[test]
     fn db_put_n_get() {
@@ -204,6 +536,42 @@ mod tests {
         assert_eq!(fdb.get_aux(b"height").unwrap().unwrap(), b"100".to_vec());
     }
 
+    #[test]
+    fn db_put_ref_n_get() {
+        let mut fdb = MemoryDB::new();
+
+        // put borrowed data
+        let (k10, v10) = (b"k10".to_vec(), b"v10".to_vec());
+        fdb.put_batch_ref(&[(&k10, Some(&v10)), (b"k20", Some(b"v20"))])
+            .unwrap();
+        fdb.put_batch_ref(&[(b"k20", None)]).unwrap();
+        fdb.commit(vec![], false).unwrap();
+
+        // get and compare
+        assert_eq!(fdb.get(b"k10").unwrap().unwrap(), b"v10".to_vec());
+        assert_eq!(fdb.get(b"k20").unwrap(), None);
+    }
+
+    #[test]
+    fn db_get_into() {
+        let mut fdb = MemoryDB::new();
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![], false).unwrap();
+
+        let mut buf = b"stale".to_vec();
+        assert_eq!(fdb.get_into(b"k10", &mut buf).unwrap(), Some(3));
+        assert_eq!(buf, b"v10");
+        assert_eq!(fdb.get_into(b"k20", &mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+
+        let keys: [&[u8]; 2] = [b"k20", b"k10"];
+        let mut bufs = vec![];
+        let lens = fdb.get_many_into(&keys, &mut bufs).unwrap();
+        assert_eq!(lens, vec![None, Some(3)]);
+        assert_eq!(bufs, vec![vec![], b"v10".to_vec()]);
+    }
+
     #[test]
     fn db_del_n_get() {
         let mut fdb = MemoryDB::new();
@@ -398,6 +766,460 @@ mod tests {
         assert_eq!(expected_aux, actual_aux);
     }
 
+    #[test]
+    fn iter_bounds_range() {
+        let mut fdb = MemoryDB::new();
+        let high = [0xff; 33];
+        fdb.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k30".to_vec(), Some(b"v30".to_vec())),
+            (high.to_vec(), Some(b"high".to_vec())),
+        ])
+        .unwrap();
+        fdb.commit(vec![], false).unwrap();
+
+        let keys = |lower: Bound<&[u8]>, upper: Bound<&[u8]>, order: IterOrder| {
+            fdb.iter_bounds(lower, upper, order)
+                .map(|(k, _)| k.to_vec())
+                .collect::<Vec<_>>()
+        };
+
+        // inclusive upper bound
+        let actual = keys(Included(&b"k10"[..]), Included(&b"k20"[..]), IterOrder::Asc);
+        assert_eq!(actual, vec![b"k10".to_vec(), b"k20".to_vec()]);
+
+        // excluded lower bound, no upper bound reaches keys above 32 0xFF bytes
+        let actual = keys(Excluded(&b"k10"[..]), Unbounded, IterOrder::Desc);
+        assert_eq!(
+            actual,
+            vec![high.to_vec(), b"k30".to_vec(), b"k20".to_vec()]
+        );
+
+        // empty and inverted ranges
+        assert!(keys(Excluded(&b"k20"[..]), Excluded(&b"k20"[..]), IterOrder::Asc).is_empty());
+        assert!(keys(Included(&b"k30"[..]), Included(&b"k10"[..]), IterOrder::Asc).is_empty());
+        assert_eq!(keys(Unbounded, Unbounded, IterOrder::Asc).len(), 4);
+    }
+
+    #[test]
+    fn iter_prefix_overflow() {
+        let mut fdb = MemoryDB::new();
+        let entries = vec![
+            (b"a\xff".to_vec(), Some(b"v1".to_vec())),
+            (b"a\xff\xff\x01".to_vec(), Some(b"v2".to_vec())),
+            (b"b".to_vec(), Some(b"v3".to_vec())),
+            (b"\xff\xff".to_vec(), Some(b"v4".to_vec())),
+        ];
+        fdb.put_batch(entries.clone()).unwrap();
+        fdb.commit(entries, false).unwrap();
+
+        assert_eq!(prefix_successor(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff"), None);
+        assert_eq!(prefix_successor(b""), None);
+
+        let keys = |iter: DbIter<'_>| iter.map(|(k, _)| k.to_vec()).collect::<Vec<_>>();
+        let actual = keys(fdb.iter_prefix(b"a\xff", IterOrder::Desc));
+        assert_eq!(actual, vec![b"a\xff\xff\x01".to_vec(), b"a\xff".to_vec()]);
+        let actual = keys(fdb.iter_aux_prefix(b"a\xff", IterOrder::Asc));
+        assert_eq!(actual, vec![b"a\xff".to_vec(), b"a\xff\xff\x01".to_vec()]);
+
+        // a prefix of 0xFF bytes has no successor
+        assert_eq!(keys(fdb.iter_prefix(b"\xff", IterOrder::Asc)).len(), 1);
+        assert_eq!(keys(fdb.iter_aux_prefix(b"\xff", IterOrder::Asc)).len(), 1);
+        assert_eq!(keys(fdb.iter_prefix(b"", IterOrder::Asc)).len(), 4);
+    }
+
+    #[test]
+    fn db_all_iterator_order() {
+        let mut fdb = MemoryDB::new();
+        fdb.put_batch(vec![
+            (b"\x00".to_vec(), Some(b"v00".to_vec())),
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (vec![0xff; 33], Some(b"vff".to_vec())),
+        ])
+        .unwrap();
+        fdb.commit(
+            vec![
+                (b"k11".to_vec(), Some(b"v11".to_vec())),
+                (b"k21".to_vec(), Some(b"v21".to_vec())),
+            ],
+            false,
+        )
+        .unwrap();
+        fdb.put_batch(vec![(b"k20".to_vec(), None)]).unwrap();
+        fdb.commit(vec![(b"k21".to_vec(), None)], false).unwrap();
+
+        let keys = |iter: DbIter<'_>| iter.map(|(k, _)| k.to_vec()).collect::<Vec<_>>();
+        // every live key, below and above the usual bounds
+        let expected = vec![b"\x00".to_vec(), b"k10".to_vec(), vec![0xff; 33]];
+        assert_eq!(keys(fdb.db_all_iterator(IterOrder::Asc)), expected);
+        let reversed = expected.into_iter().rev().collect::<Vec<_>>();
+        assert_eq!(keys(fdb.db_all_iterator(IterOrder::Desc)), reversed);
+
+        assert_eq!(
+            keys(fdb.db_all_iterator_aux(IterOrder::Asc)),
+            vec![b"k11".to_vec()]
+        );
+        fdb.commit(vec![(b"k31".to_vec(), Some(b"v31".to_vec()))], false)
+            .unwrap();
+        assert_eq!(
+            keys(fdb.db_all_iterator_aux(IterOrder::Desc)),
+            vec![b"k31".to_vec(), b"k11".to_vec()]
+        );
+    }
+
+    #[test]
+    fn scan_apply_stops_early() {
+        let mut fdb = MemoryDB::new();
+        fdb.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k30".to_vec(), Some(b"v30".to_vec())),
+            (b"k40".to_vec(), None),
+            (b"k50".to_vec(), Some(b"v50".to_vec())),
+        ])
+        .unwrap();
+
+        // deleted keys are skipped
+        let mut seen = vec![];
+        let visited = fdb.scan_apply(b"k10", b"k60", IterOrder::Desc, &mut |k, v| {
+            seen.push((k.to_vec(), v.to_vec()));
+            ScanControl::Continue
+        });
+        assert_eq!(visited, 4);
+        assert_eq!(seen[0], (b"k50".to_vec(), b"v50".to_vec()));
+        assert_eq!(seen[1], (b"k30".to_vec(), b"v30".to_vec()));
+
+        // stop on the second entry
+        let mut keys = vec![];
+        let visited = fdb.scan_apply(b"k10", b"k60", IterOrder::Asc, &mut |k, _| {
+            keys.push(k.to_vec());
+            if k == b"k20" {
+                ScanControl::Stop
+            } else {
+                ScanControl::Continue
+            }
+        });
+        assert_eq!(visited, 2);
+        assert_eq!(keys, vec![b"k10".to_vec(), b"k20".to_vec()]);
+    }
+
+    #[test]
+    fn db_persist_crash_safe() {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = temp_dir().join(format!("temp-memorydb-manifest-{}", time));
+        let data_files = || {
+            std::fs::read_dir(temp_dir())
+                .unwrap()
+                .filter_map(|e| e.ok())
+                .filter(|e| {
+                    let name = e.file_name().to_string_lossy().to_string();
+                    name.starts_with(&format!("temp-memorydb-manifest-{}.", time))
+                        && name.ends_with(".data")
+                })
+                .count()
+        };
+
+        let mut fdb = MemoryDB::open(path.clone()).unwrap();
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![], true).unwrap();
+        fdb.put_batch(vec![(b"k20".to_vec(), Some(b"v20".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![], true).unwrap();
+        // the previous data file is dropped once the manifest points at the new one
+        assert_eq!(data_files(), 1);
+
+        // a crash while writing the next data file or manifest leaves the db as it was
+        let tmp = path.with_file_name(format!("temp-memorydb-manifest-{}.tmp", time));
+        std::fs::write(&tmp, b"partial").unwrap();
+        let partial = path.with_file_name(format!("temp-memorydb-manifest-{}.1.data", time));
+        std::fs::write(&partial, b"partial").unwrap();
+        let reopened = MemoryDB::open(path.clone()).unwrap();
+        assert_eq!(reopened.get(b"k20").unwrap(), Some(b"v20".to_vec()));
+        std::fs::remove_file(tmp).unwrap();
+        std::fs::remove_file(partial).unwrap();
+
+        // a db persisted as a single file by older versions still opens
+        let legacy = path.with_file_name(format!("temp-memorydb-legacy-{}", time));
+        std::fs::write(&legacy, bincode::serialize(&fdb).unwrap()).unwrap();
+        let legacy_db = MemoryDB::open(legacy).unwrap();
+        assert_eq!(legacy_db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+
+        drop(reopened);
+        assert!(!path.exists());
+        assert_eq!(data_files(), 0);
+    }
+
+    #[test]
+    fn db_root_hash() {
+        let mut fdb = MemoryDB::new();
+        assert_eq!(fdb.root_hash(), vec![0; 32]);
+
+        // a single leaf is the root
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        let leaf = Sha256::new()
+            .chain_update([0x00])
+            .chain_update(3u32.to_be_bytes())
+            .chain_update(b"k10")
+            .chain_update(b"v10")
+            .finalize()
+            .to_vec();
+        assert_eq!(fdb.root_hash(), leaf);
+
+        fdb.put_batch(vec![(b"k20".to_vec(), Some(b"v20".to_vec()))])
+            .unwrap();
+        let root = fdb.root_hash();
+        assert_ne!(root, leaf);
+        // the root depends on the pairs only, not on how they were written
+        let mut other = MemoryDB::new();
+        other
+            .put_batch(vec![
+                (b"k20".to_vec(), Some(b"v20".to_vec())),
+                (b"k10".to_vec(), Some(b"v10".to_vec())),
+            ])
+            .unwrap();
+        assert_eq!(other.root_hash(), root);
+
+        fdb.put_batch(vec![(b"k20".to_vec(), None)]).unwrap();
+        assert_eq!(fdb.root_hash(), leaf);
+    }
+
+    #[test]
+    fn db_prove_unsupported() {
+        let mut fdb = MemoryDB::new();
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        // proofs of the merkle tree are not implemented
+        assert!(fdb.prove(b"k10").is_err());
+    }
+
+    #[test]
+    fn db_from_bytes_bounded() {
+        let mut fdb = MemoryDB::new();
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![(b"a10".to_vec(), Some(b"x10".to_vec()))], false)
+            .unwrap();
+        let bytes = bincode::serialize(&fdb).unwrap();
+        let decoded = MemoryDB::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(decoded.get_aux(b"a10").unwrap(), Some(b"x10".to_vec()));
+
+        // every truncation is an error
+        for len in 0..bytes.len() {
+            assert!(MemoryDB::from_bytes(&bytes[..len]).is_err());
+        }
+
+        // a length larger than the input fails before anything is allocated
+        let mut crafted = (1u64 << 40).to_le_bytes().to_vec();
+        crafted.extend_from_slice(b"temp");
+        assert!(MemoryDB::from_bytes(&crafted).is_err());
+
+        // a manifest can't point outside its directory
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = temp_dir().join(format!("temp-memorydb-crafted-{}", time));
+        let manifest = format!("memorydb-manifest-v1\n../{}.data\n{}\n", time, bytes.len());
+        std::fs::write(&path, manifest).unwrap();
+        assert!(MemoryDB::open(path.clone()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn db_snapshot_container() {
+        let mut fdb = MemoryDB::new();
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![], false).unwrap();
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = temp_dir().join(format!("temp-memorydb-container-{}", time));
+        fdb.snapshot(&path).unwrap();
+        let manifest = std::fs::read(&path).unwrap();
+        let (data, _) = MemoryDB::read_manifest(&path, &manifest).unwrap();
+        let bytes = std::fs::read(&data).unwrap();
+        assert!(is_container(&bytes));
+        let decoded = MemoryDB::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+
+        // truncated, altered or foreign snapshots fail before decoding
+        for len in 0..bytes.len() {
+            assert!(MemoryDB::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut altered = bytes.clone();
+        altered[bytes.len() / 2] ^= 0x01;
+        assert!(MemoryDB::from_bytes(&altered).is_err());
+        let payload = open_container(PORTABLE_BACKEND, &bytes).unwrap();
+        let foreign = seal_container("findb", payload).unwrap();
+        assert!(MemoryDB::from_bytes(&foreign).is_err());
+        // containers of the internal structures written by older versions still open
+        let legacy = seal_container("memdb", &bincode::serialize(&fdb).unwrap()).unwrap();
+        let decoded = MemoryDB::from_bytes(&legacy).unwrap();
+        assert_eq!(decoded.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        let mut newer = bytes.clone();
+        newer[11] = 0x02;
+        assert!(MemoryDB::from_bytes(&newer).is_err());
+
+        // the data file of a snapshot was truncated on disk
+        std::fs::write(&data, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(MemoryDB::open(path.clone()).is_err());
+        std::fs::remove_file(data).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn db_snapshot_upper_keys() {
+        // keys at or past 32 0xFF bytes are in the snapshot too
+        let upper = vec![u8::MAX; 33];
+        let mut fdb = MemoryDB::new();
+        fdb.put_batch(vec![(upper.clone(), Some(b"v".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![(upper.clone(), Some(b"aux".to_vec()))], false)
+            .unwrap();
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = temp_dir().join(format!("temp-memorydb-upper-{}", time));
+        fdb.snapshot(&path).unwrap();
+        let decoded = MemoryDB::open(path.clone()).unwrap();
+        assert_eq!(decoded.get(&upper).unwrap(), Some(b"v".to_vec()));
+        assert_eq!(decoded.get_aux(&upper).unwrap(), Some(b"aux".to_vec()));
+        assert_eq!(decoded.root_hash(), fdb.root_hash());
+        drop(decoded);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn db_open_shared() {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = temp_dir().join(format!("temp-memorydb-shared-{}", time));
+        let mut fixture = MemoryDB::open(path.clone()).unwrap();
+        fixture
+            .put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        fixture.commit(vec![], true).unwrap();
+
+        // the committed map is loaded once for both instances
+        let mut a = MemoryDB::open_shared(path.clone()).unwrap();
+        let b = MemoryDB::open_shared(path.clone()).unwrap();
+        assert!(Arc::ptr_eq(&a.inner, &b.inner));
+
+        // dropping an instance leaves the maps of the others
+        let c = MemoryDB::open_shared(path.clone()).unwrap();
+        assert!(Arc::ptr_eq(&c.inner, &b.inner));
+        drop(c);
+        assert!(Arc::ptr_eq(&a.inner, &b.inner));
+        assert_eq!(b.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+
+        // a write copies the map of the writer only
+        a.put_batch(vec![(b"k20".to_vec(), Some(b"v20".to_vec()))])
+            .unwrap();
+        assert!(!Arc::ptr_eq(&a.inner, &b.inner));
+        assert_eq!(a.get(b"k20").unwrap(), Some(b"v20".to_vec()));
+        assert_eq!(b.get(b"k20").unwrap(), None);
+        assert_eq!(b.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+
+        // shared instances leave the snapshot on disk
+        drop(a);
+        drop(b);
+        let reopened = MemoryDB::open_shared(path.clone()).unwrap();
+        assert_eq!(reopened.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(reopened.get(b"k20").unwrap(), None);
+        drop(reopened);
+        drop(fixture);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn db_snapshot_compressed() {
+        let mut fdb = MemoryDB::new();
+        let value = vec![7u8; 0x1000];
+        let batch = (0..64u32)
+            .map(|i| (format!("k{:02}", i).into_bytes(), Some(value.clone())))
+            .collect();
+        fdb.put_batch(batch).unwrap();
+        fdb.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], false)
+            .unwrap();
+
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let mut codecs = vec![SnapshotCodec::None];
+        if cfg!(feature = "compression") {
+            codecs.push(SnapshotCodec::Zstd);
+        } else {
+            let path = temp_dir().join(format!("temp-memorydb-zstd-{}", time));
+            let opts = SnapshotOptions::new().with_codec(SnapshotCodec::Zstd);
+            assert!(fdb.snapshot_with_opts(&path, &opts).is_err());
+        }
+        if cfg!(feature = "lz4") {
+            codecs.push(SnapshotCodec::Lz4);
+        }
+
+        let mut sizes = vec![];
+        for codec in codecs {
+            let path = temp_dir().join(format!("temp-memorydb-{}-{}", codec.as_str(), time));
+            let opts = SnapshotOptions::new().with_codec(codec);
+            fdb.snapshot_with_opts(&path, &opts).unwrap();
+            let manifest = std::fs::read(&path).unwrap();
+            let (data, _) = MemoryDB::read_manifest(&path, &manifest).unwrap();
+            sizes.push(std::fs::metadata(data).unwrap().len());
+
+            let fdb_cp = MemoryDB::open(path).unwrap();
+            assert_eq!(fdb_cp.root_hash(), fdb.root_hash());
+            assert_eq!(fdb_cp.get(b"k42").unwrap(), Some(value.clone()));
+            assert_eq!(fdb_cp.get_aux(b"height").unwrap(), Some(b"1".to_vec()));
+        }
+        assert!(sizes.iter().skip(1).all(|size| *size < sizes[0] / 10));
+    }
+
+    #[test]
+    fn db_snapshot_forged_length() {
+        let mut codecs = vec![];
+        if cfg!(feature = "compression") {
+            codecs.push(SnapshotCodec::Zstd);
+        }
+        if cfg!(feature = "lz4") {
+            codecs.push(SnapshotCodec::Lz4);
+        }
+        let bytes = vec![7u8; 0x1000];
+        for codec in codecs {
+            let opts = SnapshotOptions::new().with_codec(codec);
+            let mut compressed = compress_snapshot(bytes.clone(), &opts).unwrap();
+            assert_eq!(decompress_snapshot(&compressed).unwrap().as_ref(), bytes);
+
+            // the recorded length follows the magic and the codec, a huge one isn't allocated
+            compressed[9..17].copy_from_slice(&(u64::MAX >> 8).to_be_bytes());
+            assert!(decompress_snapshot(&compressed).is_err());
+            compressed[9..17].copy_from_slice(&0x800u64.to_be_bytes());
+            assert!(decompress_snapshot(&compressed).is_err());
+        }
+
+        if cfg!(feature = "compression") {
+            // zeros compress close to the max ratio of zstd, they still decompress
+            let zeros = vec![0u8; 0x0100_0000];
+            let opts = SnapshotOptions::new().with_codec(SnapshotCodec::Zstd);
+            let compressed = compress_snapshot(zeros.clone(), &opts).unwrap();
+            assert_eq!(decompress_snapshot(&compressed).unwrap().as_ref(), zeros);
+        }
+    }
+
     #[test]
     fn db_snapshot() {
         let mut fdb = MemoryDB::new();
@@ -0,0 +1,490 @@
+/// Sled backed db
+///
+/// `SledDB` implements `MerkleDB` over sled, a pure Rust embedded store, for users who don't
+/// want the RocksDB build dependency of `FinDB`. The state and the auxiliary data live in two
+/// trees of one sled db. The root hash is the binary merkle root of `storage::merkle`, its
+/// leaves are hashed when the db is opened and rehashed by the writes, so it suits small and
+/// medium states.
+///
+/// The writes of `put_batch` are kept in memory, where reads see them, and written with the
+/// aux data of the next `commit` in one transaction over both trees, see `storage::pending`.
+///
+/// `try_iter` yields the read errors of sled, the other iterators end at the first one.
+/// Snapshots are data directories whose sled db is written with sled's export/import.
+///
+use ruc::*;
+use sled::{transaction::TransactionResult, Transactional};
+use std::{
+    fs, io,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+use storage::{
+    config::{Backend, OpenWithConfig, StorageConfig},
+    db::{key_range, DbIter, DbTryIter, IterOrder, KVBatch, KValue, MerkleDB},
+    layout::DataLayout,
+    merkle::MerkleLeaves,
+    pending::{is_empty_range, PendingWrites},
+};
+
+const TREE_STATE: &str = "state";
+const TREE_AUX: &str = "aux";
+// waits of `open_sled` for the lock of a db just dropped
+const LOCK_RETRIES: u32 = 50;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+// the background threads of a dropped sled db release its file lock a bit later, a reopen
+// right after a drop waits for them. sled reports the lock as an `Other` io error, it's only
+// told apart by its message
+fn open_sled(path: &Path) -> sled::Result<sled::Db> {
+    let mut retries = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e)) if is_lock_error(&e) && retries < LOCK_RETRIES => {
+                retries += 1;
+                thread::sleep(LOCK_RETRY_DELAY);
+            }
+            res => return res,
+        }
+    }
+}
+
+fn is_lock_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+        || (e.kind() == io::ErrorKind::Other && e.to_string().contains("could not acquire lock"))
+}
+
+fn to_batch(kvs: &KVBatch) -> sled::Batch {
+    let mut batch = sled::Batch::default();
+    for (k, v) in kvs {
+        match v {
+            Some(v) => batch.insert(k.as_slice(), v.as_slice()),
+            None => batch.remove(k.as_slice()),
+        }
+    }
+    batch
+}
+
+// pairs up to the first read error, which ends the range
+fn range_iter<'a>(iter: sled::Iter, order: IterOrder) -> DbIter<'a> {
+    Box::new(try_range_iter(iter, order).map_while(|kv| kv.ok()))
+}
+
+fn owned(bound: Bound<&[u8]>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// like `range_iter`, read errors are yielded
+fn try_range_iter<'a>(iter: sled::Iter, order: IterOrder) -> DbTryIter<'a> {
+    let iter: Box<dyn Iterator<Item = _>> = match order {
+        IterOrder::Desc => Box::new(iter.rev()),
+        _ => Box::new(iter),
+    };
+    Box::new(iter.map(|kv| {
+        kv.map(|(k, v)| (k.to_vec().into_boxed_slice(), v.to_vec().into_boxed_slice()))
+            .c(d!("Failed to iterate db"))
+    }))
+}
+
+/// Sled db
+pub struct SledDB {
+    db: sled::Db,
+    state: sled::Tree,
+    aux: sled::Tree,
+    root: PathBuf,
+    // leaf hashes of `state` with the pending writes
+    merkle: Mutex<MerkleLeaves>,
+    pending: PendingWrites,
+}
+
+impl SledDB {
+    /// Opens a db with the specified file path. If no db exists at that path, one will be
+    /// created. A data directory in an older layout is migrated first.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SledDB> {
+        let layout = DataLayout::open(path).c(d!())?;
+        let db = open_sled(&layout.main_dir()).c(d!("Failed to open db"))?;
+        let state = db.open_tree(TREE_STATE).c(d!())?;
+        let aux = db.open_tree(TREE_AUX).c(d!())?;
+        let pairs = state
+            .iter()
+            .collect::<sled::Result<Vec<_>>>()
+            .c(d!("Failed to hash db"))?;
+        Ok(SledDB {
+            db,
+            state,
+            aux,
+            root: layout.root().to_path_buf(),
+            merkle: Mutex::new(MerkleLeaves::from_pairs(pairs)),
+            pending: PendingWrites::new(),
+        })
+    }
+
+    /// Closes db and deletes all data from disk.
+    pub fn destroy(self) -> Result<()> {
+        let root = self.root.clone();
+        drop(self);
+        fs::remove_dir_all(root).c(d!())
+    }
+
+    fn merkle(&mut self) -> &mut MerkleLeaves {
+        self.merkle.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // pairs of the state in `range` as of the pending writes, then the error which stopped them
+    fn try_state_iter(
+        &self,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+        order: IterOrder,
+    ) -> DbTryIter<'_> {
+        if is_empty_range(range.0, range.1) {
+            return Box::new(std::iter::empty());
+        }
+        let iter = try_range_iter(self.state.range((owned(range.0), owned(range.1))), order);
+        self.pending.overlay(iter, range, order)
+    }
+
+    fn state_iter(&self, range: (Bound<&[u8]>, Bound<&[u8]>), order: IterOrder) -> DbIter<'_> {
+        Box::new(self.try_state_iter(range, order).map_while(|kv| kv.ok()))
+    }
+}
+
+impl MerkleDB for SledDB {
+    /// Root of a binary merkle tree over the live KV pairs in key order, see `storage::merkle`
+    fn root_hash(&self) -> Vec<u8> {
+        self.merkle.lock().unwrap_or_else(|e| e.into_inner()).root()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.clone());
+        }
+        self.state
+            .get(key)
+            .map(|v| v.map(|v| v.to_vec()))
+            .c(d!("Failed to get data from db"))
+    }
+
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        buf.clear();
+        if let Some(value) = self.pending.get(key) {
+            return Ok(value.as_ref().map(|v| {
+                buf.extend_from_slice(v);
+                v.len()
+            }));
+        }
+        let value = self.state.get(key).c(d!("Failed to get data from db"))?;
+        Ok(value.map(|v| {
+            buf.extend_from_slice(&v);
+            v.len()
+        }))
+    }
+
+    fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.aux
+            .get(key)
+            .map(|v| v.map(|v| v.to_vec()))
+            .c(d!("Failed to get aux from db"))
+    }
+
+    /// Keeps the writes in memory until the next `commit`
+    fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
+        let merkle = self.merkle();
+        for (k, v) in kvs.iter() {
+            merkle.update(k, v.as_deref());
+        }
+        self.pending.extend(kvs);
+        Ok(())
+    }
+
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        let keys = self
+            .try_iter(lower, upper, IterOrder::Asc)
+            .map(|kv| kv.map(|(k, _)| k.to_vec()))
+            .collect::<Result<Vec<_>>>()
+            .c(d!())?;
+        self.pending.extend(keys.into_iter().map(|k| (k, None)));
+        self.merkle().remove_range(lower, upper);
+        Ok(())
+    }
+
+    fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.state_iter((Bound::Included(lower), Bound::Excluded(upper)), order)
+    }
+
+    /// Native unbounded ends, an unbounded end reaches the last key
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        let (start, end) = key_range(lower, upper);
+        if end.as_ref().is_some_and(|end| *end <= start) {
+            return Box::new(std::iter::empty());
+        }
+        let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        self.state_iter((Bound::Included(&start), end), order)
+    }
+
+    fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        range_iter(self.aux.range(lower..upper), order)
+    }
+
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.try_state_iter((Bound::Included(lower), Bound::Excluded(upper)), order)
+    }
+
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        try_range_iter(self.aux.range(lower..upper), order)
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        self.state_iter((Bound::Unbounded, Bound::Unbounded), order)
+    }
+
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        range_iter(self.aux.iter(), order)
+    }
+
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.try_state_iter((Bound::Unbounded, Bound::Unbounded), order)
+    }
+
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        try_range_iter(self.aux.iter(), order)
+    }
+
+    /// Writes the pending writes of the state and `aux` in one transaction
+    fn commit(&mut self, aux: KVBatch, flush: bool) -> Result<()> {
+        let mut state_batch = sled::Batch::default();
+        for (k, v) in self.pending.iter() {
+            match v {
+                Some(v) => state_batch.insert(k.as_slice(), v.as_slice()),
+                None => state_batch.remove(k.as_slice()),
+            }
+        }
+        let aux_batch = to_batch(&aux);
+        let committed: TransactionResult<()> =
+            (&self.state, &self.aux).transaction(|(state, aux)| {
+                state.apply_batch(&state_batch)?;
+                aux.apply_batch(&aux_batch)?;
+                Ok(())
+            });
+        committed.map_err(|e| eg!(format!("Failed to commit to db: {:?}", e)))?;
+        self.pending.clear();
+        if flush {
+            self.db.flush().c(d!("Failed to flush db"))?;
+        }
+        Ok(())
+    }
+
+    /// Exports every tree into the sled db of a new data directory at `path`
+    fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if path.as_ref().exists() {
+            return Err(eg!("snapshot path already exists"));
+        }
+        let layout = DataLayout::open(path).c(d!())?;
+        let target = sled::open(layout.main_dir()).c(d!("Failed to open snapshot"))?;
+        target.import(self.db.export());
+        target.flush().c(d!()).map(|_| ())
+    }
+
+    fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue {
+        (kv_pair.0.to_vec(), kv_pair.1.to_vec())
+    }
+
+    fn clean_aux(&mut self) -> Result<()> {
+        self.aux.clear().c(d!())
+    }
+}
+
+impl OpenWithConfig for SledDB {
+    fn open_with_config(cfg: &StorageConfig) -> Result<Self> {
+        if cfg.backend != Backend::Sled {
+            return Err(eg!(format!("config is for the {:?} backend", cfg.backend)));
+        }
+        SledDB::open(&cfg.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SledDB;
+    use std::{env::temp_dir, path::PathBuf, time::SystemTime};
+    use storage::db::{IterOrder, MerkleDB};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        temp_dir().join(format!("sleddb-{}-{}", name, time))
+    }
+
+    #[test]
+    fn db_put_n_get() {
+        let path = temp_path("put_n_get");
+        let mut db = SledDB::open(&path).unwrap();
+        let empty = db.root_hash();
+        assert_eq!(empty, vec![0; 32]);
+
+        db.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k30".to_vec(), Some(b"v30".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"100".to_vec()))], true)
+            .unwrap();
+        let root = db.root_hash();
+        assert_ne!(root, empty);
+
+        assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"100".to_vec()));
+        let keys = |order| {
+            db.iter(b"k", b"k30", order)
+                .map(|(k, _)| k.to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(IterOrder::Asc), vec![b"k10".to_vec(), b"k20".to_vec()]);
+        assert_eq!(
+            keys(IterOrder::Desc),
+            vec![b"k20".to_vec(), b"k10".to_vec()]
+        );
+
+        // deletes change the root, the data survives a reopen
+        db.put_batch(vec![(b"k20".to_vec(), None)]).unwrap();
+        db.commit(vec![], true).unwrap();
+        assert_ne!(db.root_hash(), root);
+        let root = db.root_hash();
+        drop(db);
+
+        let db = SledDB::open(&path).unwrap();
+        assert_eq!(db.root_hash(), root);
+        assert_eq!(db.get(b"k20").unwrap(), None);
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"100".to_vec()));
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn db_commit_state_with_aux() {
+        let path = temp_path("commit_state_with_aux");
+        let mut db = SledDB::open(&path).unwrap();
+        db.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+
+        // the pending writes are read before the commit
+        db.put_batch(vec![
+            (b"k10".to_vec(), None),
+            (b"k15".to_vec(), Some(b"v15".to_vec())),
+        ])
+        .unwrap();
+        assert_eq!(db.get(b"k10").unwrap(), None);
+        assert_eq!(db.get(b"k15").unwrap(), Some(b"v15".to_vec()));
+        for order in [IterOrder::Asc, IterOrder::Desc] {
+            let mut keys = db
+                .try_iter(b"k", b"l", order)
+                .map(|kv| kv.unwrap().0.to_vec())
+                .collect::<Vec<_>>();
+            if order == IterOrder::Desc {
+                keys.reverse();
+            }
+            assert_eq!(keys, vec![b"k15".to_vec(), b"k20".to_vec()]);
+        }
+        let root = db.root_hash();
+
+        // a crash before the commit loses the state writes with the aux data of the height
+        drop(db);
+        let mut db = SledDB::open(&path).unwrap();
+        assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(db.get(b"k15").unwrap(), None);
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"1".to_vec()));
+
+        db.put_batch(vec![
+            (b"k10".to_vec(), None),
+            (b"k15".to_vec(), Some(b"v15".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"2".to_vec()))], true)
+            .unwrap();
+        drop(db);
+        let db = SledDB::open(&path).unwrap();
+        assert_eq!(db.root_hash(), root);
+        assert_eq!(db.get(b"k10").unwrap(), None);
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"2".to_vec()));
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn db_snapshot() {
+        let path = temp_path("snapshot");
+        let mut db = SledDB::open(&path).unwrap();
+        db.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+
+        let snap_path = temp_path("snapshot_copy");
+        db.snapshot(&snap_path).unwrap();
+        assert!(db.snapshot(&snap_path).is_err());
+        // a data directory in the current layout, opened without migration
+        assert!(snap_path.join("LAYOUT").is_file());
+        assert!(snap_path.join("main").is_dir());
+        db.put_batch(vec![(b"k20".to_vec(), Some(b"v20".to_vec()))])
+            .unwrap();
+        db.commit(vec![], true).unwrap();
+
+        let snap = SledDB::open(&snap_path).unwrap();
+        assert_eq!(snap.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(snap.get(b"k20").unwrap(), None);
+        assert_eq!(snap.get_aux(b"height").unwrap(), Some(b"1".to_vec()));
+        assert_ne!(snap.root_hash(), db.root_hash());
+        snap.destroy().unwrap();
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn db_try_iter() {
+        let path = temp_path("try_iter");
+        let mut db = SledDB::open(&path).unwrap();
+        db.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k30".to_vec(), Some(b"v30".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+
+        // same pairs as the infallible iterators
+        for order in [IterOrder::Asc, IterOrder::Desc] {
+            let pairs = db
+                .try_iter(b"k", b"k30", order)
+                .collect::<ruc::Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(pairs, db.iter(b"k", b"k30", order).collect::<Vec<_>>());
+        }
+        let aux = db
+            .try_iter_aux(b"h", b"i", IterOrder::Asc)
+            .collect::<ruc::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            aux,
+            db.iter_aux(b"h", b"i", IterOrder::Asc).collect::<Vec<_>>()
+        );
+        assert_eq!(aux.len(), 1);
+        db.destroy().unwrap();
+    }
+}
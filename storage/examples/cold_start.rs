@@ -0,0 +1,73 @@
+//! Times the steps of opening an existing FinDB chain state, e.g. after a node restart.
+//!
+//! cargo run --release -p storage --example cold_start -- <db path> [ver window] [interval] [min value size]
+//!
+//! The version window and snapshot interval must be those the node runs with, a minimum value
+//! size opens the db with a value log.
+use fin_db::FinDB;
+use parking_lot::RwLock;
+use ruc::*;
+use std::{env, sync::Arc, thread, time::Instant};
+use storage::{
+    db::ValueLogOpts,
+    state::{ChainState, ChainStateOpts},
+};
+
+fn main() {
+    pnk!(run());
+}
+
+fn arg_or(args: &[String], index: usize, default: u64) -> Result<u64> {
+    match args.get(index) {
+        Some(n) => n.parse::<u64>().c(d!("invalid number")),
+        None => Ok(default),
+    }
+}
+
+fn run() -> Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() < 2 {
+        return Err(eg!(
+            "usage: cold_start <db path> [ver window] [interval] [min value size]"
+        ));
+    }
+
+    let start = Instant::now();
+    let db = match args.get(4) {
+        Some(_) => {
+            let size = usize::try_from(arg_or(&args, 4, 0)?).c(d!())?;
+            let opts = ValueLogOpts::default().with_min_value_size(size);
+            FinDB::open_with_value_log(&args[1], &opts).c(d!())?
+        }
+        None => FinDB::open(&args[1]).c(d!())?,
+    };
+    println!("db opened in {:?}", start.elapsed());
+
+    let opts = ChainStateOpts {
+        ver_window: arg_or(&args, 2, 0)?,
+        interval: arg_or(&args, 3, 0)?,
+        ..Default::default()
+    };
+    let cs = ChainState::try_create_with_opts(db, opts).c(d!())?;
+    println!(
+        "chain state opened in {:?} at height {}",
+        start.elapsed(),
+        cs.height().c(d!())?
+    );
+
+    // the node serves blocks from here, the snapshots are counted in the background
+    let cs = Arc::new(RwLock::new(cs));
+    let loader = {
+        let cs = cs.clone();
+        thread::spawn(move || ChainState::load_deferred(&cs))
+    };
+    loader
+        .join()
+        .map_err(|_| eg!("deferred loading panicked"))?;
+    println!(
+        "chain state ready in {:?} with {} snapshots",
+        start.elapsed(),
+        cs.read().get_snapshots_info().len()
+    );
+    Ok(())
+}
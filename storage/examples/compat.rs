@@ -0,0 +1,71 @@
+//! Records the state of a FinDB chain state before a release and checks it with a new version.
+//!
+//! cargo run -p storage --example compat -- record <db path> <expectations> [ver window] [interval] [samples]
+//! cargo run -p storage --example compat -- check <db path> <expectations> [ver window] [interval]
+//!
+//! The version window and snapshot interval must be those the node runs with.
+use fin_db::FinDB;
+use ruc::*;
+use std::env;
+use storage::{
+    compat::{check, pick_samples, record, Expectations},
+    layout::DataLayout,
+    state::{ChainState, ChainStateOpts},
+};
+
+const DEFAULT_SAMPLES: usize = 0x03E8;
+
+fn main() {
+    pnk!(run());
+}
+
+fn arg_or(args: &[String], index: usize, default: u64) -> Result<u64> {
+    match args.get(index) {
+        Some(n) => n.parse::<u64>().c(d!("invalid number")),
+        None => Ok(default),
+    }
+}
+
+fn run() -> Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+    if args.len() < 4 {
+        return Err(eg!(
+            "usage: compat <record|check> <db path> <expectations> [ver window] [interval] [samples]"
+        ));
+    }
+
+    // read the header before opening the db updates it
+    let header = DataLayout::open(&args[2]).c(d!())?.header().clone();
+    let opts = ChainStateOpts {
+        ver_window: arg_or(&args, 4, 0)?,
+        interval: arg_or(&args, 5, 0)?,
+        ..Default::default()
+    };
+    let db = FinDB::open(&args[2]).c(d!())?;
+    let cs = ChainState::create_with_opts(db, opts);
+
+    match args[1].as_str() {
+        "record" => {
+            let samples = pick_samples(&cs, arg_or(&args, 6, DEFAULT_SAMPLES as u64)? as usize);
+            let expected = record(&cs, &samples).c(d!())?;
+            expected.save(&args[3]).c(d!())?;
+            println!(
+                "recorded height {} with {} entries and {} samples",
+                expected.height,
+                expected.entries,
+                expected.samples.len()
+            );
+        }
+        "check" => {
+            let expected = Expectations::load(&args[3]).c(d!())?;
+            let mut report = check(&cs, &expected).c(d!())?;
+            report.header = Some(header);
+            println!("{:#?}", report);
+            if !report.is_compatible() {
+                return Err(eg!("state is not compatible"));
+            }
+        }
+        cmd => return Err(eg!(format!("unknown command {}", cmd))),
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+/// Export and import of the auxiliary data
+///
+/// The aux data of a db holds what isn't merkleized: heights, the version index of the chain
+/// state, its snapshots and custom indexes. `export_aux` streams all of it, together with the
+/// root hash of the tree it belongs to, and `import_aux` replaces the aux data of a db with the
+/// same tree by it, e.g. to rebuild a corrupted index or to transplant the index of one node to
+/// another. The tree itself is never read nor written, only its root hash is checked.
+///
+/// A dump is a sequence of frames prefixed with their length as a big endian u32: the format,
+/// the root hash, then a key frame and a value frame per entry. It ends with a frame length of
+/// `u32::MAX` followed by the number of entries as a big endian u64.
+///
+use crate::{
+    db::{IterOrder, KVBatch, MerkleDB},
+    hex,
+    state::chain_state::KEYS_UPPER,
+};
+use ruc::*;
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+};
+
+/// format of the aux dumps
+pub const AUX_DUMP_FORMAT: &str = "storage-aux/1";
+
+const END_OF_ENTRIES: u32 = u32::MAX;
+// a single aux key or value, far above any written by the chain state
+const MAX_FRAME_LEN: u32 = 0x1000_0000;
+
+/// Result of an aux export or import
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuxDump {
+    /// root hash of the tree the aux data belongs to
+    pub root_hash: Vec<u8>,
+    pub entries: u64,
+    /// bytes of the keys and values
+    pub bytes: u64,
+}
+
+pub(crate) fn write_frame<W: Write>(writer: &mut W, data: &[u8]) -> Result<()> {
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .c(d!("aux entry too large"))?;
+    writer.write_all(&len.to_be_bytes()).c(d!())?;
+    writer.write_all(data).c(d!())
+}
+
+pub(crate) fn read_len<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => Ok(u32::from_be_bytes(len)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(eg!("truncated aux dump")),
+        Err(e) => Err(e).c(d!()),
+    }
+}
+
+pub(crate) fn read_data<R: Read>(reader: &mut R, len: u32) -> Result<Vec<u8>> {
+    if len > MAX_FRAME_LEN {
+        return Err(eg!("invalid aux dump frame"));
+    }
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data).c(d!("truncated aux dump"))?;
+    Ok(data)
+}
+
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_len(reader).c(d!())?;
+    read_data(reader, len)
+}
+
+/// Writes every aux entry of `db` to `writer`
+pub fn export_aux<D: MerkleDB, W: Write>(db: &D, writer: &mut W) -> Result<AuxDump> {
+    let mut dump = AuxDump {
+        root_hash: db.root_hash(),
+        ..Default::default()
+    };
+    write_frame(writer, AUX_DUMP_FORMAT.as_bytes()).c(d!())?;
+    write_frame(writer, &dump.root_hash).c(d!())?;
+    for (k, v) in db.iter_aux(&[], &KEYS_UPPER, IterOrder::Asc) {
+        write_frame(writer, &k).c(d!())?;
+        write_frame(writer, &v).c(d!())?;
+        dump.entries = dump.entries.saturating_add(1);
+        dump.bytes = dump.bytes.saturating_add((k.len() + v.len()) as u64);
+    }
+    writer.write_all(&END_OF_ENTRIES.to_be_bytes()).c(d!())?;
+    writer.write_all(&dump.entries.to_be_bytes()).c(d!())?;
+    writer.flush().c(d!())?;
+    Ok(dump)
+}
+
+/// Replaces the aux data of `db` by the dump read from `reader`
+///
+/// The whole dump is read and checked first, a dump of another tree or a truncated one fails
+/// before anything is written. The aux keys of `db` missing from the dump are then deleted in
+/// the same commit the entries are written in, an interrupted import leaves the previous aux
+/// data. The chain state is opened on `db` afterwards.
+pub fn import_aux<D: MerkleDB, R: Read>(db: &mut D, reader: &mut R) -> Result<AuxDump> {
+    if read_frame(reader).c(d!())? != AUX_DUMP_FORMAT.as_bytes() {
+        return Err(eg!("not an aux dump"));
+    }
+    let mut dump = AuxDump {
+        root_hash: read_frame(reader).c(d!())?,
+        ..Default::default()
+    };
+    let root = db.root_hash();
+    if dump.root_hash != root {
+        return Err(eg!(format!(
+            "aux dump of root {} but the tree root is {}",
+            hex::encode(&dump.root_hash),
+            hex::encode(&root)
+        )));
+    }
+
+    let mut entries = BTreeMap::new();
+    loop {
+        let len = read_len(reader).c(d!())?;
+        if len == END_OF_ENTRIES {
+            break;
+        }
+        let key = read_data(reader, len).c(d!())?;
+        let value = read_frame(reader).c(d!())?;
+        dump.entries = dump.entries.saturating_add(1);
+        dump.bytes = dump.bytes.saturating_add((key.len() + value.len()) as u64);
+        entries.insert(key, Some(value));
+    }
+
+    let mut count = [0; 8];
+    reader.read_exact(&mut count).c(d!("truncated aux dump"))?;
+    if u64::from_be_bytes(count) != dump.entries {
+        return Err(eg!("aux dump entry count mismatch"));
+    }
+
+    for kv_pair in db.try_db_all_iterator_aux(IterOrder::Asc) {
+        let (k, _) = kv_pair.c(d!())?;
+        entries.entry(k.into_vec()).or_insert(None);
+    }
+    let batch: KVBatch = entries.into_iter().collect();
+    db.commit(batch, true).c(d!())?;
+    Ok(dump)
+}
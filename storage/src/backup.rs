@@ -0,0 +1,131 @@
+/// Backup scheduling
+///
+/// A `BackupManager` is told about every commit and takes a snapshot of the chain state into
+/// its directory when one is due, every N commits and/or at wall clock times, e.g. daily at
+/// 02:00 UTC. A due snapshot is skipped if the root hash is the one of the newest snapshot in
+/// the directory, i.e. nothing changed since. Snapshots are named after their height and are
+/// listed with `snapshot::list_snapshots`.
+///
+use crate::{db::MerkleDB, snapshot::list_snapshots, state::ChainState};
+use ruc::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// When snapshots are due, an empty schedule never takes any
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupSchedule {
+    /// a snapshot every this many commits, zero disables it
+    pub every_n_commits: u64,
+    /// seconds after midnight UTC at which a snapshot is due every day
+    pub daily_at: Vec<u64>,
+}
+
+impl BackupSchedule {
+    pub fn every_n_commits(mut self, n: u64) -> Self {
+        self.every_n_commits = n;
+        self
+    }
+
+    /// Adds a snapshot every day at `hour:minute` UTC
+    pub fn daily_at(mut self, hour: u64, minute: u64) -> Result<Self> {
+        if hour >= 24 || minute >= 60 {
+            return Err(eg!(format!("invalid time of day {}:{}", hour, minute)));
+        }
+        self.daily_at.push(hour * 3600 + minute * 60);
+        Ok(self)
+    }
+
+    // first scheduled wall clock time after `now`, in seconds since the unix epoch
+    fn next_wall_clock(&self, now: u64) -> Option<u64> {
+        let midnight = now - now % SECS_PER_DAY;
+        self.daily_at
+            .iter()
+            .map(|t| match midnight + t {
+                at if at > now => at,
+                at => at + SECS_PER_DAY,
+            })
+            .min()
+    }
+}
+
+/// What `BackupManager::on_commit` did
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackupOutcome {
+    NotDue,
+    /// a snapshot was due but the state didn't change since the newest one
+    Unchanged,
+    Taken(PathBuf),
+}
+
+/// Takes the snapshots of a chain state as per a `BackupSchedule`
+pub struct BackupManager {
+    dir: PathBuf,
+    schedule: BackupSchedule,
+    commits: u64,
+    // wall clock of the previous commit, scheduled times in between are due
+    last_check: Option<u64>,
+}
+
+impl BackupManager {
+    /// Creates a manager taking snapshots into `dir`, created if missing
+    pub fn new<P: AsRef<Path>>(dir: P, schedule: BackupSchedule) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref()).c(d!())?;
+        Ok(BackupManager {
+            dir: dir.as_ref().to_path_buf(),
+            schedule,
+            commits: 0,
+            last_check: None,
+        })
+    }
+
+    /// Takes a snapshot of `chain` if one is due, to be called after every commit
+    ///
+    /// Wall clock times passed while no commit happened, e.g. while the node was down, are
+    /// caught up by a single snapshot at the next commit.
+    pub fn on_commit<D: MerkleDB>(&mut self, chain: &ChainState<D>) -> Result<BackupOutcome> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.on_commit_at(chain, now)
+    }
+
+    /// Same as `on_commit` with the wall clock at `now`, in seconds since the unix epoch
+    pub fn on_commit_at<D: MerkleDB>(
+        &mut self,
+        chain: &ChainState<D>,
+        now: u64,
+    ) -> Result<BackupOutcome> {
+        self.commits = self.commits.saturating_add(1);
+        let every_n = self.schedule.every_n_commits;
+        let by_commits = every_n != 0 && self.commits.is_multiple_of(every_n);
+        let by_clock = match self.last_check.replace(now) {
+            Some(last) => self
+                .schedule
+                .next_wall_clock(last)
+                .is_some_and(|at| at <= now),
+            None => false,
+        };
+        if !by_commits && !by_clock {
+            return Ok(BackupOutcome::NotDue);
+        }
+
+        // backends without a merkle tree have no root to compare, only the height
+        let root = chain.root_hash();
+        let newest = list_snapshots(&self.dir).c(d!())?.pop();
+        if !root.is_empty() && newest.is_some_and(|s| s.hash == root) {
+            return Ok(BackupOutcome::Unchanged);
+        }
+        let path = self.dir.join(format!("{:020}", chain.height().c(d!())?));
+        if path.exists() {
+            return Ok(BackupOutcome::Unchanged);
+        }
+        chain.snapshot(&path).c(d!())?;
+        Ok(BackupOutcome::Taken(path))
+    }
+}
@@ -0,0 +1,222 @@
+/// KVBatch builder
+///
+/// Collects writes in order, de-duplicates repeated writes to the same key (last write wins)
+/// and remembers where every write came from for debugging.
+///
+use crate::db::{KVBatch, KVEntry};
+use std::collections::HashMap;
+
+/// Origin of a single write in a batch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpSource {
+    /// sequence number of the write in the builder
+    pub seq: usize,
+    /// source tag active when the write was added
+    pub source: Option<String>,
+    /// true if the write was a delete
+    pub deleted: bool,
+}
+
+/// Builder of a `KVBatch` with de-duplication and ordering guarantees
+#[derive(Clone, Debug, Default)]
+pub struct BatchBuilder {
+    entries: Vec<KVEntry>,
+    index: HashMap<Vec<u8>, usize>,
+    provenance: Vec<Vec<OpSource>>,
+    source: Option<String>,
+    seq: usize,
+    sorted: bool,
+}
+
+impl BatchBuilder {
+    /// Creates an empty builder, entries are kept in insertion order
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty builder, entries are sorted by key on `build`
+    pub fn new_sorted() -> Self {
+        BatchBuilder {
+            sorted: true,
+            ..Default::default()
+        }
+    }
+
+    /// Sorts entries by key on `build` or not
+    pub fn sorted(mut self, sorted: bool) -> Self {
+        self.sorted = sorted;
+        self
+    }
+
+    /// Tags all following writes with `source`, e.g. a tx hash or a module name
+    pub fn with_source(&mut self, source: &str) -> &mut Self {
+        self.source = Some(source.to_owned());
+        self
+    }
+
+    /// Stops tagging following writes
+    pub fn clear_source(&mut self) -> &mut Self {
+        self.source = None;
+        self
+    }
+
+    /// put/update value by key
+    pub fn put(&mut self, key: &[u8], value: Vec<u8>) -> &mut Self {
+        self.push(key.to_vec(), Some(value))
+    }
+
+    /// delete value by key
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.push(key.to_vec(), None)
+    }
+
+    /// Adds a raw entry, a `None` value marks a delete
+    pub fn push(&mut self, key: Vec<u8>, value: Option<Vec<u8>>) -> &mut Self {
+        let src = OpSource {
+            seq: self.seq,
+            source: self.source.clone(),
+            deleted: value.is_none(),
+        };
+        self.seq = self.seq.saturating_add(1);
+
+        match self.index.get(&key) {
+            Some(&idx) => {
+                // last write wins, the entry keeps the position of the first write
+                if let Some(entry) = self.entries.get_mut(idx) {
+                    entry.1 = value;
+                }
+                if let Some(ops) = self.provenance.get_mut(idx) {
+                    ops.push(src);
+                }
+            }
+            None => {
+                let _ = self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                self.provenance.push(vec![src]);
+            }
+        }
+        self
+    }
+
+    /// Adds all entries of a batch in order
+    pub fn extend<I: IntoIterator<Item = KVEntry>>(&mut self, kvs: I) -> &mut Self {
+        for (k, v) in kvs {
+            self.push(k, v);
+        }
+        self
+    }
+
+    /// Returns every write applied to `key` so far, oldest first
+    pub fn provenance(&self, key: &[u8]) -> Option<&[OpSource]> {
+        self.index
+            .get(key)
+            .and_then(|idx| self.provenance.get(*idx))
+            .map(|ops| ops.as_slice())
+    }
+
+    /// Returns the keys written more than once
+    pub fn overwritten(&self) -> Vec<&[u8]> {
+        self.entries
+            .iter()
+            .zip(self.provenance.iter())
+            .filter(|(_, ops)| ops.len() > 1)
+            .map(|((k, _), _)| k.as_slice())
+            .collect()
+    }
+
+    /// Number of distinct keys
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Builds the batch, every key appears exactly once
+    pub fn build(self) -> KVBatch {
+        let mut batch = self.entries;
+        if self.sorted {
+            batch.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        batch
+    }
+}
+
+impl From<KVBatch> for BatchBuilder {
+    fn from(kvs: KVBatch) -> Self {
+        let mut builder = BatchBuilder::new();
+        builder.extend(kvs);
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchBuilder, OpSource};
+
+    #[test]
+    fn batch_dedup_last_wins() {
+        let mut builder = BatchBuilder::new();
+        builder
+            .put(b"k20", b"v20".to_vec())
+            .put(b"k10", b"v10".to_vec())
+            .put(b"k20", b"v21".to_vec())
+            .delete(b"k10");
+
+        assert_eq!(builder.len(), 2);
+        assert_eq!(
+            builder.build(),
+            vec![
+                (b"k20".to_vec(), Some(b"v21".to_vec())),
+                (b"k10".to_vec(), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn batch_sorted() {
+        let mut builder = BatchBuilder::new_sorted();
+        builder
+            .put(b"k30", b"v30".to_vec())
+            .put(b"k10", b"v10".to_vec())
+            .put(b"k20", b"v20".to_vec());
+
+        let keys = builder
+            .build()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![b"k10".to_vec(), b"k20".to_vec(), b"k30".to_vec()]
+        );
+    }
+
+    #[test]
+    fn batch_provenance() {
+        let mut builder = BatchBuilder::new();
+        builder.with_source("tx1").put(b"k10", b"v10".to_vec());
+        builder.with_source("tx2").delete(b"k10");
+        builder.clear_source().put(b"k20", b"v20".to_vec());
+
+        assert_eq!(
+            builder.provenance(b"k10").unwrap(),
+            &[
+                OpSource {
+                    seq: 0,
+                    source: Some("tx1".to_owned()),
+                    deleted: false,
+                },
+                OpSource {
+                    seq: 1,
+                    source: Some("tx2".to_owned()),
+                    deleted: true,
+                },
+            ]
+        );
+        assert_eq!(builder.provenance(b"k20").unwrap().len(), 1);
+        assert_eq!(builder.provenance(b"k30"), None);
+        assert_eq!(builder.overwritten(), vec![b"k10".as_ref()]);
+    }
+}
@@ -0,0 +1,368 @@
+/// Read-only state broker over a Unix-domain socket
+///
+/// The process owning the db runs a `Broker` on its chain state, sidecar tools on the same host
+/// query the live state through a `BrokerClient` instead of opening a secondary instance of the
+/// db. Only reads are served: gets, bounded range iterations and proofs. Frames are JSON
+/// encoded and prefixed with their length as a big endian u32.
+///
+/// Every connection is served on its own thread, up to `MAX_CONNECTIONS` at once, further
+/// connections get an error response and are closed.
+///
+use crate::{
+    chained::ReadSource,
+    db::{IterOrder, KValue, MerkleDB},
+    height::Height,
+    state::{ChainState, ProvenValues},
+};
+use parking_lot::{Mutex, RwLock};
+use ruc::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs,
+    io::{ErrorKind, Read, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// max size of a single frame, 64MB
+pub const MAX_FRAME_SIZE: usize = 0x0400_0000;
+/// max number of pairs returned by a single iteration
+pub const MAX_ITER_LIMIT: usize = 0x2710;
+/// max encoded size of the pairs returned by a single iteration, 4MB
+pub const MAX_PAGE_BYTES: usize = 0x0040_0000;
+/// max number of connections served at once
+pub const MAX_CONNECTIONS: usize = 0x40;
+
+/// Query sent to a broker
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrokerRequest {
+    Height,
+    /// value of `key` at `height`, or at the current height if `None`
+    Get {
+        key: Vec<u8>,
+        height: Option<u64>,
+    },
+    /// at most `limit` pairs in `[lower, upper)`, capped by `MAX_ITER_LIMIT` and
+    /// `MAX_PAGE_BYTES`, a page holds at least one pair
+    Iterate {
+        lower: Vec<u8>,
+        upper: Vec<u8>,
+        desc: bool,
+        limit: usize,
+    },
+    /// values of `keys` with one proof of them at the current height
+    Prove {
+        keys: Vec<Vec<u8>>,
+    },
+}
+
+/// Answer of a broker to a `BrokerRequest`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrokerResponse {
+    Height(u64),
+    Value(Option<Vec<u8>>),
+    Pairs(Vec<KValue>),
+    Proven(ProvenValues),
+    Error(String),
+}
+
+// length prefixed encoding of `msg`
+fn encode_frame<T: Serialize>(msg: &T) -> Result<Vec<u8>> {
+    let bytes = serde_json::to_vec(msg).c(d!())?;
+    if bytes.len() > MAX_FRAME_SIZE {
+        return Err(eg!("frame too large"));
+    }
+    let len = u32::try_from(bytes.len()).c(d!())?;
+    let mut frame = Vec::with_capacity(bytes.len() + 4);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&bytes);
+    Ok(frame)
+}
+
+fn write_frame<T: Serialize>(stream: &mut UnixStream, msg: &T) -> Result<()> {
+    let frame = encode_frame(msg).c(d!())?;
+    stream.write_all(&frame).c(d!())
+}
+
+// upper bound of the encoded size of a pair, a JSON byte array takes at most 4 bytes a byte
+fn encoded_len((key, value): &KValue) -> usize {
+    key.len().saturating_add(value.len()).saturating_mul(4) + 8
+}
+
+// `None` once the peer closed the connection
+fn read_frame<T: DeserializeOwned>(stream: &mut UnixStream) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).c(d!()),
+    }
+    let len = usize::try_from(u32::from_be_bytes(len)).c(d!())?;
+    if len > MAX_FRAME_SIZE {
+        return Err(eg!("frame too large"));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes).c(d!())?;
+    serde_json::from_slice(&bytes)
+        .c(d!("invalid frame"))
+        .map(Some)
+}
+
+fn handle<D: MerkleDB>(cs: &ChainState<D>, req: BrokerRequest) -> Result<BrokerResponse> {
+    match req {
+        BrokerRequest::Height => cs.height().map(BrokerResponse::Height),
+        BrokerRequest::Get { key, height: None } => cs.get(&key).map(BrokerResponse::Value),
+        BrokerRequest::Get {
+            key,
+            height: Some(height),
+        } => cs.get_ver(&key, height).map(BrokerResponse::Value),
+        BrokerRequest::Iterate {
+            lower,
+            upper,
+            desc,
+            limit,
+        } => {
+            let order = if desc {
+                IterOrder::Desc
+            } else {
+                IterOrder::Asc
+            };
+            let limit = limit.min(MAX_ITER_LIMIT);
+            let mut pairs = vec![];
+            if limit > 0 {
+                let mut size = 0usize;
+                cs.iterate(&lower, &upper, order, &mut |kv| {
+                    size = size.saturating_add(encoded_len(&kv));
+                    if size > MAX_PAGE_BYTES && !pairs.is_empty() {
+                        return true;
+                    }
+                    pairs.push(kv);
+                    pairs.len() >= limit
+                });
+            }
+            Ok(BrokerResponse::Pairs(pairs))
+        }
+        BrokerRequest::Prove { keys } => {
+            let height = cs.height().c(d!())?;
+            cs.get_many_with_proof(&keys, height)
+                .map(BrokerResponse::Proven)
+        }
+    }
+}
+
+fn serve<D: MerkleDB>(cs: Arc<RwLock<ChainState<D>>>, mut stream: UnixStream) -> Result<()> {
+    while let Some(req) = read_frame(&mut stream).c(d!())? {
+        // the lock is only held for a single request so commits are not held up
+        let resp = handle(&cs.read(), req).unwrap_or_else(|e| BrokerResponse::Error(e.to_string()));
+        // e.g. a single pair too large for a frame
+        let frame = encode_frame(&resp)
+            .or_else(|e| encode_frame(&BrokerResponse::Error(e.to_string())))
+            .c(d!())?;
+        stream.write_all(&frame).c(d!())?;
+    }
+    Ok(())
+}
+
+// removes the socket left at `path` by a broker which didn't stop, fails if one listens on it
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {}
+        Ok(_) => return Err(eg!("broker path exists and is not a socket")),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).c(d!()),
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(eg!("broker socket is in use"));
+    }
+    fs::remove_file(path).c(d!("failed to remove stale broker socket"))
+}
+
+// decrements the number of connections served when a connection ends
+struct ConnGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves read-only queries on a chain state, the broker stops when dropped
+pub struct Broker {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Broker {
+    /// Listens on a new socket at `path`, every connection is served on its own thread
+    ///
+    /// A socket left at `path` by a broker which didn't stop, e.g. of a crashed process, is
+    /// replaced.
+    pub fn spawn<D, P>(cs: Arc<RwLock<ChainState<D>>>, path: P) -> Result<Self>
+    where
+        D: MerkleDB + Send + Sync + 'static,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        remove_stale_socket(&path).c(d!())?;
+        let listener = UnixListener::bind(&path).c(d!("failed to bind broker socket"))?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let stop = stop.clone();
+            let conns = Arc::new(AtomicUsize::new(0));
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let mut stream = match stream {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    if conns.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        conns.fetch_sub(1, Ordering::SeqCst);
+                        let busy = BrokerResponse::Error("too many connections".to_owned());
+                        let _ = write_frame(&mut stream, &busy);
+                        continue;
+                    }
+                    let guard = ConnGuard(conns.clone());
+                    let cs = cs.clone();
+                    let _ = thread::spawn(move || {
+                        let _guard = guard;
+                        serve(cs, stream)
+                    });
+                }
+            })
+        };
+
+        Ok(Broker {
+            path,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stops accepting connections and removes the socket, open connections are kept
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            // wakes the listener up so it sees the stop flag
+            let _ = UnixStream::connect(&self.path);
+            let _ = handle.join();
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Connection to a `Broker`, requests are sent one at a time
+pub struct BrokerClient {
+    stream: Mutex<UnixStream>,
+}
+
+impl BrokerClient {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let stream = UnixStream::connect(path).c(d!("failed to connect to broker"))?;
+        Ok(BrokerClient {
+            stream: Mutex::new(stream),
+        })
+    }
+
+    /// Sends `req` and waits for its response, errors of the broker are returned as `Err`
+    pub fn request(&self, req: &BrokerRequest) -> Result<BrokerResponse> {
+        let mut stream = self.stream.lock();
+        write_frame(&mut stream, req).c(d!())?;
+        match read_frame(&mut stream).c(d!())? {
+            Some(BrokerResponse::Error(e)) => Err(eg!(e)),
+            Some(resp) => Ok(resp),
+            None => Err(eg!("broker closed the connection")),
+        }
+    }
+
+    pub fn height(&self) -> Result<u64> {
+        match self.request(&BrokerRequest::Height).c(d!())? {
+            BrokerResponse::Height(height) => Ok(height),
+            _ => Err(eg!("unexpected broker response")),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_value(key, None)
+    }
+
+    pub fn get_ver(&self, key: &[u8], height: impl Into<Height>) -> Result<Option<Vec<u8>>> {
+        self.get_value(key, Some(height.into().get()))
+    }
+
+    fn get_value(&self, key: &[u8], height: Option<u64>) -> Result<Option<Vec<u8>>> {
+        let req = BrokerRequest::Get {
+            key: key.to_vec(),
+            height,
+        };
+        match self.request(&req).c(d!())? {
+            BrokerResponse::Value(value) => Ok(value),
+            _ => Err(eg!("unexpected broker response")),
+        }
+    }
+
+    /// At most `limit` pairs in `[lower, upper)`, `Unordered` is served in ascending order
+    pub fn iterate(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        limit: usize,
+    ) -> Result<Vec<KValue>> {
+        let req = BrokerRequest::Iterate {
+            lower: lower.to_vec(),
+            upper: upper.to_vec(),
+            desc: order == IterOrder::Desc,
+            limit,
+        };
+        match self.request(&req).c(d!())? {
+            BrokerResponse::Pairs(pairs) => Ok(pairs),
+            _ => Err(eg!("unexpected broker response")),
+        }
+    }
+
+    /// Values of `keys` with one proof of them against the current root hash
+    pub fn prove(&self, keys: &[Vec<u8>]) -> Result<ProvenValues> {
+        let req = BrokerRequest::Prove {
+            keys: keys.to_vec(),
+        };
+        match self.request(&req).c(d!())? {
+            BrokerResponse::Proven(proven) => Ok(proven),
+            _ => Err(eg!("unexpected broker response")),
+        }
+    }
+}
+
+impl ReadSource for BrokerClient {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        BrokerClient::get(self, key)
+    }
+}
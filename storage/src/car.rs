@@ -0,0 +1,597 @@
+/// IPFS/CAR export of state snapshots
+///
+/// `export_car` writes the primary section of a chain state as a CAR v1 file. Entries are
+/// packed in raw blocks of about `chunk_size` bytes, each addressed by the sha2-256 CID of its
+/// content, and a DAG-CBOR root block links all chunks in key order together with the height
+/// and root hash. The file can be imported into IPFS as is, and every block fetched from any
+/// peer can be verified on its own against its CID.
+///
+use crate::{
+    db::{IterOrder, MerkleDB},
+    state::{chain_state::KEYS_UPPER, ChainState},
+};
+use ruc::*;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+/// default size of chunk blocks, well below the 1 MiB block limit of IPFS
+pub const DEFAULT_CAR_CHUNK_SIZE: usize = 256 * 1024;
+
+/// format of the root block
+pub const CAR_FORMAT: &str = "storage-kv/1";
+
+const CID_VERSION: u64 = 1;
+const CODEC_RAW: u64 = 0x55;
+const CODEC_DAG_CBOR: u64 = 0x71;
+const MULTIHASH_SHA2_256: u64 = 0x12;
+const SHA2_256_LEN: u64 = 32;
+const CBOR_TAG_CID: u64 = 42;
+// the root block nests a link in an array in a map
+const MAX_CBOR_DEPTH: usize = 16;
+
+/// Binary CID v1 with a sha2-256 multihash
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cid(Vec<u8>);
+
+impl Cid {
+    /// CID of `data` encoded with `codec`
+    pub fn of(codec: u64, data: &[u8]) -> Self {
+        let mut bytes = vec![];
+        for n in [CID_VERSION, codec, MULTIHASH_SHA2_256, SHA2_256_LEN] {
+            write_varint(&mut bytes, n);
+        }
+        bytes.extend_from_slice(&Sha256::digest(data));
+        Cid(bytes)
+    }
+
+    /// Parses a binary CID v1 with a sha2-256 multihash
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut rd = bytes;
+        let version = read_varint(&mut rd).c(d!())?;
+        let _codec = read_varint(&mut rd).c(d!())?;
+        let hash = read_varint(&mut rd).c(d!())?;
+        let len = read_varint(&mut rd).c(d!())?;
+        if version != CID_VERSION || hash != MULTIHASH_SHA2_256 || len != SHA2_256_LEN {
+            return Err(eg!("unsupported CID"));
+        }
+        if rd.len() as u64 != len {
+            return Err(eg!("invalid CID digest length"));
+        }
+        Ok(Cid(bytes.to_vec()))
+    }
+
+    pub fn codec(&self) -> u64 {
+        let mut rd = self.0.as_slice();
+        let _version = read_varint(&mut rd);
+        read_varint(&mut rd).unwrap_or_default()
+    }
+
+    /// Checks that `data` is the content addressed by this CID
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        if Cid::of(self.codec(), data) != *self {
+            return Err(eg!(format!("block content doesn't match {}", self)));
+        }
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Multibase base32 form used by IPFS, e.g. `bafk...` for chunks
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+        let mut out = String::from("b");
+        let (mut buf, mut bits) = (0u32, 0u32);
+        for &b in self.0.iter() {
+            buf = (buf << 8) | u32::from(b);
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(char::from(ALPHABET[((buf >> bits) & 0x1F) as usize]));
+            }
+        }
+        if bits > 0 {
+            out.push(char::from(ALPHABET[((buf << (5 - bits)) & 0x1F) as usize]));
+        }
+        f.write_str(&out)
+    }
+}
+
+/// Summary of an exported or verified CAR file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CarSummary {
+    pub root: Cid,
+    pub height: u64,
+    pub root_hash: Vec<u8>,
+    /// CIDs of the chunk blocks in key order
+    pub chunks: Vec<Cid>,
+    pub entries: u64,
+}
+
+/// Exports the current state of `cs` as a CAR file at `path`
+///
+/// The state is read twice, first to address the chunks linked by the root block which must
+/// precede them, then to write them, so `cs` must not be committed to meanwhile.
+pub fn export_car<D: MerkleDB, P: AsRef<Path>>(
+    cs: &ChainState<D>,
+    path: P,
+    chunk_size: usize,
+) -> Result<CarSummary> {
+    let mut chunks = vec![];
+    let entries = for_each_chunk(cs, chunk_size, &mut |chunk| {
+        chunks.push(Cid::of(CODEC_RAW, chunk));
+        Ok(())
+    })
+    .c(d!())?;
+
+    let height = cs.height().c(d!())?;
+    let root_hash = cs.root_hash();
+    let root_block = encode_cbor(&Cbor::Map(vec![
+        ("format".to_string(), Cbor::Text(CAR_FORMAT.to_string())),
+        ("height".to_string(), Cbor::Uint(height)),
+        ("root_hash".to_string(), Cbor::Bytes(root_hash.clone())),
+        ("entries".to_string(), Cbor::Uint(entries)),
+        (
+            "chunks".to_string(),
+            Cbor::Array(chunks.iter().cloned().map(Cbor::Link).collect()),
+        ),
+    ]));
+    let root = Cid::of(CODEC_DAG_CBOR, &root_block);
+    let header = encode_cbor(&Cbor::Map(vec![
+        ("version".to_string(), Cbor::Uint(1)),
+        (
+            "roots".to_string(),
+            Cbor::Array(vec![Cbor::Link(root.clone())]),
+        ),
+    ]));
+
+    let mut out = BufWriter::new(File::create(path).c(d!())?);
+    let mut len = vec![];
+    write_varint(&mut len, header.len() as u64);
+    out.write_all(&len).c(d!())?;
+    out.write_all(&header).c(d!())?;
+    write_block(&mut out, &root, &root_block).c(d!())?;
+
+    let mut written = chunks.iter();
+    for_each_chunk(cs, chunk_size, &mut |chunk| {
+        let cid = Cid::of(CODEC_RAW, chunk);
+        if written.next() != Some(&cid) {
+            return Err(eg!("state changed during the export"));
+        }
+        write_block(&mut out, &cid, chunk)
+    })
+    .c(d!())?;
+    out.flush().c(d!())?;
+
+    Ok(CarSummary {
+        root,
+        height,
+        root_hash,
+        chunks,
+        entries,
+    })
+}
+
+/// Verifies every block of the CAR file at `path` and that the chunks linked by its root are
+/// all there in order
+pub fn verify_car<P: AsRef<Path>>(path: P) -> Result<CarSummary> {
+    let mut rd = BufReader::new(File::open(path).c(d!())?);
+    let header = read_section(&mut rd).c(d!())?.c(d!("missing CAR header"))?;
+    let header = decode_cbor(&header).c(d!("invalid CAR header"))?;
+    let root = match (header.get("version"), header.get("roots")) {
+        (Some(Cbor::Uint(1)), Some(Cbor::Array(roots))) => match roots.as_slice() {
+            [Cbor::Link(root)] => root.clone(),
+            _ => return Err(eg!("expected one root")),
+        },
+        _ => return Err(eg!("unsupported CAR header")),
+    };
+
+    let (cid, root_block) = read_block(&mut rd).c(d!())?.c(d!("missing root block"))?;
+    if cid != root {
+        return Err(eg!("first block isn't the root"));
+    }
+    let meta = decode_cbor(&root_block).c(d!("invalid root block"))?;
+    let (height, root_hash, entries, chunks) = match (
+        meta.get("format"),
+        meta.get("height"),
+        meta.get("root_hash"),
+        meta.get("entries"),
+        meta.get("chunks"),
+    ) {
+        (
+            Some(Cbor::Text(format)),
+            Some(&Cbor::Uint(height)),
+            Some(Cbor::Bytes(root_hash)),
+            Some(&Cbor::Uint(entries)),
+            Some(Cbor::Array(chunks)),
+        ) if format == CAR_FORMAT => {
+            let chunks = chunks
+                .iter()
+                .map(|c| match c {
+                    Cbor::Link(cid) => Ok(cid.clone()),
+                    _ => Err(eg!("invalid chunk link")),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            (height, root_hash.clone(), entries, chunks)
+        }
+        _ => return Err(eg!("unsupported root block")),
+    };
+
+    let mut found = 0u64;
+    for expected in chunks.iter() {
+        let (cid, chunk) = read_block(&mut rd).c(d!())?.c(d!("missing chunk"))?;
+        if cid != *expected {
+            return Err(eg!(format!("expected chunk {}, found {}", expected, cid)));
+        }
+        found += decode_chunk(&chunk).c(d!())?.len() as u64;
+    }
+    if read_block(&mut rd).c(d!())?.is_some() {
+        return Err(eg!("unexpected block after the last chunk"));
+    }
+    if found != entries {
+        return Err(eg!(format!(
+            "expected {} entries, found {}",
+            entries, found
+        )));
+    }
+
+    Ok(CarSummary {
+        root,
+        height,
+        root_hash,
+        chunks,
+        entries,
+    })
+}
+
+/// Decodes the entries of a chunk block
+pub fn decode_chunk(mut chunk: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut entries = vec![];
+    while !chunk.is_empty() {
+        let key = read_len_prefixed(&mut chunk).c(d!())?;
+        let value = read_len_prefixed(&mut chunk).c(d!())?;
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+// calls `f` with every chunk in key order and returns the number of entries
+fn for_each_chunk<D: MerkleDB>(
+    cs: &ChainState<D>,
+    chunk_size: usize,
+    f: &mut dyn FnMut(&[u8]) -> Result<()>,
+) -> Result<u64> {
+    let mut chunk = vec![];
+    let mut entries = 0u64;
+    let mut res = Ok(());
+    cs.iterate(&[], &KEYS_UPPER, IterOrder::Asc, &mut |(k, v)| {
+        for bytes in [&k, &v] {
+            write_varint(&mut chunk, bytes.len() as u64);
+            chunk.extend_from_slice(bytes);
+        }
+        entries += 1;
+        if chunk.len() >= chunk_size {
+            res = f(&chunk);
+            chunk.clear();
+        }
+        res.is_err()
+    });
+    res.c(d!())?;
+    if !chunk.is_empty() {
+        f(&chunk).c(d!())?;
+    }
+    Ok(entries)
+}
+
+fn write_block<W: Write>(out: &mut W, cid: &Cid, data: &[u8]) -> Result<()> {
+    let mut len = vec![];
+    write_varint(&mut len, (cid.0.len() + data.len()) as u64);
+    out.write_all(&len).c(d!())?;
+    out.write_all(&cid.0).c(d!())?;
+    out.write_all(data).c(d!())
+}
+
+// reads and verifies the next block, `None` at the end of the file
+fn read_block<R: Read>(rd: &mut R) -> Result<Option<(Cid, Vec<u8>)>> {
+    let section = match read_section(rd).c(d!())? {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+    let mut cur = section.as_slice();
+    for _ in 0..4 {
+        read_varint(&mut cur).c(d!("invalid block CID"))?;
+    }
+    let cid_len = section.len() - cur.len() + SHA2_256_LEN as usize;
+    if section.len() < cid_len {
+        return Err(eg!("truncated block CID"));
+    }
+    let (cid, data) = section.split_at(cid_len);
+    let cid = Cid::from_bytes(cid).c(d!())?;
+    cid.verify(data).c(d!())?;
+    Ok(Some((cid, data.to_vec())))
+}
+
+// reads a length prefixed section, `None` at the end of the file
+fn read_section<R: Read>(rd: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = 0u64;
+    let mut shift = 0;
+    let mut byte = [0u8];
+    loop {
+        match rd.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            Err(e) => return Err(eg!(e)),
+        }
+        if shift > 63 {
+            return Err(eg!("invalid varint"));
+        }
+        len |= u64::from(byte[0] & 0x7F) << shift;
+        shift += 7;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    // the buffer grows with what is read, a corrupted length can't allocate more than the file
+    let mut section = vec![];
+    rd.by_ref().take(len).read_to_end(&mut section).c(d!())?;
+    if section.len() as u64 != len {
+        return Err(eg!("truncated CAR section"));
+    }
+    Ok(Some(section))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7F) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(rd: &mut &[u8]) -> Result<u64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = rd.split_first().c(d!("truncated varint"))?;
+        *rd = rest;
+        if shift > 63 {
+            return Err(eg!("invalid varint"));
+        }
+        n |= u64::from(byte & 0x7F) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+}
+
+fn read_len_prefixed(rd: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = read_varint(rd).c(d!())?;
+    if (rd.len() as u64) < len {
+        return Err(eg!("truncated chunk entry"));
+    }
+    let (bytes, rest) = rd.split_at(len as usize);
+    *rd = rest;
+    Ok(bytes.to_vec())
+}
+
+// the subset of DAG-CBOR used by CAR headers and root blocks
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Cbor {
+    Uint(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(String, Cbor)>),
+    Link(Cid),
+}
+
+impl Cbor {
+    fn get(&self, key: &str) -> Option<&Cbor> {
+        match self {
+            Cbor::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
+
+fn encode_cbor(value: &Cbor) -> Vec<u8> {
+    let mut out = vec![];
+    encode_cbor_into(&mut out, value);
+    out
+}
+
+fn encode_cbor_into(out: &mut Vec<u8>, value: &Cbor) {
+    match value {
+        Cbor::Uint(n) => cbor_head(out, 0, *n),
+        Cbor::Bytes(b) => {
+            cbor_head(out, 2, b.len() as u64);
+            out.extend_from_slice(b);
+        }
+        Cbor::Text(s) => {
+            cbor_head(out, 3, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Cbor::Array(items) => {
+            cbor_head(out, 4, items.len() as u64);
+            items.iter().for_each(|i| encode_cbor_into(out, i));
+        }
+        Cbor::Map(entries) => {
+            // DAG-CBOR orders keys by length first, then bytewise
+            let mut entries = entries.iter().collect::<Vec<_>>();
+            entries.sort_by(|a, b| (a.0.len(), &a.0).cmp(&(b.0.len(), &b.0)));
+            cbor_head(out, 5, entries.len() as u64);
+            for (k, v) in entries {
+                encode_cbor_into(out, &Cbor::Text(k.clone()));
+                encode_cbor_into(out, v);
+            }
+        }
+        Cbor::Link(cid) => {
+            cbor_head(out, 6, CBOR_TAG_CID);
+            cbor_head(out, 2, cid.0.len() as u64 + 1);
+            // multibase prefix of binary CIDs
+            out.push(0);
+            out.extend_from_slice(&cid.0);
+        }
+    }
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xFF => out.extend_from_slice(&[major | 24, n as u8]),
+        0x100..=0xFFFF => {
+            out.push(major | 25);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xFFFF_FFFF => {
+            out.push(major | 26);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+fn decode_cbor(mut bytes: &[u8]) -> Result<Cbor> {
+    let value = decode_cbor_from(&mut bytes, 0).c(d!())?;
+    if !bytes.is_empty() {
+        return Err(eg!("trailing bytes after CBOR value"));
+    }
+    Ok(value)
+}
+
+fn decode_cbor_from(rd: &mut &[u8], depth: usize) -> Result<Cbor> {
+    if depth > MAX_CBOR_DEPTH {
+        return Err(eg!("CBOR value nested too deep"));
+    }
+    let (major, n) = read_cbor_head(rd).c(d!())?;
+    // every item of an array or a map takes at least a byte
+    if matches!(major, 4 | 5) && (rd.len() as u64) < n {
+        return Err(eg!("truncated CBOR value"));
+    }
+    let take = |rd: &mut &[u8]| -> Result<Vec<u8>> {
+        if (rd.len() as u64) < n {
+            return Err(eg!("truncated CBOR value"));
+        }
+        let (bytes, rest) = rd.split_at(n as usize);
+        *rd = rest;
+        Ok(bytes.to_vec())
+    };
+    match major {
+        0 => Ok(Cbor::Uint(n)),
+        2 => take(rd).map(Cbor::Bytes),
+        3 => String::from_utf8(take(rd)?).map(Cbor::Text).c(d!()),
+        4 => (0..n)
+            .map(|_| decode_cbor_from(rd, depth + 1))
+            .collect::<Result<Vec<_>>>()
+            .map(Cbor::Array),
+        5 => (0..n)
+            .map(|_| match decode_cbor_from(rd, depth + 1)? {
+                Cbor::Text(k) => Ok((k, decode_cbor_from(rd, depth + 1)?)),
+                _ => Err(eg!("map keys must be strings")),
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Cbor::Map),
+        6 if n == CBOR_TAG_CID => match decode_cbor_from(rd, depth + 1)? {
+            Cbor::Bytes(b) => match b.split_first() {
+                Some((0, cid)) => Cid::from_bytes(cid).map(Cbor::Link),
+                _ => Err(eg!("invalid CID link")),
+            },
+            _ => Err(eg!("invalid CID link")),
+        },
+        _ => Err(eg!(format!("unsupported CBOR major type {}", major))),
+    }
+}
+
+fn read_cbor_head(rd: &mut &[u8]) -> Result<(u8, u64)> {
+    let (&first, rest) = rd.split_first().c(d!("truncated CBOR value"))?;
+    *rd = rest;
+    let (major, info) = (first >> 5, first & 0x1F);
+    let size = match info {
+        0..=23 => return Ok((major, u64::from(info))),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        _ => return Err(eg!("unsupported CBOR length")),
+    };
+    if rd.len() < size {
+        return Err(eg!("truncated CBOR value"));
+    }
+    let (bytes, rest) = rd.split_at(size);
+    *rd = rest;
+    Ok((major, bytes.iter().fold(0, |n, &b| (n << 8) | u64::from(b))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_cbor, decode_chunk, encode_cbor, read_section, Cbor, Cid, CODEC_RAW, MAX_CBOR_DEPTH,
+    };
+
+    #[test]
+    fn cid_of_empty_raw_block() {
+        // well known CID of an empty raw block
+        assert_eq!(
+            Cid::of(CODEC_RAW, b"").to_string(),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+    }
+
+    #[test]
+    fn cbor_round_trip() {
+        let value = Cbor::Map(vec![
+            ("height".to_string(), Cbor::Uint(0x1_0000)),
+            ("a".to_string(), Cbor::Bytes(vec![1, 2, 3])),
+            (
+                "chunks".to_string(),
+                Cbor::Array(vec![Cbor::Link(Cid::of(CODEC_RAW, b"x"))]),
+            ),
+        ]);
+        let bytes = encode_cbor(&value);
+        // keys are sorted by length first
+        assert_eq!(&bytes[1..3], &[0x61, b'a']);
+        let decoded = decode_cbor(&bytes).unwrap();
+        assert_eq!(decoded.get("height"), Some(&Cbor::Uint(0x1_0000)));
+        assert_eq!(decoded.get("chunks"), value.get("chunks"));
+    }
+
+    #[test]
+    fn corrupted_lengths_are_rejected() {
+        // a section or chunk entry longer than the input
+        let mut section = vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 1, 2, 3];
+        assert!(read_section(&mut section.as_slice()).is_err());
+        section.truncate(1);
+        assert!(read_section(&mut section.as_slice()).is_err());
+        assert!(decode_chunk(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F]).is_err());
+        assert!(decode_chunk(&[1, b'k', 2, b'v']).is_err());
+
+        // arrays and maps of more items than bytes left
+        assert!(decode_cbor(&[0x9B, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+        assert!(decode_cbor(&[0xBA, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]).is_err());
+    }
+
+    #[test]
+    fn cbor_depth_is_bounded() {
+        let nested = |depth: usize| {
+            let mut bytes = vec![0x81; depth];
+            bytes.push(0);
+            bytes
+        };
+        assert!(decode_cbor(&nested(MAX_CBOR_DEPTH)).is_ok());
+        assert!(decode_cbor(&nested(MAX_CBOR_DEPTH + 1)).is_err());
+        assert!(decode_cbor(&nested(0x10_0000)).is_err());
+    }
+}
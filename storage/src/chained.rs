@@ -0,0 +1,257 @@
+/// Store composition: read fallback chain
+///
+/// `ChainedDB` reads from an ordered list of sources, e.g. local overrides, then a snapshot
+/// base, then a remote fetcher, and writes only to the top db. Missing keys are fetched on
+/// demand, which lets a node start from partial state and download the rest lazily.
+///
+use crate::{
+    db::{DbIter, DbTryIter, IterOpts, IterOrder, KVBatch, KValue, MerkleDB, ScanControl},
+    snapshot::SnapshotOptions,
+    store::Prefix,
+};
+use ruc::*;
+use std::{ops::Bound, path::Path};
+
+// aux prefix of keys deleted in the top db, they must not be read from fallbacks
+const CHAINED_TOMBSTONE: &[u8] = b"ChainedDel";
+
+/// Read-only source of a fallback chain
+pub trait ReadSource: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn get_aux(&self, _key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+impl<D: MerkleDB + Send + Sync> ReadSource for D {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        MerkleDB::get(self, key)
+    }
+
+    fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        MerkleDB::get_aux(self, key)
+    }
+}
+
+/// Read source backed by a closure, e.g. a remote fetcher
+pub struct FnSource<F>(pub F);
+
+impl<F> ReadSource for FnSource<F>
+where
+    F: Fn(&[u8]) -> Result<Option<Vec<u8>>> + Send + Sync,
+{
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        (self.0)(key)
+    }
+}
+
+/// MerkleDB reading through a chain of sources and writing to the top db only
+///
+/// Root hash, iterators and proofs are those of the top db, keys only present in a fallback
+/// are not visited by iterators.
+pub struct ChainedDB<D: MerkleDB> {
+    top: D,
+    fallbacks: Vec<Box<dyn ReadSource>>,
+    // deletes of the current block, persisted as tombstones on commit
+    deleted: KVBatch,
+}
+
+impl<D: MerkleDB> ChainedDB<D> {
+    pub fn new(top: D) -> Self {
+        ChainedDB {
+            top,
+            fallbacks: vec![],
+            deleted: vec![],
+        }
+    }
+
+    /// Appends a source to the end of the chain, it's read after all sources added before
+    pub fn with_fallback<S: ReadSource + 'static>(mut self, source: S) -> Self {
+        self.fallbacks.push(Box::new(source));
+        self
+    }
+
+    /// Number of fallback sources
+    pub fn depth(&self) -> usize {
+        self.fallbacks.len()
+    }
+
+    pub fn top(&self) -> &D {
+        &self.top
+    }
+
+    pub fn into_top(self) -> D {
+        self.top
+    }
+
+    fn tombstone(key: &[u8]) -> Vec<u8> {
+        Prefix::new(CHAINED_TOMBSTONE).push(key).as_ref().to_vec()
+    }
+
+    fn deleted_in_top(&self, key: &[u8]) -> Result<bool> {
+        self.top
+            .get_aux(&Self::tombstone(key))
+            .map(|v| v.is_some())
+            .c(d!())
+    }
+}
+
+impl<D: MerkleDB> MerkleDB for ChainedDB<D> {
+    fn root_hash(&self) -> Vec<u8> {
+        self.top.root_hash()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(v) = self.top.get(key).c(d!())? {
+            return Ok(Some(v));
+        }
+        if self.deleted_in_top(key).c(d!())? {
+            return Ok(None);
+        }
+        for source in self.fallbacks.iter() {
+            if let Some(v) = source.get(key).c(d!())? {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(v) = self.top.get_aux(key).c(d!())? {
+            return Ok(Some(v));
+        }
+        for source in self.fallbacks.iter() {
+            if let Some(v) = source.get_aux(key).c(d!())? {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Writes to the top db, deletes are also recorded so fallbacks can't resurrect the key
+    fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
+        let mut batch = KVBatch::with_capacity(kvs.len());
+        for (k, v) in kvs {
+            match v {
+                Some(v) => {
+                    if self.deleted_in_top(&k).c(d!())? {
+                        self.deleted.push((Self::tombstone(&k), None));
+                    }
+                    batch.push((k, Some(v)));
+                }
+                None => {
+                    self.deleted.push((Self::tombstone(&k), Some(vec![])));
+                    // the top db may never have had the key
+                    if self.top.get(&k).c(d!())?.is_some() {
+                        batch.push((k, None));
+                    }
+                }
+            }
+        }
+        self.top.put_batch(batch)
+    }
+
+    fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.top.iter(lower, upper, order)
+    }
+
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbIter<'_> {
+        self.top.iter_with_opts(lower, upper, order, opts)
+    }
+
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        self.top.iter_bounds(lower, upper, order)
+    }
+
+    fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        self.top.scan_apply(lower, upper, order, f)
+    }
+
+    fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.top.iter_aux(lower, upper, order)
+    }
+
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.top.try_iter(lower, upper, order)
+    }
+
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.top.try_iter_aux(lower, upper, order)
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        self.top.db_all_iterator(order)
+    }
+
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        self.top.db_all_iterator_aux(order)
+    }
+
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.top.try_db_all_iterator(order)
+    }
+
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.top.try_db_all_iterator_aux(order)
+    }
+
+    fn has_proofs(&self) -> bool {
+        self.top.has_proofs()
+    }
+
+    fn prove_keys(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        self.top.prove_keys(keys)
+    }
+
+    fn prove_range(&self, lower: &[u8], upper: &[u8]) -> Result<Vec<u8>> {
+        self.top.prove_range(lower, upper)
+    }
+
+    fn verify_entry(&self, kv_pair: &(Box<[u8]>, Box<[u8]>)) -> Result<()> {
+        self.top.verify_entry(kv_pair)
+    }
+
+    fn compact_range(&self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        self.top.compact_range(lower, upper)
+    }
+
+    fn commit(&mut self, mut kvs: KVBatch, flush: bool) -> Result<()> {
+        let mut deleted = std::mem::take(&mut self.deleted);
+        deleted.append(&mut kvs);
+        self.top.commit(deleted, flush)
+    }
+
+    fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.top.snapshot(path)
+    }
+
+    fn snapshot_with_opts<P: AsRef<Path>>(&self, path: P, opts: &SnapshotOptions) -> Result<()> {
+        self.top.snapshot_with_opts(path, opts)
+    }
+
+    fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue {
+        self.top.decode_kv(kv_pair)
+    }
+
+    fn clean_aux(&mut self) -> Result<()> {
+        self.top.clean_aux()
+    }
+}
@@ -0,0 +1,198 @@
+/// Cross-version state compatibility checks
+///
+/// Before a release, expectations of a db are recorded with the released version, i.e. its
+/// height, root hash, a digest of all entries and the values of some sample keys. The new
+/// version opens the same db and `check` certifies that every read and the root hash still
+/// match, so an upgrade can't silently change the state.
+///
+use crate::{
+    db::{IterOrder, MerkleDB},
+    hex,
+    layout::{StorageHeader, CRATE_VERSION},
+    state::{chain_state::KEYS_UPPER, ChainState},
+};
+use ruc::*;
+use serde_json::{json, Value};
+use std::{fs, path::Path};
+
+/// Recorded state of a db
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Expectations {
+    /// crate version which recorded the expectations
+    pub crate_version: String,
+    pub height: u64,
+    pub root_hash: Vec<u8>,
+    /// number of entries in the primary section
+    pub entries: u64,
+    /// digest of all entries in the primary section
+    pub digest: u64,
+    /// expected values of sample keys
+    pub samples: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+/// A difference found by `check`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Incompatibility {
+    Height { expected: u64, found: u64 },
+    RootHash,
+    Entries { expected: u64, found: u64 },
+    Digest,
+    Value(Vec<u8>),
+}
+
+/// Result of a compatibility check
+#[derive(Clone, Debug, Default)]
+pub struct CompatReport {
+    /// version which recorded the expectations
+    pub recorded_by: String,
+    /// versions found in the header of the data directory, if known
+    pub header: Option<StorageHeader>,
+    pub checked_samples: u64,
+    pub incompatibilities: Vec<Incompatibility>,
+}
+
+impl CompatReport {
+    pub fn is_compatible(&self) -> bool {
+        self.incompatibilities.is_empty()
+    }
+}
+
+/// Records the expectations of `cs`, including the current values of `sample_keys`
+pub fn record<D: MerkleDB>(cs: &ChainState<D>, sample_keys: &[Vec<u8>]) -> Result<Expectations> {
+    let (entries, digest) = digest_entries(cs);
+    let samples = sample_keys
+        .iter()
+        .map(|k| Ok((k.clone(), cs.get(k).c(d!())?)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Expectations {
+        crate_version: CRATE_VERSION.to_string(),
+        height: cs.height().c(d!())?,
+        root_hash: cs.root_hash(),
+        entries,
+        digest,
+        samples,
+    })
+}
+
+/// Checks `cs` against expectations recorded before
+pub fn check<D: MerkleDB>(cs: &ChainState<D>, expected: &Expectations) -> Result<CompatReport> {
+    let mut report = CompatReport {
+        recorded_by: expected.crate_version.clone(),
+        ..Default::default()
+    };
+    let mut fail = |i| report.incompatibilities.push(i);
+
+    // the root of another height differs anyway, only the height is reported
+    let height = cs.height().c(d!())?;
+    if height != expected.height {
+        fail(Incompatibility::Height {
+            expected: expected.height,
+            found: height,
+        });
+    } else if cs.root_hash() != expected.root_hash {
+        fail(Incompatibility::RootHash);
+    }
+    let (entries, digest) = digest_entries(cs);
+    if entries != expected.entries {
+        fail(Incompatibility::Entries {
+            expected: expected.entries,
+            found: entries,
+        });
+    }
+    if digest != expected.digest {
+        fail(Incompatibility::Digest);
+    }
+    for (key, value) in expected.samples.iter() {
+        if cs.get(key).c(d!())? != *value {
+            fail(Incompatibility::Value(key.clone()));
+        }
+    }
+    report.checked_samples = expected.samples.len() as u64;
+    Ok(report)
+}
+
+/// Picks up to `n` keys spread evenly over the primary section
+pub fn pick_samples<D: MerkleDB>(cs: &ChainState<D>, n: usize) -> Vec<Vec<u8>> {
+    let (entries, _) = digest_entries(cs);
+    let stride = (entries / n.max(1) as u64).max(1);
+    let mut samples = vec![];
+    let mut index = 0u64;
+    cs.iterate(&[], &KEYS_UPPER, IterOrder::Asc, &mut |(k, _)| {
+        if index.is_multiple_of(stride) {
+            samples.push(k);
+        }
+        index += 1;
+        samples.len() >= n
+    });
+    samples
+}
+
+// FNV-1a over length prefixed keys and values, stable across versions and platforms
+fn digest_entries<D: MerkleDB>(cs: &ChainState<D>) -> (u64, u64) {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for b in (bytes.len() as u64).to_be_bytes().iter().chain(bytes) {
+            hash = (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01B3);
+        }
+    };
+    let mut entries = 0u64;
+    cs.iterate(&[], &KEYS_UPPER, IterOrder::Asc, &mut |(k, v)| {
+        feed(&k);
+        feed(&v);
+        entries += 1;
+        false
+    });
+    (entries, hash)
+}
+
+impl Expectations {
+    pub fn to_json(&self) -> Value {
+        let samples = self
+            .samples
+            .iter()
+            .map(|(k, v)| json!([hex::encode(k), v.as_deref().map(hex::encode)]))
+            .collect::<Vec<_>>();
+        json!({
+            "crate_version": self.crate_version,
+            "height": self.height,
+            "root_hash": hex::encode(&self.root_hash),
+            "entries": self.entries,
+            "digest": format!("{:016x}", self.digest),
+            "samples": samples,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let str_field = |name: &str| value[name].as_str().c(d!(format!("missing {}", name)));
+        let u64_field = |name: &str| value[name].as_u64().c(d!(format!("missing {}", name)));
+
+        let mut samples = vec![];
+        for sample in value["samples"].as_array().c(d!("missing samples"))? {
+            let key = sample[0].as_str().c(d!("invalid sample"))?;
+            let value = match sample[1].as_str() {
+                Some(v) => Some(hex::decode(v).c(d!())?),
+                None => None,
+            };
+            samples.push((hex::decode(key).c(d!())?, value));
+        }
+
+        Ok(Expectations {
+            crate_version: str_field("crate_version")?.to_string(),
+            height: u64_field("height")?,
+            root_hash: hex::decode(str_field("root_hash")?).c(d!())?,
+            entries: u64_field("entries")?,
+            digest: u64::from_str_radix(str_field("digest")?, 16).c(d!("invalid digest"))?,
+            samples,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let bytes = serde_json::to_vec_pretty(&self.to_json()).c(d!())?;
+        fs::write(path, bytes).c(d!())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let value: Value = serde_json::from_slice(&fs::read(path).c(d!())?).c(d!())?;
+        Self::from_json(&value)
+    }
+}
@@ -0,0 +1,435 @@
+/// Typed storage configuration
+///
+/// `StorageConfig` gathers the options of the whole storage layer in one serde struct, so a
+/// binary deserializes the storage section of its config file and opens its state with
+/// `State::open_with_config`. Missing fields take their defaults, unknown ones are rejected.
+///
+/// This crate doesn't depend on the backends, they are opened through `OpenWithConfig`.
+///
+use crate::{
+    db::{
+        CfOpts, Compression, IterOpts, MerkleDB, PrefixBloomOpts, PrefixExtractor, ValueLogOpts,
+        WalOpts,
+    },
+    state::{chain_state::DEFAULT_COMPACTION_TOMBSTONES, ChainStateOpts, PruningPolicy, ScrubOpts},
+    tracked::IterLimits,
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// default timeout of a state sync chunk request
+const DEFAULT_CHUNK_TIMEOUT_SECS: u64 = 30;
+/// default number of chunks requested from a peer at once
+const DEFAULT_MAX_IN_FLIGHT: usize = 4;
+
+/// Backends which can be opened from a `StorageConfig`
+pub trait OpenWithConfig: MerkleDB + Sized {
+    fn open_with_config(cfg: &StorageConfig) -> Result<Self>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    FinDB,
+    RocksDB,
+    Memory,
+    Sled,
+    Lmdb,
+}
+
+impl Backend {
+    /// Whether keys and values are checked against the limits of a merk tree
+    pub fn is_merkle(&self) -> bool {
+        matches!(self, Backend::FinDB)
+    }
+}
+
+/// Options of the whole storage layer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    /// Data directory of the backend
+    pub path: PathBuf,
+    /// Name of the chain state, the default one if `None`
+    pub name: Option<String>,
+    pub rocksdb: RocksDbConfig,
+    /// Large values of FinDB and RocksDB in blob files, ignored by other backends
+    pub value_log: Option<ValueLogConfig>,
+    pub pruning: PruningConfig,
+    pub snapshot: SnapshotConfig,
+    pub cache: CacheConfig,
+    pub metrics: MetricsConfig,
+    pub scrub: ScrubConfig,
+    pub sync: SyncConfig,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: Backend::default(),
+            path: PathBuf::from("data"),
+            name: None,
+            rocksdb: RocksDbConfig::default(),
+            value_log: None,
+            pruning: PruningConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            cache: CacheConfig::default(),
+            metrics: MetricsConfig::default(),
+            scrub: ScrubConfig::default(),
+            sync: SyncConfig::default(),
+        }
+    }
+}
+
+/// RocksDB tuning, ignored by other backends
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RocksDbConfig {
+    /// Write-ahead log limits, zero leaves a limit to RocksDB
+    pub wal_size_limit_mb: u64,
+    pub wal_ttl_seconds: u64,
+    pub max_total_wal_size: u64,
+    pub prefix_bloom: Option<PrefixBloomConfig>,
+    /// Read-ahead size of large scans, the built-in one if `None`
+    pub scan_readahead_size: Option<usize>,
+    /// Capacity in bytes of the block cache of the db, RocksDB's default if `None`
+    pub block_cache_size: Option<usize>,
+    /// Name of a block cache of `block_cache_size` bytes shared with the other dbs of the
+    /// process configured with the same name, a cache of this db alone if `None`
+    pub shared_block_cache: Option<String>,
+    /// Tuning of the column family of the state, the aux data can't be tuned apart: fmerk
+    /// fixes the options of its aux column family and `RocksDB` keeps the aux data with the
+    /// state
+    pub data: Option<CfConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionConfig {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+/// Column family tuning, see `CfOpts`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CfConfig {
+    pub compression: Option<CompressionConfig>,
+    pub write_buffer_size: Option<usize>,
+    pub bloom_bits_per_key: Option<u32>,
+}
+
+impl CfConfig {
+    fn cf_opts(&self) -> CfOpts {
+        let mut opts = CfOpts::default();
+        if let Some(compression) = self.compression {
+            opts = opts.with_compression(match compression {
+                CompressionConfig::None => Compression::None,
+                CompressionConfig::Snappy => Compression::Snappy,
+                CompressionConfig::Lz4 => Compression::Lz4,
+                CompressionConfig::Zstd => Compression::Zstd,
+            });
+        }
+        if let Some(size) = self.write_buffer_size {
+            opts = opts.with_write_buffer_size(size);
+        }
+        if let Some(bits) = self.bloom_bits_per_key {
+            opts = opts.with_bloom_bits_per_key(bits);
+        }
+        opts
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtractorConfig {
+    Fixed(usize),
+    Module,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrefixBloomConfig {
+    pub extractor: ExtractorConfig,
+    pub bits_per_key: Option<u32>,
+    pub memtable_bloom_ratio: Option<f64>,
+}
+
+/// Value log of large values, see `ValueLogOpts`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValueLogConfig {
+    pub min_value_size: usize,
+    pub segment_size_mb: u64,
+    pub gc_live_ratio: f64,
+}
+
+impl Default for ValueLogConfig {
+    fn default() -> Self {
+        let opts = ValueLogOpts::default();
+        ValueLogConfig {
+            min_value_size: opts.min_value_size,
+            segment_size_mb: opts.segment_size >> 20,
+            gc_live_ratio: opts.gc_live_ratio,
+        }
+    }
+}
+
+/// Version history and tombstone compaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PruningConfig {
+    /// Number of heights whose versioned values are kept, zero keeps no history
+    pub ver_window: u64,
+    pub cleanup_aux: bool,
+    /// Deleted keys under a module prefix triggering its compaction, zero disables it
+    pub compaction_tombstones: u64,
+    /// Versions kept once out of the window, its window replaces `ver_window` if set
+    pub policy: Option<PruningPolicy>,
+}
+
+impl Default for PruningConfig {
+    fn default() -> Self {
+        PruningConfig {
+            ver_window: 0,
+            cleanup_aux: false,
+            compaction_tombstones: DEFAULT_COMPACTION_TOMBSTONES,
+            policy: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Heights between two snapshots of the version history, zero disables them
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Proofs of the current root kept in memory by backends with a proof memo, the backend
+    /// default if `None`
+    pub proof_memo_size: Option<usize>,
+}
+
+/// Iterator tracking, see `TrackedDB`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub track_iterators: bool,
+    pub max_open_iterators: Option<usize>,
+    pub max_iterator_age_secs: Option<u64>,
+}
+
+/// Background scrubbing, see `Scrubber`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrubConfig {
+    pub enabled: bool,
+    pub bytes_per_sec: u64,
+    pub step_bytes: u64,
+    pub quarantine: bool,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        let opts = ScrubOpts::default();
+        ScrubConfig {
+            enabled: false,
+            bytes_per_sec: opts.bytes_per_sec,
+            step_bytes: opts.step_bytes,
+            quarantine: opts.quarantine,
+        }
+    }
+}
+
+/// State sync downloads, see `ChunkTracker`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    pub chunk_timeout_secs: u64,
+    pub max_in_flight: usize,
+    /// Where the download progress is persisted, not persisted if `None`
+    pub progress_path: Option<PathBuf>,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            chunk_timeout_secs: DEFAULT_CHUNK_TIMEOUT_SECS,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            progress_path: None,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s).c(d!("invalid storage config"))
+    }
+
+    #[cfg(feature = "config_toml")]
+    pub fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str(s).c(d!("invalid storage config"))
+    }
+
+    /// Loads a config file, TOML if its extension is `toml`, JSON otherwise
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path).c(d!())?;
+        match path.extension().and_then(|e| e.to_str()) {
+            #[cfg(feature = "config_toml")]
+            Some("toml") => Self::from_toml(&s),
+            #[cfg(not(feature = "config_toml"))]
+            Some("toml") => Err(eg!("TOML configs need the `config_toml` feature")),
+            _ => Self::from_json(&s),
+        }
+    }
+
+    /// Checks the options `ChainState` would otherwise panic on
+    pub fn validate(&self) -> Result<()> {
+        let (ver_window, interval) = (self.ver_window(), self.snapshot.interval);
+        if interval == 1 {
+            return Err(eg!("snapshot interval cannot be One"));
+        }
+        if ver_window < interval {
+            return Err(eg!("version window is smaller than snapshot interval"));
+        }
+        if interval != 0 && ver_window % interval != 0 {
+            return Err(eg!("ver_window should align at snapshot interval"));
+        }
+        if ver_window == 0 && self.pruning.cleanup_aux {
+            return Err(eg!("cleanup_aux needs a version window"));
+        }
+        if let Some(PruningPolicy::KeepEvery { interval: 0, .. }) = self.pruning.policy {
+            return Err(eg!("pruning interval cannot be zero"));
+        }
+        if self.pruning.policy == Some(PruningPolicy::KeepAll) && ver_window == 0 {
+            return Err(eg!("keeping all versions needs a version window"));
+        }
+        if self.sync.max_in_flight == 0 {
+            return Err(eg!("max_in_flight must not be zero"));
+        }
+        if self.rocksdb.block_cache_size == Some(0)
+            || self
+                .rocksdb
+                .data
+                .as_ref()
+                .is_some_and(|cf| cf.write_buffer_size == Some(0))
+        {
+            return Err(eg!("block cache and write buffer sizes must not be zero"));
+        }
+        if self.rocksdb.shared_block_cache.is_some() && self.rocksdb.block_cache_size.is_none() {
+            return Err(eg!("a shared block cache needs a block_cache_size"));
+        }
+        if let Some(vlog) = self.value_log.as_ref() {
+            if vlog.min_value_size == 0 || vlog.segment_size_mb == 0 {
+                return Err(eg!("value log sizes must not be zero"));
+            }
+            if !(0.0..=1.0).contains(&vlog.gc_live_ratio) {
+                return Err(eg!("gc_live_ratio must be between 0 and 1"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn chain_state_opts(&self) -> ChainStateOpts {
+        ChainStateOpts {
+            name: self.name.clone(),
+            ver_window: self.pruning.ver_window,
+            interval: self.snapshot.interval,
+            cleanup_aux: self.pruning.cleanup_aux,
+        }
+    }
+
+    fn ver_window(&self) -> u64 {
+        let ver_window = self.pruning.ver_window;
+        self.pruning
+            .policy
+            .map_or(ver_window, |p| p.ver_window(ver_window))
+    }
+
+    pub fn wal_opts(&self) -> WalOpts {
+        WalOpts::default()
+            .with_size_limit_mb(self.rocksdb.wal_size_limit_mb)
+            .with_ttl_seconds(self.rocksdb.wal_ttl_seconds)
+            .with_max_total_size(self.rocksdb.max_total_wal_size)
+    }
+
+    pub fn prefix_bloom_opts(&self) -> Option<PrefixBloomOpts> {
+        self.rocksdb.prefix_bloom.as_ref().map(|bloom| {
+            let extractor = match bloom.extractor {
+                ExtractorConfig::Fixed(n) => PrefixExtractor::Fixed(n),
+                ExtractorConfig::Module => PrefixExtractor::Module,
+            };
+            let mut opts = PrefixBloomOpts::new(extractor);
+            if let Some(bits) = bloom.bits_per_key {
+                opts = opts.with_bits_per_key(bits);
+            }
+            if let Some(ratio) = bloom.memtable_bloom_ratio {
+                opts = opts.with_memtable_bloom_ratio(ratio);
+            }
+            opts
+        })
+    }
+
+    /// Value log options of FinDB, `None` if values are kept in the tree
+    pub fn value_log_opts(&self) -> Option<ValueLogOpts> {
+        self.value_log.as_ref().map(|vlog| {
+            ValueLogOpts::default()
+                .with_min_value_size(vlog.min_value_size)
+                .with_segment_size(vlog.segment_size_mb.saturating_mul(1 << 20))
+                .with_gc_live_ratio(vlog.gc_live_ratio)
+        })
+    }
+
+    /// Tuning of the column family of the state
+    pub fn data_cf_opts(&self) -> CfOpts {
+        self.rocksdb
+            .data
+            .as_ref()
+            .map(CfConfig::cf_opts)
+            .unwrap_or_default()
+    }
+
+    /// Read hints of large scans
+    pub fn scan_opts(&self) -> IterOpts {
+        match self.rocksdb.scan_readahead_size {
+            Some(size) => IterOpts::scan().with_readahead_size(size),
+            None => IterOpts::scan(),
+        }
+    }
+
+    /// Limits of a `TrackedDB`, `None` if iterators are not tracked
+    pub fn iter_limits(&self) -> Option<IterLimits> {
+        self.metrics.track_iterators.then(|| IterLimits {
+            max_open: self.metrics.max_open_iterators,
+            max_age: self.metrics.max_iterator_age_secs.map(Duration::from_secs),
+            debug_assert: false,
+        })
+    }
+
+    /// Options of the background scrubber, `None` if it's disabled
+    pub fn scrub_opts(&self) -> Option<ScrubOpts> {
+        self.scrub.enabled.then_some(ScrubOpts {
+            bytes_per_sec: self.scrub.bytes_per_sec,
+            step_bytes: self.scrub.step_bytes,
+            quarantine: self.scrub.quarantine,
+        })
+    }
+
+    pub fn chunk_timeout(&self) -> Duration {
+        Duration::from_secs(self.sync.chunk_timeout_secs)
+    }
+}
@@ -1,5 +1,12 @@
-use ruc::Result;
+use crate::{
+    merge::MergeOp,
+    proof::{MultiProof, Proof},
+    snapshot::{SnapshotCodec, SnapshotOptions},
+    state::chain_state::KEYS_UPPER,
+};
+use ruc::{eg, Result};
 use std::iter::Iterator;
+use std::ops::Bound;
 use std::path::Path;
 
 /// types
@@ -7,12 +14,345 @@ pub type StoreKey = Vec<u8>;
 pub type KValue = (StoreKey, Vec<u8>);
 pub type KVEntry = (StoreKey, Option<Vec<u8>>);
 pub type KVBatch = Vec<KVEntry>;
+pub type KVEntryRef<'a> = (&'a [u8], Option<&'a [u8]>);
 pub type DbIter<'a> = Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a>;
+/// iterator yielding the read errors of the backend, see `MerkleDB::try_iter`
+pub type DbTryIter<'a> = Box<dyn Iterator<Item = Result<(Box<[u8]>, Box<[u8]>)>> + 'a>;
 
-#[derive(Debug)]
+/// Order of the keys of an iteration, more orders may be added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum IterOrder {
     Asc,
     Desc,
+    /// Keys in any order, for full scans whose result doesn't depend on it, like integrity
+    /// checks. Every backend serves it as `Asc` for now, it's reserved for backends where
+    /// ordered iteration is costly, callers must not rely on either order.
+    Unordered,
+}
+
+/// Tells `scan_apply` whether to go on with the next entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanControl {
+    Continue,
+    Stop,
+}
+
+/// Progress of `copy_from`, reported after every committed batch
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CopyProgress {
+    /// entries copied so far
+    pub entries: u64,
+    /// bytes of the keys and values copied so far
+    pub bytes: u64,
+    /// batches committed so far
+    pub batches: u64,
+    /// last key copied, a resumed copy starts right after it
+    pub last_key: Vec<u8>,
+}
+
+/// Start and end of the `[start, end)` range holding the keys within `lower` and `upper`,
+/// `None` for no end
+///
+/// An excluded start, or an included end, is followed by a zero byte, the smallest key after
+/// it, so the range holds exactly the same keys.
+#[inline]
+pub fn key_range(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> (Vec<u8>, Option<Vec<u8>>) {
+    let successor = |key: &[u8]| {
+        let mut next = key.to_vec();
+        next.push(0x0);
+        next
+    };
+    let start = match lower {
+        Bound::Included(key) => key.to_vec(),
+        Bound::Excluded(key) => successor(key),
+        Bound::Unbounded => vec![],
+    };
+    let end = match upper {
+        Bound::Included(key) => Some(successor(key)),
+        Bound::Excluded(key) => Some(key.to_vec()),
+        Bound::Unbounded => None,
+    };
+    (start, end)
+}
+
+/// Smallest key after all keys starting with `prefix`, `None` if there's none, i.e. the
+/// prefix is empty or only 0xFF bytes
+///
+/// Trailing 0xFF bytes are dropped and the last byte left is incremented, `b"a\xff"` ends
+/// before `b"b"`.
+#[inline]
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|byte| *byte != 0xff)?;
+    let mut end = prefix.get(..=last)?.to_vec();
+    if let Some(byte) = end.last_mut() {
+        *byte = byte.saturating_add(1);
+    }
+    Some(end)
+}
+
+/// 2MB read-ahead for sequential scans
+const SCAN_READAHEAD_SIZE: usize = 0x0020_0000;
+
+/// Read hints for range iteration
+///
+/// Backends are free to ignore them, the result of an iteration never depends on these options.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct IterOpts {
+    /// Read-ahead size in bytes, `None` leaves it to the backend
+    pub readahead_size: Option<usize>,
+    /// Whether blocks read by this iteration are put into the block cache
+    pub fill_cache: bool,
+    /// Keep the blocks of returned keys/values pinned while the iterator lives
+    pub pin_data: bool,
+}
+
+impl Default for IterOpts {
+    #[inline]
+    fn default() -> Self {
+        IterOpts {
+            readahead_size: None,
+            fill_cache: true,
+            pin_data: false,
+        }
+    }
+}
+
+impl IterOpts {
+    /// Options for large sequential scans which must not evict the hot block cache
+    #[inline]
+    pub fn scan() -> Self {
+        IterOpts {
+            readahead_size: Some(SCAN_READAHEAD_SIZE),
+            fill_cache: false,
+            pin_data: false,
+        }
+    }
+
+    #[inline]
+    pub fn with_readahead_size(mut self, size: usize) -> Self {
+        self.readahead_size = Some(size);
+        self
+    }
+
+    #[inline]
+    pub fn with_fill_cache(mut self, fill: bool) -> Self {
+        self.fill_cache = fill;
+        self
+    }
+
+    #[inline]
+    pub fn with_pin_data(mut self, pin: bool) -> Self {
+        self.pin_data = pin;
+        self
+    }
+}
+
+/// Write-ahead log size and retention limits
+///
+/// A zero value leaves the limit to the backend.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct WalOpts {
+    /// Archived log files are deleted once their total size exceeds this many MB
+    pub size_limit_mb: u64,
+    /// Archived log files older than this many seconds are deleted
+    pub ttl_seconds: u64,
+    /// Memtables are flushed once the live log files exceed this many bytes
+    pub max_total_size: u64,
+}
+
+impl WalOpts {
+    #[inline]
+    pub fn with_size_limit_mb(mut self, size: u64) -> Self {
+        self.size_limit_mb = size;
+        self
+    }
+
+    #[inline]
+    pub fn with_ttl_seconds(mut self, ttl: u64) -> Self {
+        self.ttl_seconds = ttl;
+        self
+    }
+
+    #[inline]
+    pub fn with_max_total_size(mut self, size: u64) -> Self {
+        self.max_total_size = size;
+        self
+    }
+}
+
+/// default bloom filter size, ~1% false positives
+const DEFAULT_BLOOM_BITS_PER_KEY: u32 = 0x0A;
+
+/// How a key is mapped to the prefix used by prefix bloom filters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PrefixExtractor {
+    /// The first `n` bytes of a key
+    Fixed(usize),
+    /// A key up to and including its first `_`, i.e. the module prefix of a `Prefix` key
+    Module,
+}
+
+impl PrefixExtractor {
+    /// Returns the prefix of `key`, `None` if the key is out of the extractor's domain
+    #[inline]
+    pub fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            PrefixExtractor::Fixed(n) => key.get(..n),
+            PrefixExtractor::Module => key
+                .iter()
+                .position(|&b| b == b'_')
+                .and_then(|pos| key.get(..=pos)),
+        }
+    }
+
+    /// Returns the prefix shared by every key in `[lower, upper)`, if both bounds have the same one
+    #[inline]
+    pub fn range_prefix<'a>(&self, lower: &'a [u8], upper: &[u8]) -> Option<&'a [u8]> {
+        let prefix = self.prefix(lower)?;
+        (self.prefix(upper) == Some(prefix)).then_some(prefix)
+    }
+}
+
+/// Prefix bloom filter options
+///
+/// Range scans within a single prefix skip every file whose filter doesn't contain the prefix.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PrefixBloomOpts {
+    pub extractor: PrefixExtractor,
+    /// Bits per key of the table bloom filters
+    pub bits_per_key: u32,
+    /// Share of the memtable size used by its prefix bloom filter, zero disables it
+    pub memtable_bloom_ratio: f64,
+}
+
+impl PrefixBloomOpts {
+    #[inline]
+    pub fn new(extractor: PrefixExtractor) -> Self {
+        PrefixBloomOpts {
+            extractor,
+            bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            memtable_bloom_ratio: 0.1,
+        }
+    }
+
+    #[inline]
+    pub fn with_bits_per_key(mut self, bits: u32) -> Self {
+        self.bits_per_key = bits;
+        self
+    }
+
+    #[inline]
+    pub fn with_memtable_bloom_ratio(mut self, ratio: f64) -> Self {
+        self.memtable_bloom_ratio = ratio;
+        self
+    }
+}
+
+/// Compression of the blocks of a column family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+/// Tuning of one column family of a RocksDB based backend
+///
+/// A `None` option is left to the backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CfOpts {
+    pub compression: Option<Compression>,
+    /// Size of a single memtable in bytes
+    pub write_buffer_size: Option<usize>,
+    /// Bits per key of whole key bloom filters, zero disables them
+    pub bloom_bits_per_key: Option<u32>,
+}
+
+impl CfOpts {
+    #[inline]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    #[inline]
+    pub fn with_write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = Some(size);
+        self
+    }
+
+    #[inline]
+    pub fn with_bloom_bits_per_key(mut self, bits: u32) -> Self {
+        self.bloom_bits_per_key = Some(bits);
+        self
+    }
+
+    /// Whether every option is left to the backend
+    #[inline]
+    pub fn is_default(&self) -> bool {
+        *self == CfOpts::default()
+    }
+}
+
+/// default size of the smallest value kept out of the tree
+const DEFAULT_MIN_VALUE_SIZE: usize = 0x1000;
+/// default size a value log segment is sealed at, 64 MB
+const DEFAULT_SEGMENT_SIZE: u64 = 0x400_0000;
+
+/// Value log options of backends which keep large values apart from their keys
+///
+/// Values are separated below the tree, the root hash doesn't depend on where a value is
+/// stored and nodes of a chain may use different options.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ValueLogOpts {
+    /// Values of at least this many bytes are appended to the log
+    pub min_value_size: usize,
+    /// Segments are sealed once they reach this many bytes
+    pub segment_size: u64,
+    /// Sealed segments whose live share is at most this ratio are rewritten by garbage
+    /// collection
+    pub gc_live_ratio: f64,
+}
+
+impl Default for ValueLogOpts {
+    #[inline]
+    fn default() -> Self {
+        ValueLogOpts {
+            min_value_size: DEFAULT_MIN_VALUE_SIZE,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            gc_live_ratio: 0.5,
+        }
+    }
+}
+
+impl ValueLogOpts {
+    #[inline]
+    pub fn with_min_value_size(mut self, size: usize) -> Self {
+        self.min_value_size = size;
+        self
+    }
+
+    #[inline]
+    pub fn with_segment_size(mut self, size: u64) -> Self {
+        self.segment_size = size;
+        self
+    }
+
+    #[inline]
+    pub fn with_gc_live_ratio(mut self, ratio: f64) -> Self {
+        self.gc_live_ratio = ratio;
+        self
+    }
 }
 
 /// Merkleized KV store interface
@@ -23,18 +363,324 @@ pub trait MerkleDB {
 
     fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
+    /// Reads the value of `key` into `buf` and returns its length, `None` if the key doesn't exist
+    ///
+    /// `buf` is cleared first and its allocation reused. Backends able to copy a value out of
+    /// their own buffers override this to skip the allocation of every read.
+    #[inline]
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        buf.clear();
+        Ok(self.get(key)?.map(|value| {
+            buf.extend_from_slice(&value);
+            value.len()
+        }))
+    }
+
+    /// Reads the values of `keys` into `bufs` with `get_into`, one buffer per key
+    ///
+    /// `bufs` is resized to the number of keys, the buffers of a previous call are reused.
+    #[inline]
+    fn get_many_into(&self, keys: &[&[u8]], bufs: &mut Vec<Vec<u8>>) -> Result<Vec<Option<usize>>> {
+        bufs.resize_with(keys.len(), Vec::new);
+        keys.iter()
+            .zip(bufs.iter_mut())
+            .map(|(key, buf)| self.get_into(key, buf))
+            .collect()
+    }
+
     fn put_batch(&mut self, kvs: KVBatch) -> Result<()>;
 
+    /// Puts a batch of borrowed KVs
+    ///
+    /// Backends able to write from borrowed buffers override this to skip the copy of every entry.
+    #[inline]
+    fn put_batch_ref(&mut self, kvs: &[KVEntryRef<'_>]) -> Result<()> {
+        let batch = kvs
+            .iter()
+            .map(|&(k, v)| (k.to_vec(), v.map(<[u8]>::to_vec)))
+            .collect();
+        self.put_batch(batch)
+    }
+
     fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_>;
 
+    /// Range iterator with read hints, falls back to `iter` if hints are not supported
+    #[inline]
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        _opts: &IterOpts,
+    ) -> DbIter<'_> {
+        self.iter(lower, upper, order)
+    }
+
+    /// Range iterator over any bounds, e.g. an inclusive upper bound or no upper bound
+    ///
+    /// An unbounded end reaches the last key, whatever its length, which no exclusive upper
+    /// bound of `iter` does. Backends translate the bounded ones with `key_range`.
+    fn iter_bounds(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>, order: IterOrder)
+        -> DbIter<'_>;
+
+    /// Iterator over the keys starting with `prefix`
+    #[inline]
+    fn iter_prefix(&self, prefix: &[u8], order: IterOrder) -> DbIter<'_> {
+        let end = prefix_successor(prefix);
+        let upper = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        self.iter_bounds(Bound::Included(prefix), upper, order)
+    }
+
     fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_>;
 
-    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_>;
+    /// Range iterator yielding read errors instead of ending early
+    ///
+    /// `iter` stops at a read error of the backend like at the end of the range, so callers
+    /// can't tell a truncated range from a complete one. Backends whose iterators can't fail,
+    /// or can't report their errors, only yield pairs.
+    #[inline]
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        Box::new(self.iter(lower, upper, order).map(Ok))
+    }
+
+    /// Range iterator over aux yielding read errors, see `try_iter`
+    #[inline]
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        Box::new(self.iter_aux(lower, upper, order).map(Ok))
+    }
+
+    /// Iterator over the aux keys starting with `prefix`
+    ///
+    /// A prefix without successor, e.g. 0xFF bytes only, stops before the keys continuing with
+    /// 32 more 0xFF bytes, `iter_aux` takes no unbounded end.
+    #[inline]
+    fn iter_aux_prefix(&self, prefix: &[u8], order: IterOrder) -> DbIter<'_> {
+        let upper = prefix_successor(prefix).unwrap_or_else(|| {
+            let mut upper = prefix.to_vec();
+            upper.extend_from_slice(&KEYS_UPPER);
+            upper
+        });
+        self.iter_aux(prefix, &upper, order)
+    }
+
+    /// Iterator over all keys
+    ///
+    /// Backends without native support iterate `iter_bounds` without bounds.
+    #[inline]
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        self.iter_bounds(Bound::Unbounded, Bound::Unbounded, order)
+    }
+
+    /// Iterator over all aux keys
+    ///
+    /// Backends without native support iterate `iter_aux_prefix` with an empty prefix, which
+    /// stops before the keys starting with 32 0xFF bytes.
+    #[inline]
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        self.iter_aux_prefix(&[], order)
+    }
+
+    /// Iterator over all keys yielding read errors, see `try_iter`
+    ///
+    /// Backends without native support iterate `db_all_iterator`, which can't report errors.
+    #[inline]
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        Box::new(self.db_all_iterator(order).map(Ok))
+    }
+
+    /// Iterator over all aux keys yielding read errors, see `try_iter`
+    ///
+    /// Backends without native support iterate `db_all_iterator_aux`.
+    #[inline]
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        Box::new(self.db_all_iterator_aux(order).map(Ok))
+    }
+
+    /// Whether the backend proves keys against its root hash, see `prove_keys`
+    #[inline]
+    fn has_proofs(&self) -> bool {
+        false
+    }
+
+    /// Generates a proof of `keys` (present or absent) against the current `root_hash`
+    ///
+    /// The encoding of the proof is backend specific.
+    #[inline]
+    fn prove_keys(&self, _keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        Err(eg!("proofs are not supported by this backend"))
+    }
+
+    /// Generates a proof of all the pairs with a key in `[lower, upper)` against the current
+    /// `root_hash`, see `ProofVerifier::verify_range`
+    #[inline]
+    fn prove_range(&self, _lower: &[u8], _upper: &[u8]) -> Result<Vec<u8>> {
+        Err(eg!("range proofs are not supported by this backend"))
+    }
+
+    /// Proves `key` (present or absent) against the current `root_hash`
+    #[inline]
+    fn prove(&self, key: &[u8]) -> Result<Proof> {
+        let proof = self.prove_keys(&[key.to_vec()])?;
+        Ok(Proof {
+            root_hash: self.root_hash(),
+            key: key.to_vec(),
+            value: self.get(key)?,
+            proof,
+        })
+    }
+
+    /// Proves the presence or absence of every key in `keys` with a single proof against the
+    /// current `root_hash`
+    #[inline]
+    fn prove_batch(&self, keys: &[Vec<u8>]) -> Result<MultiProof> {
+        let mut keys = keys.to_vec();
+        keys.sort();
+        keys.dedup();
+        let proof = self.prove_keys(&keys)?;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = self.get(&key)?;
+            values.push((key, value));
+        }
+        Ok(MultiProof {
+            root_hash: self.root_hash(),
+            values,
+            proof,
+        })
+    }
+
+    /// Verifies the integrity of a raw KV pair yielded by `iter` or `db_all_iterator`
+    ///
+    /// Backends storing hashes or checksums with their entries re-check them here.
+    #[inline]
+    fn verify_entry(&self, _kv_pair: &(Box<[u8]>, Box<[u8]>)) -> Result<()> {
+        Ok(())
+    }
+
+    /// Compacts the keys in `[lower, upper)`, dropping the tombstones of deleted keys
+    ///
+    /// Backends without tombstones keep this no-op.
+    #[inline]
+    fn compact_range(&self, _lower: &[u8], _upper: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Deletes every key in `[lower, upper)`
+    ///
+    /// Backends with native range deletes override it instead of deleting key by key.
+    #[inline]
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        let keys = self
+            .iter(lower, upper, IterOrder::Asc)
+            .map(|kv_pair| (self.decode_kv(kv_pair).0, None))
+            .collect::<KVBatch>();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.put_batch(keys)
+    }
+
+    /// Merges `patch` into the value of `key` with `op`
+    ///
+    /// Backends with native merge operators override it to write the patch without reading
+    /// the value, the default reads, merges and writes it.
+    #[inline]
+    fn merge(&mut self, key: &[u8], patch: &[u8], op: MergeOp) -> Result<()> {
+        let current = self.get(key)?;
+        let value = op.apply(current.as_deref(), patch)?;
+        self.put_batch(vec![(key.to_vec(), Some(value))])
+    }
+
+    /// Walks `[lower, upper)` calling `f` with borrowed keys and values until it returns
+    /// `ScanControl::Stop`, returns the number of entries visited
+    ///
+    /// Backends override it to hand out their own buffers instead of allocating every pair.
+    #[inline]
+    fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        let mut visited: u64 = 0x0;
+        for kv_pair in self.iter(lower, upper, order) {
+            visited = visited.saturating_add(0x1);
+            let (k, v) = self.decode_kv(kv_pair);
+            if f(&k, &v) == ScanControl::Stop {
+                break;
+            }
+        }
+        visited
+    }
+
+    /// Copies the entries of `src` in `[lower, upper)` into this db, `batch_size` entries per
+    /// commit, e.g. to migrate a store to another backend or to clone a module's prefix
+    ///
+    /// `progress` is called after every committed batch, the last batch is flushed. Entries of
+    /// this db which are not in `src` are kept. A read error of `src` stops the copy, the
+    /// batches committed before it are reported.
+    #[inline]
+    fn copy_from<S: MerkleDB>(
+        &mut self,
+        src: &S,
+        lower: &[u8],
+        upper: &[u8],
+        batch_size: usize,
+        progress: &mut dyn FnMut(&CopyProgress),
+    ) -> Result<CopyProgress>
+    where
+        Self: Sized,
+    {
+        if batch_size == 0x0 {
+            return Err(eg!("batch size must be positive"));
+        }
+        let mut copied = CopyProgress::default();
+        let mut batch = KVBatch::with_capacity(batch_size);
+        let mut entries = src.try_iter(lower, upper, IterOrder::Asc).peekable();
+        while let Some(kv_pair) = entries.next() {
+            let (k, v) = src.decode_kv(kv_pair?);
+            let size = u64::try_from(k.len().saturating_add(v.len())).unwrap_or(u64::MAX);
+            copied.entries = copied.entries.saturating_add(0x1);
+            copied.bytes = copied.bytes.saturating_add(size);
+            batch.push((k, Some(v)));
+            let last = entries.peek().is_none();
+            if batch.len() < batch_size && !last {
+                continue;
+            }
+            copied.last_key = batch
+                .last()
+                .map(|entry| entry.0.clone())
+                .unwrap_or_default();
+            self.put_batch(std::mem::replace(
+                &mut batch,
+                KVBatch::with_capacity(batch_size),
+            ))?;
+            self.commit(vec![], last)?;
+            copied.batches = copied.batches.saturating_add(0x1);
+            progress(&copied);
+        }
+        Ok(copied)
+    }
 
     fn commit(&mut self, kvs: KVBatch, flush: bool) -> Result<()>;
 
     fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()>;
 
+    /// Takes a snapshot like `snapshot`, compressed with the codec of `opts`
+    ///
+    /// Backends writing their snapshots as a single file override it, the others only take
+    /// uncompressed snapshots.
+    #[inline]
+    fn snapshot_with_opts<P: AsRef<Path>>(&self, path: P, opts: &SnapshotOptions) -> Result<()> {
+        if opts.codec != SnapshotCodec::None {
+            return Err(eg!(
+                "compressed snapshots are not supported by this backend"
+            ));
+        }
+        self.snapshot(path)
+    }
+
     fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue;
 
     #[inline]
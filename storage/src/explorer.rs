@@ -0,0 +1,427 @@
+/// Read-only HTTP explorer of the chain state
+///
+/// `Explorer` serves a small embedded page for support investigations: the prefixes registered
+/// in the key schema of the chain state, the keys under a prefix page by page and the value of
+/// a key as hex or pretty printed, as JSON or text when it parses as such. Nothing is written.
+///
+/// The data endpoints under `/api/` are in the admin scope, a request must carry the admin token
+/// as `Authorization: Bearer <token>`. The page itself holds no data, it asks for the token and
+/// sends it with its own requests. Connections are served one at a time on the listener thread,
+/// it's a tool for operators and not a public endpoint, bind it to a loopback address.
+///
+/// Keys, prefixes and cursors are passed as hex in the query string:
+/// - `GET /api/prefixes`
+/// - `GET /api/keys?prefix=<hex>&after=<hex>&limit=<n>`, `next` of a page is the `after` of the
+///   next one
+/// - `GET /api/value?key=<hex>&height=<n>`, at the current height without `height`
+///
+use crate::{
+    db::{prefix_successor, IterOrder, MerkleDB},
+    hex,
+    state::{chain_state::KEYS_UPPER, ChainState},
+};
+use parking_lot::RwLock;
+use ruc::*;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// max size of the request line and headers
+pub const MAX_REQUEST_SIZE: usize = 0x2000;
+/// number of keys in a page when the request has no limit
+pub const DEFAULT_PAGE_LIMIT: usize = 50;
+/// max number of keys in a page
+pub const MAX_PAGE_LIMIT: usize = 500;
+/// a page ends early once its keys and values reach 4MB
+pub const MAX_PAGE_BYTES: usize = 0x0040_0000;
+
+// a stalled client can't hold up the other connections for longer than this
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PAGE: &str = include_str!("explorer.html");
+
+/// Value as shown by the pretty view
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+enum Pretty {
+    Json(serde_json::Value),
+    Text(String),
+    Binary,
+}
+
+impl Pretty {
+    fn of(value: &[u8]) -> Self {
+        if let Ok(json) = serde_json::from_slice::<serde_json::Value>(value) {
+            if json.is_object() || json.is_array() {
+                return Pretty::Json(json);
+            }
+        }
+        match str::from_utf8(value) {
+            Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+                Pretty::Text(text.to_owned())
+            }
+            _ => Pretty::Binary,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PrefixView {
+    prefix: String,
+    text: String,
+    rules: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct EntryView {
+    key: String,
+    text: String,
+    size: usize,
+    value: String,
+    pretty: Pretty,
+}
+
+impl EntryView {
+    fn new(key: &[u8], value: &[u8]) -> Self {
+        EntryView {
+            key: hex::encode(key),
+            text: String::from_utf8_lossy(key).into_owned(),
+            size: value.len(),
+            value: hex::encode(value),
+            pretty: Pretty::of(value),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct KeysView {
+    height: u64,
+    entries: Vec<EntryView>,
+    /// cursor of the next page, `None` after the last one
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ValueView {
+    height: u64,
+    key: String,
+    entry: Option<EntryView>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: BTreeMap<String, String>,
+    token: Option<String>,
+}
+
+impl Request {
+    fn hex_param(&self, name: &str) -> Result<Vec<u8>> {
+        match self.query.get(name) {
+            Some(value) => hex::decode(value).c(d!(format!("invalid {}", name))),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn num_param(&self, name: &str) -> Result<Option<u64>> {
+        self.query
+            .get(name)
+            .map(|value| value.parse::<u64>().c(d!(format!("invalid {}", name))))
+            .transpose()
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = vec![];
+    let mut chunk = [0; 0x400];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(eg!("request too large"));
+        }
+        let n = stream.read(&mut chunk).c(d!())?;
+        if n == 0 {
+            return Err(eg!("truncated request"));
+        }
+        buf.extend_from_slice(chunk.get(..n).c(d!())?);
+    }
+    let head = str::from_utf8(&buf).c(d!("invalid request"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_owned();
+    let target = request_line.next().c(d!("invalid request"))?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+    let token = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Bearer "))
+        .map(|token| token.trim().to_owned());
+    Ok(Request {
+        method,
+        path: path.to_owned(),
+        query,
+        token,
+    })
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).c(d!())?;
+    stream.write_all(body).c(d!())?;
+    stream.flush().c(d!())
+}
+
+// compares every byte so the time taken doesn't tell how much of the token matched
+fn token_matches(expected: &str, given: Option<&str>) -> bool {
+    let given = given.unwrap_or_default().as_bytes();
+    let expected = expected.as_bytes();
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn prefixes<D: MerkleDB>(cs: &ChainState<D>) -> Vec<PrefixView> {
+    let schema = match cs.key_schema() {
+        Some(schema) => schema,
+        None => return vec![],
+    };
+    schema
+        .prefixes()
+        .map(|(prefix, rules)| PrefixView {
+            prefix: hex::encode(prefix),
+            text: String::from_utf8_lossy(prefix).into_owned(),
+            rules: rules.iter().map(|rule| format!("{:?}", rule)).collect(),
+        })
+        .collect()
+}
+
+fn keys<D: MerkleDB>(cs: &ChainState<D>, req: &Request) -> Result<KeysView> {
+    let prefix = req.hex_param("prefix").c(d!())?;
+    let after = req.hex_param("after").c(d!())?;
+    let limit = req
+        .num_param("limit")
+        .c(d!())?
+        .map_or(DEFAULT_PAGE_LIMIT, |n| {
+            usize::try_from(n).unwrap_or(usize::MAX)
+        })
+        .clamp(1, MAX_PAGE_LIMIT);
+
+    // the page starts right after the cursor, `after` followed by a zero byte
+    let mut lower = prefix.clone();
+    if !after.is_empty() {
+        let mut cursor = after;
+        cursor.push(0);
+        lower = lower.max(cursor);
+    }
+    let upper = prefix_successor(&prefix).unwrap_or_else(|| KEYS_UPPER.to_vec());
+
+    let height = cs.height().c(d!())?;
+    let mut entries = vec![];
+    let mut bytes = 0;
+    let mut more = false;
+    if lower >= upper {
+        return Ok(KeysView {
+            height,
+            entries,
+            next: None,
+        });
+    }
+    cs.iterate(&lower, &upper, IterOrder::Asc, &mut |(k, v)| {
+        if entries.len() >= limit || bytes >= MAX_PAGE_BYTES {
+            more = true;
+            return true;
+        }
+        bytes += k.len() + v.len();
+        entries.push(EntryView::new(&k, &v));
+        false
+    });
+    let next = entries
+        .last()
+        .filter(|_| more)
+        .map(|entry| entry.key.clone());
+    Ok(KeysView {
+        height,
+        entries,
+        next,
+    })
+}
+
+fn value<D: MerkleDB>(cs: &ChainState<D>, req: &Request) -> Result<ValueView> {
+    let key = req.hex_param("key").c(d!())?;
+    if key.is_empty() {
+        return Err(eg!("missing key"));
+    }
+    let (height, value) = match req.num_param("height").c(d!())? {
+        Some(height) => (height, cs.get_ver(&key, height).c(d!())?),
+        None => (cs.height().c(d!())?, cs.get(&key).c(d!())?),
+    };
+    Ok(ValueView {
+        height,
+        key: hex::encode(&key),
+        entry: value.map(|v| EntryView::new(&key, &v)),
+    })
+}
+
+fn handle<D: MerkleDB>(cs: &ChainState<D>, req: &Request) -> Result<Vec<u8>> {
+    match req.path.as_str() {
+        "/api/prefixes" => serde_json::to_vec(&prefixes(cs)).c(d!()),
+        "/api/keys" => serde_json::to_vec(&keys(cs, req).c(d!())?).c(d!()),
+        "/api/value" => serde_json::to_vec(&value(cs, req).c(d!())?).c(d!()),
+        _ => Err(eg!("not found")),
+    }
+}
+
+// status, content type and body of the response to `req`
+fn route<D: MerkleDB>(
+    cs: &RwLock<ChainState<D>>,
+    token: &str,
+    req: &Request,
+) -> (&'static str, &'static str, Vec<u8>) {
+    if req.method != "GET" {
+        return (
+            "405 Method Not Allowed",
+            "text/plain",
+            b"read only".to_vec(),
+        );
+    }
+    if req.path == "/" {
+        return (
+            "200 OK",
+            "text/html; charset=utf-8",
+            PAGE.as_bytes().to_vec(),
+        );
+    }
+    if !req.path.starts_with("/api/") {
+        return ("404 Not Found", "text/plain", b"not found".to_vec());
+    }
+    if !token_matches(token, req.token.as_deref()) {
+        return (
+            "401 Unauthorized",
+            "text/plain",
+            b"admin token required".to_vec(),
+        );
+    }
+    // the lock is only held for a single request so commits are not held up
+    let resp = handle(&cs.read(), req);
+    match resp {
+        Ok(body) => ("200 OK", "application/json", body),
+        Err(e) => ("400 Bad Request", "text/plain", e.to_string().into_bytes()),
+    }
+}
+
+fn serve<D: MerkleDB>(
+    cs: &RwLock<ChainState<D>>,
+    token: &str,
+    mut stream: TcpStream,
+) -> Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT)).c(d!())?;
+    stream.set_write_timeout(Some(IO_TIMEOUT)).c(d!())?;
+    let (status, content_type, body) = match read_request(&mut stream) {
+        Ok(req) => route(cs, token, &req),
+        Err(e) => ("400 Bad Request", "text/plain", e.to_string().into_bytes()),
+    };
+    write_response(&mut stream, status, content_type, &body)
+}
+
+/// Serves the explorer of a chain state, the explorer stops when dropped
+pub struct Explorer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Explorer {
+    /// Listens on `addr`, the data endpoints require `admin_token` which must not be empty
+    pub fn spawn<D, A>(cs: Arc<RwLock<ChainState<D>>>, addr: A, admin_token: &str) -> Result<Self>
+    where
+        D: MerkleDB + Send + Sync + 'static,
+        A: ToSocketAddrs,
+    {
+        if admin_token.is_empty() {
+            return Err(eg!("explorer needs an admin token"));
+        }
+        let listener = TcpListener::bind(addr).c(d!("failed to bind explorer"))?;
+        let addr = listener.local_addr().c(d!())?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let stop = stop.clone();
+            let token = admin_token.to_owned();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let _ = serve(&cs, &token, stream);
+                    }
+                }
+            })
+        };
+
+        Ok(Explorer {
+            addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    /// Address the explorer listens on, with the port picked by the system if it was 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting connections
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            // wakes the listener up so it sees the stop flag
+            let mut addr = self.addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect(addr);
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Explorer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
@@ -0,0 +1,85 @@
+/// Typed heights and versions
+///
+/// Heights and data format versions are both plain `u64`s in the storage apis, nothing stops
+/// a caller from passing one for the other. `Height` and `Version` wrap them, the apis taking
+/// a height, e.g. `ChainState::commit`, `State::state_at` or `ChainState::root_hash_at`,
+/// accept a `Height` or a `u64`, so typing is opted into by callers and a `Version` passed
+/// for a height doesn't compile. Heights returned by the apis are plain `u64`s, converted
+/// with `Height::from`.
+///
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Height of a block, i.e. of a commit of a chain state
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Height(pub u64);
+
+impl Height {
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    /// Height of the next commit
+    pub fn next(self) -> Self {
+        Height(self.0.saturating_add(1))
+    }
+
+    /// Height of the previous commit, `None` at height zero
+    pub fn prev(self) -> Option<Self> {
+        self.0.checked_sub(1).map(Height)
+    }
+}
+
+/// Version of a stored data format, e.g. the aux version of a chain state
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Version(pub u64);
+
+impl Version {
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+// Only `u64` converts, more integer types would make literals ambiguous where a height is
+// taken as `impl Into<Height>`.
+impl From<u64> for Height {
+    fn from(height: u64) -> Self {
+        Height(height)
+    }
+}
+
+impl From<Height> for u64 {
+    fn from(height: Height) -> Self {
+        height.0
+    }
+}
+
+impl From<u64> for Version {
+    fn from(version: u64) -> Self {
+        Version(version)
+    }
+}
+
+impl From<Version> for u64 {
+    fn from(version: Version) -> Self {
+        version.0
+    }
+}
+
+impl fmt::Display for Height {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
@@ -0,0 +1,17 @@
+/// Hex encoding of hashes and keys in metadata files
+///
+use ruc::*;
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode(s: &str) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(eg!("invalid hex length"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2).unwrap_or_default(), 16).c(d!("invalid hex")))
+        .collect()
+}
@@ -0,0 +1,217 @@
+/// Generic key/value traits
+///
+/// Components which only need to get, set and scan bytes, e.g. the caches and indexes of
+/// other Findora services, are written against `KvGet`, `KvSet` and `KvScan` instead of a
+/// concrete store, modeled after the sled api. `KvAdapter` implements them over any
+/// `MerkleDB`, so these components share the db of the node instead of opening a storage
+/// layer of their own.
+///
+/// An adapter only sees the auxiliary data of the db under its own namespace, the primary
+/// data stays the state of the chain and its root hash is never touched. Writes are staged in
+/// the adapter and handed to the owner of the db with `KvAdapter::into_aux`, to be persisted
+/// by its next commit, e.g. `ChainState::commit_with_aux`.
+///
+use crate::db::{prefix_successor, IterOrder, KVBatch, KValue, MerkleDB};
+use ruc::*;
+use std::{
+    cmp::Ordering,
+    collections::{btree_map, BTreeMap},
+    iter::Peekable,
+    ops::Bound,
+};
+
+/// Iterator over owned KV pairs in key order
+pub type KvIter<'a> = Box<dyn Iterator<Item = KValue> + 'a>;
+
+/// Aux prefix of the namespaces of the adapters
+pub const KV_NAMESPACE_PREFIX: &[u8; 3] = b"Kv_";
+
+const NAMESPACE_SPLIT: u8 = b'_';
+
+/// Point reads
+pub trait KvGet {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        self.get(key).map(|value| value.is_some())
+    }
+}
+
+/// Writes
+pub trait KvSet {
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn remove(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Applies a batch of sets and removes in order
+    fn apply_batch(&mut self, batch: KVBatch) -> Result<()> {
+        for (key, value) in batch {
+            match value {
+                Some(value) => self.insert(&key, &value).c(d!())?,
+                None => self.remove(&key).c(d!())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ordered scans
+pub trait KvScan {
+    /// Pairs within `lower` and `upper` in ascending key order
+    fn range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> KvIter<'_>;
+
+    /// Pairs whose key starts with `prefix` in ascending key order
+    fn scan_prefix(&self, prefix: &[u8]) -> KvIter<'_> {
+        let end = prefix_successor(prefix);
+        let upper = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        self.range(Bound::Included(prefix), upper)
+    }
+}
+
+/// Stores implementing all the generic traits
+pub trait KvStore: KvGet + KvSet + KvScan {}
+
+impl<T: KvGet + KvSet + KvScan> KvStore for T {}
+
+/// Generic key/value store over a namespace of the aux data of a db
+pub struct KvAdapter<'a, D: MerkleDB> {
+    db: &'a D,
+    // `KV_NAMESPACE_PREFIX`, the namespace and a separator
+    prefix: Vec<u8>,
+    // writes not committed yet, `None` removes a key
+    staged: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a, D: MerkleDB> KvAdapter<'a, D> {
+    /// Adapter over the keys of `namespace`, which is not empty and has no `_`
+    pub fn new(db: &'a D, namespace: &[u8]) -> Result<Self> {
+        if namespace.is_empty() || namespace.contains(&NAMESPACE_SPLIT) {
+            return Err(eg!("invalid kv namespace"));
+        }
+        let mut prefix = KV_NAMESPACE_PREFIX.to_vec();
+        prefix.extend_from_slice(namespace);
+        prefix.push(NAMESPACE_SPLIT);
+        Ok(KvAdapter {
+            db,
+            prefix,
+            staged: BTreeMap::new(),
+        })
+    }
+
+    /// Aux key of `key`
+    fn aux_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut aux_key = self.prefix.clone();
+        aux_key.extend_from_slice(key);
+        aux_key
+    }
+
+    /// Whether writes are staged
+    pub fn is_dirty(&self) -> bool {
+        !self.staged.is_empty()
+    }
+
+    /// Staged writes as an aux batch, to be committed by the owner of the db
+    pub fn into_aux(self) -> KVBatch {
+        let prefix = self.prefix;
+        self.staged
+            .into_iter()
+            .map(|(key, value)| {
+                let mut aux_key = prefix.clone();
+                aux_key.extend_from_slice(&key);
+                (aux_key, value)
+            })
+            .collect()
+    }
+}
+
+impl<D: MerkleDB> KvGet for KvAdapter<'_, D> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.staged.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.db.get_aux(&self.aux_key(key)).c(d!()),
+        }
+    }
+}
+
+impl<D: MerkleDB> KvSet for KvAdapter<'_, D> {
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.staged.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.staged.insert(key.to_vec(), None);
+        Ok(())
+    }
+}
+
+impl<D: MerkleDB> KvScan for KvAdapter<'_, D> {
+    fn range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> KvIter<'_> {
+        // the bounds of `iter_aux` are an inclusive lower and an exclusive upper key
+        let aux_lower = match lower {
+            Bound::Included(key) => self.aux_key(key),
+            Bound::Excluded(key) => {
+                let mut aux_key = self.aux_key(key);
+                aux_key.push(0);
+                aux_key
+            }
+            Bound::Unbounded => self.prefix.clone(),
+        };
+        let aux_upper = match upper {
+            Bound::Included(key) => {
+                let mut aux_key = self.aux_key(key);
+                aux_key.push(0);
+                aux_key
+            }
+            Bound::Excluded(key) => self.aux_key(key),
+            // the prefix ends with the separator, it has a successor
+            Bound::Unbounded => prefix_successor(&self.prefix).unwrap_or_default(),
+        };
+        if aux_lower >= aux_upper {
+            return Box::new(std::iter::empty());
+        }
+        let len = self.prefix.len();
+        let committed = self
+            .db
+            .iter_aux(&aux_lower, &aux_upper, IterOrder::Asc)
+            .map(move |(k, v)| (k[len..].to_vec(), v.to_vec()));
+        Box::new(Merged {
+            committed: (Box::new(committed) as KvIter<'_>).peekable(),
+            staged: self.staged.range::<[u8], _>((lower, upper)).peekable(),
+        })
+    }
+}
+
+// Committed pairs overlaid with the staged writes, in key order
+struct Merged<'a> {
+    committed: Peekable<KvIter<'a>>,
+    staged: Peekable<btree_map::Range<'a, Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl Iterator for Merged<'_> {
+    type Item = KValue;
+
+    fn next(&mut self) -> Option<KValue> {
+        loop {
+            let order = match (self.committed.peek(), self.staged.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((key, _)), Some((staged_key, _))) => {
+                    key.as_slice().cmp(staged_key.as_slice())
+                }
+            };
+            match order {
+                Ordering::Less => return self.committed.next(),
+                // overwritten or removed by the staged write
+                Ordering::Equal => {
+                    self.committed.next();
+                }
+                Ordering::Greater => {}
+            }
+            if let Some((key, Some(value))) = self.staged.next() {
+                return Some((key.clone(), value.clone()));
+            }
+        }
+    }
+}
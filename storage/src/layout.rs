@@ -0,0 +1,368 @@
+/// Versioned on-disk layout of a data directory
+///
+/// ```text
+/// <root>/LAYOUT     layout version
+/// <root>/main/      backend db, holding the state and the aux data
+/// <root>/backups/   backups and snapshots
+/// <root>/meta/      metadata of tools and operators
+/// <root>/meta/HEADER crate versions which created and last wrote the directory
+/// ```
+///
+/// Where the aux data is kept in `main/` is backend specific: the aux column family of fmerk
+/// for FinDB, the state column family for RocksDB, a tree of sled, a named db of LMDB and a
+/// map of MemoryDB.
+///
+/// Opening a directory migrates it step by step to the current version, e.g. a flat directory
+/// written before layouts were versioned is moved into `main/`.
+///
+use crate::{
+    snapshot::{describe_container, describe_header},
+    state::chain_state::describe_aux,
+};
+use ruc::*;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// current layout version
+pub const LAYOUT_VERSION: u32 = 1;
+
+const LAYOUT_FILE: &str = "LAYOUT";
+const MAIN_DIR: &str = "main";
+const BACKUP_DIR: &str = "backups";
+const META_DIR: &str = "meta";
+// `main` while the flat layout is being moved into it
+const MIGRATING_DIR: &str = "main.migrating";
+const HEADER_FILE: &str = "HEADER";
+
+/// version of this crate, recorded in the metadata header
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Metadata header of a data directory
+///
+/// Versions are `None` for directories written before the header existed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StorageHeader {
+    /// crate version which created the directory
+    pub created_by: Option<String>,
+    /// crate version which opened the directory last
+    pub written_by: Option<String>,
+}
+
+/// Machine readable description of the on-disk formats, e.g. for external tools and audits
+///
+/// It's generated from the constants the formats are written with. Formats private to a
+/// backend, e.g. the value log of FinDB, are not included.
+pub fn describe_layout() -> Value {
+    let entry = |path: String, description: &str| json!({"path": path, "description": description});
+    json!({
+        "layout_version": LAYOUT_VERSION,
+        "crate_version": CRATE_VERSION,
+        "files": [
+            entry(LAYOUT_FILE.to_string(), "layout version, decimal"),
+            entry(format!("{}/", MAIN_DIR), "backend db, holding the state and the aux data"),
+            entry(format!("{}/", BACKUP_DIR), "backups and snapshots"),
+            entry(
+                format!("{}/{}", META_DIR, HEADER_FILE),
+                "JSON {layout_version, created_by, written_by}",
+            ),
+        ],
+        "aux_location": {
+            "findb": "column family \"aux\" of fmerk",
+            "rocksdb": "column family \"state\", with the state",
+            "sled": "tree \"aux\"",
+            "lmdb": "named db \"aux\"",
+            "memdb": "map persisted with the state",
+        },
+        "snapshot_header": describe_header(),
+        "snapshot_container": describe_container(),
+        "chain_state": describe_aux(),
+    })
+}
+
+/// A data directory in the current layout
+#[derive(Clone, Debug)]
+pub struct DataLayout {
+    root: PathBuf,
+    header: StorageHeader,
+}
+
+impl DataLayout {
+    /// Opens the data directory at `root`, creating or migrating it as needed
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let mut layout = DataLayout {
+            root: root.as_ref().to_path_buf(),
+            header: StorageHeader::default(),
+        };
+        fs::create_dir_all(&layout.root).c(d!("failed to create data directory"))?;
+
+        let mut version = layout.read_version().c(d!())?;
+        if version > LAYOUT_VERSION {
+            return Err(eg!(format!(
+                "data directory layout {} is newer than the supported layout {}",
+                version, LAYOUT_VERSION
+            )));
+        }
+        while version < LAYOUT_VERSION {
+            layout.migrate_from(version).c(d!())?;
+            version = version.saturating_add(1);
+            layout.write_version(version).c(d!())?;
+        }
+
+        for dir in [MAIN_DIR, BACKUP_DIR, META_DIR] {
+            fs::create_dir_all(layout.root.join(dir)).c(d!())?;
+        }
+        if !layout.root.join(LAYOUT_FILE).exists() {
+            layout.write_version(LAYOUT_VERSION).c(d!())?;
+        }
+
+        layout.header = layout.read_header().c(d!())?;
+        layout.write_header().c(d!())?;
+        Ok(layout)
+    }
+
+    /// Opens the data directory at `root` without writing to it, e.g. for a follower of the
+    /// process owning it
+    ///
+    /// The directory must exist in the current layout, it is never created nor migrated.
+    pub fn open_read_only<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let mut layout = DataLayout {
+            root: root.as_ref().to_path_buf(),
+            header: StorageHeader::default(),
+        };
+        let version = fs::read_to_string(layout.root.join(LAYOUT_FILE))
+            .c(d!("no data directory in a versioned layout"))?
+            .trim()
+            .parse::<u32>()
+            .c(d!("invalid layout file"))?;
+        if version != LAYOUT_VERSION {
+            return Err(eg!(format!(
+                "data directory layout {} isn't the supported layout {}",
+                version, LAYOUT_VERSION
+            )));
+        }
+        layout.header = layout.read_header().c(d!())?;
+        Ok(layout)
+    }
+
+    /// Layout version and metadata header of the data directory at `root`, read without
+    /// creating nor migrating it
+    pub(crate) fn inspect<P: AsRef<Path>>(root: P) -> Result<(u32, StorageHeader)> {
+        let layout = DataLayout {
+            root: root.as_ref().to_path_buf(),
+            header: StorageHeader::default(),
+        };
+        if !layout.root.is_dir() {
+            return Ok((LAYOUT_VERSION, StorageHeader::default()));
+        }
+        let version = layout.read_version().c(d!())?;
+        Ok((version, layout.read_header().c(d!())?))
+    }
+
+    /// Directory of the backend db of a data directory in layout `version`
+    pub(crate) fn db_dir<P: AsRef<Path>>(root: P, version: u32) -> PathBuf {
+        match version {
+            0 => root.as_ref().to_path_buf(),
+            _ => root.as_ref().join(MAIN_DIR),
+        }
+    }
+
+    /// Returns the metadata header as found on open, i.e. before this version wrote it
+    pub fn header(&self) -> &StorageHeader {
+        &self.header
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Directory of the backend db
+    pub fn main_dir(&self) -> PathBuf {
+        self.root.join(MAIN_DIR)
+    }
+
+    pub fn backup_dir(&self) -> PathBuf {
+        self.root.join(BACKUP_DIR)
+    }
+
+    pub fn meta_dir(&self) -> PathBuf {
+        self.root.join(META_DIR)
+    }
+
+    // version 0 is the flat layout, an empty directory is created in the current layout
+    fn read_version(&self) -> Result<u32> {
+        match fs::read_to_string(self.root.join(LAYOUT_FILE)) {
+            Ok(v) => v.trim().parse::<u32>().c(d!("invalid layout file")),
+            Err(_) => {
+                let empty = fs::read_dir(&self.root).c(d!())?.next().is_none();
+                Ok(if empty { LAYOUT_VERSION } else { 0 })
+            }
+        }
+    }
+
+    fn read_header(&self) -> Result<StorageHeader> {
+        let path = self.meta_dir().join(HEADER_FILE);
+        if !path.exists() {
+            return Ok(StorageHeader::default());
+        }
+        let header: Value =
+            serde_json::from_slice(&fs::read(path).c(d!())?).c(d!("invalid header"))?;
+        let version = |name: &str| header[name].as_str().map(str::to_string);
+        Ok(StorageHeader {
+            created_by: version("created_by"),
+            written_by: version("written_by"),
+        })
+    }
+
+    fn write_header(&self) -> Result<()> {
+        let header = json!({
+            "layout_version": LAYOUT_VERSION,
+            "created_by": self.header.created_by.as_deref().unwrap_or(CRATE_VERSION),
+            "written_by": CRATE_VERSION,
+        });
+        let path = self.meta_dir().join(HEADER_FILE);
+        let tmp = self.meta_dir().join(format!("{}.tmp", HEADER_FILE));
+        fs::write(&tmp, serde_json::to_vec_pretty(&header).c(d!())?).c(d!())?;
+        fs::rename(&tmp, path).c(d!())
+    }
+
+    // the layout file is written last, an interrupted migration is repeated on next open
+    fn write_version(&self, version: u32) -> Result<()> {
+        let tmp = self.root.join(format!("{}.tmp", LAYOUT_FILE));
+        fs::write(&tmp, format!("{}\n", version)).c(d!())?;
+        fs::rename(&tmp, self.root.join(LAYOUT_FILE)).c(d!())
+    }
+
+    fn migrate_from(&self, version: u32) -> Result<()> {
+        match version {
+            0 => self.migrate_flat(),
+            _ => Err(eg!(format!("no migration from layout {}", version))),
+        }
+    }
+
+    // moves every entry of a flat directory into `main/`
+    fn migrate_flat(&self) -> Result<()> {
+        let migrating = self.root.join(MIGRATING_DIR);
+        if self.main_dir().is_dir() && !migrating.exists() {
+            // interrupted after the move, only the layout file is missing
+            return Ok(());
+        }
+        fs::create_dir_all(&migrating).c(d!())?;
+        for entry in fs::read_dir(&self.root).c(d!())? {
+            let entry = entry.c(d!())?;
+            let name = entry.file_name();
+            if name == MIGRATING_DIR {
+                continue;
+            }
+            fs::rename(entry.path(), migrating.join(&name))
+                .c(d!(format!("failed to move {:?}", name)))?;
+        }
+        fs::rename(&migrating, self.main_dir()).c(d!())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        describe_layout, DataLayout, StorageHeader, CRATE_VERSION, LAYOUT_FILE, LAYOUT_VERSION,
+    };
+    use std::{env::temp_dir, fs, time::SystemTime};
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        temp_dir().join(format!("{}_{}", name, time))
+    }
+
+    #[test]
+    fn layout_create() {
+        let root = test_dir("layout_create");
+        let layout = DataLayout::open(&root).unwrap();
+        assert!(layout.main_dir().is_dir());
+        assert!(layout.backup_dir().is_dir());
+        assert!(layout.meta_dir().is_dir());
+        let version = fs::read_to_string(root.join(LAYOUT_FILE)).unwrap();
+        assert_eq!(version.trim(), LAYOUT_VERSION.to_string());
+
+        assert_eq!(layout.header(), &StorageHeader::default());
+
+        // reopening keeps the layout and finds the header written before
+        let layout = DataLayout::open(&root).unwrap();
+        assert_eq!(layout.header().created_by.as_deref(), Some(CRATE_VERSION));
+        assert_eq!(layout.header().written_by.as_deref(), Some(CRATE_VERSION));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn layout_migrate_flat() {
+        let root = test_dir("layout_migrate_flat");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("CURRENT"), b"MANIFEST-000001").unwrap();
+        fs::write(root.join("sub").join("000001.sst"), b"sst").unwrap();
+
+        let layout = DataLayout::open(&root).unwrap();
+        let main = layout.main_dir();
+        assert_eq!(fs::read(main.join("CURRENT")).unwrap(), b"MANIFEST-000001");
+        assert_eq!(
+            fs::read(main.join("sub").join("000001.sst")).unwrap(),
+            b"sst"
+        );
+        assert!(!root.join("CURRENT").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn layout_newer_version() {
+        let root = test_dir("layout_newer_version");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join(LAYOUT_FILE), format!("{}\n", LAYOUT_VERSION + 1)).unwrap();
+        assert!(DataLayout::open(&root).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn layout_describe() {
+        let layout = describe_layout();
+        assert_eq!(layout["layout_version"], LAYOUT_VERSION);
+        assert_eq!(layout["files"][0]["path"], LAYOUT_FILE);
+        let chain = &layout["chain_state"];
+        assert_eq!(chain["keys"][0]["key"], "Height");
+        assert_eq!(chain["sections"][0]["key"], "VER_{height}_{key}");
+        assert_eq!(chain["tombstone"], "ce");
+        assert_eq!(layout["snapshot_header"]["path"], "{snapshot}.meta");
+        assert_eq!(layout["snapshot_container"]["magic"], "STORSNAP");
+        assert_eq!(layout["aux_location"]["sled"], "tree \"aux\"");
+    }
+
+    #[test]
+    fn layout_describe_aux_keys() {
+        // every aux key constant of the chain state is described, as a key or a prefix
+        let chain = &describe_layout()["chain_state"];
+        let described = chain["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .chain(chain["sections"].as_array().unwrap())
+            .map(|entry| entry["key"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        let constants = include_str!("state/chain_state.rs")
+            .lines()
+            .map(|line| line.trim_start_matches("pub(crate) "))
+            .filter(|line| line.starts_with("const ") && line.contains(": &[u8; "))
+            .map(|line| line.split('"').nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert!(constants.contains(&"Height") && constants.contains(&"Mmr"));
+        for key in constants {
+            let prefix = format!("{}_", key);
+            assert!(
+                described.iter().any(|d| d == key || d.starts_with(&prefix)),
+                "aux key {} isn't described",
+                key
+            );
+        }
+    }
+}
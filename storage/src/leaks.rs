@@ -0,0 +1,242 @@
+/// Leak tracking of temporary dbs
+///
+/// Test dbs, e.g. `TempFinDB` and `MemoryDB`, delete their files when dropped, but a db which
+/// is never dropped or fails to delete its files leaves them behind, and CI machines collect
+/// gigabytes of orphaned directories. With tracking enabled every temporary db is recorded
+/// when it's created and forgotten once its files are deleted. At process exit the dbs still
+/// recorded are reported with their paths, sizes on disk and the threads, i.e. the tests,
+/// which created them.
+///
+/// Tracking is enabled by the `STORAGE_TEMP_DB_TRACKING` environment variable, `report` prints
+/// the leftover dbs, `strict` also aborts the process so the run fails, or by
+/// `set_temp_db_tracking`.
+///
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    thread,
+};
+
+/// environment variable enabling the tracking, see `TempDbTracking::parse`
+pub const TEMP_DB_TRACKING_ENV: &str = "STORAGE_TEMP_DB_TRACKING";
+
+/// What's done about the temporary dbs left at process exit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TempDbTracking {
+    /// nothing is recorded
+    #[default]
+    Off,
+    /// leftover dbs are printed
+    Report,
+    /// leftover dbs are printed and the process is aborted
+    Strict,
+}
+
+impl TempDbTracking {
+    /// `report` or `strict`, tracking is off for anything else
+    pub fn parse(value: &str) -> Self {
+        match value.trim() {
+            "report" => TempDbTracking::Report,
+            "strict" => TempDbTracking::Strict,
+            _ => TempDbTracking::Off,
+        }
+    }
+}
+
+/// A temporary db created and not deleted so far
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TempDbRecord {
+    /// type of the db, e.g. `TempFinDB`
+    pub kind: &'static str,
+    pub path: PathBuf,
+    /// name of the thread which created it, the test name under the test harness
+    pub created_by: Option<String>,
+    /// size on disk in bytes, when reported
+    pub size: u64,
+}
+
+/// Recorded temporary db, released once its files are deleted
+///
+/// A ticket dropped without being released, e.g. after a failed deletion, keeps its db
+/// recorded.
+#[must_use]
+#[derive(Debug)]
+pub struct TempDbTicket(u64);
+
+impl TempDbTicket {
+    /// Forgets the db, its files are deleted
+    pub fn release(self) {
+        let _ = tracker().dbs.remove(&self.0);
+    }
+}
+
+struct Tracker {
+    // decided on first use, from the environment unless set before
+    mode: Option<TempDbTracking>,
+    exit_hook: bool,
+    next_id: u64,
+    dbs: BTreeMap<u64, TempDbRecord>,
+}
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    mode: None,
+    exit_hook: false,
+    next_id: 0,
+    dbs: BTreeMap::new(),
+});
+
+fn tracker() -> MutexGuard<'static, Tracker> {
+    // a test panicking while recording doesn't stop the others
+    TRACKER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sets the tracking mode, overriding `STORAGE_TEMP_DB_TRACKING`
+///
+/// Dbs created before tracking is enabled aren't recorded.
+pub fn set_temp_db_tracking(mode: TempDbTracking) {
+    tracker().mode = Some(mode);
+}
+
+/// Records a temporary db at `path`, `None` if tracking is off
+pub fn track_temp_db(kind: &'static str, path: &Path) -> Option<TempDbTicket> {
+    let mut tracker = tracker();
+    let mode = *tracker.mode.get_or_insert_with(|| {
+        env::var(TEMP_DB_TRACKING_ENV).map_or(TempDbTracking::Off, |v| TempDbTracking::parse(&v))
+    });
+    if mode == TempDbTracking::Off {
+        return None;
+    }
+    if !tracker.exit_hook {
+        tracker.exit_hook = true;
+        exit_hook::install();
+    }
+
+    let id = tracker.next_id;
+    tracker.next_id += 1;
+    let record = TempDbRecord {
+        kind,
+        path: path.to_path_buf(),
+        created_by: thread::current().name().map(str::to_owned),
+        size: 0,
+    };
+    let _ = tracker.dbs.insert(id, record);
+    Some(TempDbTicket(id))
+}
+
+/// Temporary dbs recorded and not deleted so far, with their sizes on disk
+pub fn leaked_temp_dbs() -> Vec<TempDbRecord> {
+    let dbs = tracker().dbs.values().cloned().collect::<Vec<_>>();
+    dbs.into_iter()
+        .map(|db| TempDbRecord {
+            size: disk_size(&db.path),
+            ..db
+        })
+        .collect()
+}
+
+/// Human readable report of the leftover dbs, `None` if there are none
+pub fn leak_report() -> Option<String> {
+    let leaked = leaked_temp_dbs();
+    if leaked.is_empty() {
+        return None;
+    }
+    let total = leaked.iter().map(|db| db.size).sum::<u64>();
+    let mut report = format!(
+        "{} temporary dbs were not deleted, {} bytes on disk:\n",
+        leaked.len(),
+        total
+    );
+    for db in leaked {
+        report.push_str(&format!(
+            "  {} at {}, {} bytes, created by {}\n",
+            db.kind,
+            db.path.display(),
+            db.size,
+            db.created_by.as_deref().unwrap_or("an unnamed thread")
+        ));
+    }
+    Some(report)
+}
+
+// total size of a file or of all files under a directory, zero if missing
+fn disk_size(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| disk_size(&e.path()))
+                    .sum()
+            })
+            .unwrap_or_default(),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
+
+// the test harness has joined the tests by then, no thread holds the tracker
+extern "C" fn report_at_exit() {
+    let mode = tracker().mode;
+    if let Some(report) = leak_report() {
+        eprintln!("{}", report);
+        if mode == Some(TempDbTracking::Strict) {
+            // exit handlers can't unwind nor change the exit status
+            std::process::abort();
+        }
+    }
+}
+
+// The test harness exits the process once all tests ran, without dropping anything, the
+// report is printed by a C exit handler.
+mod exit_hook {
+    use std::os::raw::c_int;
+
+    extern "C" {
+        fn atexit(callback: extern "C" fn()) -> c_int;
+    }
+
+    pub(super) fn install() {
+        // SAFETY: `atexit` only stores the pointer of a function taking no arguments, which
+        // doesn't unwind and lives as long as the process
+        let _ = unsafe { atexit(super::report_at_exit) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        leak_report, leaked_temp_dbs, set_temp_db_tracking, track_temp_db, TempDbTracking,
+    };
+    use std::{env::temp_dir, fs, time::SystemTime};
+
+    #[test]
+    fn leak_tracking() {
+        assert_eq!(TempDbTracking::parse("strict"), TempDbTracking::Strict);
+        assert_eq!(TempDbTracking::parse("yes"), TempDbTracking::Off);
+
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let path = temp_dir().join(format!("leak_tracking_{}", time));
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("data"), [0u8; 10]).unwrap();
+
+        set_temp_db_tracking(TempDbTracking::Report);
+        let ticket = track_temp_db("TestDB", &path).unwrap();
+        let leaked = leaked_temp_dbs()
+            .into_iter()
+            .find(|db| db.path == path)
+            .unwrap();
+        assert_eq!(leaked.kind, "TestDB");
+        assert_eq!(leaked.size, 10);
+        assert!(leaked.created_by.unwrap().contains("leak_tracking"));
+        assert!(leak_report().unwrap().contains(&path.display().to_string()));
+
+        fs::remove_dir_all(&path).unwrap();
+        ticket.release();
+        assert!(leaked_temp_dbs().iter().all(|db| db.path != path));
+    }
+}
@@ -107,5 +107,37 @@ clippy::panic, //allow debug_assert,panic in production code
 clippy::multiple_crate_versions, //caused by the dependency, can't be fixed
 )]
 pub mod db;
+pub mod aux_dump;
+pub mod backup;
+pub mod batch;
+#[cfg(unix)]
+pub mod broker;
+#[cfg(feature = "car")]
+pub mod car;
+pub mod chained;
+pub mod compat;
+pub mod config;
+pub mod explorer;
+pub mod height;
+mod hex;
+pub mod kv;
+pub mod layout;
+pub mod leaks;
+pub mod merge;
+pub mod merkle;
+pub mod parallel;
+pub mod pending;
+pub mod portable;
+pub mod prelude;
+pub mod proof;
+pub mod remote;
+pub mod schema;
+pub mod session;
+pub mod shadow;
+pub mod simulate;
+pub mod snapshot;
 pub mod state;
 pub mod store;
+pub mod testing;
+pub mod tracked;
+pub mod upgrade;
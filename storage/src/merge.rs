@@ -0,0 +1,158 @@
+/// Value merge operators
+///
+/// `merge(key, patch)` combines a patch with the current value of a key instead of reading
+/// the value, modifying it and writing it back. Backends with native merge support, e.g.
+/// RocksDB merge operators, store the patch as an operand and combine operands lazily on read
+/// or compaction, other backends read and write the value.
+///
+/// Operands carry their merge function in a leading tag byte, so a single operator registered
+/// with the backend serves every function.
+///
+use ruc::*;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+
+const TAG_COUNTER_ADD: u8 = 0x01;
+const TAG_JSON_MERGE_PATCH: u8 = 0x02;
+const TAG_SET_UNION: u8 = 0x03;
+
+/// Merge functions of `merge`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeOp {
+    /// Values and patches are big-endian i64, added with wrap-around, a missing value is 0
+    CounterAdd,
+    /// Values and patches are JSON documents, patched as per RFC 7396
+    JsonMergePatch,
+    /// Values and patches are sets encoded with `encode_set`, the result is their union
+    SetUnion,
+}
+
+impl MergeOp {
+    /// Merges `patch` into `existing`, `None` if the key has no value yet
+    pub fn apply(&self, existing: Option<&[u8]>, patch: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            MergeOp::CounterAdd => {
+                let current = existing.map_or(Ok(0), decode_counter).c(d!())?;
+                let delta = decode_counter(patch).c(d!())?;
+                Ok(current.wrapping_add(delta).to_be_bytes().to_vec())
+            }
+            MergeOp::JsonMergePatch => {
+                let mut target = match existing {
+                    Some(v) => serde_json::from_slice(v).c(d!("invalid JSON value"))?,
+                    None => Value::Null,
+                };
+                let patch = serde_json::from_slice(patch).c(d!("invalid JSON patch"))?;
+                json_merge_patch(&mut target, &patch);
+                serde_json::to_vec(&target).c(d!())
+            }
+            MergeOp::SetUnion => {
+                let mut set = existing.map_or(Ok(BTreeSet::new()), decode_set).c(d!())?;
+                set.append(&mut decode_set(patch).c(d!())?);
+                Ok(encode_set(&set))
+            }
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            MergeOp::CounterAdd => TAG_COUNTER_ADD,
+            MergeOp::JsonMergePatch => TAG_JSON_MERGE_PATCH,
+            MergeOp::SetUnion => TAG_SET_UNION,
+        }
+    }
+
+    /// Operand stored by backends with native merge support
+    pub fn encode_operand(&self, patch: &[u8]) -> Vec<u8> {
+        let mut operand = Vec::with_capacity(patch.len().saturating_add(1));
+        operand.push(self.tag());
+        operand.extend_from_slice(patch);
+        operand
+    }
+
+    /// Splits an operand into its merge function and patch
+    pub fn decode_operand(operand: &[u8]) -> Result<(MergeOp, &[u8])> {
+        let (tag, patch) = operand.split_first().c(d!("empty merge operand"))?;
+        let op = match *tag {
+            TAG_COUNTER_ADD => MergeOp::CounterAdd,
+            TAG_JSON_MERGE_PATCH => MergeOp::JsonMergePatch,
+            TAG_SET_UNION => MergeOp::SetUnion,
+            _ => return Err(eg!(format!("unknown merge operand tag {}", tag))),
+        };
+        Ok((op, patch))
+    }
+
+    /// Merges encoded operands in order into `existing`, e.g. in a backend merge operator
+    pub fn merge_operands<'a, I>(existing: Option<&[u8]>, operands: I) -> Result<Option<Vec<u8>>>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut value = existing.map(<[u8]>::to_vec);
+        for operand in operands {
+            let (op, patch) = Self::decode_operand(operand).c(d!())?;
+            value = Some(op.apply(value.as_deref(), patch).c(d!())?);
+        }
+        Ok(value)
+    }
+}
+
+/// Encodes a counter patch or value of `MergeOp::CounterAdd`
+pub fn encode_counter(n: i64) -> Vec<u8> {
+    n.to_be_bytes().to_vec()
+}
+
+pub fn decode_counter(bytes: &[u8]) -> Result<i64> {
+    let bytes = <[u8; 8]>::try_from(bytes).c(d!("counter must be 8 bytes"))?;
+    Ok(i64::from_be_bytes(bytes))
+}
+
+/// Encodes a set of `MergeOp::SetUnion`, every element length-prefixed in order
+pub fn encode_set(set: &BTreeSet<Vec<u8>>) -> Vec<u8> {
+    let mut bytes = vec![];
+    for item in set {
+        bytes.extend_from_slice(&(item.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(item);
+    }
+    bytes
+}
+
+pub fn decode_set(mut bytes: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+    let mut set = BTreeSet::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 4 {
+            return Err(eg!("truncated set"));
+        }
+        let (len, rest) = bytes.split_at(4);
+        let len = u32::from_be_bytes(<[u8; 4]>::try_from(len).c(d!())?) as usize;
+        if rest.len() < len {
+            return Err(eg!("truncated set"));
+        }
+        let (item, rest) = rest.split_at(len);
+        set.insert(item.to_vec());
+        bytes = rest;
+    }
+    Ok(set)
+}
+
+// RFC 7396: objects are merged member by member, null removes a member, anything else
+// replaces the target
+fn json_merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (k, v) in patch {
+            if v.is_null() {
+                target.remove(k);
+            } else {
+                json_merge_patch(target.entry(k.as_str()).or_insert(Value::Null), v);
+            }
+        }
+    }
+}
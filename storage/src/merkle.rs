@@ -0,0 +1,163 @@
+/// Merkle root of the backends without a tree of their own
+///
+/// MemoryDB, SledDB and LmdbDB hash their live pairs into a binary tree in key order. A leaf
+/// is `sha256(0x00 || len(key) as u32 BE || key || value)`, a node `sha256(0x01 || left ||
+/// right)`, a node without sibling moves up a level unchanged and an empty tree has the zero
+/// hash.
+///
+/// `MerkleLeaves` keeps the hash of every leaf, a write only hashes its own pair and the
+/// nodes are hashed again when the root is asked for, i.e. once per commit instead of once
+/// per write.
+///
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// root hash of an empty tree, same as fmerk
+pub const EMPTY_ROOT: [u8; 32] = [0; 32];
+
+const LEAF_TAG: u8 = 0x00;
+const NODE_TAG: u8 = 0x01;
+
+/// Hash of the leaf of a pair
+pub fn leaf_hash(key: &[u8], value: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_TAG]);
+    hasher.update((key.len() as u32).to_be_bytes());
+    hasher.update(key);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8], right: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_TAG]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Leaf hashes of the live pairs of a db, with the root cached until the next write
+#[derive(Clone, Debug, Default)]
+pub struct MerkleLeaves {
+    leaves: BTreeMap<Box<[u8]>, [u8; 32]>,
+    // root of `leaves`, `None` once they changed
+    root: Option<Vec<u8>>,
+}
+
+impl MerkleLeaves {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hashes the leaves of `pairs`, e.g. the content of a db when it's opened
+    pub fn from_pairs<K, V>(pairs: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let leaves = pairs
+            .into_iter()
+            .map(|(k, v)| {
+                let hash = leaf_hash(k.as_ref(), v.as_ref());
+                (Box::from(k.as_ref()), hash)
+            })
+            .collect();
+        MerkleLeaves { leaves, root: None }
+    }
+
+    /// Records the write of `key`, `None` deletes it
+    pub fn update(&mut self, key: &[u8], value: Option<&[u8]>) {
+        match value {
+            Some(value) => {
+                self.leaves.insert(Box::from(key), leaf_hash(key, value));
+            }
+            None => {
+                if self.leaves.remove(key).is_none() {
+                    return;
+                }
+            }
+        }
+        self.root = None;
+    }
+
+    /// Records the deletion of the keys from `lower` up to `upper` excluded
+    pub fn remove_range(&mut self, lower: &[u8], upper: &[u8]) {
+        if lower >= upper {
+            return;
+        }
+        let mut removed = self.leaves.split_off(lower);
+        let mut rest = removed.split_off(upper);
+        self.leaves.append(&mut rest);
+        if !removed.is_empty() {
+            self.root = None;
+        }
+    }
+
+    /// Removes all the leaves
+    pub fn clear(&mut self) {
+        self.leaves.clear();
+        self.root = None;
+    }
+
+    /// Number of live pairs
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// Root hash of the tree, the nodes are hashed again after a write
+    pub fn root(&mut self) -> Vec<u8> {
+        let leaves = &self.leaves;
+        self.root.get_or_insert_with(|| root_of(leaves)).clone()
+    }
+}
+
+fn root_of(leaves: &BTreeMap<Box<[u8]>, [u8; 32]>) -> Vec<u8> {
+    let mut level = leaves.values().copied().collect::<Vec<_>>();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node_hash(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level.pop().unwrap_or(EMPTY_ROOT).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leaf_hash, MerkleLeaves, EMPTY_ROOT};
+
+    #[test]
+    fn merkle_leaves_incremental() {
+        let mut leaves = MerkleLeaves::new();
+        assert_eq!(leaves.root(), EMPTY_ROOT.to_vec());
+
+        // a single leaf is the root
+        leaves.update(b"k10", Some(b"v10"));
+        assert_eq!(leaves.root(), leaf_hash(b"k10", b"v10").to_vec());
+
+        // updated leaves give the root of the same pairs hashed at once
+        for i in 0..100u32 {
+            leaves.update(format!("k{:03}", i).as_bytes(), Some(&i.to_be_bytes()));
+        }
+        leaves.update(b"k10", None);
+        leaves.update(b"k050", None);
+        leaves.update(b"missing", None);
+        leaves.remove_range(b"k090", b"k095");
+        let pairs = (0..100u32)
+            .filter(|i| *i != 50 && !(90..95).contains(i))
+            .map(|i| (format!("k{:03}", i).into_bytes(), i.to_be_bytes()));
+        assert_eq!(leaves.root(), MerkleLeaves::from_pairs(pairs).root());
+        assert_eq!(leaves.len(), 94);
+
+        leaves.clear();
+        assert_eq!(leaves.root(), EMPTY_ROOT.to_vec());
+    }
+}
@@ -0,0 +1,91 @@
+/// Deterministic parallel transaction execution
+///
+/// Every transaction runs in its own lane, a session forked from the block state which
+/// records its accesses, and lanes are spread over a pool of threads. Lanes are merged in
+/// transaction order, a lane conflicting with the transactions merged before it runs again on
+/// top of the merged state, so a transaction runs at most twice. The merged state is the one
+/// a serial execution of the block gives, whatever the thread scheduling.
+///
+use crate::{
+    db::MerkleDB,
+    state::{AccessList, State},
+};
+use ruc::*;
+use std::{panic, slice, thread};
+
+/// Executes `txs` in parallel on `state` and returns the result of every transaction in order
+///
+/// The writes of a failed transaction are dropped, its reads still order the ones after it.
+pub fn execute<D, T, R, F>(state: &mut State<D>, txs: &[T], exec_fn: F) -> Result<Vec<Result<R>>>
+where
+    D: MerkleDB + Send + Sync,
+    T: Sync,
+    R: Send,
+    F: Fn(&T, &mut State<D>) -> Result<R> + Sync,
+{
+    let lanes = run_lanes(state, txs, &exec_fn);
+    let mut results = Vec::with_capacity(txs.len());
+    // accesses of the transactions merged so far
+    let mut merged = AccessList::new();
+    for ((lane, res), tx) in lanes.into_iter().zip(txs) {
+        let mut list = lane.access_list().unwrap_or_default();
+        let (lane, res) = if list.conflicts_with(&merged) {
+            // the lane may have seen stale values, it runs again as it would serially
+            let mut lane = state.substate();
+            lane.record_access();
+            let res = exec_fn(tx, &mut lane);
+            list = lane.access_list().unwrap_or_default();
+            (lane, res)
+        } else {
+            (lane, res)
+        };
+        if res.is_ok() {
+            let report = state.merge_lanes(slice::from_ref(&lane)).c(d!())?;
+            debug_assert!(report.is_empty());
+            merged.append(&mut list);
+        }
+        results.push(res);
+    }
+    Ok(results)
+}
+
+/// Runs every transaction in a lane forked from `state`, lanes are returned in order
+fn run_lanes<D, T, R, F>(state: &State<D>, txs: &[T], exec_fn: &F) -> Vec<(State<D>, Result<R>)>
+where
+    D: MerkleDB + Send + Sync,
+    T: Sync,
+    R: Send,
+    F: Fn(&T, &mut State<D>) -> Result<R> + Sync,
+{
+    let workers = thread::available_parallelism()
+        .map_or(1, usize::from)
+        .min(txs.len());
+    let mut lanes = thread::scope(|s| {
+        let handles = (0..workers)
+            .map(|w| {
+                s.spawn(move || {
+                    txs.iter()
+                        .enumerate()
+                        .skip(w)
+                        .step_by(workers)
+                        .map(|(i, tx)| {
+                            let mut lane = state.substate();
+                            lane.record_access();
+                            let res = exec_fn(tx, &mut lane);
+                            (i, lane, res)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect::<Vec<_>>()
+    });
+    lanes.sort_by_key(|(i, _, _)| *i);
+    lanes
+        .into_iter()
+        .map(|(_, lane, res)| (lane, res))
+        .collect()
+}
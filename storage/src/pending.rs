@@ -0,0 +1,122 @@
+/// Writes staged by a backend until its commit
+///
+/// SledDB and LmdbDB write the state and the aux data of a commit in one transaction, so a
+/// crash never leaves the state ahead of the height recorded in the aux data. The writes of
+/// `put_batch` wait in `PendingWrites` until then and reads lay them over the stored pairs.
+///
+use crate::db::{DbTryIter, IterOrder, KVEntry};
+use ruc::*;
+use std::{cmp::Ordering, collections::BTreeMap, iter::Peekable, ops::Bound};
+
+type PendingRange<'a> = Box<dyn Iterator<Item = (&'a Vec<u8>, &'a Option<Vec<u8>>)> + 'a>;
+
+/// Whether no key is within the bounds
+pub fn is_empty_range(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(lower), Bound::Included(upper)) => lower > upper,
+        (
+            Bound::Included(lower) | Bound::Excluded(lower),
+            Bound::Included(upper) | Bound::Excluded(upper),
+        ) => lower >= upper,
+        _ => false,
+    }
+}
+
+/// Pending writes by key, `None` for a delete
+#[derive(Clone, Debug, Default)]
+pub struct PendingWrites(BTreeMap<Vec<u8>, Option<Vec<u8>>>);
+
+impl PendingWrites {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pending write of `key`, `Some(None)` for a delete
+    pub fn get(&self, key: &[u8]) -> Option<&Option<Vec<u8>>> {
+        self.0.get(key)
+    }
+
+    /// Stages `kvs`, replacing the pending writes of their keys
+    pub fn extend(&mut self, kvs: impl IntoIterator<Item = KVEntry>) {
+        self.0.extend(kvs);
+    }
+
+    /// Pending writes in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Option<Vec<u8>>)> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Drops the writes, once committed
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    /// Pairs of `stored`, the pairs of a backend in `range` and `order`, with the pending
+    /// writes of the range laid over them
+    ///
+    /// A pending write replaces the stored pair of its key and a pending delete hides it. The
+    /// read errors of `stored` are yielded as they come.
+    pub fn overlay<'a>(
+        &'a self,
+        stored: DbTryIter<'a>,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+        order: IterOrder,
+    ) -> DbTryIter<'a> {
+        let pending: PendingRange<'a> = if is_empty_range(range.0, range.1) {
+            Box::new(std::iter::empty())
+        } else {
+            let pending = self.0.range::<[u8], _>(range);
+            match order {
+                IterOrder::Asc | IterOrder::Unordered => Box::new(pending),
+                IterOrder::Desc => Box::new(pending.rev()),
+            }
+        };
+        Box::new(Overlay {
+            stored: stored.peekable(),
+            pending: pending.peekable(),
+            order,
+        })
+    }
+}
+
+// pairs of a backend with the pending writes of the same range laid over them
+struct Overlay<'a> {
+    stored: Peekable<DbTryIter<'a>>,
+    pending: Peekable<PendingRange<'a>>,
+    order: IterOrder,
+}
+
+impl Iterator for Overlay<'_> {
+    type Item = Result<(Box<[u8]>, Box<[u8]>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // which of the stored pair and the pending write comes first
+            let first = match (self.stored.peek(), self.pending.peek()) {
+                (None, None) => return None,
+                (Some(Err(_)), _) | (Some(Ok(_)), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(Ok((key, _))), Some((pending, _))) => match self.order {
+                    IterOrder::Asc | IterOrder::Unordered => key[..].cmp(&pending[..]),
+                    IterOrder::Desc => pending[..].cmp(&key[..]),
+                },
+            };
+            match first {
+                Ordering::Less => return self.stored.next(),
+                // the pending write replaces the stored pair
+                Ordering::Equal => {
+                    self.stored.next();
+                }
+                Ordering::Greater => {}
+            }
+            if let (key, Some(value)) = self.pending.next()? {
+                return Some(Ok((key.clone().into(), value.clone().into())));
+            }
+            // a pending delete hides the stored pair
+        }
+    }
+}
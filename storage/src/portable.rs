@@ -0,0 +1,260 @@
+/// Backend agnostic snapshots
+///
+/// A portable snapshot holds the live key/value pairs of a db and its aux data instead of the
+/// internal structures of a backend, so a snapshot taken from one backend is restored into
+/// any other, e.g. a FinDB snapshot loaded into a MemoryDB for tests. It's slower to take
+/// than a checkpoint, every entry is read and written again.
+///
+/// The payload is a sequence of frames prefixed with their length as a big endian u32, see
+/// `aux_dump`: the format, the backend the snapshot was taken from, its root hash, then a key
+/// frame and a value frame per entry. The entries end with a frame length of `u32::MAX`
+/// followed by their number as a big endian u64, the aux entries follow the same way. It's
+/// compressed and sealed in a snapshot container like the other snapshots, see `snapshot`.
+/// The frames are streamed from the db into a file and from a file into the db, see
+/// `export_portable_file` and `import_portable_file`.
+///
+/// Root hashes depend on the tree of a backend, the restored root is only comparable with
+/// the recorded one when both backends are the same. The root of the source backend is
+/// recorded next to the restored root so the chain state opens on the other backend, the
+/// roots recorded at past heights are those of the source backend.
+///
+use crate::{
+    aux_dump::{read_data, read_frame, read_len, write_frame},
+    db::{IterOrder, KVBatch, KVEntry, MerkleDB},
+    snapshot::{
+        compress_snapshot, compress_snapshot_stream, container_backend, decompress_snapshot,
+        decompress_snapshot_reader, open_container, open_container_reader, seal_container,
+        seal_container_file, PrefixFilter, SnapshotCodec, SnapshotOptions,
+    },
+    state::chain_state::ChainState,
+};
+use ruc::*;
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// backend recorded in the containers of portable snapshots, they're opened by every backend
+pub const PORTABLE_BACKEND: &str = "kv";
+
+const PORTABLE_TAG: &str = "storage-kv/1";
+const END_OF_ENTRIES: u32 = u32::MAX;
+// number of state entries of a snapshot written per commit on import
+const IMPORT_BATCH: usize = 0x2710;
+
+/// Result of a portable snapshot export or import
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortableSnapshot {
+    /// backend the snapshot was taken from
+    pub backend: String,
+    /// root hash of the db the snapshot was taken from
+    pub root_hash: Vec<u8>,
+    pub entries: u64,
+    pub aux_entries: u64,
+}
+
+/// Whether `bytes` are a portable snapshot, see `export_portable`
+pub fn is_portable(bytes: &[u8]) -> bool {
+    container_backend(bytes) == Some(PORTABLE_BACKEND)
+}
+
+/// Takes a portable snapshot of `db`, a backend named `backend`, compressed with the codec of
+/// `opts`
+///
+/// Aux keys not allowed by `aux_filter` are left out, e.g. internal bookkeeping of the backend
+/// meaningless to the others. The snapshot is built in memory, see `export_portable_file`.
+pub fn export_portable<D: MerkleDB>(
+    db: &D,
+    backend: &str,
+    aux_filter: &PrefixFilter,
+    opts: &SnapshotOptions,
+) -> Result<(Vec<u8>, PortableSnapshot)> {
+    let mut payload = vec![];
+    let snapshot = export_portable_to(db, backend, aux_filter, &mut payload).c(d!())?;
+    let payload = compress_snapshot(payload, opts).c(d!())?;
+    let bytes = seal_container(PORTABLE_BACKEND, &payload).c(d!())?;
+    Ok((bytes, snapshot))
+}
+
+/// Takes a portable snapshot of `db` into the file at `path`, like `export_portable` without
+/// holding it in memory
+///
+/// A compressed snapshot is first written uncompressed next to `path`, its length is recorded
+/// before the compressed bytes.
+pub fn export_portable_file<D: MerkleDB>(
+    db: &D,
+    backend: &str,
+    aux_filter: &PrefixFilter,
+    opts: &SnapshotOptions,
+    path: &Path,
+) -> Result<PortableSnapshot> {
+    if opts.codec == SnapshotCodec::None {
+        let mut snapshot = None;
+        seal_container_file(PORTABLE_BACKEND, path, |writer| {
+            snapshot = Some(export_portable_to(db, backend, aux_filter, writer).c(d!())?);
+            Ok(())
+        })
+        .c(d!())?;
+        return snapshot.c(d!());
+    }
+
+    let mut frames_path = path.as_os_str().to_owned();
+    frames_path.push(".frames");
+    let frames_path = PathBuf::from(frames_path);
+    let exported = (|| -> Result<PortableSnapshot> {
+        let mut frames = BufWriter::new(File::create(&frames_path).c(d!())?);
+        let snapshot = export_portable_to(db, backend, aux_filter, &mut frames).c(d!())?;
+        frames.flush().c(d!())?;
+        drop(frames);
+        let len = fs::metadata(&frames_path).c(d!())?.len();
+        let mut frames = BufReader::new(File::open(&frames_path).c(d!())?);
+        seal_container_file(PORTABLE_BACKEND, path, |writer| {
+            compress_snapshot_stream(&mut frames, len, opts, writer)
+        })
+        .c(d!())?;
+        Ok(snapshot)
+    })();
+    let _ = fs::remove_file(&frames_path);
+    exported
+}
+
+/// Writes the frames of a portable snapshot of `db` to `writer`, uncompressed and without
+/// container
+pub fn export_portable_to<D: MerkleDB, W: Write>(
+    db: &D,
+    backend: &str,
+    aux_filter: &PrefixFilter,
+    mut writer: W,
+) -> Result<PortableSnapshot> {
+    let mut snapshot = PortableSnapshot {
+        backend: backend.to_owned(),
+        root_hash: db.root_hash(),
+        ..Default::default()
+    };
+    write_frame(&mut writer, PORTABLE_TAG.as_bytes()).c(d!())?;
+    write_frame(&mut writer, backend.as_bytes()).c(d!())?;
+    write_frame(&mut writer, &snapshot.root_hash).c(d!())?;
+
+    // a read error would otherwise end the iteration and truncate the snapshot
+    for kv_pair in db.try_db_all_iterator(IterOrder::Asc) {
+        let (k, v) = db.decode_kv(kv_pair.c(d!())?);
+        write_frame(&mut writer, &k).c(d!())?;
+        write_frame(&mut writer, &v).c(d!())?;
+        snapshot.entries = snapshot.entries.saturating_add(1);
+    }
+    write_end(&mut writer, snapshot.entries).c(d!())?;
+
+    for kv_pair in db.try_db_all_iterator_aux(IterOrder::Asc) {
+        let (k, v) = kv_pair.c(d!())?;
+        if !aux_filter.allows(&k) {
+            continue;
+        }
+        write_frame(&mut writer, &k).c(d!())?;
+        write_frame(&mut writer, &v).c(d!())?;
+        snapshot.aux_entries = snapshot.aux_entries.saturating_add(1);
+    }
+    write_end(&mut writer, snapshot.aux_entries).c(d!())?;
+    Ok(snapshot)
+}
+
+/// Writes the portable snapshot `bytes` into the empty db `db`
+///
+/// The container is verified before anything is written, see `import_portable_from`.
+pub fn import_portable<D: MerkleDB>(db: &mut D, bytes: &[u8]) -> Result<PortableSnapshot> {
+    let payload = open_container(PORTABLE_BACKEND, bytes).c(d!())?;
+    let payload = decompress_snapshot(payload).c(d!())?;
+    import_portable_from(db, payload.as_ref()).c(d!())
+}
+
+/// Writes the portable snapshot file at `path` into the empty db `db`, like
+/// `import_portable` without holding it in memory
+///
+/// The entries are written as they're read and the checksum of the container is checked
+/// once they're all read, a failed import leaves a partial db to be discarded.
+pub fn import_portable_file<D: MerkleDB>(db: &mut D, path: &Path) -> Result<PortableSnapshot> {
+    let file = BufReader::new(File::open(path).c(d!())?);
+    let mut container = open_container_reader(PORTABLE_BACKEND, file).c(d!())?;
+    let payload = decompress_snapshot_reader(&mut container).c(d!())?;
+    let snapshot = import_portable_from(db, payload).c(d!())?;
+    container.finish().c(d!())?;
+    Ok(snapshot)
+}
+
+/// Writes the frames of a portable snapshot read from `reader` into the empty db `db`
+///
+/// The state entries are committed in batches of `IMPORT_BATCH` and the aux data of `db` is
+/// replaced by the one of the snapshot, nothing is flushed. An interrupted import leaves a
+/// partial db, it's restored again into a new one.
+pub fn import_portable_from<D: MerkleDB, R: Read>(
+    db: &mut D,
+    mut reader: R,
+) -> Result<PortableSnapshot> {
+    if read_frame(&mut reader).c(d!())? != PORTABLE_TAG.as_bytes() {
+        return Err(eg!("not a portable snapshot"));
+    }
+    let backend = read_frame(&mut reader).c(d!())?;
+    let mut snapshot = PortableSnapshot {
+        backend: String::from_utf8(backend).c(d!("invalid backend name"))?,
+        root_hash: read_frame(&mut reader).c(d!())?,
+        ..Default::default()
+    };
+    if let Some(kv_pair) = db.try_db_all_iterator(IterOrder::Asc).next() {
+        kv_pair.c(d!())?;
+        return Err(eg!("can't restore a snapshot into a non-empty db"));
+    }
+
+    let mut batch = KVBatch::with_capacity(IMPORT_BATCH);
+    snapshot.entries = read_entries(&mut reader, &mut |entry| {
+        batch.push(entry);
+        if batch.len() >= IMPORT_BATCH {
+            db.put_batch(std::mem::take(&mut batch)).c(d!())?;
+            db.commit(vec![], false).c(d!())?;
+        }
+        Ok(())
+    })
+    .c(d!())?;
+    if !batch.is_empty() {
+        db.put_batch(batch).c(d!())?;
+    }
+
+    db.clean_aux().c(d!())?;
+    let mut aux = KVBatch::new();
+    snapshot.aux_entries = read_entries(&mut reader, &mut |entry| {
+        aux.push(entry);
+        Ok(())
+    })
+    .c(d!())?;
+    if reader.read(&mut [0; 1]).c(d!())? != 0 {
+        return Err(eg!("trailing bytes after the snapshot entries"));
+    }
+    db.commit(aux, false).c(d!())?;
+    ChainState::record_source_root(db, &snapshot.root_hash).c(d!())?;
+    Ok(snapshot)
+}
+
+fn write_end<W: Write>(writer: &mut W, count: u64) -> Result<()> {
+    writer.write_all(&END_OF_ENTRIES.to_be_bytes()).c(d!())?;
+    writer.write_all(&count.to_be_bytes()).c(d!())
+}
+
+// reads entries up to their end, checking their count
+fn read_entries<R: Read>(reader: &mut R, f: &mut dyn FnMut(KVEntry) -> Result<()>) -> Result<u64> {
+    let mut entries: u64 = 0;
+    loop {
+        let len = read_len(reader).c(d!())?;
+        if len == END_OF_ENTRIES {
+            break;
+        }
+        let key = read_data(reader, len).c(d!())?;
+        let value = read_frame(reader).c(d!())?;
+        f((key, Some(value))).c(d!())?;
+        entries = entries.saturating_add(1);
+    }
+    let mut count = [0; 8];
+    reader.read_exact(&mut count).c(d!("truncated snapshot"))?;
+    if u64::from_be_bytes(count) != entries {
+        return Err(eg!("snapshot entry count mismatch"));
+    }
+    Ok(entries)
+}
@@ -0,0 +1,16 @@
+/// Common imports of storage users
+///
+/// `use storage::prelude::*;` brings the db trait, the typed heights, the state types and the
+/// typed store primitives in scope. Test code gets the temporary backends too from
+/// `temp_db::prelude` with its `test-utils` feature.
+///
+pub use crate::{
+    db::{IterOrder, KVBatch, KVEntry, KValue, MerkleDB, ScanControl, StoreKey},
+    height::{Height, Version},
+    state::{ChainState, ChainStateOpts, SessionedCache, State},
+    store::{
+        ImmutablePrefixedStore, Index, IndexedMap, Item, KeyDecode, KeyEncode, Map, Module, Prefix,
+        PrefixedStore, Queue, Seq, SnapshotMap, SnapshotStrategy, Stated, StatelessStore, Store,
+    },
+};
+pub use ruc::{Result, RucError, RucResult};
@@ -0,0 +1,415 @@
+/// Proof helpers shared by backends
+///
+use crate::{db::KVBatch, remote::ProofVerifier};
+use parking_lot::Mutex;
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Proof of a single key, present or absent, against a root hash
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Proof {
+    pub root_hash: Vec<u8>,
+    pub key: Vec<u8>,
+    /// value of the key, `None` if it doesn't exist
+    pub value: Option<Vec<u8>>,
+    /// backend specific encoding, checked by the `ProofVerifier` of the backend
+    pub proof: Vec<u8>,
+}
+
+impl Proof {
+    /// Checks the proof against `root`, a trusted root hash
+    pub fn verify(&self, root: &[u8], verifier: &dyn ProofVerifier) -> Result<()> {
+        if self.root_hash != root {
+            return Err(eg!("proof is for another root"));
+        }
+        let values = verifier
+            .verify(root, std::slice::from_ref(&self.key), &self.proof)
+            .c(d!())?;
+        if values.first() != Some(&self.value) {
+            return Err(eg!("proven value doesn't match"));
+        }
+        Ok(())
+    }
+}
+
+/// One proof of the presence or absence of several keys against a root hash
+///
+/// Tree nodes shared by the keys are encoded once, e.g. for a light client querying many
+/// accounts per block.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultiProof {
+    pub root_hash: Vec<u8>,
+    /// sorted and de-duplicated keys with their values, `None` if a key doesn't exist
+    pub values: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    /// backend specific encoding, checked by the `ProofVerifier` of the backend
+    pub proof: Vec<u8>,
+}
+
+impl MultiProof {
+    /// Checks the proof of every key against `root`, a trusted root hash
+    pub fn verify(&self, root: &[u8], verifier: &dyn ProofVerifier) -> Result<()> {
+        if self.root_hash != root {
+            return Err(eg!("proof is for another root"));
+        }
+        let (keys, values): (Vec<_>, Vec<_>) = self.values.iter().cloned().unzip();
+        if verifier.verify(root, &keys, &self.proof).c(d!())? != values {
+            return Err(eg!("proven values don't match"));
+        }
+        Ok(())
+    }
+}
+
+/// Everything needed to dispute the state transition of a height
+///
+/// The witness proves the values of the written keys before the height against the root of
+/// the previous height, `post_proof` proves their values after it against `post_root`, see
+/// `ChainState::set_challenge_window`. On backends without proofs both proofs are empty, the
+/// changelog is kept but only a bundle of an empty batch or an empty tree verifies.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FraudProofBundle {
+    pub height: u64,
+    /// pre-state values of the keys of `batch`, against the root of `height - 1`
+    pub witness: MultiProof,
+    /// sorted puts and deletes committed at `height`
+    pub batch: KVBatch,
+    /// root hash after the commit of `height`
+    pub post_root: Vec<u8>,
+    /// proof of the keys of `batch` against `post_root`
+    pub post_proof: Vec<u8>,
+}
+
+impl FraudProofBundle {
+    /// Root hash before the commit of `height`
+    pub fn pre_root(&self) -> &[u8] {
+        &self.witness.root_hash
+    }
+
+    /// Checks that applying the batch to the state of `pre_root`, a trusted root hash, leads
+    /// to `post_root`
+    ///
+    /// The witness must cover every key of the batch and verify against `pre_root`, and the
+    /// post proof must show every key of the batch holding the value the batch wrote. An error
+    /// disputes the transition. Keys out of the batch are not covered, a transition changing
+    /// them as well only fails if it also gets a written key wrong.
+    pub fn verify(&self, pre_root: &[u8], verifier: &dyn ProofVerifier) -> Result<()> {
+        if self.witness.root_hash != pre_root {
+            return Err(eg!("proof is for another root"));
+        }
+        let keys = self
+            .batch
+            .iter()
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        if !self.witness.values.iter().map(|(k, _)| k).eq(keys.iter()) {
+            return Err(eg!("witness doesn't cover the keys of the batch"));
+        }
+        if keys.is_empty() && self.post_root != pre_root {
+            return Err(eg!("an empty batch changed the root"));
+        }
+
+        let pre_values = self.witness.values.iter().map(|(_, v)| v.clone());
+        check_values(pre_root, &keys, pre_values, &self.witness.proof, verifier)
+            .c(d!("invalid witness"))?;
+        let post_values = self.batch.iter().map(|(_, v)| v.clone());
+        check_values(
+            &self.post_root,
+            &keys,
+            post_values,
+            &self.post_proof,
+            verifier,
+        )
+        .c(d!("post root doesn't hold the writes of the batch"))
+    }
+
+    /// Whether the bundle carries proofs, i.e. it was retained on a backend with proofs
+    pub fn is_proven(&self) -> bool {
+        self.batch.is_empty() || !self.witness.proof.is_empty()
+    }
+
+    /// Binary encoding of the bundle, the keys of the batch are written once
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        if !self
+            .witness
+            .values
+            .iter()
+            .map(|(k, _)| k)
+            .eq(self.batch.iter().map(|(k, _)| k))
+        {
+            return Err(eg!("witness doesn't cover the keys of the batch"));
+        }
+        let mut bytes = vec![BUNDLE_FORMAT];
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        put_bytes(&mut bytes, &self.witness.root_hash);
+        put_bytes(&mut bytes, &self.post_root);
+        bytes.extend_from_slice(&(self.batch.len() as u32).to_be_bytes());
+        for ((key, value), (_, pre_value)) in self.batch.iter().zip(self.witness.values.iter()) {
+            put_bytes(&mut bytes, key);
+            put_value(&mut bytes, value.as_deref());
+            put_value(&mut bytes, pre_value.as_deref());
+        }
+        put_bytes(&mut bytes, &self.witness.proof);
+        put_bytes(&mut bytes, &self.post_proof);
+        Ok(bytes)
+    }
+
+    /// Reads a bundle written by `encode`
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let (format, mut rd) = bytes.split_first().c(d!("empty fraud proof"))?;
+        if *format != BUNDLE_FORMAT {
+            return Err(eg!(format!("unknown fraud proof format {}", format)));
+        }
+        let height = take(&mut rd, 8).c(d!())?;
+        let height = u64::from_be_bytes(<[u8; 8]>::try_from(height).c(d!())?);
+        let pre_root = take_bytes(&mut rd).c(d!())?;
+        let post_root = take_bytes(&mut rd).c(d!())?;
+        let count = take(&mut rd, 4).c(d!())?;
+        let count = u32::from_be_bytes(<[u8; 4]>::try_from(count).c(d!())?);
+
+        // every entry takes at least 6 bytes, a corrupted count can't allocate more
+        let mut batch = KVBatch::with_capacity((count as usize).min(rd.len() / 6));
+        let mut values = Vec::with_capacity(batch.capacity());
+        for _ in 0..count {
+            let key = take_bytes(&mut rd).c(d!())?;
+            batch.push((key.clone(), take_value(&mut rd).c(d!())?));
+            values.push((key, take_value(&mut rd).c(d!())?));
+        }
+        let proof = take_bytes(&mut rd).c(d!())?;
+        let post_proof = take_bytes(&mut rd).c(d!())?;
+        if !rd.is_empty() {
+            return Err(eg!("trailing bytes after the fraud proof"));
+        }
+        Ok(FraudProofBundle {
+            height,
+            witness: MultiProof {
+                root_hash: pre_root,
+                values,
+                proof,
+            },
+            batch,
+            post_root,
+            post_proof,
+        })
+    }
+}
+
+// version of the encoding of `FraudProofBundle`
+const BUNDLE_FORMAT: u8 = 1;
+
+// checks that `keys` hold `values` under `root`
+fn check_values(
+    root: &[u8],
+    keys: &[Vec<u8>],
+    values: impl Iterator<Item = Option<Vec<u8>>>,
+    proof: &[u8],
+    verifier: &dyn ProofVerifier,
+) -> Result<()> {
+    let values = values.collect::<Vec<_>>();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    if proof.is_empty() {
+        // every key is absent from an empty tree, there is nothing to prove
+        if root.iter().all(|b| *b == 0) && values.iter().all(Option::is_none) {
+            return Ok(());
+        }
+        return Err(eg!("values are not proven, the backend has no proofs"));
+    }
+    if verifier.verify(root, keys, proof).c(d!())? != values {
+        return Err(eg!("proven values don't match"));
+    }
+    Ok(())
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+// a tag byte, 0 for `None`, followed by the bytes of a value
+fn put_value(out: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(value) => {
+            out.push(1);
+            put_bytes(out, value);
+        }
+        None => out.push(0),
+    }
+}
+
+fn take<'a>(rd: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if rd.len() < len {
+        return Err(eg!("truncated fraud proof"));
+    }
+    let (bytes, rest) = rd.split_at(len);
+    *rd = rest;
+    Ok(bytes)
+}
+
+fn take_bytes(rd: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = take(rd, 4).c(d!())?;
+    let len = u32::from_be_bytes(<[u8; 4]>::try_from(len).c(d!())?);
+    take(rd, len as usize).map(<[u8]>::to_vec)
+}
+
+fn take_value(rd: &mut &[u8]) -> Result<Option<Vec<u8>>> {
+    match take(rd, 1).c(d!())? {
+        [0] => Ok(None),
+        [1] => take_bytes(rd).map(Some),
+        _ => Err(eg!("invalid value tag")),
+    }
+}
+
+/// default number of proofs kept by a `ProofMemo`
+pub const DEFAULT_PROOF_MEMO_SIZE: usize = 1024;
+
+/// Bounded LRU memo of the proofs generated against a single root hash
+///
+/// Hot keys get proven over and over between two commits; the memo hands out the proof built
+/// the first time instead of walking the tree again. It holds whole proofs, not tree nodes, so
+/// nothing outlives the root: the proofs are dropped by every commit and as soon as a proof
+/// for a new root is requested, the first proof of a key after a commit walks the whole tree.
+pub struct ProofMemo {
+    capacity: usize,
+    inner: Mutex<ProofMemoInner>,
+}
+
+#[derive(Default)]
+struct ProofMemoInner {
+    root: Vec<u8>,
+    proofs: HashMap<Vec<Vec<u8>>, Vec<u8>>,
+    lru: VecDeque<Vec<Vec<u8>>>,
+    hits: u64,
+    misses: u64,
+}
+
+/// Hit/miss counters of a `ProofMemo`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProofMemoStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl Default for ProofMemo {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROOF_MEMO_SIZE)
+    }
+}
+
+impl ProofMemo {
+    /// Creates a memo keeping at most `capacity` proofs, zero disables it
+    pub fn new(capacity: usize) -> Self {
+        ProofMemo {
+            capacity,
+            inner: Mutex::new(ProofMemoInner::default()),
+        }
+    }
+
+    /// Returns the kept proof of `keys` under `root`, or builds and keeps it with `prove`
+    pub fn get_or_prove<F>(&self, root: &[u8], keys: &[Vec<u8>], prove: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        if self.capacity == 0 {
+            return prove();
+        }
+
+        let mut inner = self.inner.lock();
+        if inner.root != root {
+            inner.proofs.clear();
+            inner.lru.clear();
+            inner.root = root.to_vec();
+        }
+
+        if let Some(proof) = inner.proofs.get(keys).cloned() {
+            inner.hits = inner.hits.saturating_add(1);
+            // move to the most recently used end
+            if let Some(pos) = inner.lru.iter().position(|k| k.as_slice() == keys) {
+                if let Some(k) = inner.lru.remove(pos) {
+                    inner.lru.push_back(k);
+                }
+            }
+            return Ok(proof);
+        }
+
+        inner.misses = inner.misses.saturating_add(1);
+        let proof = prove().c(d!())?;
+        if inner.lru.len() >= self.capacity {
+            if let Some(oldest) = inner.lru.pop_front() {
+                inner.proofs.remove(&oldest);
+            }
+        }
+        inner.proofs.insert(keys.to_vec(), proof.clone());
+        inner.lru.push_back(keys.to_vec());
+        Ok(proof)
+    }
+
+    /// Drops all kept proofs
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.proofs.clear();
+        inner.lru.clear();
+    }
+
+    /// Returns hit/miss counters and the number of kept proofs
+    pub fn stats(&self) -> ProofMemoStats {
+        let inner = self.inner.lock();
+        ProofMemoStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            entries: inner.proofs.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProofMemo;
+    use ruc::*;
+
+    #[test]
+    fn proof_memo_hit_n_miss() {
+        let memo = ProofMemo::new(2);
+        let keys = vec![b"k10".to_vec()];
+
+        let proof = memo.get_or_prove(b"r1", &keys, || Ok(b"p1".to_vec()));
+        assert_eq!(proof.unwrap(), b"p1".to_vec());
+        // served from the memo
+        let proof = memo.get_or_prove(b"r1", &keys, || Err(eg!("not kept")));
+        assert_eq!(proof.unwrap(), b"p1".to_vec());
+        // a new root invalidates older proofs
+        let proof = memo.get_or_prove(b"r2", &keys, || Ok(b"p2".to_vec()));
+        assert_eq!(proof.unwrap(), b"p2".to_vec());
+
+        let stats = memo.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+    }
+
+    #[test]
+    fn proof_memo_evicts_lru() {
+        let memo = ProofMemo::new(2);
+        let k1 = vec![b"k1".to_vec()];
+        let k2 = vec![b"k2".to_vec()];
+        let k3 = vec![b"k3".to_vec()];
+
+        memo.get_or_prove(b"r", &k1, || Ok(b"p1".to_vec())).unwrap();
+        memo.get_or_prove(b"r", &k2, || Ok(b"p2".to_vec())).unwrap();
+        // touch k1, k2 becomes the oldest entry
+        memo.get_or_prove(b"r", &k1, || Err(eg!())).unwrap();
+        memo.get_or_prove(b"r", &k3, || Ok(b"p3".to_vec())).unwrap();
+
+        assert!(memo.get_or_prove(b"r", &k1, || Err(eg!())).is_ok());
+        assert!(memo.get_or_prove(b"r", &k2, || Err(eg!())).is_err());
+        assert_eq!(memo.stats().entries, 2);
+    }
+
+    #[test]
+    fn proof_memo_disabled() {
+        let memo = ProofMemo::new(0);
+        let keys = vec![b"k10".to_vec()];
+        memo.get_or_prove(b"r", &keys, || Ok(b"p".to_vec()))
+            .unwrap();
+        assert!(memo.get_or_prove(b"r", &keys, || Err(eg!())).is_err());
+    }
+}
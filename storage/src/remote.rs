@@ -0,0 +1,126 @@
+/// Remote state fetcher
+///
+/// `RemoteDB` serves reads by querying another node and only returns data proven against a
+/// trusted root hash, the read side of a stateless/light execution mode. The transport, e.g.
+/// a gRPC query client, plugs in through `RemoteClient`, the proof format through
+/// `ProofVerifier`.
+///
+use crate::chained::ReadSource;
+use parking_lot::RwLock;
+use ruc::*;
+
+/// Response of a remote node to a proven query
+#[derive(Clone, Debug, Default)]
+pub struct RemoteResponse {
+    /// values claimed by the remote, in the order of the queried keys
+    pub values: Vec<Option<Vec<u8>>>,
+    /// a single proof of all queried keys
+    pub proof: Vec<u8>,
+}
+
+/// Query client of a remote node
+pub trait RemoteClient: Send + Sync {
+    /// Fetches the values of `keys` together with a proof against the remote's latest root
+    fn get_with_proof(&self, keys: &[Vec<u8>]) -> Result<RemoteResponse>;
+}
+
+/// Verifies proofs produced by a backend
+pub trait ProofVerifier: Send + Sync {
+    /// Checks `proof` against `root` and returns the proven value of every key in `keys`
+    fn verify(&self, root: &[u8], keys: &[Vec<u8>], proof: &[u8]) -> Result<Vec<Option<Vec<u8>>>>;
+
+    /// Checks a range proof against `root` and returns all the proven pairs with a key in
+    /// `[lower, upper)`, failing if the proof doesn't cover the whole range
+    fn verify_range(
+        &self,
+        _root: &[u8],
+        _lower: &[u8],
+        _upper: &[u8],
+        _proof: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Err(eg!("range proofs are not supported by this verifier"))
+    }
+}
+
+/// Fetches the value of a key whose local read failed, e.g. on a missing merkle node
+///
+/// Hooked into a chain state with `ChainState::set_missing_node_resolver`.
+pub trait MissingNodeResolver: Send + Sync {
+    /// Returns the value of `key` proven against `root`, the local root hash
+    fn resolve(&self, root: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
+/// Read-only db fetching state from a remote node
+///
+/// Every response is verified against the trusted root before it's returned, a lying or
+/// out of sync remote results in an error, never in unproven data.
+pub struct RemoteDB<C: RemoteClient, V: ProofVerifier> {
+    client: C,
+    verifier: V,
+    trusted_root: RwLock<Vec<u8>>,
+}
+
+impl<C: RemoteClient, V: ProofVerifier> RemoteDB<C, V> {
+    pub fn new(client: C, verifier: V, trusted_root: Vec<u8>) -> Self {
+        RemoteDB {
+            client,
+            verifier,
+            trusted_root: RwLock::new(trusted_root),
+        }
+    }
+
+    /// Moves to a new trusted root, e.g. after a light client verified the next header
+    pub fn set_trusted_root(&self, root: Vec<u8>) {
+        *self.trusted_root.write() = root;
+    }
+
+    pub fn trusted_root(&self) -> Vec<u8> {
+        self.trusted_root.read().clone()
+    }
+
+    /// Gets a single value proven against the trusted root
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut values = self.get_many(&[key.to_vec()]).c(d!())?;
+        values.pop().c(d!("missing value in remote response"))
+    }
+
+    /// Gets the values of `keys` proven against the trusted root
+    pub fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.prove(keys).map(|resp| resp.values)
+    }
+
+    /// Gets the values of `keys` together with the verified proof of them
+    pub fn prove(&self, keys: &[Vec<u8>]) -> Result<RemoteResponse> {
+        self.prove_against(&self.trusted_root(), keys)
+    }
+
+    fn prove_against(&self, root: &[u8], keys: &[Vec<u8>]) -> Result<RemoteResponse> {
+        let resp = self
+            .client
+            .get_with_proof(keys)
+            .c(d!("remote query failed"))?;
+        let proven = self
+            .verifier
+            .verify(root, keys, &resp.proof)
+            .c(d!("invalid proof from remote"))?;
+
+        if proven.len() != keys.len() || proven != resp.values {
+            return Err(eg!("remote values don't match the proof"));
+        }
+        Ok(resp)
+    }
+}
+
+impl<C: RemoteClient, V: ProofVerifier> ReadSource for RemoteDB<C, V> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        RemoteDB::get(self, key)
+    }
+}
+
+/// Heals local reads from the remote node, which must be at the local height
+impl<C: RemoteClient, V: ProofVerifier> MissingNodeResolver for RemoteDB<C, V> {
+    fn resolve(&self, root: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut resp = self.prove_against(root, &[key.to_vec()]).c(d!())?;
+        resp.values.pop().c(d!("missing value in remote response"))
+    }
+}
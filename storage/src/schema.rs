@@ -0,0 +1,125 @@
+/// Key schema registry
+///
+/// Modules register the format of the keys under their prefixes, e.g. a fixed length account
+/// id or `height_index` made of two segments. Debug builds, and release builds with the
+/// `strict_keys` feature, check every key written through a `State` against the rules of the
+/// prefixes it's under, so malformed keys are caught where they are written rather than when a
+/// later iteration fails to parse them.
+///
+use crate::store::Prefix;
+use ruc::*;
+use std::{collections::BTreeMap, fmt, ops::Bound, str, sync::Arc};
+
+/// check of a `KeyRule::Custom`
+pub type KeyCheck = Arc<dyn Fn(&[u8]) -> Result<()> + Send + Sync>;
+
+/// Check of the part of a key after a registered prefix
+#[derive(Clone)]
+pub enum KeyRule {
+    /// valid UTF-8
+    Utf8,
+    /// exactly this many bytes
+    FixedLen(usize),
+    /// `parts` non-empty segments separated by `sep`
+    Segments {
+        sep: u8,
+        parts: usize,
+    },
+    Custom(KeyCheck),
+}
+
+impl fmt::Debug for KeyRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyRule::Utf8 => write!(f, "Utf8"),
+            KeyRule::FixedLen(n) => write!(f, "FixedLen({})", n),
+            KeyRule::Segments { sep, parts } => {
+                write!(
+                    f,
+                    "Segments {{ sep: {:?}, parts: {} }}",
+                    char::from(*sep),
+                    parts
+                )
+            }
+            KeyRule::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+impl KeyRule {
+    pub fn check(&self, rest: &[u8]) -> Result<()> {
+        match self {
+            KeyRule::Utf8 => str::from_utf8(rest).map(|_| ()).c(d!("key is not UTF-8")),
+            KeyRule::FixedLen(n) if rest.len() != *n => Err(eg!(format!(
+                "key is {} bytes long, expected {}",
+                rest.len(),
+                n
+            ))),
+            KeyRule::FixedLen(_) => Ok(()),
+            KeyRule::Segments { sep, parts } => {
+                let segments = rest.split(|b| b == sep).collect::<Vec<_>>();
+                if segments.len() != *parts || segments.iter().any(|s| s.is_empty()) {
+                    return Err(eg!(format!(
+                        "key must be {} non-empty segments separated by {:?}",
+                        parts,
+                        char::from(*sep)
+                    )));
+                }
+                Ok(())
+            }
+            KeyRule::Custom(check) => check(rest),
+        }
+    }
+}
+
+/// Key rules by prefix
+#[derive(Clone, Debug, Default)]
+pub struct KeySchema {
+    rules: BTreeMap<Vec<u8>, Vec<KeyRule>>,
+}
+
+impl KeySchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule for the keys under `prefix`, a key under several prefixes passes all
+    /// of their rules
+    pub fn register(&mut self, prefix: &Prefix, rule: KeyRule) -> &mut Self {
+        self.rules.entry(prefix.begin()).or_default().push(rule);
+        self
+    }
+
+    /// Checks `key` against the rules of every registered prefix it's under
+    pub fn validate(&self, key: &[u8]) -> Result<()> {
+        for (prefix, rules) in self
+            .rules
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+        {
+            if let Some(rest) = key.strip_prefix(prefix.as_slice()) {
+                for rule in rules {
+                    rule.check(rest).c(d!(format!(
+                        "invalid key {} under {}",
+                        String::from_utf8_lossy(key),
+                        String::from_utf8_lossy(prefix)
+                    )))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Registered prefixes in key order, with the rules of each
+    pub fn prefixes(&self) -> impl Iterator<Item = (&[u8], &[KeyRule])> {
+        self.rules.iter().map(|(p, r)| (p.as_slice(), r.as_slice()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+/// Whether written keys are validated in this build
+pub const fn strict_keys() -> bool {
+    cfg!(any(debug_assertions, feature = "strict_keys"))
+}
@@ -0,0 +1,141 @@
+/// Write-ahead session cache over a MerkleDB
+///
+/// `SessionedDB` buffers puts and deletes in a `SessionedCache` on top of a backend, without a
+/// chain state, so the transactions of a block are applied speculatively: a session is kept
+/// with `commit_session` or rolled back with `discard_session`, and `commit` writes the kept
+/// sessions to the backend. Reads and range iterations see the cache merged over the backend.
+///
+use crate::{
+    db::{DbIter, IterOrder, KVBatch, KVEntry, KValue, MerkleDB},
+    state::cache::SessionedCache,
+};
+use ruc::*;
+use std::{cmp::Ordering, iter::Peekable, vec::IntoIter};
+
+/// Buffers the writes of sessions in memory on top of a `MerkleDB`
+pub struct SessionedDB<D: MerkleDB> {
+    db: D,
+    cache: SessionedCache,
+}
+
+impl<D: MerkleDB> SessionedDB<D> {
+    /// Wraps `db`, a merkle backend limits the size of keys and values
+    pub fn new(db: D, is_merkle: bool) -> Self {
+        SessionedDB {
+            db,
+            cache: SessionedCache::new(is_merkle),
+        }
+    }
+
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Unwraps the backend, the writes not committed with `commit` are dropped
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.cache.get(key) {
+            Some(value) => Ok(value),
+            None => self.db.get(key),
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        if self.cache.put(key, value) {
+            Ok(())
+        } else {
+            Err(eg!("Invalid key-value pair detected."))
+        }
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.cache.delete(key);
+    }
+
+    /// Keeps the writes of the current session, they are written by the next `commit`
+    pub fn commit_session(&mut self) {
+        self.cache.commit_only();
+    }
+
+    /// Drops the writes of the current session
+    pub fn discard_session(&mut self) {
+        self.cache.discard();
+    }
+
+    /// Keys in `[lower, upper)` with their values, the cache merged over the backend
+    pub fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> MergedIter<'_, D> {
+        let mut cached = self
+            .cache
+            .keys()
+            .into_iter()
+            .filter(|k| lower <= k.as_slice() && k.as_slice() < upper)
+            .map(|k| {
+                let v = self.cache.getv(&k);
+                (k, v)
+            })
+            .collect::<Vec<_>>();
+        if order == IterOrder::Desc {
+            cached.reverse();
+        }
+        MergedIter {
+            db: &self.db,
+            backend: self.db.iter(lower, upper, order).peekable(),
+            cached: cached.into_iter().peekable(),
+            desc: order == IterOrder::Desc,
+        }
+    }
+
+    /// Writes the committed sessions to the backend along with `aux`
+    ///
+    /// The current session is committed too. Deletes of keys missing in the backend are
+    /// dropped.
+    pub fn commit(&mut self, aux: KVBatch, flush: bool) -> Result<()> {
+        let mut batch = KVBatch::new();
+        for (k, v) in self.cache.commit() {
+            if v.is_some() || self.db.get(&k).c(d!())?.is_some() {
+                batch.push((k, v));
+            }
+        }
+        self.cache = SessionedCache::new(self.cache.is_merkle());
+        self.db.put_batch(batch).c(d!())?;
+        self.db.commit(aux, flush).c(d!())
+    }
+}
+
+/// Iterator over the cache of a `SessionedDB` merged with its backend
+///
+/// Cached values override the ones of the backend, deleted keys are skipped.
+pub struct MergedIter<'a, D: MerkleDB> {
+    db: &'a D,
+    backend: Peekable<DbIter<'a>>,
+    cached: Peekable<IntoIter<KVEntry>>,
+    desc: bool,
+}
+
+impl<'a, D: MerkleDB> Iterator for MergedIter<'a, D> {
+    type Item = KValue;
+
+    fn next(&mut self) -> Option<KValue> {
+        loop {
+            let ordering = match (self.backend.peek(), self.cached.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((k, _)), Some((ck, _))) if self.desc => ck.as_slice().cmp(k),
+                (Some((k, _)), Some((ck, _))) => k.as_ref().cmp(ck.as_slice()),
+            };
+            if ordering == Ordering::Less {
+                return self.backend.next().map(|kv| self.db.decode_kv(kv));
+            }
+            if ordering == Ordering::Equal {
+                self.backend.next();
+            }
+            if let Some((k, Some(v))) = self.cached.next() {
+                return Some((k, v));
+            }
+        }
+    }
+}
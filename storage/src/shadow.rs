@@ -0,0 +1,295 @@
+/// Dual-write shadow mode for backend migrations
+///
+/// `ShadowDB` applies every write to a primary and a secondary backend, serves all reads from
+/// the primary and compares them with the secondary. Operators can run a new backend in
+/// production next to the old one and cut over once no mismatch shows up.
+///
+use crate::{
+    db::{
+        DbIter, DbTryIter, IterOpts, IterOrder, KVBatch, KVEntryRef, KValue, MerkleDB, ScanControl,
+    },
+    merge::MergeOp,
+    snapshot::SnapshotOptions,
+};
+use parking_lot::Mutex;
+use ruc::*;
+use std::{ops::Bound, path::Path};
+
+/// max number of mismatched keys kept in a report
+const MAX_MISMATCHES: usize = 0x100;
+
+/// Where a shadow comparison failed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// values of a key in the primary section differ
+    Get(Vec<u8>),
+    /// values of a key in the aux section differ
+    GetAux(Vec<u8>),
+    /// root hashes differ after a commit
+    RootHash,
+}
+
+/// Comparison results of a `ShadowDB`
+#[derive(Clone, Debug, Default)]
+pub struct ShadowReport {
+    /// number of compared reads
+    pub reads: u64,
+    /// number of compared commits
+    pub commits: u64,
+    /// total number of mismatches
+    pub mismatches: u64,
+    /// number of failed operations of the secondary
+    pub secondary_errors: u64,
+    /// the first mismatches found
+    pub recent: Vec<Mismatch>,
+}
+
+/// MerkleDB writing to two backends and comparing their reads
+///
+/// Failures of the secondary are counted but never fail an operation.
+pub struct ShadowDB<P: MerkleDB, S: MerkleDB> {
+    primary: P,
+    secondary: S,
+    compare_roots: bool,
+    report: Mutex<ShadowReport>,
+}
+
+impl<P: MerkleDB, S: MerkleDB> ShadowDB<P, S> {
+    pub fn new(primary: P, secondary: S) -> Self {
+        ShadowDB {
+            primary,
+            secondary,
+            compare_roots: false,
+            report: Mutex::new(ShadowReport::default()),
+        }
+    }
+
+    /// Compares root hashes after each commit, only if both backends use the same merkle tree
+    pub fn with_root_check(mut self, compare: bool) -> Self {
+        self.compare_roots = compare;
+        self
+    }
+
+    /// Returns the comparison results so far
+    pub fn report(&self) -> ShadowReport {
+        self.report.lock().clone()
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    /// Splits into both backends, e.g. to cut over to the secondary
+    pub fn into_inner(self) -> (P, S) {
+        (self.primary, self.secondary)
+    }
+
+    fn compare_read(
+        &self,
+        primary: &Result<Option<Vec<u8>>>,
+        secondary: Result<Option<Vec<u8>>>,
+        mismatch: impl FnOnce() -> Mismatch,
+    ) {
+        let mut report = self.report.lock();
+        report.reads = report.reads.saturating_add(1);
+        match (primary, secondary) {
+            (Ok(p), Ok(s)) if *p != s => Self::record(&mut report, mismatch()),
+            (Ok(_), Err(_)) => report.secondary_errors = report.secondary_errors.saturating_add(1),
+            _ => {}
+        }
+    }
+
+    fn record(report: &mut ShadowReport, mismatch: Mismatch) {
+        report.mismatches = report.mismatches.saturating_add(1);
+        if report.recent.len() < MAX_MISMATCHES {
+            report.recent.push(mismatch);
+        }
+    }
+
+    fn secondary_error(&self) {
+        let mut report = self.report.lock();
+        report.secondary_errors = report.secondary_errors.saturating_add(1);
+    }
+}
+
+impl<P: MerkleDB, S: MerkleDB> MerkleDB for ShadowDB<P, S> {
+    fn root_hash(&self) -> Vec<u8> {
+        self.primary.root_hash()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.primary.get(key);
+        self.compare_read(&value, self.secondary.get(key), || {
+            Mismatch::Get(key.to_vec())
+        });
+        value
+    }
+
+    fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.primary.get_aux(key);
+        self.compare_read(&value, self.secondary.get_aux(key), || {
+            Mismatch::GetAux(key.to_vec())
+        });
+        value
+    }
+
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        let len = self.primary.get_into(key, buf);
+        if let Ok(len) = len.as_ref() {
+            let value = Ok(len.map(|_| buf.clone()));
+            self.compare_read(&value, self.secondary.get(key), || {
+                Mismatch::Get(key.to_vec())
+            });
+        }
+        len
+    }
+
+    fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
+        if self.secondary.put_batch(kvs.clone()).is_err() {
+            self.secondary_error();
+        }
+        self.primary.put_batch(kvs)
+    }
+
+    fn put_batch_ref(&mut self, kvs: &[KVEntryRef<'_>]) -> Result<()> {
+        if self.secondary.put_batch_ref(kvs).is_err() {
+            self.secondary_error();
+        }
+        self.primary.put_batch_ref(kvs)
+    }
+
+    fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.primary.iter(lower, upper, order)
+    }
+
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbIter<'_> {
+        self.primary.iter_with_opts(lower, upper, order, opts)
+    }
+
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        self.primary.iter_bounds(lower, upper, order)
+    }
+
+    fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        self.primary.scan_apply(lower, upper, order, f)
+    }
+
+    fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.primary.iter_aux(lower, upper, order)
+    }
+
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.primary.try_iter(lower, upper, order)
+    }
+
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.primary.try_iter_aux(lower, upper, order)
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        self.primary.db_all_iterator(order)
+    }
+
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        self.primary.db_all_iterator_aux(order)
+    }
+
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.primary.try_db_all_iterator(order)
+    }
+
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.primary.try_db_all_iterator_aux(order)
+    }
+
+    fn has_proofs(&self) -> bool {
+        self.primary.has_proofs()
+    }
+
+    fn prove_keys(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        self.primary.prove_keys(keys)
+    }
+
+    fn prove_range(&self, lower: &[u8], upper: &[u8]) -> Result<Vec<u8>> {
+        self.primary.prove_range(lower, upper)
+    }
+
+    fn verify_entry(&self, kv_pair: &(Box<[u8]>, Box<[u8]>)) -> Result<()> {
+        self.primary.verify_entry(kv_pair)
+    }
+
+    fn compact_range(&self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        if self.secondary.compact_range(lower, upper).is_err() {
+            self.secondary_error();
+        }
+        self.primary.compact_range(lower, upper)
+    }
+
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        if self.secondary.delete_range(lower, upper).is_err() {
+            self.secondary_error();
+        }
+        self.primary.delete_range(lower, upper)
+    }
+
+    fn merge(&mut self, key: &[u8], patch: &[u8], op: MergeOp) -> Result<()> {
+        if self.secondary.merge(key, patch, op).is_err() {
+            self.secondary_error();
+        }
+        self.primary.merge(key, patch, op)
+    }
+
+    fn commit(&mut self, kvs: KVBatch, flush: bool) -> Result<()> {
+        if self.secondary.commit(kvs.clone(), flush).is_err() {
+            self.secondary_error();
+        }
+        self.primary.commit(kvs, flush).c(d!())?;
+
+        let mut report = self.report.lock();
+        report.commits = report.commits.saturating_add(1);
+        if self.compare_roots && self.primary.root_hash() != self.secondary.root_hash() {
+            Self::record(&mut report, Mismatch::RootHash);
+        }
+        Ok(())
+    }
+
+    fn snapshot<P2: AsRef<Path>>(&self, path: P2) -> Result<()> {
+        self.primary.snapshot(path)
+    }
+
+    fn snapshot_with_opts<P2: AsRef<Path>>(&self, path: P2, opts: &SnapshotOptions) -> Result<()> {
+        self.primary.snapshot_with_opts(path, opts)
+    }
+
+    fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue {
+        self.primary.decode_kv(kv_pair)
+    }
+
+    fn clean_aux(&mut self) -> Result<()> {
+        if self.secondary.clean_aux().is_err() {
+            self.secondary_error();
+        }
+        self.primary.clean_aux()
+    }
+}
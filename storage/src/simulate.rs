@@ -0,0 +1,450 @@
+/// Storage simulator for capacity planning
+///
+/// Projects disk usage, compaction load and commit latency of a synthetic workload on each
+/// backend, e.g. before launching a new chain. The projection is analytic: every block applies
+/// the expected number of inserts, updates and deletes to a cost model of the backend, so runs
+/// are instant and reproducible. The default models are rough estimates, operators should tune
+/// them with numbers measured on their own hardware.
+///
+/// Updates are spread over the live keys as per the `AccessDist` of the workload. Skewed
+/// accesses update the same hot keys several times in a block, which are written once.
+///
+use std::fmt;
+
+// width growth of the buckets of ranks summed by `rank_buckets`
+const RANK_BUCKET_GROWTH: f64 = 1.5;
+
+/// Distribution of value sizes in bytes
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueSizeDist {
+    Fixed(u64),
+    /// uniformly distributed in `[min, max]`
+    Uniform {
+        min: u64,
+        max: u64,
+    },
+    /// mostly `small` values, `large_share` of them are `large`
+    Bimodal {
+        small: u64,
+        large: u64,
+        large_share: f64,
+    },
+}
+
+impl ValueSizeDist {
+    /// Expected value size
+    pub fn mean(&self) -> f64 {
+        match *self {
+            ValueSizeDist::Fixed(size) => size as f64,
+            ValueSizeDist::Uniform { min, max } => (min as f64 + max as f64) / 2.0,
+            ValueSizeDist::Bimodal {
+                small,
+                large,
+                large_share,
+            } => {
+                let share = large_share.clamp(0.0, 1.0);
+                small as f64 * (1.0 - share) + large as f64 * share
+            }
+        }
+    }
+}
+
+/// Distribution of the accessed keys among the live keys
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum AccessDist {
+    /// every key is as likely
+    #[default]
+    Uniform,
+    /// the key of rank `i` is accessed with a probability proportional to `1 / i^theta`, a
+    /// few hot keys get most of the accesses; `theta` is in `[0, 1)`, e.g. 0.99 like YCSB
+    Zipfian { theta: f64 },
+}
+
+impl AccessDist {
+    /// Expected number of distinct keys among `draws` accesses to `keys` keys
+    pub fn expected_distinct(&self, keys: f64, draws: f64) -> f64 {
+        if keys < 1.0 || draws <= 0.0 {
+            return 0.0;
+        }
+        // chance of a key of probability `p` being drawn at least once
+        let drawn = |p: f64| -(draws * (-p).ln_1p()).exp_m1();
+        match *self {
+            AccessDist::Uniform => keys * drawn(1.0 / keys),
+            AccessDist::Zipfian { theta } => {
+                let norm = zeta(keys, theta);
+                rank_buckets(keys)
+                    .into_iter()
+                    .map(|(rank, count)| count * drawn(rank.powf(-theta) / norm))
+                    .sum()
+            }
+        }
+    }
+}
+
+/// Sum of `1 / i^theta` for the ranks `i` in `1..=keys`, approximated over buckets of ranks
+pub(crate) fn zeta(keys: f64, theta: f64) -> f64 {
+    rank_buckets(keys)
+        .into_iter()
+        .map(|(rank, count)| count * rank.powf(-theta))
+        .sum()
+}
+
+// ranks `1..=keys` in buckets of growing width, a representative rank and the number of
+// ranks per bucket; the first ranks, which weigh the most, get a bucket each
+fn rank_buckets(keys: f64) -> Vec<(f64, f64)> {
+    let mut buckets = vec![];
+    let mut start = 1.0_f64;
+    while start <= keys {
+        let end = (start * RANK_BUCKET_GROWTH)
+            .floor()
+            .max(start + 1.0)
+            .min(keys.floor() + 1.0);
+        buckets.push(((start * (end - 1.0)).sqrt(), end - start));
+        start = end;
+    }
+    buckets
+}
+
+/// Synthetic workload parameters
+#[derive(Clone, Debug)]
+pub struct Workload {
+    /// number of simulated blocks
+    pub blocks: u64,
+    /// number of written keys per block
+    pub keys_per_block: u64,
+    pub key_size: u64,
+    pub value_size: ValueSizeDist,
+    /// share of written keys overwriting an existing key
+    pub update_rate: f64,
+    /// share of written keys deleting an existing key
+    pub delete_rate: f64,
+    /// how updates are spread over the live keys
+    pub access: AccessDist,
+    /// number of keys present before the first block
+    pub initial_keys: u64,
+    /// a projection point is recorded every this many blocks
+    pub sample_every: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            blocks: 100_000,
+            keys_per_block: 1_000,
+            key_size: 32,
+            value_size: ValueSizeDist::Fixed(128),
+            update_rate: 0.5,
+            delete_rate: 0.05,
+            access: AccessDist::Uniform,
+            initial_keys: 0,
+            sample_every: 10_000,
+        }
+    }
+}
+
+/// Cost model of a backend
+#[derive(Clone, Debug)]
+pub struct BackendModel {
+    pub name: &'static str,
+    /// stored bytes per live key on top of its key and value, e.g. hashes and child links
+    pub entry_overhead: f64,
+    /// whether a write rewrites the path from the key to the root, like a merkle tree does
+    pub rewrites_path: bool,
+    /// whether data is written to disk at all
+    pub persistent: bool,
+    /// size ratio of two adjacent LSM levels
+    pub level_multiplier: f64,
+    /// size of the first LSM level in bytes
+    pub base_level_bytes: f64,
+    /// space used by obsolete versions not compacted yet, as a share of the live data
+    pub space_amplification: f64,
+    /// fixed cost of a commit in microseconds
+    pub commit_base_us: f64,
+    /// cost of hashing a tree node in microseconds
+    pub hash_node_us: f64,
+    /// sustained write bandwidth in bytes per microsecond
+    pub write_bytes_per_us: f64,
+}
+
+impl BackendModel {
+    /// Merk tree on RocksDB
+    pub fn fin_db() -> Self {
+        BackendModel {
+            name: "FinDB",
+            entry_overhead: 2.0 * (32.0 + 32.0) + 32.0 + 16.0,
+            rewrites_path: true,
+            ..Self::rocks_db()
+        }
+    }
+
+    /// Plain RocksDB
+    pub fn rocks_db() -> Self {
+        BackendModel {
+            name: "RocksDB",
+            entry_overhead: 16.0,
+            rewrites_path: false,
+            persistent: true,
+            level_multiplier: 10.0,
+            base_level_bytes: 256.0 * 1024.0 * 1024.0,
+            space_amplification: 0.1,
+            commit_base_us: 200.0,
+            hash_node_us: 0.5,
+            write_bytes_per_us: 200.0,
+        }
+    }
+
+    /// In-memory db, nothing is written to disk
+    pub fn memory_db() -> Self {
+        BackendModel {
+            name: "MemoryDB",
+            entry_overhead: 48.0,
+            rewrites_path: false,
+            persistent: false,
+            level_multiplier: 0.0,
+            base_level_bytes: 0.0,
+            space_amplification: 0.0,
+            commit_base_us: 5.0,
+            hash_node_us: 0.0,
+            write_bytes_per_us: 2000.0,
+        }
+    }
+
+    /// Models of all backends of this workspace
+    pub fn all() -> Vec<Self> {
+        vec![Self::fin_db(), Self::rocks_db(), Self::memory_db()]
+    }
+
+    // number of LSM levels holding `bytes`
+    fn levels(&self, bytes: f64) -> f64 {
+        if !self.persistent || bytes <= self.base_level_bytes || self.level_multiplier <= 1.0 {
+            return 1.0;
+        }
+        1.0 + (bytes / self.base_level_bytes)
+            .log(self.level_multiplier)
+            .ceil()
+    }
+
+    /// Bytes rewritten by compaction per written byte, including the write-ahead log
+    pub fn write_amplification(&self, bytes: f64) -> f64 {
+        if !self.persistent {
+            return 0.0;
+        }
+        // a byte is written to the log and the first level, then rewritten about
+        // `multiplier / 2` times in every deeper level
+        2.0 + (self.levels(bytes) - 1.0) * self.level_multiplier / 2.0
+    }
+}
+
+/// State of the simulated db after a block
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProjectionPoint {
+    pub block: u64,
+    pub live_keys: u64,
+    /// bytes of the live data including backend overhead
+    pub live_bytes: u64,
+    /// projected size on disk
+    pub disk_bytes: u64,
+    /// bytes written by flushes and compactions in this block
+    pub compaction_bytes: u64,
+    /// projected commit latency of this block in microseconds
+    pub commit_latency_us: u64,
+}
+
+/// Projection of a workload on a backend
+#[derive(Clone, Debug)]
+pub struct Projection {
+    pub backend: &'static str,
+    /// sampled points, the last one is the final block
+    pub points: Vec<ProjectionPoint>,
+    /// total bytes written by flushes and compactions
+    pub total_compaction_bytes: u64,
+    /// highest projected commit latency in microseconds
+    pub max_commit_latency_us: u64,
+}
+
+impl Projection {
+    /// Projected state after the last block
+    pub fn last(&self) -> ProjectionPoint {
+        self.points.last().cloned().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Projection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.last();
+        write!(
+            f,
+            "{}: {} keys, {} MB on disk, {} MB compacted, max commit {} ms",
+            self.backend,
+            last.live_keys,
+            last.disk_bytes >> 20,
+            self.total_compaction_bytes >> 20,
+            self.max_commit_latency_us / 1000
+        )
+    }
+}
+
+/// Projects `workload` on the given backend model
+pub fn simulate(workload: &Workload, model: &BackendModel) -> Projection {
+    let entry_bytes = workload.key_size as f64 + workload.value_size.mean() + model.entry_overhead;
+    let update_rate = workload.update_rate.clamp(0.0, 1.0);
+    let delete_rate = workload.delete_rate.clamp(0.0, 1.0 - update_rate);
+    let writes = workload.keys_per_block as f64;
+    let sample_every = workload.sample_every.max(1);
+
+    let mut live = workload.initial_keys as f64;
+    let mut projection = Projection {
+        backend: model.name,
+        points: vec![],
+        total_compaction_bytes: 0,
+        max_commit_latency_us: 0,
+    };
+
+    for block in 1..=workload.blocks {
+        // updates and deletes need existing keys, a young db gets inserts instead
+        let updates = (writes * update_rate).min(live);
+        let deletes = (writes * delete_rate).min(live - updates);
+        let inserts = writes - updates - deletes;
+        // a key updated several times in a block is written once
+        let written_keys = inserts + deletes + workload.access.expected_distinct(live, updates);
+        live += inserts - deletes;
+
+        // a merkle tree rewrites the nodes on the paths of all written keys, paths of
+        // keys in the same batch share their top levels
+        let nodes = if model.rewrites_path && live >= 1.0 {
+            let depth = live.log2().ceil().max(1.0);
+            let shared = written_keys.log2().max(0.0);
+            written_keys * (1.0 + (depth - shared).max(0.0)) + written_keys.min(live)
+        } else {
+            written_keys
+        };
+        let written = nodes * entry_bytes;
+
+        let live_bytes = live * entry_bytes;
+        let disk_bytes = if model.persistent {
+            live_bytes * (1.0 + model.space_amplification)
+        } else {
+            0.0
+        };
+        let compaction = written * model.write_amplification(live_bytes);
+        let latency = model.commit_base_us
+            + nodes * model.hash_node_us
+            + written / model.write_bytes_per_us.max(f64::MIN_POSITIVE);
+
+        let latency_us = latency as u64;
+        projection.total_compaction_bytes = projection
+            .total_compaction_bytes
+            .saturating_add(compaction as u64);
+        projection.max_commit_latency_us = projection.max_commit_latency_us.max(latency_us);
+
+        if block % sample_every == 0 || block == workload.blocks {
+            projection.points.push(ProjectionPoint {
+                block,
+                live_keys: live as u64,
+                live_bytes: live_bytes as u64,
+                disk_bytes: disk_bytes as u64,
+                compaction_bytes: compaction as u64,
+                commit_latency_us: latency_us,
+            });
+        }
+    }
+    projection
+}
+
+/// Projects `workload` on the models of all backends
+pub fn simulate_all(workload: &Workload) -> Vec<Projection> {
+    BackendModel::all()
+        .iter()
+        .map(|model| simulate(workload, model))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{simulate, simulate_all, zeta, AccessDist, BackendModel, ValueSizeDist, Workload};
+
+    fn workload() -> Workload {
+        Workload {
+            blocks: 1000,
+            keys_per_block: 100,
+            key_size: 32,
+            value_size: ValueSizeDist::Uniform { min: 64, max: 192 },
+            update_rate: 0.5,
+            delete_rate: 0.1,
+            access: AccessDist::Uniform,
+            initial_keys: 0,
+            sample_every: 100,
+        }
+    }
+
+    #[test]
+    fn value_size_mean() {
+        assert_eq!(ValueSizeDist::Fixed(100).mean(), 100.0);
+        assert_eq!(ValueSizeDist::Uniform { min: 0, max: 10 }.mean(), 5.0);
+        let bimodal = ValueSizeDist::Bimodal {
+            small: 10,
+            large: 110,
+            large_share: 0.1,
+        };
+        assert!((bimodal.mean() - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn simulate_growth() {
+        let w = workload();
+        let p = simulate(&w, &BackendModel::rocks_db());
+        assert_eq!(p.points.len(), 10);
+        assert_eq!(p.last().block, 1000);
+
+        // the first block only inserts, then 40% of the writes are inserts and 10% deletes
+        let last = p.last();
+        assert_eq!(last.live_keys, 100 + 999 * 30);
+        assert!(p
+            .points
+            .windows(2)
+            .all(|w| w[0].disk_bytes < w[1].disk_bytes));
+
+        // same input, same projection
+        let again = simulate(&w, &BackendModel::rocks_db());
+        assert_eq!(p.points, again.points);
+    }
+
+    #[test]
+    fn simulate_backends() {
+        let projections = simulate_all(&workload());
+        assert_eq!(projections.len(), 3);
+        let (fin, rocks, mem) = (&projections[0], &projections[1], &projections[2]);
+
+        // the merkle tree stores and rewrites more than the plain kv store
+        assert!(fin.last().disk_bytes > rocks.last().disk_bytes);
+        assert!(fin.total_compaction_bytes > rocks.total_compaction_bytes);
+        assert!(fin.max_commit_latency_us >= rocks.max_commit_latency_us);
+
+        assert_eq!(mem.last().disk_bytes, 0);
+        assert_eq!(mem.total_compaction_bytes, 0);
+        assert_eq!(mem.last().live_keys, rocks.last().live_keys);
+    }
+
+    #[test]
+    fn access_distinct() {
+        // the bucketed sum is close to the exact one
+        let exact = (1..=1000).map(|i| (i as f64).powf(-0.99)).sum::<f64>();
+        assert!((zeta(1000.0, 0.99) - exact).abs() / exact < 0.01);
+
+        let uniform = AccessDist::Uniform.expected_distinct(1000.0, 1000.0);
+        assert!((uniform - 1000.0 * (1.0 - (-1.0f64).exp())).abs() < 1.0);
+        let zipfian = AccessDist::Zipfian { theta: 0.99 }.expected_distinct(1000.0, 1000.0);
+        assert!(zipfian < uniform * 0.7);
+        assert_eq!(AccessDist::Uniform.expected_distinct(0.0, 10.0), 0.0);
+
+        // hot keys are rewritten less often than spread updates, not fewer keys are live
+        let skewed = Workload {
+            access: AccessDist::Zipfian { theta: 0.99 },
+            ..workload()
+        };
+        let uniform = simulate(&workload(), &BackendModel::fin_db());
+        let zipfian = simulate(&skewed, &BackendModel::fin_db());
+        assert_eq!(zipfian.last().live_keys, uniform.last().live_keys);
+        assert!(zipfian.total_compaction_bytes < uniform.total_compaction_bytes);
+    }
+}
@@ -0,0 +1,857 @@
+/// Snapshot headers and listing
+///
+/// Every snapshot taken by a chain state gets a header next to it, `<snapshot>.meta`, holding
+/// its height, root hash, creation time and format. Backup managers, state-sync servers and
+/// tools list the available snapshots from their headers instead of parsing file names.
+///
+/// The header of a snapshot of a filtered export also records the prefix filter, so whoever
+/// receives it knows which parts of the state were left out.
+///
+/// Snapshots are written in a container: a magic, the container version, the backend which
+/// wrote it, the length of the payload, the payload and a sha256 checksum of all of it. A
+/// backend snapshotting a directory writes the container next to its files, listing their
+/// checksums. Opening a truncated file, or a snapshot of another backend, fails before
+/// anything is decoded.
+///
+/// Snapshots of MemoryDB, and portable snapshots of any backend, hold key/value records
+/// rather than the internal structures of a backend, see `portable`.
+///
+/// Backends writing their snapshots as a single file, e.g. MemoryDB, compress them with the
+/// codec of the `SnapshotOptions`. A compressed snapshot is wrapped in an envelope, the
+/// magic, the codec, the uncompressed length as a big endian u64 and the compressed bytes, so
+/// it's decompressed on open whatever codec it was written with. zstd needs the
+/// `compression` feature, lz4 the `lz4` feature.
+///
+use crate::{hex, layout::CRATE_VERSION};
+use ruc::*;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// format of snapshots written by the backend's own `snapshot`, e.g. a RocksDB checkpoint
+pub const CHECKPOINT_FORMAT: &str = "checkpoint";
+
+const HEADER_SUFFIX: &str = ".meta";
+// a header is a small JSON object, mostly its filter prefixes
+const MAX_HEADER_LEN: u64 = 1 << 20;
+
+/// version of the snapshot container format
+pub const CONTAINER_VERSION: u32 = 1;
+/// container of a snapshot taken as a directory, e.g. a RocksDB checkpoint
+pub const CONTAINER_FILE: &str = "SNAPSHOT";
+
+const CONTAINER_MAGIC: &[u8; 8] = b"STORSNAP";
+// magic, version, backend name length and payload length
+const CONTAINER_HEAD_LEN: usize = 21;
+const CHECKSUM_LEN: usize = 32;
+const COMPRESSED_MAGIC: &[u8; 8] = b"SNAPCMP1";
+// magic, codec and uncompressed length
+const ENVELOPE_LEN: usize = 17;
+const STREAM_BUF_LEN: usize = 0x0001_0000;
+// lz4 doesn't compress more than 255 to 1
+#[cfg(feature = "lz4")]
+const LZ4_MAX_RATIO: usize = 255;
+// zstd doesn't compress more than a block of 128KiB into 4 bytes, an RLE block
+#[cfg(feature = "compression")]
+const ZSTD_MAX_RATIO: usize = 0x8000;
+
+/// Compression codec of a snapshot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SnapshotCodec {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl SnapshotCodec {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotCodec::None => "none",
+            SnapshotCodec::Zstd => "zstd",
+            SnapshotCodec::Lz4 => "lz4",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "none" => Ok(SnapshotCodec::None),
+            "zstd" => Ok(SnapshotCodec::Zstd),
+            "lz4" => Ok(SnapshotCodec::Lz4),
+            _ => Err(eg!(format!("unknown snapshot codec {}", name))),
+        }
+    }
+
+    #[cfg(any(feature = "compression", feature = "lz4"))]
+    fn tag(&self) -> u8 {
+        match self {
+            SnapshotCodec::None => 0x00,
+            SnapshotCodec::Zstd => 0x01,
+            SnapshotCodec::Lz4 => 0x02,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0x00 => Ok(SnapshotCodec::None),
+            0x01 => Ok(SnapshotCodec::Zstd),
+            0x02 => Ok(SnapshotCodec::Lz4),
+            _ => Err(eg!("unknown snapshot codec")),
+        }
+    }
+}
+
+/// Options of a snapshot, see `MerkleDB::snapshot_with_opts`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotOptions {
+    pub codec: SnapshotCodec,
+    /// compression level, zstd only
+    pub level: i32,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        SnapshotOptions {
+            codec: SnapshotCodec::None,
+            level: 3,
+        }
+    }
+}
+
+impl SnapshotOptions {
+    /// Options of an uncompressed snapshot
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_codec(mut self, codec: SnapshotCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+}
+
+/// Compresses the bytes of a snapshot as per `opts`, they're returned as is without a codec
+pub fn compress_snapshot(bytes: Vec<u8>, opts: &SnapshotOptions) -> Result<Vec<u8>> {
+    match opts.codec {
+        SnapshotCodec::None => Ok(bytes),
+        #[cfg(feature = "compression")]
+        SnapshotCodec::Zstd => {
+            let compressed = zstd::bulk::compress(&bytes, opts.level).c(d!())?;
+            let mut out = Vec::with_capacity(compressed.len() + ENVELOPE_LEN);
+            write_envelope(&mut out, opts.codec, bytes.len() as u64).c(d!())?;
+            out.extend_from_slice(&compressed);
+            Ok(out)
+        }
+        #[cfg(feature = "lz4")]
+        SnapshotCodec::Lz4 => {
+            let compressed = lz4_flex::compress(&bytes);
+            let mut out = Vec::with_capacity(compressed.len() + ENVELOPE_LEN);
+            write_envelope(&mut out, opts.codec, bytes.len() as u64).c(d!())?;
+            out.extend_from_slice(&compressed);
+            Ok(out)
+        }
+        #[allow(unreachable_patterns)]
+        codec => Err(eg!(format!("{} support is not enabled", codec.as_str()))),
+    }
+}
+
+// writes the magic, the codec and the uncompressed length heading a compressed snapshot
+#[cfg(any(feature = "compression", feature = "lz4"))]
+fn write_envelope<W: Write + ?Sized>(writer: &mut W, codec: SnapshotCodec, len: u64) -> Result<()> {
+    writer.write_all(COMPRESSED_MAGIC).c(d!())?;
+    writer.write_all(&[codec.tag()]).c(d!())?;
+    writer.write_all(&len.to_be_bytes()).c(d!())
+}
+
+/// Decompresses the bytes of a snapshot written by `compress_snapshot`, uncompressed bytes are
+/// returned as is
+///
+/// The uncompressed length recorded in the snapshot isn't trusted for allocations, a length
+/// above the max ratio of the codec is rejected before decompressing and zstd is decompressed
+/// as a stream up to it.
+pub fn decompress_snapshot(bytes: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let rest = match bytes.strip_prefix(COMPRESSED_MAGIC.as_slice()) {
+        Some(rest) => rest,
+        None => return Ok(Cow::Borrowed(bytes)),
+    };
+    let (tag, rest) = rest.split_first().c(d!("truncated snapshot"))?;
+    let len = rest
+        .get(..8)
+        .and_then(|len| <[u8; 8]>::try_from(len).ok())
+        .c(d!("truncated snapshot"))?;
+    let len = usize::try_from(u64::from_be_bytes(len)).c(d!("snapshot too large"))?;
+    let payload = rest.get(8..).c(d!("truncated snapshot"))?;
+    let decompressed = match SnapshotCodec::from_tag(*tag).c(d!())? {
+        SnapshotCodec::None => payload.to_vec(),
+        #[cfg(feature = "compression")]
+        SnapshotCodec::Zstd => {
+            if len > payload.len().saturating_mul(ZSTD_MAX_RATIO) {
+                return Err(eg!(format!(
+                    "zstd snapshot of {} bytes can't hold {} bytes",
+                    payload.len(),
+                    len
+                )));
+            }
+            // one byte more than recorded reveals a longer snapshot
+            let limit = (len as u64).saturating_add(1);
+            let mut out = vec![];
+            zstd::stream::read::Decoder::with_buffer(payload)
+                .c(d!("invalid zstd snapshot"))?
+                .take(limit)
+                .read_to_end(&mut out)
+                .c(d!("invalid zstd snapshot"))?;
+            out
+        }
+        #[cfg(feature = "lz4")]
+        SnapshotCodec::Lz4 => decompress_lz4(payload, len).c(d!())?,
+        #[allow(unreachable_patterns)]
+        codec => return Err(eg!(format!("{} support is not enabled", codec.as_str()))),
+    };
+    if decompressed.len() != len {
+        return Err(eg!("decompressed snapshot length doesn't match"));
+    }
+    Ok(Cow::Owned(decompressed))
+}
+
+#[cfg(feature = "lz4")]
+fn decompress_lz4(payload: &[u8], len: usize) -> Result<Vec<u8>> {
+    if len > payload.len().saturating_mul(LZ4_MAX_RATIO) {
+        return Err(eg!(format!(
+            "lz4 snapshot of {} bytes can't hold {} bytes",
+            payload.len(),
+            len
+        )));
+    }
+    lz4_flex::decompress(payload, len).c(d!("invalid lz4 snapshot"))
+}
+
+/// Compresses the `len` bytes of `reader` into `writer` as per `opts`, like
+/// `compress_snapshot` without holding them in memory
+///
+/// lz4 compresses a single block, the bytes are read in memory for it.
+#[cfg_attr(
+    not(any(feature = "compression", feature = "lz4")),
+    allow(unused_variables)
+)]
+pub fn compress_snapshot_stream<R: Read, W: Write + ?Sized>(
+    reader: &mut R,
+    len: u64,
+    opts: &SnapshotOptions,
+    writer: &mut W,
+) -> Result<()> {
+    match opts.codec {
+        SnapshotCodec::None => io::copy(reader, writer).map(|_| ()).c(d!()),
+        #[cfg(feature = "compression")]
+        SnapshotCodec::Zstd => {
+            write_envelope(writer, opts.codec, len).c(d!())?;
+            zstd::stream::copy_encode(reader, writer, opts.level).c(d!())
+        }
+        #[cfg(feature = "lz4")]
+        SnapshotCodec::Lz4 => {
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes).c(d!())?;
+            write_envelope(writer, opts.codec, len).c(d!())?;
+            writer.write_all(&lz4_flex::compress(&bytes)).c(d!())
+        }
+        #[allow(unreachable_patterns)]
+        codec => Err(eg!(format!("{} support is not enabled", codec.as_str()))),
+    }
+}
+
+/// Decompresses a snapshot written by `compress_snapshot` as it's read from `reader`,
+/// uncompressed bytes are read as is
+///
+/// Reading fails once more or less bytes than the recorded length are decompressed.
+pub fn decompress_snapshot_reader<'a, R: Read + 'a>(mut reader: R) -> Result<Box<dyn Read + 'a>> {
+    let mut magic = Vec::with_capacity(COMPRESSED_MAGIC.len());
+    (&mut reader)
+        .take(COMPRESSED_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .c(d!())?;
+    if magic != COMPRESSED_MAGIC {
+        return Ok(Box::new(io::Cursor::new(magic).chain(reader)));
+    }
+    let mut head = [0; ENVELOPE_LEN - COMPRESSED_MAGIC.len()];
+    reader.read_exact(&mut head).c(d!("truncated snapshot"))?;
+    let (tag, len) = head.split_first().c(d!())?;
+    let len = u64::from_be_bytes(<[u8; 8]>::try_from(len).c(d!())?);
+    let decompressed: Box<dyn Read + 'a> = match SnapshotCodec::from_tag(*tag).c(d!())? {
+        SnapshotCodec::None => Box::new(reader),
+        #[cfg(feature = "compression")]
+        SnapshotCodec::Zstd => {
+            Box::new(zstd::stream::read::Decoder::new(reader).c(d!("invalid zstd snapshot"))?)
+        }
+        #[cfg(feature = "lz4")]
+        SnapshotCodec::Lz4 => {
+            let mut payload = vec![];
+            reader.read_to_end(&mut payload).c(d!())?;
+            let len = usize::try_from(len).c(d!("snapshot too large"))?;
+            Box::new(io::Cursor::new(decompress_lz4(&payload, len).c(d!())?))
+        }
+        #[allow(unreachable_patterns)]
+        codec => return Err(eg!(format!("{} support is not enabled", codec.as_str()))),
+    };
+    Ok(Box::new(LengthChecked {
+        reader: decompressed,
+        left: len,
+    }))
+}
+
+// Fails once the bytes read are more or less than those recorded
+struct LengthChecked<R> {
+    reader: R,
+    left: u64,
+}
+
+impl<R: Read> Read for LengthChecked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.reader.read(buf)?;
+        if (n == 0 && self.left != 0) || n as u64 > self.left {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decompressed snapshot length doesn't match",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Wraps the bytes of a snapshot of `backend` in a container, see `open_container`
+pub fn seal_container(backend: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    let backend_len = u8::try_from(backend.len()).c(d!("backend name too long"))?;
+    let mut out =
+        Vec::with_capacity(CONTAINER_HEAD_LEN + backend.len() + payload.len() + CHECKSUM_LEN);
+    out.extend_from_slice(CONTAINER_MAGIC);
+    out.extend_from_slice(&CONTAINER_VERSION.to_be_bytes());
+    out.push(backend_len);
+    out.extend_from_slice(backend.as_bytes());
+    out.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    out.extend_from_slice(payload);
+    let checksum = Sha256::digest(&out);
+    out.extend_from_slice(&checksum);
+    Ok(out)
+}
+
+/// Whether `bytes` start like a snapshot container
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(CONTAINER_MAGIC)
+}
+
+/// Backend recorded in a container, `None` if `bytes` don't start like one
+///
+/// Nothing is verified, the container is checked when it's opened.
+pub fn container_backend(bytes: &[u8]) -> Option<&str> {
+    let rest = bytes.strip_prefix(CONTAINER_MAGIC.as_slice())?;
+    let (len, rest) = rest.get(4..)?.split_first()?;
+    std::str::from_utf8(rest.get(..usize::from(*len))?).ok()
+}
+
+/// Checks a container written by `seal_container` and returns its payload
+///
+/// Fails on a container of another format version or of another backend than `backend`,
+/// and on a truncated or altered one, before the payload is decoded.
+pub fn open_container<'a>(backend: &str, bytes: &'a [u8]) -> Result<&'a [u8]> {
+    let rest = bytes
+        .strip_prefix(CONTAINER_MAGIC.as_slice())
+        .c(d!("not a snapshot container"))?;
+    let (version, rest) = split_bytes::<4>(rest).c(d!("truncated snapshot"))?;
+    let version = u32::from_be_bytes(version);
+    if version != CONTAINER_VERSION {
+        return Err(eg!(format!(
+            "snapshot container version {} isn't the supported version {}",
+            version, CONTAINER_VERSION
+        )));
+    }
+    let (len, rest) = rest.split_first().c(d!("truncated snapshot"))?;
+    let name = rest.get(..usize::from(*len)).c(d!("truncated snapshot"))?;
+    if name != backend.as_bytes() {
+        return Err(eg!(format!(
+            "snapshot of backend {} can't be opened by {}",
+            String::from_utf8_lossy(name),
+            backend
+        )));
+    }
+    let (len, rest) = split_bytes::<8>(&rest[name.len()..]).c(d!("truncated snapshot"))?;
+    let len = usize::try_from(u64::from_be_bytes(len)).c(d!("snapshot too large"))?;
+    if rest.len() != len.saturating_add(CHECKSUM_LEN) {
+        return Err(eg!(format!(
+            "truncated snapshot, {} bytes of payload and checksum expected, {} found",
+            len.saturating_add(CHECKSUM_LEN),
+            rest.len()
+        )));
+    }
+    let (payload, checksum) = rest.split_at(len);
+    let body = &bytes[..bytes.len() - CHECKSUM_LEN];
+    if Sha256::digest(body).as_slice() != checksum {
+        return Err(eg!("snapshot checksum mismatch"));
+    }
+    Ok(payload)
+}
+
+/// Writes a container of `backend` at `path` with the payload streamed by `write_payload`,
+/// like `seal_container` without holding the payload in memory
+///
+/// The length of the payload is written once it's streamed, the file is read again for the
+/// checksum.
+pub fn seal_container_file<F>(backend: &str, path: &Path, write_payload: F) -> Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    let backend_len = u8::try_from(backend.len()).c(d!("backend name too long"))?;
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .c(d!())?;
+    let mut writer = io::BufWriter::new(&mut file);
+    writer.write_all(CONTAINER_MAGIC).c(d!())?;
+    writer.write_all(&CONTAINER_VERSION.to_be_bytes()).c(d!())?;
+    writer.write_all(&[backend_len]).c(d!())?;
+    writer.write_all(backend.as_bytes()).c(d!())?;
+    writer.write_all(&0u64.to_be_bytes()).c(d!())?;
+    let mut payload = CountingWriter { writer, written: 0 };
+    write_payload(&mut payload).c(d!())?;
+    let len = payload.written;
+    payload.writer.flush().c(d!())?;
+    drop(payload);
+
+    let len_offset = CONTAINER_HEAD_LEN - 8 + backend.len();
+    file.seek(SeekFrom::Start(len_offset as u64)).c(d!())?;
+    file.write_all(&len.to_be_bytes()).c(d!())?;
+    file.seek(SeekFrom::Start(0)).c(d!())?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; STREAM_BUF_LEN];
+    loop {
+        let n = file.read(&mut buf).c(d!())?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    file.write_all(&hasher.finalize()).c(d!())?;
+    file.sync_all().c(d!())
+}
+
+struct CountingWriter<W> {
+    writer: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Payload of a container read as a stream, see `open_container_reader`
+pub struct ContainerReader<R> {
+    reader: R,
+    hasher: Sha256,
+    // payload bytes not read yet
+    left: u64,
+}
+
+/// Checks the head of a container written by `seal_container` and streams its payload
+///
+/// Fails on a container of another format version or of another backend than `backend`
+/// before the payload is read. The payload is read unverified, `ContainerReader::finish`
+/// checks the checksum once it was read to its end.
+pub fn open_container_reader<R: Read>(backend: &str, mut reader: R) -> Result<ContainerReader<R>> {
+    let mut head = [0; CONTAINER_HEAD_LEN - 8];
+    reader.read_exact(&mut head).c(d!("truncated snapshot"))?;
+    let (magic, rest) = split_bytes::<8>(&head).c(d!())?;
+    if &magic != CONTAINER_MAGIC {
+        return Err(eg!("not a snapshot container"));
+    }
+    let (version, rest) = split_bytes::<4>(rest).c(d!())?;
+    let version = u32::from_be_bytes(version);
+    if version != CONTAINER_VERSION {
+        return Err(eg!(format!(
+            "snapshot container version {} isn't the supported version {}",
+            version, CONTAINER_VERSION
+        )));
+    }
+    let mut name = vec![0; usize::from(rest[0])];
+    reader.read_exact(&mut name).c(d!("truncated snapshot"))?;
+    if name != backend.as_bytes() {
+        return Err(eg!(format!(
+            "snapshot of backend {} can't be opened by {}",
+            String::from_utf8_lossy(&name),
+            backend
+        )));
+    }
+    let mut len = [0; 8];
+    reader.read_exact(&mut len).c(d!("truncated snapshot"))?;
+    let mut hasher = Sha256::new();
+    hasher.update(head);
+    hasher.update(&name);
+    hasher.update(len);
+    Ok(ContainerReader {
+        reader,
+        hasher,
+        left: u64::from_be_bytes(len),
+    })
+}
+
+impl<R: Read> ContainerReader<R> {
+    /// Checks the checksum of the container once its payload was read, and that nothing
+    /// follows it
+    pub fn finish(mut self) -> Result<()> {
+        if self.left != 0 {
+            return Err(eg!("snapshot payload not read to its end"));
+        }
+        let mut checksum = [0; CHECKSUM_LEN];
+        self.reader
+            .read_exact(&mut checksum)
+            .c(d!("truncated snapshot"))?;
+        if self.hasher.finalize().as_slice() != checksum {
+            return Err(eg!("snapshot checksum mismatch"));
+        }
+        if self.reader.read(&mut [0; 1]).c(d!())? != 0 {
+            return Err(eg!("trailing bytes after the snapshot container"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ContainerReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = usize::try_from(self.left).map_or(buf.len(), |left| left.min(buf.len()));
+        if max == 0 {
+            return Ok(0);
+        }
+        let n = self.reader.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated snapshot",
+            ));
+        }
+        self.hasher.update(&buf[..n]);
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+fn split_bytes<const N: usize>(bytes: &[u8]) -> Option<([u8; N], &[u8])> {
+    let head = <[u8; N]>::try_from(bytes.get(..N)?).ok()?;
+    Some((head, &bytes[N..]))
+}
+
+/// Writes the container of a snapshot taken as a directory, `CONTAINER_FILE` in it, listing
+/// the length and sha256 of every file under it
+pub fn seal_container_dir(backend: &str, dir: &Path) -> Result<()> {
+    let listing = list_dir_files(dir).c(d!())?;
+    let bytes = seal_container(backend, listing.as_bytes()).c(d!())?;
+    fs::write(dir.join(CONTAINER_FILE), bytes).c(d!())
+}
+
+/// Checks the container of a snapshot directory written by `seal_container_dir` against the
+/// files under it
+pub fn open_container_dir(backend: &str, dir: &Path) -> Result<()> {
+    let bytes = fs::read(dir.join(CONTAINER_FILE)).c(d!("snapshot container missing"))?;
+    let listing = open_container(backend, &bytes).c(d!())?;
+    if listing != list_dir_files(dir).c(d!())?.as_bytes() {
+        return Err(eg!("snapshot files don't match the container"));
+    }
+    Ok(())
+}
+
+// `<sha256> <len> <path>` per file under `dir` but the container, sorted by path
+fn list_dir_files(dir: &Path) -> Result<String> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current).c(d!())? {
+            let path = entry.c(d!())?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path != dir.join(CONTAINER_FILE) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+
+    let mut listing = String::new();
+    for path in files {
+        // checkpoint files can be large, they're hashed as they're read
+        let mut hasher = Sha256::new();
+        let len = io::copy(&mut fs::File::open(&path).c(d!())?, &mut hasher).c(d!())?;
+        let name = path.strip_prefix(dir).c(d!())?.to_string_lossy();
+        listing.push_str(&format!(
+            "{} {} {}\n",
+            hex::encode(&hasher.finalize()),
+            len,
+            name
+        ));
+    }
+    Ok(listing)
+}
+
+/// Header written next to a snapshot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    pub height: u64,
+    pub root_hash: Vec<u8>,
+    /// seconds since the unix epoch
+    pub created_at: u64,
+    pub format: String,
+    /// crate version which wrote the snapshot
+    pub crate_version: String,
+    /// prefixes kept by the export the snapshot was taken from, `None` for the whole state
+    pub filter: Option<PrefixFilter>,
+    pub codec: SnapshotCodec,
+}
+
+impl SnapshotHeader {
+    /// Header of a snapshot taken now
+    pub fn new(height: u64, root_hash: Vec<u8>, format: &str) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        SnapshotHeader {
+            height,
+            root_hash,
+            created_at,
+            format: format.to_string(),
+            crate_version: CRATE_VERSION.to_string(),
+            filter: None,
+            codec: SnapshotCodec::None,
+        }
+    }
+}
+
+/// Include/exclude lists of key prefixes
+///
+/// A key passes if it's under one of the included prefixes, or `include` is empty, and under
+/// none of the excluded ones, e.g. the whole state but the peer and secret modules.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrefixFilter {
+    pub include: Vec<Vec<u8>>,
+    pub exclude: Vec<Vec<u8>>,
+}
+
+impl PrefixFilter {
+    /// A filter passing every key
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include<P: AsRef<[u8]>>(mut self, prefix: P) -> Self {
+        self.include.push(prefix.as_ref().to_vec());
+        self
+    }
+
+    pub fn exclude<P: AsRef<[u8]>>(mut self, prefix: P) -> Self {
+        self.exclude.push(prefix.as_ref().to_vec());
+        self
+    }
+
+    /// Whether the filter passes every key
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn allows(&self, key: &[u8]) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| key.starts_with(p)))
+            && !self.exclude.iter().any(|p| key.starts_with(p))
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let encode = |prefixes: &[Vec<u8>]| {
+            prefixes
+                .iter()
+                .map(|p| Value::from(hex::encode(p)))
+                .collect::<Vec<_>>()
+        };
+        json!({
+            "include": encode(&self.include),
+            "exclude": encode(&self.exclude),
+        })
+    }
+
+    pub(crate) fn from_json(value: &Value) -> Result<Self> {
+        let decode = |name: &str| -> Result<Vec<Vec<u8>>> {
+            value[name]
+                .as_array()
+                .c(d!(format!("missing {}", name)))?
+                .iter()
+                .map(|p| hex::decode(p.as_str().c(d!("invalid prefix"))?))
+                .collect()
+        };
+        Ok(PrefixFilter {
+            include: decode("include")?,
+            exclude: decode("exclude")?,
+        })
+    }
+}
+
+/// A snapshot found by `list_snapshots`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub path: PathBuf,
+    pub height: u64,
+    /// size on disk in bytes
+    pub size: u64,
+    pub hash: Vec<u8>,
+    pub created_at: u64,
+    pub format: String,
+    pub filter: Option<PrefixFilter>,
+    pub codec: SnapshotCodec,
+}
+
+/// Describes the header written next to a snapshot
+pub(crate) fn describe_header() -> Value {
+    json!({
+        "path": format!("{{snapshot}}{}", HEADER_SUFFIX),
+        "encoding": "JSON",
+        "fields": {
+            "height": "u64",
+            "root_hash": "hex",
+            "created_at": "seconds since the unix epoch",
+            "format": "string",
+            "crate_version": "string",
+            "filter": "optional {include, exclude} lists of hex prefixes",
+            "codec": "optional compression codec, none if missing",
+        },
+    })
+}
+
+/// Describes the container snapshots are written in
+pub(crate) fn describe_container() -> Value {
+    json!({
+        "version": CONTAINER_VERSION,
+        "magic": String::from_utf8_lossy(CONTAINER_MAGIC),
+        "layout": "magic, version u32, backend name length u8, backend name, payload length u64, \
+                   payload, sha256 of all the preceding bytes; integers big endian",
+        "directory_file": CONTAINER_FILE,
+        "directory_listing": "`{sha256} {length} {path}` per file, sorted by path",
+    })
+}
+
+/// Path of the header of the snapshot at `snapshot`
+pub fn header_path<P: AsRef<Path>>(snapshot: P) -> PathBuf {
+    let mut path = snapshot.as_ref().as_os_str().to_os_string();
+    path.push(HEADER_SUFFIX);
+    PathBuf::from(path)
+}
+
+pub fn write_header<P: AsRef<Path>>(snapshot: P, header: &SnapshotHeader) -> Result<()> {
+    let mut value = json!({
+        "height": header.height,
+        "root_hash": hex::encode(&header.root_hash),
+        "created_at": header.created_at,
+        "format": header.format,
+        "crate_version": header.crate_version,
+    });
+    if let Some(filter) = header.filter.as_ref() {
+        value["filter"] = filter.to_json();
+    }
+    if header.codec != SnapshotCodec::None {
+        value["codec"] = Value::from(header.codec.as_str());
+    }
+    let bytes = serde_json::to_vec_pretty(&value).c(d!())?;
+    fs::write(header_path(snapshot), bytes).c(d!())
+}
+
+pub fn read_header<P: AsRef<Path>>(snapshot: P) -> Result<SnapshotHeader> {
+    let path = header_path(snapshot);
+    // headers come with snapshots received from peers, don't read whatever is there
+    if fs::metadata(&path).c(d!())?.len() > MAX_HEADER_LEN {
+        return Err(eg!("snapshot header too large"));
+    }
+    let bytes = fs::read(path).c(d!())?;
+    let value: Value = serde_json::from_slice(&bytes).c(d!("invalid snapshot header"))?;
+    let str_field = |name: &str| value[name].as_str().c(d!(format!("missing {}", name)));
+    let u64_field = |name: &str| value[name].as_u64().c(d!(format!("missing {}", name)));
+
+    Ok(SnapshotHeader {
+        height: u64_field("height")?,
+        root_hash: hex::decode(str_field("root_hash")?).c(d!())?,
+        created_at: u64_field("created_at")?,
+        format: str_field("format")?.to_string(),
+        crate_version: str_field("crate_version")?.to_string(),
+        // headers written before filtered exports have none
+        filter: match value.get("filter") {
+            Some(filter) => Some(PrefixFilter::from_json(filter).c(d!())?),
+            None => None,
+        },
+        codec: match value.get("codec").and_then(Value::as_str) {
+            Some(codec) => SnapshotCodec::parse(codec).c(d!())?,
+            None => SnapshotCodec::None,
+        },
+    })
+}
+
+/// Lists the snapshots in `dir` ordered by height
+///
+/// Snapshots without a readable header are skipped.
+pub fn list_snapshots<P: AsRef<Path>>(dir: P) -> Result<Vec<SnapshotInfo>> {
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir).c(d!())? {
+        let path = entry.c(d!())?.path();
+        let snapshot = match path
+            .to_str()
+            .and_then(|p| p.strip_suffix(HEADER_SUFFIX))
+            .map(PathBuf::from)
+        {
+            Some(snapshot) if snapshot.exists() => snapshot,
+            _ => continue,
+        };
+        if let Ok(header) = read_header(&snapshot) {
+            snapshots.push(SnapshotInfo {
+                size: disk_size(&snapshot),
+                path: snapshot,
+                height: header.height,
+                hash: header.root_hash,
+                created_at: header.created_at,
+                format: header.format,
+                filter: header.filter,
+                codec: header.codec,
+            });
+        }
+    }
+    snapshots.sort_by(|a, b| a.height.cmp(&b.height).then(a.path.cmp(&b.path)));
+    Ok(snapshots)
+}
+
+// total size of a file or of all files under a directory
+fn disk_size(path: &Path) -> u64 {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| disk_size(&e.path()))
+                    .sum()
+            })
+            .unwrap_or_default(),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
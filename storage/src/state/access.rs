@@ -0,0 +1,169 @@
+/// Access lists and write conflict detection
+///
+/// Parallel execution lanes run in their own `State` sessions forked from the same base and
+/// record the keys they read and write, and the key ranges they iterate. Before the lanes are
+/// merged their access lists are checked against each other in lane order: a key written by
+/// two lanes is a write-write conflict, a key read by a lane, or within a range it iterated,
+/// and written by an earlier lane is a read-write conflict as the read may have seen a stale
+/// value. Lanes without conflicts merge as if run serially.
+///
+use std::{collections::BTreeSet, ops::Bound};
+
+/// Keys read and written by a session
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccessList {
+    reads: BTreeSet<Vec<u8>>,
+    writes: BTreeSet<Vec<u8>>,
+    // iterated ranges, lower key included and upper key excluded
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl AccessList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_read(&mut self, key: &[u8]) {
+        if !self.reads.contains(key) {
+            self.reads.insert(key.to_vec());
+        }
+    }
+
+    pub fn record_write(&mut self, key: &[u8]) {
+        if !self.writes.contains(key) {
+            self.writes.insert(key.to_vec());
+        }
+    }
+
+    /// Records the iteration of the keys from `lower` included to `upper` excluded
+    pub fn record_range(&mut self, lower: &[u8], upper: &[u8]) {
+        if lower < upper {
+            self.ranges.push((lower.to_vec(), upper.to_vec()));
+        }
+    }
+
+    pub fn reads(&self) -> &BTreeSet<Vec<u8>> {
+        &self.reads
+    }
+
+    pub fn writes(&self) -> &BTreeSet<Vec<u8>> {
+        &self.writes
+    }
+
+    pub fn ranges(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty() && self.writes.is_empty() && self.ranges.is_empty()
+    }
+
+    /// Moves the accesses of `other` into this list, e.g. to check a lane against all the
+    /// lanes merged before it at once
+    pub fn append(&mut self, other: &mut AccessList) {
+        self.reads.append(&mut other.reads);
+        self.writes.append(&mut other.writes);
+        self.ranges.append(&mut other.ranges);
+    }
+
+    /// Whether this list conflicts with the `earlier` one, merged first
+    pub fn conflicts_with(&self, earlier: &AccessList) -> bool {
+        let mut found = false;
+        find_conflicts(self, earlier, &mut |_, _| {
+            found = true;
+            true
+        });
+        found
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// both lanes wrote the key
+    WriteWrite,
+    /// the later lane read a key written by the earlier one, or iterated a range holding it
+    ReadWrite,
+}
+
+/// A key two lanes conflict on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub key: Vec<u8>,
+    pub kind: ConflictKind,
+    /// index of the lane merged first
+    pub earlier: usize,
+    /// index of the lane which has to be re-executed
+    pub later: usize,
+}
+
+/// Conflicts found between the access lists of a set of lanes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConflictReport {
+    pub conflicts: Vec<Conflict>,
+}
+
+impl ConflictReport {
+    pub fn is_empty(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// Lanes to re-execute on top of the merged state of the others
+    pub fn lanes(&self) -> BTreeSet<usize> {
+        self.conflicts.iter().map(|c| c.later).collect()
+    }
+}
+
+/// Checks the access lists of lanes merged in the given order against each other
+pub fn detect_conflicts(lanes: &[AccessList]) -> ConflictReport {
+    let mut report = ConflictReport::default();
+    for (later, list) in lanes.iter().enumerate() {
+        for (earlier, prev) in lanes.iter().enumerate().take(later) {
+            find_conflicts(list, prev, &mut |key, kind| {
+                report.conflicts.push(Conflict {
+                    key: key.to_vec(),
+                    kind,
+                    earlier,
+                    later,
+                });
+                false
+            });
+        }
+    }
+    report
+}
+
+// Calls `f` with every key `list` conflicts on with `prev` until it returns true
+fn find_conflicts(
+    list: &AccessList,
+    prev: &AccessList,
+    f: &mut dyn FnMut(&[u8], ConflictKind) -> bool,
+) {
+    for key in list.writes.intersection(&prev.writes) {
+        if f(key, ConflictKind::WriteWrite) {
+            return;
+        }
+    }
+    for key in list.reads.intersection(&prev.writes) {
+        if f(key, ConflictKind::ReadWrite) {
+            return;
+        }
+    }
+    // a key in several ranges, or also read on its own, is reported once
+    let mut in_ranges = BTreeSet::new();
+    for (lower, upper) in list.ranges.iter() {
+        let range = (
+            Bound::Included(lower.as_slice()),
+            Bound::Excluded(upper.as_slice()),
+        );
+        in_ranges.extend(
+            prev.writes
+                .range::<[u8], _>(range)
+                .filter(|key| !list.reads.contains(*key)),
+        );
+    }
+    for key in in_ranges {
+        if f(key, ConflictKind::ReadWrite) {
+            return;
+        }
+    }
+}
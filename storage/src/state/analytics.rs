@@ -0,0 +1,260 @@
+/// Long-running analytics sessions
+///
+/// An `AnalyticsSession` runs ad-hoc heavy queries against a production node. It pins the
+/// height it reads at, so pruning keeps its versions, and reads every value as of that height.
+/// Scans read one bounded step at a time under the chain state lock and hand it to the caller
+/// once the lock is released, so commits go on meanwhile.
+/// The number of scans and the bytes read are capped by a budget. An `AnalyticsHandle` shows
+/// the progress of the session and cancels it from another thread.
+///
+use crate::{
+    db::{IterOrder, MerkleDB, ScanControl},
+    state::ChainState,
+};
+use parking_lot::RwLock;
+use ruc::*;
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// default number of keys visited while holding the chain state lock
+pub const DEFAULT_ANALYTICS_STEP: usize = 0x400;
+
+/// Limits of an `AnalyticsSession`
+#[derive(Clone, Debug)]
+pub struct AnalyticsOpts {
+    /// Max number of scans, `None` for no limit
+    pub max_scans: Option<u64>,
+    /// Max number of key and value bytes read, `None` for no limit
+    pub max_bytes: Option<u64>,
+    /// Number of keys visited in one step of a scan
+    pub step_keys: usize,
+}
+
+impl Default for AnalyticsOpts {
+    fn default() -> Self {
+        AnalyticsOpts {
+            max_scans: None,
+            max_bytes: None,
+            step_keys: DEFAULT_ANALYTICS_STEP,
+        }
+    }
+}
+
+/// Resources used by an `AnalyticsSession` so far
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AnalyticsProgress {
+    pub scans: u64,
+    /// number of pairs read by gets and scans
+    pub entries: u64,
+    pub bytes: u64,
+    pub cancelled: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    scans: AtomicU64,
+    entries: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// Watches and cancels an `AnalyticsSession` from another thread, clones share the session
+#[derive(Clone, Debug)]
+pub struct AnalyticsHandle {
+    shared: Arc<Shared>,
+}
+
+impl AnalyticsHandle {
+    /// Makes the running and later reads of the session fail
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn progress(&self) -> AnalyticsProgress {
+        AnalyticsProgress {
+            scans: self.shared.scans.load(Ordering::SeqCst),
+            entries: self.shared.entries.load(Ordering::SeqCst),
+            bytes: self.shared.bytes.load(Ordering::SeqCst),
+            cancelled: self.is_cancelled(),
+        }
+    }
+}
+
+/// Read-only view of a chain state at a pinned height, the height is unpinned when dropped
+pub struct AnalyticsSession<D: MerkleDB> {
+    cs: Arc<RwLock<ChainState<D>>>,
+    height: u64,
+    opts: AnalyticsOpts,
+    handle: AnalyticsHandle,
+}
+
+impl<D: MerkleDB> AnalyticsSession<D> {
+    /// Pins `height`, the current height if `None`, fails on a chain without versions
+    pub fn open(
+        cs: Arc<RwLock<ChainState<D>>>,
+        height: Option<u64>,
+        opts: AnalyticsOpts,
+    ) -> Result<Self> {
+        if opts.step_keys == 0 {
+            return Err(eg!("step_keys must not be zero"));
+        }
+        let height = {
+            let mut cs = cs.write();
+            let height = match height {
+                Some(height) => height,
+                None => cs.height().c(d!())?,
+            };
+            cs.pin_at(height).c(d!())?;
+            height
+        };
+        Ok(AnalyticsSession {
+            cs,
+            height,
+            opts,
+            handle: AnalyticsHandle {
+                shared: Arc::default(),
+            },
+        })
+    }
+
+    /// The pinned height every value is read at
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn handle(&self) -> AnalyticsHandle {
+        self.handle.clone()
+    }
+
+    pub fn progress(&self) -> AnalyticsProgress {
+        self.handle.progress()
+    }
+
+    /// Gets the value of `key` at the pinned height
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check_cancelled()?;
+        let value = self.cs.read().get_ver(key, self.height).c(d!())?;
+        if let Some(value) = value.as_ref() {
+            self.record(key, value).c(d!())?;
+        }
+        Ok(value)
+    }
+
+    /// Walks the pairs in `[lower, upper)` at the pinned height in ascending key order
+    ///
+    /// Stops as soon as `func` returns `ScanControl::Stop` and returns the number of pairs
+    /// visited. Fails once the session is cancelled or over its budget.
+    pub fn scan(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        func: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> Result<u64> {
+        self.check_cancelled()?;
+        let scans = self.handle.shared.scans.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.opts.max_scans.is_some_and(|max| scans > max) {
+            return Err(eg!("analytics budget exceeded: too many scans"));
+        }
+
+        // keys changed after the pinned height, the live range misses those deleted since
+        let mut changed = BTreeSet::new();
+        let mut changed_upto = self.height;
+        let mut from = lower.to_vec();
+        let mut visited = 0;
+        loop {
+            let cs = self.cs.read();
+            for height in changed_upto + 1..=cs.height().c(d!())? {
+                for (key, _) in cs.changes_at(height).c(d!())? {
+                    if lower <= key.as_slice() && key.as_slice() < upper {
+                        changed.insert(key);
+                    }
+                }
+                changed_upto = height;
+            }
+
+            let mut keys = BTreeSet::new();
+            cs.scan_apply(&from, upper, IterOrder::Asc, &mut |k, _| {
+                keys.insert(k.to_vec());
+                if keys.len() < self.opts.step_keys {
+                    ScanControl::Continue
+                } else {
+                    ScanControl::Stop
+                }
+            });
+            // the step ends at its last live key unless it reached the end of the range
+            let last = if keys.len() < self.opts.step_keys {
+                None
+            } else {
+                keys.iter().next_back().cloned()
+            };
+            let step_end = last.as_deref().unwrap_or(upper);
+            keys.extend(
+                changed
+                    .range(from.clone()..)
+                    .take_while(|k| k.as_slice() <= step_end && k.as_slice() < upper)
+                    .cloned(),
+            );
+
+            let mut pairs = Vec::with_capacity(keys.len());
+            for key in keys {
+                self.check_cancelled()?;
+                if let Some(value) = cs.get_ver(&key, self.height).c(d!())? {
+                    pairs.push((key, value));
+                }
+            }
+
+            // the lock is only held while a step is read, `func` runs without it so neither
+            // commits nor a `func` taking the lock itself are held up
+            drop(cs);
+            for (key, value) in pairs {
+                self.check_cancelled()?;
+                self.record(&key, &value).c(d!())?;
+                visited += 1;
+                if func(&key, &value) == ScanControl::Stop {
+                    return Ok(visited);
+                }
+            }
+
+            match last {
+                Some(mut last) => {
+                    last.push(0);
+                    from = last;
+                }
+                None => return Ok(visited),
+            }
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.handle.is_cancelled() {
+            return Err(eg!("analytics session cancelled"));
+        }
+        Ok(())
+    }
+
+    fn record(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let shared = &self.handle.shared;
+        let size = (key.len() + value.len()) as u64;
+        let bytes = shared.bytes.fetch_add(size, Ordering::SeqCst) + size;
+        shared.entries.fetch_add(1, Ordering::SeqCst);
+        if self.opts.max_bytes.is_some_and(|max| bytes > max) {
+            return Err(eg!("analytics budget exceeded: too many bytes read"));
+        }
+        Ok(())
+    }
+}
+
+impl<D: MerkleDB> Drop for AnalyticsSession<D> {
+    fn drop(&mut self) {
+        self.cs.write().unpin_at(self.height);
+    }
+}
@@ -1,7 +1,11 @@
 use crate::db::KVBatch;
-use std::collections::{BTreeMap, BTreeSet};
 #[cfg(feature = "iterator")]
 use std::iter::Iterator;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    iter,
+    ops::Bound,
+};
 
 /// key-value map
 pub type KVMap = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
@@ -49,6 +53,27 @@ impl<'a> Iterator for CacheIter<'a> {
     }
 }
 
+/// detached cache iterator
+///
+/// Owns the KVs of the cache at the time it was created, so it keeps yielding that view after
+/// the cache is written, committed or discarded.
+pub struct CacheSnapshotIter {
+    iter: std::collections::btree_map::IntoIter<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Iterator for CacheSnapshotIter {
+    type Item = (Vec<u8>, Option<Vec<u8>>);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+impl DoubleEndedIterator for CacheSnapshotIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back()
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug, Copy)]
 pub enum StackStatus {
     Good,
@@ -308,6 +333,23 @@ impl SessionedCache {
         }
     }
 
+    /// get value by key without copying it
+    ///
+    /// returns Some(value) if available
+    ///
+    /// returns None otherwise
+    pub fn getv_ref(&self, key: &[u8]) -> Option<&[u8]> {
+        let layers = std::iter::once(&self.delta)
+            .chain(self.stack.iter().rev())
+            .chain(std::iter::once(&self.base));
+        for layer in layers {
+            if let Some(value) = layer.get(key) {
+                return value.as_deref();
+            }
+        }
+        None
+    }
+
     /// get value by key
     ///
     /// returns Some(Some(value)) if available
@@ -340,7 +382,7 @@ impl SessionedCache {
 
     /// iterator
     #[cfg(feature = "iterator")]
-    pub fn iter(&self) -> CacheIter {
+    pub fn iter(&self) -> CacheIter<'_> {
         CacheIter {
             iter: self.base.iter(),
             layer: 0,
@@ -348,6 +390,20 @@ impl SessionedCache {
         }
     }
 
+    /// detached iterator over the touched KVs in key order, deleted KVs have a `None` value
+    ///
+    /// Unlike `iter` it doesn't borrow the cache, the view is fixed when it's created.
+    pub fn snapshot_iter(&self) -> CacheSnapshotIter {
+        let mut kvs = self.base.clone();
+        for delta in &self.stack {
+            kvs.extend(delta.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        kvs.extend(self.delta.iter().map(|(k, v)| (k.clone(), v.clone())));
+        CacheSnapshotIter {
+            iter: kvs.into_iter(),
+        }
+    }
+
     /// prefix iterator
     pub fn iter_prefix(&self, prefix: &[u8], map: &mut KVecMap) {
         // insert/update new KVs and remove deleted KVs
@@ -383,6 +439,28 @@ impl SessionedCache {
         }
     }
 
+    /// range iterator
+    ///
+    /// Like `iter_prefix` for the keys in `[lower, upper)`, only the range of every layer is
+    /// visited.
+    pub fn iter_range(&self, lower: &[u8], upper: &[u8], map: &mut KVecMap) {
+        if lower >= upper {
+            return;
+        }
+        let layers = iter::once(&self.base)
+            .chain(self.stack.iter())
+            .chain(iter::once(&self.delta));
+        for layer in layers {
+            for (k, v) in layer.range::<[u8], _>((Bound::Included(lower), Bound::Excluded(upper))) {
+                if let Some(v) = v {
+                    map.insert(k.to_owned(), v.to_owned());
+                } else {
+                    map.remove(k.as_slice());
+                }
+            }
+        }
+    }
+
     /// rebases delta onto base
     /// make sure stack is empty before calling me
     fn rebase(&mut self) {
@@ -872,6 +950,37 @@ mod tests {
 
         assert_eq!(values, expected);
     }
+
+    #[test]
+    fn test_iterate_range() {
+        let mut cache = SessionedCache::new(true);
+        let mut my_cache = KVecMap::new();
+
+        cache.put(b"k10", b"v10".to_vec());
+        cache.put(b"k20", b"v20".to_vec());
+        cache.put(b"k30", b"v30".to_vec());
+        cache.stack_push();
+        cache.delete(b"k20");
+        cache.put(b"k25", b"v25".to_vec());
+        cache.stack_push();
+        cache.put(b"k10", b"v11".to_vec());
+        cache.put(b"k40", b"v40".to_vec());
+
+        cache.iter_range(b"k10", b"k30", &mut my_cache);
+        let values: Vec<_> = my_cache.into_iter().collect();
+        assert_eq!(
+            values,
+            vec![
+                (b"k10".to_vec(), b"v11".to_vec()),
+                (b"k25".to_vec(), b"v25".to_vec()),
+            ]
+        );
+
+        let mut my_cache = KVecMap::new();
+        cache.iter_range(b"k30", b"k30", &mut my_cache);
+        assert!(my_cache.is_empty());
+    }
+
     #[test]
     fn cache_stack_push() {
         let mut cache = SessionedCache::new(true);
@@ -1182,4 +1291,39 @@ mod tests {
             assert_eq!(cache.getv(b"key2"), Some(b"value2".to_vec()));
         }
     }
+
+    #[test]
+    fn cache_snapshot_iter() {
+        let mut cache = SessionedCache::new(true);
+        cache.put(b"k10", b"v10".to_vec());
+        cache.put(b"k20", b"v20".to_vec());
+        cache.commit_only();
+        cache.stack_push();
+        cache.put(b"k10", b"v11".to_vec());
+        cache.delete(b"k20");
+        cache.put(b"k30", b"v30".to_vec());
+
+        let expected = vec![
+            (b"k10".to_vec(), Some(b"v11".to_vec())),
+            (b"k20".to_vec(), None),
+            (b"k30".to_vec(), Some(b"v30".to_vec())),
+        ];
+        let mut iter = cache.snapshot_iter();
+        assert_eq!(iter.next(), Some(expected[0].clone()));
+
+        // the iterator keeps the view it was created with
+        cache.stack_commit();
+        cache.put(b"k40", b"v40".to_vec());
+        let _ = cache.commit();
+        cache.put(b"k30", b"v31".to_vec());
+        assert_eq!(iter.collect::<Vec<_>>(), expected[1..].to_vec());
+
+        let iter = cache.snapshot_iter();
+        cache.discard();
+        assert_eq!(
+            iter.rev().next(),
+            Some((b"k40".to_vec(), Some(b"v40".to_vec())))
+        );
+        assert_eq!(cache.snapshot_iter().count(), 4);
+    }
 }
@@ -4,28 +4,75 @@
 /// and RocksDB backend.
 ///
 use crate::{
-    db::{IterOrder, KVBatch, KVEntry, KValue, MerkleDB},
-    state::cache::KVMap,
+    aux_dump::{self, AuxDump},
+    batch::BatchBuilder,
+    db::{IterOpts, IterOrder, KVBatch, KVEntry, KValue, MerkleDB, PrefixExtractor, ScanControl},
+    height::{Height, Version},
+    hex,
+    proof::{FraudProofBundle, MultiProof},
+    remote::MissingNodeResolver,
+    schema::KeySchema,
+    snapshot::{
+        read_header, write_header, PrefixFilter, SnapshotHeader, SnapshotOptions, CHECKPOINT_FORMAT,
+    },
+    state::{
+        cache::KVMap,
+        chunks::{ChunkRestorer, SnapshotChunks},
+        diff::{DiffHeight, DiffReader, DiffWriter, DIFF_FORMAT},
+        feed::{ProofSubscriber, ProofUpdate},
+        metrics::{BlockMetrics, MetricsRecorder},
+        migration::{self, PrefixMigration},
+        mmr::{self, RootInclusionProof},
+        scrub::{Corruption, ScrubStep},
+        sketch::{KeySketch, KeySketchStats},
+        token::CommitToken,
+    },
     store::Prefix,
+    upgrade::{UpgradeReport, UpgradeStep},
 };
+use parking_lot::{Mutex, RwLock};
 use ruc::*;
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    collections::{BTreeMap, VecDeque},
-    ops::Range,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io::Write,
+    ops::{Bound, Range},
+    panic,
     path::Path,
     str,
+    sync::{mpsc::Receiver, Arc},
+    thread,
 };
 
 const HEIGHT_KEY: &[u8; 6] = b"Height";
 const BASE_HEIGHT_KEY: &[u8; 10] = b"BaseHeight";
 const SNAPSHOT_KEY: &[u8; 8] = b"Snapshot";
 const AUX_VERSION: &[u8; 10] = b"AuxVersion";
+const IMPORT_PROGRESS_KEY: &[u8; 14] = b"ImportProgress";
+const SEQUENCE_KEY: &[u8; 8] = b"Sequence";
+const EXPORT_FILTER_KEY: &[u8; 12] = b"ExportFilter";
+const ROOT_RECORD_KEY: &[u8; 10] = b"RootRecord";
+const SOURCE_ROOT_KEY: &[u8; 10] = b"SourceRoot";
+const CHALLENGE_KEY: &[u8; 9] = b"Challenge";
+const ROOT_AT_KEY: &[u8; 6] = b"RootAt";
+const KEEP_SECTION: &[u8; 4] = b"KEEP";
+const MMR_KEY: &[u8; 3] = b"Mmr";
+// progress of a restore from snapshot chunks
+pub(crate) const RESTORE_PROGRESS_KEY: &[u8; 15] = b"RestoreProgress";
+const REINDEX_KEY: &[u8; 7] = b"Reindex";
+const PRUNING_KEY: &[u8; 13] = b"PruningPolicy";
 const AUX_VERSION_00: u64 = 0x00;
 const AUX_VERSION_01: u64 = 0x01;
 const AUX_VERSION_02: u64 = 0x02;
 const SPLIT_BGN: &str = "_";
 const TOMBSTONE: [u8; 1] = [206u8];
+// upper bound of passes over the whole primary section, e.g. by the scrubber
+pub(crate) const KEYS_UPPER: [u8; 32] = [u8::MAX; 32];
+/// default number of deleted keys under a module prefix which triggers its compaction
+pub const DEFAULT_COMPACTION_TOMBSTONES: u64 = 0x0001_0000;
+/// number of aux entries removed or written per commit by a reindex resumed on open
+pub const DEFAULT_REINDEX_BATCH: usize = 0x2710;
 
 /// The length of a `Hash` (in bytes). same with fmerk.
 pub const HASH_LENGTH: usize = 32;
@@ -33,27 +80,164 @@ pub const HASH_LENGTH: usize = 32;
 /// A zero-filled `Hash`. same with fmerk.
 pub const NULL_HASH: [u8; HASH_LENGTH] = [0; HASH_LENGTH];
 
+/// Count of a snapshot found by the open of a chain state until `ChainState::load_deferred`
+/// counts its keys
+pub const UNCOUNTED: u64 = u64::MAX;
+
 #[derive(Debug, Clone)]
 pub struct SnapShotInfo {
     pub start: u64,
     pub end: u64,
+    /// number of keys in the snapshot, `UNCOUNTED` if not known yet
     pub count: u64,
 }
 
+/// Values of a set of keys together with one combined proof of them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvenValues {
+    pub height: u64,
+    pub root_hash: Vec<u8>,
+    /// sorted and de-duplicated keys with their values, `None` if a key doesn't exist
+    pub values: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    /// proof of all keys, merkle nodes shared by the keys are encoded once
+    pub proof: Vec<u8>,
+}
+
+/// Heights of a chain state, read together so they are consistent with each other
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeightWatermarks {
+    /// height of the last commit
+    pub committed: u64,
+    /// height of the last commit whose memtables were flushed to disk
+    pub flushed: u64,
+    /// oldest height whose state can still be read with `get_ver`
+    pub oldest_retained: u64,
+}
+
+/// Reads the height watermarks of a chain state without taking its lock, e.g. in an indexer
+#[derive(Clone, Debug)]
+pub struct WatermarkReader(Arc<RwLock<HeightWatermarks>>);
+
+impl WatermarkReader {
+    pub fn get(&self) -> HeightWatermarks {
+        *self.0.read()
+    }
+}
+
+/// Progress of `reindex`, reported after every committed batch
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReindexProgress {
+    /// derived aux entries removed so far
+    pub removed: u64,
+    /// keys of the primary section indexed so far
+    pub indexed: u64,
+    /// batches committed so far
+    pub batches: u64,
+    /// last key indexed
+    pub last_key: Vec<u8>,
+}
+
+/// Result of a batch import
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportProgress {
+    /// batches skipped because a previous run had already imported them
+    pub skipped: u64,
+    /// batches imported by this run
+    pub imported: u64,
+    /// KV pairs imported by this run
+    pub entries: u64,
+}
+
 /// Concrete ChainState struct containing a reference to an instance of MerkleDB, a name and
 /// current tree height.
 pub struct ChainState<D: MerkleDB> {
     name: String,
     ver_window: u64,
+    pruning: PruningPolicy,
     interval: u64,
     snapshot_info: VecDeque<SnapShotInfo>,
     // the min height of the versioned keys
     min_height: u64,
     pinned_height: BTreeMap<u64, u64>,
     version: u64,
+    proof_subs: Vec<ProofSubscriber>,
+    // last height acknowledged by each replication follower
+    follower_acks: BTreeMap<String, u64>,
+    // keys found corrupted by the scrubber
+    quarantine: RwLock<BTreeSet<Vec<u8>>>,
+    // deletes not compacted yet, by module prefix
+    pending_tombstones: BTreeMap<Vec<u8>, u64>,
+    compaction_threshold: u64,
+    // key formats checked on write in strict builds
+    key_schema: Option<Arc<KeySchema>>,
+    // fetches the values of keys whose local read failed
+    node_resolver: Option<Arc<dyn MissingNodeResolver>>,
+    // keys read through the resolver, not repaired locally yet
+    unrepaired: RwLock<BTreeSet<Vec<u8>>>,
+    // load of the registered prefixes, `None` until one is registered
+    metrics: Option<Mutex<MetricsRecorder>>,
+    // sketches of the keys written under registered prefixes
+    key_sketches: BTreeMap<Vec<u8>, KeySketch>,
+    // heights of the fraud proofs retained, zero disables them
+    challenge_window: u64,
+    // whether the roots are appended to the Merkle Mountain Range
+    root_mmr: bool,
+    // updated as a whole once a commit is done
+    watermarks: Arc<RwLock<HeightWatermarks>>,
+    // height of a commit which failed once its writes were staged, nothing is committed
+    // until the chain state is reopened
+    failed_commit: Option<u64>,
     db: D,
 }
 
+/// Which versions of the keys a chain state keeps
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PruningPolicy {
+    /// never merges versions into base, every height stays readable with `get_ver`
+    KeepAll,
+    /// keeps the versions of the last `n` heights, older ones are merged into base
+    KeepRecent(u64),
+    /// keeps the versions of the last `recent` heights, and the writes and root of every
+    /// `interval`-th height once merged into base, see `ChainState::changes_at`
+    KeepEvery { interval: u64, recent: u64 },
+}
+
+impl PruningPolicy {
+    // version window and policy of a chain state opened with `policy` and `ver_window`, no
+    // policy keeps the last `ver_window` heights
+    fn resolve(policy: Option<Self>, ver_window: u64) -> Result<(u64, Self)> {
+        let ver_window = policy.map_or(ver_window, |p| p.ver_window(ver_window));
+        let policy = policy.unwrap_or(PruningPolicy::KeepRecent(ver_window));
+        match policy {
+            PruningPolicy::KeepAll if ver_window == 0 => {
+                Err(eg!("keeping all versions needs a version window"))
+            }
+            PruningPolicy::KeepEvery { interval: 0, .. } => {
+                Err(eg!("pruning interval cannot be zero"))
+            }
+            _ => Ok((ver_window, policy)),
+        }
+    }
+
+    /// Version window of the policy, `KeepAll` keeps the window it is given
+    pub(crate) fn ver_window(&self, ver_window: u64) -> u64 {
+        match self {
+            PruningPolicy::KeepAll => ver_window,
+            PruningPolicy::KeepRecent(n) | PruningPolicy::KeepEvery { recent: n, .. } => *n,
+        }
+    }
+
+    // whether the writes and root of `height` are kept once it leaves the version window
+    fn keeps(&self, height: u64) -> bool {
+        match self {
+            PruningPolicy::KeepAll => true,
+            PruningPolicy::KeepRecent(_) => false,
+            PruningPolicy::KeepEvery { interval, .. } => height.is_multiple_of(*interval),
+        }
+    }
+}
+
 /// Configurable options
 #[derive(Default, Clone, Debug)]
 pub struct ChainStateOpts {
@@ -72,6 +256,11 @@ impl<D: MerkleDB> ChainState<D> {
     ///
     /// Returns the implicit struct
     pub fn new(db: D, name: String, ver_window: u64) -> Self {
+        pnk!(Self::try_new(db, name, ver_window))
+    }
+
+    /// Same as `new`, returning an error instead of panicking
+    pub fn try_new(db: D, name: String, ver_window: u64) -> Result<Self> {
         let opts = ChainStateOpts {
             name: if name.is_empty() { None } else { Some(name) },
             ver_window,
@@ -79,51 +268,122 @@ impl<D: MerkleDB> ChainState<D> {
             ..Default::default()
         };
 
-        Self::create_with_opts(db, opts)
+        Self::try_create_with_opts(db, opts)
     }
+
     /// Create a new instance of ChainState with user specified options
     ///
+    /// Panics on invalid options or an inconsistent db, see `try_create_with_opts`.
     pub fn create_with_opts(db: D, opts: ChainStateOpts) -> Self {
+        pnk!(Self::try_create_with_opts(db, opts))
+    }
+
+    /// Same as `create_with_opts`, returning an error instead of panicking
+    ///
+    /// Along with the other `try_` functions and those returning a `Result`, this is the panic
+    /// free mode of a chain state, for services which must keep running when a db can't be
+    /// opened.
+    pub fn try_create_with_opts(db: D, opts: ChainStateOpts) -> Result<Self> {
+        Self::try_open(db, opts, None)
+    }
+
+    /// Same as `create_with_opts`, keeping the versions selected by `pruning`
+    ///
+    /// The window of the policy replaces `opts.ver_window`. The policy is persisted in the aux
+    /// data, later opens keep it until another one is given.
+    pub fn create_with_pruning(db: D, opts: ChainStateOpts, pruning: PruningPolicy) -> Self {
+        pnk!(Self::try_create_with_pruning(db, opts, pruning))
+    }
+
+    /// Same as `create_with_pruning`, returning an error instead of panicking
+    pub fn try_create_with_pruning(
+        db: D,
+        opts: ChainStateOpts,
+        pruning: PruningPolicy,
+    ) -> Result<Self> {
+        Self::try_open(db, opts, Some(pruning))
+    }
+
+    // policy given to an open, or else the one persisted by an earlier open
+    fn open_pruning(db: &D, pruning: Option<PruningPolicy>) -> Result<Option<PruningPolicy>> {
+        if pruning.is_some() {
+            return Ok(pruning);
+        }
+        match db.get_aux(PRUNING_KEY).c(d!())? {
+            Some(v) => serde_json::from_slice(&v)
+                .c(d!("invalid pruning policy"))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn try_open(db: D, opts: ChainStateOpts, pruning: Option<PruningPolicy>) -> Result<Self> {
         let db_name = opts.name.unwrap_or_else(|| String::from("chain-state"));
+        let persisted = Self::open_pruning(&db, pruning).c(d!())?;
+        let (ver_window, pruning) = PruningPolicy::resolve(persisted, opts.ver_window).c(d!())?;
 
         if opts.interval == 1 {
-            panic!("snapshot interval cannot be One")
+            return Err(eg!("snapshot interval cannot be One"));
         }
 
-        if opts.ver_window < opts.interval {
-            panic!("version window is smaller than snapshot interval");
+        if ver_window < opts.interval {
+            return Err(eg!("version window is smaller than snapshot interval"));
         }
         // ver_window is larger than snapshot_interval
         // ver_window should align at snapshot_interval
-        if opts.interval != 0 && opts.ver_window % opts.interval != 0 {
-            panic!("ver_window should align at snapshot interval");
+        if opts.interval != 0 && ver_window % opts.interval != 0 {
+            return Err(eg!("ver_window should align at snapshot interval"));
         }
 
-        if opts.ver_window == 0 && opts.cleanup_aux {
-            panic!("perform an cleanup_aux and construct base on a no-version chain");
+        if ver_window == 0 && opts.cleanup_aux {
+            return Err(eg!(
+                "perform an cleanup_aux and construct base on a no-version chain"
+            ));
         }
 
         let mut cs = ChainState {
             name: db_name,
-            ver_window: opts.ver_window,
+            ver_window,
+            pruning,
             interval: opts.interval,
             snapshot_info: Default::default(),
             min_height: 0,
             pinned_height: Default::default(),
             version: Default::default(),
+            proof_subs: vec![],
+            follower_acks: Default::default(),
+            quarantine: Default::default(),
+            pending_tombstones: Default::default(),
+            compaction_threshold: DEFAULT_COMPACTION_TOMBSTONES,
+            key_schema: None,
+            node_resolver: None,
+            unrepaired: Default::default(),
+            metrics: None,
+            key_sketches: Default::default(),
+            challenge_window: 0,
+            root_mmr: false,
+            watermarks: Default::default(),
+            failed_commit: None,
             db,
         };
 
         if opts.cleanup_aux {
-            cs.clean_aux().unwrap();
+            cs.clean_aux().c(d!())?;
             // move all keys to base
-            cs.construct_base();
+            cs.construct_base().c(d!())?;
         }
+        cs.verify_height_consistency()
+            .c(d!("inconsistent chain state"))?;
+        if cs.db.get_aux(REINDEX_KEY).c(d!())?.is_some() {
+            println!("{} resuming an interrupted reindex", cs.name);
+            cs.reindex(DEFAULT_REINDEX_BATCH, &mut |_| {}).c(d!())?;
+        }
+        cs.root_mmr = cs.mmr_size().c(d!())?.is_some();
 
         let mut base_height = None;
         let mut prev_interval = 0;
 
-        match cs.get_aux_version().expect("Need a valid version") {
+        match cs.get_aux_version().c(d!("Need a valid version"))? {
             None => {
                 // initializing
                 // version will be updated in `commit_db_with_meta`
@@ -133,9 +393,9 @@ impl<D: MerkleDB> ChainState<D> {
                 // Version_01
                 // 1. versioned keys are seperated into two sections: `base` and `VER`
 
-                let h = cs.height().expect("Failed to get height");
-                base_height = match h.cmp(&opts.ver_window) {
-                    Ordering::Greater => Some(h.saturating_sub(opts.ver_window)),
+                let h = cs.height().c(d!("Failed to get height"))?;
+                base_height = match h.cmp(&ver_window) {
+                    Ordering::Greater => Some(h.saturating_sub(ver_window)),
                     _ => None,
                 };
                 // version will be updated in `commit_db_with_meta`
@@ -147,16 +407,16 @@ impl<D: MerkleDB> ChainState<D> {
                 // 2. add snapshots for speedup get_ver
                 base_height = cs
                     .base_height()
-                    .expect("Failed to read base_height from aux db");
+                    .c(d!("Failed to read base_height from aux db"))?;
                 prev_interval = cs
                     .snapshot_meta()
-                    .expect("Failed to read snapshot meta from aux db")
-                    .expect("missing snapshot meta");
+                    .c(d!("Failed to read snapshot meta from aux db"))?
+                    .c(d!("missing snapshot meta"))?;
 
                 cs.version = AUX_VERSION_02;
             }
             Some(_) => {
-                panic!("Invalid db version");
+                return Err(eg!("Invalid db version"));
             }
         }
 
@@ -166,15 +426,127 @@ impl<D: MerkleDB> ChainState<D> {
         );
 
         let mut batch = KVBatch::new();
-        cs.clean_aux_db(&mut base_height, &mut batch);
-        cs.build_snapshots(base_height, prev_interval, opts.interval, &mut batch);
-        cs.commit_db_with_meta(batch);
-        cs
+        cs.clean_aux_db(&mut base_height, &mut batch).c(d!())?;
+        cs.build_snapshots(base_height, prev_interval, opts.interval, &mut batch)
+            .c(d!())?;
+        if let Some(pruning) = persisted {
+            let value = serde_json::to_vec(&pruning).c(d!())?;
+            batch.push((PRUNING_KEY.to_vec(), Some(value)));
+        }
+        cs.commit_db_with_meta(batch).c(d!())?;
+        // whatever was committed before opening is on disk
+        cs.refresh_watermarks(true);
+        Ok(cs)
+    }
+
+    /// Adds the steps `try_create_with_opts` would run on `db` with `opts` to `report`,
+    /// without writing anything, see `upgrade::plan_upgrade`
+    pub fn plan_open(db: &D, opts: &ChainStateOpts, report: &mut UpgradeReport) -> Result<()> {
+        let persisted = Self::open_pruning(db, None).c(d!())?;
+        let (ver_window, pruning) = PruningPolicy::resolve(persisted, opts.ver_window).c(d!())?;
+        let read_u64 = |key: &[u8]| -> Result<Option<u64>> {
+            match db.get_aux(key).c(d!())? {
+                Some(v) => Ok(Some(String::from_utf8(v).c(d!())?.parse::<u64>().c(d!())?)),
+                None => Ok(None),
+            }
+        };
+        let count_keys = || db.db_all_iterator(IterOrder::Unordered).count() as u64;
+
+        if db.get_aux(RESTORE_PROGRESS_KEY).c(d!())?.is_some() {
+            return Err(eg!(
+                "unfinished restore from snapshot chunks, the open would fail"
+            ));
+        }
+        let height = read_u64(HEIGHT_KEY).c(d!())?.unwrap_or(0);
+        let version = read_u64(AUX_VERSION).c(d!("invalid aux version"))?;
+        let mut steps = vec![];
+        let mut base_height = None;
+        let mut prev_interval = 0;
+
+        // both leave a version index of the current version based at the current height
+        if opts.cleanup_aux {
+            steps.push(UpgradeStep::CleanupAux { keys: count_keys() });
+            base_height = Some(height);
+        } else if db.get_aux(REINDEX_KEY).c(d!())?.is_some() {
+            steps.push(UpgradeStep::ResumeReindex { keys: count_keys() });
+            base_height = Some(height);
+            prev_interval = opts.interval;
+        } else {
+            match version {
+                None if height == 0 => {}
+                None | Some(AUX_VERSION_01) => {
+                    if version.is_some() && height > ver_window {
+                        base_height = Some(height.saturating_sub(ver_window));
+                    }
+                    steps.push(UpgradeStep::UpgradeAux {
+                        from: Version(version.unwrap_or(AUX_VERSION_00)),
+                        to: Version(AUX_VERSION_02),
+                    });
+                }
+                Some(AUX_VERSION_02) => {
+                    base_height = read_u64(BASE_HEIGHT_KEY).c(d!())?;
+                    prev_interval = read_u64(SNAPSHOT_KEY)
+                        .c(d!())?
+                        .c(d!("missing snapshot meta"))?;
+                }
+                Some(_) => return Err(eg!("Invalid db version")),
+            }
+        }
+
+        // see `clean_aux_db`
+        if height != 0 && height > ver_window && pruning != PruningPolicy::KeepAll {
+            let min_height = height - ver_window;
+            if base_height.is_none_or(|h| h < min_height) {
+                let upper = Self::versioned_key_prefix(min_height);
+                let entries = db
+                    .iter_aux(&Prefix::new(b"VER").begin(), upper.as_ref(), IterOrder::Asc)
+                    .count() as u64;
+                // the base is rebuilt on every open, it's only work if versions are pruned
+                if entries != 0 {
+                    steps.push(UpgradeStep::PruneVersions {
+                        from: base_height.map_or(0, |h| h.saturating_add(1)),
+                        to: min_height,
+                        entries,
+                    });
+                }
+                base_height = Some(min_height - 1);
+            }
+        }
+
+        // see `build_snapshots`
+        if prev_interval != opts.interval {
+            let mut snapshots = 0u64;
+            if opts.interval != 0 {
+                let s = base_height.map_or(0, |h| h.saturating_add(1));
+                let mut e = if s != 0 && s % opts.interval == 0 {
+                    s
+                } else {
+                    (s / opts.interval + 1) * opts.interval
+                };
+                while e <= height {
+                    snapshots += 1;
+                    e = e.saturating_add(opts.interval);
+                }
+            }
+            if snapshots != 0 || prev_interval != 0 {
+                steps.push(UpgradeStep::RebuildSnapshots {
+                    from_interval: prev_interval,
+                    to_interval: opts.interval,
+                    snapshots,
+                });
+            }
+        }
+
+        report.aux_version = Some(Version(version.unwrap_or(AUX_VERSION_00)));
+        report.height = height;
+        report.steps.append(&mut steps);
+        Ok(())
     }
 
     /// Pin the ChainState at specified height
     ///
-    pub fn pin_at(&mut self, height: u64) -> Result<()> {
+    pub fn pin_at(&mut self, height: impl Into<Height>) -> Result<()> {
+        let height = height.into().get();
         let current = self.height()?;
         if current < height {
             return Err(eg!("pin at future height"));
@@ -193,7 +565,8 @@ impl<D: MerkleDB> ChainState<D> {
 
     /// Unpin the ChainState at specified height
     ///
-    pub fn unpin_at(&mut self, height: u64) {
+    pub fn unpin_at(&mut self, height: impl Into<Height>) {
+        let height = height.into().get();
         let remove = match self.pinned_height.get_mut(&height) {
             Some(count) if *count > 0 => {
                 *count = count.saturating_sub(1);
@@ -207,8 +580,51 @@ impl<D: MerkleDB> ChainState<D> {
     }
 
     /// Gets a value for the given key from the primary data section in RocksDB
+    ///
+    /// Fails if the key has been quarantined by the scrubber. A failed read, e.g. on a missing
+    /// merkle node, is served by the missing node resolver if one is set, the key is repaired
+    /// by `repair_missing`.
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self.db.get(key)
+        self.check_quarantine(key)?;
+        match self.db.get(key) {
+            Ok(value) => {
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.lock().record_read(key, value.as_deref());
+                }
+                Ok(value)
+            }
+            Err(e) => self.resolve_missing(key, e),
+        }
+    }
+
+    // serves a failed read of `key` by the missing node resolver, `err` if there's none
+    fn resolve_missing(&self, key: &[u8], err: Box<dyn RucError>) -> Result<Option<Vec<u8>>> {
+        let resolver = match self.node_resolver.as_ref() {
+            Some(resolver) => resolver,
+            None => return Err(err),
+        };
+        let value = resolver
+            .resolve(&self.db.root_hash(), key)
+            .c(d!(format!("local read failed: {}", err)))?;
+        self.unrepaired.write().insert(key.to_vec());
+        Ok(value)
+    }
+
+    /// Reads the value of `key` into `buf` like `get`, see `MerkleDB::get_into`
+    pub fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        self.check_quarantine(key)?;
+        match self.db.get_into(key, buf) {
+            Ok(len) => {
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.lock().record_read(key, len.map(|_| buf.as_slice()));
+                }
+                Ok(len)
+            }
+            Err(e) => {
+                let value = self.resolve_missing(key, e)?;
+                Ok(copy_into(value.as_deref(), buf))
+            }
+        }
     }
 
     // ver_window == 0 -> ver_window = 100
@@ -217,6 +633,14 @@ impl<D: MerkleDB> ChainState<D> {
 
     // [9900, 10000] versioned, [0,9899] -> base prefix saved to aux
 
+    /// Gets a value for the given key if this ChainState is at least as fresh as `min_token`.
+    ///
+    /// Fails with a retryable `NotYetAvailable` error while the replica is lagging behind.
+    pub fn get_with_token(&self, key: &[u8], min_token: CommitToken) -> Result<Option<Vec<u8>>> {
+        min_token.check(self.commit_token().c(d!())?)?;
+        self.get(key)
+    }
+
     /// Gets a value for the given key from the auxiliary data section in RocksDB.
     ///
     /// This section of data is not used for root hash calculations.
@@ -224,6 +648,40 @@ impl<D: MerkleDB> ChainState<D> {
         self.db.get_aux(key)
     }
 
+    /// Gets the last value of the named sequence, zero if it was never incremented
+    pub fn get_sequence(&self, name: &str) -> Result<u64> {
+        match self.get_aux(&Self::sequence_key(name)).c(d!())? {
+            Some(value) => String::from_utf8(value)
+                .c(d!("Invalid sequence string"))?
+                .parse::<u64>()
+                .c(d!("sequence should be a valid 64-bit long integer")),
+            None => Ok(0),
+        }
+    }
+
+    /// Builds the aux entry storing `value` as the last value of the named sequence
+    pub fn sequence_entry(name: &str, value: u64) -> KVEntry {
+        (
+            Self::sequence_key(name),
+            Some(value.to_string().into_bytes()),
+        )
+    }
+
+    pub(crate) fn sequence_key(name: &str) -> Vec<u8> {
+        Prefix::new(SEQUENCE_KEY)
+            .push(name.as_bytes())
+            .as_ref()
+            .to_vec()
+    }
+
+    /// Returns the committed progress of the named prefix migration
+    pub fn get_migration(&self, name: &str) -> Result<Option<PrefixMigration>> {
+        match self.get(&migration::progress_key(name)).c(d!())? {
+            Some(value) => migration::decode_progress(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
     /// Get aux database version
     ///
     /// The default version is ox00
@@ -248,9 +706,23 @@ impl<D: MerkleDB> ChainState<D> {
         upper: &[u8],
         order: IterOrder,
         func: &mut dyn FnMut(KValue) -> bool,
+    ) -> bool {
+        self.iterate_with_opts(lower, upper, order, &IterOpts::default(), func)
+    }
+
+    /// Iterates MerkleDB for a given range of keys with read hints.
+    ///
+    /// Large analytical scans should pass `IterOpts::scan()` to keep the block cache intact.
+    pub fn iterate_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+        func: &mut dyn FnMut(KValue) -> bool,
     ) -> bool {
         // Get DB iterator
-        let mut db_iter = self.db.iter(lower, upper, order);
+        let mut db_iter = self.db.iter_with_opts(lower, upper, order, opts);
         let mut stop = false;
 
         // Loop through each entry in range
@@ -266,6 +738,37 @@ impl<D: MerkleDB> ChainState<D> {
         true
     }
 
+    /// Walks MerkleDB for a given range of keys without allocating a pair per entry.
+    ///
+    /// Stops as soon as `func` returns `ScanControl::Stop`, returns the number of entries visited.
+    pub fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        func: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        self.db.scan_apply(lower, upper, order, func)
+    }
+
+    /// Warms up the backend by touching every KV pair stored under the given prefixes.
+    ///
+    /// Called at startup so the first block after a restart doesn't pay for cold reads.
+    ///
+    /// Returns the number of KV pairs loaded.
+    pub fn warm_up(&self, prefixes: &[Prefix]) -> u64 {
+        prefixes.iter().map(|p| self.warm_up_prefix(p)).sum()
+    }
+
+    // Loads all KV pairs under a single prefix
+    fn warm_up_prefix(&self, prefix: &Prefix) -> u64 {
+        let count = self
+            .db
+            .iter(&prefix.begin(), &prefix.end(), IterOrder::Asc)
+            .count();
+        count as u64
+    }
+
     pub fn all_iterator(&self, order: IterOrder, func: &mut dyn FnMut(KValue) -> bool) -> bool {
         // Get DB iterator
         let mut db_iter = self.db.db_all_iterator(order);
@@ -340,13 +843,24 @@ impl<D: MerkleDB> ChainState<D> {
     ///
     /// The main purpose is to save memory on the disk
     fn prune_aux_batch(&self, height: u64, batch: &mut KVBatch) -> Result<()> {
-        if self.ver_window == 0 || height < self.ver_window + 1 {
+        if self.ver_window == 0
+            || height < self.ver_window + 1
+            || self.pruning == PruningPolicy::KeepAll
+        {
             return Ok(());
         }
 
-        //Build range keys for window limits
-        let pruning_height = Self::height_str(height - self.ver_window - 1);
+        self.move_versions_to_base(height - self.ver_window - 1, batch);
+        Ok(())
+    }
+
+    /// Merges the versioned keys of `height` into base and deletes them
+    ///
+    /// The writes of a height kept by the pruning policy are copied to the `KEEP` section.
+    fn move_versions_to_base(&self, height: u64, batch: &mut KVBatch) {
+        let pruning_height = Self::height_str(height);
         let pruning_prefix = Prefix::new("VER".as_bytes()).push(pruning_height.as_bytes());
+        let keep = self.pruning.keeps(height);
         // move key-value pairs of left window side to baseline
         self.iterate_aux(
             &pruning_prefix.begin(),
@@ -357,6 +871,9 @@ impl<D: MerkleDB> ChainState<D> {
                 if raw_key.is_empty() {
                     return false;
                 }
+                if keep {
+                    batch.push((Self::kept_key(raw_key.as_bytes(), height), Some(v.clone())));
+                }
                 // Merge(update/remove) to baseline
                 let base_key = Self::base_key(raw_key.as_bytes());
                 if v.ne(&TOMBSTONE) {
@@ -369,8 +886,10 @@ impl<D: MerkleDB> ChainState<D> {
                 false
             },
         );
-
-        Ok(())
+        let root_key = Self::root_at_key(height);
+        if !keep && self.exists_aux(&root_key).unwrap_or(false) {
+            batch.push((root_key, None));
+        }
     }
 
     /// Builds a new batch which is a copy of the original commit with the current height
@@ -400,8 +919,10 @@ impl<D: MerkleDB> ChainState<D> {
             }
 
             let last_min_height = self.min_height;
-            // update the left side of version window
-            self.min_height = if upper > self.ver_window {
+            // update the left side of version window, which never moves when keeping all
+            self.min_height = if self.pruning == PruningPolicy::KeepAll {
+                last_min_height
+            } else if upper > self.ver_window {
                 upper.saturating_sub(self.ver_window)
             } else {
                 // we only build base if height > ver_window
@@ -432,109 +953,1290 @@ impl<D: MerkleDB> ChainState<D> {
     /// An optional flag is also passed to indicate whether RocksDB should flush its mem table
     /// to disk.
     ///
-    /// Due to the requirements of MerkleDB, the batch needs to be sorted prior to a commit.
+    /// Due to the requirements of MerkleDB, the batch needs to be sorted and free of duplicated
+    /// keys prior to a commit. Repeated writes to the same key are merged, the last one wins.
     ///
     /// Returns the current height as well as the updated root hash of the Merkle Tree.
     pub fn commit(
         &mut self,
-        mut batch: KVBatch,
+        batch: KVBatch,
+        height: impl Into<Height>,
+        flush: bool,
+    ) -> Result<(Vec<u8>, u64)> {
+        self.commit_with_aux(batch, vec![], height, flush)
+    }
+
+    /// Commits a key value batch like `commit`, writing `extra_aux` to the auxiliary data in
+    /// the same commit, e.g. sequence counters
+    pub fn commit_with_aux(
+        &mut self,
+        batch: KVBatch,
+        extra_aux: KVBatch,
+        height: impl Into<Height>,
+        flush: bool,
+    ) -> Result<(Vec<u8>, u64)> {
+        self.commit_checked(batch, extra_aux, height.into().get(), flush, None)
+    }
+
+    // Commits like `commit_with_aux`, the root hash of the written batch is first checked
+    // against `expected_root`
+    //
+    // The writes of the batch are staged in the db before the root is known and a backend
+    // can't drop them, a commit failing after that leaves them staged: the chain state refuses
+    // any further commit, it's reopened to drop them. Nothing else is updated before the root
+    // is checked.
+    fn commit_checked(
+        &mut self,
+        batch: KVBatch,
+        extra_aux: KVBatch,
         height: u64,
         flush: bool,
+        expected_root: Option<&[u8]>,
     ) -> Result<(Vec<u8>, u64)> {
-        batch.sort();
-        let aux = self.build_aux_batch(height, &batch).c(d!())?;
+        if let Some(failed) = self.failed_commit {
+            return Err(eg!(format!(
+                "the commit of height {} failed with its writes staged, reopen the chain state",
+                failed
+            )));
+        }
+        let batch = BatchBuilder::from(batch).sorted(true).build();
+        let bundle = match self.challenge_window {
+            0 => None,
+            _ => Some(FraudProofBundle {
+                height,
+                witness: self.challenge_witness(&batch).c(d!())?,
+                batch: batch.clone(),
+                post_root: vec![],
+                post_proof: vec![],
+            }),
+        };
 
-        self.db.put_batch(batch).c(d!())?;
+        let staged = batch
+            .iter()
+            .map(|(k, v)| (k.as_slice(), v.as_deref()))
+            .collect::<Vec<_>>();
+        self.failed_commit = Some(height);
+        self.db.put_batch_ref(&staged).c(d!())?;
+        if let Some(expected) = expected_root {
+            let root_hash = self.db.root_hash();
+            if root_hash != expected {
+                return Err(eg!(format!(
+                    "height {} leads to root {}, expected {}",
+                    height,
+                    hex::encode(&root_hash),
+                    hex::encode(expected)
+                )));
+            }
+        }
+        self.commit_staged(batch, extra_aux, height, flush, bundle)
+            .c(d!())?;
+        self.failed_commit = None;
+
+        self.refresh_watermarks(flush);
+        self.compact_tombstones(false).c(d!())?;
+        let root_hash = self.root_hash();
+        self.publish_proofs(height, &root_hash);
+        Ok((root_hash, height))
+    }
+
+    // Commits the aux data of the batch staged at `height` and records it
+    fn commit_staged(
+        &mut self,
+        batch: KVBatch,
+        mut extra_aux: KVBatch,
+        height: u64,
+        flush: bool,
+        bundle: Option<FraudProofBundle>,
+    ) -> Result<()> {
+        let mut aux = self.build_aux_batch(height, &batch).c(d!())?;
+        aux.append(&mut extra_aux);
+        aux.extend(self.root_record(height));
+        if self.ver_window != 0 {
+            aux.extend(self.root_at(height));
+        }
+        if self.root_mmr {
+            aux.append(&mut self.mmr_aux(height).c(d!())?);
+        }
+        aux.append(&mut self.challenge_aux(height, bundle).c(d!())?);
         self.db.commit(aux, flush).c(d!())?;
 
-        Ok((self.root_hash(), height))
+        self.count_tombstones(&batch);
+        if let Some(metrics) = self.metrics.as_mut() {
+            let metrics = metrics.get_mut();
+            for (k, v) in batch.iter() {
+                metrics.record_write(k, v.as_deref());
+            }
+            metrics.end_block(height);
+        }
+        // a key under nested prefixes counts for each of them
+        for (k, _) in batch.iter() {
+            for (prefix, sketch) in self
+                .key_sketches
+                .range_mut::<[u8], _>((Bound::Unbounded, Bound::Included(k.as_slice())))
+            {
+                if k.starts_with(prefix) {
+                    sketch.record(k);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the number of deleted keys under a module prefix which triggers a compaction of
+    /// the prefix after a commit, zero disables automatic compactions
+    pub fn set_compaction_threshold(&mut self, tombstones: u64) {
+        self.compaction_threshold = tombstones;
     }
 
-    /// Export a copy of chain state on a specific height.
+    /// Sets the key formats checked on write, see `schema::strict_keys`
+    pub fn set_key_schema(&mut self, schema: KeySchema) {
+        self.key_schema = Some(Arc::new(schema));
+    }
+
+    pub fn key_schema(&self) -> Option<Arc<KeySchema>> {
+        self.key_schema.clone()
+    }
+
+    /// Retains a fraud proof of each of the last `heights` commits, zero disables them
     ///
-    /// * `cs` - The target chain state that holds the copy.
-    /// * `height` - On which height the copy will be taken. It MUST be in range `[cur_height - ver_window, cur_height]`.\
-    ///    Notes: Exported chain state holds less historical commits because `height <= cur_height`. `snapshot` is the
-    ///    preferred method to export a copy on current height.
+    /// Every commit then stores the changelog of its height with a witness of the values it
+    /// overwrites, proven against the previous root, and a proof of the values it wrote against
+    /// the new root in the aux data, until the height leaves the challenge window. Backends
+    /// without proofs keep the changelog and the overwritten values unproven, see
+    /// `FraudProofBundle::is_proven`.
+    pub fn set_challenge_window(&mut self, heights: u64) {
+        self.challenge_window = heights;
+    }
+
+    pub fn challenge_window(&self) -> u64 {
+        self.challenge_window
+    }
+
+    /// Fraud proof bundle of the commit at `height`, to dispute its state transition
+    pub fn fraud_proof(&self, height: impl Into<Height>) -> Result<FraudProofBundle> {
+        let height = height.into().get();
+        let bundle = self
+            .db
+            .get_aux(&Self::challenge_key(height))
+            .c(d!())?
+            .c(d!(format!(
+                "no fraud proof of height {}, outside of the challenge window",
+                height
+            )))?;
+        FraudProofBundle::decode(&bundle).c(d!("invalid fraud proof"))
+    }
+
+    // Values of the keys of `batch` in the current tree, i.e. before it is written
+    fn challenge_witness(&self, batch: &[KVEntry]) -> Result<MultiProof> {
+        let root_hash = self.db.root_hash();
+        // nothing to prove for an empty batch, nor against an empty tree
+        if batch.is_empty() || root_hash == NULL_HASH {
+            return Ok(MultiProof {
+                root_hash,
+                values: batch.iter().map(|(k, _)| (k.clone(), None)).collect(),
+                proof: vec![],
+            });
+        }
+        let keys = batch.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        if self.db.has_proofs() {
+            return self.db.prove_batch(&keys).c(d!());
+        }
+        let values = keys
+            .into_iter()
+            .map(|k| self.db.get(&k).map(|v| (k, v)))
+            .collect::<Result<Vec<_>>>()
+            .c(d!())?;
+        Ok(MultiProof {
+            root_hash,
+            values,
+            proof: vec![],
+        })
+    }
+
+    // Stores the bundle of `height` with the current root and drops the bundles leaving the
+    // challenge window, all of them once it is disabled
+    fn challenge_aux(&self, height: u64, bundle: Option<FraudProofBundle>) -> Result<KVBatch> {
+        let mut batch = KVBatch::new();
+        let oldest = height
+            .saturating_sub(self.challenge_window)
+            .saturating_add(1);
+        self.iterate_aux(
+            &Prefix::new(CHALLENGE_KEY).begin(),
+            &Self::challenge_key(oldest),
+            IterOrder::Asc,
+            &mut |(k, _)| {
+                batch.push((k, None));
+                false
+            },
+        );
+        if let Some(mut bundle) = bundle {
+            bundle.post_root = self.db.root_hash();
+            // the values written, nothing to prove in an empty tree
+            if self.db.has_proofs() && !bundle.batch.is_empty() && bundle.post_root != NULL_HASH {
+                let keys = bundle
+                    .batch
+                    .iter()
+                    .map(|(k, _)| k.clone())
+                    .collect::<Vec<_>>();
+                bundle.post_proof = self.db.prove_keys(&keys).c(d!())?;
+            }
+            batch.push((Self::challenge_key(height), Some(bundle.encode().c(d!())?)));
+        }
+        Ok(batch)
+    }
+
+    fn challenge_key(height: u64) -> Vec<u8> {
+        Prefix::new(CHALLENGE_KEY)
+            .push(Self::height_str(height).as_bytes())
+            .as_ref()
+            .to_vec()
+    }
+
+    /// Height of the last commit
+    pub fn latest_committed_height(&self) -> u64 {
+        self.watermarks.read().committed
+    }
+
+    /// Height of the last commit whose memtables were flushed to disk
+    pub fn latest_flushed_height(&self) -> u64 {
+        self.watermarks.read().flushed
+    }
+
+    /// Oldest height whose state can still be read with `get_ver`
+    pub fn oldest_retained_height(&self) -> u64 {
+        self.watermarks.read().oldest_retained
+    }
+
+    /// All height watermarks, consistent with each other
+    pub fn watermarks(&self) -> HeightWatermarks {
+        *self.watermarks.read()
+    }
+
+    pub fn watermark_reader(&self) -> WatermarkReader {
+        WatermarkReader(self.watermarks.clone())
+    }
+
+    // the db is read before the lock is taken, readers never see a partial update
+    fn refresh_watermarks(&self, flushed: bool) {
+        let range = match self.get_ver_range() {
+            Ok(range) => range,
+            Err(_) => return,
+        };
+        let mut marks = self.watermarks.write();
+        marks.committed = range.end;
+        marks.oldest_retained = range.start;
+        if flushed {
+            marks.flushed = range.end;
+        }
+    }
+
+    /// Tracks the reads and writes of the keys under `prefix`, see `prefix_metrics`
+    pub fn register_metrics_prefix(&mut self, prefix: &Prefix) {
+        self.metrics
+            .get_or_insert_with(Default::default)
+            .get_mut()
+            .register(prefix);
+    }
+
+    /// Load of every registered prefix during the last committed block
     ///
-    pub fn export(&self, cs: &mut Self, height: u64) -> Result<()> {
-        // Height must be in version window
-        let cur_height = self.height().c(d!())?;
-        let ver_range = (cur_height - self.ver_window)..=cur_height;
-        if !ver_range.contains(&height) {
-            return Err(eg!(format!(
-                "height MUST be in the range: [{}, {}].",
-                ver_range.start(),
-                ver_range.end()
-            )));
+    /// Reads are counted up to the commit of the block they are made in, writes when the block
+    /// is committed.
+    pub fn prefix_metrics(&self) -> BlockMetrics {
+        self.metrics
+            .as_ref()
+            .map(|m| m.lock().last())
+            .unwrap_or_default()
+    }
+
+    /// Sketches the keys written under `prefix` from now on, see `key_sketch`
+    pub fn register_sketch_prefix(&mut self, prefix: &Prefix) {
+        self.key_sketches.entry(prefix.begin()).or_default();
+    }
+
+    /// Approximate distinct keys and most updated keys written under a registered prefix
+    pub fn key_sketch(&self, prefix: &Prefix) -> Option<KeySketchStats> {
+        self.key_sketches.get(&prefix.begin()).map(KeySketch::stats)
+    }
+
+    /// Sets the hook fetching the values of keys whose local read failed
+    pub fn set_missing_node_resolver(&mut self, resolver: Arc<dyn MissingNodeResolver>) {
+        self.node_resolver = Some(resolver);
+    }
+
+    /// Keys served by the missing node resolver since their last repair
+    pub fn unrepaired(&self) -> Vec<Vec<u8>> {
+        self.unrepaired.read().iter().cloned().collect()
+    }
+
+    /// Writes back the keys served by the missing node resolver, returns how many
+    ///
+    /// The values are fetched again against the current root and written unchanged, so the
+    /// root hash stays the same. A backend which can't rewrite the broken part of its tree
+    /// fails here, its keys keep being served by the resolver.
+    pub fn repair_missing(&mut self) -> Result<usize> {
+        let keys = self.unrepaired();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let resolver = self
+            .node_resolver
+            .clone()
+            .c(d!("no missing node resolver"))?;
+        let root = self.db.root_hash();
+        let mut batch = KVBatch::with_capacity(keys.len());
+        for key in keys.iter() {
+            let value = resolver.resolve(&root, key).c(d!())?;
+            batch.push((key.clone(), value));
+        }
+        self.db.put_batch(batch).c(d!())?;
+        self.db.commit(vec![], true).c(d!())?;
+        if self.db.root_hash() != root {
+            return Err(eg!("repair changed the root hash"));
+        }
+
+        let mut unrepaired = self.unrepaired.write();
+        for key in keys.iter() {
+            unrepaired.remove(key);
+        }
+        Ok(keys.len())
+    }
+
+    /// Checks `key` against the key schema, if any
+    pub fn validate_key(&self, key: &[u8]) -> Result<()> {
+        match self.key_schema.as_ref() {
+            Some(schema) => schema.validate(key),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of deleted keys which have not been compacted yet
+    pub fn pending_tombstones(&self) -> u64 {
+        self.pending_tombstones.values().sum()
+    }
+
+    /// Compacts all prefixes with pending tombstones, regardless of the threshold
+    ///
+    /// Returns the number of compacted tombstones.
+    pub fn compact_pending(&mut self) -> Result<u64> {
+        self.compact_tombstones(true)
+    }
+
+    // groups deletes by their module prefix, i.e. up to the first `_`
+    fn count_tombstones(&mut self, batch: &KVBatch) {
+        for (k, _) in batch.iter().filter(|(_, v)| v.is_none()) {
+            let prefix = PrefixExtractor::Module.prefix(k).unwrap_or_default();
+            *self.pending_tombstones.entry(prefix.to_vec()).or_insert(0) += 1;
+        }
+    }
+
+    fn compact_tombstones(&mut self, all: bool) -> Result<u64> {
+        let threshold = self.compaction_threshold;
+        if !all && threshold == 0 {
+            return Ok(0);
+        }
+        let due = self
+            .pending_tombstones
+            .iter()
+            .filter(|(_, &n)| all || n >= threshold)
+            .map(|(p, &n)| (p.clone(), n))
+            .collect::<Vec<_>>();
+
+        let mut compacted = 0;
+        for (prefix, n) in due {
+            // a module prefix ends with `_`, its range ends before the next byte value
+            let (lower, upper) = match prefix.split_last() {
+                Some((_, base)) => {
+                    let mut upper = base.to_vec();
+                    upper.push(b'_' + 1);
+                    (prefix.clone(), upper)
+                }
+                None => (vec![], KEYS_UPPER.to_vec()),
+            };
+            self.db.compact_range(&lower, &upper).c(d!())?;
+            self.pending_tombstones.remove(&prefix);
+            compacted += n;
+        }
+        Ok(compacted)
+    }
+
+    /// Gets the values of `keys` at `height` together with a single combined proof.
+    ///
+    /// Proofs can only be generated against the latest root, so `height` must be the current
+    /// height of the chain.
+    pub fn get_many_with_proof(
+        &self,
+        keys: &[Vec<u8>],
+        height: impl Into<Height>,
+    ) -> Result<ProvenValues> {
+        let height = height.into().get();
+        let cur_height = self.height().c(d!("error reading current height"))?;
+        if height != cur_height {
+            return Err(eg!(format!(
+                "proofs are only available at the current height {}",
+                cur_height
+            )));
+        }
+
+        let mut keys = keys.to_vec();
+        keys.sort();
+        keys.dedup();
+
+        let proof = self.db.prove_keys(&keys).c(d!("error generating proof"))?;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = self.get(&key).c(d!("error getting value"))?;
+            values.push((key, value));
+        }
+
+        Ok(ProvenValues {
+            height,
+            root_hash: self.root_hash(),
+            values,
+            proof,
+        })
+    }
+
+    /// Subscribes to the proof feed of the given keys.
+    ///
+    /// After each commit the receiver gets the height, the root hash and the values of `keys`
+    /// together with a proof of them. At most `capacity` updates are buffered, a subscriber
+    /// lagging behind misses updates instead of slowing down commits.
+    pub fn subscribe_proofs(
+        &mut self,
+        keys: Vec<Vec<u8>>,
+        capacity: usize,
+    ) -> Receiver<ProofUpdate> {
+        let (sub, receiver) = ProofSubscriber::new(keys, capacity);
+        self.proof_subs.push(sub);
+        receiver
+    }
+
+    /// Returns the number of proof updates missed by lagging subscribers
+    pub fn proof_updates_dropped(&self) -> u64 {
+        self.proof_subs.iter().map(|sub| sub.dropped()).sum()
+    }
+
+    // Pushes proof updates to all subscribers, subscribers gone away are removed
+    fn publish_proofs(&mut self, height: u64, root_hash: &[u8]) {
+        let db = &self.db;
+        self.proof_subs
+            .retain_mut(|sub| sub.publish(db, height, root_hash));
+    }
+
+    /// Export a copy of chain state on a specific height.
+    ///
+    /// * `cs` - The target chain state that holds the copy.
+    /// * `height` - On which height the copy will be taken. It MUST be in range `[cur_height - ver_window, cur_height]`.\
+    ///   Notes: Exported chain state holds less historical commits because `height <= cur_height`. `snapshot` is the
+    ///   preferred method to export a copy on current height.
+    ///
+    pub fn export(&self, cs: &mut Self, height: impl Into<Height>) -> Result<()> {
+        self.export_filtered(cs, height, &PrefixFilter::default())
+    }
+
+    /// Export a copy of chain state on a specific height like `export`, keeping only the keys
+    /// passing `filter`.
+    ///
+    /// The filter is kept in the auxiliary data of `cs` and recorded in the header of its
+    /// snapshots, an empty filter exports the whole state.
+    ///
+    pub fn export_filtered(
+        &self,
+        cs: &mut Self,
+        height: impl Into<Height>,
+        filter: &PrefixFilter,
+    ) -> Result<()> {
+        let height = height.into().get();
+        // Height must be in version window
+        let cur_height = self.height().c(d!())?;
+        let ver_range = (cur_height - self.ver_window)..=cur_height;
+        if !ver_range.contains(&height) {
+            return Err(eg!(format!(
+                "height MUST be in the range: [{}, {}].",
+                ver_range.start(),
+                ver_range.end()
+            )));
+        }
+
+        // Replay historical commit, if any, on every height
+        for h in *ver_range.start()..=height {
+            let mut kvs = KVMap::new();
+
+            // setup bounds
+            let lower = Prefix::new("VER".as_bytes()).push(Self::height_str(h).as_bytes());
+            let upper = Prefix::new("VER".as_bytes()).push(Self::height_str(h + 1).as_bytes());
+
+            // collect commits on this height
+            self.iterate_aux(
+                &lower.begin(),
+                &upper.begin(),
+                IterOrder::Asc,
+                &mut |(k, v)| -> bool {
+                    let raw_key = Self::get_raw_versioned_key(&k).unwrap_or_default();
+                    if raw_key.is_empty() || !filter.allows(raw_key.as_bytes()) {
+                        return false;
+                    }
+
+                    if v.eq(&TOMBSTONE) {
+                        kvs.insert(raw_key.as_bytes().to_vec(), None);
+                    } else {
+                        kvs.insert(raw_key.as_bytes().to_vec(), Some(v));
+                    }
+                    false
+                },
+            );
+
+            // commit this batch
+            let batch = kvs.into_iter().collect::<Vec<_>>();
+            let aux = if filter.is_empty() {
+                vec![]
+            } else {
+                let value = serde_json::to_vec(&filter.to_json()).c(d!())?;
+                vec![(EXPORT_FILTER_KEY.to_vec(), Some(value))]
+            };
+            if cs.commit_with_aux(batch, aux, h, true).is_err() {
+                let msg = format!("Replay failed on height {}", h);
+                return Err(eg!(msg));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Imports a sequence of KV batches into the primary section, without creating a height.
+    ///
+    /// Progress is committed to aux together with the imported data every
+    /// `checkpoint_every_n` batches. If an import stops halfway, running it again with the same
+    /// batches skips those already committed and resumes after the last checkpoint. The
+    /// progress is removed once all batches are imported.
+    ///
+    /// The order of the batches must be deterministic for a resume to be correct.
+    pub fn import_batches<I>(
+        &mut self,
+        batches: I,
+        checkpoint_every_n: u64,
+    ) -> Result<ImportProgress>
+    where
+        I: IntoIterator<Item = Result<KVBatch>>,
+    {
+        let checkpoint_every_n = checkpoint_every_n.max(1);
+        let done = self.import_progress().c(d!())?.unwrap_or(0);
+        let mut progress = ImportProgress {
+            skipped: done,
+            ..Default::default()
+        };
+
+        for batch in batches.into_iter().skip(done as usize) {
+            let batch = batch.c(d!("error reading import batch"))?;
+            let mut batch = BatchBuilder::from(batch).sorted(true).build();
+            // deleting a key which doesn't exist is an error for MerkleDB
+            batch.retain(|(k, v)| v.is_some() || self.exists(k).unwrap_or(false));
+
+            progress.entries = progress.entries.saturating_add(batch.len() as u64);
+            self.db.put_batch(batch).c(d!())?;
+            progress.imported = progress.imported.saturating_add(1);
+
+            if progress.imported.is_multiple_of(checkpoint_every_n) {
+                let count = done.saturating_add(progress.imported);
+                let aux = vec![(
+                    IMPORT_PROGRESS_KEY.to_vec(),
+                    Some(count.to_string().into_bytes()),
+                )];
+                self.db
+                    .commit(aux, true)
+                    .c(d!("error committing import checkpoint"))?;
+            }
+        }
+
+        let mut aux = vec![(IMPORT_PROGRESS_KEY.to_vec(), None)];
+        aux.extend(self.root_record(self.height().c(d!())?));
+        self.db.commit(aux, true).c(d!())?;
+        Ok(progress)
+    }
+
+    /// Returns the number of batches committed by an unfinished import, if any
+    pub fn import_progress(&self) -> Result<Option<u64>> {
+        match self.db.get_aux(IMPORT_PROGRESS_KEY).c(d!())? {
+            Some(v) => {
+                let count = String::from_utf8(v).c(d!())?;
+                count.parse::<u64>().map(Some).c(d!())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Take a snapshot of chain state on a specific height.
+    ///
+    /// * `path` - The path of database that holds the snapshot.
+    ///
+    /// A header with the height and root hash is written next to it, see `list_snapshots`.
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.snapshot_with_opts(path, &SnapshotOptions::default())
+    }
+
+    /// Take a snapshot like `snapshot`, compressed with the codec of `opts`
+    ///
+    /// The codec is recorded in the header, backends which can't compress their snapshots
+    /// fail unless no codec is set.
+    pub fn snapshot_with_opts<P: AsRef<Path>>(
+        &self,
+        path: P,
+        opts: &SnapshotOptions,
+    ) -> Result<()> {
+        self.db.snapshot_with_opts(path.as_ref(), opts).c(d!())?;
+        let mut header =
+            SnapshotHeader::new(self.height().c(d!())?, self.root_hash(), CHECKPOINT_FORMAT);
+        header.filter = self.export_filter().c(d!())?;
+        header.codec = opts.codec;
+        write_header(path, &header).c(d!())
+    }
+
+    /// Writes the batches committed after `since_height` to `path`, see `apply_diff`
+    ///
+    /// `since_height` must be in the version window, e.g. the height of the last full
+    /// snapshot. A header with the current height and root hash is written next to the diff.
+    pub fn snapshot_diff<P: AsRef<Path>>(&self, since_height: u64, path: P) -> Result<()> {
+        if self.ver_window == 0 {
+            return Err(eg!("diffs are taken from a versioned chain"));
+        }
+        let height = self.height().c(d!())?;
+        let lower = self.get_ver_range().c(d!())?.start;
+        if since_height < lower || since_height > height {
+            return Err(eg!(format!(
+                "diff base MUST be in the range: [{}, {}].",
+                lower, height
+            )));
+        }
+        let since_root = match self.root_hash_at(since_height) {
+            Ok(root) => root,
+            Err(e) if since_height != 0 => return Err(e).c(d!()),
+            Err(_) => NULL_HASH.to_vec(),
+        };
+
+        let mut writer = DiffWriter::create(path.as_ref(), since_height, &since_root).c(d!())?;
+        for h in since_height.saturating_add(1)..=height {
+            let mut batch = KVBatch::new();
+            let mut res = Ok(());
+            let prefix = Self::versioned_key_prefix(h);
+            self.iterate_aux(
+                &prefix.begin(),
+                &prefix.end(),
+                IterOrder::Asc,
+                &mut |(k, v)| -> bool {
+                    match Self::get_raw_versioned_key(&k) {
+                        Ok(raw_key) => {
+                            let v = if v.eq(&TOMBSTONE) { None } else { Some(v) };
+                            batch.push((raw_key.into_bytes(), v));
+                            false
+                        }
+                        Err(e) => {
+                            res = Err(e);
+                            true
+                        }
+                    }
+                },
+            );
+            res.c(d!())?;
+            let root_hash = self.root_hash_at(h).c(d!())?;
+            writer.write_height(h, &root_hash, &batch).c(d!())?;
+        }
+        writer.finish().c(d!())?;
+
+        let header = SnapshotHeader::new(height, self.root_hash(), DIFF_FORMAT);
+        write_header(path, &header).c(d!())
+    }
+
+    /// Replays the diff at `path` on this chain state, which is at the base height of the diff
+    ///
+    /// The whole diff is read and checked against its header before anything is committed,
+    /// it is held in memory until it is applied. Every height is then committed once the root
+    /// hash of its batch matches the root recorded by the source chain state. A failed replay
+    /// leaves this chain state at the last height of the diff that matched, with the writes of
+    /// the failed height staged: it refuses any further commit and must be reopened to drop
+    /// them.
+    pub fn apply_diff<P: AsRef<Path>>(&mut self, path: P) -> Result<(Vec<u8>, u64)> {
+        let header = read_header(path.as_ref()).c(d!())?;
+        if header.format != DIFF_FORMAT {
+            return Err(eg!(format!("{} is not a diff", path.as_ref().display())));
+        }
+        let mut reader = DiffReader::open(path.as_ref()).c(d!())?;
+        let height = self.height().c(d!())?;
+        if reader.since != height || reader.since_root != self.db.root_hash() {
+            return Err(eg!(format!(
+                "diff applies to height {} but the chain state is at height {}",
+                reader.since, height
+            )));
+        }
+
+        let mut heights = vec![];
+        while let Some(next) = reader.next_height().c(d!())? {
+            let expected = heights.last().map_or(height, |h: &DiffHeight| h.height) + 1;
+            if next.height != expected {
+                return Err(eg!(format!(
+                    "diff skips from height {} to height {}",
+                    expected - 1,
+                    next.height
+                )));
+            }
+            heights.push(next);
+        }
+        let last = heights
+            .last()
+            .map(|h| (h.root_hash.clone(), h.height))
+            .unwrap_or((reader.since_root.clone(), height));
+        if last != (header.root_hash.clone(), header.height) {
+            return Err(eg!(format!(
+                "diff leads to root {} at height {}, its header to {} at height {}",
+                hex::encode(&last.0),
+                last.1,
+                hex::encode(&header.root_hash),
+                header.height
+            )));
+        }
+
+        let count = heights.len();
+        for (i, h) in heights.into_iter().enumerate() {
+            // the last height is flushed
+            let flush = i + 1 == count;
+            self.commit_checked(h.batch, vec![], h.height, flush, Some(&h.root_hash))
+                .c(d!())?;
+        }
+        Ok((self.root_hash(), header.height))
+    }
+
+    /// Splits the primary section at the current height into chunks for state sync
+    ///
+    /// A chunk holds entries until their keys and values reach `chunk_size` bytes. Commits
+    /// made while iterating may be seen, the chunks are taken from a chain state not being
+    /// committed to, e.g. a snapshot.
+    pub fn snapshot_chunks(&self, chunk_size: usize) -> Result<SnapshotChunks<'_, D>> {
+        let height = self.height().c(d!())?;
+        Ok(SnapshotChunks::new(
+            &self.db,
+            height,
+            self.root_hash(),
+            chunk_size,
+        ))
+    }
+
+    /// Restores the snapshot of `height` and `root_hash`, a trusted root, into `db` from its
+    /// chunks, see `ChunkRestorer`
+    ///
+    /// `db` must be empty or hold an unfinished restore of the same snapshot, which is resumed.
+    pub fn restore_from_chunks(db: D, height: u64, root_hash: Vec<u8>) -> Result<ChunkRestorer<D>> {
+        if db.get_aux(RESTORE_PROGRESS_KEY).c(d!())?.is_none() {
+            let empty = db.get_aux(HEIGHT_KEY).c(d!())?.is_none()
+                && db.db_all_iterator(IterOrder::Asc).next().is_none();
+            if !empty {
+                return Err(eg!("snapshot chunks are restored into an empty db only"));
+            }
+        }
+        ChunkRestorer::new(db, height, root_hash).c(d!())
+    }
+
+    // Returns the root of a tree restored from snapshot chunks, empty for an empty tree, if
+    // it's `root_hash`
+    pub(crate) fn check_restored_root(db: &D, root_hash: &[u8]) -> Result<Vec<u8>> {
+        let mut root = db.root_hash();
+        if root == NULL_HASH {
+            root.clear();
+        }
+        if root != root_hash {
+            return Err(eg!(format!(
+                "restored root {} doesn't match the snapshot root {}",
+                hex::encode(&root),
+                hex::encode(root_hash)
+            )));
+        }
+        Ok(root)
+    }
+
+    // Opens the chain state of a tree restored from snapshot chunks at `height`
+    pub(crate) fn open_restored(
+        mut db: D,
+        height: u64,
+        root_hash: &[u8],
+        mut opts: ChainStateOpts,
+    ) -> Result<Self> {
+        let root = Self::check_restored_root(&db, root_hash).c(d!())?;
+        let mut aux = vec![
+            (HEIGHT_KEY.to_vec(), Some(height.to_string().into_bytes())),
+            (RESTORE_PROGRESS_KEY.to_vec(), None),
+        ];
+        if !root.is_empty() {
+            let record = format!("{}_{}", height, hex::encode(&root));
+            aux.push((ROOT_RECORD_KEY.to_vec(), Some(record.into_bytes())));
+        }
+        db.commit(aux, true).c(d!())?;
+
+        // the restored keys are the base of the versions to come
+        let persisted = Self::open_pruning(&db, None).c(d!())?;
+        let (ver_window, _) = PruningPolicy::resolve(persisted, opts.ver_window).c(d!())?;
+        opts.cleanup_aux = ver_window != 0;
+        Self::try_create_with_opts(db, opts)
+    }
+
+    /// Returns the filter of the export this chain state holds, `None` for a whole state
+    pub fn export_filter(&self) -> Result<Option<PrefixFilter>> {
+        match self.db.get_aux(EXPORT_FILTER_KEY).c(d!())? {
+            Some(v) => {
+                let value = serde_json::from_slice(&v).c(d!("invalid export filter"))?;
+                PrefixFilter::from_json(&value).map(Some).c(d!())
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Calculate and returns current root hash of the Merkle tree
+    pub fn root_hash(&self) -> Vec<u8> {
+        let hash = self.db.root_hash();
+        if hash == NULL_HASH {
+            return vec![];
+        }
+        hash
+    }
+
+    /// Returns current height of the ChainState
+    pub fn height(&self) -> Result<u64> {
+        let height = self.db.get_aux(HEIGHT_KEY).c(d!())?;
+        if let Some(value) = height {
+            let height_str = String::from_utf8(value).c(d!())?;
+            let last_height = height_str.parse::<u64>().c(d!())?;
+
+            return Ok(last_height);
+        }
+        Ok(0u64)
+    }
+
+    /// Verifies the entries of the primary section starting at `from`
+    ///
+    /// Stops once about `max_bytes` bytes have been verified, corrupted keys are quarantined
+    /// if `quarantine` is set. The returned step tells where to resume.
+    pub fn scrub_step(&self, from: &[u8], max_bytes: u64, quarantine: bool) -> ScrubStep {
+        let mut step = ScrubStep::default();
+        for kv_pair in self.db.iter(from, &KEYS_UPPER, IterOrder::Asc) {
+            if step.bytes >= max_bytes {
+                step.next = Some(kv_pair.0.to_vec());
+                break;
+            }
+            self.scrub_entry(&mut step, kv_pair, quarantine);
+        }
+        step
+    }
+
+    /// Verifies the whole primary section at once, in the order cheapest for the backend
+    ///
+    /// Unlike `scrub_step` it can't be resumed, it's meant for offline integrity checks.
+    pub fn scrub_all(&self, quarantine: bool) -> ScrubStep {
+        let mut step = ScrubStep::default();
+        for kv_pair in self.db.iter(&[], &KEYS_UPPER, IterOrder::Unordered) {
+            self.scrub_entry(&mut step, kv_pair, quarantine);
+        }
+        step
+    }
+
+    fn scrub_entry(&self, step: &mut ScrubStep, kv_pair: (Box<[u8]>, Box<[u8]>), quarantine: bool) {
+        step.keys = step.keys.saturating_add(1);
+        step.bytes = step
+            .bytes
+            .saturating_add((kv_pair.0.len() + kv_pair.1.len()) as u64);
+
+        if let Err(e) = self.db.verify_entry(&kv_pair) {
+            let key = self.db.decode_kv(kv_pair).0;
+            if quarantine {
+                self.quarantine_key(&key);
+            }
+            step.corrupted.push(Corruption {
+                key,
+                error: e.to_string(),
+            });
+        }
+    }
+
+    /// Copies of the version history of renamed keys, from old key to new key
+    ///
+    /// Scans the whole versioned section of aux, which is fine for the occasional upgrade
+    /// migration but not per block. The history of the old keys is kept.
+    pub fn history_copies(&self, moves: &BTreeMap<Vec<u8>, Vec<u8>>) -> Result<KVBatch> {
+        let mut batch = KVBatch::new();
+        if moves.is_empty() || self.ver_window == 0 {
+            return Ok(batch);
+        }
+        for section in ["VER", "BASE", "SNAPSHOT", "KEEP"] {
+            let prefix = Prefix::new(section.as_bytes());
+            self.iterate_aux(
+                &prefix.begin(),
+                &prefix.end(),
+                IterOrder::Asc,
+                &mut |(k, v)| -> bool {
+                    let raw_key = Self::get_raw_versioned_key(&k).unwrap_or_default();
+                    if let Some(new) = moves.get(raw_key.as_bytes()) {
+                        // same section and height, new raw key
+                        let mut key = k;
+                        key.truncate(key.len().saturating_sub(raw_key.len()));
+                        key.extend_from_slice(new);
+                        batch.push((key, Some(v)));
+                    }
+                    false
+                },
+            );
+        }
+        Ok(batch)
+    }
+
+    /// Quarantines a key, reads of it fail until it is released
+    pub fn quarantine_key(&self, key: &[u8]) {
+        self.quarantine.write().insert(key.to_vec());
+    }
+
+    /// Releases a quarantined key, e.g. after it has been repaired
+    pub fn release_key(&self, key: &[u8]) -> bool {
+        self.quarantine.write().remove(key)
+    }
+
+    /// Returns the quarantined keys
+    pub fn quarantined(&self) -> Vec<Vec<u8>> {
+        self.quarantine.read().iter().cloned().collect()
+    }
+
+    fn check_quarantine(&self, key: &[u8]) -> Result<()> {
+        if self.quarantine.read().contains(key) {
+            return Err(eg!("key is quarantined as corrupted"));
+        }
+        Ok(())
+    }
+
+    /// Returns the token of the last commit
+    pub fn commit_token(&self) -> Result<CommitToken> {
+        self.height().map(CommitToken::new)
+    }
+
+    /// Root hash of the tree after the commit at `height`
+    ///
+    /// Roots are kept for the heights of the version window, like the versions of the keys
+    /// read with `get_ver`, and for the heights kept by the `PruningPolicy`. Backends without
+    /// a merkle tree have none.
+    pub fn root_hash_at(&self, height: impl Into<Height>) -> Result<Vec<u8>> {
+        let height = height.into().get();
+        self.get_aux(&Self::root_at_key(height))
+            .c(d!())?
+            .c(d!(format!("no root recorded at height {}", height)))
+    }
+
+    /// Root hashes a chain state on `db` still reads at, the current root first
+    ///
+    /// They are the current root and the roots recorded by `root_hash_at`, i.e. the trees a
+    /// garbage collection of the nodes of `db` must keep. Read errors are returned.
+    pub fn retained_roots(db: &D) -> Result<Vec<Vec<u8>>> {
+        let mut roots = vec![];
+        let root = db.root_hash();
+        if !root.is_empty() && root != NULL_HASH {
+            roots.push(root);
+        }
+        let prefix = Prefix::new(ROOT_AT_KEY);
+        for kv in db.try_iter_aux(&prefix.begin(), &prefix.end(), IterOrder::Asc) {
+            let (_, root) = kv.c(d!())?;
+            if !roots.iter().any(|r| r[..] == root[..]) {
+                roots.push(root.to_vec());
+            }
+        }
+        Ok(roots)
+    }
+
+    // Aux entry of the current root in the roots of the version window
+    fn root_at(&self, height: u64) -> Option<KVEntry> {
+        let root = self.db.root_hash();
+        if root.is_empty() {
+            return None;
+        }
+        Some((Self::root_at_key(height), Some(root)))
+    }
+
+    /// Appends the root of every later commit to a Merkle Mountain Range
+    ///
+    /// The range is kept in the aux data and stays enabled once the chain state is reopened.
+    /// Heights committed before it was enabled, and the commits of backends without a merkle
+    /// tree, are not in the range.
+    pub fn enable_root_mmr(&mut self) {
+        self.root_mmr = true;
+    }
+
+    pub fn root_mmr_enabled(&self) -> bool {
+        self.root_mmr
+    }
+
+    /// Root of the Merkle Mountain Range of the committed roots, see `prove_root_inclusion`
+    pub fn mmr_root(&self) -> Result<Vec<u8>> {
+        let leaves = self.mmr_size().c(d!())?.map_or(0, |(leaves, _)| leaves);
+        let peaks = mmr::peaks(leaves)
+            .into_iter()
+            .map(|(level, index)| self.mmr_node(level, index))
+            .collect::<Result<Vec<_>>>()
+            .c(d!())?;
+        Ok(mmr::bag_peaks(&peaks))
+    }
+
+    /// Proof of the root hash committed at `height` against the current `mmr_root`
+    pub fn prove_root_inclusion(&self, height: impl Into<Height>) -> Result<RootInclusionProof> {
+        let height = height.into().get();
+        let (leaves, _) = self.mmr_size().c(d!())?.c(d!("no root mmr"))?;
+        let leaf_index = self
+            .get_aux(&Self::mmr_leaf_key(height))
+            .c(d!())?
+            .c(d!(format!("root of height {} is not in the mmr", height)))?;
+        let leaf_index = String::from_utf8(leaf_index)
+            .c(d!())?
+            .parse::<u64>()
+            .c(d!())?;
+        let root_hash = self.root_hash_in_mmr(height, leaf_index).c(d!())?;
+        let (siblings, peaks) = mmr::prove(leaves, leaf_index, &|level, index| {
+            self.mmr_node(level, index)
+        })
+        .c(d!())?;
+        Ok(RootInclusionProof {
+            height,
+            root_hash,
+            leaf_index,
+            leaves,
+            siblings,
+            peaks,
+        })
+    }
+
+    // The root of `height` is only kept in the version window, the leaf stores it too
+    fn root_hash_in_mmr(&self, height: u64, leaf_index: u64) -> Result<Vec<u8>> {
+        self.get_aux(&Self::mmr_root_key(leaf_index))
+            .c(d!())?
+            .c(d!(format!("missing mmr root of height {}", height)))
+    }
+
+    // Number of leaves of the range and the height of the last one
+    fn mmr_size(&self) -> Result<Option<(u64, u64)>> {
+        let size = match self.get_aux(&Self::mmr_size_key()).c(d!())? {
+            Some(size) => String::from_utf8(size).c(d!("invalid mmr size"))?,
+            None => return Ok(None),
+        };
+        let (leaves, height) = size.split_once('_').c(d!("invalid mmr size"))?;
+        Ok(Some((
+            leaves.parse::<u64>().c(d!("invalid mmr size"))?,
+            height.parse::<u64>().c(d!("invalid mmr size"))?,
+        )))
+    }
+
+    fn mmr_size_key() -> Vec<u8> {
+        Prefix::new(MMR_KEY).push(b"Size").as_ref().to_vec()
+    }
+
+    fn mmr_node(&self, level: u32, index: u64) -> Result<Vec<u8>> {
+        self.get_aux(&Self::mmr_node_key(level, index))
+            .c(d!())?
+            .c(d!("missing mmr node"))
+    }
+
+    fn mmr_node_key(level: u32, index: u64) -> Vec<u8> {
+        Prefix::new(MMR_KEY)
+            .push_sub(b"Node", format!("{:02}", level).as_bytes())
+            .push(Self::height_str(index).as_bytes())
+            .as_ref()
+            .to_vec()
+    }
+
+    fn mmr_leaf_key(height: u64) -> Vec<u8> {
+        Prefix::new(MMR_KEY)
+            .push_sub(b"Leaf", Self::height_str(height).as_bytes())
+            .as_ref()
+            .to_vec()
+    }
+
+    fn mmr_root_key(leaf_index: u64) -> Vec<u8> {
+        Prefix::new(MMR_KEY)
+            .push_sub(b"Root", Self::height_str(leaf_index).as_bytes())
+            .as_ref()
+            .to_vec()
+    }
+
+    // Aux entries appending the current root to the range, a height already in it is kept
+    fn mmr_aux(&self, height: u64) -> Result<KVBatch> {
+        if self.db.root_hash().is_empty() {
+            return Ok(vec![]);
         }
+        let (leaves, last) = self.mmr_size().c(d!())?.unwrap_or_default();
+        if leaves > 0 && height <= last {
+            return Ok(vec![]);
+        }
+        let root_hash = self.root_hash();
+        let nodes = mmr::append(
+            leaves,
+            mmr::leaf_hash(height, &root_hash),
+            &|level, index| self.mmr_node(level, index),
+        )
+        .c(d!())?;
+
+        let mut batch = nodes
+            .into_iter()
+            .map(|((level, index), hash)| (Self::mmr_node_key(level, index), Some(hash)))
+            .collect::<KVBatch>();
+        batch.push((Self::mmr_root_key(leaves), Some(root_hash)));
+        batch.push((
+            Self::mmr_leaf_key(height),
+            Some(leaves.to_string().into_bytes()),
+        ));
+        batch.push((
+            Self::mmr_size_key(),
+            Some(format!("{}_{}", leaves + 1, height).into_bytes()),
+        ));
+        Ok(batch)
+    }
 
-        // Replay historical commit, if any, on every height
-        for h in *ver_range.start()..=height {
-            let mut kvs = KVMap::new();
+    pub fn pruning_policy(&self) -> PruningPolicy {
+        self.pruning
+    }
 
-            // setup bounds
-            let lower = Prefix::new("VER".as_bytes()).push(Self::height_str(h).as_bytes());
-            let upper = Prefix::new("VER".as_bytes()).push(Self::height_str(h + 1).as_bytes());
+    /// Keys written at `height` with their values, `None` for deletes
+    ///
+    /// Heights of the version window are read from their versions, older ones only if the
+    /// `PruningPolicy` kept them.
+    pub fn changes_at(&self, height: impl Into<Height>) -> Result<KVBatch> {
+        let height = height.into().get();
+        if self.ver_window == 0 {
+            return Err(eg!("non-versioned chain"));
+        }
+        let prefix = if height >= self.min_height {
+            Self::versioned_key_prefix(height)
+        } else if self.pruning.keeps(height) {
+            Prefix::new(KEEP_SECTION).push(Self::height_str(height).as_bytes())
+        } else {
+            return Err(eg!(format!("changes of height {} are pruned", height)));
+        };
 
-            // collect commits on this height
-            self.iterate_aux(
-                &lower.begin(),
-                &upper.begin(),
-                IterOrder::Asc,
-                &mut |(k, v)| -> bool {
-                    let raw_key = Self::get_raw_versioned_key(&k).unwrap_or_default();
-                    if raw_key.is_empty() {
-                        return false;
-                    }
+        let mut batch = KVBatch::new();
+        self.iterate_aux(
+            &prefix.begin(),
+            &prefix.end(),
+            IterOrder::Asc,
+            &mut |(k, v)| -> bool {
+                let raw_key = Self::get_raw_versioned_key(&k).unwrap_or_default();
+                if !raw_key.is_empty() {
+                    let v = if v.eq(&TOMBSTONE) { None } else { Some(v) };
+                    batch.push((raw_key.into_bytes(), v));
+                }
+                false
+            },
+        );
+        Ok(batch)
+    }
 
-                    if v.eq(&TOMBSTONE) {
-                        kvs.insert(raw_key.as_bytes().to_vec(), None);
-                    } else {
-                        kvs.insert(raw_key.as_bytes().to_vec(), Some(v));
-                    }
-                    false
-                },
-            );
+    fn kept_key(key: &[u8], height: u64) -> Vec<u8> {
+        Prefix::new(KEEP_SECTION)
+            .push(Self::height_str(height).as_bytes())
+            .push(key)
+            .as_ref()
+            .to_vec()
+    }
 
-            // commit this batch
-            let batch = kvs.into_iter().collect::<Vec<_>>();
-            if cs.commit(batch, h, true).is_err() {
-                let msg = format!("Replay failed on height {}", h);
-                return Err(eg!(msg));
-            }
-        }
+    // Height of a key of the version window or of the roots, e.g. `VER_{height}_{key}`
+    fn key_height(key: &[u8]) -> Option<u64> {
+        str::from_utf8(key)
+            .ok()?
+            .split(SPLIT_BGN)
+            .nth(1)?
+            .parse::<u64>()
+            .ok()
+    }
 
-        Ok(())
+    fn root_at_key(height: u64) -> Vec<u8> {
+        Prefix::new(ROOT_AT_KEY)
+            .push(Self::height_str(height).as_bytes())
+            .as_ref()
+            .to_vec()
     }
 
-    /// Take a snapshot of chain state on a specific height.
-    ///
-    /// * `path` - The path of database that holds the snapshot.
-    ///
-    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        self.db.snapshot(path)
+    // Aux entry tying `height` to the current root, `None` for backends without a merkle tree
+    fn root_record(&self, height: u64) -> Option<KVEntry> {
+        let root = self.db.root_hash();
+        if root.is_empty() {
+            return None;
+        }
+        let record = format!("{}_{}", height, hex::encode(&root));
+        Some((ROOT_RECORD_KEY.to_vec(), Some(record.into_bytes())))
     }
 
-    /// Calculate and returns current root hash of the Merkle tree
-    pub fn root_hash(&self) -> Vec<u8> {
-        let hash = self.db.root_hash();
-        if hash == NULL_HASH {
-            return vec![];
+    // Ties `source_root`, the root of the same keys in another backend, to the root of `db`,
+    // see `portable`; the root records written by the source backend are left as they were
+    pub(crate) fn record_source_root(db: &mut D, source_root: &[u8]) -> Result<()> {
+        let root = db.root_hash();
+        if root == source_root {
+            return Ok(());
         }
-        hash
+        let record = format!("{}_{}", hex::encode(source_root), hex::encode(&root));
+        let aux = vec![(SOURCE_ROOT_KEY.to_vec(), Some(record.into_bytes()))];
+        db.commit(aux, false).c(d!())
     }
 
-    /// Returns current height of the ChainState
-    pub fn height(&self) -> Result<u64> {
-        let height = self.db.get_aux(HEIGHT_KEY).c(d!())?;
-        if let Some(value) = height {
-            let height_str = String::from_utf8(value).c(d!())?;
-            let last_height = height_str.parse::<u64>().c(d!())?;
+    // Whether `recorded` is the root of the source backend the current tree was restored from
+    fn is_source_root(&self, recorded: &[u8], root: &[u8]) -> Result<bool> {
+        let record = match self.db.get_aux(SOURCE_ROOT_KEY).c(d!())? {
+            Some(record) => String::from_utf8(record).c(d!("invalid source root record"))?,
+            None => return Ok(false),
+        };
+        let (source, restored) = record.split_once('_').c(d!("invalid source root record"))?;
+        let source = hex::decode(source).c(d!("invalid source root record"))?;
+        let restored = hex::decode(restored).c(d!("invalid source root record"))?;
+        Ok(source == recorded && restored == root)
+    }
 
-            return Ok(last_height);
+    /// Checks that the committed height, the root recorded with it and the root of the
+    /// backend tree agree
+    ///
+    /// Stores written before roots were recorded, and backends without a merkle tree, have
+    /// nothing to compare and pass, the tree of an unfinished import isn't compared either.
+    /// Called when a chain state is opened.
+    pub fn verify_height_consistency(&self) -> Result<()> {
+        if self.db.get_aux(RESTORE_PROGRESS_KEY).c(d!())?.is_some() {
+            return Err(eg!(format!(
+                "{}: unfinished restore from snapshot chunks, finish it with \
+                 `restore_from_chunks` or re-sync into an empty db",
+                self.name
+            )));
         }
-        Ok(0u64)
+        let record = match self.db.get_aux(ROOT_RECORD_KEY).c(d!())? {
+            Some(record) => String::from_utf8(record).c(d!("invalid root record"))?,
+            None => return Ok(()),
+        };
+        let (recorded_height, recorded_root) =
+            record.split_once('_').c(d!("invalid root record"))?;
+        let recorded_height = recorded_height
+            .parse::<u64>()
+            .c(d!("invalid root record"))?;
+        let recorded_root = hex::decode(recorded_root).c(d!("invalid root record"))?;
+
+        let height = self.height().c(d!())?;
+        if recorded_height != height {
+            return Err(eg!(format!(
+                "{}: committed height {} but the root was last recorded at height {}, the aux \
+                 data was changed outside of a commit; restore a snapshot or re-open with \
+                 `cleanup_aux` if the tree is known to be at height {}",
+                self.name, height, recorded_height, height
+            )));
+        }
+        let root = self.db.root_hash();
+        if recorded_root != root
+            && self.import_progress().c(d!())?.is_none()
+            && !self.is_source_root(&recorded_root, &root).c(d!())?
+        {
+            return Err(eg!(format!(
+                "{}: root {} recorded at height {} but the tree root is {}, the tree was \
+                 written without its aux data, e.g. by an interrupted import or a partial \
+                 restore; restore a snapshot taken at height {} or re-sync the state",
+                self.name,
+                hex::encode(&recorded_root),
+                height,
+                hex::encode(&root),
+                height
+            )));
+        }
+        Ok(())
     }
 
     // Get max height of keys stored in `base`
@@ -615,8 +2317,8 @@ impl<D: MerkleDB> ChainState<D> {
     /// The fn is NOT building a full chainstate any more after BASE introduced, it's now just building the delta
     /// - Option-1: Considering renaming as `build_state_delta()` in future
     /// - Option-2: Considering add a flag `delta_or_full` parameter in future
-    pub fn build_state(&self, height: u64, prefix: Option<Prefix>) -> KVBatch {
-        self.build_state_to(None, height, prefix, false)
+    pub fn build_state(&self, height: impl Into<Height>, prefix: Option<Prefix>) -> KVBatch {
+        self.build_state_to(None, height.into().get(), prefix, false)
     }
 
     // height range is [s, e]
@@ -679,12 +2381,31 @@ impl<D: MerkleDB> ChainState<D> {
             lower.begin().as_ref(),
             upper.as_ref(),
             IterOrder::Asc,
-            &mut |(k, _v)| -> bool {
+            &mut |(k, v)| -> bool {
+                let h = Self::key_height(&k);
+                if h.is_some_and(|h| self.pruning.keeps(h)) {
+                    let raw_key = Self::get_raw_versioned_key(&k).unwrap_or_default();
+                    batch.push((
+                        Self::kept_key(raw_key.as_bytes(), h.unwrap_or_default()),
+                        Some(v),
+                    ));
+                }
                 //Delete the key from aux db
                 batch.push((k, None));
                 false
             },
         );
+        self.iterate_aux(
+            &Prefix::new(ROOT_AT_KEY).begin(),
+            &Self::root_at_key(height + 1),
+            IterOrder::Asc,
+            &mut |(k, _v)| -> bool {
+                if !Self::key_height(&k).is_some_and(|h| self.pruning.keeps(h)) {
+                    batch.push((k, None));
+                }
+                false
+            },
+        );
 
         batch
     }
@@ -694,7 +2415,8 @@ impl<D: MerkleDB> ChainState<D> {
     /// Returns the value of the given key at a particular height
     /// Returns None if the key was deleted or invalid at height H
     #[cfg(feature = "optimize_get_ver")]
-    pub fn get_ver(&self, key: &[u8], height: u64) -> Result<Option<Vec<u8>>> {
+    pub fn get_ver(&self, key: &[u8], height: impl Into<Height>) -> Result<Option<Vec<u8>>> {
+        let height = height.into().get();
         if self.ver_window == 0 {
             return Err(eg!("non-versioned chain"));
         }
@@ -728,6 +2450,8 @@ impl<D: MerkleDB> ChainState<D> {
         if lower_bound > self.min_height {
             lower_bound = self.min_height
         }
+        // versions before a truncation of the change log are only left in base
+        lower_bound = lower_bound.max(self.min_height);
 
         match lower_bound.cmp(&height.saturating_add(1)) {
             Ordering::Greater => {
@@ -787,7 +2511,8 @@ impl<D: MerkleDB> ChainState<D> {
     /// Returns the value of the given key at a particular height
     /// Returns None if the key was deleted or invalid at height H
     #[cfg(not(feature = "optimize_get_ver"))]
-    pub fn get_ver(&self, key: &[u8], height: u64) -> Result<Option<Vec<u8>>> {
+    pub fn get_ver(&self, key: &[u8], height: impl Into<Height>) -> Result<Option<Vec<u8>>> {
+        let height = height.into().get();
         //Make sure that this key exists to avoid expensive query
         let val = self.get(key).c(d!("error getting value"))?;
         if val.is_none() {
@@ -808,6 +2533,8 @@ impl<D: MerkleDB> ChainState<D> {
         if lower_bound > self.min_height {
             lower_bound = self.min_height
         }
+        // versions before a truncation of the change log are only left in base
+        lower_bound = lower_bound.max(self.min_height);
 
         // The keys at querying height are moved to base and override by later height
         // So we cannot determine version info of the querying key
@@ -838,7 +2565,7 @@ impl<D: MerkleDB> ChainState<D> {
     }
 
     // simple commit to db
-    fn commit_db_with_meta(&mut self, mut batch: KVBatch) {
+    fn commit_db_with_meta(&mut self, mut batch: KVBatch) -> Result<()> {
         // Update aux version if needed
         if self.version != AUX_VERSION_02 {
             batch.push((
@@ -848,16 +2575,16 @@ impl<D: MerkleDB> ChainState<D> {
         }
 
         //Commit this batch to db
-        if self.db.commit(batch, true).is_err() {
-            println!("error building base chain state");
-            return;
-        }
+        self.db
+            .commit(batch, true)
+            .c(d!("error building base chain state"))?;
 
         // Read back to make sure previous commit works well and update in-memory field
         self.version = self
             .get_aux_version()
-            .expect("cannot read back version")
-            .expect("Need a valid version");
+            .c(d!("cannot read back version"))?
+            .c(d!("Need a valid version"))?;
+        Ok(())
     }
 
     fn build_snapshots_at_height(
@@ -872,25 +2599,10 @@ impl<D: MerkleDB> ChainState<D> {
             return;
         }
 
-        // Versioned keys before height `min_height` have been pruned and moved to `base`,
-        // if there is a snapshot at height `min_height-1`, it should be removed too.
-        // This could be multiple removals if `unpin` operations occurred.
-        for snapshot_at in last_min_height..self.min_height {
-            if snapshot_at > 0 && snapshot_at % self.interval == 0 {
-                let mut batch = self.remove_snapshot(snapshot_at);
-                aux_batch.append(&mut batch);
-                while let Some(last) = self.snapshot_info.front() {
-                    if last.end <= snapshot_at {
-                        self.snapshot_info.pop_front();
-                    } else {
-                        break;
-                    }
-                }
-            }
-        }
+        self.remove_pruned_snapshots(last_min_height, aux_batch);
 
         // create last snapshot if necessary
-        if height > 1 && height.saturating_sub(1) % self.interval == 0 {
+        if height > 1 && height.saturating_sub(1).is_multiple_of(self.interval) {
             let e = height.saturating_sub(1);
             let s = if e == self.interval {
                 0
@@ -908,14 +2620,37 @@ impl<D: MerkleDB> ChainState<D> {
         }
     }
 
+    // Versioned keys before height `min_height` have been pruned and moved to `base`,
+    // if there is a snapshot at height `min_height-1`, it should be removed too.
+    // This could be multiple removals if `unpin` operations occurred.
+    fn remove_pruned_snapshots(&mut self, last_min_height: u64, aux_batch: &mut KVBatch) {
+        if self.interval < 2 {
+            return;
+        }
+
+        for snapshot_at in last_min_height..self.min_height {
+            if snapshot_at > 0 && snapshot_at % self.interval == 0 {
+                let mut batch = self.remove_snapshot(snapshot_at);
+                aux_batch.append(&mut batch);
+                while let Some(last) = self.snapshot_info.front() {
+                    if last.end <= snapshot_at {
+                        self.snapshot_info.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
     fn build_snapshots(
         &mut self,
         base_height: Option<u64>,
         prev_interval: u64,
         interval: u64,
         batch: &mut KVBatch,
-    ) {
-        let height = self.height().expect("Failed to read chain height");
+    ) -> Result<()> {
+        let height = self.height().c(d!("Failed to read chain height"))?;
 
         batch.push((
             SNAPSHOT_KEY.to_vec(),
@@ -937,7 +2672,7 @@ impl<D: MerkleDB> ChainState<D> {
         if interval != 0 {
             //create snapshots
             let mut s = base_height.map(|v| v.saturating_add(1)).unwrap_or_default();
-            let mut e = if s != 0 && s % interval == 0 {
+            let mut e = if s != 0 && s.is_multiple_of(interval) {
                 s
             } else {
                 (s / interval + 1) * interval
@@ -951,8 +2686,9 @@ impl<D: MerkleDB> ChainState<D> {
                     batch.append(&mut snapshot);
                     count as u64
                 } else {
-                    // reconstruct snapshot info
-                    self.count_in_snapshot(e)
+                    // counting the keys of every snapshot would hold up the open, it's left
+                    // to `load_deferred`
+                    UNCOUNTED
                 };
                 self.snapshot_info.push_back(SnapShotInfo {
                     start: s,
@@ -963,25 +2699,28 @@ impl<D: MerkleDB> ChainState<D> {
                 e = e.saturating_add(interval);
             }
         }
+        Ok(())
     }
 
     /// When creating a new chain-state instance, any residual aux data outside the current window
     /// needs to be cleared as to not waste memory or disrupt the versioning behaviour.
-    fn clean_aux_db(&mut self, base_height: &mut Option<u64>, batch: &mut KVBatch) {
+    fn clean_aux_db(&mut self, base_height: &mut Option<u64>, batch: &mut KVBatch) -> Result<()> {
         // A ChainState with pinned height, should never call this function
-        assert!(self.pinned_height.is_empty());
+        if !self.pinned_height.is_empty() {
+            return Err(eg!("cannot clean the aux data of a pinned chain state"));
+        }
 
         //Get current height
-        let current_height = self.height().expect("failed to get chain height");
+        let current_height = self.height().c(d!("failed to get chain height"))?;
         if current_height == 0 {
-            return;
+            return Ok(());
         }
-        if current_height < self.ver_window + 1 {
-            // ver_window is increased, just update the min_height
+        if current_height < self.ver_window + 1 || self.pruning == PruningPolicy::KeepAll {
+            // ver_window is increased or nothing is pruned, just update the min_height
             if let Some(h) = base_height {
                 self.min_height = h.saturating_add(1);
             }
-            return;
+            return Ok(());
         }
 
         self.min_height = current_height - self.ver_window;
@@ -989,7 +2728,9 @@ impl<D: MerkleDB> ChainState<D> {
         let mut base_batch = self.build_state(self.min_height - 1, Some(Self::base_key_prefix()));
         let current_base = match base_height {
             Some(h) if *h >= self.min_height => {
-                assert!(base_batch.is_empty());
+                if !base_batch.is_empty() {
+                    return Err(eg!("base height is ahead of the version window"));
+                }
                 self.min_height = h.saturating_add(1);
                 *h
             }
@@ -1007,10 +2748,11 @@ impl<D: MerkleDB> ChainState<D> {
         let mut removed_keys = self.remove_versioned_keys_before(self.min_height - 1);
 
         batch.append(&mut removed_keys);
+        Ok(())
     }
 
-    fn construct_base(&mut self) {
-        let height = self.height().expect("Failed to get chain height");
+    fn construct_base(&mut self) -> Result<()> {
+        let height = self.height().c(d!("Failed to get chain height"))?;
 
         let mut batch = vec![
             (
@@ -1034,7 +2776,7 @@ impl<D: MerkleDB> ChainState<D> {
         //Commit this batch to db
         self.db
             .commit(batch, true)
-            .expect("error constructing chain base state");
+            .c(d!("error constructing chain base state"))
     }
 
     /// Gets current versioning range of the chain-state
@@ -1043,7 +2785,7 @@ impl<D: MerkleDB> ChainState<D> {
     pub fn get_ver_range(&self) -> Result<Range<u64>> {
         let upper = self.height().c(d!("error reading current height"))?;
         let mut lower = 0;
-        if upper > self.ver_window {
+        if upper > self.ver_window && self.pruning != PruningPolicy::KeepAll {
             lower = upper.saturating_sub(self.ver_window);
         }
         if let Some(&pinned) = self.pinned_height.keys().min() {
@@ -1051,17 +2793,150 @@ impl<D: MerkleDB> ChainState<D> {
                 lower = pinned;
             }
         }
+        // the change log may have been truncated ahead of the window
+        lower = lower.max(self.min_height);
         Ok(lower..upper)
     }
 
     pub fn clean_aux(&mut self) -> Result<()> {
-        let height = self.height().expect("Failed to read chain height");
-        let batch = vec![(HEIGHT_KEY.to_vec(), Some(height.to_string().into_bytes()))];
+        let height = self.height().c(d!("Failed to read chain height"))?;
+        let mut batch = vec![(HEIGHT_KEY.to_vec(), Some(height.to_string().into_bytes()))];
+        batch.extend(self.root_record(height));
+        if let Some(pruning) = self.db.get_aux(PRUNING_KEY).c(d!())? {
+            batch.push((PRUNING_KEY.to_vec(), Some(pruning)));
+        }
+        // the range of the roots can't be rebuilt, it is kept
+        self.iterate_aux(
+            &Prefix::new(MMR_KEY).begin(),
+            &Prefix::new(MMR_KEY).end(),
+            IterOrder::Asc,
+            &mut |(k, v)| -> bool {
+                batch.push((k, Some(v)));
+                false
+            },
+        );
 
         self.db.clean_aux()?;
         self.db.commit(batch, true)
     }
 
+    /// Writes the aux data to `writer`, see `aux_dump::import_aux` to read it back
+    pub fn export_aux<W: Write>(&self, writer: &mut W) -> Result<AuxDump> {
+        aux_dump::export_aux(&self.db, writer)
+    }
+
+    /// Wipes the aux data derived from the commits, i.e. the version index, and rebuilds it
+    /// from a scan of the primary section, e.g. after a bug corrupted the index
+    ///
+    /// The rebuilt index starts at the current height, versions of earlier heights are lost.
+    /// The key sketches of the registered prefixes are rebuilt from the scan too. Entries are
+    /// removed and written `batch_size` per commit, `progress` is called after every commit.
+    /// An interrupted reindex is run again when the chain state is opened.
+    pub fn reindex(
+        &mut self,
+        batch_size: usize,
+        progress: &mut dyn FnMut(&ReindexProgress),
+    ) -> Result<ReindexProgress> {
+        if batch_size == 0 {
+            return Err(eg!("batch size must be positive"));
+        }
+        if !self.pinned_height.is_empty() {
+            return Err(eg!("cannot reindex a pinned chain state"));
+        }
+        let height = self.height().c(d!())?;
+        let marker = (REINDEX_KEY.to_vec(), Some(height.to_string().into_bytes()));
+        self.db.commit(vec![marker], true).c(d!())?;
+        let mut done = ReindexProgress::default();
+
+        let derived = [
+            Prefix::new(b"VER"),
+            Prefix::new(b"BASE"),
+            Prefix::new(b"SNAPSHOT"),
+            Prefix::new(KEEP_SECTION),
+            Prefix::new(ROOT_AT_KEY),
+        ];
+        for prefix in derived.iter() {
+            loop {
+                let batch = self
+                    .db
+                    .iter_aux(&prefix.begin(), &prefix.end(), IterOrder::Asc)
+                    .take(batch_size)
+                    .map(|(k, _)| (k.to_vec(), None))
+                    .collect::<KVBatch>();
+                if batch.is_empty() {
+                    break;
+                }
+                done.removed = done.removed.saturating_add(batch.len() as u64);
+                self.db.commit(batch, false).c(d!())?;
+                done.batches = done.batches.saturating_add(1);
+                progress(&done);
+            }
+        }
+
+        for sketch in self.key_sketches.values_mut() {
+            *sketch = KeySketch::default();
+        }
+        let mut lower = vec![];
+        loop {
+            let entries = self
+                .db
+                .iter(&lower, &KEYS_UPPER, IterOrder::Asc)
+                .take(batch_size)
+                .map(|kv_pair| self.db.decode_kv(kv_pair))
+                .collect::<Vec<_>>();
+            let (last_key, _) = match entries.last() {
+                Some(last) => last.clone(),
+                None => break,
+            };
+            done.indexed = done.indexed.saturating_add(entries.len() as u64);
+            let mut batch = KVBatch::with_capacity(entries.len());
+            for (k, v) in entries {
+                for (prefix, sketch) in self
+                    .key_sketches
+                    .range_mut::<[u8], _>((Bound::Unbounded, Bound::Included(k.as_slice())))
+                {
+                    if k.starts_with(prefix) {
+                        sketch.record(&k);
+                    }
+                }
+                if self.ver_window != 0 {
+                    batch.push((Self::base_key(&k), Some(v)));
+                }
+            }
+            self.db.commit(batch, false).c(d!())?;
+            done.batches = done.batches.saturating_add(1);
+            // the next batch starts right after the last key
+            lower = last_key.clone();
+            lower.push(0);
+            done.last_key = last_key;
+            progress(&done);
+        }
+
+        let mut aux = vec![
+            (REINDEX_KEY.to_vec(), None),
+            (
+                AUX_VERSION.to_vec(),
+                Some(AUX_VERSION_02.to_string().into_bytes()),
+            ),
+            (
+                BASE_HEIGHT_KEY.to_vec(),
+                Some(height.to_string().into_bytes()),
+            ),
+            (
+                SNAPSHOT_KEY.to_vec(),
+                Some(self.interval.to_string().into_bytes()),
+            ),
+        ];
+        if self.ver_window != 0 {
+            aux.extend(self.root_at(height));
+        }
+        self.db.commit(aux, true).c(d!())?;
+        self.version = AUX_VERSION_02;
+        self.min_height = height.saturating_add(1);
+        self.snapshot_info.clear();
+        Ok(done)
+    }
+
     /// get current pinned height
     ///
     pub fn current_pinned_height(&self) -> Vec<u64> {
@@ -1178,16 +3053,110 @@ impl<D: MerkleDB> ChainState<D> {
         last
     }
 
+    /// Records that replication follower `follower` has applied all commits up to `height`
+    pub fn ack_follower(&mut self, follower: &str, height: u64) {
+        let acked = self.follower_acks.entry(follower.to_owned()).or_insert(0);
+        if height > *acked {
+            *acked = height;
+        }
+    }
+
+    /// Stops retaining versioned keys for `follower`
+    pub fn remove_follower(&mut self, follower: &str) {
+        self.follower_acks.remove(follower);
+    }
+
+    /// Returns the lowest height acknowledged by all followers, `None` if there is no follower
+    pub fn min_follower_ack(&self) -> Option<u64> {
+        self.follower_acks.values().min().copied()
+    }
+
+    /// Truncates the change log, i.e. the versioned keys, before `height`
+    ///
+    /// Versions older than `height` are merged into base ahead of the version window. The
+    /// truncation never goes past a pinned height or past a commit some follower hasn't
+    /// acknowledged yet, so it may stop short of `height`.
+    ///
+    /// Returns the lowest height still covered by the change log.
+    pub fn truncate_log_before(&mut self, height: u64) -> Result<u64> {
+        if self.ver_window == 0 {
+            return Err(eg!("Not supported for an non-versioned chain"));
+        }
+
+        let mut target = height.min(self.height().c(d!())?);
+        if let Some(acked) = self.min_follower_ack() {
+            target = target.min(acked.saturating_add(1));
+        }
+        if let Some(pinned) = self.pinned_height.keys().min() {
+            target = target.min(*pinned);
+        }
+        if target <= self.min_height {
+            return Ok(self.min_height);
+        }
+
+        let mut batch = KVBatch::new();
+        for h in self.min_height..target {
+            self.move_versions_to_base(h, &mut batch);
+        }
+
+        let last_min_height = self.min_height;
+        self.min_height = target;
+        batch.push((
+            BASE_HEIGHT_KEY.to_vec(),
+            Some((target - 1).to_string().into_bytes()),
+        ));
+        self.remove_pruned_snapshots(last_min_height, &mut batch);
+
+        self.db.commit(batch, true).c(d!())?;
+        self.refresh_watermarks(true);
+        Ok(target)
+    }
+
     /// Get snapshot info
     pub fn get_snapshots_info(&self) -> Vec<SnapShotInfo> {
         self.snapshot_info.iter().cloned().collect()
     }
 
+    /// Whether the loading deferred by the open is done, i.e. the keys of the snapshots kept
+    /// from before the open are counted
+    ///
+    /// `get_ver` returns the same values before, it reads the snapshots with an unknown count
+    /// instead of skipping the empty ones.
+    pub fn ready(&self) -> bool {
+        self.snapshot_info.iter().all(|ss| ss.count != UNCOUNTED)
+    }
+
+    /// Counts the keys of the snapshots kept from before the open, see `ready`
+    ///
+    /// Meant to run on a background thread once the chain state is shared, each snapshot is
+    /// counted under a read lock of its own so commits go on in between.
+    pub fn load_deferred(cs: &RwLock<Self>) {
+        loop {
+            let (end, count) = {
+                let cs = cs.read();
+                let end = match cs.snapshot_info.iter().find(|ss| ss.count == UNCOUNTED) {
+                    Some(ss) => ss.end,
+                    None => return,
+                };
+                (end, cs.count_in_snapshot(end))
+            };
+            // the snapshot may have been pruned meanwhile, the keys of one kept don't change
+            let mut cs = cs.write();
+            if let Some(ss) = cs
+                .snapshot_info
+                .iter_mut()
+                .find(|ss| ss.end == end && ss.count == UNCOUNTED)
+            {
+                ss.count = count;
+            }
+        }
+    }
+
     /// The height of last snapshot before `height(included)`
     pub fn last_snapshot_before(&self, height: u64) -> Option<u64> {
         let interval = self.interval;
         if interval >= 2 {
-            Some(if height % interval == 0 {
+            Some(if height.is_multiple_of(interval) {
                 height
             } else {
                 height / interval * interval
@@ -1276,7 +3245,8 @@ impl<D: MerkleDB> ChainState<D> {
     }
 
     // hMove all the data before the specified height to base
-    pub fn height_internal_to_base(&mut self, height: u64) -> Result<()> {
+    pub fn height_internal_to_base(&mut self, height: impl Into<Height>) -> Result<()> {
+        let height = height.into().get();
         let mut batch = KVBatch::new();
 
         self.all_iterator(IterOrder::Asc, &mut |(k, v)| -> bool {
@@ -1289,9 +3259,140 @@ impl<D: MerkleDB> ChainState<D> {
             BASE_HEIGHT_KEY.to_vec(),
             Some(height.to_string().into_bytes()),
         ));
-        if self.db.commit(batch, true).is_err() {
-            panic!("error move before a certain height chain state");
-        }
-        Ok(())
+        self.db
+            .commit(batch, true)
+            .c(d!("error move before a certain height chain state"))
+    }
+}
+
+impl<D: MerkleDB + Sync> ChainState<D> {
+    /// Same as `warm_up`, but the prefixes are split over a thread per available core.
+    ///
+    /// Returns the number of KV pairs loaded.
+    pub fn warm_up_parallel(&self, prefixes: &[Prefix]) -> u64 {
+        let workers = thread::available_parallelism()
+            .map_or(1, usize::from)
+            .min(prefixes.len())
+            .max(1);
+        let chunk = prefixes.len().div_ceil(workers).max(1);
+        thread::scope(|s| {
+            let handles = prefixes
+                .chunks(chunk)
+                .map(|ps| s.spawn(move || ps.iter().map(|p| self.warm_up_prefix(p)).sum::<u64>()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .sum()
+        })
     }
 }
+
+/// Replaces the content of `buf` with `value` and returns its length, see `MerkleDB::get_into`
+pub(crate) fn copy_into(value: Option<&[u8]>, buf: &mut Vec<u8>) -> Option<usize> {
+    buf.clear();
+    value.map(|value| {
+        buf.extend_from_slice(value);
+        value.len()
+    })
+}
+
+/// Describes the aux keys of a chain state and the encoding of its version index
+pub(crate) fn describe_aux() -> serde_json::Value {
+    let key = |key: &[u8], value: &str, description: &str| {
+        serde_json::json!({
+            "key": String::from_utf8_lossy(key),
+            "value": value,
+            "description": description,
+        })
+    };
+    let section = |prefix: Prefix, value: &str, description: &str| {
+        key(prefix.push(b"{key}").as_ref(), value, description)
+    };
+    let height = "{height}".as_bytes();
+    serde_json::json!({
+        "aux_version": AUX_VERSION_02,
+        "separator": SPLIT_BGN,
+        "height_in_keys": "decimal, zero-padded to 20 digits",
+        "tombstone": hex::encode(&TOMBSTONE),
+        "keys": [
+            key(HEIGHT_KEY, "decimal u64", "height of the last commit"),
+            key(BASE_HEIGHT_KEY, "decimal u64", "last height merged into the base section"),
+            key(SNAPSHOT_KEY, "decimal u64", "interval of the snapshot section"),
+            key(AUX_VERSION, "decimal u64", "version of the aux format"),
+            key(IMPORT_PROGRESS_KEY, "decimal u64", "batches committed by an unfinished import"),
+            key(EXPORT_FILTER_KEY, "JSON", "prefix filter of a filtered export"),
+            key(ROOT_RECORD_KEY, "{height}_{hex root hash}", "root of the last commit"),
+            key(
+                SOURCE_ROOT_KEY,
+                "{hex source root hash}_{hex root hash}",
+                "root of the backend a portable snapshot was taken from",
+            ),
+            key(RESTORE_PROGRESS_KEY, "JSON", "progress of an unfinished restore from snapshot chunks"),
+            key(REINDEX_KEY, "decimal u64", "height of an unfinished reindex"),
+            key(PRUNING_KEY, "JSON", "pruning policy given to an open"),
+        ],
+        "sections": [
+            section(
+                Prefix::new(b"VER").push(height),
+                "value or tombstone",
+                "keys written at a height of the version window",
+            ),
+            section(
+                Prefix::new(b"BASE").push(b"00000000000000000000"),
+                "value or tombstone",
+                "values at the base height, merged out of the version window",
+            ),
+            section(
+                Prefix::new(KEEP_SECTION).push(height),
+                "value or tombstone",
+                "keys written at a height kept by the pruning policy once out of the window",
+            ),
+            section(
+                Prefix::new(b"SNAPSHOT").push(height),
+                "value or tombstone",
+                "values at every interval height, to speed up reads of old versions",
+            ),
+            key(
+                Prefix::new(SEQUENCE_KEY).push(b"{name}").as_ref(),
+                "decimal u64",
+                "last value of a named sequence",
+            ),
+            key(
+                Prefix::new(ROOT_AT_KEY).push(height).as_ref(),
+                "raw root hash",
+                "root after the commit of a height of the version window, or of a height kept by \
+                 the pruning policy; none on backends without a merkle tree",
+            ),
+            key(
+                Prefix::new(CHALLENGE_KEY).push(height).as_ref(),
+                "binary, see FraudProofBundle::encode",
+                "fraud proof bundle of a height of the challenge window: the changelog of the \
+                 height with the values it overwrote, proven against the previous root, and a \
+                 proof of the values it wrote against its root",
+            ),
+            key(
+                Prefix::new(MMR_KEY).push(b"Size").as_ref(),
+                "{leaves}_{height of the last leaf}",
+                "size of the Merkle Mountain Range of the roots",
+            ),
+            key(
+                Prefix::new(MMR_KEY).push_sub(b"Node", b"{level}").push(b"{index}").as_ref(),
+                "hash",
+                "node of the Merkle Mountain Range of the roots, the level is zero-padded to 2 \
+                 digits and the index like heights",
+            ),
+            key(
+                Prefix::new(MMR_KEY).push_sub(b"Leaf", height).as_ref(),
+                "decimal u64",
+                "index of the leaf of a height in the Merkle Mountain Range",
+            ),
+            key(
+                Prefix::new(MMR_KEY).push_sub(b"Root", b"{index}").as_ref(),
+                "root hash",
+                "root hash of a leaf of the Merkle Mountain Range, the index is zero-padded like \
+                 heights",
+            ),
+        ],
+    })
+}
@@ -0,0 +1,362 @@
+/// Snapshot streaming in chunks for state sync
+///
+/// `ChainState::snapshot_chunks` splits the primary section at the current height into chunks
+/// of sorted entries a serving node hands out to syncing peers, and
+/// `ChainState::restore_from_chunks` writes them back into an empty db on the syncing side.
+///
+/// The hash of a chunk covers its entries and the hash of the chunk before it, the first one
+/// starts from the height and root hash of the snapshot. A restorer thus rejects a chunk out of
+/// order or of another snapshot as soon as it's applied. The chunks of a backend with range
+/// proofs also carry a proof of the key range they cover against the root hash, a restorer
+/// with a verifier rejects a forged chunk as soon as it's applied as well. Without proofs it's
+/// caught when the root of the restored tree is compared with the trusted root, and the
+/// restore starts over.
+///
+use crate::{
+    db::{DbIter, IterOrder, KVBatch, MerkleDB},
+    hex,
+    remote::ProofVerifier,
+    state::chain_state::{ChainState, ChainStateOpts, KEYS_UPPER, RESTORE_PROGRESS_KEY},
+};
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::iter::Peekable;
+
+/// One chunk of a snapshot
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotChunk {
+    pub height: u64,
+    /// root hash the snapshot is taken at
+    pub root_hash: Vec<u8>,
+    pub index: u64,
+    /// whether it's the last chunk of the snapshot
+    pub last: bool,
+    /// entries in ascending key order, keys of a chunk follow those of the chunk before it
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    pub hash: Vec<u8>,
+    /// end of the key range of the chunk, excluded, i.e. the first key of the next chunk
+    ///
+    /// The range starts at the end of the chunk before it, the last chunk ends at `KEYS_UPPER`.
+    pub end: Vec<u8>,
+    /// proof of the pairs in the key range against the root hash, empty if not proven
+    pub proof: Vec<u8>,
+}
+
+impl SnapshotChunk {
+    /// Hash the chain of the chunks of a snapshot starts from
+    pub fn first_prev_hash(height: u64, root_hash: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(height.to_be_bytes());
+        hasher.update(root_hash);
+        hasher.finalize().to_vec()
+    }
+
+    /// Hash of the chunk following the chunk of hash `prev_hash`
+    pub fn compute_hash(&self, prev_hash: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(prev_hash);
+        hasher.update(self.index.to_be_bytes());
+        hasher.update([u8::from(self.last)]);
+        hasher.update((self.entries.len() as u64).to_be_bytes());
+        for (k, v) in self.entries.iter() {
+            hasher.update((k.len() as u64).to_be_bytes());
+            hasher.update(k);
+            hasher.update((v.len() as u64).to_be_bytes());
+            hasher.update(v);
+        }
+        hasher.finalize().to_vec()
+    }
+
+    /// Checks the hash of the chunk, `prev_hash` being the hash of the chunk before it
+    pub fn verify(&self, prev_hash: &[u8]) -> Result<()> {
+        if self.compute_hash(prev_hash) != self.hash {
+            return Err(eg!(format!(
+                "invalid hash of snapshot chunk {}",
+                self.index
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Chunks of the primary section, see `ChainState::snapshot_chunks`
+pub struct SnapshotChunks<'a, D: MerkleDB> {
+    db: &'a D,
+    iter: Peekable<DbIter<'a>>,
+    height: u64,
+    root_hash: Vec<u8>,
+    chunk_size: usize,
+    index: u64,
+    prev_hash: Vec<u8>,
+    // start of the key range of the next chunk
+    start: Vec<u8>,
+    proofs: bool,
+    done: bool,
+}
+
+impl<'a, D: MerkleDB> SnapshotChunks<'a, D> {
+    pub(crate) fn new(db: &'a D, height: u64, root_hash: Vec<u8>, chunk_size: usize) -> Self {
+        SnapshotChunks {
+            db,
+            iter: db.db_all_iterator(IterOrder::Asc).peekable(),
+            height,
+            prev_hash: SnapshotChunk::first_prev_hash(height, &root_hash),
+            root_hash,
+            chunk_size: chunk_size.max(1),
+            index: 0,
+            start: vec![],
+            proofs: false,
+            done: false,
+        }
+    }
+
+    /// Proves the key range of every chunk, see `MerkleDB::prove_range`
+    pub fn with_proofs(mut self) -> Self {
+        self.proofs = true;
+        self
+    }
+}
+
+impl<D: MerkleDB> Iterator for SnapshotChunks<'_, D> {
+    type Item = Result<SnapshotChunk>;
+
+    fn next(&mut self) -> Option<Result<SnapshotChunk>> {
+        if self.done {
+            return None;
+        }
+        let mut entries = vec![];
+        let mut size = 0usize;
+        // a chunk holds at least one entry, an empty state is a single empty chunk
+        while size < self.chunk_size {
+            let (k, v) = match self.iter.next() {
+                Some(kv_pair) => self.db.decode_kv(kv_pair),
+                None => break,
+            };
+            size = size.saturating_add(k.len() + v.len());
+            entries.push((k, v));
+        }
+        let end = match self.iter.peek() {
+            Some(kv_pair) => self.db.decode_kv(kv_pair.clone()).0,
+            None => KEYS_UPPER.to_vec(),
+        };
+        self.done = self.iter.peek().is_none();
+        let proof = if self.proofs {
+            match self.db.prove_range(&self.start, &end).c(d!()) {
+                Ok(proof) => proof,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        } else {
+            vec![]
+        };
+
+        let mut chunk = SnapshotChunk {
+            height: self.height,
+            root_hash: self.root_hash.clone(),
+            index: self.index,
+            last: self.done,
+            entries,
+            hash: vec![],
+            end,
+            proof,
+        };
+        chunk.hash = chunk.compute_hash(&self.prev_hash);
+        self.prev_hash = chunk.hash.clone();
+        self.start = chunk.end.clone();
+        self.index = self.index.saturating_add(1);
+        Some(Ok(chunk))
+    }
+}
+
+/// Writes the chunks of a snapshot into a db, see `ChainState::restore_from_chunks`
+///
+/// Every chunk is committed together with the progress of the restore, an interrupted restore
+/// is resumed from `next_index` by a restorer of the same snapshot.
+pub struct ChunkRestorer<D: MerkleDB> {
+    db: D,
+    height: u64,
+    root_hash: Vec<u8>,
+    next: u64,
+    prev_hash: Vec<u8>,
+    last_key: Option<Vec<u8>>,
+    // start of the key range of the next chunk
+    start: Vec<u8>,
+    verifier: Option<Box<dyn ProofVerifier>>,
+    done: bool,
+}
+
+impl<D: MerkleDB> ChunkRestorer<D> {
+    pub(crate) fn new(db: D, height: u64, root_hash: Vec<u8>) -> Result<Self> {
+        let mut restorer = ChunkRestorer {
+            prev_hash: SnapshotChunk::first_prev_hash(height, &root_hash),
+            db,
+            height,
+            root_hash,
+            next: 0,
+            last_key: None,
+            start: vec![],
+            verifier: None,
+            done: false,
+        };
+        if let Some(progress) = restorer.db.get_aux(RESTORE_PROGRESS_KEY).c(d!())? {
+            restorer.resume(&progress).c(d!())?;
+        }
+        Ok(restorer)
+    }
+
+    fn resume(&mut self, progress: &[u8]) -> Result<()> {
+        let value: Value = serde_json::from_slice(progress).c(d!("invalid restore progress"))?;
+        let root_hash = value["root_hash"].as_str().map(hex::decode);
+        if value["height"].as_u64() != Some(self.height)
+            || !matches!(root_hash, Some(Ok(ref root)) if *root == self.root_hash)
+        {
+            return Err(eg!(
+                "the db holds an unfinished restore of another snapshot"
+            ));
+        }
+        self.next = value["next"].as_u64().c(d!("invalid restore progress"))?;
+        self.prev_hash = hex::decode(value["prev_hash"].as_str().c(d!())?).c(d!())?;
+        self.last_key = match value["last_key"].as_str() {
+            Some(key) => Some(hex::decode(key).c(d!())?),
+            None => None,
+        };
+        self.start = match value["start"].as_str() {
+            Some(key) => hex::decode(key).c(d!())?,
+            None => vec![],
+        };
+        self.done = value["done"].as_bool().unwrap_or(false);
+        Ok(())
+    }
+
+    /// Checks the range proof of every chunk against the trusted root hash when it's applied
+    ///
+    /// Chunks without proof are rejected then, see `SnapshotChunks::with_proofs`.
+    pub fn with_verifier<V: ProofVerifier + 'static>(mut self, verifier: V) -> Self {
+        self.verifier = Some(Box::new(verifier));
+        self
+    }
+
+    /// Index of the chunk expected next
+    pub fn next_index(&self) -> u64 {
+        self.next
+    }
+
+    /// Whether the last chunk has been applied
+    pub fn is_complete(&self) -> bool {
+        self.done
+    }
+
+    /// Verifies `chunk` and writes its entries
+    ///
+    /// Chunks are applied in order, a chunk failing verification is dropped and can be
+    /// requested again, e.g. from another peer.
+    pub fn apply(&mut self, mut chunk: SnapshotChunk) -> Result<()> {
+        if chunk.height != self.height || chunk.root_hash != self.root_hash {
+            return Err(eg!("snapshot chunk of another snapshot"));
+        }
+        if self.done || chunk.index != self.next {
+            return Err(eg!(format!(
+                "snapshot chunk {} out of order, expecting chunk {}",
+                chunk.index, self.next
+            )));
+        }
+        chunk.verify(&self.prev_hash).c(d!())?;
+        let mut last_key = self.last_key.as_deref();
+        for (k, _) in chunk.entries.iter() {
+            if last_key.is_some_and(|last| k.as_slice() <= last) {
+                return Err(eg!("keys of snapshot chunks are not in ascending order"));
+            }
+            last_key = Some(k);
+        }
+        if chunk.end.as_slice() <= self.start.as_slice()
+            || (chunk.last && chunk.end.as_slice() != KEYS_UPPER)
+            || last_key.is_some_and(|last| last >= chunk.end.as_slice())
+        {
+            return Err(eg!(format!(
+                "invalid key range of snapshot chunk {}",
+                chunk.index
+            )));
+        }
+        if let Some(verifier) = self.verifier.as_ref() {
+            let proven = verifier
+                .verify_range(&self.root_hash, &self.start, &chunk.end, &chunk.proof)
+                .c(d!(format!(
+                    "invalid proof of snapshot chunk {}",
+                    chunk.index
+                )))?;
+            if proven != chunk.entries {
+                return Err(eg!(format!(
+                    "entries of snapshot chunk {} don't match its proof",
+                    chunk.index
+                )));
+            }
+        }
+
+        self.last_key = last_key.map(<[u8]>::to_vec);
+        self.start = std::mem::take(&mut chunk.end);
+        self.prev_hash = chunk.hash;
+        self.next = chunk.index.saturating_add(1);
+        self.done = chunk.last;
+        let batch = chunk
+            .entries
+            .into_iter()
+            .map(|(k, v)| (k, Some(v)))
+            .collect::<KVBatch>();
+        if !batch.is_empty() {
+            self.db.put_batch(batch).c(d!())?;
+        }
+        let progress = json!({
+            "height": self.height,
+            "root_hash": hex::encode(&self.root_hash),
+            "next": self.next,
+            "prev_hash": hex::encode(&self.prev_hash),
+            "last_key": self.last_key.as_deref().map(hex::encode),
+            "start": hex::encode(&self.start),
+            "done": self.done,
+        });
+        let aux = vec![(
+            RESTORE_PROGRESS_KEY.to_vec(),
+            Some(serde_json::to_vec(&progress).c(d!())?),
+        )];
+        self.db.commit(aux, false).c(d!())
+    }
+
+    /// Checks the restored tree against the root hash of the snapshot and opens a chain state
+    /// at the height of the snapshot
+    ///
+    /// Versions before the height aren't restored, the restored keys are the base of a
+    /// versioned chain state. A restored tree of another root is removed together with the
+    /// progress, the db is empty again for a new restore.
+    pub fn finish(mut self, opts: ChainStateOpts) -> Result<ChainState<D>> {
+        if !self.done {
+            return Err(eg!(format!(
+                "snapshot restore incomplete, expecting chunk {}",
+                self.next
+            )));
+        }
+        if let Err(e) = ChainState::check_restored_root(&self.db, &self.root_hash) {
+            self.reset().c(d!())?;
+            return Err(e);
+        }
+        ChainState::open_restored(self.db, self.height, &self.root_hash, opts).c(d!())
+    }
+
+    // removes the restored entries and the progress
+    fn reset(&mut self) -> Result<()> {
+        let batch = self
+            .db
+            .db_all_iterator(IterOrder::Asc)
+            .map(|kv_pair| (self.db.decode_kv(kv_pair).0, None))
+            .collect::<KVBatch>();
+        if !batch.is_empty() {
+            self.db.put_batch(batch).c(d!())?;
+        }
+        self.db
+            .commit(vec![(RESTORE_PROGRESS_KEY.to_vec(), None)], true)
+            .c(d!())
+    }
+}
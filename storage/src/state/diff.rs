@@ -0,0 +1,137 @@
+/// Differential snapshots
+///
+/// `ChainState::snapshot_diff` writes the batches committed after a height of the version
+/// window, `ChainState::apply_diff` replays them on a chain state at that height, e.g. a
+/// restored full snapshot, so periodic backups only hold what changed since the last one.
+///
+/// A diff is written after its base height and root hash, then every height with its root
+/// hash, its count of entries and the entries: a key frame, then a value frame or a frame
+/// length of `u32::MAX` for a deletion. It ends with the height `u64::MAX`. Frames are prefixed with their length
+/// as a big endian u32, see `aux_dump`. The header next to a diff, see `snapshot`, holds the
+/// height and root hash it leads to.
+///
+use crate::{
+    aux_dump::{read_data, read_frame, read_len, write_frame},
+    db::{KVBatch, KVEntry},
+};
+use ruc::*;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+/// format of the diffs, also recorded in their headers
+pub const DIFF_FORMAT: &str = "diff";
+
+const DIFF_TAG: &str = "storage-diff/2";
+const DELETED: u32 = u32::MAX;
+const END_OF_HEIGHTS: u64 = u64::MAX;
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes).c(d!("truncated diff"))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Writes a diff height by height
+pub(crate) struct DiffWriter {
+    writer: BufWriter<File>,
+}
+
+impl DiffWriter {
+    pub(crate) fn create<P: AsRef<Path>>(path: P, since: u64, since_root: &[u8]) -> Result<Self> {
+        let file = File::create(path).c(d!("failed to create diff"))?;
+        let mut writer = BufWriter::new(file);
+        write_frame(&mut writer, DIFF_TAG.as_bytes()).c(d!())?;
+        writer.write_all(&since.to_be_bytes()).c(d!())?;
+        write_frame(&mut writer, since_root).c(d!())?;
+        Ok(DiffWriter { writer })
+    }
+
+    pub(crate) fn write_height(
+        &mut self,
+        height: u64,
+        root_hash: &[u8],
+        batch: &[KVEntry],
+    ) -> Result<()> {
+        self.writer.write_all(&height.to_be_bytes()).c(d!())?;
+        write_frame(&mut self.writer, root_hash).c(d!())?;
+        self.writer
+            .write_all(&(batch.len() as u64).to_be_bytes())
+            .c(d!())?;
+        for (k, v) in batch.iter() {
+            write_frame(&mut self.writer, k).c(d!())?;
+            match v {
+                Some(v) => write_frame(&mut self.writer, v).c(d!())?,
+                None => self.writer.write_all(&DELETED.to_be_bytes()).c(d!())?,
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<()> {
+        self.writer
+            .write_all(&END_OF_HEIGHTS.to_be_bytes())
+            .c(d!())?;
+        self.writer.flush().c(d!())?;
+        self.writer.get_ref().sync_all().c(d!())
+    }
+}
+
+/// A height of a diff
+pub(crate) struct DiffHeight {
+    pub(crate) height: u64,
+    /// root hash of the source chain state after the height was committed
+    pub(crate) root_hash: Vec<u8>,
+    pub(crate) batch: KVBatch,
+}
+
+/// Reads a diff height by height
+pub(crate) struct DiffReader {
+    reader: BufReader<File>,
+    /// height and root hash the diff applies to
+    pub(crate) since: u64,
+    pub(crate) since_root: Vec<u8>,
+}
+
+impl DiffReader {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path).c(d!("failed to open diff"))?;
+        let mut reader = BufReader::new(file);
+        if read_frame(&mut reader).c(d!())? != DIFF_TAG.as_bytes() {
+            return Err(eg!("not a diff"));
+        }
+        let since = read_u64(&mut reader).c(d!())?;
+        let since_root = read_frame(&mut reader).c(d!())?;
+        Ok(DiffReader {
+            reader,
+            since,
+            since_root,
+        })
+    }
+
+    /// Next height, its root hash and its batch, `None` at the end of the diff
+    pub(crate) fn next_height(&mut self) -> Result<Option<DiffHeight>> {
+        let height = read_u64(&mut self.reader).c(d!())?;
+        if height == END_OF_HEIGHTS {
+            return Ok(None);
+        }
+        let root_hash = read_frame(&mut self.reader).c(d!())?;
+        let count = read_u64(&mut self.reader).c(d!())?;
+        let mut batch = KVBatch::new();
+        for _ in 0..count {
+            let key = read_frame(&mut self.reader).c(d!())?;
+            let value = match read_len(&mut self.reader).c(d!())? {
+                DELETED => None,
+                len => Some(read_data(&mut self.reader, len).c(d!())?),
+            };
+            batch.push((key, value));
+        }
+        Ok(Some(DiffHeight {
+            height,
+            root_hash,
+            batch,
+        }))
+    }
+}
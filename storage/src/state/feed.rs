@@ -0,0 +1,74 @@
+/// Proof feed for light clients
+///
+/// A subscriber registers a watch-list of keys and receives, on every commit, the committed
+/// height, the new root hash, the current values of the watched keys and a proof of them.
+///
+use crate::db::MerkleDB;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+/// default number of updates buffered per subscriber
+pub const DEFAULT_FEED_CAPACITY: usize = 64;
+
+/// Update pushed to subscribers after each commit
+#[derive(Clone, Debug)]
+pub struct ProofUpdate {
+    pub height: u64,
+    pub root_hash: Vec<u8>,
+    /// current values of the watched keys, `None` if a key doesn't exist
+    pub values: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    /// proof of the watched keys against `root_hash`, `None` if the backend can't prove
+    pub proof: Option<Vec<u8>>,
+}
+
+/// A single subscription to the proof feed
+pub(crate) struct ProofSubscriber {
+    keys: Vec<Vec<u8>>,
+    sender: SyncSender<ProofUpdate>,
+    dropped: u64,
+}
+
+impl ProofSubscriber {
+    /// Creates a subscriber watching `keys` and the receiving end of its channel
+    pub(crate) fn new(keys: Vec<Vec<u8>>, capacity: usize) -> (Self, Receiver<ProofUpdate>) {
+        let (sender, receiver) = sync_channel(capacity);
+        let sub = ProofSubscriber {
+            keys,
+            sender,
+            dropped: 0,
+        };
+        (sub, receiver)
+    }
+
+    /// Builds the update of this subscriber and pushes it without blocking
+    ///
+    /// A lagging subscriber misses updates instead of stalling the commit.
+    ///
+    /// Returns false if the receiver has gone away.
+    pub(crate) fn publish<D: MerkleDB>(&mut self, db: &D, height: u64, root_hash: &[u8]) -> bool {
+        let values = self
+            .keys
+            .iter()
+            .map(|k| (k.clone(), db.get(k).unwrap_or_default()))
+            .collect();
+        let update = ProofUpdate {
+            height,
+            root_hash: root_hash.to_vec(),
+            values,
+            proof: db.prove_keys(&self.keys).ok(),
+        };
+
+        match self.sender.try_send(update) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped = self.dropped.saturating_add(1);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Number of updates missed because the channel was full
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
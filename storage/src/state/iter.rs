@@ -0,0 +1,126 @@
+/// Snapshot iteration of a state
+///
+/// `State::snapshot_iter` walks the live pairs of a range, the writes of the session merged
+/// over the chain state. The writes are copied when the iterator is created. The chain state is
+/// read a step at a time, every step checks that it wasn't committed to since the iterator was
+/// created, by the session or anyone else, and fails with an `IteratorInvalidated` error
+/// otherwise. An iterator yields the view of the state it was created on or an error, never a
+/// mix of two versions, whatever the backend.
+///
+use crate::{
+    db::{IterOrder, KVEntry, KValue, MerkleDB},
+    state::{ChainState, State},
+};
+use parking_lot::RwLock;
+use ruc::*;
+use std::{cmp::Ordering, collections::VecDeque, iter::Peekable, sync::Arc, vec};
+
+/// marker carried by the errors of iterators whose chain state was committed to
+pub const ITERATOR_INVALIDATED: &str = "IteratorInvalidated";
+
+/// number of pairs read from the chain state in a step
+pub const SNAPSHOT_ITER_STEP: usize = 0x400;
+
+/// Returns true if `err` is the error of an iterator invalidated by a commit
+pub fn is_iterator_invalidated(err: &dyn RucError) -> bool {
+    err.to_string().contains(ITERATOR_INVALIDATED)
+}
+
+/// Live pairs of a state in a range in ascending key order, see `State::snapshot_iter`
+pub struct StateSnapshotIter<D: MerkleDB> {
+    chain_state: Arc<RwLock<ChainState<D>>>,
+    // root hash or height of the chain state when the iterator was created
+    version: Vec<u8>,
+    // writes of the session in the range, `None` deletes a key
+    writes: Peekable<vec::IntoIter<KVEntry>>,
+    // pairs of the chain state read ahead
+    step: VecDeque<KValue>,
+    // first key of the next step, `None` once the range is read
+    from: Option<Vec<u8>>,
+    upper: Vec<u8>,
+    failed: bool,
+}
+
+impl<D: MerkleDB> StateSnapshotIter<D> {
+    pub(crate) fn new(state: &State<D>, lower: &[u8], upper: &[u8]) -> Result<Self> {
+        let version = State::prefetch_version(&state.chain_state.read()).c(d!())?;
+        let writes = state
+            .cache
+            .snapshot_iter()
+            .filter(|(k, _)| lower <= k.as_slice() && k.as_slice() < upper)
+            .collect::<Vec<_>>();
+        Ok(StateSnapshotIter {
+            chain_state: state.chain_state.clone(),
+            version,
+            writes: writes.into_iter().peekable(),
+            step: VecDeque::new(),
+            from: (lower < upper).then(|| lower.to_vec()),
+            upper: upper.to_vec(),
+            failed: false,
+        })
+    }
+
+    // reads the next step of the chain state if the previous one is used up
+    fn read_step(&mut self) -> Result<()> {
+        let from = match self.from.take() {
+            Some(from) if self.step.is_empty() => from,
+            from => {
+                self.from = from;
+                return Ok(());
+            }
+        };
+        let cs = self.chain_state.read();
+        if State::prefetch_version(&cs).c(d!())? != self.version {
+            return Err(eg!(format!(
+                "{}: the chain state was committed to",
+                ITERATOR_INVALIDATED
+            )));
+        }
+        let step = &mut self.step;
+        cs.iterate(&from, &self.upper, IterOrder::Asc, &mut |kv| {
+            step.push_back(kv);
+            step.len() >= SNAPSHOT_ITER_STEP
+        });
+        if self.step.len() >= SNAPSHOT_ITER_STEP {
+            self.from = self.step.back().map(|(k, _)| {
+                let mut next = k.clone();
+                next.push(0);
+                next
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<D: MerkleDB> Iterator for StateSnapshotIter<D> {
+    type Item = Result<KValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            if let Err(e) = self.read_step() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+            let order = match (self.step.front(), self.writes.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((key, _)), Some((written, _))) => key.cmp(written),
+            };
+            match order {
+                Ordering::Less => return self.step.pop_front().map(Ok),
+                // overwritten or deleted by the session
+                Ordering::Equal => {
+                    self.step.pop_front();
+                }
+                Ordering::Greater => {}
+            }
+            if let Some((key, Some(value))) = self.writes.next() {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
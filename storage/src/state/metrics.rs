@@ -0,0 +1,87 @@
+/// Per prefix load metrics
+///
+/// A chain state counts the reads and writes of the keys under registered prefixes, e.g. one
+/// per application module, block by block. `ChainState::prefix_metrics` returns the counts of
+/// the last committed block, so slow commits can be attributed to the modules loading the
+/// store. Reads are the ones reaching the chain state, i.e. missing the caches of `State`.
+///
+use crate::store::Prefix;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+};
+
+/// Load of the keys under a prefix during a block
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefixMetrics {
+    pub reads: u64,
+    /// bytes of the keys and values read
+    pub read_bytes: u64,
+    /// puts and deletes committed
+    pub writes: u64,
+    /// bytes of the keys and values committed
+    pub write_bytes: u64,
+}
+
+/// Metrics of every registered prefix during a block
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BlockMetrics {
+    pub height: u64,
+    pub prefixes: BTreeMap<Vec<u8>, PrefixMetrics>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct MetricsRecorder {
+    prefixes: BTreeSet<Vec<u8>>,
+    current: BTreeMap<Vec<u8>, PrefixMetrics>,
+    last: BlockMetrics,
+}
+
+impl MetricsRecorder {
+    pub(crate) fn register(&mut self, prefix: &Prefix) {
+        self.prefixes.insert(prefix.begin());
+    }
+
+    // a key under nested prefixes counts for each of them
+    fn update<F: Fn(&mut PrefixMetrics)>(&mut self, key: &[u8], f: F) {
+        for prefix in self
+            .prefixes
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+        {
+            if key.starts_with(prefix) {
+                f(self.current.entry(prefix.clone()).or_default());
+            }
+        }
+    }
+
+    pub(crate) fn record_read(&mut self, key: &[u8], value: Option<&[u8]>) {
+        let bytes = (key.len() + value.map_or(0, <[u8]>::len)) as u64;
+        self.update(key, |m| {
+            m.reads = m.reads.saturating_add(1);
+            m.read_bytes = m.read_bytes.saturating_add(bytes);
+        });
+    }
+
+    pub(crate) fn record_write(&mut self, key: &[u8], value: Option<&[u8]>) {
+        let bytes = (key.len() + value.map_or(0, <[u8]>::len)) as u64;
+        self.update(key, |m| {
+            m.writes = m.writes.saturating_add(1);
+            m.write_bytes = m.write_bytes.saturating_add(bytes);
+        });
+    }
+
+    /// Closes the block committed at `height`, prefixes without load report zeros
+    pub(crate) fn end_block(&mut self, height: u64) {
+        let mut current = std::mem::take(&mut self.current);
+        let prefixes = self
+            .prefixes
+            .iter()
+            .map(|p| (p.clone(), current.remove(p).unwrap_or_default()))
+            .collect();
+        self.last = BlockMetrics { height, prefixes };
+    }
+
+    pub(crate) fn last(&self) -> BlockMetrics {
+        self.last.clone()
+    }
+}
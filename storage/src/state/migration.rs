@@ -0,0 +1,150 @@
+/// Online re-prefixing
+///
+/// A `PrefixMigration` moves the keys under an old prefix to the same keys under a new prefix
+/// while the chain keeps serving them, e.g. to re-layout a hot prefix without downtime. During
+/// the migration window reads look up the new key first and fall back to the old one, writes
+/// go to the new key and drop the old one. `State::migrate_step` moves a bounded number of keys
+/// per block, one sub-range after the other.
+///
+/// The progress is kept in the state under the reserved `MIG` prefix and committed along with
+/// the keys it moved, so the root hash covers it and a node synced from a snapshot resumes the
+/// migration where the chain is. Keys under `MIG` can't be written or deleted through the state.
+///
+use crate::store::Prefix;
+use ruc::*;
+use serde::{Deserialize, Serialize};
+
+const MIGRATION_PREFIX: &[u8; 3] = b"MIG";
+// begin of `Prefix::new(MIGRATION_PREFIX)`
+const MIGRATION_BEGIN: &[u8; 4] = b"MIG_";
+
+/// Progress of moving the keys under one prefix to another
+///
+/// Keys are given relative to the prefixes, i.e. without the prefix and its separator.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixMigration {
+    old_begin: Vec<u8>,
+    old_end: Vec<u8>,
+    new_begin: Vec<u8>,
+    // first keys of the sub-ranges following the first one
+    splits: Vec<Vec<u8>>,
+    done: Vec<bool>,
+    moved: u64,
+}
+
+impl PrefixMigration {
+    /// Migration of the keys under `old` to `new` as a single sub-range
+    pub fn new(old: &Prefix, new: &Prefix) -> Result<Self> {
+        let (old_begin, new_begin) = (old.begin(), new.begin());
+        if old_begin.starts_with(&new_begin) || new_begin.starts_with(&old_begin) {
+            return Err(eg!("migration prefixes must not overlap"));
+        }
+        Ok(PrefixMigration {
+            old_begin,
+            old_end: old.end(),
+            new_begin,
+            splits: vec![],
+            done: vec![false],
+            moved: 0,
+        })
+    }
+
+    /// Splits the keys into sub-ranges starting at `splits`, before the migration starts
+    pub fn with_splits(mut self, mut splits: Vec<Vec<u8>>) -> Result<Self> {
+        if self.moved != 0 || self.completed() != 0 {
+            return Err(eg!("migration already started"));
+        }
+        splits.retain(|s| !s.is_empty());
+        splits.sort();
+        splits.dedup();
+        self.done = vec![false; splits.len() + 1];
+        self.splits = splits;
+        Ok(self)
+    }
+
+    pub fn old_key(&self, key: &[u8]) -> Vec<u8> {
+        [self.old_begin.as_slice(), key].concat()
+    }
+
+    pub fn new_key(&self, key: &[u8]) -> Vec<u8> {
+        [self.new_begin.as_slice(), key].concat()
+    }
+
+    /// Index of the sub-range holding `key`
+    pub fn sub_range(&self, key: &[u8]) -> usize {
+        self.splits.partition_point(|s| s.as_slice() <= key)
+    }
+
+    /// Whether no key of the sub-range of `key` is left under the old prefix
+    pub fn is_migrated(&self, key: &[u8]) -> bool {
+        self.done
+            .get(self.sub_range(key))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn sub_ranges(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Number of completed sub-ranges
+    pub fn completed(&self) -> usize {
+        self.done.iter().filter(|d| **d).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done.iter().all(|d| *d)
+    }
+
+    /// Number of keys moved so far
+    pub fn moved(&self) -> u64 {
+        self.moved
+    }
+
+    // first pending sub-range with its bounds under the old prefix
+    pub(crate) fn pending(&self) -> Option<(usize, Vec<u8>, Vec<u8>)> {
+        let index = self.done.iter().position(|d| !*d)?;
+        let lower = match index.checked_sub(1).and_then(|i| self.splits.get(i)) {
+            Some(split) => self.old_key(split),
+            None => self.old_begin.clone(),
+        };
+        let upper = match self.splits.get(index) {
+            Some(split) => self.old_key(split),
+            None => self.old_end.clone(),
+        };
+        Some((index, lower, upper))
+    }
+
+    // begins of the old and the new prefix
+    pub(crate) fn begins(&self) -> (Vec<u8>, Vec<u8>) {
+        (self.old_begin.clone(), self.new_begin.clone())
+    }
+
+    pub(crate) fn record(&mut self, moved: u64, completed: Option<usize>) {
+        self.moved = self.moved.saturating_add(moved);
+        if let Some(done) = completed.and_then(|i| self.done.get_mut(i)) {
+            *done = true;
+        }
+    }
+}
+
+/// Whether `key` is under the reserved prefix of the migration progress
+pub(crate) fn is_reserved(key: &[u8]) -> bool {
+    key.starts_with(MIGRATION_BEGIN)
+}
+
+/// State key of the progress of the named migration
+pub(crate) fn progress_key(name: &str) -> Vec<u8> {
+    Prefix::new(MIGRATION_PREFIX)
+        .push(name.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+pub(crate) fn encode_progress(migration: &PrefixMigration) -> Result<Vec<u8>> {
+    serde_json::to_vec(migration).c(d!())
+}
+
+pub(crate) fn decode_progress(value: &[u8]) -> Result<PrefixMigration> {
+    serde_json::from_slice(value).c(d!("invalid migration"))
+}
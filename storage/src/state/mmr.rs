@@ -0,0 +1,154 @@
+/// Merkle Mountain Range over the committed root hashes
+///
+/// Every commit appends a leaf binding its height to its root hash. The accumulator only grows,
+/// so a light client holding the latest `mmr_root` of a chain state checks the root of any
+/// earlier height with a `RootInclusionProof`, without trusting an indexer for old app hashes.
+///
+/// Nodes are addressed by their level and their index in the level, leaves are at level 0.
+/// The peaks are bagged from right to left into the root of the range.
+///
+use ruc::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// root of an empty range
+pub const EMPTY_MMR_ROOT: [u8; 32] = [0; 32];
+
+// level and index of a node
+pub(crate) type NodePos = (u32, u64);
+type Hashes = Vec<Vec<u8>>;
+
+const LEAF_TAG: u8 = 0x00;
+const NODE_TAG: u8 = 0x01;
+
+/// Hash of the leaf of `height`
+pub fn leaf_hash(height: u64, root_hash: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_TAG]);
+    hasher.update(height.to_be_bytes());
+    hasher.update(root_hash);
+    hasher.finalize().to_vec()
+}
+
+fn node_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_TAG]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+/// Level and index of the peaks of a range of `leaves` leaves, from left to right
+pub(crate) fn peaks(leaves: u64) -> Vec<NodePos> {
+    let mut start = 0;
+    (0..u64::BITS)
+        .rev()
+        .filter(|level| leaves >> level & 1 == 1)
+        .map(|level| {
+            let peak = (level, start >> level);
+            start += 1 << level;
+            peak
+        })
+        .collect()
+}
+
+/// Root of a range from its peaks
+pub(crate) fn bag_peaks(peaks: &[Vec<u8>]) -> Vec<u8> {
+    let mut peaks = peaks.iter().rev();
+    match peaks.next() {
+        Some(last) => peaks.fold(last.clone(), |acc, peak| node_hash(peak, &acc)),
+        None => EMPTY_MMR_ROOT.to_vec(),
+    }
+}
+
+/// Nodes added by appending `leaf` to a range of `leaves` leaves, `get` reads the existing ones
+pub(crate) fn append(
+    leaves: u64,
+    leaf: Vec<u8>,
+    get: &dyn Fn(u32, u64) -> Result<Vec<u8>>,
+) -> Result<Vec<(NodePos, Vec<u8>)>> {
+    let (mut level, mut index) = (0, leaves);
+    let mut hash = leaf.clone();
+    let mut nodes = vec![((level, index), leaf)];
+    // a right child completes its parent
+    while index & 1 == 1 {
+        hash = node_hash(&get(level, index - 1).c(d!())?, &hash);
+        level += 1;
+        index >>= 1;
+        nodes.push(((level, index), hash.clone()));
+    }
+    Ok(nodes)
+}
+
+/// Siblings of leaf `index` up to its peak and the peaks of a range of `leaves` leaves
+pub(crate) fn prove(
+    leaves: u64,
+    index: u64,
+    get: &dyn Fn(u32, u64) -> Result<Vec<u8>>,
+) -> Result<(Hashes, Hashes)> {
+    let (level, _) = peak_of(leaves, index).c(d!())?;
+    let siblings = (0..level)
+        .map(|k| get(k, (index >> k) ^ 1))
+        .collect::<Result<Vec<_>>>()
+        .c(d!())?;
+    let peaks = peaks(leaves)
+        .into_iter()
+        .map(|(level, index)| get(level, index))
+        .collect::<Result<Vec<_>>>()
+        .c(d!())?;
+    Ok((siblings, peaks))
+}
+
+// level of the peak covering leaf `index` and its position in the peaks
+fn peak_of(leaves: u64, index: u64) -> Result<(u32, usize)> {
+    if index >= leaves {
+        return Err(eg!("leaf out of the range"));
+    }
+    peaks(leaves)
+        .into_iter()
+        .enumerate()
+        .find(|(_, (level, peak))| index >> level == *peak)
+        .map(|(pos, (level, _))| (level, pos))
+        .c(d!())
+}
+
+/// Proof that the root hash of a height is a leaf of a Merkle Mountain Range
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootInclusionProof {
+    pub height: u64,
+    /// root hash after the commit of `height`
+    pub root_hash: Vec<u8>,
+    /// position of the leaf of `height`
+    pub leaf_index: u64,
+    /// number of leaves of the range the proof is against
+    pub leaves: u64,
+    /// siblings from the leaf up to its peak
+    pub siblings: Vec<Vec<u8>>,
+    /// every peak of the range, from left to right
+    pub peaks: Vec<Vec<u8>>,
+}
+
+impl RootInclusionProof {
+    /// Checks the proof against `mmr_root`, a trusted root of the range
+    pub fn verify(&self, mmr_root: &[u8]) -> Result<()> {
+        let (level, pos) = peak_of(self.leaves, self.leaf_index).c(d!())?;
+        if self.siblings.len() != level as usize || self.peaks.len() != peaks(self.leaves).len() {
+            return Err(eg!("malformed root inclusion proof"));
+        }
+        let mut hash = leaf_hash(self.height, &self.root_hash);
+        for (k, sibling) in self.siblings.iter().enumerate() {
+            hash = if self.leaf_index >> k & 1 == 0 {
+                node_hash(&hash, sibling)
+            } else {
+                node_hash(sibling, &hash)
+            };
+        }
+        if self.peaks[pos] != hash {
+            return Err(eg!("root hash is not in the range"));
+        }
+        if bag_peaks(&self.peaks) != mmr_root {
+            return Err(eg!("proof is for another range"));
+        }
+        Ok(())
+    }
+}
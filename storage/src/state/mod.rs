@@ -1,15 +1,65 @@
 /// Definition of State structure containing the data defining the current state of the
 /// blockchain. The struct wraps an interface to the persistence layer as well as a cache.
 ///
+pub mod access;
+pub mod analytics;
 pub mod cache;
 pub mod chain_state;
+pub mod chunks;
+pub mod diff;
+pub mod feed;
+pub mod iter;
+pub mod metrics;
+pub mod migration;
+pub mod mmr;
+pub mod scrub;
+pub mod sketch;
+pub mod sync;
+pub mod token;
+pub mod ttl;
 
-use crate::db::{IterOrder, KValue, MerkleDB};
-pub use cache::{KVMap, KVecMap, SessionedCache};
-pub use chain_state::{ChainState, ChainStateOpts};
-use parking_lot::RwLock;
+use crate::{
+    config::{OpenWithConfig, StorageConfig},
+    db::{IterOpts, IterOrder, KVBatch, KValue, MerkleDB, ScanControl},
+    height::Height,
+    merge::MergeOp,
+    schema::strict_keys,
+    snapshot::PrefixFilter,
+    store::Prefix,
+};
+pub use access::{detect_conflicts, AccessList, Conflict, ConflictKind, ConflictReport};
+pub use analytics::{AnalyticsHandle, AnalyticsOpts, AnalyticsProgress, AnalyticsSession};
+pub use cache::{CacheSnapshotIter, KVMap, KVecMap, SessionedCache};
+pub use chain_state::{
+    ChainState, ChainStateOpts, HeightWatermarks, ImportProgress, ProvenValues, PruningPolicy,
+    ReindexProgress, WatermarkReader, DEFAULT_REINDEX_BATCH,
+};
+pub use chunks::{ChunkRestorer, SnapshotChunk, SnapshotChunks};
+pub use feed::{ProofUpdate, DEFAULT_FEED_CAPACITY};
+pub use iter::{is_iterator_invalidated, StateSnapshotIter, ITERATOR_INVALIDATED};
+pub use metrics::{BlockMetrics, PrefixMetrics};
+pub use migration::PrefixMigration;
+pub use mmr::RootInclusionProof;
+use parking_lot::{Mutex, RwLock};
 use ruc::*;
-use std::sync::Arc;
+pub use scrub::{ScrubOpts, ScrubReport, Scrubber};
+pub use sketch::KeySketchStats;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{mpsc::Receiver, Arc},
+};
+pub use sync::{ChunkSet, ChunkTracker};
+pub use token::{is_not_yet_available, CommitToken, NOT_YET_AVAILABLE};
+use ttl::ExpiryNotifier;
+pub use ttl::{ExpiryEvent, ExpiryHook};
+
+// Committed values of predicted keys, see `State::prefetch`
+#[derive(Clone, Default)]
+struct Prefetched {
+    // root hash or height of the chain state the values were read at
+    version: Vec<u8>,
+    values: KVMap,
+}
 
 /// State Definition used by all stores
 ///
@@ -19,6 +69,26 @@ pub struct State<D: MerkleDB> {
     chain_state: Arc<RwLock<ChainState<D>>>,
     cache: SessionedCache,
     height_cap: Option<u64>,
+    access: Option<Mutex<AccessList>>,
+    // committed values of predicted keys, served while the chain state doesn't move
+    prefetched: Arc<Prefetched>,
+    // sequence values of committed sessions and of the current one, written to aux at commit
+    sequences: BTreeMap<String, u64>,
+    sequences_delta: BTreeMap<String, u64>,
+    // renamed keys whose version history is copied at commit, old key to new key
+    key_moves: BTreeMap<Vec<u8>, Vec<u8>>,
+    key_moves_delta: BTreeMap<Vec<u8>, Vec<u8>>,
+    // deltas above when each open nested transaction began, innermost last
+    savepoints: Vec<Savepoint>,
+    // hooks and subscribers of the keys expired by commits
+    expiry: ExpiryNotifier<D>,
+}
+
+// session deltas restored by the rollback of a nested transaction
+#[derive(Clone)]
+struct Savepoint {
+    sequences_delta: BTreeMap<String, u64>,
+    key_moves_delta: BTreeMap<Vec<u8>, Vec<u8>>,
 }
 
 impl<D: MerkleDB> Drop for State<D> {
@@ -40,6 +110,14 @@ impl<D: MerkleDB> State<D> {
             chain_state: self.chain_state.clone(),
             cache: self.cache.clone(),
             height_cap: None,
+            access: None,
+            prefetched: self.prefetched.clone(),
+            sequences: self.sequences.clone(),
+            sequences_delta: self.sequences_delta.clone(),
+            key_moves: self.key_moves.clone(),
+            key_moves_delta: self.key_moves_delta.clone(),
+            savepoints: self.savepoints.clone(),
+            expiry: self.expiry.clone(),
         }
     }
 
@@ -55,6 +133,42 @@ impl<D: MerkleDB> State<D> {
         self.cache.stack_discard();
     }
 
+    /// Begins a nested transaction on top of the current one and returns its depth
+    ///
+    /// Nested transactions stack to any depth, e.g. one per call frame. Each one ends with
+    /// `commit_nested`, which keeps its writes in the enclosing transaction, or with
+    /// `rollback_nested`. Like `stack_push` it overlays the cache, and it also covers the
+    /// sequences and key moves of the session.
+    pub fn begin(&mut self) -> usize {
+        self.cache.stack_push();
+        self.savepoints.push(Savepoint {
+            sequences_delta: self.sequences_delta.clone(),
+            key_moves_delta: self.key_moves_delta.clone(),
+        });
+        self.savepoints.len()
+    }
+
+    /// Ends the innermost nested transaction, keeping its writes
+    pub fn commit_nested(&mut self) -> Result<()> {
+        self.savepoints.pop().c(d!("no nested transaction"))?;
+        self.cache.stack_commit();
+        Ok(())
+    }
+
+    /// Ends the innermost nested transaction, dropping its writes
+    pub fn rollback_nested(&mut self) -> Result<()> {
+        let savepoint = self.savepoints.pop().c(d!("no nested transaction"))?;
+        self.cache.stack_discard();
+        self.sequences_delta = savepoint.sequences_delta;
+        self.key_moves_delta = savepoint.key_moves_delta;
+        Ok(())
+    }
+
+    /// Number of open nested transactions
+    pub fn nested_depth(&self) -> usize {
+        self.savepoints.len()
+    }
+
     /// Creates a State with a new cache and shared ChainState
     pub fn new(cs: Arc<RwLock<ChainState<D>>>, is_merkle: bool) -> Self {
         State {
@@ -62,25 +176,73 @@ impl<D: MerkleDB> State<D> {
             chain_state: cs,
             cache: SessionedCache::new(is_merkle),
             height_cap: None,
+            access: None,
+            prefetched: Arc::default(),
+            sequences: BTreeMap::new(),
+            sequences_delta: BTreeMap::new(),
+            key_moves: BTreeMap::new(),
+            key_moves_delta: BTreeMap::new(),
+            savepoints: vec![],
+            expiry: ExpiryNotifier::default(),
         }
     }
 
+    /// Opens the backend and the ChainState described by `cfg` and creates a State on them
+    ///
+    /// Scrubbing, iterator tracking and state sync are started by the caller with the options
+    /// derived from the same config.
+    pub fn open_with_config(cfg: &StorageConfig) -> Result<Self>
+    where
+        D: OpenWithConfig,
+    {
+        cfg.validate().c(d!())?;
+        let db = D::open_with_config(cfg).c(d!())?;
+        let mut cs = match cfg.pruning.policy {
+            Some(pruning) => {
+                ChainState::try_create_with_pruning(db, cfg.chain_state_opts(), pruning).c(d!())?
+            }
+            None => ChainState::try_create_with_opts(db, cfg.chain_state_opts()).c(d!())?,
+        };
+        cs.set_compaction_threshold(cfg.pruning.compaction_tombstones);
+        Ok(Self::new(
+            Arc::new(RwLock::new(cs)),
+            cfg.backend.is_merkle(),
+        ))
+    }
+
     /// Creates a State with a same cache and shared ChainState
     pub fn copy(&self) -> Self {
         State {
             chain_state: self.chain_state.clone(),
             cache: self.cache.clone(),
             height_cap: None,
+            access: None,
+            prefetched: self.prefetched.clone(),
+            sequences: self.sequences.clone(),
+            sequences_delta: self.sequences_delta.clone(),
+            key_moves: self.key_moves.clone(),
+            key_moves_delta: self.key_moves_delta.clone(),
+            savepoints: self.savepoints.clone(),
+            expiry: self.expiry.clone(),
         }
     }
 
     /// Creates a State at specific height
-    pub fn state_at(&self, height: u64) -> Result<Self> {
+    pub fn state_at(&self, height: impl Into<Height>) -> Result<Self> {
+        let height = height.into().get();
         self.chain_state.write().pin_at(height)?;
         Ok(State {
             chain_state: self.chain_state.clone(),
             cache: SessionedCache::new(self.cache.is_merkle()),
             height_cap: Some(height),
+            access: None,
+            prefetched: Arc::default(),
+            sequences: BTreeMap::new(),
+            sequences_delta: BTreeMap::new(),
+            key_moves: BTreeMap::new(),
+            key_moves_delta: BTreeMap::new(),
+            savepoints: vec![],
+            expiry: ExpiryNotifier::default(),
         })
     }
 
@@ -96,6 +258,8 @@ impl<D: MerkleDB> State<D> {
     ///
     /// Can either return None or a Vec<u8> as the value.
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.record_read(key);
+
         //Check if value was deleted
         if self.cache.deleted(key) {
             return Ok(None);
@@ -107,13 +271,66 @@ impl<D: MerkleDB> State<D> {
 
         //If the key isn't found in the cache then query the chain state directly
         let cs = self.chain_state.read();
+        if let Some(value) = self.prefetched(&cs, key).c(d!())? {
+            return Ok(value.clone());
+        }
         match self.height_cap {
             Some(height) => cs.get_ver(key, height),
             None => cs.get(key),
         }
     }
 
-    pub fn get_ver(&self, key: &[u8], height: u64) -> Result<Option<Vec<u8>>> {
+    /// Reads the value of the given key into `buf` like `get`, reusing the allocation of `buf`
+    ///
+    /// Returns the length of the value, `None` if the key doesn't exist.
+    pub fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        self.record_read(key);
+
+        if self.cache.deleted(key) {
+            return Ok(chain_state::copy_into(None, buf));
+        }
+        if self.cache.hasv(key) {
+            return Ok(chain_state::copy_into(self.cache.getv_ref(key), buf));
+        }
+
+        let cs = self.chain_state.read();
+        if let Some(value) = self.prefetched(&cs, key).c(d!())? {
+            return Ok(chain_state::copy_into(value.as_deref(), buf));
+        }
+        match self.height_cap {
+            Some(height) => Ok(chain_state::copy_into(
+                cs.get_ver(key, height).c(d!())?.as_deref(),
+                buf,
+            )),
+            None => cs.get_into(key, buf),
+        }
+    }
+
+    /// Reads the values of `keys` into `bufs` with `get_into`, one buffer per key
+    ///
+    /// `bufs` is resized to the number of keys, the buffers of a previous call are reused.
+    pub fn get_many_into(
+        &self,
+        keys: &[&[u8]],
+        bufs: &mut Vec<Vec<u8>>,
+    ) -> Result<Vec<Option<usize>>> {
+        bufs.resize_with(keys.len(), Vec::new);
+        keys.iter()
+            .zip(bufs.iter_mut())
+            .map(|(key, buf)| self.get_into(key, buf))
+            .collect()
+    }
+
+    /// Gets a value for the given key if the state is at least as fresh as `min_token`.
+    ///
+    /// Fails with a retryable `NotYetAvailable` error while the replica is lagging behind.
+    pub fn get_with_token(&self, key: &[u8], min_token: CommitToken) -> Result<Option<Vec<u8>>> {
+        min_token.check(self.commit_token().c(d!())?)?;
+        self.get(key)
+    }
+
+    pub fn get_ver(&self, key: &[u8], height: impl Into<Height>) -> Result<Option<Vec<u8>>> {
+        let height = height.into().get();
         let query_at = match self.height_cap {
             Some(cap) if cap < height => cap,
             _ => height,
@@ -125,12 +342,17 @@ impl<D: MerkleDB> State<D> {
     ///
     /// First Checks the cache, returns true if found otherwise queries the chainState.
     pub fn exists(&self, key: &[u8]) -> Result<bool> {
+        self.record_read(key);
+
         //Check if the key exists in the cache otherwise check the chain state
         let val = self.cache.getv(key);
         if val.is_some() {
             return Ok(true);
         }
         let cs = self.chain_state.read();
+        if let Some(value) = self.prefetched(&cs, key).c(d!())? {
+            return Ok(value.is_some());
+        }
         match self.height_cap {
             Some(height) => cs.get_ver(key, height).map(|v| v.is_some()),
             None => cs.exists(key),
@@ -138,7 +360,19 @@ impl<D: MerkleDB> State<D> {
     }
 
     /// Sets a key value pair in the cache
+    ///
+    /// In strict builds the key is checked against the key schema of the chain state.
     pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        if ttl::is_reserved(key) {
+            return Err(eg!("keys under TTL are reserved for expiries"));
+        }
+        if migration::is_reserved(key) {
+            return Err(eg!("keys under MIG are reserved for migrations"));
+        }
+        if strict_keys() {
+            self.chain_state.read().validate_key(key).c(d!())?;
+        }
+        self.record_write(key);
         if self.cache.put(key, value) {
             Ok(())
         } else {
@@ -146,14 +380,405 @@ impl<D: MerkleDB> State<D> {
         }
     }
 
+    /// Merges `patch` into the value of `key` with `op`
+    ///
+    /// The merge is done in the cache, which keeps later merges of the same block in memory,
+    /// and committed as a plain value.
+    pub fn merge(&mut self, key: &[u8], patch: &[u8], op: MergeOp) -> Result<()> {
+        let current = self.get(key).c(d!())?;
+        let value = op.apply(current.as_deref(), patch).c(d!())?;
+        self.set(key, value).c(d!())
+    }
+
     /// Deletes a key from the State.
     pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        if ttl::is_reserved(key) {
+            return Err(eg!("keys under TTL are reserved for expiries"));
+        }
+        if migration::is_reserved(key) {
+            return Err(eg!("keys under MIG are reserved for migrations"));
+        }
+        self.record_write(key);
         self.cache.delete(key);
         Ok(())
     }
 
+    /// Deletes every key under `prefix` and returns the number of deleted keys
+    ///
+    /// The chain state compacts the prefix after the commit once enough keys were deleted.
+    pub fn delete_prefix(&mut self, prefix: &Prefix) -> Result<u64> {
+        let mut kv_map = KVecMap::new();
+        self.iterate(
+            &prefix.begin(),
+            &prefix.end(),
+            IterOrder::Asc,
+            &mut |(k, v)| {
+                kv_map.insert(k, v);
+                false
+            },
+        );
+        self.iterate_cache(&prefix.begin(), &mut kv_map);
+
+        // the expiry index is only changed along with the keys it holds, the migration progress
+        // by the migration
+        kv_map.retain(|k, _| !ttl::is_reserved(k) && !migration::is_reserved(k));
+        for key in kv_map.keys() {
+            self.record_write(key);
+            self.cache.delete(key);
+        }
+        Ok(kv_map.len() as u64)
+    }
+
+    /// Moves the value of `old` to `new` in the current session, returns false if `old` is absent
+    ///
+    /// The move is committed atomically with the rest of the block. With `with_history` the
+    /// version history of `old` is copied to `new` at commit, so `get_ver` of `new` at past
+    /// heights returns the values `old` had. The target key must not exist.
+    pub fn rename_key(&mut self, old: &[u8], new: &[u8], with_history: bool) -> Result<bool> {
+        if self.height_cap.is_some() {
+            return Err(eg!("Not support renames on a state with height cap"));
+        }
+        if self.exists(new).c(d!())? {
+            return Err(eg!("target key already exists"));
+        }
+        let value = match self.get(old).c(d!())? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        self.set(new, value).c(d!())?;
+        self.delete(old).c(d!())?;
+        if with_history {
+            self.key_moves_delta.insert(old.to_vec(), new.to_vec());
+        }
+        Ok(true)
+    }
+
+    /// Moves every key under `old` to the same key under `new`, returns the number of keys moved
+    ///
+    /// Like `rename_key` for a whole prefix, e.g. when a module is renamed. No key may exist
+    /// under `new` yet.
+    pub fn move_prefix(&mut self, old: &Prefix, new: &Prefix, with_history: bool) -> Result<u64> {
+        if self.height_cap.is_some() {
+            return Err(eg!("Not support renames on a state with height cap"));
+        }
+        if !self.live_under_prefix(new).is_empty() {
+            return Err(eg!("target prefix is not empty"));
+        }
+        let kv_map = self.live_under_prefix(old);
+        self.move_keys(kv_map, &old.begin(), &new.begin(), with_history)
+    }
+
+    // moves `kvs` from under `old_begin` to the same keys under `new_begin`, a key already
+    // under `new_begin` keeps its value
+    fn move_keys(
+        &mut self,
+        kvs: impl IntoIterator<Item = KValue>,
+        old_begin: &[u8],
+        new_begin: &[u8],
+        with_history: bool,
+    ) -> Result<u64> {
+        let mut moved = 0u64;
+        for (old_key, value) in kvs {
+            let mut new_key = new_begin.to_vec();
+            new_key.extend_from_slice(old_key.get(old_begin.len()..).unwrap_or_default());
+            if !self.exists(&new_key).c(d!())? {
+                self.set(&new_key, value).c(d!())?;
+            }
+            self.delete(&old_key).c(d!())?;
+            if with_history {
+                self.key_moves_delta.insert(old_key, new_key);
+            }
+            moved = moved.saturating_add(1);
+        }
+        Ok(moved)
+    }
+
+    /// Starts the named prefix migration, persisted with the next commit
+    ///
+    /// Keys are moved by `migrate_step`. In the meantime they are read and written through
+    /// `get_migrating`, `set_migrating` and `delete_migrating`.
+    pub fn start_migration(&mut self, name: &str, migration: PrefixMigration) -> Result<()> {
+        if self.height_cap.is_some() {
+            return Err(eg!("Not support migrations on a state with height cap"));
+        }
+        if self.migration(name).c(d!())?.is_some() {
+            return Err(eg!(format!("migration {} already exists", name)));
+        }
+        self.write_progress(name, &migration)
+    }
+
+    /// Returns the progress of the named migration, including the steps of this session
+    pub fn migration(&self, name: &str) -> Result<Option<PrefixMigration>> {
+        match self.get(&migration::progress_key(name)).c(d!())? {
+            Some(value) => migration::decode_progress(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn write_progress(&mut self, name: &str, migration: &PrefixMigration) -> Result<()> {
+        let value = migration::encode_progress(migration).c(d!())?;
+        self.write_reserved(&migration::progress_key(name), Some(value))
+    }
+
+    /// Gets the value of `key` under either prefix of `migration`, the new one first
+    pub fn get_migrating(
+        &self,
+        migration: &PrefixMigration,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.get(&migration.new_key(key)).c(d!())? {
+            return Ok(Some(value));
+        }
+        if migration.is_migrated(key) {
+            return Ok(None);
+        }
+        self.get(&migration.old_key(key))
+    }
+
+    /// Sets `key` under the new prefix of `migration` and drops the old copy of it
+    pub fn set_migrating(
+        &mut self,
+        migration: &PrefixMigration,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<()> {
+        self.set(&migration.new_key(key), value).c(d!())?;
+        if !migration.is_migrated(key) {
+            self.delete(&migration.old_key(key)).c(d!())?;
+        }
+        Ok(())
+    }
+
+    /// Deletes `key` under both prefixes of `migration`
+    pub fn delete_migrating(&mut self, migration: &PrefixMigration, key: &[u8]) -> Result<()> {
+        self.delete(&migration.new_key(key)).c(d!())?;
+        if !migration.is_migrated(key) {
+            self.delete(&migration.old_key(key)).c(d!())?;
+        }
+        Ok(())
+    }
+
+    /// Moves up to `max_keys` keys of the named migration to its new prefix
+    ///
+    /// Sub-ranges are moved in order, one is complete once no key is left under the old prefix
+    /// in it. A key already written under the new prefix keeps its new value. Returns the
+    /// updated progress, which is persisted with the next commit.
+    pub fn migrate_step(&mut self, name: &str, max_keys: u64) -> Result<PrefixMigration> {
+        if self.height_cap.is_some() {
+            return Err(eg!("Not support migrations on a state with height cap"));
+        }
+        let mut migration = self
+            .migration(name)
+            .c(d!())?
+            .c(d!(format!("no migration {}", name)))?;
+        let (old_begin, new_begin) = migration.begins();
+
+        let mut moved = 0;
+        while moved < max_keys {
+            let (index, lower, upper) = match migration.pending() {
+                Some(pending) => pending,
+                None => break,
+            };
+            let limit = max_keys.saturating_sub(moved);
+            let kvs = self.live_in_range(&lower, &upper, limit);
+            let n = self.move_keys(kvs, &old_begin, &new_begin, false).c(d!())?;
+            moved = moved.saturating_add(n);
+            migration.record(n, if n < limit { Some(index) } else { None });
+        }
+        self.write_progress(name, &migration).c(d!())?;
+        Ok(migration)
+    }
+
+    // the first `limit` keys in `[lower, upper)` with their values, including the cache
+    fn live_in_range(&self, lower: &[u8], upper: &[u8], limit: u64) -> Vec<KValue> {
+        let mut kv_map = KVecMap::new();
+        let cache = &self.cache;
+        self.iterate(lower, upper, IterOrder::Asc, &mut |(k, v)| {
+            if !cache.deleted(&k) {
+                kv_map.insert(k, v);
+            }
+            kv_map.len() as u64 >= limit
+        });
+        // once `limit` pairs are read, writes past the last of them can't be among the first
+        let mut cache_upper = upper.to_vec();
+        if kv_map.len() as u64 >= limit {
+            if let Some(last) = kv_map.keys().next_back() {
+                cache_upper = last.clone();
+                cache_upper.push(0);
+            }
+        }
+        self.cache.iter_range(lower, &cache_upper, &mut kv_map);
+        kv_map.into_iter().take(limit as usize).collect()
+    }
+
+    // keys under the prefix with their values, including the writes in the cache
+    fn live_under_prefix(&self, prefix: &Prefix) -> KVecMap {
+        let begin = prefix.begin();
+        let mut kv_map = KVecMap::new();
+        let cache = &self.cache;
+        self.iterate(&begin, &prefix.end(), IterOrder::Asc, &mut |(k, v)| {
+            if k.starts_with(&begin) && !cache.deleted(&k) {
+                kv_map.insert(k, v);
+            }
+            false
+        });
+        self.iterate_cache(&begin, &mut kv_map);
+        kv_map
+    }
+
+    /// Deletes every key in `[lower, upper)` and returns the number of deleted keys
+    ///
+    /// Unlike `delete_prefix` the range may span several prefixes, e.g. expired time buckets.
+    /// Every key is deleted through the session cache, so the versioned history keeps a
+    /// tombstone for it.
+    pub fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<u64> {
+        let mut keys = BTreeSet::new();
+        let cache = &self.cache;
+        self.iterate(lower, upper, IterOrder::Asc, &mut |(k, _)| {
+            if !cache.deleted(&k) {
+                keys.insert(k);
+            }
+            false
+        });
+        for key in self.cache.keys() {
+            if lower <= key.as_slice() && key.as_slice() < upper && self.cache.hasv(&key) {
+                keys.insert(key);
+            }
+        }
+        // the expiry index is only changed along with the keys it holds, the migration progress
+        // by the migration
+        keys.retain(|k| !ttl::is_reserved(k) && !migration::is_reserved(k));
+
+        for key in keys.iter() {
+            self.record_write(key);
+            self.cache.delete(key);
+        }
+        Ok(keys.len() as u64)
+    }
+
+    /// Sets a key value pair like `set`, the key is deleted by the commit of `expires_at`
+    ///
+    /// The expiry stays with the key across `set` and `delete` until the key expires, another
+    /// `set_with_ttl` moves it or `clear_ttl` removes it.
+    pub fn set_with_ttl(&mut self, key: &[u8], value: Vec<u8>, expires_at: u64) -> Result<()> {
+        if self.height_cap.is_some() {
+            return Err(eg!("Not support expiries on a state with height cap"));
+        }
+        if expires_at <= self.height().c(d!())? {
+            return Err(eg!("expiry height is already committed"));
+        }
+        self.set(key, value).c(d!())?;
+        self.clear_ttl(key).c(d!())?;
+        let height = ttl::encode_height(expires_at);
+        self.write_reserved(&ttl::index_key(expires_at, key), Some(height.clone()))
+            .c(d!())?;
+        self.write_reserved(&ttl::record_key(key), Some(height))
+    }
+
+    /// Height whose commit deletes `key`, `None` if it doesn't expire
+    pub fn ttl(&self, key: &[u8]) -> Result<Option<u64>> {
+        match self.get(&ttl::record_key(key)).c(d!())? {
+            Some(height) => ttl::decode_height(&height).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the expiry of `key`, the key is kept
+    pub fn clear_ttl(&mut self, key: &[u8]) -> Result<()> {
+        if let Some(height) = self.ttl(key).c(d!())? {
+            let index_key = ttl::index_key(height, key);
+            self.write_reserved(&index_key, None).c(d!())?;
+            self.write_reserved(&ttl::record_key(key), None).c(d!())?;
+        }
+        Ok(())
+    }
+
+    // Writes a key of the expiry index or of the migration progress, bypassing the key schema
+    fn write_reserved(&mut self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+        self.record_write(key);
+        match value {
+            Some(value) => {
+                if self.cache.put(key, value) {
+                    Ok(())
+                } else {
+                    Err(eg!("Invalid key-value pair detected."))
+                }
+            }
+            None => {
+                self.cache.delete(key);
+                Ok(())
+            }
+        }
+    }
+
+    /// Calls `hook` within the commit which deletes an expired key under `prefix`
+    ///
+    /// Hooks registered for nested prefixes are all called, in registration order. States
+    /// created by `copy` and `substate` inherit the hooks.
+    pub fn on_expire(&mut self, prefix: &Prefix, hook: ExpiryHook<D>) {
+        self.expiry.add_hook(prefix, hook);
+    }
+
+    /// Subscribes to the keys expired by the commits of this state
+    ///
+    /// Events are sent once the commit is done. At most `capacity` events are buffered, a
+    /// subscriber lagging behind misses events instead of slowing down commits.
+    pub fn subscribe_expiries(&mut self, capacity: usize) -> Receiver<ExpiryEvent> {
+        self.expiry.subscribe(capacity)
+    }
+
+    // Deletes the keys expiring at or before `height` and calls their hooks, nothing is written
+    // if a hook fails
+    fn purge_expired(&mut self, height: u64) -> Result<Vec<ExpiryEvent>> {
+        self.begin();
+        match self.purge_expired_inner(height) {
+            Ok(events) => {
+                self.commit_nested().c(d!())?;
+                Ok(events)
+            }
+            Err(e) => {
+                self.rollback_nested().c(d!())?;
+                Err(e)
+            }
+        }
+    }
+
+    fn purge_expired_inner(&mut self, height: u64) -> Result<Vec<ExpiryEvent>> {
+        let upper = ttl::index_prefix(height.saturating_add(1));
+        let entries = self.live_in_range(&ttl::index_begin(), upper.as_ref(), u64::MAX);
+
+        let mut events = vec![];
+        for (index_key, _) in entries {
+            self.write_reserved(&index_key, None).c(d!())?;
+            let (expires_at, key) = ttl::parse_index_key(&index_key).c(d!("invalid expiry"))?;
+            // moved by a later `set_with_ttl`
+            if self.ttl(&key).c(d!())? != Some(expires_at) {
+                continue;
+            }
+            self.write_reserved(&ttl::record_key(&key), None).c(d!())?;
+            if let Some(value) = self.get(&key).c(d!())? {
+                self.delete(&key).c(d!())?;
+                events.push(ExpiryEvent { height, key, value });
+            }
+        }
+
+        for event in events.iter() {
+            for hook in self.expiry.hooks_of(&event.key) {
+                hook(self, event).c(d!("expiry hook failed"))?;
+            }
+        }
+        Ok(events)
+    }
+
     // Deprecated and replaced by `delete`
     pub fn delete_v0(&mut self, key: &[u8]) -> Result<()> {
+        if ttl::is_reserved(key) {
+            return Err(eg!("keys under TTL are reserved for expiries"));
+        }
+        if migration::is_reserved(key) {
+            return Err(eg!("keys under MIG are reserved for migrations"));
+        }
+        self.record_write(key);
         let cs = self.chain_state.read();
         match cs.get(key).c(d!())? {
             //Mark key as deleted
@@ -172,10 +797,71 @@ impl<D: MerkleDB> State<D> {
         order: IterOrder,
         func: &mut dyn FnMut(KValue) -> bool,
     ) -> bool {
+        self.record_range(lower, upper);
         let cs = self.chain_state.read();
         cs.iterate(lower, upper, order, func)
     }
 
+    /// Iterates the ChainState for the given range of keys with read hints
+    pub fn iterate_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+        func: &mut dyn FnMut(KValue) -> bool,
+    ) -> bool {
+        self.record_range(lower, upper);
+        let cs = self.chain_state.read();
+        cs.iterate_with_opts(lower, upper, order, opts, func)
+    }
+
+    /// Walks the ChainState for the given range of keys with borrowed keys and values
+    pub fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        func: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        self.record_range(lower, upper);
+        let cs = self.chain_state.read();
+        cs.scan_apply(lower, upper, order, func)
+    }
+
+    /// Iterates the live pairs in `[lower, upper)` in ascending key order, with the writes of
+    /// the session
+    ///
+    /// The iterator doesn't borrow the state. It yields the view of the state when it was
+    /// created, once the chain state is committed to, e.g. by `commit`, reading further fails
+    /// with `ITERATOR_INVALIDATED`, see `is_iterator_invalidated`.
+    pub fn snapshot_iter(&self, lower: &[u8], upper: &[u8]) -> Result<StateSnapshotIter<D>> {
+        self.record_range(lower, upper);
+        StateSnapshotIter::new(self, lower, upper)
+    }
+
+    /// Returns the `n` greatest keys under `prefix` with their values, greatest first
+    ///
+    /// Seeks from the end of the prefix and stops after `n` live keys instead of scanning the
+    /// whole prefix, e.g. for the latest N events or blocks. Writes in the cache are included.
+    pub fn last_n_under_prefix(&self, prefix: &Prefix, n: usize) -> Vec<KValue> {
+        if n == 0 {
+            return vec![];
+        }
+        let begin = prefix.begin();
+        let mut kv_map = KVecMap::new();
+        let cache = &self.cache;
+        self.iterate(&begin, &prefix.end(), IterOrder::Desc, &mut |(k, v)| {
+            // the range also holds keys like "base<char>" which are not under the prefix
+            if k.starts_with(&begin) && !cache.deleted(&k) {
+                kv_map.insert(k, v);
+            }
+            kv_map.len() >= n
+        });
+        self.iterate_cache(&begin, &mut kv_map);
+        kv_map.into_iter().rev().take(n).collect()
+    }
+
     /// Iterates the cache for a given prefix
     pub(crate) fn iterate_cache(&self, prefix: &[u8], map: &mut KVecMap) {
         self.cache.iter_prefix(prefix, map);
@@ -184,39 +870,249 @@ impl<D: MerkleDB> State<D> {
     /// Commits the current state to the DB with the given height
     ///
     /// The cache gets persisted to the MerkleDB and then cleared
-    pub fn commit(&mut self, height: u64) -> Result<(Vec<u8>, u64)> {
+    pub fn commit(&mut self, height: impl Into<Height>) -> Result<(Vec<u8>, u64)> {
+        let height = height.into().get();
         if self.height_cap.is_some() {
             return Err(eg!("Not support commit a state with height cap"));
         }
+        if self.nested_depth() != 0 {
+            return Err(eg!(
+                "Not support commit a state with open nested transactions"
+            ));
+        }
+        let expired = self.purge_expired(height).c(d!())?;
         let mut cs = self.chain_state.write();
 
         //Get batch for current block and remove uncessary DELETE.
         //Note: DB will panic if it doesn't contain the key being deleted.
-        let mut kv_batch = self.cache.commit();
-        kv_batch.retain(|(k, v)| match cs.exists(k).unwrap() {
-            true => true,
-            false => v.is_some(),
-        });
+        let mut kv_batch = KVBatch::new();
+        for (k, v) in self.cache.commit() {
+            if v.is_some() || cs.exists(&k).c(d!())? {
+                kv_batch.push((k, v));
+            }
+        }
+
+        let mut sequences = std::mem::take(&mut self.sequences);
+        sequences.append(&mut self.sequences_delta);
+        let mut aux = sequences
+            .iter()
+            .map(|(name, value)| ChainState::<D>::sequence_entry(name, *value))
+            .collect::<KVBatch>();
+
+        let mut key_moves = std::mem::take(&mut self.key_moves);
+        key_moves.append(&mut self.key_moves_delta);
+        aux.append(&mut cs.history_copies(&key_moves).c(d!())?);
 
         //Clear the cache from the current state
         self.cache = SessionedCache::new(self.cache.is_merkle());
+        self.prefetched = Arc::default();
+        self.savepoints.clear();
 
         //Commit batch to db
-        cs.commit(kv_batch, height, true)
+        let committed = cs.commit_with_aux(kv_batch, aux, height, true).c(d!())?;
+        drop(cs);
+        self.expiry.publish(&expired);
+        Ok(committed)
+    }
+
+    /// Commits the current state like `commit` and returns the token of the commit
+    pub fn commit_with_token(
+        &mut self,
+        height: impl Into<Height>,
+    ) -> Result<(Vec<u8>, CommitToken)> {
+        let (root_hash, height) = self.commit(height).c(d!())?;
+        Ok((root_hash, CommitToken::new(height)))
     }
 
     /// Commits the cache of the current session.
     ///
-    /// The Base cache gets updated with the current cache.
-    pub fn commit_session(&mut self) {
+    /// The Base cache gets updated with the current cache. Nested transactions must be ended
+    /// first.
+    pub fn commit_session(&mut self) -> Result<()> {
+        if self.nested_depth() != 0 {
+            return Err(eg!(
+                "Not support commit a session with open nested transactions"
+            ));
+        }
         self.cache.commit_only();
+        self.sequences.append(&mut self.sequences_delta);
+        self.key_moves.append(&mut self.key_moves_delta);
+        self.savepoints.clear();
+        Ok(())
     }
 
     /// Discards the current session cache.
     ///
     /// The current cache is rebased.
     pub fn discard_session(&mut self) {
-        self.cache.discard()
+        self.cache.discard();
+        self.sequences_delta.clear();
+        self.key_moves_delta.clear();
+        self.savepoints.clear();
+    }
+
+    /// Increments the named sequence and returns its new value, the first one being 1
+    ///
+    /// Sequences live in the auxiliary data and follow the session lifecycle: increments are
+    /// dropped with a discarded session and persisted by the next commit. An increment is
+    /// recorded as a read and a write of the aux key of the sequence, two lanes incrementing
+    /// the same sequence conflict.
+    pub fn next_sequence(&mut self, name: &str) -> Result<u64> {
+        if self.height_cap.is_some() {
+            return Err(eg!("Not support sequences on a state with height cap"));
+        }
+        let current = match self
+            .sequences_delta
+            .get(name)
+            .or_else(|| self.sequences.get(name))
+        {
+            Some(value) => *value,
+            None => self.chain_state.read().get_sequence(name).c(d!())?,
+        };
+        let next = current.checked_add(1).c(d!("sequence overflow"))?;
+        self.sequences_delta.insert(name.to_owned(), next);
+        if self.access.is_some() {
+            let key = ChainState::<D>::sequence_key(name);
+            self.record_read(&key);
+            self.record_write(&key);
+        }
+        Ok(next)
+    }
+
+    /// Starts recording the keys read and written by this state, e.g. in an execution lane
+    ///
+    /// Range iterations are recorded as ranges, a key written in one of them by an earlier
+    /// lane is a conflict like a key read on its own.
+    pub fn record_access(&mut self) {
+        self.access = Some(Mutex::new(AccessList::new()));
+    }
+
+    /// Returns the keys read and written, and the ranges iterated, since `record_access` was
+    /// called
+    pub fn access_list(&self) -> Option<AccessList> {
+        self.access.as_ref().map(|access| access.lock().clone())
+    }
+
+    fn record_read(&self, key: &[u8]) {
+        if let Some(access) = &self.access {
+            access.lock().record_read(key);
+        }
+    }
+
+    fn record_range(&self, lower: &[u8], upper: &[u8]) {
+        if let Some(access) = &self.access {
+            access.lock().record_range(lower, upper);
+        }
+    }
+
+    fn record_write(&self, key: &[u8]) {
+        if let Some(access) = &self.access {
+            access.lock().record_write(key);
+        }
+    }
+
+    /// Pre-fetches the keys of predicted access lists, e.g. from mempool simulation
+    ///
+    /// Called while waiting for consensus so the reads of the next block are served from
+    /// memory. Lanes forked later share the prefetched values. They're tied to the root the
+    /// chain state had when they were read: once anything commits to the chain state they're
+    /// no longer served, and they're dropped at the next commit of this state. Returns the
+    /// number of keys loaded.
+    pub fn prefetch(&mut self, predicted: &[AccessList]) -> Result<u64> {
+        let cs = self.chain_state.read();
+        let version = Self::prefetch_version(&cs).c(d!())?;
+        let mut prefetched = if version == self.prefetched.version {
+            (*self.prefetched).clone()
+        } else {
+            Prefetched {
+                version,
+                values: KVMap::new(),
+            }
+        };
+        let keys = predicted
+            .iter()
+            .flat_map(|list| list.reads().iter().chain(list.writes()))
+            .filter(|key| !prefetched.values.contains_key(*key))
+            .collect::<BTreeSet<_>>();
+        for key in keys.iter() {
+            let value = match self.height_cap {
+                Some(height) => cs.get_ver(key, height),
+                None => cs.get(key),
+            };
+            prefetched.values.insert(key.to_vec(), value.c(d!())?);
+        }
+        drop(cs);
+        self.prefetched = Arc::new(prefetched);
+        Ok(keys.len() as u64)
+    }
+
+    // Prefetched value of `key`, `None` if it wasn't prefetched or the chain state moved since
+    fn prefetched(&self, cs: &ChainState<D>, key: &[u8]) -> Result<Option<&Option<Vec<u8>>>> {
+        let value = match self.prefetched.values.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let current = Self::prefetch_version(cs).c(d!())? == self.prefetched.version;
+        Ok(current.then_some(value))
+    }
+
+    // What prefetched values are tied to: the root hash, the height for backends without
+    // a merkle tree
+    fn prefetch_version(cs: &ChainState<D>) -> Result<Vec<u8>> {
+        let root = cs.root_hash();
+        if !root.is_empty() {
+            return Ok(root);
+        }
+        Ok(cs.height().c(d!())?.to_be_bytes().to_vec())
+    }
+
+    /// Merges the writes and the sequence increments of lanes forked from this state, in lane
+    /// order
+    ///
+    /// Every lane must record its accesses. Nothing is merged if the lanes conflict, the
+    /// returned report tells which lanes have to be re-executed.
+    pub fn merge_lanes(&mut self, lanes: &[State<D>]) -> Result<ConflictReport> {
+        let lists = lanes
+            .iter()
+            .map(|lane| {
+                lane.access_list()
+                    .c(d!("lane does not record its accesses"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let report = detect_conflicts(&lists);
+        if !report.is_empty() {
+            return Ok(report);
+        }
+
+        for (lane, list) in lanes.iter().zip(&lists) {
+            for key in list.writes() {
+                match lane.cache.get(key) {
+                    Some(Some(value)) => self.set(key, value).c(d!())?,
+                    Some(None) => self.delete(key).c(d!())?,
+                    // the write was discarded by the lane
+                    None => {}
+                }
+            }
+            for (name, value) in lane.sequences_delta.iter() {
+                if list.writes().contains(&ChainState::<D>::sequence_key(name)) {
+                    self.sequences_delta.insert(name.clone(), *value);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Gets the committed values of `keys` at `height` together with a single combined proof.
+    pub fn get_many_with_proof(
+        &self,
+        keys: &[Vec<u8>],
+        height: impl Into<Height>,
+    ) -> Result<ProvenValues> {
+        let height = height.into().get();
+        if matches!(self.height_cap, Some(cap) if cap < height) {
+            return Err(eg!("height is beyond the height cap of this state"));
+        }
+        self.chain_state.read().get_many_with_proof(keys, height)
     }
 
     /// Export a copy of chain state on a specific height.
@@ -224,10 +1120,20 @@ impl<D: MerkleDB> State<D> {
     /// * `cs` - The target chain state that holds the copy.
     /// * `height` - On which height the copy will be taken.
     ///
-    pub fn export(&self, cs: &mut ChainState<D>, height: u64) -> Result<()> {
+    pub fn export(&self, cs: &mut ChainState<D>, height: impl Into<Height>) -> Result<()> {
         self.chain_state.read().export(cs, height)
     }
 
+    /// Export a copy of chain state on a specific height, keeping only the keys passing `filter`
+    pub fn export_filtered(
+        &self,
+        cs: &mut ChainState<D>,
+        height: u64,
+        filter: &PrefixFilter,
+    ) -> Result<()> {
+        self.chain_state.read().export_filtered(cs, height, filter)
+    }
+
     /// Returns whether or not a key has been modified in the current block
     pub fn touched(&self, key: &[u8]) -> bool {
         self.cache.touched(key)
@@ -243,6 +1149,11 @@ impl<D: MerkleDB> State<D> {
         })
     }
 
+    /// Returns the token of the last commit visible to this state
+    pub fn commit_token(&self) -> Result<CommitToken> {
+        self.height().map(CommitToken::new)
+    }
+
     /// Returns the root hash of the last commit
     pub fn root_hash(&self) -> Vec<u8> {
         if self.height_cap.is_some() {
@@ -0,0 +1,154 @@
+/// Background integrity scrubber
+///
+/// Slowly walks the primary section of the db and asks the backend to re-verify every entry,
+/// e.g. merkle node hashes. Corrupted keys are reported and optionally quarantined, reads of
+/// quarantined keys fail instead of returning corrupted data.
+///
+use crate::{db::MerkleDB, state::ChainState};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// default scrubbing rate, 1MB/s
+pub const DEFAULT_SCRUB_RATE: u64 = 0x0010_0000;
+/// default number of bytes verified while holding the chain state lock
+pub const DEFAULT_SCRUB_STEP: u64 = 0x0001_0000;
+
+/// Scrubber options
+#[derive(Clone, Debug)]
+pub struct ScrubOpts {
+    /// Max number of bytes verified per second
+    pub bytes_per_sec: u64,
+    /// Number of bytes verified in one step
+    pub step_bytes: u64,
+    /// Quarantine corrupted keys or only report them
+    pub quarantine: bool,
+}
+
+impl Default for ScrubOpts {
+    fn default() -> Self {
+        ScrubOpts {
+            bytes_per_sec: DEFAULT_SCRUB_RATE,
+            step_bytes: DEFAULT_SCRUB_STEP,
+            quarantine: false,
+        }
+    }
+}
+
+/// A corrupted entry found by the scrubber
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Corruption {
+    pub key: Vec<u8>,
+    pub error: String,
+}
+
+/// Result of a single scrubbing step
+#[derive(Clone, Debug, Default)]
+pub struct ScrubStep {
+    /// key to resume from, `None` once the end of the db is reached
+    pub next: Option<Vec<u8>>,
+    pub keys: u64,
+    pub bytes: u64,
+    pub corrupted: Vec<Corruption>,
+}
+
+/// Accumulated results of a running scrubber
+#[derive(Clone, Debug, Default)]
+pub struct ScrubReport {
+    /// number of complete passes over the db
+    pub passes: u64,
+    pub keys: u64,
+    pub bytes: u64,
+    pub corrupted: Vec<Corruption>,
+}
+
+/// Handle of a scrubber running on its own thread, the scrubber stops when dropped
+pub struct Scrubber {
+    stop: Arc<AtomicBool>,
+    report: Arc<Mutex<ScrubReport>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Scrubber {
+    /// Starts scrubbing `cs` in the background
+    pub fn spawn<D>(cs: Arc<RwLock<ChainState<D>>>, opts: ScrubOpts) -> Self
+    where
+        D: MerkleDB + Send + Sync + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let report = Arc::new(Mutex::new(ScrubReport::default()));
+
+        let handle = {
+            let stop = stop.clone();
+            let report = report.clone();
+            thread::spawn(move || scrub_loop(cs, opts, stop, report))
+        };
+
+        Scrubber {
+            stop,
+            report,
+            handle: Some(handle),
+        }
+    }
+
+    /// Returns the results collected so far
+    pub fn report(&self) -> ScrubReport {
+        self.report.lock().clone()
+    }
+
+    /// Stops the scrubber and returns its final report
+    pub fn stop(mut self) -> ScrubReport {
+        self.shutdown();
+        self.report()
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn scrub_loop<D: MerkleDB>(
+    cs: Arc<RwLock<ChainState<D>>>,
+    opts: ScrubOpts,
+    stop: Arc<AtomicBool>,
+    report: Arc<Mutex<ScrubReport>>,
+) {
+    let mut from = vec![];
+    while !stop.load(Ordering::SeqCst) {
+        // the lock is only held for a single step so commits are not held up
+        let step = cs
+            .read()
+            .scrub_step(&from, opts.step_bytes, opts.quarantine);
+
+        {
+            let mut report = report.lock();
+            report.keys = report.keys.saturating_add(step.keys);
+            report.bytes = report.bytes.saturating_add(step.bytes);
+            report.corrupted.extend(step.corrupted);
+            if step.next.is_none() {
+                report.passes = report.passes.saturating_add(1);
+            }
+        }
+        from = step.next.unwrap_or_default();
+
+        // throttle as if a full step was verified, woken up early on stop
+        let secs = opts.step_bytes.max(1) as f64 / opts.bytes_per_sec.max(1) as f64;
+        thread::park_timeout(Duration::from_secs_f64(secs));
+    }
+}
@@ -0,0 +1,133 @@
+/// Write-time key sketches
+///
+/// A chain state keeps a small sketch of the keys written under each registered prefix: a
+/// HyperLogLog estimating the number of distinct keys and a count-min sketch tracking the
+/// most frequently updated keys. Sketches are updated on commit in constant memory, so cache
+/// sizes and pruning policies can be tuned without scanning the store. They live in memory
+/// only and cover the writes since the prefix was registered.
+///
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    hash::{Hash, Hasher},
+};
+
+/// HyperLogLog precision, 4096 one byte registers, ~1.6% standard error
+const HLL_PRECISION: u32 = 12;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+const CM_WIDTH: usize = 1024;
+const CM_DEPTH: usize = 4;
+/// number of heavy hitters kept by a sketch
+pub const DEFAULT_HEAVY_HITTERS: usize = 16;
+
+/// Estimates of the keys written under a prefix
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeySketchStats {
+    /// puts and deletes committed
+    pub updates: u64,
+    /// approximate number of distinct keys written
+    pub distinct_keys: u64,
+    /// most updated keys with their approximate update counts, most updated first
+    pub heavy_hitters: Vec<(Vec<u8>, u64)>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct KeySketch {
+    updates: u64,
+    registers: Vec<u8>,
+    counts: Vec<u64>,
+    heavy_hitters: BTreeMap<Vec<u8>, u64>,
+}
+
+impl Default for KeySketch {
+    fn default() -> Self {
+        KeySketch {
+            updates: 0,
+            registers: vec![0; HLL_REGISTERS],
+            counts: vec![0; CM_WIDTH * CM_DEPTH],
+            heavy_hitters: BTreeMap::new(),
+        }
+    }
+}
+
+fn hash(seed: u64, key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl KeySketch {
+    pub(crate) fn record(&mut self, key: &[u8]) {
+        self.updates = self.updates.saturating_add(1);
+
+        // the first bits pick a register, which keeps the longest run of leading zeros seen
+        // in the remaining bits
+        let h = hash(0, key);
+        let register = (h >> (64 - HLL_PRECISION)) as usize;
+        let rank = ((h << HLL_PRECISION) | (1 << (HLL_PRECISION - 1))).leading_zeros() + 1;
+        self.registers[register] = self.registers[register].max(rank as u8);
+
+        let mut estimate = u64::MAX;
+        for row in 0..CM_DEPTH {
+            let col = (hash(row as u64 + 1, key) % CM_WIDTH as u64) as usize;
+            let count = &mut self.counts[row * CM_WIDTH + col];
+            *count = count.saturating_add(1);
+            estimate = estimate.min(*count);
+        }
+        self.update_heavy_hitters(key, estimate);
+    }
+
+    // keeps the keys with the highest estimates, evicting the lowest one when full
+    fn update_heavy_hitters(&mut self, key: &[u8], estimate: u64) {
+        if let Some(count) = self.heavy_hitters.get_mut(key) {
+            *count = estimate;
+            return;
+        }
+        if self.heavy_hitters.len() < DEFAULT_HEAVY_HITTERS {
+            self.heavy_hitters.insert(key.to_vec(), estimate);
+            return;
+        }
+        let lowest = self
+            .heavy_hitters
+            .iter()
+            .min_by_key(|(_, count)| **count)
+            .map(|(k, count)| (k.clone(), *count));
+        if let Some((lowest, count)) = lowest {
+            if estimate > count {
+                self.heavy_hitters.remove(&lowest);
+                self.heavy_hitters.insert(key.to_vec(), estimate);
+            }
+        }
+    }
+
+    fn distinct_keys(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|r| 2f64.powi(-i32::from(*r)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        // linear counting is more accurate for small cardinalities
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+
+    pub(crate) fn stats(&self) -> KeySketchStats {
+        let mut heavy_hitters = self
+            .heavy_hitters
+            .iter()
+            .map(|(k, count)| (k.clone(), *count))
+            .collect::<Vec<_>>();
+        heavy_hitters.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        KeySketchStats {
+            updates: self.updates,
+            distinct_keys: self.distinct_keys(),
+            heavy_hitters,
+        }
+    }
+}
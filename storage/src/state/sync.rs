@@ -0,0 +1,274 @@
+/// Chunk tracking for state sync
+///
+/// A syncing node downloads a snapshot split in chunks from many peers at once. The tracker
+/// knows which chunks every peer has, hands out requests rarest chunk first so that chunks
+/// held by few peers don't end up last, re-queues requests of slow or gone peers, and persists
+/// the chunks received so far so a download resumes where it stopped after a restart.
+///
+use crate::hex;
+use ruc::*;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Set of chunk indexes in `[0, len)`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkSet {
+    bits: Vec<u8>,
+    len: u64,
+}
+
+impl ChunkSet {
+    /// Empty set of `len` chunks
+    pub fn new(len: u64) -> Self {
+        ChunkSet {
+            bits: vec![0; len.div_ceil(8) as usize],
+            len,
+        }
+    }
+
+    /// Set holding all `len` chunks, e.g. the availability of a peer with the full snapshot
+    pub fn full(len: u64) -> Self {
+        let mut set = Self::new(len);
+        (0..len).for_each(|i| set.insert(i));
+        set
+    }
+
+    /// Set from a bitmap, bit `i % 8` of byte `i / 8` being chunk `i`
+    ///
+    /// The bits after chunk `len - 1` must be zero, they would be counted as chunks otherwise.
+    pub fn from_bytes(bytes: &[u8], len: u64) -> Result<Self> {
+        let mut set = Self::new(len);
+        if bytes.len() != set.bits.len() {
+            return Err(eg!("invalid chunk bitmap length"));
+        }
+        let used = len % 8;
+        if let Some(last) = bytes.last().filter(|_| used != 0) {
+            if *last >> used != 0 {
+                return Err(eg!("chunk bitmap has bits out of range"));
+            }
+        }
+        set.bits.copy_from_slice(bytes);
+        Ok(set)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Ignores chunks out of range
+    pub fn insert(&mut self, chunk: u64) {
+        if chunk < self.len {
+            self.bits[(chunk / 8) as usize] |= 1 << (chunk % 8);
+        }
+    }
+
+    pub fn contains(&self, chunk: u64) -> bool {
+        chunk < self.len && self.bits[(chunk / 8) as usize] & (1 << (chunk % 8)) != 0
+    }
+
+    /// Number of chunks in the set
+    pub fn count(&self) -> u64 {
+        self.bits.iter().map(|b| u64::from(b.count_ones())).sum()
+    }
+}
+
+/// Chunk availability and request tracker of one snapshot
+pub struct ChunkTracker<P: Ord + Clone> {
+    // identifies the snapshot, e.g. its hash, progress of another snapshot is never resumed
+    target: Vec<u8>,
+    done: ChunkSet,
+    peers: BTreeMap<P, ChunkSet>,
+    // chunk => peer it's requested from and when
+    in_flight: BTreeMap<u64, (P, Instant)>,
+    path: Option<PathBuf>,
+}
+
+impl<P: Ord + Clone> ChunkTracker<P> {
+    /// Tracker of the `n_chunks` chunks of the snapshot `target`, without persistence
+    pub fn new(target: Vec<u8>, n_chunks: u64) -> Self {
+        ChunkTracker {
+            target,
+            done: ChunkSet::new(n_chunks),
+            peers: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+            path: None,
+        }
+    }
+
+    /// Tracker persisting the received chunks at `path`
+    ///
+    /// Progress found at `path` is resumed if it belongs to the same snapshot, otherwise the
+    /// download starts over.
+    pub fn open<Q: AsRef<Path>>(path: Q, target: Vec<u8>, n_chunks: u64) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut tracker = Self::new(target, n_chunks);
+        if path.exists() {
+            let value: Value = serde_json::from_slice(&fs::read(&path).c(d!())?).c(d!())?;
+            let saved_target = value["target"].as_str().map(hex::decode);
+            let saved_chunks = value["chunks"].as_u64();
+            match (saved_target, saved_chunks, value["done"].as_str()) {
+                (Some(Ok(t)), Some(n), Some(done)) if t == tracker.target && n == n_chunks => {
+                    let done = hex::decode(done).c(d!())?;
+                    tracker.done = ChunkSet::from_bytes(&done, n_chunks).c(d!())?;
+                }
+                // progress of another snapshot, the download starts over
+                _ => {}
+            }
+        }
+        tracker.path = Some(path);
+        Ok(tracker)
+    }
+
+    /// Sets the chunks `peer` has, replacing what was known before
+    pub fn set_peer(&mut self, peer: P, available: ChunkSet) {
+        self.peers.insert(peer, available);
+    }
+
+    /// Records that `peer` has got `chunk`
+    pub fn peer_has(&mut self, peer: P, chunk: u64) {
+        let len = self.done.len();
+        self.peers
+            .entry(peer)
+            .or_insert_with(|| ChunkSet::new(len))
+            .insert(chunk);
+    }
+
+    /// Forgets `peer` and returns the chunks which were requested from it
+    pub fn remove_peer(&mut self, peer: &P) -> Vec<u64> {
+        self.peers.remove(peer);
+        let requeued = self
+            .in_flight
+            .iter()
+            .filter(|(_, (p, _))| p == peer)
+            .map(|(&chunk, _)| chunk)
+            .collect::<Vec<_>>();
+        requeued.iter().for_each(|c| {
+            self.in_flight.remove(c);
+        });
+        requeued
+    }
+
+    /// Picks the next chunks to request from `peer`, up to `max_in_flight` requests in total
+    ///
+    /// Chunks held by the fewest peers come first. Returned chunks are in flight until they
+    /// are completed, failed or expired.
+    pub fn request(&mut self, peer: &P, max_in_flight: usize) -> Vec<u64> {
+        let available = match self.peers.get(peer) {
+            Some(available) => available,
+            None => return vec![],
+        };
+        let budget = max_in_flight.saturating_sub(self.in_flight(peer));
+        let mut candidates = (0..self.done.len())
+            .filter(|&c| {
+                available.contains(c) && !self.done.contains(c) && !self.in_flight.contains_key(&c)
+            })
+            .map(|c| (self.peers.values().filter(|a| a.contains(c)).count(), c))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+
+        let now = Instant::now();
+        let picked = candidates
+            .into_iter()
+            .take(budget)
+            .map(|(_, c)| c)
+            .collect::<Vec<_>>();
+        for &c in picked.iter() {
+            self.in_flight.insert(c, (peer.clone(), now));
+        }
+        picked
+    }
+
+    /// Marks `chunk` as received and persists the progress
+    ///
+    /// Returns false if it had been received before.
+    pub fn complete(&mut self, chunk: u64) -> Result<bool> {
+        if chunk >= self.done.len() {
+            return Err(eg!(format!("chunk {} out of range", chunk)));
+        }
+        self.in_flight.remove(&chunk);
+        if self.done.contains(chunk) {
+            return Ok(false);
+        }
+        self.done.insert(chunk);
+        self.save().c(d!())?;
+        Ok(true)
+    }
+
+    /// Gives up the request of `chunk`, e.g. its content didn't match its hash
+    ///
+    /// The chunk can be requested again, e.g. from another peer.
+    pub fn fail(&mut self, chunk: u64) {
+        self.in_flight.remove(&chunk);
+    }
+
+    /// Gives up requests older than `timeout` and returns them
+    pub fn expire(&mut self, timeout: Duration) -> Vec<(P, u64)> {
+        let now = Instant::now();
+        let expired = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, at))| now.duration_since(*at) >= timeout)
+            .map(|(&chunk, (peer, _))| (peer.clone(), chunk))
+            .collect::<Vec<_>>();
+        expired.iter().for_each(|(_, c)| {
+            self.in_flight.remove(c);
+        });
+        expired
+    }
+
+    /// Number of requests in flight to `peer`
+    pub fn in_flight(&self, peer: &P) -> usize {
+        self.in_flight.values().filter(|(p, _)| p == peer).count()
+    }
+
+    /// Chunks received so far
+    pub fn done(&self) -> &ChunkSet {
+        &self.done
+    }
+
+    /// Number of chunks still missing
+    pub fn missing(&self) -> u64 {
+        self.done.len() - self.done.count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing() == 0
+    }
+
+    /// Removes the persisted progress, e.g. once the snapshot is applied
+    pub fn finish(self) -> Result<()> {
+        match self.path {
+            Some(path) if path.exists() => fs::remove_file(path).c(d!()),
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let value = json!({
+            "target": hex::encode(&self.target),
+            "chunks": self.done.len(),
+            "done": hex::encode(self.done.as_bytes()),
+        });
+        // a crash while writing must not lose the progress saved before
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&value).c(d!())?).c(d!())?;
+        fs::rename(tmp, path).c(d!())
+    }
+}
@@ -0,0 +1,54 @@
+/// Read consistency tokens
+///
+/// Every commit yields a token that only grows. A client remembers the token of its last
+/// write and passes it as `min_token` when reading from a replica; a replica which hasn't
+/// caught up yet answers with a retryable `NotYetAvailable` error instead of stale data.
+///
+use ruc::*;
+use std::fmt;
+
+/// marker carried by errors of reads the replica can't serve yet
+pub const NOT_YET_AVAILABLE: &str = "NotYetAvailable";
+
+/// Monotonically increasing token of a commit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommitToken(u64);
+
+impl CommitToken {
+    pub fn new(height: u64) -> Self {
+        CommitToken(height)
+    }
+
+    /// Returns the height of the commit the token was issued for
+    pub fn height(&self) -> u64 {
+        self.0
+    }
+
+    /// Fails with `NotYetAvailable` if `current` is older than this token
+    pub fn check(&self, current: CommitToken) -> Result<()> {
+        if current < *self {
+            return Err(eg!(format!(
+                "{}: replica is at {}, token requires {}",
+                NOT_YET_AVAILABLE, current, self
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl From<u64> for CommitToken {
+    fn from(height: u64) -> Self {
+        CommitToken(height)
+    }
+}
+
+impl fmt::Display for CommitToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Returns true if `err` is a retryable `NotYetAvailable` error
+pub fn is_not_yet_available(err: &dyn RucError) -> bool {
+    err.to_string().contains(NOT_YET_AVAILABLE)
+}
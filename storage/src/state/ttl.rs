@@ -0,0 +1,150 @@
+/// Key expiration
+///
+/// `State::set_with_ttl` gives a key an expiry height. The expiry index lives in the state
+/// under a reserved prefix, so every node purges the same keys at the same height and the root
+/// hash covers it. The commit of a height deletes the keys expiring at or before it, calls the
+/// hooks registered for their prefixes, whose writes are committed along, e.g. to unlock the
+/// funds held by an expired order, and sends an `ExpiryEvent` to the subscribers.
+///
+/// Keys under `TTL` are reserved for the index, they can't be written or deleted through the
+/// state. A hook failing fails the commit and drops the writes of the purge.
+///
+use crate::{db::MerkleDB, state::State, store::Prefix};
+use ruc::*;
+use std::sync::{
+    mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    Arc,
+};
+
+const TTL_PREFIX: &[u8; 3] = b"TTL";
+const TTL_INDEX: &[u8; 2] = b"At";
+const TTL_RECORD: &[u8; 3] = b"Key";
+// begin of `Prefix::new(TTL_PREFIX)`
+const TTL_BEGIN: &[u8; 4] = b"TTL_";
+
+/// A key deleted because it expired
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExpiryEvent {
+    /// height of the commit which deleted the key
+    pub height: u64,
+    pub key: Vec<u8>,
+    /// last value of the key
+    pub value: Vec<u8>,
+}
+
+/// Called within the commit for every expired key under the prefix it is registered for
+///
+/// Its writes to the state are part of the same commit, an error fails the commit.
+pub type ExpiryHook<D> = Arc<dyn Fn(&mut State<D>, &ExpiryEvent) -> Result<()> + Send + Sync>;
+
+/// Hooks and subscribers of the expired keys
+pub(crate) struct ExpiryNotifier<D: MerkleDB> {
+    hooks: Vec<(Vec<u8>, ExpiryHook<D>)>,
+    subs: Vec<SyncSender<ExpiryEvent>>,
+}
+
+impl<D: MerkleDB> Default for ExpiryNotifier<D> {
+    fn default() -> Self {
+        ExpiryNotifier {
+            hooks: vec![],
+            subs: vec![],
+        }
+    }
+}
+
+// subscribers get the events of the state they subscribed on only
+impl<D: MerkleDB> Clone for ExpiryNotifier<D> {
+    fn clone(&self) -> Self {
+        ExpiryNotifier {
+            hooks: self.hooks.clone(),
+            subs: vec![],
+        }
+    }
+}
+
+impl<D: MerkleDB> ExpiryNotifier<D> {
+    pub(crate) fn add_hook(&mut self, prefix: &Prefix, hook: ExpiryHook<D>) {
+        self.hooks.push((prefix.begin(), hook));
+    }
+
+    pub(crate) fn subscribe(&mut self, capacity: usize) -> Receiver<ExpiryEvent> {
+        let (sender, receiver) = sync_channel(capacity);
+        self.subs.push(sender);
+        receiver
+    }
+
+    /// Hooks registered for a prefix of `key`, in registration order
+    pub(crate) fn hooks_of(&self, key: &[u8]) -> Vec<ExpiryHook<D>> {
+        self.hooks
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix))
+            .map(|(_, hook)| hook.clone())
+            .collect()
+    }
+
+    /// Pushes the events without blocking, subscribers gone away are removed
+    pub(crate) fn publish(&mut self, events: &[ExpiryEvent]) {
+        self.subs.retain(|sub| {
+            events.iter().all(|event| {
+                !matches!(
+                    sub.try_send(event.clone()),
+                    Err(TrySendError::Disconnected(_))
+                )
+            })
+        });
+    }
+}
+
+/// Whether `key` is under the reserved prefix of the expiry index
+pub(crate) fn is_reserved(key: &[u8]) -> bool {
+    key.starts_with(TTL_BEGIN)
+}
+
+fn height_str(height: u64) -> String {
+    format!("{:020}", height)
+}
+
+/// Key of `key` in the index of the keys expiring at `height`
+pub(crate) fn index_key(height: u64, key: &[u8]) -> Vec<u8> {
+    index_prefix(height).push(key).as_ref().to_vec()
+}
+
+/// Prefix of the index entries of `height`
+pub(crate) fn index_prefix(height: u64) -> Prefix {
+    Prefix::new(TTL_PREFIX)
+        .push(TTL_INDEX)
+        .push(height_str(height).as_bytes())
+}
+
+/// First key of the index
+pub(crate) fn index_begin() -> Vec<u8> {
+    Prefix::new(TTL_PREFIX).push(TTL_INDEX).begin()
+}
+
+/// Height and key of an index entry
+pub(crate) fn parse_index_key(index_key: &[u8]) -> Option<(u64, Vec<u8>)> {
+    let rest = index_key.strip_prefix(index_begin().as_slice())?;
+    let height = std::str::from_utf8(rest.get(..20)?).ok()?.parse().ok()?;
+    let key = rest.get(21..)?.to_vec();
+    Some((height, key))
+}
+
+/// Key holding the expiry height of `key`
+pub(crate) fn record_key(key: &[u8]) -> Vec<u8> {
+    Prefix::new(TTL_PREFIX)
+        .push(TTL_RECORD)
+        .push(key)
+        .as_ref()
+        .to_vec()
+}
+
+pub(crate) fn encode_height(height: u64) -> Vec<u8> {
+    height.to_string().into_bytes()
+}
+
+pub(crate) fn decode_height(value: &[u8]) -> Result<u64> {
+    std::str::from_utf8(value)
+        .c(d!("invalid expiry height"))?
+        .parse::<u64>()
+        .c(d!("invalid expiry height"))
+}
@@ -0,0 +1,223 @@
+/// Typed map with secondary indexes
+///
+/// Indexes are declared from value fields. A unique index maps an index key to the primary
+/// key of the only entry having it, a multi index maps an index key and a primary key to the
+/// primary key, so entries sharing an index key are found with one range scan. Indexes are
+/// stored next to the map as `<module>_<map>.<index>_<index key>[_<primary key>]`, with index
+/// keys hex encoded so they sort like the raw ones and never contain a separator.
+///
+use crate::{
+    db::MerkleDB,
+    hex,
+    state::State,
+    store::{
+        module::merged_range, DecodeMode, KeyDecode, KeyEncode, Map, Module, Prefix, TypedRange,
+    },
+};
+use ruc::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Secondary index of an `IndexedMap`
+pub struct Index<V> {
+    name: &'static str,
+    unique: bool,
+    key_fn: fn(&V) -> Vec<u8>,
+    pfx: Prefix,
+}
+
+impl<V> Index<V> {
+    // store key of an index entry
+    fn entry_key(&self, index_key: &[u8], primary: &[u8]) -> Vec<u8> {
+        let pfx = self.pfx.push(hex::encode(index_key).as_bytes());
+        if self.unique {
+            pfx.as_ref().to_vec()
+        } else {
+            pfx.push(primary).as_ref().to_vec()
+        }
+    }
+}
+
+/// Typed map of a module with secondary indexes
+pub struct IndexedMap<K, V> {
+    module: Module,
+    name: String,
+    map: Map<K, V>,
+    indexes: Vec<Index<V>>,
+}
+
+impl Module {
+    pub fn indexed_map<K, V>(&self, name: &str) -> IndexedMap<K, V> {
+        IndexedMap {
+            module: *self,
+            name: name.to_string(),
+            map: self.map(name),
+            indexes: vec![],
+        }
+    }
+}
+
+impl<K, V> IndexedMap<K, V>
+where
+    K: KeyEncode + KeyDecode,
+    V: Serialize + DeserializeOwned,
+{
+    /// Adds an index which at most one entry can have any key of
+    pub fn unique_index(self, name: &'static str, key_fn: fn(&V) -> Vec<u8>) -> Self {
+        self.with_index(name, true, key_fn)
+    }
+
+    /// Adds an index which any number of entries can share a key of
+    pub fn multi_index(self, name: &'static str, key_fn: fn(&V) -> Vec<u8>) -> Self {
+        self.with_index(name, false, key_fn)
+    }
+
+    fn with_index(mut self, name: &'static str, unique: bool, key_fn: fn(&V) -> Vec<u8>) -> Self {
+        let pfx = self
+            .module
+            .prefix()
+            .push(format!("{}.{}", self.name, name).as_bytes());
+        self.indexes.push(Index {
+            name,
+            unique,
+            key_fn,
+            pfx,
+        });
+        self
+    }
+
+    /// The map without its indexes, for reads
+    pub fn map(&self) -> &Map<K, V> {
+        &self.map
+    }
+
+    pub fn get<D: MerkleDB>(&self, state: &State<D>, key: &K) -> Result<Option<V>> {
+        self.map.get(state, key)
+    }
+
+    pub fn load<D: MerkleDB>(&self, state: &State<D>, key: &K) -> Result<V> {
+        self.map.load(state, key)
+    }
+
+    pub fn has<D: MerkleDB>(&self, state: &State<D>, key: &K) -> Result<bool> {
+        self.map.has(state, key)
+    }
+
+    /// Saves `value` and updates the indexes
+    ///
+    /// Fails without writing anything if a unique index key is taken by another entry.
+    pub fn save<D: MerkleDB>(&self, state: &mut State<D>, key: &K, value: &V) -> Result<()> {
+        let primary = key.encode_key();
+        let old = self.map.get(state, key).c(d!())?;
+
+        for index in self.indexes.iter().filter(|i| i.unique) {
+            let entry = index.entry_key(&(index.key_fn)(value), &primary);
+            if let Some(owner) = state.get(&entry).c(d!())? {
+                if owner != primary {
+                    return Err(eg!(format!("unique index {} violated", index.name)));
+                }
+            }
+        }
+
+        for index in self.indexes.iter() {
+            let new_key = (index.key_fn)(value);
+            if let Some(old) = old.as_ref() {
+                let old_key = (index.key_fn)(old);
+                if old_key == new_key {
+                    continue;
+                }
+                state.delete(&index.entry_key(&old_key, &primary)).c(d!())?;
+            }
+            state
+                .set(&index.entry_key(&new_key, &primary), primary.clone())
+                .c(d!())?;
+        }
+        self.map.save(state, key, value)
+    }
+
+    /// Removes the entry of `key` and its index entries
+    pub fn remove<D: MerkleDB>(&self, state: &mut State<D>, key: &K) -> Result<()> {
+        let old = match self.map.get(state, key).c(d!())? {
+            Some(old) => old,
+            None => return Ok(()),
+        };
+        let primary = key.encode_key();
+        for index in self.indexes.iter() {
+            state
+                .delete(&index.entry_key(&(index.key_fn)(&old), &primary))
+                .c(d!())?;
+        }
+        self.map.remove(state, key)
+    }
+
+    /// All entries in primary key order
+    pub fn range<D: MerkleDB>(
+        &self,
+        state: &State<D>,
+        mode: DecodeMode,
+    ) -> Result<TypedRange<K, V>> {
+        self.map.range(state, mode)
+    }
+
+    /// Entries whose key of `index` is `index_key`
+    pub fn by_index<D: MerkleDB>(
+        &self,
+        state: &State<D>,
+        index: &str,
+        index_key: &[u8],
+    ) -> Result<Vec<(K, V)>> {
+        let index = self.index(index).c(d!())?;
+        let pfx = index.pfx.push(hex::encode(index_key).as_bytes());
+        if index.unique {
+            match state.get(pfx.as_ref()).c(d!())? {
+                Some(primary) => self.load_primary(state, &primary).map(|e| vec![e]),
+                None => Ok(vec![]),
+            }
+        } else {
+            self.load_range(state, &pfx, &pfx.begin(), &pfx.end())
+        }
+    }
+
+    /// Entries whose key of `index` is in `[lower, upper)`, in index key order
+    pub fn index_range<D: MerkleDB>(
+        &self,
+        state: &State<D>,
+        index: &str,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Result<Vec<(K, V)>> {
+        let index = self.index(index).c(d!())?;
+        let bound = |key: &[u8]| {
+            let mut bound = index.pfx.begin();
+            bound.extend_from_slice(hex::encode(key).as_bytes());
+            bound
+        };
+        self.load_range(state, &index.pfx, &bound(lower), &bound(upper))
+    }
+
+    fn index(&self, name: &str) -> Result<&Index<V>> {
+        self.indexes
+            .iter()
+            .find(|i| i.name == name)
+            .c(d!(format!("no index {}", name)))
+    }
+
+    fn load_primary<D: MerkleDB>(&self, state: &State<D>, primary: &[u8]) -> Result<(K, V)> {
+        let key = K::decode_key(primary).c(d!())?;
+        let value = self.map.load(state, &key).c(d!())?;
+        Ok((key, value))
+    }
+
+    // loads the entries of the index entries in [lower, upper), including uncommitted ones
+    fn load_range<D: MerkleDB>(
+        &self,
+        state: &State<D>,
+        pfx: &Prefix,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Result<Vec<(K, V)>> {
+        merged_range(state, pfx, lower, upper)
+            .values()
+            .map(|primary| self.load_primary(state, primary))
+            .collect()
+    }
+}
@@ -1,8 +1,17 @@
 use crate::db::MerkleDB;
 use crate::state::State;
-pub use traits::{Stated, Store};
+pub use indexed::{Index, IndexedMap};
+pub use module::{Item, Map, Module, Queue, Seq};
+use ruc::*;
+pub use snapshot_map::{SnapshotMap, SnapshotStrategy};
+pub use traits::{
+    DecodeFailure, DecodeMode, KeyDecode, KeyEncode, Stated, StatelessStore, Store, TypedRange,
+};
 pub use util::Prefix;
 
+pub mod indexed;
+pub mod module;
+pub mod snapshot_map;
 pub mod traits;
 mod util;
 
@@ -39,6 +48,42 @@ impl<'a, D: MerkleDB> PrefixedStore<'a, D> {
             state,
         }
     }
+
+    /// Store under a raw byte prefix, e.g. one shared by several modules of a `FinDB`
+    pub fn with_prefix(prefix: Prefix, state: &'a mut State<D>) -> Self {
+        PrefixedStore { pfx: prefix, state }
+    }
+
+    /// Full store key of `key` in this store
+    ///
+    /// "prefix" and "key" ==> "prefix_key"
+    pub fn scoped_key(&self, key: &[u8]) -> Vec<u8> {
+        self.pfx.push(key).as_ref().to_vec()
+    }
+
+    /// get value of `key` in this store. Returns None if deleted
+    pub fn get_scoped(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get(&self.scoped_key(key))
+    }
+
+    /// put/update `key` in this store
+    pub fn put_scoped(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let key = self.scoped_key(key);
+        self.set(&key, value)
+    }
+
+    /// delete `key` in this store. Nothing happens if key not found
+    pub fn delete_scoped(&mut self, key: &[u8]) -> Result<()> {
+        let key = self.scoped_key(key);
+        self.delete(&key)
+    }
+
+    /// iterate db AND cache combined over this store, keys stripped of the prefix
+    pub fn iter_scoped(&self) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> {
+        let begin = self.pfx.begin().len();
+        self.iter_cur(self.pfx.clone())
+            .map(move |(k, v)| (k[begin..].to_vec(), v))
+    }
 }
 
 /// Merkle-based prefixed store
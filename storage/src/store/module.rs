@@ -0,0 +1,328 @@
+/// Typed storage primitives of a module
+///
+/// A `Module` owns a key prefix and hands out typed maps, items, sequences and queues under it, so
+/// application code reads and writes objects instead of assembling raw keys:
+///
+/// ```ignore
+/// const BANK: Module = Module::new("bank");
+/// let balances = BANK.map::<String, u64>("balances");
+/// balances.save(&mut state, &"alice".to_string(), &10)?;
+/// ```
+///
+/// Keys are laid out as `<module>_<name>_<key>` and values are stored as json like the rest of
+/// the stores, so existing data can be read through the facade.
+///
+use crate::{
+    db::{IterOrder, MerkleDB},
+    state::{KVecMap, State},
+    store::{DecodeMode, KeyDecode, KeyEncode, Prefix, PrefixedStore, StatelessStore, TypedRange},
+};
+use ruc::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+/// Storage namespace of a module
+#[derive(Clone, Copy, Debug)]
+pub struct Module {
+    name: &'static str,
+}
+
+impl Module {
+    pub const fn new(name: &'static str) -> Self {
+        Module { name }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn prefix(&self) -> Prefix {
+        Prefix::new(self.name.as_bytes())
+    }
+
+    /// Raw store over the whole module
+    pub fn store<'a, D: MerkleDB>(&self, state: &'a mut State<D>) -> PrefixedStore<'a, D> {
+        PrefixedStore::new(self.name, state)
+    }
+
+    pub fn map<K, V>(&self, name: &str) -> Map<K, V> {
+        Map {
+            pfx: self.prefix().push(name.as_bytes()),
+            _types: PhantomData,
+        }
+    }
+
+    pub fn item<V>(&self, name: &str) -> Item<V> {
+        Item {
+            key: self.prefix().push(name.as_bytes()).as_ref().to_vec(),
+            _type: PhantomData,
+        }
+    }
+
+    pub fn seq(&self, name: &str) -> Seq {
+        Seq {
+            item: self.item(name),
+        }
+    }
+
+    pub fn queue<V>(&self, name: &str) -> Queue<V> {
+        Queue {
+            cursors: self.item(name),
+            entries: self.map(name),
+        }
+    }
+}
+
+/// Typed map of a module
+///
+/// As in every prefixed store, `range` only covers keys whose encoding sorts below `~`.
+pub struct Map<K, V> {
+    pfx: Prefix,
+    _types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for Map<K, V> {
+    fn clone(&self) -> Self {
+        Map {
+            pfx: self.pfx.clone(),
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<K, V> StatelessStore for Map<K, V> {}
+
+impl<K, V> Map<K, V>
+where
+    K: KeyEncode + KeyDecode,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn prefix(&self) -> Prefix {
+        self.pfx.clone()
+    }
+
+    /// Store key of `key`
+    pub fn key(&self, key: &K) -> Vec<u8> {
+        self.pfx.push(&key.encode_key()).as_ref().to_vec()
+    }
+
+    pub fn get<D: MerkleDB>(&self, state: &State<D>, key: &K) -> Result<Option<V>> {
+        Self::get_obj(state, &self.key(key))
+    }
+
+    /// Like `get`, failing if `key` doesn't exist
+    pub fn load<D: MerkleDB>(&self, state: &State<D>, key: &K) -> Result<V> {
+        self.get(state, key)
+            .c(d!())?
+            .c(d!(format!("{} not found", self.pfx.to_string())))
+    }
+
+    pub fn has<D: MerkleDB>(&self, state: &State<D>, key: &K) -> Result<bool> {
+        Self::exists(state, &self.key(key))
+    }
+
+    pub fn save<D: MerkleDB>(&self, state: &mut State<D>, key: &K, value: &V) -> Result<()> {
+        Self::set_obj(state, &self.key(key), value)
+    }
+
+    pub fn remove<D: MerkleDB>(&self, state: &mut State<D>, key: &K) -> Result<()> {
+        Self::delete(state, &self.key(key))
+    }
+
+    /// Saves the value returned by `f` called with the current one
+    pub fn update<D, F>(&self, state: &mut State<D>, key: &K, f: F) -> Result<V>
+    where
+        D: MerkleDB,
+        F: FnOnce(Option<V>) -> Result<V>,
+    {
+        let value = f(self.get(state, key).c(d!())?).c(d!())?;
+        self.save(state, key, &value).c(d!())?;
+        Ok(value)
+    }
+
+    /// All entries, including those not committed yet, in key order
+    pub fn range<D: MerkleDB>(
+        &self,
+        state: &State<D>,
+        mode: DecodeMode,
+    ) -> Result<TypedRange<K, V>> {
+        Self::iter_obj(state, self.pfx.clone(), mode)
+    }
+}
+
+/// Single typed value of a module
+pub struct Item<V> {
+    key: Vec<u8>,
+    _type: PhantomData<fn() -> V>,
+}
+
+impl<V> Clone for Item<V> {
+    fn clone(&self) -> Self {
+        Item {
+            key: self.key.clone(),
+            _type: PhantomData,
+        }
+    }
+}
+
+impl<V> StatelessStore for Item<V> {}
+
+impl<V: Serialize + DeserializeOwned> Item<V> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn get<D: MerkleDB>(&self, state: &State<D>) -> Result<Option<V>> {
+        Self::get_obj(state, &self.key)
+    }
+
+    /// Like `get`, failing if the item doesn't exist
+    pub fn load<D: MerkleDB>(&self, state: &State<D>) -> Result<V> {
+        self.get(state).c(d!())?.c(d!(format!(
+            "{} not found",
+            String::from_utf8_lossy(&self.key)
+        )))
+    }
+
+    pub fn has<D: MerkleDB>(&self, state: &State<D>) -> Result<bool> {
+        Self::exists(state, &self.key)
+    }
+
+    pub fn save<D: MerkleDB>(&self, state: &mut State<D>, value: &V) -> Result<()> {
+        Self::set_obj(state, &self.key, value)
+    }
+
+    pub fn remove<D: MerkleDB>(&self, state: &mut State<D>) -> Result<()> {
+        Self::delete(state, &self.key)
+    }
+
+    /// Saves the value returned by `f` called with the current one
+    pub fn update<D, F>(&self, state: &mut State<D>, f: F) -> Result<V>
+    where
+        D: MerkleDB,
+        F: FnOnce(Option<V>) -> Result<V>,
+    {
+        let value = f(self.get(state).c(d!())?).c(d!())?;
+        self.save(state, &value).c(d!())?;
+        Ok(value)
+    }
+}
+
+/// Counter of a module, e.g. to allocate ids
+#[derive(Clone)]
+pub struct Seq {
+    item: Item<u64>,
+}
+
+impl Seq {
+    /// Last value handed out by `next`, 0 if none
+    pub fn current<D: MerkleDB>(&self, state: &State<D>) -> Result<u64> {
+        self.item.get(state).map(Option::unwrap_or_default)
+    }
+
+    /// Increments the counter and returns its new value, starting at 1
+    pub fn next<D: MerkleDB>(&self, state: &mut State<D>) -> Result<u64> {
+        self.item.update(state, |current| {
+            current
+                .unwrap_or_default()
+                .checked_add(1)
+                .c(d!("sequence overflow"))
+        })
+    }
+
+    pub fn set<D: MerkleDB>(&self, state: &mut State<D>, value: u64) -> Result<()> {
+        self.item.save(state, &value)
+    }
+}
+
+/// FIFO queue of a module
+///
+/// Entries get increasing indexes and are stored as `<module>_<name>_<index>`, the indexes of
+/// the first and next entry are stored as `<module>_<name>`. Indexes are never reused, so an
+/// entry popped and a new one pushed in the same block don't overwrite each other.
+pub struct Queue<V> {
+    // (head, tail), entries are in [head, tail)
+    cursors: Item<(u64, u64)>,
+    entries: Map<u64, V>,
+}
+
+impl<V> Clone for Queue<V> {
+    fn clone(&self) -> Self {
+        Queue {
+            cursors: self.cursors.clone(),
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> Queue<V> {
+    /// Appends `value` and returns its index
+    pub fn push_back<D: MerkleDB>(&self, state: &mut State<D>, value: &V) -> Result<u64> {
+        let (head, tail) = self.cursors(state).c(d!())?;
+        self.entries.save(state, &tail, value).c(d!())?;
+        let next = tail.checked_add(1).c(d!("queue index overflow"))?;
+        self.cursors.save(state, &(head, next)).c(d!())?;
+        Ok(tail)
+    }
+
+    /// Removes and returns the first entry
+    pub fn pop_front<D: MerkleDB>(&self, state: &mut State<D>) -> Result<Option<V>> {
+        let (head, tail) = self.cursors(state).c(d!())?;
+        if head == tail {
+            return Ok(None);
+        }
+        let value = self.entries.load(state, &head).c(d!())?;
+        self.entries.remove(state, &head).c(d!())?;
+        self.cursors.save(state, &(head + 1, tail)).c(d!())?;
+        Ok(Some(value))
+    }
+
+    /// Returns the first entry without removing it
+    pub fn peek<D: MerkleDB>(&self, state: &State<D>) -> Result<Option<V>> {
+        let (head, tail) = self.cursors(state).c(d!())?;
+        if head == tail {
+            return Ok(None);
+        }
+        self.entries.load(state, &head).map(Some)
+    }
+
+    pub fn len<D: MerkleDB>(&self, state: &State<D>) -> Result<u64> {
+        self.cursors(state).map(|(head, tail)| tail - head)
+    }
+
+    pub fn is_empty<D: MerkleDB>(&self, state: &State<D>) -> Result<bool> {
+        self.len(state).map(|len| len == 0)
+    }
+
+    /// Entries from the first one on, with their indexes
+    pub fn entries<D: MerkleDB>(&self, state: &State<D>) -> Result<Vec<(u64, V)>> {
+        let (head, tail) = self.cursors(state).c(d!())?;
+        (head..tail)
+            .map(|i| Ok((i, self.entries.load(state, &i).c(d!())?)))
+            .collect()
+    }
+
+    fn cursors<D: MerkleDB>(&self, state: &State<D>) -> Result<(u64, u64)> {
+        self.cursors.get(state).map(Option::unwrap_or_default)
+    }
+}
+
+// entries of the db and the cache in [lower, upper), all under `pfx`
+pub(crate) fn merged_range<D: MerkleDB>(
+    state: &State<D>,
+    pfx: &Prefix,
+    lower: &[u8],
+    upper: &[u8],
+) -> KVecMap {
+    let mut entries = KVecMap::new();
+    if lower >= upper {
+        return entries;
+    }
+    state.iterate(lower, upper, IterOrder::Asc, &mut |(k, v)| {
+        entries.insert(k, v);
+        false
+    });
+    state.iterate_cache(pfx.as_ref(), &mut entries);
+    entries.split_off(upper);
+    entries.split_off(lower)
+}
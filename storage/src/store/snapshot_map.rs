@@ -0,0 +1,201 @@
+/// Typed map keeping past values at checkpoints
+///
+/// The chain state only keeps versions within its window. A `SnapshotMap` records the value a
+/// key had before its first change since a checkpoint, so "value at height H" stays cheap
+/// to query for any checkpoint, e.g. the voting power of a validator at a proposal's height.
+/// Changelog entries are stored as `<module>_<map>.changelog_<key>_<height>` and checkpoints
+/// as `<module>_<map>.checkpoints_<height>`, keys hex encoded and heights in fixed width hex so
+/// they sort numerically.
+///
+use crate::{
+    db::MerkleDB,
+    hex,
+    state::State,
+    store::{
+        module::merged_range, DecodeMode, KeyDecode, KeyEncode, Map, Module, Prefix,
+        StatelessStore, TypedRange,
+    },
+};
+use ruc::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Heights at which a `SnapshotMap` records history
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotStrategy {
+    /// every height, any height can be queried
+    EveryHeight,
+    /// only heights added with `add_checkpoint`
+    Checkpoints,
+    /// no history, only the current values
+    Never,
+}
+
+/// Typed map of a module keeping past values
+pub struct SnapshotMap<K, V> {
+    map: Map<K, V>,
+    changelog: Prefix,
+    checkpoints: Prefix,
+    strategy: SnapshotStrategy,
+}
+
+impl Module {
+    pub fn snapshot_map<K, V>(&self, name: &str, strategy: SnapshotStrategy) -> SnapshotMap<K, V> {
+        let sub = |part: &str| self.prefix().push(format!("{}.{}", name, part).as_bytes());
+        SnapshotMap {
+            map: self.map(name),
+            changelog: sub("changelog"),
+            checkpoints: sub("checkpoints"),
+            strategy,
+        }
+    }
+}
+
+impl<K, V> StatelessStore for SnapshotMap<K, V> {}
+
+fn height_key(height: u64) -> String {
+    format!("{:016x}", height)
+}
+
+impl<K, V> SnapshotMap<K, V>
+where
+    K: KeyEncode + KeyDecode,
+    V: Serialize + DeserializeOwned,
+{
+    /// The map of the current values, for reads
+    pub fn map(&self) -> &Map<K, V> {
+        &self.map
+    }
+
+    pub fn get<D: MerkleDB>(&self, state: &State<D>, key: &K) -> Result<Option<V>> {
+        self.map.get(state, key)
+    }
+
+    pub fn load<D: MerkleDB>(&self, state: &State<D>, key: &K) -> Result<V> {
+        self.map.load(state, key)
+    }
+
+    pub fn range<D: MerkleDB>(
+        &self,
+        state: &State<D>,
+        mode: DecodeMode,
+    ) -> Result<TypedRange<K, V>> {
+        self.map.range(state, mode)
+    }
+
+    /// Marks `height` as a checkpoint, only used by `SnapshotStrategy::Checkpoints`
+    ///
+    /// A checkpoint must be added before the writes of its height.
+    pub fn add_checkpoint<D: MerkleDB>(&self, state: &mut State<D>, height: u64) -> Result<()> {
+        let key = self.checkpoints.push(height_key(height).as_bytes());
+        Self::set_obj(state, key.as_ref(), &true)
+    }
+
+    /// Stops recording at `height`, history recorded so far is kept
+    pub fn remove_checkpoint<D: MerkleDB>(&self, state: &mut State<D>, height: u64) -> Result<()> {
+        let key = self.checkpoints.push(height_key(height).as_bytes());
+        Self::delete(state, key.as_ref())
+    }
+
+    /// Whether the history of `height` is recorded
+    pub fn is_checkpoint<D: MerkleDB>(&self, state: &State<D>, height: u64) -> Result<bool> {
+        match self.strategy {
+            SnapshotStrategy::EveryHeight => Ok(true),
+            SnapshotStrategy::Never => Ok(false),
+            SnapshotStrategy::Checkpoints => {
+                let key = self.checkpoints.push(height_key(height).as_bytes());
+                Self::exists(state, key.as_ref())
+            }
+        }
+    }
+
+    /// Saves `value` as written at `height`, usually the height being executed
+    pub fn save<D: MerkleDB>(
+        &self,
+        state: &mut State<D>,
+        key: &K,
+        value: &V,
+        height: u64,
+    ) -> Result<()> {
+        self.record(state, key, height).c(d!())?;
+        self.map.save(state, key, value)
+    }
+
+    /// Removes `key` as written at `height`
+    pub fn remove<D: MerkleDB>(&self, state: &mut State<D>, key: &K, height: u64) -> Result<()> {
+        self.record(state, key, height).c(d!())?;
+        self.map.remove(state, key)
+    }
+
+    /// Value of `key` at the beginning of `height`, before the writes of that height
+    ///
+    /// Fails if the history of `height` isn't recorded.
+    pub fn get_at_height<D: MerkleDB>(
+        &self,
+        state: &State<D>,
+        key: &K,
+        height: u64,
+    ) -> Result<Option<V>> {
+        if !self.is_checkpoint(state, height).c(d!())? {
+            return Err(eg!(format!("height {} is not a checkpoint", height)));
+        }
+        // the first change at or after `height` holds the value before it
+        let pfx = self.key_changelog(key);
+        let mut lower = pfx.begin();
+        lower.extend_from_slice(height_key(height).as_bytes());
+        match merged_range(state, &pfx, &lower, &pfx.end())
+            .values()
+            .next()
+        {
+            Some(old) => serde_json::from_slice(old).c(d!()),
+            None => self.map.get(state, key),
+        }
+    }
+
+    fn key_changelog(&self, key: &K) -> Prefix {
+        self.changelog
+            .push(hex::encode(&key.encode_key()).as_bytes())
+    }
+
+    // latest checkpoint at or before `height`
+    fn last_checkpoint<D: MerkleDB>(&self, state: &State<D>, height: u64) -> Result<Option<u64>> {
+        let pfx = &self.checkpoints;
+        let mut upper = pfx.begin();
+        upper.extend_from_slice(height_key(height.saturating_add(1)).as_bytes());
+        match merged_range(state, pfx, &pfx.begin(), &upper)
+            .keys()
+            .next_back()
+        {
+            Some(k) => {
+                let hex = std::str::from_utf8(&k[pfx.begin().len()..]).c(d!())?;
+                u64::from_str_radix(hex, 16)
+                    .map(Some)
+                    .c(d!("invalid checkpoint"))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // records the value before the first write since the latest checkpoint
+    //
+    // Writes after a checkpoint but at a later height are recorded too, so a query at the
+    // checkpoint doesn't see a change made between two checkpoints.
+    fn record<D: MerkleDB>(&self, state: &mut State<D>, key: &K, height: u64) -> Result<()> {
+        let since = match self.strategy {
+            SnapshotStrategy::EveryHeight => height,
+            SnapshotStrategy::Never => return Ok(()),
+            SnapshotStrategy::Checkpoints => match self.last_checkpoint(state, height).c(d!())? {
+                Some(checkpoint) => checkpoint,
+                None => return Ok(()),
+            },
+        };
+        let pfx = self.key_changelog(key);
+        let mut lower = pfx.begin();
+        lower.extend_from_slice(height_key(since).as_bytes());
+        if !merged_range(state, &pfx, &lower, &pfx.end()).is_empty() {
+            return Ok(());
+        }
+        let old = self.map.get(state, key).c(d!())?;
+        let entry = pfx.push(height_key(height).as_bytes());
+        Self::set_obj(state, entry.as_ref(), &old)
+    }
+}
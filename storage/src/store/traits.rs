@@ -5,6 +5,114 @@ use ruc::*;
 use serde::{de, Serialize};
 use std::collections::btree_map::IntoIter;
 
+/// How typed iteration deals with KV pairs that fail to decode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeMode {
+    /// silently skip bad pairs
+    Skip,
+    /// stop and return an error on the first bad pair
+    FailFast,
+    /// keep going and collect every failure in `TypedRange::failures`
+    Collect,
+}
+
+/// A KV pair which could not be decoded
+#[derive(Clone, Debug)]
+pub struct DecodeFailure {
+    pub key: Vec<u8>,
+    pub error: String,
+}
+
+/// Result of a typed range iteration
+#[derive(Debug)]
+pub struct TypedRange<K, V> {
+    pub items: Vec<(K, V)>,
+    pub failures: Vec<DecodeFailure>,
+}
+
+/// Decodes the part of a store key following the prefix into a typed key
+pub trait KeyDecode: Sized {
+    fn decode_key(raw: &[u8]) -> Result<Self>;
+}
+
+impl KeyDecode for Vec<u8> {
+    fn decode_key(raw: &[u8]) -> Result<Self> {
+        Ok(raw.to_vec())
+    }
+}
+
+impl KeyDecode for String {
+    fn decode_key(raw: &[u8]) -> Result<Self> {
+        String::from_utf8(raw.to_vec()).c(d!("key is not a valid utf8 string"))
+    }
+}
+
+impl KeyDecode for u64 {
+    fn decode_key(raw: &[u8]) -> Result<Self> {
+        let key = std::str::from_utf8(raw).c(d!("key is not a valid utf8 string"))?;
+        key.parse::<u64>().c(d!("key is not a valid u64"))
+    }
+}
+
+/// Encodes a typed key into the part of a store key following the prefix, see `KeyDecode`
+pub trait KeyEncode {
+    fn encode_key(&self) -> Vec<u8>;
+}
+
+impl KeyEncode for Vec<u8> {
+    fn encode_key(&self) -> Vec<u8> {
+        self.clone()
+    }
+}
+
+impl KeyEncode for String {
+    fn encode_key(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl KeyEncode for u64 {
+    fn encode_key(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+/// Decodes raw KV pairs under `prefix` into typed pairs
+fn decode_range<K, V, I>(kvs: I, prefix: &Prefix, mode: DecodeMode) -> Result<TypedRange<K, V>>
+where
+    K: KeyDecode,
+    V: de::DeserializeOwned,
+    I: IntoIterator<Item = KValue>,
+{
+    let begin = prefix.begin();
+    let mut range = TypedRange {
+        items: vec![],
+        failures: vec![],
+    };
+    for (k, v) in kvs {
+        let decoded = k
+            .strip_prefix(begin.as_slice())
+            .c(d!("key out of prefix"))
+            .and_then(K::decode_key)
+            .and_then(|key| {
+                let obj = serde_json::from_slice::<V>(&v).c(d!())?;
+                Ok((key, obj))
+            });
+        match decoded {
+            Ok(item) => range.items.push(item),
+            Err(e) => match mode {
+                DecodeMode::Skip => {}
+                DecodeMode::FailFast => return Err(e).c(d!("failed to decode kv pair")),
+                DecodeMode::Collect => range.failures.push(DecodeFailure {
+                    key: k,
+                    error: e.to_string(),
+                }),
+            },
+        }
+    }
+    Ok(range)
+}
+
 /// statable
 pub trait Stated<'a, D: MerkleDB> {
     /// set state
@@ -133,10 +241,26 @@ where
         );
 
         // Iterate cache
-        self.state().iterate_cache(prefix.as_ref(), &mut kv_map);
+        self.state().iterate_cache(&prefix.begin(), &mut kv_map);
         kv_map.into_iter()
     }
 
+    /// greatest `n` keys under `prefix` in db AND cache combined, greatest first
+    fn last_n_under_prefix(&self, prefix: Prefix, n: usize) -> Vec<KValue> {
+        self.state().last_n_under_prefix(&prefix, n)
+    }
+
+    /// iterate db AND cache combined, decoding keys and values into typed pairs
+    ///
+    /// keys are decoded from the part following `prefix`, values are deserialized from json
+    fn iter_obj<K, V>(&self, prefix: Prefix, mode: DecodeMode) -> Result<TypedRange<K, V>>
+    where
+        K: KeyDecode,
+        V: de::DeserializeOwned,
+    {
+        decode_range(self.iter_cur(prefix.clone()), &prefix, mode)
+    }
+
     /// key exists or not. Returns false if deleted
     fn exists(&self, key: &[u8]) -> Result<bool> {
         self.state().exists(key)
@@ -296,10 +420,31 @@ pub trait StatelessStore {
         );
 
         // Iterate cache
-        state.iterate_cache(prefix.as_ref(), &mut kv_map);
+        state.iterate_cache(&prefix.begin(), &mut kv_map);
         kv_map.into_iter()
     }
 
+    /// greatest `n` keys under `prefix` in db AND cache combined, greatest first
+    fn last_n_under_prefix<D: MerkleDB>(state: &State<D>, prefix: Prefix, n: usize) -> Vec<KValue> {
+        state.last_n_under_prefix(&prefix, n)
+    }
+
+    /// iterate db AND cache combined, decoding keys and values into typed pairs
+    ///
+    /// keys are decoded from the part following `prefix`, values are deserialized from json
+    fn iter_obj<K, V, D>(
+        state: &State<D>,
+        prefix: Prefix,
+        mode: DecodeMode,
+    ) -> Result<TypedRange<K, V>>
+    where
+        K: KeyDecode,
+        V: de::DeserializeOwned,
+        D: MerkleDB,
+    {
+        decode_range(Self::iter_cur(state, prefix.clone()), &prefix, mode)
+    }
+
     /// key exists or not. Returns false if deleted
     fn exists<D: MerkleDB>(state: &State<D>, key: &[u8]) -> Result<bool> {
         state.exists(key)
@@ -0,0 +1,368 @@
+/// Deterministic fixtures for tests and benchmarks
+///
+/// `populate` fills a db with pseudo random state derived from a seed only, so every run and
+/// every backend gets byte-identical data without checking large fixture files in.
+///
+/// `KeyAccess` draws the keys read or written by a benchmark from a `KeySpace` as per an
+/// `AccessDist`, e.g. Zipfian accesses to keys clustered under module prefixes. Uniformly
+/// random keys overstate the hit rate of caches on spread out hot data and understate the
+/// compaction of the levels holding dense prefixes.
+///
+use crate::{
+    db::{KVBatch, MerkleDB},
+    simulate::{zeta, AccessDist, ValueSizeDist},
+};
+use ruc::*;
+
+/// number of entries committed at once by `populate`
+const POPULATE_BATCH_SIZE: u64 = 10_000;
+
+/// size of generated keys
+pub const FIXTURE_KEY_SIZE: usize = 32;
+
+// highest skew of the Zipfian sampler, its formula diverges at 1
+const MAX_ZIPF_THETA: f64 = 0.999;
+
+/// SplitMix64 generator
+///
+/// Its output is fixed by its definition, unlike the generators of `rand` which may change
+/// between releases, so fixtures stay the same across dependency upgrades.
+#[derive(Clone, Debug)]
+pub struct FixtureRng {
+    state: u64,
+}
+
+impl FixtureRng {
+    pub fn new(seed: u64) -> Self {
+        FixtureRng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `[min, max]`
+    pub fn gen_range(&mut self, min: u64, max: u64) -> u64 {
+        if max <= min {
+            return min;
+        }
+        match (max - min).checked_add(1) {
+            Some(span) => min + self.next_u64() % span,
+            None => self.next_u64(),
+        }
+    }
+
+    /// Returns a number in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    /// Draws a value size from `dist`
+    pub fn value_size(&mut self, dist: &ValueSizeDist) -> usize {
+        let size = match *dist {
+            ValueSizeDist::Fixed(size) => size,
+            ValueSizeDist::Uniform { min, max } => self.gen_range(min, max),
+            ValueSizeDist::Bimodal {
+                small,
+                large,
+                large_share,
+            } => {
+                if self.next_f64() < large_share {
+                    large
+                } else {
+                    small
+                }
+            }
+        };
+        size as usize
+    }
+}
+
+/// How the keys of a `KeySpace` are laid out
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeyLayout {
+    /// keys are spread over the whole key space, like hashed keys
+    #[default]
+    Random,
+    /// keys start with one of `clusters` random prefixes of `prefix_len` bytes, like the
+    /// module prefixes of a chain state; consecutive key indexes share their prefix
+    Clustered { clusters: u64, prefix_len: usize },
+}
+
+/// The keys of indexes `0..n_keys` derived from a seed
+#[derive(Clone, Debug)]
+pub struct KeySpace {
+    seed: u64,
+    n_keys: u64,
+    layout: KeyLayout,
+}
+
+impl KeySpace {
+    /// A key space of at least one key
+    pub fn new(seed: u64, n_keys: u64, layout: KeyLayout) -> Self {
+        KeySpace {
+            seed,
+            n_keys: n_keys.max(1),
+            layout,
+        }
+    }
+
+    pub fn n_keys(&self) -> u64 {
+        self.n_keys
+    }
+
+    /// Key of `index`, `FIXTURE_KEY_SIZE` bytes
+    pub fn key(&self, index: u64) -> Vec<u8> {
+        let mut key = vec![0; FIXTURE_KEY_SIZE];
+        let stream = index.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        FixtureRng::new(self.seed ^ stream).fill_bytes(&mut key);
+        if let KeyLayout::Clustered {
+            clusters,
+            prefix_len,
+        } = self.layout
+        {
+            let clusters = clusters.max(1);
+            let per_cluster = self.n_keys.saturating_add(clusters - 1) / clusters;
+            let cluster = index / per_cluster;
+            let prefix = &mut key[..prefix_len.min(FIXTURE_KEY_SIZE)];
+            FixtureRng::new(!self.seed ^ cluster.wrapping_mul(0xBF58_476D_1CE4_E5B9))
+                .fill_bytes(prefix);
+        }
+        key
+    }
+}
+
+/// Keys drawn from a `KeySpace` as per an `AccessDist`, e.g. the reads of a benchmark
+///
+/// The same seed draws the same keys. The ranks of a Zipfian distribution are shuffled over
+/// the key indexes, so the hot keys are spread over the clusters of a clustered layout.
+#[derive(Clone, Debug)]
+pub struct KeyAccess {
+    rng: FixtureRng,
+    space: KeySpace,
+    zipf: Option<ZipfSampler>,
+    // the index of rank `r` is `(r * mul + add) % n_keys`, `mul` coprime with `n_keys`
+    mul: u64,
+    add: u64,
+}
+
+impl KeyAccess {
+    pub fn new(seed: u64, space: KeySpace, dist: &AccessDist) -> Self {
+        let mut rng = FixtureRng::new(seed);
+        let len = space.n_keys();
+        let mut mul = rng.gen_range(1, len) | 1;
+        while gcd(mul, len) != 1 {
+            mul = mul.wrapping_add(2) % len;
+        }
+        let add = rng.gen_range(0, len - 1);
+        let zipf = match *dist {
+            AccessDist::Uniform => None,
+            AccessDist::Zipfian { theta } => Some(ZipfSampler::new(len, theta)),
+        };
+        KeyAccess {
+            rng,
+            space,
+            zipf,
+            mul,
+            add,
+        }
+    }
+
+    /// Index of the next accessed key
+    pub fn next_index(&mut self) -> u64 {
+        let len = self.space.n_keys();
+        let rank = match self.zipf.as_ref() {
+            Some(zipf) => zipf.rank(self.rng.next_f64()),
+            None => self.rng.gen_range(0, len - 1),
+        };
+        let index = u128::from(rank) * u128::from(self.mul) + u128::from(self.add);
+        (index % u128::from(len)) as u64
+    }
+}
+
+impl Iterator for KeyAccess {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let index = self.next_index();
+        Some(self.space.key(index))
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+// Zipfian ranks in `0..len` by the method of Gray et al., "Quickly generating billion-record
+// synthetic databases", as used by YCSB
+#[derive(Clone, Debug)]
+struct ZipfSampler {
+    len: f64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl ZipfSampler {
+    fn new(len: u64, theta: f64) -> Self {
+        let len = len as f64;
+        let theta = theta.clamp(0.0, MAX_ZIPF_THETA);
+        let zetan = zeta(len, theta);
+        let zeta2 = zeta(2.0_f64.min(len), theta);
+        ZipfSampler {
+            len,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / len).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    // rank of the uniform draw `u` in `[0, 1)`
+    fn rank(&self, u: f64) -> u64 {
+        let uz = u * self.zetan;
+        if uz < 1.0 || self.len < 2.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5_f64.powf(self.theta) {
+            return 1;
+        }
+        let rank = self.len * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (rank as u64).min(self.len as u64 - 1)
+    }
+}
+
+/// Generates the `n_keys` entries of the fixture of `seed`
+///
+/// Keys are random, i.e. spread over the whole key space, and unique with overwhelming
+/// probability.
+pub fn fixture(
+    seed: u64,
+    n_keys: u64,
+    value_size: &ValueSizeDist,
+) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> {
+    let mut rng = FixtureRng::new(seed);
+    let value_size = value_size.clone();
+    (0..n_keys).map(move |_| {
+        let mut key = vec![0; FIXTURE_KEY_SIZE];
+        rng.fill_bytes(&mut key);
+        let mut value = vec![0; rng.value_size(&value_size)];
+        rng.fill_bytes(&mut value);
+        (key, value)
+    })
+}
+
+/// Fills `db` with the fixture of `seed` and commits it
+///
+/// The same arguments produce the same state on every run and backend, and so the same root
+/// hash on backends sharing a merkle tree.
+pub fn populate<D: MerkleDB>(
+    db: &mut D,
+    seed: u64,
+    n_keys: u64,
+    value_size: &ValueSizeDist,
+) -> Result<()> {
+    let mut batch = KVBatch::new();
+    let mut entries = fixture(seed, n_keys, value_size).peekable();
+    while let Some((key, value)) = entries.next() {
+        batch.push((key, Some(value)));
+        if batch.len() as u64 >= POPULATE_BATCH_SIZE || entries.peek().is_none() {
+            // merkle backends only take sorted batches
+            batch.sort_by(|a, b| a.0.cmp(&b.0));
+            batch.dedup_by(|a, b| a.0 == b.0);
+            db.put_batch(std::mem::take(&mut batch)).c(d!())?;
+        }
+    }
+    db.commit(vec![], true).c(d!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fixture, FixtureRng, KeyAccess, KeyLayout, KeySpace};
+    use crate::simulate::{AccessDist, ValueSizeDist};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn fixture_rng_is_stable() {
+        // reference output of SplitMix64 seeded with 0
+        let mut rng = FixtureRng::new(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+    }
+
+    #[test]
+    fn fixture_is_deterministic() {
+        let dist = ValueSizeDist::Uniform { min: 1, max: 64 };
+        let a: Vec<_> = fixture(7, 100, &dist).collect();
+        let b: Vec<_> = fixture(7, 100, &dist).collect();
+        let c: Vec<_> = fixture(8, 100, &dist).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a
+            .iter()
+            .all(|(k, v)| k.len() == 32 && (1..=64).contains(&v.len())));
+    }
+
+    #[test]
+    fn key_access_distributions() {
+        let space = KeySpace::new(3, 1000, KeyLayout::Random);
+        let draws = |dist: &AccessDist| {
+            let mut counts = BTreeMap::new();
+            let mut access = KeyAccess::new(5, space.clone(), dist);
+            for _ in 0..10_000 {
+                *counts.entry(access.next_index()).or_insert(0u64) += 1;
+            }
+            counts
+        };
+
+        // uniform draws hit every key about as often, Zipfian ones favour a few hot keys
+        let uniform = draws(&AccessDist::Uniform);
+        assert!(uniform.len() > 990 && uniform.values().all(|n| *n < 40));
+        let dist = AccessDist::Zipfian { theta: 0.99 };
+        let zipfian = draws(&dist);
+        assert!(*zipfian.values().max().unwrap() > 1000);
+        assert!(zipfian.keys().all(|i| *i < 1000));
+        assert_eq!(zipfian, draws(&dist));
+        // as many distinct keys as the simulator expects
+        let expected = dist.expected_distinct(1000.0, 10_000.0);
+        assert!((zipfian.len() as f64 - expected).abs() < expected * 0.03);
+
+        // the hottest keys aren't neighbours
+        let mut hot = zipfian.iter().collect::<Vec<_>>();
+        hot.sort_by(|a, b| b.1.cmp(a.1));
+        assert!(hot[..3].windows(2).all(|w| w[0].0.abs_diff(*w[1].0) > 1));
+    }
+
+    #[test]
+    fn key_space_layouts() {
+        let random = KeySpace::new(3, 100, KeyLayout::Random);
+        assert_eq!(random.key(7), random.key(7));
+        assert_ne!(random.key(7), random.key(8));
+        assert_eq!(random.key(7).len(), 32);
+
+        let layout = KeyLayout::Clustered {
+            clusters: 4,
+            prefix_len: 2,
+        };
+        let clustered = KeySpace::new(3, 100, layout);
+        let (a, b, c) = (clustered.key(0), clustered.key(24), clustered.key(25));
+        assert_eq!(a[..2], b[..2]);
+        assert_ne!(a[..2], c[..2]);
+        assert_ne!(a[2..], b[2..]);
+    }
+}
@@ -0,0 +1,350 @@
+/// Live iterator tracking and leak detection
+///
+/// Every iterator handed out by a `TrackedDB` is registered until it's dropped. Iterators pin
+/// a snapshot of the backend, e.g. RocksDB keeps the files of obsolete versions on disk while
+/// an iterator is alive, so leaked ones are reported when they outlive a commit or are held
+/// longer than a threshold, and the number of open iterators can be capped.
+///
+use crate::{
+    db::{
+        DbIter, DbTryIter, IterOpts, IterOrder, KVBatch, KVEntryRef, KValue, MerkleDB, ScanControl,
+    },
+    merge::MergeOp,
+    snapshot::SnapshotOptions,
+};
+use parking_lot::Mutex;
+use ruc::*;
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Limits of a `TrackedDB`
+#[derive(Clone, Debug, Default)]
+pub struct IterLimits {
+    /// Max number of open iterators, `None` for no limit
+    pub max_open: Option<usize>,
+    /// Iterators open longer than this are reported as leaked
+    pub max_age: Option<Duration>,
+    /// Debug builds panic on a violation instead of only logging it
+    pub debug_assert: bool,
+}
+
+/// A live iterator reported as leaked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeakedIter {
+    pub id: u64,
+    pub age: Duration,
+    /// number of commits done since the iterator was opened
+    pub commits_outlived: u64,
+}
+
+/// Iterator counters of a `TrackedDB`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IterStats {
+    pub open: usize,
+    pub peak: usize,
+    pub opened: u64,
+    /// number of iterators refused or reported over the limit
+    pub over_limit: u64,
+}
+
+struct IterInfo {
+    opened_at: Instant,
+    epoch: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    live: BTreeMap<u64, IterInfo>,
+    next_id: u64,
+    // number of commits so far
+    epoch: u64,
+    stats: IterStats,
+}
+
+// unregisters its iterator when dropped
+struct TrackedIter<'a, T> {
+    inner: Box<dyn Iterator<Item = T> + 'a>,
+    id: u64,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl<T> Iterator for TrackedIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl<T> Drop for TrackedIter<'_, T> {
+    fn drop(&mut self) {
+        let mut registry = self.registry.lock();
+        registry.live.remove(&self.id);
+        registry.stats.open = registry.live.len();
+    }
+}
+
+/// MerkleDB wrapper tracking the iterators it hands out
+pub struct TrackedDB<D: MerkleDB> {
+    db: D,
+    limits: IterLimits,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl<D: MerkleDB> TrackedDB<D> {
+    pub fn new(db: D, limits: IterLimits) -> Self {
+        TrackedDB {
+            db,
+            limits,
+            registry: Arc::new(Mutex::new(Registry::default())),
+        }
+    }
+
+    pub fn stats(&self) -> IterStats {
+        self.registry.lock().stats.clone()
+    }
+
+    /// Returns the live iterators which outlived a commit or are older than `max_age`
+    pub fn leaked(&self) -> Vec<LeakedIter> {
+        let registry = self.registry.lock();
+        let now = Instant::now();
+        registry
+            .live
+            .iter()
+            .map(|(&id, info)| LeakedIter {
+                id,
+                age: now.duration_since(info.opened_at),
+                commits_outlived: registry.epoch - info.epoch,
+            })
+            .filter(|leak| {
+                leak.commits_outlived > 0 || self.limits.max_age.is_some_and(|max| leak.age > max)
+            })
+            .collect()
+    }
+
+    /// Range iterator failing once `max_open` iterators are open
+    pub fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> Result<DbIter<'_>> {
+        if let Some(max) = self.limits.max_open {
+            let mut registry = self.registry.lock();
+            if registry.live.len() >= max {
+                registry.stats.over_limit += 1;
+                return Err(eg!(format!("too many open iterators, max {}", max)));
+            }
+        }
+        Ok(self.track(self.db.iter(lower, upper, order)))
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+
+    // pairs or results of pairs, see `DbTryIter`
+    fn track<'a, T: 'a>(
+        &'a self,
+        inner: Box<dyn Iterator<Item = T> + 'a>,
+    ) -> Box<dyn Iterator<Item = T> + 'a> {
+        Box::new(self.register(inner))
+    }
+
+    // an iterator the backend opens and drops within a call, e.g. the one of a scan
+    fn register_call(&self) -> TrackedIter<'static, ()> {
+        self.register(Box::new(std::iter::empty()))
+    }
+
+    fn register<'a, T>(&self, inner: Box<dyn Iterator<Item = T> + 'a>) -> TrackedIter<'a, T> {
+        let id = {
+            let mut registry = self.registry.lock();
+            let id = registry.next_id;
+            registry.next_id += 1;
+            let epoch = registry.epoch;
+            registry.live.insert(
+                id,
+                IterInfo {
+                    opened_at: Instant::now(),
+                    epoch,
+                },
+            );
+            let stats = &mut registry.stats;
+            stats.opened += 1;
+            stats.open += 1;
+            stats.peak = stats.peak.max(stats.open);
+            if self.limits.max_open.is_some_and(|max| stats.open > max) {
+                stats.over_limit += 1;
+                let open = stats.open;
+                drop(registry);
+                self.violation(&format!("{} iterators open", open));
+            }
+            id
+        };
+        TrackedIter {
+            inner,
+            id,
+            registry: self.registry.clone(),
+        }
+    }
+
+    fn violation(&self, msg: &str) {
+        println!("TrackedDB: {}", msg);
+        if self.limits.debug_assert {
+            debug_assert!(false, "TrackedDB: {}", msg);
+        }
+    }
+
+    fn report_leaks(&self) {
+        let leaked = self.leaked();
+        if !leaked.is_empty() {
+            self.violation(&format!("leaked iterators {:?}", leaked));
+        }
+    }
+}
+
+impl<D: MerkleDB> MerkleDB for TrackedDB<D> {
+    fn root_hash(&self) -> Vec<u8> {
+        self.db.root_hash()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get(key)
+    }
+
+    fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_aux(key)
+    }
+
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        self.db.get_into(key, buf)
+    }
+
+    fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
+        self.db.put_batch(kvs)
+    }
+
+    fn put_batch_ref(&mut self, kvs: &[KVEntryRef<'_>]) -> Result<()> {
+        self.db.put_batch_ref(kvs)
+    }
+
+    fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.track(self.db.iter(lower, upper, order))
+    }
+
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbIter<'_> {
+        self.track(self.db.iter_with_opts(lower, upper, order, opts))
+    }
+
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        self.track(self.db.iter_bounds(lower, upper, order))
+    }
+
+    fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        let _scan = self.register_call();
+        self.db.scan_apply(lower, upper, order, f)
+    }
+
+    fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.track(self.db.iter_aux(lower, upper, order))
+    }
+
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.track(self.db.try_iter(lower, upper, order))
+    }
+
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.track(self.db.try_iter_aux(lower, upper, order))
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        self.track(self.db.db_all_iterator(order))
+    }
+
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        self.track(self.db.db_all_iterator_aux(order))
+    }
+
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.track(self.db.try_db_all_iterator(order))
+    }
+
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.track(self.db.try_db_all_iterator_aux(order))
+    }
+
+    fn has_proofs(&self) -> bool {
+        self.db.has_proofs()
+    }
+
+    fn prove_keys(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        self.db.prove_keys(keys)
+    }
+
+    fn prove_range(&self, lower: &[u8], upper: &[u8]) -> Result<Vec<u8>> {
+        self.db.prove_range(lower, upper)
+    }
+
+    fn verify_entry(&self, kv_pair: &(Box<[u8]>, Box<[u8]>)) -> Result<()> {
+        self.db.verify_entry(kv_pair)
+    }
+
+    fn compact_range(&self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        self.db.compact_range(lower, upper)
+    }
+
+    /// Backends without range deletes iterate the range, it's tracked like a scan
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        let _scan = self.register_call();
+        self.db.delete_range(lower, upper)
+    }
+
+    fn merge(&mut self, key: &[u8], patch: &[u8], op: MergeOp) -> Result<()> {
+        self.db.merge(key, patch, op)
+    }
+
+    /// Commits and reports iterators which are still open from before
+    fn commit(&mut self, kvs: KVBatch, flush: bool) -> Result<()> {
+        self.report_leaks();
+        self.db.commit(kvs, flush).c(d!())?;
+        self.registry.lock().epoch += 1;
+        Ok(())
+    }
+
+    fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.db.snapshot(path)
+    }
+
+    fn snapshot_with_opts<P: AsRef<Path>>(&self, path: P, opts: &SnapshotOptions) -> Result<()> {
+        self.db.snapshot_with_opts(path, opts)
+    }
+
+    fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue {
+        self.db.decode_kv(kv_pair)
+    }
+
+    fn clean_aux(&mut self) -> Result<()> {
+        self.db.clean_aux()
+    }
+}
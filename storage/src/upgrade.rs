@@ -0,0 +1,112 @@
+/// Open-time upgrade dry run
+///
+/// Opening a data directory written by an older version migrates its layout, see `layout`,
+/// and opening the chain state upgrades and cleans the version index. `plan_upgrade` lists
+/// the steps an open would run without writing anything, with the number of entries each
+/// one touches, so operators can estimate the downtime of an upgrade beforehand.
+///
+/// The layout is inspected from the path alone, the chain state steps are added by
+/// `UpgradeReport::plan_chain_state` with the backend db opened read-only, e.g.
+/// `FinDB::plan_upgrade`. Opening runs no compaction, tombstones are compacted by commits.
+///
+use crate::{
+    db::MerkleDB,
+    height::Version,
+    layout::{DataLayout, StorageHeader, CRATE_VERSION, LAYOUT_VERSION},
+    state::{ChainState, ChainStateOpts},
+};
+use ruc::*;
+use std::path::{Path, PathBuf};
+
+/// A step run when a data directory is opened
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpgradeStep {
+    /// the data directory is migrated from one layout version to the next
+    MigrateLayout { from: u32, to: u32 },
+    /// the metadata header is rewritten with the current crate version
+    UpdateHeader { from: Option<String>, to: String },
+    /// the aux data is cleaned and every key is copied to the base, see
+    /// `ChainStateOpts::cleanup_aux`
+    CleanupAux { keys: u64 },
+    /// an interrupted reindex is run again, see `ChainState::reindex`
+    ResumeReindex { keys: u64 },
+    /// the version index is converted to the current aux version
+    UpgradeAux { from: Version, to: Version },
+    /// versions of the heights in `from..to` are moved to the base and removed
+    PruneVersions { from: u64, to: u64, entries: u64 },
+    /// the snapshots of the version index are rebuilt for another interval
+    RebuildSnapshots {
+        from_interval: u64,
+        to_interval: u64,
+        snapshots: u64,
+    },
+}
+
+/// Steps an open of a data directory would run, see `plan_upgrade`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpgradeReport {
+    pub path: PathBuf,
+    /// layout version found on disk
+    pub layout_version: u32,
+    pub header: StorageHeader,
+    /// directory of the backend db before the layout is migrated
+    pub db_dir: PathBuf,
+    /// aux version of the chain state, `None` before `plan_chain_state`
+    pub aux_version: Option<Version>,
+    /// committed height of the chain state
+    pub height: u64,
+    pub steps: Vec<UpgradeStep>,
+}
+
+impl UpgradeReport {
+    /// Whether opening changes nothing but the metadata header
+    pub fn is_noop(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| matches!(step, UpgradeStep::UpdateHeader { .. }))
+    }
+
+    /// Adds the steps opening a chain state with `opts` on `db` would run
+    ///
+    /// Fails like the open would, e.g. on an unfinished restore or an unknown aux version.
+    pub fn plan_chain_state<D: MerkleDB>(&mut self, db: &D, opts: &ChainStateOpts) -> Result<()> {
+        ChainState::plan_open(db, opts, self)
+    }
+}
+
+/// Lists the layout steps opening the data directory at `path` would run
+///
+/// Nothing is created nor written, a missing or empty directory is created in the current
+/// layout.
+pub fn plan_upgrade<P: AsRef<Path>>(path: P) -> Result<UpgradeReport> {
+    let path = path.as_ref().to_path_buf();
+    let (layout_version, header) = DataLayout::inspect(&path).c(d!())?;
+    if layout_version > LAYOUT_VERSION {
+        return Err(eg!(format!(
+            "data directory layout {} is newer than the supported layout {}",
+            layout_version, LAYOUT_VERSION
+        )));
+    }
+
+    let mut steps = (layout_version..LAYOUT_VERSION)
+        .map(|from| UpgradeStep::MigrateLayout {
+            from,
+            to: from.saturating_add(1),
+        })
+        .collect::<Vec<_>>();
+    if header.written_by.as_deref() != Some(CRATE_VERSION) {
+        steps.push(UpgradeStep::UpdateHeader {
+            from: header.written_by.clone(),
+            to: CRATE_VERSION.to_owned(),
+        });
+    }
+    Ok(UpgradeReport {
+        db_dir: DataLayout::db_dir(&path, layout_version),
+        path,
+        layout_version,
+        header,
+        aux_version: None,
+        height: 0,
+        steps,
+    })
+}
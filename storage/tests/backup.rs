@@ -0,0 +1,53 @@
+use std::{env::temp_dir, time::SystemTime};
+use storage::{
+    backup::{BackupManager, BackupOutcome, BackupSchedule},
+    snapshot::list_snapshots,
+    state::ChainState,
+};
+use temp_db::TempFinDB;
+
+#[test]
+fn test_backup_schedule() {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = temp_dir().join(format!("backups-{}", time));
+    assert!(BackupSchedule::default().daily_at(24, 0).is_err());
+    let schedule = BackupSchedule::default()
+        .every_n_commits(3)
+        .daily_at(2, 0)
+        .unwrap();
+    let mut manager = BackupManager::new(&dir, schedule).unwrap();
+
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    let midnight = 19_700 * 86400;
+    let mut commit = |height: u64, write: bool, now: u64| {
+        let batch = match write {
+            true => vec![(b"k".to_vec(), Some(height.to_be_bytes().to_vec()))],
+            false => vec![],
+        };
+        chain.commit(batch, height, true).unwrap();
+        manager.on_commit_at(&chain, now).unwrap()
+    };
+    let taken = |height: u64| BackupOutcome::Taken(dir.join(format!("{:020}", height)));
+
+    assert_eq!(commit(1, true, midnight + 3600), BackupOutcome::NotDue);
+    assert_eq!(commit(2, true, midnight + 3700), BackupOutcome::NotDue);
+    assert_eq!(commit(3, true, midnight + 3800), taken(3));
+    // 02:00 passed, but nothing changed since the last snapshot
+    assert_eq!(commit(4, false, midnight + 7200), BackupOutcome::Unchanged);
+    assert_eq!(commit(5, true, midnight + 7300), BackupOutcome::NotDue);
+    assert_eq!(commit(6, true, midnight + 7400), taken(6));
+    // the next day's 02:00 passed while no commit happened
+    assert_eq!(commit(7, true, midnight + 2 * 86400), taken(7));
+
+    let snapshots = list_snapshots(&dir).unwrap();
+    assert_eq!(
+        snapshots.iter().map(|s| s.height).collect::<Vec<_>>(),
+        vec![3, 6, 7]
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,148 @@
+#![cfg(unix)]
+
+use parking_lot::RwLock;
+use std::{env::temp_dir, os::unix::net::UnixListener, path::PathBuf, sync::Arc, time::SystemTime};
+use storage::{
+    broker::{
+        Broker, BrokerClient, BrokerRequest, BrokerResponse, MAX_CONNECTIONS, MAX_PAGE_BYTES,
+    },
+    db::IterOrder,
+    state::ChainState,
+};
+use temp_db::TempFinDB;
+
+fn socket_path(name: &str) -> PathBuf {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    temp_dir().join(format!("broker-{}-{}.sock", name, time))
+}
+
+fn gen_cs(ver_window: u64) -> Arc<RwLock<ChainState<TempFinDB>>> {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut cs = ChainState::new(fdb, "test".to_string(), ver_window);
+    let batch = vec![
+        (b"k10".to_vec(), Some(b"v10".to_vec())),
+        (b"k20".to_vec(), Some(b"v20".to_vec())),
+        (b"k30".to_vec(), Some(b"v30".to_vec())),
+    ];
+    cs.commit(batch, 1, true).unwrap();
+    cs.commit(vec![(b"k10".to_vec(), Some(b"v11".to_vec()))], 2, true)
+        .unwrap();
+    Arc::new(RwLock::new(cs))
+}
+
+#[test]
+fn broker_serves_reads() {
+    let cs = gen_cs(10);
+    let path = socket_path("reads");
+    let broker = Broker::spawn(cs.clone(), &path).unwrap();
+    let client = BrokerClient::connect(&path).unwrap();
+
+    assert_eq!(client.height().unwrap(), 2);
+    assert_eq!(client.get(b"k10").unwrap(), Some(b"v11".to_vec()));
+    assert_eq!(client.get(b"k40").unwrap(), None);
+    assert_eq!(client.get_ver(b"k10", 1).unwrap(), Some(b"v10".to_vec()));
+
+    let pairs = client.iterate(b"k", b"k30", IterOrder::Asc, 10).unwrap();
+    assert_eq!(
+        pairs,
+        vec![
+            (b"k10".to_vec(), b"v11".to_vec()),
+            (b"k20".to_vec(), b"v20".to_vec()),
+        ]
+    );
+    let pairs = client.iterate(b"k", b"l", IterOrder::Desc, 1).unwrap();
+    assert_eq!(pairs, vec![(b"k30".to_vec(), b"v30".to_vec())]);
+
+    let proven = client.prove(&[b"k20".to_vec(), b"k40".to_vec()]).unwrap();
+    assert_eq!(proven.height, 2);
+    assert_eq!(proven.root_hash, cs.read().root_hash());
+    assert_eq!(
+        proven.values,
+        vec![
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k40".to_vec(), None)
+        ]
+    );
+    assert!(!proven.proof.is_empty());
+
+    // later commits are visible to connected clients
+    cs.write()
+        .commit(vec![(b"k40".to_vec(), Some(b"v40".to_vec()))], 3, true)
+        .unwrap();
+    assert_eq!(client.get(b"k40").unwrap(), Some(b"v40".to_vec()));
+
+    broker.stop();
+    assert!(!path.exists());
+    assert!(BrokerClient::connect(&path).is_err());
+}
+
+#[test]
+fn broker_errors() {
+    let cs = gen_cs(0);
+    let path = socket_path("errors");
+    let _broker = Broker::spawn(cs.clone(), &path).unwrap();
+    // the socket is in use
+    assert!(Broker::spawn(cs, &path).is_err());
+
+    // errors of the owner are returned to the client, the connection stays usable
+    let client = BrokerClient::connect(&path).unwrap();
+    assert!(client.get_ver(b"k10", 1).is_err());
+    assert!(matches!(
+        client.request(&BrokerRequest::Height).unwrap(),
+        BrokerResponse::Height(2)
+    ));
+}
+
+#[test]
+fn broker_pages_are_capped() {
+    let cs = gen_cs(0);
+    let value = vec![0xAB; 60_000];
+    let batch = (0..40u8)
+        .map(|i| (vec![b'p', i], Some(value.clone())))
+        .collect::<Vec<_>>();
+    cs.write().commit(batch, 3, true).unwrap();
+    let path = socket_path("pages");
+    let _broker = Broker::spawn(cs, &path).unwrap();
+    let client = BrokerClient::connect(&path).unwrap();
+
+    // pages stop at `MAX_PAGE_BYTES` whatever the limit, the next one starts after the last key
+    let mut keys = vec![];
+    let mut lower = b"p".to_vec();
+    loop {
+        let pairs = client.iterate(&lower, b"q", IterOrder::Asc, 100).unwrap();
+        let Some((last, _)) = pairs.last() else {
+            break;
+        };
+        assert!(pairs.len() < 40);
+        assert!(pairs.len() * value.len() * 4 <= MAX_PAGE_BYTES);
+        lower = last.clone();
+        lower.push(0);
+        keys.extend(pairs.into_iter().map(|(k, _)| k));
+    }
+    assert_eq!(keys, (0..40u8).map(|i| vec![b'p', i]).collect::<Vec<_>>());
+}
+
+#[test]
+fn broker_connections() {
+    let cs = gen_cs(0);
+    let path = socket_path("connections");
+
+    // the socket of a broker which didn't stop is replaced
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    let _broker = Broker::spawn(cs, &path).unwrap();
+
+    let clients = (0..MAX_CONNECTIONS)
+        .map(|_| BrokerClient::connect(&path).unwrap())
+        .collect::<Vec<_>>();
+    for client in clients.iter() {
+        assert_eq!(client.height().unwrap(), 2);
+    }
+    // connections past the limit are refused
+    let extra = BrokerClient::connect(&path).unwrap();
+    assert!(extra.height().is_err());
+    drop(clients);
+}
@@ -0,0 +1,42 @@
+#![cfg(feature = "car")]
+
+use mem_db::MemoryDB;
+use std::{env::temp_dir, fs};
+use storage::{
+    car::{export_car, verify_car},
+    state::ChainState,
+};
+
+#[test]
+fn car_export_verify() {
+    let mut cs = ChainState::new(MemoryDB::new(), "car".to_string(), 0);
+    let batch = (0..1000u32)
+        .map(|i| {
+            (
+                format!("key_{:04}", i).into_bytes(),
+                Some(vec![i as u8; 100]),
+            )
+        })
+        .collect();
+    cs.commit(batch, 3, true).unwrap();
+
+    let path = temp_dir().join(format!("car_{}.car", std::process::id()));
+    let exported = export_car(&cs, &path, 4096).unwrap();
+    assert_eq!(exported.height, 3);
+    assert_eq!(exported.entries, 1000);
+    assert!(exported.chunks.len() > 1);
+    assert!(exported.root.to_string().starts_with("bafy"));
+    assert_eq!(verify_car(&path).unwrap(), exported);
+
+    // the same state exports to the same blocks
+    let again = export_car(&cs, &path, 4096).unwrap();
+    assert_eq!(again.root, exported.root);
+
+    // any corrupted byte is caught by the hash of its block
+    let mut bytes = fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xFF;
+    fs::write(&path, &bytes).unwrap();
+    assert!(verify_car(&path).is_err());
+    fs::remove_file(path).unwrap();
+}
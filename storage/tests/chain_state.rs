@@ -1,8 +1,28 @@
-use fin_db::FinDB;
-use std::{env::temp_dir, time::SystemTime};
+use fin_db::{FinDB, FinDBOpts, MerkVerifier};
+use mem_db::MemoryDB;
+use parking_lot::RwLock;
+use ruc::*;
+use std::{
+    env::temp_dir,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
 use storage::{
-    db::MerkleDB,
-    state::{ChainState, ChainStateOpts},
+    aux_dump::import_aux,
+    db::{KVBatch, MerkleDB, ScanControl},
+    height::{Height, Version},
+    proof::FraudProofBundle,
+    snapshot::{
+        header_path, list_snapshots, open_container, seal_container, PrefixFilter, SnapshotCodec,
+        SnapshotOptions, CHECKPOINT_FORMAT, CONTAINER_FILE,
+    },
+    state::{
+        chain_state::UNCOUNTED, AnalyticsOpts, AnalyticsSession, ChainState, ChainStateOpts,
+        PruningPolicy, ScrubOpts, Scrubber, SnapshotChunk,
+    },
+    store::Prefix,
+    upgrade::UpgradeStep,
 };
 use temp_db::TempFinDB;
 
@@ -189,6 +209,27 @@ fn test_create_snapshot_2_2() {
     let _ = ChainState::create_with_opts(fdb, opts);
 }
 
+#[test]
+fn test_create_panic_free() {
+    for (ver_window, interval, cleanup_aux) in [(10, 1, false), (0, 2, false), (0, 0, true)] {
+        let fdb = TempFinDB::new().expect("failed to create temp findb");
+        let opts = ChainStateOpts {
+            name: Some("test".to_string()),
+            ver_window,
+            interval,
+            cleanup_aux,
+        };
+        assert!(ChainState::try_create_with_opts(fdb, opts).is_err());
+    }
+
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::try_new(fdb, "test".to_string(), 2).unwrap();
+    chain
+        .commit(vec![(b"k".to_vec(), Some(b"v".to_vec()))], 1, true)
+        .unwrap();
+    assert_eq!(chain.height().unwrap(), 1);
+}
+
 #[test]
 fn test_create_snapshot_3() {
     let ver_window = 12;
@@ -561,6 +602,44 @@ fn test_chain_reload_with_snapshots_2() {
     drop(cs);
 }
 
+#[test]
+fn test_chain_reload_deferred() {
+    let (path, mut cs) = gen_findb_cs(None, 100, 10);
+    commit_n(&mut cs, 200);
+    assert!(cs.ready());
+    let counts = cs
+        .get_snapshots_info()
+        .iter()
+        .map(|ss| (ss.end, ss.count))
+        .collect::<Vec<_>>();
+    assert!(counts.iter().any(|(_, count)| *count != 0));
+    drop(cs);
+
+    // the snapshots are not counted by the open, reads don't depend on it
+    let (_, cs) = gen_findb_cs(Some(path), 100, 10);
+    assert!(!cs.ready());
+    assert!(cs
+        .get_snapshots_info()
+        .iter()
+        .all(|ss| ss.count == UNCOUNTED));
+    compare_n(&cs, 98, 200);
+
+    let cs = Arc::new(RwLock::new(cs));
+    let loader = {
+        let cs = cs.clone();
+        thread::spawn(move || ChainState::load_deferred(&cs))
+    };
+    loader.join().unwrap();
+    let cs = cs.read();
+    assert!(cs.ready());
+    for ss in cs.get_snapshots_info() {
+        if let Some((_, count)) = counts.iter().find(|(end, _)| *end == ss.end) {
+            assert_eq!(ss.count, *count);
+        }
+    }
+    compare_n(&cs, 98, 200);
+}
+
 #[test]
 fn test_chain_reload_with_snapshots_3() {
     println!("ver_window 140, interval 7");
@@ -812,3 +891,1250 @@ fn test_chain_no_version_1() {
 
     std::fs::remove_dir_all(path).unwrap();
 }
+
+#[test]
+fn test_warm_up() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    let batch = vec![
+        (b"bank_k1".to_vec(), Some(b"v1".to_vec())),
+        (b"bank_k2".to_vec(), Some(b"v2".to_vec())),
+        (b"stake_k1".to_vec(), Some(b"v3".to_vec())),
+        (b"other_k1".to_vec(), Some(b"v4".to_vec())),
+    ];
+    chain.commit(batch, 1, true).unwrap();
+
+    let prefixes = vec![Prefix::new(b"bank"), Prefix::new(b"stake")];
+    assert_eq!(chain.warm_up(&prefixes), 3);
+    assert_eq!(chain.warm_up_parallel(&prefixes), 3);
+    assert_eq!(chain.warm_up_parallel(&[]), 0);
+    assert_eq!(chain.warm_up(&[Prefix::new(b"none")]), 0);
+}
+
+#[test]
+fn test_subscribe_proofs() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    let watched = vec![b"k10".to_vec(), b"k20".to_vec()];
+    let rx = chain.subscribe_proofs(watched, 1);
+    let rx_gone = chain.subscribe_proofs(vec![b"k10".to_vec()], 1);
+    drop(rx_gone);
+
+    let batch = vec![
+        (b"k10".to_vec(), Some(b"v10".to_vec())),
+        (b"k30".to_vec(), Some(b"v30".to_vec())),
+    ];
+    let (root_hash, _) = chain.commit(batch, 1, true).unwrap();
+
+    let update = rx.try_recv().unwrap();
+    assert_eq!(update.height, 1);
+    assert_eq!(update.root_hash, root_hash);
+    assert_eq!(
+        update.values,
+        vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), None)
+        ]
+    );
+
+    // a lagging subscriber misses updates but never blocks commits
+    chain.commit(vec![], 2, true).unwrap();
+    chain.commit(vec![], 3, true).unwrap();
+    assert_eq!(chain.proof_updates_dropped(), 1);
+    assert_eq!(rx.try_recv().unwrap().height, 2);
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn test_get_many_with_proof() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    let batch = vec![
+        (b"k10".to_vec(), Some(b"v10".to_vec())),
+        (b"k20".to_vec(), Some(b"v20".to_vec())),
+    ];
+    chain.commit(batch, 1, true).unwrap();
+    chain.commit(vec![], 2, true).unwrap();
+
+    let keys = vec![
+        b"k20".to_vec(),
+        b"k30".to_vec(),
+        b"k10".to_vec(),
+        b"k20".to_vec(),
+    ];
+    let proven = chain.get_many_with_proof(&keys, 2).unwrap();
+    assert_eq!(proven.height, 2);
+    assert_eq!(proven.root_hash, chain.root_hash());
+    assert_eq!(
+        proven.values,
+        vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k30".to_vec(), None),
+        ]
+    );
+    assert!(!proven.proof.is_empty());
+
+    // proofs are only generated against the latest root
+    assert!(chain.get_many_with_proof(&keys, 1).is_err());
+}
+
+#[test]
+fn test_truncate_log_before() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 10);
+    for h in 1..=5u64 {
+        let batch = vec![(b"k10".to_vec(), Some(format!("v{}", h).into_bytes()))];
+        chain.commit(batch, h, true).unwrap();
+    }
+    assert_eq!(chain.get_ver_range().unwrap().start, 0);
+
+    // follower `f1` still needs the changes after height 2
+    chain.ack_follower("f1", 2);
+    chain.ack_follower("f1", 1);
+    assert_eq!(chain.min_follower_ack(), Some(2));
+    assert_eq!(chain.truncate_log_before(4).unwrap(), 3);
+    assert_eq!(chain.get_ver_range().unwrap().start, 3);
+    assert_eq!(chain.get_ver(b"k10", 2).unwrap(), Some(b"v2".to_vec()));
+    assert_eq!(chain.get_ver(b"k10", 3).unwrap(), Some(b"v3".to_vec()));
+    assert!(chain.get_ver(b"k10", 1).is_err());
+
+    chain.remove_follower("f1");
+    assert_eq!(chain.truncate_log_before(4).unwrap(), 4);
+    assert_eq!(chain.get_ver(b"k10", 5).unwrap(), Some(b"v5".to_vec()));
+
+    // commits keep working on top of the truncated log
+    chain
+        .commit(vec![(b"k10".to_vec(), Some(b"v6".to_vec()))], 6, true)
+        .unwrap();
+    assert_eq!(chain.get_ver_range().unwrap().start, 4);
+    assert_eq!(chain.get_ver(b"k10", 4).unwrap(), Some(b"v4".to_vec()));
+    assert_eq!(chain.get(b"k10").unwrap(), Some(b"v6".to_vec()));
+}
+
+#[test]
+fn test_scrub() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    let batch = (0..10u8)
+        .map(|i| (vec![b'k', i], Some(vec![i; 8])))
+        .collect::<Vec<_>>();
+    chain.commit(batch, 1, true).unwrap();
+
+    // a small budget walks the db in several steps
+    let mut from = vec![];
+    let mut keys = 0;
+    let mut steps = 0;
+    loop {
+        let step = chain.scrub_step(&from, 0x10, true);
+        assert!(step.corrupted.is_empty());
+        keys += step.keys;
+        steps += 1;
+        match step.next {
+            Some(next) => from = next,
+            None => break,
+        }
+    }
+    assert_eq!(keys, 10);
+    assert!(steps > 1);
+
+    // a full unordered pass visits the same keys
+    let all = chain.scrub_all(false);
+    assert_eq!(all.keys, 10);
+    assert!(all.next.is_none() && all.corrupted.is_empty());
+
+    // reads of quarantined keys fail until the key is released
+    chain.quarantine_key(&[b'k', 1]);
+    assert!(chain.get(&[b'k', 1]).is_err());
+    assert_eq!(chain.quarantined(), vec![vec![b'k', 1]]);
+    assert!(chain.release_key(&[b'k', 1]));
+    assert_eq!(chain.get(&[b'k', 1]).unwrap(), Some(vec![1; 8]));
+
+    let cs = Arc::new(RwLock::new(chain));
+    let opts = ScrubOpts {
+        bytes_per_sec: 0x0100_0000,
+        ..Default::default()
+    };
+    let scrubber = Scrubber::spawn(cs, opts);
+    for _ in 0..100 {
+        if scrubber.report().passes > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let report = scrubber.stop();
+    assert!(report.passes > 0);
+    assert!(report.keys >= 10);
+    assert!(report.corrupted.is_empty());
+}
+
+#[test]
+fn test_import_batches_resume() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    let batches = (0..10u8)
+        .map(|i| {
+            vec![
+                (vec![b'k', i], Some(vec![i])),
+                (vec![b'k', i], Some(vec![i; 2])),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    // the source fails at batch 5, progress is checkpointed every 2 batches
+    let failing = batches.iter().enumerate().map(|(i, b)| {
+        if i == 5 {
+            Err(eg!("source unavailable"))
+        } else {
+            Ok(b.clone())
+        }
+    });
+    assert!(chain.import_batches(failing, 2).is_err());
+    assert_eq!(chain.import_progress().unwrap(), Some(4));
+
+    let progress = chain
+        .import_batches(batches.iter().cloned().map(Ok), 2)
+        .unwrap();
+    assert_eq!(progress.skipped, 4);
+    assert_eq!(progress.imported, 6);
+    assert_eq!(progress.entries, 6);
+    assert_eq!(chain.import_progress().unwrap(), None);
+
+    for i in 0..10u8 {
+        assert_eq!(chain.get(&[b'k', i]).unwrap(), Some(vec![i; 2]));
+    }
+    assert_eq!(chain.height().unwrap(), 0);
+}
+
+#[test]
+fn test_list_snapshots() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = temp_dir().join(format!("snapshots-{}", time));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // snapshots are ordered by height, not by name
+    for (height, name) in [(1u64, "b"), (2, "a")] {
+        chain
+            .commit(
+                vec![(vec![b'k', height as u8], Some(vec![0; 8]))],
+                height,
+                true,
+            )
+            .unwrap();
+        chain.snapshot(dir.join(name)).unwrap();
+    }
+    // a snapshot without a header is skipped
+    std::fs::write(dir.join("stray"), b"").unwrap();
+
+    let snapshots = list_snapshots(&dir).unwrap();
+    assert_eq!(snapshots.len(), 2);
+    assert_eq!(snapshots[0].path, dir.join("b"));
+    assert_eq!(snapshots[0].height, 1);
+    assert_eq!(snapshots[1].path, dir.join("a"));
+    assert_eq!(snapshots[1].height, 2);
+    assert_eq!(snapshots[1].hash, chain.root_hash());
+    assert_eq!(snapshots[1].format, CHECKPOINT_FORMAT);
+    assert!(snapshots.iter().all(|s| s.size > 0));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_export_filtered() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 2);
+    for height in 1..=3u64 {
+        let batch = ["pub_a", "peer_b", "secret_c"]
+            .iter()
+            .map(|k| (k.as_bytes().to_vec(), Some(vec![height as u8])))
+            .collect();
+        chain.commit(batch, height, true).unwrap();
+    }
+
+    // the whole state but the peer and secret modules
+    let filter = PrefixFilter::new().exclude("peer_").exclude("secret_");
+    let exp_fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut exp = ChainState::new(exp_fdb, "test".to_string(), 2);
+    chain.export_filtered(&mut exp, 3, &filter).unwrap();
+    assert_eq!(exp.height().unwrap(), 3);
+    assert_eq!(exp.get(b"pub_a").unwrap(), Some(vec![3]));
+    assert_eq!(exp.get(b"peer_b").unwrap(), None);
+    assert_eq!(exp.get(b"secret_c").unwrap(), None);
+    assert_eq!(exp.export_filter().unwrap(), Some(filter.clone()));
+    assert!(filter.allows(b"pub_a") && !filter.allows(b"peer_b"));
+    assert!(PrefixFilter::new().include("pub_").allows(b"pub_a"));
+    assert!(!PrefixFilter::new().include("pub_").allows(b"secret_c"));
+
+    // the filter is recorded in the header of snapshots of the export
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = temp_dir().join(format!("filtered-snapshots-{}", time));
+    std::fs::create_dir_all(&dir).unwrap();
+    exp.snapshot(dir.join("public")).unwrap();
+    chain.snapshot(dir.join("full")).unwrap();
+    let snapshots = list_snapshots(&dir).unwrap();
+    let find = |name: &str| snapshots.iter().find(|s| s.path == dir.join(name)).unwrap();
+    assert_eq!(find("public").filter, Some(filter));
+    assert_eq!(find("full").filter, None);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_prefix_metrics() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    chain.register_metrics_prefix(&Prefix::new(b"pub"));
+    chain.register_metrics_prefix(&Prefix::new(b"peer"));
+    let batch = vec![
+        (b"pub_a".to_vec(), Some(b"1".to_vec())),
+        (b"pub_b".to_vec(), Some(b"22".to_vec())),
+        (b"secret_c".to_vec(), Some(b"3".to_vec())),
+    ];
+    chain.commit(batch, 1, true).unwrap();
+    let metrics = chain.prefix_metrics();
+    assert_eq!(metrics.height, 1);
+    let pub_metrics = metrics.prefixes[b"pub_".as_slice()];
+    assert_eq!((pub_metrics.writes, pub_metrics.write_bytes), (2, 13));
+    assert_eq!(pub_metrics.reads, 0);
+    // registered prefixes without load report zeros, others are not tracked
+    assert_eq!(metrics.prefixes[b"peer_".as_slice()], Default::default());
+    assert_eq!(metrics.prefixes.len(), 2);
+
+    // reads count for the block they are made in
+    chain.get(b"pub_b").unwrap();
+    chain.get(b"peer_x").unwrap();
+    chain
+        .commit(vec![(b"pub_a".to_vec(), None)], 2, true)
+        .unwrap();
+    let metrics = chain.prefix_metrics();
+    assert_eq!(metrics.height, 2);
+    let pub_metrics = metrics.prefixes[b"pub_".as_slice()];
+    assert_eq!((pub_metrics.reads, pub_metrics.read_bytes), (1, 7));
+    assert_eq!((pub_metrics.writes, pub_metrics.write_bytes), (1, 5));
+    assert_eq!(metrics.prefixes[b"peer_".as_slice()].reads, 1);
+}
+
+#[test]
+fn test_key_sketch() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    let acct = Prefix::new(b"acct");
+    chain.register_sketch_prefix(&acct);
+    assert_eq!(chain.key_sketch(&acct), Some(Default::default()));
+    assert_eq!(chain.key_sketch(&Prefix::new(b"other")), None);
+
+    for height in 1..=20u64 {
+        let mut batch = (0..50u64)
+            .map(|i| {
+                let key = format!("acct_{:05}", height * 50 + i).into_bytes();
+                (key, Some(b"v".to_vec()))
+            })
+            .collect::<Vec<_>>();
+        batch.push((b"acct_hot".to_vec(), Some(height.to_be_bytes().to_vec())));
+        batch.push((b"other_x".to_vec(), Some(b"v".to_vec())));
+        chain.commit(batch, height, false).unwrap();
+    }
+
+    let sketch = chain.key_sketch(&acct).unwrap();
+    assert_eq!(sketch.updates, 20 * 51);
+    assert!(
+        (950..=1050).contains(&sketch.distinct_keys),
+        "{}",
+        sketch.distinct_keys
+    );
+    let (hot, count) = &sketch.heavy_hitters[0];
+    assert_eq!(hot, &b"acct_hot".to_vec());
+    assert!(*count >= 20);
+}
+
+#[test]
+fn test_height_watermarks() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 2);
+    assert_eq!(chain.watermarks(), Default::default());
+    let reader = chain.watermark_reader();
+
+    for height in 1..=3u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_be_bytes().to_vec()))];
+        chain.commit(batch, height, true).unwrap();
+    }
+    chain.commit(vec![], 4, false).unwrap();
+    assert_eq!(chain.latest_committed_height(), 4);
+    assert_eq!(chain.latest_flushed_height(), 3);
+    assert_eq!(chain.oldest_retained_height(), 2);
+    assert_eq!(reader.get(), chain.watermarks());
+    assert_eq!(
+        chain.oldest_retained_height(),
+        chain.get_ver_range().unwrap().start
+    );
+}
+
+#[test]
+fn test_height_consistency() {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let path = temp_dir().join(format!("findb-consistency-{}", time));
+    let open = || {
+        let fdb = FinDB::open(&path).unwrap();
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            ChainState::new(fdb, "test".to_string(), 0)
+        }))
+    };
+
+    let mut chain = open().unwrap();
+    for height in 1..=3u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_be_bytes().to_vec()))];
+        chain.commit(batch, height, false).unwrap();
+    }
+    assert!(chain.verify_height_consistency().is_ok());
+    drop(chain);
+    assert!(open().is_ok());
+
+    // height written without a commit
+    let mut fdb = FinDB::open(&path).unwrap();
+    fdb.commit(vec![(b"Height".to_vec(), Some(b"5".to_vec()))], true)
+        .unwrap();
+    drop(fdb);
+    assert!(open().is_err());
+
+    let mut fdb = FinDB::open(&path).unwrap();
+    fdb.commit(vec![(b"Height".to_vec(), Some(b"3".to_vec()))], true)
+        .unwrap();
+    drop(fdb);
+    assert!(open().is_ok());
+
+    // tree written without its aux data
+    let mut fdb = FinDB::open(&path).unwrap();
+    fdb.put_batch(vec![(b"k".to_vec(), Some(b"stray".to_vec()))])
+        .unwrap();
+    fdb.commit(vec![], true).unwrap();
+    drop(fdb);
+    assert!(open().is_err());
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_fraud_proofs() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 0);
+    chain.set_challenge_window(2);
+
+    let mut roots = vec![chain.root_hash()];
+    for height in 1..=4u64 {
+        let batch = vec![
+            (b"k1".to_vec(), Some(height.to_be_bytes().to_vec())),
+            (format!("k{}", height + 1).into_bytes(), Some(b"v".to_vec())),
+        ];
+        roots.push(chain.commit(batch, height, true).unwrap().0);
+    }
+
+    // only the heights of the challenge window are retained
+    assert!(chain.fraud_proof(1).is_err());
+    assert!(chain.fraud_proof(2).is_err());
+    let bundle = chain.fraud_proof(4).unwrap();
+    assert_eq!(bundle.height, 4);
+    assert_eq!(bundle.pre_root(), roots[3].as_slice());
+    assert_eq!(bundle.post_root, roots[4]);
+    assert_eq!(
+        bundle.batch,
+        vec![
+            (b"k1".to_vec(), Some(4u64.to_be_bytes().to_vec())),
+            (b"k5".to_vec(), Some(b"v".to_vec())),
+        ]
+    );
+    // pre-state values, `k5` is new at height 4
+    assert_eq!(
+        bundle.witness.values,
+        vec![
+            (b"k1".to_vec(), Some(3u64.to_be_bytes().to_vec())),
+            (b"k5".to_vec(), None),
+        ]
+    );
+    assert!(bundle.is_proven());
+    assert!(bundle.verify(&roots[3], &MerkVerifier).is_ok());
+    assert!(bundle.verify(&roots[4], &MerkVerifier).is_err());
+    let mut forged = bundle.clone();
+    forged.batch.pop();
+    assert!(forged.verify(&roots[3], &MerkVerifier).is_err());
+    assert_eq!(chain.fraud_proof(3).unwrap().post_root, roots[3]);
+
+    // a batch the post root doesn't hold disputes the transition
+    let mut forged = bundle.clone();
+    forged.batch[0].1 = Some(b"forged".to_vec());
+    assert!(forged.verify(&roots[3], &MerkVerifier).is_err());
+    let mut forged = bundle.clone();
+    forged.post_root = roots[3].clone();
+    assert!(forged.verify(&roots[3], &MerkVerifier).is_err());
+
+    // the keys of the batch are encoded once
+    let encoded = bundle.encode().unwrap();
+    assert_eq!(FraudProofBundle::decode(&encoded).unwrap(), bundle);
+    assert!(FraudProofBundle::decode(&encoded[..encoded.len() - 1]).is_err());
+
+    // disabling the window drops the retained bundles
+    chain.set_challenge_window(0);
+    chain.commit(vec![], 5, true).unwrap();
+    assert!(chain.fraud_proof(4).is_err());
+
+    // backends without proofs keep the changelog unproven
+    let mut chain = ChainState::new(MemoryDB::new(), "test".to_string(), 0);
+    chain.set_challenge_window(2);
+    chain
+        .commit(vec![(b"k1".to_vec(), Some(b"v1".to_vec()))], 1, true)
+        .unwrap();
+    let root = chain.root_hash();
+    chain
+        .commit(vec![(b"k1".to_vec(), Some(b"v2".to_vec()))], 2, true)
+        .unwrap();
+    let bundle = chain.fraud_proof(2).unwrap();
+    assert!(!bundle.is_proven());
+    assert_eq!(
+        bundle.witness.values,
+        vec![(b"k1".to_vec(), Some(b"v1".to_vec()))]
+    );
+    assert!(bundle.verify(&root, &MerkVerifier).is_err());
+    let root = chain.root_hash();
+    chain.commit(vec![], 3, true).unwrap();
+    let bundle = chain.fraud_proof(3).unwrap();
+    assert!(bundle.is_proven());
+    assert!(bundle.verify(&root, &MerkVerifier).is_ok());
+}
+
+#[test]
+fn test_root_hash_at() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 2);
+
+    let mut roots = vec![chain.root_hash()];
+    for height in 1..=5u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_be_bytes().to_vec()))];
+        roots.push(chain.commit(batch, height, true).unwrap().0);
+    }
+
+    // roots are kept along with the versions of the keys
+    let range = chain.get_ver_range().unwrap();
+    for height in range.start..=5 {
+        assert_eq!(chain.root_hash_at(height).unwrap(), roots[height as usize]);
+        assert_eq!(
+            chain.get_ver(b"k", height).unwrap(),
+            Some(height.to_be_bytes().to_vec())
+        );
+    }
+    assert!(chain.root_hash_at(range.start - 1).is_err());
+    assert!(chain.root_hash_at(6).is_err());
+}
+
+#[test]
+fn test_typed_heights() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 2);
+
+    let first = Height::default().next();
+    let batch = vec![(b"k".to_vec(), Some(b"v1".to_vec()))];
+    let (root, height) = chain.commit(batch, first, true).unwrap();
+    assert_eq!(Height::from(height), first);
+    let batch = vec![(b"k".to_vec(), Some(b"v2".to_vec()))];
+    chain.commit(batch, first.next(), true).unwrap();
+
+    // typed and plain heights address the same versions
+    assert_eq!(chain.root_hash_at(first).unwrap(), root);
+    assert_eq!(chain.get_ver(b"k", first).unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(chain.get_ver(b"k", 2).unwrap(), Some(b"v2".to_vec()));
+    assert_eq!(first.next().prev(), Some(first));
+    assert_eq!(Height(0).prev(), None);
+}
+
+#[test]
+fn test_pruning_policy() {
+    let (path, chain) = gen_findb_cs_v2(None, 10, 0, false);
+    drop(chain);
+    let fdb = FinDB::open(&path).unwrap();
+    let opts = ChainStateOpts {
+        name: Some("findb".to_string()),
+        ver_window: 10,
+        interval: 0,
+        cleanup_aux: false,
+    };
+    let every = PruningPolicy::KeepEvery {
+        interval: 2,
+        recent: 2,
+    };
+    let mut chain = ChainState::create_with_pruning(fdb, opts, every);
+
+    let mut roots = vec![chain.root_hash()];
+    for height in 1..=6u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_be_bytes().to_vec()))];
+        roots.push(chain.commit(batch, height, true).unwrap().0);
+    }
+
+    // every second height stays readable once out of the window of the last 2 heights
+    let start = chain.get_ver_range().unwrap().start;
+    assert!(start > 2);
+    for height in 1..start {
+        if height % 2 == 0 {
+            assert_eq!(
+                chain.changes_at(height).unwrap(),
+                vec![(b"k".to_vec(), Some(height.to_be_bytes().to_vec()))]
+            );
+            assert_eq!(chain.root_hash_at(height).unwrap(), roots[height as usize]);
+        } else {
+            assert!(chain.changes_at(height).is_err());
+            assert!(chain.root_hash_at(height).is_err());
+        }
+    }
+    assert_eq!(
+        chain.changes_at(6).unwrap(),
+        vec![(b"k".to_vec(), Some(6u64.to_be_bytes().to_vec()))]
+    );
+
+    // the policy is persisted, an open without one keeps it
+    drop(chain);
+    let (_, chain) = gen_findb_cs_v2(Some(path), 10, 0, false);
+    assert_eq!(chain.pruning_policy(), every);
+    assert_eq!(chain.get_ver_range().unwrap().start, start);
+    assert_eq!(
+        chain.changes_at(2).unwrap(),
+        vec![(b"k".to_vec(), Some(2u64.to_be_bytes().to_vec()))]
+    );
+    assert!(chain.changes_at(3).is_err());
+
+    // nothing is merged into base when all versions are kept
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let opts = ChainStateOpts {
+        ver_window: 2,
+        ..Default::default()
+    };
+    let mut chain = ChainState::create_with_pruning(fdb, opts, PruningPolicy::KeepAll);
+    for height in 1..=6u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_be_bytes().to_vec()))];
+        chain.commit(batch, height, true).unwrap();
+    }
+    assert_eq!(chain.pruning_policy(), PruningPolicy::KeepAll);
+    assert_eq!(chain.get_ver_range().unwrap(), 0..6);
+    assert_eq!(
+        chain.get_ver(b"k", 1).unwrap(),
+        Some(1u64.to_be_bytes().to_vec())
+    );
+    assert!(chain.root_hash_at(1).is_ok());
+
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let opts = ChainStateOpts::default();
+    assert!(ChainState::try_create_with_pruning(fdb, opts, PruningPolicy::KeepAll).is_err());
+}
+
+#[test]
+fn test_analytics_session() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 10);
+    let batch = (1..=5u8)
+        .map(|i| (vec![b'k', i], Some(vec![i; 4])))
+        .collect::<Vec<_>>();
+    chain.commit(batch, 1, true).unwrap();
+    let cs = Arc::new(RwLock::new(chain));
+
+    let opts = AnalyticsOpts {
+        max_scans: Some(2),
+        step_keys: 2,
+        ..Default::default()
+    };
+    let session = AnalyticsSession::open(cs.clone(), None, opts).unwrap();
+    assert_eq!(session.height(), 1);
+    assert_eq!(cs.read().current_pinned_height(), vec![1]);
+
+    // later commits are not seen by the session
+    let batch = vec![
+        (vec![b'k', 1], Some(vec![9; 4])),
+        (vec![b'k', 6], Some(vec![6; 4])),
+    ];
+    cs.write().commit(batch, 2, true).unwrap();
+    assert_eq!(session.get(&[b'k', 1]).unwrap(), Some(vec![1; 4]));
+    assert_eq!(session.get(&[b'k', 6]).unwrap(), None);
+
+    // the pairs are handed out without the chain state lock held
+    let mut pairs = vec![];
+    let visited = session
+        .scan(b"k", b"l", &mut |k, v| {
+            assert!(cs.try_write().is_some());
+            pairs.push((k.to_vec(), v.to_vec()));
+            ScanControl::Continue
+        })
+        .unwrap();
+    assert_eq!(visited, 5);
+    let expected = (1..=5u8)
+        .map(|i| (vec![b'k', i], vec![i; 4]))
+        .collect::<Vec<_>>();
+    assert_eq!(pairs, expected);
+
+    let visited = session
+        .scan(b"k", b"l", &mut |_, _| ScanControl::Stop)
+        .unwrap();
+    assert_eq!(visited, 1);
+    let progress = session.progress();
+    assert_eq!((progress.scans, progress.entries), (2, 7));
+    assert_eq!(progress.bytes, 7 * 6);
+
+    // the scan budget is spent, a cancelled session fails every read
+    assert!(session
+        .scan(b"k", b"l", &mut |_, _| ScanControl::Continue)
+        .is_err());
+    let handle = session.handle();
+    handle.cancel();
+    assert!(session.get(&[b'k', 1]).is_err());
+    assert!(session.progress().cancelled);
+
+    drop(session);
+    assert!(cs.read().current_pinned_height().is_empty());
+
+    // the byte budget
+    let opts = AnalyticsOpts {
+        max_bytes: Some(10),
+        ..Default::default()
+    };
+    let session = AnalyticsSession::open(cs.clone(), Some(1), opts).unwrap();
+    assert!(session.get(&[b'k', 1]).is_ok());
+    assert!(session.get(&[b'k', 2]).is_err());
+}
+
+#[test]
+fn test_root_mmr() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test".to_string(), 2);
+    chain
+        .commit(vec![(b"k0".to_vec(), Some(b"v0".to_vec()))], 1, true)
+        .unwrap();
+    assert!(chain.prove_root_inclusion(1).is_err());
+
+    chain.enable_root_mmr();
+    let mut roots = vec![];
+    let mut mmr_roots = vec![];
+    for height in 2..=8u64 {
+        let batch = vec![(
+            format!("k{}", height).into_bytes(),
+            Some(height.to_string().into_bytes()),
+        )];
+        roots.push(chain.commit(batch, height, true).unwrap());
+        mmr_roots.push(chain.mmr_root().unwrap());
+    }
+    assert!(chain.prove_root_inclusion(1).is_err());
+
+    // the roots of heights out of the version window are proven too
+    let mmr_root = chain.mmr_root().unwrap();
+    for (root, height) in roots.iter() {
+        let proof = chain.prove_root_inclusion(*height).unwrap();
+        assert_eq!(&proof.root_hash, root);
+        assert_eq!(proof.leaves, 7);
+        proof.verify(&mmr_root).unwrap();
+        assert!(proof.verify(&mmr_roots[0]).is_err());
+
+        let mut forged = proof.clone();
+        forged.root_hash = roots[0].0.clone();
+        forged.root_hash[0] ^= 1;
+        assert!(forged.verify(&mmr_root).is_err());
+        let mut forged = proof.clone();
+        forged.height += 1;
+        assert!(forged.verify(&mmr_root).is_err());
+    }
+    assert!(mmr_roots.windows(2).all(|w| w[0] != w[1]));
+}
+
+#[test]
+fn test_aux_export_import() {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let path = temp_dir().join(format!("findb-aux-dump-{}", time));
+    let open = || ChainState::try_new(FinDB::open(&path).unwrap(), "test".to_string(), 2);
+
+    let mut chain = open().unwrap();
+    for height in 1..=3u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_be_bytes().to_vec()))];
+        chain.commit(batch, height, false).unwrap();
+    }
+    let mut dump = vec![];
+    let exported = chain.export_aux(&mut dump).unwrap();
+    assert!(exported.entries > 0);
+    assert_eq!(exported.root_hash, chain.root_hash());
+    drop(chain);
+
+    // the index is lost, the tree is kept
+    let mut fdb = FinDB::open(&path).unwrap();
+    fdb.clean_aux().unwrap();
+    drop(fdb);
+    assert!(open().is_err());
+
+    let mut fdb = FinDB::open(&path).unwrap();
+    fdb.commit(vec![(b"stale".to_vec(), Some(b"1".to_vec()))], false)
+        .unwrap();
+    assert!(import_aux(&mut fdb, &mut &dump[..dump.len() - 1]).is_err());
+    assert_eq!(fdb.get_aux(b"stale").unwrap(), Some(b"1".to_vec()));
+    let imported = import_aux(&mut fdb, &mut dump.as_slice()).unwrap();
+    assert_eq!(imported, exported);
+    // keys missing from the dump are deleted by the same commit
+    assert_eq!(fdb.get_aux(b"stale").unwrap(), None);
+    drop(fdb);
+
+    let chain = open().unwrap();
+    assert_eq!(chain.height().unwrap(), 3);
+    assert_eq!(
+        chain.get_ver(b"k", 2).unwrap(),
+        Some(2u64.to_be_bytes().to_vec())
+    );
+    drop(chain);
+
+    // a dump belongs to its tree only
+    let mut other = TempFinDB::new().unwrap();
+    assert!(import_aux(&mut other, &mut dump.as_slice()).is_err());
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_snapshot_chunks() {
+    let mut chain = gen_cs(4, 0);
+    for height in 1..=5u64 {
+        let batch = (0..20u64)
+            .map(|i| {
+                let key = format!("key_{:02}", i).into_bytes();
+                (key, Some(format!("{}_{}", height, i).into_bytes()))
+            })
+            .collect();
+        chain.commit(batch, height, true).unwrap();
+    }
+    let root = chain.root_hash();
+    let chunks = chain
+        .snapshot_chunks(64)
+        .unwrap()
+        .with_proofs()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert!(chunks.len() > 1);
+    assert_eq!(chunks.iter().filter(|c| c.last).count(), 1);
+    assert!(chunks.last().unwrap().last);
+
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let path = temp_dir().join(format!("findb-chunks-{}", time));
+    let restore = || {
+        let fdb = FinDB::open(&path).unwrap();
+        ChainState::restore_from_chunks(fdb, 5, root.clone()).unwrap()
+    };
+
+    // out of order and tampered chunks
+    let mut restorer = restore();
+    assert!(restorer.apply(chunks[1].clone()).is_err());
+    let mut tampered = chunks[0].clone();
+    tampered.entries[0].1 = b"forged".to_vec();
+    assert!(restorer.apply(tampered.clone()).is_err());
+
+    // forged chunks of a consistent chain are caught by their proofs
+    let mut forged = vec![];
+    let mut prev_hash = SnapshotChunk::first_prev_hash(5, &root);
+    for chunk in chunks.iter() {
+        let mut chunk = chunk.clone();
+        if chunk.index == 0 {
+            chunk.entries = tampered.entries.clone();
+        }
+        chunk.hash = chunk.compute_hash(&prev_hash);
+        prev_hash = chunk.hash.clone();
+        forged.push(chunk);
+    }
+    let mut verified = ChainState::restore_from_chunks(TempFinDB::new().unwrap(), 5, root.clone())
+        .unwrap()
+        .with_verifier(MerkVerifier);
+    assert!(verified.apply(forged[0].clone()).is_err());
+    let mut unproven = chunks[0].clone();
+    unproven.proof.clear();
+    assert!(verified.apply(unproven).is_err());
+
+    // without verifier they're caught by the restored root, and the restore starts over
+    for chunk in forged {
+        restorer.apply(chunk).unwrap();
+    }
+    assert!(restorer.is_complete());
+    assert!(restorer.finish(ChainStateOpts::default()).is_err());
+    let restorer = restore();
+    assert_eq!(restorer.next_index(), 0);
+    drop(restorer);
+    std::fs::remove_dir_all(&path).unwrap();
+
+    let mut restorer = verified;
+    for chunk in chunks.iter() {
+        restorer.apply(chunk.clone()).unwrap();
+    }
+    assert_eq!(restorer.next_index(), chunks.len() as u64);
+    let opts = ChainStateOpts {
+        ver_window: 4,
+        ..Default::default()
+    };
+    let mut restored = restorer.finish(opts).unwrap();
+    assert_eq!(restored.height().unwrap(), 5);
+    assert_eq!(restored.root_hash(), root);
+    assert_eq!(restored.get(b"key_07").unwrap(), Some(b"5_7".to_vec()));
+
+    let batch = vec![(b"key_07".to_vec(), Some(b"6_7".to_vec()))];
+    restored.commit(batch.clone(), 6, true).unwrap();
+    chain.commit(batch, 6, true).unwrap();
+    assert_eq!(restored.root_hash(), chain.root_hash());
+    assert_eq!(
+        restored.get_ver(b"key_07", 5).unwrap(),
+        Some(b"5_7".to_vec())
+    );
+
+    // a restore into a db in use is refused
+    let mut mdb = MemoryDB::new();
+    mdb.put_batch(vec![(b"k".to_vec(), Some(b"v".to_vec()))])
+        .unwrap();
+    mdb.commit(vec![], true).unwrap();
+    assert!(ChainState::restore_from_chunks(mdb, 5, root).is_err());
+}
+
+#[test]
+fn test_snapshot_chunks_memory_db() {
+    let mut chain = ChainState::new(MemoryDB::new(), "test".to_string(), 0);
+    for height in 1..=3u64 {
+        let batch = (0..20u64)
+            .map(|i| {
+                let key = format!("key_{:02}", i).into_bytes();
+                (key, Some(format!("{}_{}", height, i).into_bytes()))
+            })
+            .collect();
+        chain.commit(batch, height, true).unwrap();
+    }
+    let root = chain.root_hash();
+
+    // MemoryDB has no range proofs, its chunks are checked by the restored root only
+    assert!(chain
+        .snapshot_chunks(64)
+        .unwrap()
+        .with_proofs()
+        .collect::<Result<Vec<_>>>()
+        .is_err());
+    let chunks = chain
+        .snapshot_chunks(64)
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert!(chunks.len() > 1);
+
+    let mut restorer = ChainState::restore_from_chunks(MemoryDB::new(), 3, root.clone()).unwrap();
+    for chunk in chunks {
+        restorer.apply(chunk).unwrap();
+    }
+    let restored = restorer.finish(ChainStateOpts::default()).unwrap();
+    assert_eq!(restored.height().unwrap(), 3);
+    assert_eq!(restored.root_hash(), root);
+    assert_eq!(restored.get(b"key_07").unwrap(), Some(b"3_7".to_vec()));
+}
+
+#[test]
+fn test_snapshot_diff() {
+    let batch_at = |height: u64| -> KVBatch {
+        let mut batch = vec![
+            (format!("key_{}", height).into_bytes(), Some(b"v".to_vec())),
+            (b"counter".to_vec(), Some(height.to_string().into_bytes())),
+        ];
+        if height > 4 {
+            batch.push((format!("key_{}", height - 3).into_bytes(), None));
+        }
+        batch
+    };
+    let mut chain = gen_cs(4, 0);
+    let mut base = gen_cs(4, 0);
+    let mut forged_base = gen_cs(4, 0);
+    for height in 1..=3u64 {
+        chain.commit(batch_at(height), height, true).unwrap();
+        base.commit(batch_at(height), height, true).unwrap();
+        forged_base.commit(batch_at(height), height, true).unwrap();
+    }
+    for height in 4..=6u64 {
+        chain.commit(batch_at(height), height, true).unwrap();
+    }
+
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let path = temp_dir().join(format!("chain-diff-{}", time));
+    assert!(chain.snapshot_diff(1, &path).is_err());
+    chain.snapshot_diff(3, &path).unwrap();
+    let snapshots = list_snapshots(temp_dir()).unwrap();
+    let info = snapshots.iter().find(|s| s.path == path).unwrap();
+    assert_eq!(info.height, 6);
+    assert_eq!(info.format, "diff");
+
+    // a diff applies at its base height only
+    assert!(chain.apply_diff(&path).is_err());
+
+    // nothing of a truncated diff is committed
+    let truncated = temp_dir().join(format!("chain-diff-truncated-{}", time));
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::write(&truncated, &bytes[..bytes.len() - 12]).unwrap();
+    std::fs::copy(header_path(&path), header_path(&truncated)).unwrap();
+    assert!(base.apply_diff(&truncated).is_err());
+    assert_eq!(base.height().unwrap(), 3);
+    std::fs::remove_file(&truncated).unwrap();
+    std::fs::remove_file(header_path(&truncated)).unwrap();
+
+    // a height not leading to its root stops the replay, its staged writes block commits
+    let forged = temp_dir().join(format!("chain-diff-forged-{}", time));
+    let needle = b"counter\x00\x00\x00\x015";
+    let at = bytes
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .unwrap();
+    let mut forged_bytes = bytes.clone();
+    forged_bytes[at + needle.len() - 1] = b'9';
+    std::fs::write(&forged, &forged_bytes).unwrap();
+    std::fs::copy(header_path(&path), header_path(&forged)).unwrap();
+    assert!(forged_base.apply_diff(&forged).is_err());
+    assert_eq!(forged_base.height().unwrap(), 4);
+    assert!(forged_base.commit(batch_at(5), 5, true).is_err());
+    assert_eq!(forged_base.height().unwrap(), 4);
+    std::fs::remove_file(&forged).unwrap();
+    std::fs::remove_file(header_path(&forged)).unwrap();
+
+    assert_eq!(base.apply_diff(&path).unwrap(), (chain.root_hash(), 6));
+    assert_eq!(base.get(b"key_2").unwrap(), None);
+    assert_eq!(base.get(b"counter").unwrap(), Some(b"6".to_vec()));
+    assert_eq!(base.get_ver(b"counter", 4).unwrap(), Some(b"4".to_vec()));
+    assert_eq!(base.get_ver(b"key_2", 4).unwrap(), Some(b"v".to_vec()));
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(header_path(&path)).unwrap();
+}
+
+#[test]
+fn test_reindex() {
+    let mut chain = gen_cs(3, 0);
+    for height in 1..=6u64 {
+        let batch = vec![
+            (b"k".to_vec(), Some(height.to_string().into_bytes())),
+            (format!("k_{}", height).into_bytes(), Some(b"v".to_vec())),
+        ];
+        chain.commit(batch, height, true).unwrap();
+    }
+    // an index bug writes a wrong version
+    let bogus = vec![(
+        ChainState::<TempFinDB>::versioned_key(b"k", 6),
+        Some(b"bogus".to_vec()),
+    )];
+    chain.commit_with_aux(vec![], bogus, 7, true).unwrap();
+    assert_eq!(chain.get_ver(b"k", 6).unwrap(), Some(b"bogus".to_vec()));
+
+    let mut reports = vec![];
+    let done = chain
+        .reindex(2, &mut |progress| reports.push(progress.clone()))
+        .unwrap();
+    assert_eq!(done.indexed, 7);
+    assert_eq!(done.last_key, b"k_6".to_vec());
+    assert_eq!(reports.last(), Some(&done));
+    assert!(reports.len() > 4);
+
+    assert_eq!(chain.height().unwrap(), 7);
+    assert_eq!(chain.get(b"k").unwrap(), Some(b"6".to_vec()));
+    assert_eq!(chain.get_ver(b"k", 7).unwrap(), Some(b"6".to_vec()));
+    assert!(chain.get_ver(b"k", 6).is_err());
+
+    chain
+        .commit(vec![(b"k".to_vec(), Some(b"8".to_vec()))], 8, true)
+        .unwrap();
+    assert_eq!(chain.get_ver(b"k", 7).unwrap(), Some(b"6".to_vec()));
+    assert_eq!(chain.get_ver(b"k", 8).unwrap(), Some(b"8".to_vec()));
+}
+
+#[test]
+fn test_plan_upgrade() {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let path = temp_dir().join(format!("findb-plan-upgrade-{}", time));
+    let opts = |ver_window, interval| ChainStateOpts {
+        name: Some("plan".to_string()),
+        ver_window,
+        interval,
+        ..Default::default()
+    };
+    let mut chain = ChainState::create_with_opts(FinDB::open(&path).unwrap(), opts(10, 0));
+    for height in 1..=6u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_string().into_bytes()))];
+        chain.commit(batch, height, true).unwrap();
+    }
+    drop(chain);
+
+    // shrinking the window prunes heights 1 to 3, snapshots are built at heights 4 and 6
+    let report = FinDB::plan_upgrade(&path, &opts(2, 2)).unwrap();
+    assert_eq!(report.height, 6);
+    assert_eq!(report.aux_version, Some(Version(2)));
+    assert_eq!(
+        report.steps,
+        vec![
+            UpgradeStep::PruneVersions {
+                from: 0,
+                to: 4,
+                entries: 3,
+            },
+            UpgradeStep::RebuildSnapshots {
+                from_interval: 0,
+                to_interval: 2,
+                snapshots: 2,
+            },
+        ]
+    );
+    let cleanup = ChainStateOpts {
+        cleanup_aux: true,
+        ..opts(2, 2)
+    };
+    let report = FinDB::plan_upgrade(&path, &cleanup).unwrap();
+    assert_eq!(report.steps, vec![UpgradeStep::CleanupAux { keys: 1 }]);
+
+    // the plan is a dry run, opening runs it and nothing is left to run
+    assert!(!FinDB::plan_upgrade(&path, &opts(2, 2)).unwrap().is_noop());
+    let chain = ChainState::create_with_opts(FinDB::open(&path).unwrap(), opts(2, 2));
+    drop(chain);
+    assert!(FinDB::plan_upgrade(&path, &opts(2, 2)).unwrap().is_noop());
+
+    std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_snapshot_restore() {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = temp_dir().join(format!("findb-restore-{}", time));
+    let mut chain = ChainState::new(FinDB::open(dir.join("db")).unwrap(), "test".into(), 10);
+    for height in 1..=3u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_string().into_bytes()))];
+        chain.commit(batch, height, true).unwrap();
+    }
+    let snapshot = dir.join("snap");
+    chain.snapshot(&snapshot).unwrap();
+
+    let fdb = FinDB::restore(&snapshot, dir.join("restored"), &FinDBOpts::default()).unwrap();
+    let restored = ChainState::new(fdb, "test".into(), 10);
+    assert_eq!(restored.height().unwrap(), 3);
+    assert_eq!(restored.root_hash(), chain.root_hash());
+    assert_eq!(restored.get(b"k").unwrap(), Some(b"3".to_vec()));
+    drop(restored);
+    // only into an empty db
+    assert!(FinDB::restore(&snapshot, dir.join("restored"), &FinDBOpts::default()).is_err());
+
+    // a snapshot of another backend
+    let container = snapshot.join(CONTAINER_FILE);
+    let sealed = std::fs::read(&container).unwrap();
+    let listing = open_container("findb", &sealed).unwrap();
+    std::fs::write(&container, seal_container("memdb", listing).unwrap()).unwrap();
+    assert!(FinDB::restore(&snapshot, dir.join("foreign"), &FinDBOpts::default()).is_err());
+    std::fs::write(&container, &sealed).unwrap();
+
+    // a truncated file fails before anything is written
+    let current = snapshot.join("CURRENT");
+    let bytes = std::fs::read(&current).unwrap();
+    std::fs::write(&current, &bytes[..bytes.len() - 1]).unwrap();
+    assert!(FinDB::restore(&snapshot, dir.join("truncated"), &FinDBOpts::default()).is_err());
+    assert!(!dir.join("truncated").join("main").join("CURRENT").exists());
+
+    drop(chain);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_snapshot_cross_backend() {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = temp_dir().join(format!("findb-portable-{}", time));
+    let mut chain = ChainState::new(FinDB::open(dir.join("db")).unwrap(), "test".into(), 10);
+    for height in 1..=3u64 {
+        let batch = vec![(b"k".to_vec(), Some(height.to_string().into_bytes()))];
+        chain.commit(batch, height, true).unwrap();
+    }
+    let root = chain.root_hash();
+    drop(chain);
+
+    // a FinDB snapshot loaded into a MemoryDB
+    let fdb = FinDB::open(dir.join("db")).unwrap();
+    let snapshot = dir.join("portable");
+    fdb.snapshot_portable(&snapshot, &SnapshotOptions::new())
+        .unwrap();
+    drop(fdb);
+    let mdb = MemoryDB::from_bytes(&std::fs::read(&snapshot).unwrap()).unwrap();
+    let loaded = ChainState::new(mdb, "test".into(), 10);
+    assert_eq!(loaded.height().unwrap(), 3);
+    assert_eq!(loaded.get(b"k").unwrap(), Some(b"3".to_vec()));
+    assert_eq!(loaded.get_ver(b"k", 2).unwrap(), Some(b"2".to_vec()));
+    drop(loaded);
+
+    // and restored into a FinDB with the same root
+    let fdb = FinDB::restore(&snapshot, dir.join("restored"), &FinDBOpts::default()).unwrap();
+    assert_eq!(ChainState::new(fdb, "test".into(), 10).root_hash(), root);
+
+    // a compressed FinDB snapshot is a portable one
+    if cfg!(feature = "compression") {
+        let fdb = FinDB::open(dir.join("db")).unwrap();
+        let snapshot = dir.join("compressed");
+        let opts = SnapshotOptions::new().with_codec(SnapshotCodec::Zstd);
+        fdb.snapshot_with_opts(&snapshot, &opts).unwrap();
+        drop(fdb);
+        assert!(snapshot.is_file());
+        let opts = FinDBOpts::default();
+        let fdb = FinDB::restore(&snapshot, dir.join("decompressed"), &opts).unwrap();
+        assert_eq!(ChainState::new(fdb, "test".into(), 10).root_hash(), root);
+    }
+
+    // a MemoryDB persists portable snapshots, restored into a FinDB
+    let mdb = MemoryDB::open(dir.join("mem")).unwrap();
+    let mut chain = ChainState::new(mdb, "test".into(), 10);
+    for height in 1..=3u64 {
+        let batch = vec![(b"m".to_vec(), Some(height.to_string().into_bytes()))];
+        chain.commit(batch, height, true).unwrap();
+    }
+    let data = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.extension().map_or(false, |ext| ext == "data"))
+        .unwrap();
+    let fdb = FinDB::restore(&data, dir.join("from_mem"), &FinDBOpts::default()).unwrap();
+    let restored = ChainState::new(fdb, "test".into(), 10);
+    assert_eq!(restored.height().unwrap(), 3);
+    assert_eq!(restored.get(b"m").unwrap(), Some(b"3".to_vec()));
+    assert_eq!(restored.get_ver(b"m", 1).unwrap(), Some(b"1".to_vec()));
+    // the root recorded by the MemoryDB is kept next to the FinDB root
+    let record = restored.get_aux(b"RootRecord").unwrap().unwrap();
+    assert!(String::from_utf8(record).unwrap().starts_with("3_"));
+    assert!(restored.get_aux(b"SourceRoot").unwrap().is_some());
+    // only into an empty db
+    drop(restored);
+    assert!(FinDB::restore(&data, dir.join("from_mem"), &FinDBOpts::default()).is_err());
+    assert!(dir.join("from_mem").join("main").join("CURRENT").exists());
+
+    // a failed restore leaves no partial db behind
+    let corrupted = dir.join("corrupted");
+    let mut bytes = std::fs::read(&data).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&corrupted, bytes).unwrap();
+    let opts = FinDBOpts::default();
+    assert!(FinDB::restore(&corrupted, dir.join("from_corrupted"), &opts).is_err());
+    assert!(!dir.join("from_corrupted").exists());
+
+    drop(chain);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,69 @@
+use mem_db::MemoryDB;
+use parking_lot::RwLock;
+use std::sync::Arc;
+use storage::{
+    chained::{ChainedDB, FnSource},
+    db::MerkleDB,
+    state::{ChainState, State},
+};
+
+#[test]
+fn chained_db_fallback_reads() {
+    let mut base = MemoryDB::new();
+    base.put_batch(vec![
+        (b"k10".to_vec(), Some(b"base10".to_vec())),
+        (b"k20".to_vec(), Some(b"base20".to_vec())),
+    ])
+    .unwrap();
+    base.commit(vec![], true).unwrap();
+
+    let remote = FnSource(|key: &[u8]| {
+        Ok(match key {
+            b"k30" => Some(b"remote30".to_vec()),
+            _ => None,
+        })
+    });
+
+    let mut db = ChainedDB::new(MemoryDB::new())
+        .with_fallback(base)
+        .with_fallback(remote);
+    assert_eq!(db.depth(), 2);
+
+    db.put_batch(vec![(b"k10".to_vec(), Some(b"top10".to_vec()))])
+        .unwrap();
+    db.commit(vec![], true).unwrap();
+
+    assert_eq!(db.get(b"k10").unwrap(), Some(b"top10".to_vec()));
+    assert_eq!(db.get(b"k20").unwrap(), Some(b"base20".to_vec()));
+    assert_eq!(db.get(b"k30").unwrap(), Some(b"remote30".to_vec()));
+    assert_eq!(db.get(b"k40").unwrap(), None);
+    // writes never reach the fallbacks
+    assert_eq!(db.top().get(b"k20").unwrap(), None);
+}
+
+#[test]
+fn chained_db_delete_masks_fallbacks() {
+    let mut base = MemoryDB::new();
+    base.put_batch(vec![(b"k10".to_vec(), Some(b"base10".to_vec()))])
+        .unwrap();
+    base.commit(vec![], true).unwrap();
+
+    let db = ChainedDB::new(MemoryDB::new()).with_fallback(base);
+    let cs = Arc::new(RwLock::new(ChainState::new(db, "test".to_string(), 0)));
+    let mut state = State::new(cs.clone(), false);
+
+    assert_eq!(state.get(b"k10").unwrap(), Some(b"base10".to_vec()));
+    state.delete(b"k10").unwrap();
+    state.commit(1).unwrap();
+    assert_eq!(state.get(b"k10").unwrap(), None);
+
+    // a new write lifts the delete
+    state.set(b"k10", b"top10".to_vec()).unwrap();
+    state.commit(2).unwrap();
+    state.delete(b"k10").unwrap();
+    state.commit(3).unwrap();
+    assert_eq!(state.get(b"k10").unwrap(), None);
+    state.set(b"k10", b"top11".to_vec()).unwrap();
+    state.commit(4).unwrap();
+    assert_eq!(cs.read().get(b"k10").unwrap(), Some(b"top11".to_vec()));
+}
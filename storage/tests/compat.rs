@@ -0,0 +1,80 @@
+use mem_db::MemoryDB;
+use std::env::temp_dir;
+use storage::{
+    compat::{check, pick_samples, record, Expectations, Incompatibility},
+    state::ChainState,
+};
+
+fn gen_cs() -> ChainState<MemoryDB> {
+    let mut cs = ChainState::new(MemoryDB::new(), "compat".to_string(), 0);
+    let batch = (0..100u32)
+        .map(|i| (format!("key_{:03}", i).into_bytes(), Some(vec![i as u8; 8])))
+        .collect();
+    cs.commit(batch, 1, true).unwrap();
+    cs
+}
+
+#[test]
+fn compat_record_check() {
+    let cs = gen_cs();
+    let samples = pick_samples(&cs, 10);
+    assert_eq!(samples.len(), 10);
+    assert_eq!(samples[1], b"key_010".to_vec());
+
+    let mut samples = samples;
+    samples.push(b"missing".to_vec());
+    let expected = record(&cs, &samples).unwrap();
+    assert_eq!(expected.height, 1);
+    assert_eq!(expected.entries, 100);
+    assert_eq!(expected.samples.last().unwrap().1, None);
+
+    let report = check(&cs, &expected).unwrap();
+    assert!(report.is_compatible());
+    assert_eq!(report.checked_samples, 11);
+
+    // expectations survive a round trip through their file
+    let path = temp_dir().join(format!("compat_{}.json", std::process::id()));
+    expected.save(&path).unwrap();
+    assert_eq!(Expectations::load(&path).unwrap(), expected);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn compat_detects_changes() {
+    let mut cs = gen_cs();
+    let expected = record(&cs, &[b"key_005".to_vec()]).unwrap();
+
+    cs.commit(
+        vec![(b"key_005".to_vec(), Some(b"changed".to_vec()))],
+        2,
+        true,
+    )
+    .unwrap();
+    let report = check(&cs, &expected).unwrap();
+    assert!(!report.is_compatible());
+    assert_eq!(
+        report.incompatibilities,
+        vec![
+            Incompatibility::Height {
+                expected: 1,
+                found: 2
+            },
+            Incompatibility::Digest,
+            Incompatibility::Value(b"key_005".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn compat_detects_root_change() {
+    let cs = gen_cs();
+    let mut expected = record(&cs, &[]).unwrap();
+    assert!(check(&cs, &expected).unwrap().is_compatible());
+
+    // same height and entries, another hashing of them
+    expected.root_hash = vec![0; 32];
+    assert_eq!(
+        check(&cs, &expected).unwrap().incompatibilities,
+        vec![Incompatibility::RootHash]
+    );
+}
@@ -0,0 +1,155 @@
+use fin_db::RocksDB;
+use mem_db::MemoryDB;
+use std::{env::temp_dir, path::PathBuf};
+use storage::{
+    config::{Backend, CfConfig, ExtractorConfig, StorageConfig, ValueLogConfig},
+    db::{Compression, PrefixExtractor},
+    state::{PruningPolicy, State},
+};
+
+#[test]
+fn config_from_json() {
+    let cfg = StorageConfig::from_json(
+        r#"{
+            "backend": "rocksdb",
+            "path": "/var/lib/node/state",
+            "rocksdb": {
+                "wal_size_limit_mb": 512,
+                "prefix_bloom": { "extractor": "module", "bits_per_key": 12 },
+                "block_cache_size": 67108864,
+                "data": { "compression": "lz4", "bloom_bits_per_key": 10 }
+            },
+            "pruning": { "ver_window": 100 },
+            "snapshot": { "interval": 10 },
+            "metrics": { "track_iterators": true, "max_open_iterators": 64 }
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(cfg.backend, Backend::RocksDB);
+    assert_eq!(cfg.path, PathBuf::from("/var/lib/node/state"));
+    assert!(cfg.validate().is_ok());
+
+    // missing sections keep their defaults
+    assert_eq!(cfg.sync, StorageConfig::default().sync);
+    assert!(cfg.scrub_opts().is_none());
+
+    let opts = cfg.chain_state_opts();
+    assert_eq!((opts.ver_window, opts.interval), (100, 10));
+    assert_eq!(cfg.wal_opts().size_limit_mb, 512);
+    let bloom = cfg.prefix_bloom_opts().unwrap();
+    assert_eq!(bloom.extractor, PrefixExtractor::Module);
+    assert_eq!(bloom.bits_per_key, 12);
+    assert_eq!(cfg.iter_limits().unwrap().max_open, Some(64));
+    assert_eq!(cfg.rocksdb.block_cache_size, Some(64 << 20));
+    let data = cfg.data_cf_opts();
+    assert_eq!(data.compression, Some(Compression::Lz4));
+    assert_eq!(data.bloom_bits_per_key, Some(10));
+    assert_eq!(data.write_buffer_size, None);
+    // the aux data isn't tuned apart
+    assert!(StorageConfig::from_json(r#"{"rocksdb": {"aux": {"compression": "zstd"}}}"#).is_err());
+
+    let fixed =
+        StorageConfig::from_json(r#"{"rocksdb": {"prefix_bloom": {"extractor": {"fixed": 4}}}}"#)
+            .unwrap();
+    assert_eq!(
+        fixed.rocksdb.prefix_bloom.unwrap().extractor,
+        ExtractorConfig::Fixed(4)
+    );
+
+    let vlog = StorageConfig::from_json(r#"{"value_log": {"min_value_size": 1024}}"#).unwrap();
+    let opts = vlog.value_log_opts().unwrap();
+    assert_eq!(opts.min_value_size, 1024);
+    assert_eq!(opts.segment_size, 64 << 20);
+    assert!(StorageConfig::default().value_log_opts().is_none());
+
+    let kept = StorageConfig::from_json(
+        r#"{"pruning": {"policy": {"keep_every": {"interval": 100, "recent": 20}}}}"#,
+    )
+    .unwrap();
+    assert!(kept.validate().is_ok());
+    assert_eq!(
+        kept.pruning.policy,
+        Some(PruningPolicy::KeepEvery {
+            interval: 100,
+            recent: 20
+        })
+    );
+
+    let sled = StorageConfig::from_json(r#"{"backend": "sled"}"#).unwrap();
+    assert_eq!(sled.backend, Backend::Sled);
+    assert!(!sled.backend.is_merkle());
+    let lmdb = StorageConfig::from_json(r#"{"backend": "lmdb"}"#).unwrap();
+    assert_eq!(lmdb.backend, Backend::Lmdb);
+
+    // typos are rejected instead of silently ignored
+    assert!(StorageConfig::from_json(r#"{"pruning": {"ver_widow": 100}}"#).is_err());
+}
+
+#[test]
+fn config_validate() {
+    let mut cfg = StorageConfig::default();
+    assert!(cfg.validate().is_ok());
+
+    cfg.snapshot.interval = 1;
+    assert!(cfg.validate().is_err());
+    cfg.pruning.ver_window = 15;
+    cfg.snapshot.interval = 10;
+    assert!(cfg.validate().is_err());
+    cfg.pruning.ver_window = 20;
+    assert!(cfg.validate().is_ok());
+
+    cfg.pruning.ver_window = 0;
+    cfg.snapshot.interval = 0;
+    cfg.pruning.cleanup_aux = true;
+    assert!(cfg.validate().is_err());
+    cfg.pruning.cleanup_aux = false;
+    cfg.pruning.policy = Some(PruningPolicy::KeepAll);
+    assert!(cfg.validate().is_err());
+
+    let mut cfg = StorageConfig::default();
+    cfg.value_log = Some(ValueLogConfig {
+        gc_live_ratio: 1.5,
+        ..Default::default()
+    });
+    assert!(cfg.validate().is_err());
+
+    let mut cfg = StorageConfig::default();
+    cfg.rocksdb.data = Some(CfConfig {
+        write_buffer_size: Some(0),
+        ..Default::default()
+    });
+    assert!(cfg.validate().is_err());
+
+    let mut cfg = StorageConfig::default();
+    cfg.rocksdb.shared_block_cache = Some("node".to_owned());
+    assert!(cfg.validate().is_err());
+    cfg.rocksdb.block_cache_size = Some(64 << 20);
+    assert!(cfg.validate().is_ok());
+}
+
+#[test]
+fn state_open_with_config() {
+    let cfg = StorageConfig {
+        backend: Backend::Memory,
+        ..Default::default()
+    };
+    let mut state = State::<MemoryDB>::open_with_config(&cfg).unwrap();
+    state.set(b"k", b"v".to_vec()).unwrap();
+    state.commit(1).unwrap();
+    assert_eq!(state.get(b"k").unwrap(), Some(b"v".to_vec()));
+
+    // the backend type must match the config
+    assert!(State::<RocksDB>::open_with_config(&cfg).is_err());
+
+    let cfg = StorageConfig {
+        backend: Backend::RocksDB,
+        path: temp_dir().join(format!("config_rocks_{}", std::process::id())),
+        ..Default::default()
+    };
+    let mut state = State::<RocksDB>::open_with_config(&cfg).unwrap();
+    state.set(b"k", b"v".to_vec()).unwrap();
+    state.commit(1).unwrap();
+    assert_eq!(state.get(b"k").unwrap(), Some(b"v".to_vec()));
+    drop(state);
+    std::fs::remove_dir_all(&cfg.path).unwrap();
+}
@@ -0,0 +1,75 @@
+use mem_db::MemoryDB;
+use storage::db::{CopyProgress, IterOrder, KValue, MerkleDB};
+use temp_db::{TempFinDB, TempRocksDB};
+
+fn source() -> MemoryDB {
+    let mut db = MemoryDB::new();
+    let batch = (0..10u8)
+        .flat_map(|i| {
+            [
+                (format!("mod1_{}", i).into_bytes(), Some(vec![i; 3])),
+                (format!("mod2_{}", i).into_bytes(), Some(vec![i; 5])),
+            ]
+        })
+        .collect::<Vec<_>>();
+    db.put_batch(batch).unwrap();
+    db.commit(vec![], true).unwrap();
+    db
+}
+
+fn entries<D: MerkleDB>(db: &D, lower: &[u8], upper: &[u8]) -> Vec<KValue> {
+    db.iter(lower, upper, IterOrder::Asc)
+        .map(|kv_pair| db.decode_kv(kv_pair))
+        .collect()
+}
+
+fn test_copy_from_impl<D: MerkleDB>(mut dst: D) {
+    let src = source();
+    assert!(dst
+        .copy_from(&src, b"mod1_", b"mod1`", 0, &mut |_| {})
+        .is_err());
+
+    let mut reports = vec![];
+    let copied = dst
+        .copy_from(&src, b"mod1_", b"mod1`", 4, &mut |p| {
+            reports.push(p.clone())
+        })
+        .unwrap();
+    assert_eq!(copied.entries, 10);
+    assert_eq!(copied.bytes, 10 * (6 + 3));
+    assert_eq!(copied.batches, 3);
+    assert_eq!(copied.last_key, b"mod1_9".to_vec());
+    assert_eq!(
+        reports.iter().map(|p| p.entries).collect::<Vec<_>>(),
+        vec![4, 8, 10]
+    );
+    assert_eq!(reports[0].last_key, b"mod1_3".to_vec());
+    assert_eq!(reports.last(), Some(&copied));
+
+    assert_eq!(
+        entries(&dst, b"mod1_", b"mod1`"),
+        entries(&src, b"mod1_", b"mod1`")
+    );
+    assert!(entries(&dst, b"mod2_", b"mod2`").is_empty());
+
+    // an empty range copies nothing
+    let copied = dst
+        .copy_from(&src, b"mod3_", b"mod3`", 4, &mut |_| panic!())
+        .unwrap();
+    assert_eq!(copied, CopyProgress::default());
+}
+
+#[test]
+fn test_copy_from_memdb() {
+    test_copy_from_impl(MemoryDB::new());
+}
+
+#[test]
+fn test_copy_from_findb() {
+    test_copy_from_impl(TempFinDB::new().unwrap());
+}
+
+#[test]
+fn test_copy_from_rocksdb() {
+    test_copy_from_impl(TempRocksDB::new().unwrap());
+}
@@ -0,0 +1,123 @@
+use parking_lot::RwLock;
+use serde_json::Value;
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+};
+use storage::{
+    explorer::Explorer,
+    schema::{KeyRule, KeySchema},
+    state::ChainState,
+    store::Prefix,
+};
+use temp_db::TempFinDB;
+
+const TOKEN: &str = "secret";
+
+fn gen_cs() -> Arc<RwLock<ChainState<TempFinDB>>> {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut cs = ChainState::new(fdb, "test".to_string(), 10);
+    let mut schema = KeySchema::new();
+    schema.register(&Prefix::new(b"acct"), KeyRule::Utf8);
+    cs.set_key_schema(schema);
+    let mut batch = (0..5u8)
+        .map(|i| (format!("acct_{}", i).into_bytes(), Some(vec![i, 0xFF])))
+        .collect::<Vec<_>>();
+    batch.push((b"cfg_a".to_vec(), Some(br#"{"fee":1}"#.to_vec())));
+    batch.push((b"cfg_b".to_vec(), Some(b"plain text".to_vec())));
+    cs.commit(batch, 1, true).unwrap();
+    cs.commit(
+        vec![(b"cfg_b".to_vec(), Some(b"changed".to_vec()))],
+        2,
+        true,
+    )
+    .unwrap();
+    Arc::new(RwLock::new(cs))
+}
+
+// status code and body of a GET
+fn get(addr: SocketAddr, target: &str, token: Option<&str>) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let auth = token
+        .map(|t| format!("Authorization: Bearer {}\r\n", t))
+        .unwrap_or_default();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: x\r\n{}\r\n", target, auth).unwrap();
+    let mut resp = vec![];
+    stream.read_to_end(&mut resp).unwrap();
+    let split = resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let status = String::from_utf8_lossy(&resp[9..12]).parse().unwrap();
+    (status, resp[split + 4..].to_vec())
+}
+
+fn get_json(addr: SocketAddr, target: &str) -> Value {
+    let (status, body) = get(addr, target, Some(TOKEN));
+    assert_eq!(status, 200, "{}", String::from_utf8_lossy(&body));
+    serde_json::from_slice(&body).unwrap()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn explorer_serves_reads() {
+    let cs = gen_cs();
+    assert!(Explorer::spawn(cs.clone(), "127.0.0.1:0", "").is_err());
+    let explorer = Explorer::spawn(cs, "127.0.0.1:0", TOKEN).unwrap();
+    let addr = explorer.local_addr();
+
+    // the page is served to anyone, the data only with the admin token
+    let (status, page) = get(addr, "/", None);
+    assert_eq!(status, 200);
+    assert!(String::from_utf8_lossy(&page).contains("/api/keys"));
+    assert_eq!(get(addr, "/api/prefixes", None).0, 401);
+    assert_eq!(get(addr, "/api/prefixes", Some("wrong")).0, 401);
+    assert_eq!(get(addr, "/other", Some(TOKEN)).0, 404);
+
+    let prefixes = get_json(addr, "/api/prefixes");
+    assert_eq!(prefixes[0]["prefix"], hex(b"acct_"));
+    assert_eq!(prefixes[0]["rules"][0], "Utf8");
+
+    // pages of 2 keys under the prefix
+    let mut keys = vec![];
+    let mut target = format!("/api/keys?prefix={}&limit=2", hex(b"acct_"));
+    loop {
+        let page = get_json(addr, &target);
+        assert_eq!(page["height"], 2);
+        let entries = page["entries"].as_array().unwrap();
+        assert!(entries.len() <= 2);
+        keys.extend(
+            entries
+                .iter()
+                .map(|e| e["text"].as_str().unwrap().to_owned()),
+        );
+        match page["next"].as_str() {
+            Some(next) => {
+                target = format!("/api/keys?prefix={}&after={}&limit=2", hex(b"acct_"), next)
+            }
+            None => break,
+        }
+    }
+    assert_eq!(keys, ["acct_0", "acct_1", "acct_2", "acct_3", "acct_4"]);
+
+    // hex and pretty views of the values
+    let page = get_json(addr, &format!("/api/keys?prefix={}", hex(b"cfg")));
+    let entries = page["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["pretty"]["kind"], "json");
+    assert_eq!(entries[0]["pretty"]["value"]["fee"], 1);
+    assert_eq!(entries[1]["pretty"]["value"], "changed");
+    assert!(page["next"].is_null());
+
+    let value = get_json(addr, &format!("/api/value?key={}", hex(b"acct_3")));
+    assert_eq!(value["entry"]["value"], hex(&[3, 0xFF]));
+    assert_eq!(value["entry"]["pretty"]["kind"], "binary");
+    let value = get_json(addr, &format!("/api/value?key={}&height=1", hex(b"cfg_b")));
+    assert_eq!(value["entry"]["pretty"]["value"], "plain text");
+    let value = get_json(addr, &format!("/api/value?key={}", hex(b"missing")));
+    assert!(value["entry"].is_null());
+
+    assert_eq!(get(addr, "/api/value?key=zz", Some(TOKEN)).0, 400);
+    explorer.stop();
+}
@@ -0,0 +1,141 @@
+//! Root hash regression tests
+//!
+//! `golden/script.txt` is replayed on every backend and the root hash after each commit is
+//! compared with the value recorded in `golden/root_hashes.txt`. A mismatch or a backend
+//! missing from the file fails, `UPDATE_GOLDEN=1` records the hashes after an intended change.
+use mem_db::MemoryDB;
+use parking_lot::{const_mutex, Mutex, RwLock};
+use std::{collections::BTreeMap, env, fs, path::PathBuf, sync::Arc};
+use storage::{
+    db::MerkleDB,
+    simulate::ValueSizeDist,
+    state::{ChainState, State},
+    testing::fixture,
+};
+use temp_db::{TempFinDB, TempRocksDB};
+
+type Golden = BTreeMap<(String, u64), String>;
+
+// backends are checked in parallel, they must not record at the same time
+static GOLDEN_FILE: Mutex<()> = const_mutex(());
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+}
+
+fn lines(text: &str) -> impl Iterator<Item = Vec<&str>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.split_whitespace().collect())
+}
+
+/// Replays the script and returns the root hash of every commit
+fn replay<D: MerkleDB>(db: D) -> Vec<(u64, Vec<u8>)> {
+    let script = fs::read_to_string(golden_dir().join("script.txt")).unwrap();
+    let cs = Arc::new(RwLock::new(ChainState::new(db, "golden".to_string(), 0)));
+    let mut state = State::new(cs, true);
+    let dist = ValueSizeDist::Uniform { min: 1, max: 64 };
+
+    let mut roots = vec![];
+    for op in lines(&script) {
+        match op.as_slice() {
+            ["put", key, value] => state
+                .set(key.as_bytes(), value.as_bytes().to_vec())
+                .unwrap(),
+            ["del", key] => state.delete(key.as_bytes()).unwrap(),
+            ["fixture", seed, n] => {
+                for (k, v) in fixture(seed.parse().unwrap(), n.parse().unwrap(), &dist) {
+                    state.set(&k, v).unwrap();
+                }
+            }
+            ["commit", height] => {
+                let (root, height) = state.commit(height.parse().unwrap()).unwrap();
+                roots.push((height, root));
+            }
+            _ => panic!("invalid golden script line {:?}", op),
+        }
+    }
+    roots
+}
+
+fn read_golden() -> Golden {
+    let text = fs::read_to_string(golden_dir().join("root_hashes.txt")).unwrap_or_default();
+    lines(&text)
+        .map(|l| match l.as_slice() {
+            [backend, height, hash] => (
+                (backend.to_string(), height.parse().unwrap()),
+                hash.to_string(),
+            ),
+            _ => panic!("invalid golden hash line {:?}", l),
+        })
+        .collect()
+}
+
+fn write_golden(golden: &Golden) {
+    let mut text = String::from(
+        "# <backend> <height> <root hash in hex, \"-\" if empty>\n\
+         # Recorded by tests/golden.rs, a mismatch means the hashing path changed and breaks consensus.\n",
+    );
+    for ((backend, height), hash) in golden {
+        text.push_str(&format!("{} {} {}\n", backend, height, hash));
+    }
+    fs::write(golden_dir().join("root_hashes.txt"), text).unwrap();
+}
+
+fn to_hex(root: &[u8]) -> String {
+    if root.is_empty() {
+        return "-".to_string();
+    }
+    root.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn check_golden<D: MerkleDB>(backend: &str, db: D) {
+    let roots = replay(db);
+    let _guard = GOLDEN_FILE.lock();
+    let mut golden = read_golden();
+    if env::var("UPDATE_GOLDEN").is_ok_and(|v| v == "1") {
+        golden.retain(|(b, _), _| b != backend);
+        for (height, root) in roots {
+            golden.insert((backend.to_string(), height), to_hex(&root));
+        }
+        write_golden(&golden);
+        eprintln!("recorded golden root hashes of {}", backend);
+        return;
+    }
+
+    assert!(
+        golden.keys().any(|(b, _)| b == backend),
+        "no golden root hashes of {}, record them with UPDATE_GOLDEN=1",
+        backend
+    );
+    for (height, root) in roots {
+        let expected = golden
+            .get(&(backend.to_string(), height))
+            .unwrap_or_else(|| panic!("no golden root hash of {} at {}", backend, height));
+        assert_eq!(
+            &to_hex(&root),
+            expected,
+            "root hash of {} at height {} changed",
+            backend,
+            height
+        );
+    }
+}
+
+#[test]
+fn golden_findb() {
+    check_golden("findb", TempFinDB::new().unwrap());
+}
+
+#[test]
+fn golden_rocks() {
+    check_golden("rocks", TempRocksDB::new().unwrap());
+}
+
+#[test]
+fn golden_mem() {
+    check_golden("mem", MemoryDB::new());
+}
@@ -0,0 +1,83 @@
+use mem_db::MemoryDB;
+use ruc::*;
+use std::ops::Bound;
+use storage::{
+    db::{IterOrder, MerkleDB},
+    kv::{KvAdapter, KvGet, KvScan, KvStore},
+};
+
+// a component written against the generic traits only
+fn record_accounts<S: KvStore + ?Sized>(store: &mut S, accounts: &[(&str, u64)]) -> Result<()> {
+    for (name, balance) in accounts {
+        let key = format!("acct_{}", name);
+        store
+            .insert(key.as_bytes(), &balance.to_be_bytes())
+            .c(d!())?;
+    }
+    store.remove(b"acct_closed")
+}
+
+fn balance<S: KvGet>(store: &S, name: &str) -> Option<u64> {
+    let value = store.get(format!("acct_{}", name).as_bytes()).unwrap()?;
+    Some(u64::from_be_bytes(value.try_into().unwrap()))
+}
+
+fn names<S: KvScan>(store: &S, prefix: &[u8]) -> Vec<Vec<u8>> {
+    store.scan_prefix(prefix).map(|(k, _)| k).collect()
+}
+
+#[test]
+fn merkle_db_as_kv_store() {
+    let mut db = MemoryDB::new();
+    db.put_batch(vec![(b"acct_alice".to_vec(), Some(b"state".to_vec()))])
+        .unwrap();
+    db.commit(
+        vec![
+            (b"Kv_bank_acct_closed".to_vec(), Some(b"0".to_vec())),
+            (b"Kv_bank_other".to_vec(), Some(b"1".to_vec())),
+            (b"Kv_other_acct_carol".to_vec(), Some(b"2".to_vec())),
+        ],
+        false,
+    )
+    .unwrap();
+    let root = db.root_hash();
+
+    assert!(KvAdapter::new(&db, b"").is_err());
+    assert!(KvAdapter::new(&db, b"a_b").is_err());
+
+    // through a trait object, like a component handed a shared store
+    let mut adapter = KvAdapter::new(&db, b"bank").unwrap();
+    let store: &mut dyn KvStore = &mut adapter;
+    record_accounts(store, &[("alice", 10), ("bob", 20)]).unwrap();
+
+    // staged writes are seen by the adapter before they are committed
+    assert_eq!(balance(&adapter, "alice"), Some(10));
+    assert_eq!(balance(&adapter, "closed"), None);
+    assert_eq!(
+        names(&adapter, b"acct_"),
+        vec![b"acct_alice".to_vec(), b"acct_bob".to_vec()]
+    );
+    let aux = adapter.into_aux();
+    db.commit(aux, false).unwrap();
+
+    // the primary data and the root hash are not touched
+    assert_eq!(db.root_hash(), root);
+    assert_eq!(db.get(b"acct_alice").unwrap(), Some(b"state".to_vec()));
+    assert_eq!(db.iter(b"acct_", b"acct~", IterOrder::Asc).count(), 1);
+
+    let adapter = KvAdapter::new(&db, b"bank").unwrap();
+    assert_eq!(balance(&adapter, "alice"), Some(10));
+    assert_eq!(balance(&adapter, "bob"), Some(20));
+    assert_eq!(balance(&adapter, "closed"), None);
+    assert_eq!(balance(&adapter, "carol"), None);
+    assert!(adapter.contains_key(b"other").unwrap());
+    assert_eq!(
+        names(&adapter, b"acct_"),
+        vec![b"acct_alice".to_vec(), b"acct_bob".to_vec()]
+    );
+    let upper = adapter
+        .range(Bound::Excluded(&b"acct_alice"[..]), Bound::Unbounded)
+        .map(|(k, _)| k)
+        .collect::<Vec<_>>();
+    assert_eq!(upper, vec![b"acct_bob".to_vec(), b"other".to_vec()]);
+}
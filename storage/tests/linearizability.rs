@@ -0,0 +1,254 @@
+//! Concurrent readers during commit, checked for linearizability
+//!
+//! A writer commits blocks while readers get, scan and read the height of the shared chain
+//! state. Every operation is recorded with its invocation and response time on a global
+//! clock, then the history is checked against a sequential model of the chain state: it must
+//! be possible to order the operations, respecting real time, so that every read returns what
+//! the model returns at that point.
+//!
+//! parking_lot and the backends are not instrumented for loom or shuttle, so schedules are
+//! explored by repeating short runs with random yields between operations.
+use mem_db::MemoryDB;
+use parking_lot::RwLock;
+use rand::Rng;
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Barrier,
+    },
+    thread,
+};
+use storage::{
+    db::{IterOrder, KValue, MerkleDB},
+    state::{ChainState, State},
+};
+use temp_db::TempRocksDB;
+
+const KEYS: u64 = 4;
+const COMMITS: u64 = 8;
+const READERS: usize = 3;
+const READS: usize = 5;
+const RUNS: usize = 50;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Commit(u64),
+    Get(Vec<u8>, Option<Vec<u8>>),
+    Height(u64),
+    Scan(Vec<KValue>),
+}
+
+#[derive(Clone, Debug)]
+struct Event {
+    call: u64,
+    ret: u64,
+    op: Op,
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("k{}", i % KEYS).into_bytes()
+}
+
+/// Writes of the block at `height`, `None` deletes the key
+fn block(height: u64) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+    let mut writes = vec![(key(height), Some(height.to_string().into_bytes()))];
+    if height % 3 == 0 {
+        writes.push((key(height + 1), None));
+    }
+    writes
+}
+
+/// Sequential model, the committed key-value map after every height
+struct Model {
+    states: Vec<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Model {
+    fn new(commits: u64) -> Self {
+        let mut states = vec![BTreeMap::new()];
+        for height in 1..=commits {
+            let mut map = states.last().cloned().unwrap_or_default();
+            for (k, v) in block(height) {
+                match v {
+                    Some(v) => map.insert(k, v),
+                    None => map.remove(&k),
+                };
+            }
+            states.push(map);
+        }
+        Model { states }
+    }
+
+    /// Applies `op` on the state at `height`, returns the next state if the output matches
+    fn apply(&self, height: u64, op: &Op) -> Option<u64> {
+        let map = &self.states[height as usize];
+        let ok = match op {
+            Op::Commit(h) => *h == height + 1,
+            Op::Get(k, v) => map.get(k) == v.as_ref(),
+            Op::Height(h) => *h == height,
+            Op::Scan(kvs) => kvs.iter().cloned().eq(map.clone().into_iter()),
+        };
+        match (ok, op) {
+            (false, _) => None,
+            (true, Op::Commit(h)) => Some(*h),
+            (true, _) => Some(height),
+        }
+    }
+}
+
+/// Checks whether `history` is linearizable with respect to `model`
+///
+/// Wing & Gong search: repeatedly linearize one of the pending operations invoked before the
+/// first pending response. Failed (linearized set, model state) pairs are memoized.
+fn linearizable(model: &Model, history: &[Event]) -> bool {
+    assert!(history.len() <= 64);
+    fn search(
+        model: &Model,
+        history: &[Event],
+        done: u64,
+        height: u64,
+        failed: &mut HashSet<(u64, u64)>,
+    ) -> bool {
+        let pending = (0..history.len())
+            .filter(|&i| done & (1 << i) == 0)
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            return true;
+        }
+        if !failed.insert((done, height)) {
+            return false;
+        }
+        let first_ret = pending.iter().map(|&i| history[i].ret).min().unwrap();
+        pending
+            .into_iter()
+            .filter(|&i| history[i].call < first_ret)
+            .any(|i| match model.apply(height, &history[i].op) {
+                Some(next) => search(model, history, done | (1 << i), next, failed),
+                None => false,
+            })
+    }
+    search(model, history, 0, 0, &mut HashSet::new())
+}
+
+fn yield_randomly() {
+    if rand::thread_rng().gen_bool(0.5) {
+        thread::yield_now();
+    }
+}
+
+/// Commits `COMMITS` blocks while readers read the shared chain state, returns the history
+fn run<D: MerkleDB + Send + Sync>(db: D) -> Vec<Event> {
+    let chain = Arc::new(RwLock::new(ChainState::new(db, "lin".to_string(), 0)));
+    let clock = AtomicU64::new(0);
+    let barrier = Barrier::new(READERS + 1);
+    let history = parking_lot::Mutex::new(vec![]);
+    let record = |call: u64, op: Op| {
+        let ret = clock.fetch_add(1, Ordering::SeqCst);
+        history.lock().push(Event { call, ret, op });
+    };
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut state = State::new(chain.clone(), false);
+            barrier.wait();
+            for height in 1..=COMMITS {
+                for (k, v) in block(height) {
+                    match v {
+                        Some(v) => state.set(&k, v).unwrap(),
+                        None => state.delete(&k).unwrap(),
+                    }
+                }
+                yield_randomly();
+                let call = clock.fetch_add(1, Ordering::SeqCst);
+                state.commit(height).unwrap();
+                record(call, Op::Commit(height));
+            }
+        });
+        for _ in 0..READERS {
+            s.spawn(|| {
+                let state = State::new(chain.clone(), false);
+                let mut rng = rand::thread_rng();
+                barrier.wait();
+                for _ in 0..READS {
+                    yield_randomly();
+                    let call = clock.fetch_add(1, Ordering::SeqCst);
+                    let op = match rng.gen_range(0..3) {
+                        0 => {
+                            let k = key(rng.gen_range(0..KEYS));
+                            let v = state.get(&k).unwrap();
+                            Op::Get(k, v)
+                        }
+                        1 => Op::Height(state.height().unwrap()),
+                        _ => {
+                            let mut kvs = vec![];
+                            state.iterate(b"k", b"l", IterOrder::Asc, &mut |kv| {
+                                kvs.push(kv);
+                                false
+                            });
+                            Op::Scan(kvs)
+                        }
+                    };
+                    record(call, op);
+                }
+            });
+        }
+    });
+    history.into_inner()
+}
+
+#[test]
+fn test_checker_rejects_stale_read() {
+    let model = Model::new(1);
+    let stale = [
+        Event {
+            call: 0,
+            ret: 1,
+            op: Op::Commit(1),
+        },
+        Event {
+            call: 2,
+            ret: 3,
+            op: Op::Get(key(1), None),
+        },
+    ];
+    assert!(!linearizable(&model, &stale));
+
+    // the same read overlapping the commit may see either state
+    let overlapping = [
+        Event {
+            call: 0,
+            ret: 3,
+            op: Op::Commit(1),
+        },
+        Event {
+            call: 1,
+            ret: 2,
+            op: Op::Get(key(1), None),
+        },
+        Event {
+            call: 4,
+            ret: 5,
+            op: Op::Height(1),
+        },
+    ];
+    assert!(linearizable(&model, &overlapping));
+}
+
+#[test]
+fn test_reads_during_commit_memdb() {
+    let model = Model::new(COMMITS);
+    for _ in 0..RUNS {
+        let history = run(MemoryDB::new());
+        assert!(linearizable(&model, &history), "{:?}", history);
+    }
+}
+
+#[test]
+fn test_reads_during_commit_rocksdb() {
+    let model = Model::new(COMMITS);
+    for _ in 0..RUNS {
+        let history = run(TempRocksDB::new().unwrap());
+        assert!(linearizable(&model, &history), "{:?}", history);
+    }
+}
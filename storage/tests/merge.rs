@@ -0,0 +1,99 @@
+use mem_db::MemoryDB;
+use parking_lot::RwLock;
+use std::{collections::BTreeSet, sync::Arc};
+use storage::{
+    db::MerkleDB,
+    merge::{decode_counter, decode_set, encode_counter, encode_set, MergeOp},
+    state::{ChainState, State},
+};
+use temp_db::{TempFinDB, TempRocksDB};
+
+fn set_of(items: &[&str]) -> Vec<u8> {
+    encode_set(&items.iter().map(|i| i.as_bytes().to_vec()).collect())
+}
+
+#[test]
+fn test_merge_ops() {
+    let op = MergeOp::CounterAdd;
+    let v = op.apply(None, &encode_counter(5)).unwrap();
+    let v = op.apply(Some(&v), &encode_counter(-7)).unwrap();
+    assert_eq!(decode_counter(&v).unwrap(), -2);
+    assert!(op.apply(Some(b"bad"), &encode_counter(1)).is_err());
+
+    let op = MergeOp::JsonMergePatch;
+    let v = op
+        .apply(
+            Some(br#"{"a":1,"b":{"c":2,"d":3}}"#),
+            br#"{"b":{"c":null,"e":4},"f":5}"#,
+        )
+        .unwrap();
+    let v: serde_json::Value = serde_json::from_slice(&v).unwrap();
+    assert_eq!(v, serde_json::json!({"a":1,"b":{"d":3,"e":4},"f":5}));
+
+    let op = MergeOp::SetUnion;
+    let v = op
+        .apply(Some(&set_of(&["x", "y"])), &set_of(&["y", "z"]))
+        .unwrap();
+    let expected = ["x", "y", "z"]
+        .iter()
+        .map(|i| i.as_bytes().to_vec())
+        .collect::<BTreeSet<_>>();
+    assert_eq!(decode_set(&v).unwrap(), expected);
+    assert!(decode_set(&[0, 0, 0, 9, 1]).is_err());
+
+    let operand = MergeOp::SetUnion.encode_operand(b"patch");
+    assert_eq!(
+        MergeOp::decode_operand(&operand).unwrap(),
+        (MergeOp::SetUnion, &b"patch"[..])
+    );
+    assert!(MergeOp::decode_operand(&[0xFF]).is_err());
+}
+
+fn test_db_merge_impl<D: MerkleDB>(mut db: D) {
+    for _ in 0..3 {
+        db.merge(b"counter", &encode_counter(2), MergeOp::CounterAdd)
+            .unwrap();
+    }
+    db.merge(b"set", &set_of(&["a"]), MergeOp::SetUnion)
+        .unwrap();
+    db.merge(b"set", &set_of(&["b"]), MergeOp::SetUnion)
+        .unwrap();
+
+    let counter = db.get(b"counter").unwrap().unwrap();
+    assert_eq!(decode_counter(&counter).unwrap(), 6);
+    assert_eq!(db.get(b"set").unwrap(), Some(set_of(&["a", "b"])));
+
+    // a put overrides earlier merges, later merges apply on top of it
+    db.put_batch(vec![(b"counter".to_vec(), Some(encode_counter(10)))])
+        .unwrap();
+    db.merge(b"counter", &encode_counter(1), MergeOp::CounterAdd)
+        .unwrap();
+    let counter = db.get(b"counter").unwrap().unwrap();
+    assert_eq!(decode_counter(&counter).unwrap(), 11);
+}
+
+#[test]
+fn test_db_merge() {
+    test_db_merge_impl(TempRocksDB::new().unwrap());
+    test_db_merge_impl(TempFinDB::new().unwrap());
+    test_db_merge_impl(MemoryDB::new());
+}
+
+#[test]
+fn test_state_merge() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs.clone(), true);
+
+    state
+        .merge(b"hits", &encode_counter(3), MergeOp::CounterAdd)
+        .unwrap();
+    state.commit(1).unwrap();
+    state
+        .merge(b"hits", &encode_counter(4), MergeOp::CounterAdd)
+        .unwrap();
+    state.commit(2).unwrap();
+
+    let hits = cs.read().get(b"hits").unwrap().unwrap();
+    assert_eq!(decode_counter(&hits).unwrap(), 7);
+}
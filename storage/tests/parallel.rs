@@ -0,0 +1,83 @@
+use parking_lot::RwLock;
+use ruc::*;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use storage::{
+    parallel,
+    state::{ChainState, State},
+};
+use temp_db::TempFinDB;
+
+// moves one unit from `from` to `to`, fails if `from` is empty
+fn transfer<D: storage::db::MerkleDB>(state: &mut State<D>, from: &[u8], to: &[u8]) -> Result<u8> {
+    let balance =
+        |state: &State<D>, key: &[u8]| -> Result<u8> { Ok(state.get(key)?.map_or(0, |v| v[0])) };
+    let from_balance = balance(state, from)?;
+    if from_balance == 0 {
+        return Err(eg!("insufficient balance"));
+    }
+    let to_balance = balance(state, to)?;
+    state.set(from, vec![from_balance - 1])?;
+    state.set(to, vec![to_balance + 1])?;
+    Ok(from_balance - 1)
+}
+
+fn new_state(name: &str) -> State<TempFinDB> {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, name.to_string(), 0)));
+    let mut state = State::new(cs, true);
+    state.set(b"alice", vec![2]).unwrap();
+    state.set(b"bob", vec![0]).unwrap();
+    state.set(b"carol", vec![1]).unwrap();
+    state.set(b"dave", vec![0]).unwrap();
+    state.commit(1).unwrap();
+    state
+}
+
+#[test]
+fn parallel_execute_matches_serial() {
+    let txs: Vec<(&[u8], &[u8])> = vec![
+        (b"alice", b"bob"),
+        (b"carol", b"dave"),
+        (b"bob", b"carol"),
+        (b"dave", b"alice"),
+        (b"alice", b"bob"),
+        (b"alice", b"bob"),
+        (b"alice", b"bob"),
+        (b"bob", b"dave"),
+    ];
+
+    let mut serial = new_state("serial");
+    let expected = txs
+        .iter()
+        .map(|(from, to)| transfer(&mut serial, from, to).ok())
+        .collect::<Vec<_>>();
+
+    let mut state = new_state("parallel");
+    let runs = AtomicUsize::new(0);
+    let results = parallel::execute(&mut state, &txs, |(from, to), lane| {
+        runs.fetch_add(1, Ordering::Relaxed);
+        transfer(lane, from, to)
+    })
+    .unwrap();
+    // a conflicting transaction runs again once
+    assert!(runs.into_inner() <= 2 * txs.len());
+    let actual = results.into_iter().map(|r| r.ok()).collect::<Vec<_>>();
+    assert_eq!(expected, actual);
+    // the third alice transfer fails
+    assert_eq!(actual[6], None);
+
+    for key in [&b"alice"[..], b"bob", b"carol", b"dave"] {
+        assert_eq!(state.get(key).unwrap(), serial.get(key).unwrap());
+    }
+}
+
+#[test]
+fn parallel_execute_empty_block() {
+    let mut state = new_state("empty");
+    let txs: Vec<u8> = vec![];
+    let results = parallel::execute(&mut state, &txs, |_, _| Ok(())).unwrap();
+    assert!(results.is_empty());
+}
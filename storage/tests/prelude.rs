@@ -0,0 +1,20 @@
+use parking_lot::RwLock;
+use std::sync::Arc;
+use temp_db::prelude::*;
+
+fn roundtrip<D: MerkleDB>(db: D) -> Result<()> {
+    let chain = Arc::new(RwLock::new(ChainState::new(db, "prelude".to_string(), 0)));
+    let mut state = State::new(chain, false);
+    let mut store = PrefixedStore::new("prelude", &mut state);
+    let key = store.prefix().push(b"k10");
+    store.set(key.as_ref(), b"v10".to_vec())?;
+    assert_eq!(store.get(key.as_ref())?, Some(b"v10".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn test_prelude_imports() {
+    roundtrip(MemoryDB::new()).unwrap();
+    roundtrip(TempFinDB::new().unwrap()).unwrap();
+    roundtrip(TempRocksDB::new().unwrap()).unwrap();
+}
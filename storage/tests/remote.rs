@@ -0,0 +1,184 @@
+use mem_db::MemoryDB;
+use ruc::*;
+use std::{
+    collections::BTreeSet,
+    ops::Bound,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use storage::{
+    chained::ChainedDB,
+    db::{DbIter, IterOrder, KVBatch, KValue, MerkleDB},
+    remote::{ProofVerifier, RemoteClient, RemoteDB, RemoteResponse},
+    state::ChainState,
+};
+
+// the proof of the test node is the root followed by the json encoded values
+const ROOT: &[u8] = b"root1";
+
+struct TestNode {
+    db: MemoryDB,
+    tamper: bool,
+}
+
+impl RemoteClient for TestNode {
+    fn get_with_proof(&self, keys: &[Vec<u8>]) -> Result<RemoteResponse> {
+        let mut values = keys
+            .iter()
+            .map(|k| self.db.get(k))
+            .collect::<Result<Vec<_>>>()?;
+        let mut proof = ROOT.to_vec();
+        proof.extend(serde_json::to_vec(&values).c(d!())?);
+        if self.tamper {
+            values = vec![Some(b"forged".to_vec()); keys.len()];
+        }
+        Ok(RemoteResponse { values, proof })
+    }
+}
+
+struct TestVerifier;
+
+impl ProofVerifier for TestVerifier {
+    fn verify(&self, root: &[u8], _keys: &[Vec<u8>], proof: &[u8]) -> Result<Vec<Option<Vec<u8>>>> {
+        let values = proof.strip_prefix(root).c(d!("proof doesn't match root"))?;
+        serde_json::from_slice(values).c(d!())
+    }
+}
+
+fn node(tamper: bool) -> TestNode {
+    let mut db = MemoryDB::new();
+    db.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+        .unwrap();
+    db.commit(vec![], true).unwrap();
+    TestNode { db, tamper }
+}
+
+#[test]
+fn remote_db_verified_reads() {
+    let remote = RemoteDB::new(node(false), TestVerifier, ROOT.to_vec());
+    assert_eq!(remote.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+    assert_eq!(remote.get(b"k20").unwrap(), None);
+
+    let keys = vec![b"k10".to_vec(), b"k20".to_vec()];
+    let resp = remote.prove(&keys).unwrap();
+    assert_eq!(resp.values, vec![Some(b"v10".to_vec()), None]);
+    assert!(resp.proof.starts_with(ROOT));
+
+    // proofs against another root are rejected
+    remote.set_trusted_root(b"root2".to_vec());
+    assert!(remote.get(b"k10").is_err());
+}
+
+#[test]
+fn remote_db_rejects_forged_values() {
+    let remote = RemoteDB::new(node(true), TestVerifier, ROOT.to_vec());
+    assert!(remote.get(b"k10").is_err());
+}
+
+#[test]
+fn remote_db_as_fallback() {
+    let remote = RemoteDB::new(node(false), TestVerifier, ROOT.to_vec());
+    let db = ChainedDB::new(MemoryDB::new()).with_fallback(remote);
+    assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+}
+
+/// Local db with the root of the test node whose reads of `missing` keys fail until the keys
+/// are written again
+struct BrokenDB {
+    db: MemoryDB,
+    missing: Mutex<BTreeSet<Vec<u8>>>,
+}
+
+impl MerkleDB for BrokenDB {
+    fn root_hash(&self) -> Vec<u8> {
+        ROOT.to_vec()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.missing.lock().unwrap().contains(key) {
+            return Err(eg!("missing node"));
+        }
+        self.db.get(key)
+    }
+
+    fn get_aux(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_aux(key)
+    }
+
+    fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
+        for (k, _) in kvs.iter() {
+            self.missing.lock().unwrap().remove(k);
+        }
+        self.db.put_batch(kvs)
+    }
+
+    fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.db.iter(lower, upper, order)
+    }
+
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        self.db.iter_bounds(lower, upper, order)
+    }
+
+    fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
+        self.db.iter_aux(lower, upper, order)
+    }
+
+    fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_> {
+        self.db.db_all_iterator(order)
+    }
+
+    fn commit(&mut self, kvs: KVBatch, flush: bool) -> Result<()> {
+        self.db.commit(kvs, flush)
+    }
+
+    fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.db.snapshot(path)
+    }
+
+    fn decode_kv(&self, kv_pair: (Box<[u8]>, Box<[u8]>)) -> KValue {
+        self.db.decode_kv(kv_pair)
+    }
+
+    fn clean_aux(&mut self) -> Result<()> {
+        self.db.clean_aux()
+    }
+}
+
+#[test]
+fn chain_state_heals_missing_nodes() {
+    let db = BrokenDB {
+        db: MemoryDB::new(),
+        missing: Mutex::new(BTreeSet::from([b"k10".to_vec()])),
+    };
+    let mut cs = ChainState::new(db, "heal".to_string(), 0);
+    assert!(cs.get(b"k10").is_err());
+
+    // reads are served from the peer, proven against the local root
+    cs.set_missing_node_resolver(Arc::new(RemoteDB::new(node(false), TestVerifier, vec![])));
+    assert_eq!(cs.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+    assert_eq!(cs.unrepaired(), vec![b"k10".to_vec()]);
+
+    // the value is written back locally
+    assert_eq!(cs.repair_missing().unwrap(), 1);
+    assert!(cs.unrepaired().is_empty());
+    assert_eq!(cs.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+
+    // a forged peer value is never returned
+    let mut cs = ChainState::new(
+        BrokenDB {
+            db: MemoryDB::new(),
+            missing: Mutex::new(BTreeSet::from([b"k10".to_vec()])),
+        },
+        "heal".to_string(),
+        0,
+    );
+    cs.set_missing_node_resolver(Arc::new(RemoteDB::new(node(true), TestVerifier, vec![])));
+    assert!(cs.get(b"k10").is_err());
+    assert!(cs.unrepaired().is_empty());
+}
@@ -0,0 +1,67 @@
+use mem_db::MemoryDB;
+use storage::{
+    db::{IterOrder, KValue, MerkleDB},
+    session::SessionedDB,
+};
+use temp_db::TempFinDB;
+
+fn kv(k: &[u8], v: &[u8]) -> KValue {
+    (k.to_vec(), v.to_vec())
+}
+
+fn test_sessioned_db_impl<D: MerkleDB>(mut db: D) {
+    db.put_batch(vec![
+        (b"k1".to_vec(), Some(b"v1".to_vec())),
+        (b"k3".to_vec(), Some(b"v3".to_vec())),
+        (b"k5".to_vec(), Some(b"v5".to_vec())),
+    ])
+    .unwrap();
+    db.commit(vec![], true).unwrap();
+
+    let mut sdb = SessionedDB::new(db, true);
+    sdb.put(b"k2", b"v2".to_vec()).unwrap();
+    sdb.put(b"k3", b"v3'".to_vec()).unwrap();
+    sdb.delete(b"k5");
+    sdb.commit_session();
+
+    // a failed transaction is rolled back
+    sdb.put(b"k4", b"v4".to_vec()).unwrap();
+    sdb.delete(b"k1");
+    sdb.discard_session();
+    assert_eq!(sdb.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(sdb.get(b"k4").unwrap(), None);
+
+    let expected = vec![kv(b"k1", b"v1"), kv(b"k2", b"v2"), kv(b"k3", b"v3'")];
+    assert_eq!(
+        sdb.iter(b"k", b"l", IterOrder::Asc).collect::<Vec<_>>(),
+        expected
+    );
+    assert_eq!(
+        sdb.iter(b"k", b"l", IterOrder::Desc).collect::<Vec<_>>(),
+        expected.iter().rev().cloned().collect::<Vec<_>>()
+    );
+    assert_eq!(sdb.db().get(b"k2").unwrap(), None);
+
+    // deletes of keys the backend doesn't have are dropped
+    sdb.delete(b"k9");
+    sdb.commit(vec![], true).unwrap();
+    let db = sdb.into_inner();
+    assert_eq!(db.get(b"k3").unwrap(), Some(b"v3'".to_vec()));
+    assert_eq!(db.get(b"k5").unwrap(), None);
+    assert_eq!(
+        db.iter(b"k", b"l", IterOrder::Asc)
+            .map(|kv_pair| db.decode_kv(kv_pair))
+            .collect::<Vec<_>>(),
+        expected
+    );
+}
+
+#[test]
+fn test_sessioned_db_memdb() {
+    test_sessioned_db_impl(MemoryDB::new());
+}
+
+#[test]
+fn test_sessioned_db_findb() {
+    test_sessioned_db_impl(TempFinDB::new().unwrap());
+}
@@ -0,0 +1,79 @@
+use mem_db::MemoryDB;
+use storage::{
+    db::MerkleDB,
+    merge::MergeOp,
+    shadow::{Mismatch, ShadowDB},
+};
+
+#[test]
+fn shadow_db_dual_write() {
+    let mut db = ShadowDB::new(MemoryDB::new(), MemoryDB::new()).with_root_check(true);
+    db.put_batch(vec![
+        (b"k10".to_vec(), Some(b"v10".to_vec())),
+        (b"k20".to_vec(), Some(b"v20".to_vec())),
+    ])
+    .unwrap();
+    db.commit(vec![(b"a10".to_vec(), Some(b"x".to_vec()))], true)
+        .unwrap();
+
+    assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+    assert_eq!(db.get_aux(b"a10").unwrap(), Some(b"x".to_vec()));
+    // writes reach both backends
+    assert_eq!(db.secondary().get(b"k20").unwrap(), Some(b"v20".to_vec()));
+    assert_eq!(db.secondary().get_aux(b"a10").unwrap(), Some(b"x".to_vec()));
+
+    let report = db.report();
+    assert_eq!(report.reads, 2);
+    assert_eq!(report.commits, 1);
+    assert_eq!(report.mismatches, 0);
+}
+
+#[test]
+fn shadow_db_forwards_writes() {
+    let mut db = ShadowDB::new(MemoryDB::new(), MemoryDB::new()).with_root_check(true);
+    db.put_batch_ref(&[(b"k10", Some(b"v10")), (b"k20", Some(b"v20"))])
+        .unwrap();
+    db.merge(b"n", &5i64.to_be_bytes(), MergeOp::CounterAdd)
+        .unwrap();
+    db.delete_range(b"k15", b"k30").unwrap();
+    db.commit(vec![], true).unwrap();
+
+    for backend in [db.primary(), db.secondary()] {
+        assert_eq!(backend.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(backend.get(b"k20").unwrap(), None);
+        assert_eq!(
+            backend.get(b"n").unwrap(),
+            Some(5i64.to_be_bytes().to_vec())
+        );
+    }
+
+    // reads into a buffer are compared as well
+    let mut buf = vec![];
+    assert_eq!(db.get_into(b"k10", &mut buf).unwrap(), Some(3));
+    assert_eq!(buf, b"v10".to_vec());
+    let report = db.report();
+    assert_eq!((report.reads, report.mismatches), (1, 0));
+}
+
+#[test]
+fn shadow_db_reports_mismatches() {
+    let mut secondary = MemoryDB::new();
+    secondary
+        .put_batch(vec![(b"k30".to_vec(), Some(b"stale".to_vec()))])
+        .unwrap();
+    secondary.commit(vec![], true).unwrap();
+
+    let mut db = ShadowDB::new(MemoryDB::new(), secondary);
+    db.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+        .unwrap();
+    db.commit(vec![], true).unwrap();
+
+    // reads are served by the primary
+    assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+    assert_eq!(db.get(b"k30").unwrap(), None);
+
+    let report = db.report();
+    assert_eq!(report.reads, 2);
+    assert_eq!(report.mismatches, 1);
+    assert_eq!(report.recent, vec![Mismatch::Get(b"k30".to_vec())]);
+}
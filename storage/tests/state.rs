@@ -2,11 +2,20 @@ use fin_db::FinDB;
 use mem_db::MemoryDB;
 use parking_lot::RwLock;
 use rand::Rng;
+use ruc::*;
 use std::{sync::Arc, thread};
 use storage::{
-    db::{IterOrder, KVBatch, KValue, MerkleDB},
-    state::{ChainState, ChainStateOpts, State},
+    db::{IterOpts, IterOrder, KVBatch, KValue, MerkleDB, ScanControl},
+    schema::{strict_keys, KeyRule, KeySchema},
+    simulate::ValueSizeDist,
+    snapshot::header_path,
+    state::{
+        is_iterator_invalidated, is_not_yet_available, iter::SNAPSHOT_ITER_STEP, AccessList,
+        ChainState, ChainStateOpts, CommitToken, ConflictKind, ExpiryEvent, ExpiryHook,
+        PrefixMigration, State,
+    },
     store::Prefix,
+    testing::{fixture, populate},
 };
 use temp_db::{TempFinDB, TempRocksDB};
 
@@ -127,6 +136,83 @@ fn test_iterate_rocks() {
     test_iterate_impl(gen_cs_rocks(path));
 }
 
+fn test_iterate_with_opts_impl<D: MerkleDB>(mut cs: ChainState<D>) {
+    let batch = vec![
+        (b"k10".to_vec(), Some(b"v10".to_vec())),
+        (b"k20".to_vec(), Some(b"v20".to_vec())),
+        (b"k30".to_vec(), Some(b"v30".to_vec())),
+    ];
+    cs.commit(batch, 26, true).unwrap();
+
+    // read hints never change the result of an iteration
+    let mut expected = vec![];
+    cs.iterate(b"k10", b"k31", IterOrder::Desc, &mut |kv| {
+        expected.push(kv);
+        false
+    });
+    let opts = IterOpts::scan().with_pin_data(true);
+    let mut actual = vec![];
+    cs.iterate_with_opts(b"k10", b"k31", IterOrder::Desc, &opts, &mut |kv| {
+        actual.push(kv);
+        false
+    });
+    assert_eq!(expected.len(), 3);
+    assert_eq!(expected, actual);
+}
+
+#[test]
+fn test_iterate_with_opts() {
+    let path = thread::current().name().unwrap().to_owned();
+    test_iterate_with_opts_impl(gen_cs(path));
+}
+
+#[test]
+fn test_iterate_with_opts_rocks() {
+    let path = thread::current().name().unwrap().to_owned();
+    test_iterate_with_opts_impl(gen_cs_rocks(path));
+}
+
+fn test_scan_apply_impl<D: MerkleDB>(mut cs: ChainState<D>) {
+    let batch = vec![
+        (b"k10".to_vec(), Some(b"v10".to_vec())),
+        (b"k20".to_vec(), Some(b"v20".to_vec())),
+        (b"k30".to_vec(), Some(b"v30".to_vec())),
+    ];
+    cs.commit(batch, 26, true).unwrap();
+
+    // sees the same entries as `iterate`
+    let mut expected = vec![];
+    cs.iterate(b"k10", b"k31", IterOrder::Desc, &mut |kv| {
+        expected.push(kv);
+        false
+    });
+    let mut actual = vec![];
+    let visited = cs.scan_apply(b"k10", b"k31", IterOrder::Desc, &mut |k, v| {
+        actual.push((k.to_vec(), v.to_vec()));
+        ScanControl::Continue
+    });
+    assert_eq!(visited, 3);
+    assert_eq!(expected, actual);
+
+    // early termination
+    let visited = cs.scan_apply(b"k10", b"k31", IterOrder::Asc, &mut |_, _| {
+        ScanControl::Stop
+    });
+    assert_eq!(visited, 1);
+}
+
+#[test]
+fn test_scan_apply() {
+    let path = thread::current().name().unwrap().to_owned();
+    test_scan_apply_impl(gen_cs(path));
+}
+
+#[test]
+fn test_scan_apply_rocks() {
+    let path = thread::current().name().unwrap().to_owned();
+    test_scan_apply_impl(gen_cs_rocks(path));
+}
+
 fn test_exists_impl<D: MerkleDB>(mut cs: ChainState<D>) {
     // commit data
     cs.commit(
@@ -750,7 +836,9 @@ fn commit_n_snapshot(
 ) -> (Vec<u8>, u64) {
     let (hash, h) = cs.commit(batch, height, true).unwrap();
     let path_cp = format!("{}_{}_snap", path, height);
-    cs.snapshot(path_cp).unwrap();
+    cs.snapshot(&path_cp).unwrap();
+    // the snapshot itself is removed by the `TempFinDB` opening it, its header isn't
+    let _ = std::fs::remove_file(header_path(&path_cp));
     (hash, h)
 }
 
@@ -979,3 +1067,718 @@ fn test_state_at() {
         .get_ver(b"k10", 2)
         .map_or(false, |v| v == Some(b"v210".to_vec())));
 }
+
+#[test]
+fn test_get_with_token() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs.clone(), true);
+
+    state.set(b"k10", b"v10".to_vec()).unwrap();
+    let (_, token) = state.commit_with_token(1).unwrap();
+    assert_eq!(token, CommitToken::new(1));
+    assert_eq!(state.commit_token().unwrap(), token);
+    assert_eq!(
+        state.get_with_token(b"k10", token).unwrap(),
+        Some(b"v10".to_vec())
+    );
+
+    // a client which has seen a later commit must retry
+    let err = state
+        .get_with_token(b"k10", CommitToken::new(2))
+        .unwrap_err();
+    assert!(is_not_yet_available(&*err));
+
+    state.set(b"k10", b"v11".to_vec()).unwrap();
+    let (_, next) = state.commit_with_token(2).unwrap();
+    assert!(next > token);
+    assert_eq!(
+        cs.read().get_with_token(b"k10", next).unwrap(),
+        Some(b"v11".to_vec())
+    );
+}
+
+#[test]
+fn test_populate() {
+    let dist = ValueSizeDist::Uniform { min: 1, max: 256 };
+    let mut fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut rdb = TempRocksDB::new().expect("failed to create temp rocksdb");
+    populate(&mut fdb, 42, 25_000, &dist).unwrap();
+    populate(&mut rdb, 42, 25_000, &dist).unwrap();
+
+    for (key, value) in fixture(42, 25_000, &dist) {
+        assert_eq!(fdb.get(&key).unwrap(), Some(value.clone()));
+        assert_eq!(rdb.get(&key).unwrap(), Some(value));
+    }
+
+    // a different seed gives a different state
+    let mut other = TempFinDB::new().expect("failed to create temp findb");
+    populate(&mut other, 43, 25_000, &dist).unwrap();
+    assert_ne!(fdb.root_hash(), other.root_hash());
+}
+
+#[test]
+fn test_delete_prefix_tombstones() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs.clone(), true);
+
+    let events = Prefix::new(b"events");
+    for i in 0..10u32 {
+        let key = events.push(format!("{:02}", i).as_bytes());
+        state.set(key.as_ref(), b"event".to_vec()).unwrap();
+    }
+    state.set(b"other_k", b"v".to_vec()).unwrap();
+    state.commit(1).unwrap();
+
+    // keys written in the current block are deleted as well
+    state
+        .set(events.push(b"10").as_ref(), b"event".to_vec())
+        .unwrap();
+    assert_eq!(state.delete_prefix(&events).unwrap(), 11);
+    assert_eq!(state.get(events.push(b"05").as_ref()).unwrap(), None);
+    state.commit(2).unwrap();
+    assert_eq!(state.get(b"other_k").unwrap(), Some(b"v".to_vec()));
+    assert_eq!(cs.read().pending_tombstones(), 10);
+
+    // the prefix is compacted once the threshold is reached
+    cs.write().set_compaction_threshold(12);
+    for i in 0..2u32 {
+        let key = events.push(format!("{:02}", i).as_bytes());
+        state.set(key.as_ref(), b"event".to_vec()).unwrap();
+    }
+    state.commit(3).unwrap();
+    state.delete_prefix(&events).unwrap();
+    state.commit(4).unwrap();
+    assert_eq!(cs.read().pending_tombstones(), 0);
+
+    // deletes below the threshold wait for a forced compaction
+    state.delete(b"other_k").unwrap();
+    state.commit(5).unwrap();
+    assert_eq!(cs.read().pending_tombstones(), 1);
+    assert_eq!(cs.write().compact_pending().unwrap(), 1);
+    assert_eq!(cs.read().pending_tombstones(), 0);
+}
+
+#[test]
+fn test_merge_lanes() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs, true);
+    state.set(b"k1", b"v1".to_vec()).unwrap();
+    state.set(b"k2", b"v2".to_vec()).unwrap();
+    state.commit(1).unwrap();
+
+    // disjoint lanes merge in lane order
+    let mut lane_a = state.substate();
+    let mut lane_b = state.substate();
+    lane_a.record_access();
+    lane_b.record_access();
+    let v1 = lane_a.get(b"k1").unwrap().unwrap();
+    lane_a.set(b"k1", [v1, b"a".to_vec()].concat()).unwrap();
+    lane_b.get(b"k2").unwrap();
+    lane_b.delete(b"k2").unwrap();
+    let report = state.merge_lanes(&[lane_a, lane_b]).unwrap();
+    assert!(report.is_empty());
+    assert_eq!(state.get(b"k1").unwrap(), Some(b"v1a".to_vec()));
+    assert_eq!(state.get(b"k2").unwrap(), None);
+
+    // the second lane read and wrote a key written by the first one
+    let mut lane_a = state.substate();
+    let mut lane_b = state.substate();
+    lane_a.record_access();
+    lane_b.record_access();
+    lane_a.set(b"k1", b"a".to_vec()).unwrap();
+    lane_b.get(b"k1").unwrap();
+    lane_b.set(b"k1", b"b".to_vec()).unwrap();
+    let report = state.merge_lanes(&[lane_a, lane_b]).unwrap();
+    let kinds = report.conflicts.iter().map(|c| c.kind).collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        vec![ConflictKind::WriteWrite, ConflictKind::ReadWrite]
+    );
+    assert_eq!(report.lanes().into_iter().collect::<Vec<_>>(), vec![1]);
+    assert_eq!(state.get(b"k1").unwrap(), Some(b"v1a".to_vec()));
+
+    // the second lane iterated a range holding a key written by the first one
+    let mut lane_a = state.substate();
+    let mut lane_b = state.substate();
+    lane_a.record_access();
+    lane_b.record_access();
+    lane_a.set(b"k3", b"a".to_vec()).unwrap();
+    lane_b.iterate(b"k0", b"k9", IterOrder::Asc, &mut |_| false);
+    lane_b.set(b"sum", b"2".to_vec()).unwrap();
+    assert_eq!(
+        lane_b.access_list().unwrap().ranges(),
+        &[(b"k0".to_vec(), b"k9".to_vec())]
+    );
+    let report = state.merge_lanes(&[lane_a, lane_b]).unwrap();
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(report.conflicts[0].key, b"k3".to_vec());
+    assert_eq!(report.conflicts[0].kind, ConflictKind::ReadWrite);
+    assert_eq!(state.get(b"k3").unwrap(), None);
+
+    // sequence increments are merged, lanes incrementing the same sequence conflict
+    let mut lane_a = state.substate();
+    let mut lane_b = state.substate();
+    lane_a.record_access();
+    lane_b.record_access();
+    assert_eq!(lane_a.next_sequence("tx").unwrap(), 1);
+    assert_eq!(lane_b.next_sequence("tx").unwrap(), 1);
+    let report = state.merge_lanes(&[lane_a, lane_b]).unwrap();
+    assert_eq!(report.lanes().into_iter().collect::<Vec<_>>(), vec![1]);
+    let mut lane_a = state.substate();
+    let mut lane_b = state.substate();
+    lane_a.record_access();
+    lane_b.record_access();
+    assert_eq!(lane_a.next_sequence("tx").unwrap(), 1);
+    assert_eq!(lane_a.next_sequence("tx").unwrap(), 2);
+    assert_eq!(lane_b.next_sequence("other").unwrap(), 1);
+    assert!(state.merge_lanes(&[lane_a, lane_b]).unwrap().is_empty());
+    assert_eq!(state.next_sequence("tx").unwrap(), 3);
+    assert_eq!(state.next_sequence("other").unwrap(), 2);
+
+    // lanes must record their accesses
+    let lane = state.substate();
+    assert!(state.merge_lanes(&[lane]).is_err());
+}
+
+#[test]
+fn test_prefetch() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs.clone(), true);
+    state.set(b"k1", b"v1".to_vec()).unwrap();
+    state.set(b"k2", b"v2".to_vec()).unwrap();
+    state.commit(1).unwrap();
+
+    let mut tx1 = AccessList::new();
+    tx1.record_read(b"k1");
+    tx1.record_write(b"k2");
+    let mut tx2 = AccessList::new();
+    tx2.record_read(b"k1");
+    tx2.record_read(b"k3");
+    assert_eq!(state.prefetch(&[tx1.clone(), tx2.clone()]).unwrap(), 3);
+    // keys already prefetched are not loaded again
+    assert_eq!(state.prefetch(&[tx1]).unwrap(), 0);
+
+    // reads are served from the prefetched values, writes still win
+    assert_eq!(state.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+    assert!(!state.exists(b"k3").unwrap());
+    let lane = state.substate();
+    assert_eq!(lane.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+    state.set(b"k2", b"v3".to_vec()).unwrap();
+    assert_eq!(state.get(b"k2").unwrap(), Some(b"v3".to_vec()));
+
+    // values prefetched before a commit to the chain state are no longer served
+    cs.write()
+        .commit(vec![(b"k1".to_vec(), Some(b"x".to_vec()))], 2, true)
+        .unwrap();
+    assert_eq!(state.get(b"k1").unwrap(), Some(b"x".to_vec()));
+    assert_eq!(lane.get(b"k1").unwrap(), Some(b"x".to_vec()));
+    let mut buf = vec![];
+    assert_eq!(state.get_into(b"k1", &mut buf).unwrap(), Some(1));
+    assert_eq!(buf, b"x".to_vec());
+    // and are read again at the new root
+    assert_eq!(state.prefetch(&[tx2]).unwrap(), 2);
+
+    // prefetched values are dropped at commit
+    state.commit(3).unwrap();
+    assert_eq!(state.prefetch(&[]).unwrap(), 0);
+    assert_eq!(state.get(b"k1").unwrap(), Some(b"x".to_vec()));
+}
+
+#[test]
+fn test_delete_range() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 10)));
+    let mut state = State::new(cs.clone(), true);
+    for bucket in ["t1_a", "t1_b", "t2_a", "t3_a"] {
+        state.set(bucket.as_bytes(), b"v".to_vec()).unwrap();
+    }
+    state.commit(1).unwrap();
+
+    // committed and cached keys are deleted, keys deleted before are not counted
+    state.set(b"t2_b", b"v".to_vec()).unwrap();
+    state.delete(b"t1_b").unwrap();
+    assert_eq!(state.delete_range(b"t1", b"t3").unwrap(), 3);
+    assert_eq!(state.get(b"t2_b").unwrap(), None);
+    state.commit(2).unwrap();
+
+    assert_eq!(state.get(b"t1_a").unwrap(), None);
+    assert_eq!(state.get(b"t3_a").unwrap(), Some(b"v".to_vec()));
+    // the history still has the deleted keys
+    assert_eq!(state.get_ver(b"t1_a", 1).unwrap(), Some(b"v".to_vec()));
+    assert_eq!(state.get_ver(b"t2_a", 2).unwrap(), None);
+}
+
+fn test_db_delete_range_impl<D: MerkleDB>(mut db: D) {
+    db.put_batch(vec![
+        (b"k10".to_vec(), Some(b"v10".to_vec())),
+        (b"k20".to_vec(), Some(b"v20".to_vec())),
+        (b"k30".to_vec(), Some(b"v30".to_vec())),
+    ])
+    .unwrap();
+    db.commit(vec![], true).unwrap();
+
+    db.delete_range(b"k10", b"k30").unwrap();
+    db.commit(vec![], true).unwrap();
+    assert_eq!(db.get(b"k10").unwrap(), None);
+    assert_eq!(db.get(b"k20").unwrap(), None);
+    assert_eq!(db.get(b"k30").unwrap(), Some(b"v30".to_vec()));
+    assert_eq!(db.iter(b"k", b"l", IterOrder::Asc).count(), 1);
+
+    // an empty range is a no-op
+    db.delete_range(b"k40", b"k50").unwrap();
+}
+
+#[test]
+fn test_db_delete_range() {
+    test_db_delete_range_impl(TempFinDB::new().expect("failed to create temp findb"));
+    test_db_delete_range_impl(TempRocksDB::new().expect("failed to create temp rocksdb"));
+    test_db_delete_range_impl(MemoryDB::new());
+}
+
+#[test]
+fn test_last_n_under_prefix() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs, true);
+    let blocks = Prefix::new(b"blocks");
+    for i in 0..10u32 {
+        let key = blocks.push(format!("{:02}", i).as_bytes());
+        state.set(key.as_ref(), i.to_string().into_bytes()).unwrap();
+    }
+    state.set(b"blocksz", b"other".to_vec()).unwrap();
+    state.commit(1).unwrap();
+
+    let keys = |kvs: Vec<KValue>| kvs.into_iter().map(|(_, v)| v).collect::<Vec<_>>();
+    assert_eq!(
+        keys(state.last_n_under_prefix(&blocks, 3)),
+        vec![b"9".to_vec(), b"8".to_vec(), b"7".to_vec()]
+    );
+    assert_eq!(state.last_n_under_prefix(&blocks, 20).len(), 10);
+    assert!(state.last_n_under_prefix(&blocks, 0).is_empty());
+
+    // uncommitted writes and deletes are taken into account
+    state
+        .set(blocks.push(b"10").as_ref(), b"10".to_vec())
+        .unwrap();
+    state.delete(blocks.push(b"09").as_ref()).unwrap();
+    state.delete(blocks.push(b"08").as_ref()).unwrap();
+    assert_eq!(
+        keys(state.last_n_under_prefix(&blocks, 3)),
+        vec![b"10".to_vec(), b"7".to_vec(), b"6".to_vec()]
+    );
+}
+
+#[test]
+fn test_next_sequence() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs.clone(), true);
+
+    assert_eq!(state.next_sequence("events").unwrap(), 1);
+    assert_eq!(state.next_sequence("events").unwrap(), 2);
+    assert_eq!(state.next_sequence("orders").unwrap(), 1);
+    state.commit_session().unwrap();
+
+    // increments of a discarded session are dropped
+    assert_eq!(state.next_sequence("events").unwrap(), 3);
+    state.discard_session();
+    assert_eq!(state.next_sequence("events").unwrap(), 3);
+    assert_eq!(cs.read().get_sequence("events").unwrap(), 0);
+
+    state.commit(1).unwrap();
+    assert_eq!(cs.read().get_sequence("events").unwrap(), 3);
+    assert_eq!(cs.read().get_sequence("orders").unwrap(), 1);
+
+    // a new state goes on from the committed value
+    let mut state = State::new(cs, true);
+    assert_eq!(state.next_sequence("events").unwrap(), 4);
+}
+
+#[test]
+fn test_nested_transactions() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs.clone(), true);
+    assert!(state.commit_nested().is_err());
+
+    state.set(b"k1", b"v1".to_vec()).unwrap();
+    assert_eq!(state.begin(), 1);
+    state.set(b"k1", b"v1'".to_vec()).unwrap();
+    state.next_sequence("calls").unwrap();
+    assert_eq!(state.begin(), 2);
+    state.set(b"k2", b"v2".to_vec()).unwrap();
+    assert_eq!(state.begin(), 3);
+    state.delete(b"k1").unwrap();
+    state.next_sequence("calls").unwrap();
+
+    // a reverted frame drops its writes and sequence increments only
+    state.rollback_nested().unwrap();
+    assert_eq!(state.get(b"k1").unwrap(), Some(b"v1'".to_vec()));
+    state.commit_nested().unwrap();
+    assert_eq!(state.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+    assert_eq!(state.nested_depth(), 1);
+    state.rollback_nested().unwrap();
+    assert_eq!(state.nested_depth(), 0);
+    assert!(state.rollback_nested().is_err());
+
+    assert_eq!(state.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(state.get(b"k2").unwrap(), None);
+    assert_eq!(state.next_sequence("calls").unwrap(), 1);
+    state.commit(1).unwrap();
+    assert_eq!(cs.read().get(b"k1").unwrap(), Some(b"v1".to_vec()));
+
+    // open nested transactions block commits, none survives one
+    state.begin();
+    state.set(b"k3", b"v3".to_vec()).unwrap();
+    assert!(state.commit(2).is_err());
+    assert!(state.commit_session().is_err());
+    state.commit_nested().unwrap();
+    state.commit(2).unwrap();
+    assert!(state.rollback_nested().is_err());
+    assert_eq!(state.get(b"k3").unwrap(), Some(b"v3".to_vec()));
+    assert_eq!(cs.read().get(b"k3").unwrap(), Some(b"v3".to_vec()));
+}
+
+#[test]
+fn test_prefix_migration() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 10)));
+    let mut state = State::new(cs.clone(), true);
+    for i in 0..6 {
+        let key = format!("old_k{}", i).into_bytes();
+        state.set(&key, vec![i]).unwrap();
+    }
+    state.commit(1).unwrap();
+
+    let (old, new) = (Prefix::new(b"old"), Prefix::new(b"new"));
+    assert!(PrefixMigration::new(&old, &old.push(b"k")).is_err());
+    let migration = PrefixMigration::new(&old, &new)
+        .unwrap()
+        .with_splits(vec![b"k3".to_vec()])
+        .unwrap();
+    assert_eq!(migration.sub_ranges(), 2);
+    state.start_migration("m", migration.clone()).unwrap();
+    assert!(state.start_migration("m", migration.clone()).is_err());
+
+    // writes go to the new prefix while reads see both
+    state
+        .set_migrating(&migration, b"k4", b"v4".to_vec())
+        .unwrap();
+    assert_eq!(state.get(b"old_k4").unwrap(), None);
+    assert_eq!(
+        state.get_migrating(&migration, b"k4").unwrap(),
+        Some(b"v4".to_vec())
+    );
+    assert_eq!(
+        state.get_migrating(&migration, b"k0").unwrap(),
+        Some(vec![0])
+    );
+
+    let progress = state.migrate_step("m", 2).unwrap();
+    assert_eq!((progress.moved(), progress.completed()), (2, 0));
+    assert_eq!(state.get(b"new_k1").unwrap(), Some(vec![1]));
+    assert_eq!(state.get(b"old_k1").unwrap(), None);
+    state.commit(2).unwrap();
+    assert_eq!(
+        cs.read().get_migration("m").unwrap(),
+        Some(progress.clone())
+    );
+
+    // the progress is part of the state, only the migration writes it
+    let progress_key = Prefix::new(b"MIG").push(b"m");
+    assert!(cs.read().get(progress_key.as_ref()).unwrap().is_some());
+    assert!(state.set(progress_key.as_ref(), vec![]).is_err());
+    assert!(state.delete(progress_key.as_ref()).is_err());
+
+    // a rolled back step leaves the progress as it was
+    let mut state = State::new(cs.clone(), true);
+    state.begin();
+    state.migrate_step("m", 1).unwrap();
+    state.rollback_nested().unwrap();
+    assert_eq!(state.migration("m").unwrap(), Some(progress));
+
+    // the progress is persisted, a step goes on into the next sub-range
+    let progress = state.migrate_step("m", 2).unwrap();
+    assert_eq!((progress.moved(), progress.completed()), (4, 1));
+    assert!(progress.is_migrated(b"k2"));
+    assert!(!progress.is_migrated(b"k5"));
+    let progress = state.migrate_step("m", 10).unwrap();
+    assert!(progress.is_complete());
+    assert_eq!(progress.moved(), 5);
+
+    for i in 0..6 {
+        let key = format!("k{}", i).into_bytes();
+        let expected = if i == 4 { b"v4".to_vec() } else { vec![i] };
+        assert_eq!(
+            state.get_migrating(&progress, &key).unwrap(),
+            Some(expected)
+        );
+    }
+    assert!(state.last_n_under_prefix(&old, 1).is_empty());
+    state.delete_migrating(&progress, b"k0").unwrap();
+    assert_eq!(state.get_migrating(&progress, b"k0").unwrap(), None);
+    state.commit(3).unwrap();
+    assert!(cs.read().get_migration("m").unwrap().unwrap().is_complete());
+}
+
+#[test]
+fn test_rename_key_and_move_prefix() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 10)));
+    let mut state = State::new(cs.clone(), true);
+
+    state.set(b"old_a", b"a1".to_vec()).unwrap();
+    state.set(b"old_b", b"b1".to_vec()).unwrap();
+    state.set(b"key_x", b"x1".to_vec()).unwrap();
+    state.commit(1).unwrap();
+    state.set(b"old_a", b"a2".to_vec()).unwrap();
+    state.set(b"key_x", b"x2".to_vec()).unwrap();
+    state.commit(2).unwrap();
+
+    // renames are dropped with a discarded session
+    assert!(state.rename_key(b"key_x", b"key_y", true).unwrap());
+    state.discard_session();
+    assert_eq!(state.get(b"key_x").unwrap(), Some(b"x2".to_vec()));
+
+    assert!(!state.rename_key(b"key_none", b"key_y", true).unwrap());
+    assert!(state.rename_key(b"key_x", b"old_a", true).is_err());
+    assert!(state.rename_key(b"key_x", b"key_y", true).unwrap());
+    // a pending write under the new prefix makes it non-empty
+    state.set(b"new_c", b"c1".to_vec()).unwrap();
+    assert!(state
+        .move_prefix(&Prefix::new(b"old"), &Prefix::new(b"new"), true)
+        .is_err());
+    state.delete(b"new_c").unwrap();
+    assert_eq!(
+        state
+            .move_prefix(&Prefix::new(b"old"), &Prefix::new(b"new"), false)
+            .unwrap(),
+        2
+    );
+    state.commit(3).unwrap();
+
+    let chain = cs.read();
+    assert_eq!(chain.get(b"key_x").unwrap(), None);
+    assert_eq!(chain.get(b"key_y").unwrap(), Some(b"x2".to_vec()));
+    assert_eq!(chain.get(b"old_a").unwrap(), None);
+    assert_eq!(chain.get(b"new_a").unwrap(), Some(b"a2".to_vec()));
+    assert_eq!(chain.get(b"new_b").unwrap(), Some(b"b1".to_vec()));
+
+    // the history of the renamed key is copied, the one of the moved prefix isn't
+    assert_eq!(chain.get_ver(b"key_y", 1).unwrap(), Some(b"x1".to_vec()));
+    assert_eq!(chain.get_ver(b"key_y", 2).unwrap(), Some(b"x2".to_vec()));
+    assert_eq!(chain.get_ver(b"new_a", 1).unwrap(), None);
+}
+
+#[test]
+fn test_key_schema() {
+    let mut schema = KeySchema::new();
+    schema
+        .register(&Prefix::new(b"acct"), KeyRule::FixedLen(4))
+        .register(&Prefix::new(b"blk"), KeyRule::Utf8)
+        .register(
+            &Prefix::new(b"blk"),
+            KeyRule::Segments {
+                sep: b'_',
+                parts: 2,
+            },
+        );
+    assert!(schema.validate(b"acct_abcd").is_ok());
+    assert!(schema.validate(b"acct_abc").is_err());
+    assert!(schema.validate(b"blk_10_2").is_ok());
+    assert!(schema.validate(b"blk_10_").is_err());
+    assert!(schema
+        .validate(&[b'b', b'l', b'k', b'_', 0xFF, b'_', b'1'])
+        .is_err());
+    // keys under no registered prefix are not checked
+    assert!(schema.validate(b"other").is_ok());
+    assert_eq!(
+        schema
+            .prefixes()
+            .map(|(p, rules)| (p.to_vec(), rules.len()))
+            .collect::<Vec<_>>(),
+        vec![(b"acct_".to_vec(), 1), (b"blk_".to_vec(), 2)]
+    );
+
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let mut chain = ChainState::new(fdb, "test_db".to_string(), 0);
+    chain.set_key_schema(schema);
+    let mut state = State::new(Arc::new(RwLock::new(chain)), true);
+    assert!(state.set(b"acct_abcd", b"v".to_vec()).is_ok());
+    assert_eq!(state.set(b"acct_ab", b"v".to_vec()).is_err(), strict_keys());
+}
+
+#[test]
+fn test_get_into() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 10)));
+    let mut state = State::new(cs, true);
+    state.set(b"k10", b"v10".to_vec()).unwrap();
+    state.set(b"k20", b"v20".to_vec()).unwrap();
+    state.commit(1).unwrap();
+    state.set(b"k10", b"value11".to_vec()).unwrap();
+    state.delete(b"k20").unwrap();
+    state.set(b"k30", b"v30".to_vec()).unwrap();
+
+    // the buffer is overwritten, its allocation reused
+    let mut buf = Vec::with_capacity(64);
+    let capacity = buf.capacity();
+    assert_eq!(state.get_into(b"k10", &mut buf).unwrap(), Some(7));
+    assert_eq!(buf, b"value11");
+    assert_eq!(state.get_into(b"k20", &mut buf).unwrap(), None);
+    assert!(buf.is_empty());
+    assert_eq!(state.get_into(b"k30", &mut buf).unwrap(), Some(3));
+    assert_eq!(buf, b"v30");
+    assert_eq!(buf.capacity(), capacity);
+
+    // committed values are read from the db
+    state.commit(2).unwrap();
+    let keys: [&[u8]; 4] = [b"k10", b"k20", b"k30", b"k40"];
+    let mut bufs = vec![];
+    let lens = state.get_many_into(&keys, &mut bufs).unwrap();
+    assert_eq!(lens, vec![Some(7), None, Some(3), None]);
+    assert_eq!(bufs[0], b"value11");
+    assert_eq!(bufs[2], b"v30");
+    for (key, buf) in keys.iter().zip(&bufs) {
+        assert_eq!(state.get(key).unwrap().unwrap_or_default(), *buf);
+    }
+
+    let lens = state.get_many_into(&keys[..2], &mut bufs).unwrap();
+    assert_eq!(lens, vec![Some(7), None]);
+    assert_eq!(bufs.len(), 2);
+
+    // a db read keeps a buffer able to hold the value
+    assert_eq!(state.get_into(b"k10", &mut buf).unwrap(), Some(7));
+    assert_eq!(buf, b"value11");
+    assert_eq!(buf.capacity(), capacity);
+}
+
+#[test]
+fn test_key_expiry() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 10)));
+    let mut state = State::new(cs, true);
+    state.set(b"funds_alice", b"0".to_vec()).unwrap();
+    state.commit(1).unwrap();
+    assert!(state.set_with_ttl(b"order_1", b"x".to_vec(), 1).is_err());
+
+    // expired orders unlock their funds in the same commit
+    let hook: ExpiryHook<TempFinDB> =
+        Arc::new(|state: &mut State<TempFinDB>, event: &ExpiryEvent| {
+            assert_eq!(event.key, b"order_1");
+            state.set(b"funds_alice", event.value.clone())
+        });
+    state.on_expire(&Prefix::new(b"order"), hook);
+    let expiries = state.subscribe_expiries(8);
+
+    state.set_with_ttl(b"order_1", b"100".to_vec(), 3).unwrap();
+    state.set_with_ttl(b"order_2", b"200".to_vec(), 3).unwrap();
+    state.set_with_ttl(b"order_2", b"200".to_vec(), 5).unwrap();
+    state.set_with_ttl(b"order_3", b"300".to_vec(), 3).unwrap();
+    state.clear_ttl(b"order_3").unwrap();
+    assert_eq!(state.ttl(b"order_1").unwrap(), Some(3));
+    assert_eq!(state.ttl(b"order_3").unwrap(), None);
+    state.commit(2).unwrap();
+    assert!(expiries.try_recv().is_err());
+
+    state.commit(3).unwrap();
+    assert_eq!(state.get(b"order_1").unwrap(), None);
+    assert_eq!(state.ttl(b"order_1").unwrap(), None);
+    assert_eq!(state.get(b"funds_alice").unwrap(), Some(b"100".to_vec()));
+    assert_eq!(state.get(b"order_2").unwrap(), Some(b"200".to_vec()));
+    assert_eq!(state.get(b"order_3").unwrap(), Some(b"300".to_vec()));
+    let event = expiries.try_recv().unwrap();
+    assert_eq!(
+        event,
+        ExpiryEvent {
+            height: 3,
+            key: b"order_1".to_vec(),
+            value: b"100".to_vec(),
+        }
+    );
+    assert!(expiries.try_recv().is_err());
+    // the expiry is part of the versioned history
+    let cs = state.chain_state();
+    assert_eq!(
+        cs.read().get_ver(b"funds_alice", 2).unwrap(),
+        Some(b"0".to_vec())
+    );
+
+    // a height without commit is caught up by the next one
+    state.commit(6).unwrap();
+    assert_eq!(state.get(b"order_2").unwrap(), None);
+    assert_eq!(expiries.try_recv().unwrap().key, b"order_2");
+
+    // the expiry index can't be written through the state
+    state.set_with_ttl(b"bid_1", b"400".to_vec(), 7).unwrap();
+    assert!(state.set(b"TTL_Key_bid_1", b"9".to_vec()).is_err());
+    assert!(state.delete(b"TTL_Key_bid_1").is_err());
+    assert_eq!(state.delete_range(b"T", b"U").unwrap(), 0);
+    assert_eq!(state.ttl(b"bid_1").unwrap(), Some(7));
+
+    // a failing hook fails the commit without deleting anything
+    let failing: ExpiryHook<TempFinDB> =
+        Arc::new(|_: &mut State<TempFinDB>, _: &ExpiryEvent| Err(eg!("hook failed")));
+    state.on_expire(&Prefix::new(b"bid"), failing);
+    assert!(state.commit(7).is_err());
+    assert_eq!(state.get(b"bid_1").unwrap(), Some(b"400".to_vec()));
+    assert_eq!(state.ttl(b"bid_1").unwrap(), Some(7));
+    assert!(expiries.try_recv().is_err());
+}
+
+#[test]
+fn test_snapshot_iter() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 10)));
+    let mut state = State::new(cs.clone(), true);
+    let n = SNAPSHOT_ITER_STEP + 10;
+    for i in 0..n {
+        state
+            .set(format!("key_{:05}", i).as_bytes(), b"v1".to_vec())
+            .unwrap();
+    }
+    state.commit(1).unwrap();
+
+    // the writes of the session are merged over the chain state
+    state.delete(b"key_00001").unwrap();
+    state.set(b"key_00002", b"v2".to_vec()).unwrap();
+    state.set(b"key_99999", b"v2".to_vec()).unwrap();
+    let pairs = state
+        .snapshot_iter(b"key_", b"key~")
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(pairs.len(), n);
+    assert_eq!(pairs[1], (b"key_00002".to_vec(), b"v2".to_vec()));
+    assert_eq!(pairs[n - 1], (b"key_99999".to_vec(), b"v2".to_vec()));
+
+    // the step read before the commit is yielded, the next one fails
+    let mut iter = state.snapshot_iter(b"key_", b"key~").unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0, b"key_00000".to_vec());
+    state.set(b"key_00003", b"v3".to_vec()).unwrap();
+    state.commit(2).unwrap();
+    let mut read = 1;
+    let err = loop {
+        match iter.next().unwrap() {
+            Ok((_, value)) => {
+                assert_ne!(value, b"v3".to_vec());
+                read += 1;
+            }
+            Err(e) => break e,
+        }
+    };
+    assert!(is_iterator_invalidated(&*err));
+    assert_eq!(read, SNAPSHOT_ITER_STEP - 1);
+    assert!(iter.next().is_none());
+
+    // a commit of another session before the first step fails it right away
+    let mut iter = state.snapshot_iter(b"key_", b"key~").unwrap();
+    cs.write()
+        .commit(vec![(b"key_00004".to_vec(), None)], 3, true)
+        .unwrap();
+    assert!(is_iterator_invalidated(&*iter.next().unwrap().unwrap_err()));
+}
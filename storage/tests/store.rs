@@ -3,9 +3,9 @@ use rand::Rng;
 use ruc::*;
 use std::sync::Arc;
 use std::{thread, time};
-use storage::db::{IterOrder, KValue, MerkleDB};
+use storage::db::{IterOrder, KValue, MerkleDB, PrefixExtractor};
 use storage::state::{ChainState, State};
-use storage::store::{Prefix, PrefixedStore, Stated, Store};
+use storage::store::{DecodeMode, Module, Prefix, PrefixedStore, SnapshotStrategy, Stated, Store};
 use temp_db::{TempFinDB, TempRocksDB};
 
 const VER_WINDOW: u64 = 100;
@@ -165,6 +165,50 @@ fn prefixed_store() {
     assert_eq!(store.get(b"k30").unwrap(), None);
 }
 
+#[test]
+fn store_scoped() {
+    let path = thread::current().name().unwrap().to_owned();
+    let fdb = TempFinDB::open(path).expect("failed to open db");
+    let cs = Arc::new(RwLock::new(ChainState::new(
+        fdb,
+        "test_db".to_string(),
+        VER_WINDOW,
+    )));
+    let mut state = State::new(cs, true);
+
+    // two modules sharing one db, same keys in each
+    let mut bank = PrefixedStore::with_prefix(Prefix::new(b"bank"), &mut state);
+    bank.put_scoped(b"alice", b"10".to_vec()).unwrap();
+    bank.put_scoped(b"bob", b"20".to_vec()).unwrap();
+    assert_eq!(bank.scoped_key(b"alice"), b"bank_alice".to_vec());
+
+    let mut stake = PrefixedStore::with_prefix(Prefix::new(b"stake"), &mut state);
+    stake.put_scoped(b"alice", b"30".to_vec()).unwrap();
+    assert_eq!(stake.get_scoped(b"bob").unwrap(), None);
+    stake.state_mut().commit(1).unwrap();
+
+    let mut bank = PrefixedStore::with_prefix(Prefix::new(b"bank"), &mut state);
+    assert_eq!(bank.get_scoped(b"alice").unwrap(), Some(b"10".to_vec()));
+    assert_eq!(bank.get(b"bank_bob").unwrap(), Some(b"20".to_vec()));
+
+    // iteration covers db and cache, and only this store
+    bank.delete_scoped(b"alice").unwrap();
+    bank.put_scoped(b"carol", b"40".to_vec()).unwrap();
+    assert_eq!(
+        bank.iter_scoped().collect::<Vec<_>>(),
+        vec![
+            (b"bob".to_vec(), b"20".to_vec()),
+            (b"carol".to_vec(), b"40".to_vec()),
+        ]
+    );
+
+    let stake = PrefixedStore::with_prefix(Prefix::new(b"stake"), &mut state);
+    assert_eq!(
+        stake.iter_scoped().collect::<Vec<_>>(),
+        vec![(b"alice".to_vec(), b"30".to_vec())]
+    );
+}
+
 #[test]
 fn store_stake() {
     // create State
@@ -1109,3 +1153,298 @@ fn test_iterate_rocks() {
     let cs = gen_cs_rocks(path);
     test_iterate_impl(cs);
 }
+
+#[test]
+fn store_iter_obj() {
+    let path = thread::current().name().unwrap().to_owned();
+    let fdb = TempFinDB::open(path).expect("failed to open db");
+    let cs = Arc::new(RwLock::new(ChainState::new(
+        fdb,
+        "test_db".to_string(),
+        VER_WINDOW,
+    )));
+    let mut state = State::new(cs, true);
+    let mut store = StakeStore::new("stake", &mut state);
+    let validators = Prefix::new(b"stake").push(b"validator");
+
+    store.stake("fra1111", 100).unwrap();
+    store.stake("fra2222", 200).unwrap();
+    store.state_mut().commit(1).unwrap();
+    // an amount stored in cache only and a broken value
+    store.stake("fra3333", 300).unwrap();
+    store
+        .set(validators.push(b"fra4444").as_ref(), b"oops".to_vec())
+        .unwrap();
+
+    // skip bad pairs
+    let range = store
+        .iter_obj::<String, u64>(validators.clone(), DecodeMode::Skip)
+        .unwrap();
+    let expected = vec![
+        ("fra1111".to_owned(), 100),
+        ("fra2222".to_owned(), 200),
+        ("fra3333".to_owned(), 300),
+    ];
+    assert_eq!(range.items, expected);
+    assert!(range.failures.is_empty());
+
+    // collect bad pairs
+    let range = store
+        .iter_obj::<String, u64>(validators.clone(), DecodeMode::Collect)
+        .unwrap();
+    assert_eq!(range.items, expected);
+    assert_eq!(range.failures.len(), 1);
+    assert_eq!(range.failures[0].key, validators.push(b"fra4444").as_ref());
+
+    // fail on first bad pair
+    assert!(store
+        .iter_obj::<String, u64>(validators, DecodeMode::FailFast)
+        .is_err());
+}
+
+#[test]
+fn prefix_extractor_ranges() {
+    let module = PrefixExtractor::Module;
+    let pfx = Prefix::new(b"staking").push(b"validators");
+    assert_eq!(module.prefix(pfx.as_ref()), Some(&b"staking_"[..]));
+    assert_eq!(
+        module.range_prefix(&pfx.begin(), &pfx.end()),
+        Some(&b"staking_"[..])
+    );
+    // the top level range also covers keys of other modules, e.g. `stakingx_`
+    let top = Prefix::new(b"staking");
+    assert_eq!(module.range_prefix(&top.begin(), &top.end()), None);
+    assert_eq!(module.prefix(b"nosplit"), None);
+
+    let fixed = PrefixExtractor::Fixed(4);
+    assert_eq!(fixed.prefix(b"abcdef"), Some(&b"abcd"[..]));
+    assert_eq!(fixed.prefix(b"abc"), None);
+    assert_eq!(fixed.range_prefix(b"abcd_0", b"abcd_9"), Some(&b"abcd"[..]));
+    assert_eq!(fixed.range_prefix(b"abcd", b"abce"), None);
+}
+
+const BANK: Module = Module::new("bank");
+
+#[test]
+fn module_facade() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs, true);
+
+    let balances = BANK.map::<String, u64>("balances");
+    let alice = "alice".to_string();
+    assert_eq!(balances.get(&state, &alice).unwrap(), None);
+    assert!(balances.load(&state, &alice).is_err());
+    balances.save(&mut state, &alice, &10).unwrap();
+    balances
+        .update(&mut state, &"bob".to_string(), |b| Ok(b.unwrap_or(0) + 5))
+        .unwrap();
+    assert_eq!(balances.key(&alice), b"bank_balances_alice".to_vec());
+    assert!(balances.has(&state, &alice).unwrap());
+    state.commit(1).unwrap();
+
+    let range = balances.range(&state, DecodeMode::FailFast).unwrap();
+    assert_eq!(
+        range.items,
+        vec![(alice.clone(), 10), ("bob".to_string(), 5)]
+    );
+    balances.remove(&mut state, &alice).unwrap();
+    assert_eq!(
+        balances
+            .range(&state, DecodeMode::Skip)
+            .unwrap()
+            .items
+            .len(),
+        1
+    );
+
+    // items and sequences next to the map don't fall in its range
+    let supply = BANK.item::<u64>("balances");
+    supply.save(&mut state, &15).unwrap();
+    assert_eq!(supply.load(&state).unwrap(), 15);
+    let ids = BANK.seq("ids");
+    assert_eq!(ids.current(&state).unwrap(), 0);
+    assert_eq!(ids.next(&mut state).unwrap(), 1);
+    assert_eq!(ids.next(&mut state).unwrap(), 2);
+    assert_eq!(
+        balances
+            .range(&state, DecodeMode::Skip)
+            .unwrap()
+            .items
+            .len(),
+        1
+    );
+
+    // the raw store sees the same data
+    let store = BANK.store(&mut state);
+    assert_eq!(store.get(b"bank_ids").unwrap(), Some(b"2".to_vec()));
+}
+
+#[test]
+fn module_queue() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs, true);
+
+    let withdrawals = BANK.queue::<(String, u64)>("withdrawals");
+    assert_eq!(withdrawals.pop_front(&mut state).unwrap(), None);
+    assert!(withdrawals.is_empty(&state).unwrap());
+
+    for (i, who) in ["alice", "bob", "carol"].iter().enumerate() {
+        let index = withdrawals
+            .push_back(&mut state, &(who.to_string(), i as u64))
+            .unwrap();
+        assert_eq!(index, i as u64);
+    }
+    state.commit(1).unwrap();
+
+    assert_eq!(withdrawals.len(&state).unwrap(), 3);
+    assert_eq!(withdrawals.peek(&state).unwrap().unwrap().0, "alice");
+    assert_eq!(
+        withdrawals.pop_front(&mut state).unwrap().unwrap().0,
+        "alice"
+    );
+    // indexes aren't reused after a pop
+    assert_eq!(
+        withdrawals
+            .push_back(&mut state, &("dave".to_string(), 3))
+            .unwrap(),
+        3
+    );
+    state.commit(2).unwrap();
+
+    let entries = withdrawals.entries(&state).unwrap();
+    assert_eq!(
+        entries.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+    while withdrawals.pop_front(&mut state).unwrap().is_some() {}
+    assert_eq!(withdrawals.len(&state).unwrap(), 0);
+    assert_eq!(withdrawals.peek(&state).unwrap(), None);
+}
+
+// (email, country, age)
+type Account = (String, String, u64);
+
+#[test]
+fn module_indexed_map() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs, true);
+
+    let accounts = BANK
+        .indexed_map::<String, Account>("accounts")
+        .unique_index("email", |a| a.0.as_bytes().to_vec())
+        .multi_index("country", |a| a.1.as_bytes().to_vec())
+        .multi_index("age", |a| a.2.to_be_bytes().to_vec());
+    let account = |email: &str, country: &str, age| (email.to_string(), country.to_string(), age);
+
+    accounts
+        .save(&mut state, &"alice".to_string(), &account("a@x", "fr", 30))
+        .unwrap();
+    accounts
+        .save(&mut state, &"bob".to_string(), &account("b@x", "fr", 20))
+        .unwrap();
+    state.commit(1).unwrap();
+    accounts
+        .save(&mut state, &"carol".to_string(), &account("c@x", "de", 40))
+        .unwrap();
+
+    // a taken unique key is refused without writing anything
+    assert!(accounts
+        .save(&mut state, &"dave".to_string(), &account("a@x", "it", 50))
+        .is_err());
+    assert!(!accounts.has(&state, &"dave".to_string()).unwrap());
+
+    let keys =
+        |entries: Vec<(String, Account)>| entries.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(
+        keys(accounts.by_index(&state, "email", b"b@x").unwrap()),
+        ["bob"]
+    );
+    assert_eq!(
+        keys(accounts.by_index(&state, "country", b"fr").unwrap()),
+        ["alice", "bob"]
+    );
+    // ranges are in index key order, uncommitted entries included
+    let ages = accounts
+        .index_range(&state, "age", &25u64.to_be_bytes(), &u64::MAX.to_be_bytes())
+        .unwrap();
+    assert_eq!(keys(ages), ["alice", "carol"]);
+    assert!(accounts.by_index(&state, "missing", b"x").is_err());
+
+    // updates move index entries
+    accounts
+        .save(&mut state, &"alice".to_string(), &account("a2@x", "de", 30))
+        .unwrap();
+    state.commit(2).unwrap();
+    assert!(accounts
+        .by_index(&state, "email", b"a@x")
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        keys(accounts.by_index(&state, "country", b"de").unwrap()),
+        ["alice", "carol"]
+    );
+    accounts
+        .save(&mut state, &"dave".to_string(), &account("a@x", "it", 50))
+        .unwrap();
+
+    accounts.remove(&mut state, &"carol".to_string()).unwrap();
+    assert_eq!(
+        keys(accounts.by_index(&state, "country", b"de").unwrap()),
+        ["alice"]
+    );
+    assert!(accounts
+        .by_index(&state, "email", b"c@x")
+        .unwrap()
+        .is_empty());
+    // index entries don't show up in the map
+    assert_eq!(
+        accounts
+            .range(&state, DecodeMode::FailFast)
+            .unwrap()
+            .items
+            .len(),
+        3
+    );
+}
+
+#[test]
+fn module_snapshot_map() {
+    let fdb = TempFinDB::new().expect("failed to create temp findb");
+    let cs = Arc::new(RwLock::new(ChainState::new(fdb, "test_db".to_string(), 0)));
+    let mut state = State::new(cs, true);
+    let alice = "alice".to_string();
+
+    let every = BANK.snapshot_map::<String, u64>("power", SnapshotStrategy::EveryHeight);
+    let selected = BANK.snapshot_map::<String, u64>("power2", SnapshotStrategy::Checkpoints);
+    selected.add_checkpoint(&mut state, 2).unwrap();
+    // (height, new value), alice changes twice at height 3
+    for (height, power) in [(1, 10), (3, 30), (3, 31), (5, 50)] {
+        every.save(&mut state, &alice, &power, height).unwrap();
+        selected.save(&mut state, &alice, &power, height).unwrap();
+        state.commit(height).unwrap();
+    }
+
+    assert_eq!(every.get_at_height(&state, &alice, 1).unwrap(), None);
+    assert_eq!(every.get_at_height(&state, &alice, 2).unwrap(), Some(10));
+    assert_eq!(every.get_at_height(&state, &alice, 3).unwrap(), Some(10));
+    assert_eq!(every.get_at_height(&state, &alice, 4).unwrap(), Some(31));
+    assert_eq!(every.get_at_height(&state, &alice, 6).unwrap(), Some(50));
+
+    // the change at 3 after the checkpoint at 2 is recorded, the one at 5 isn't
+    assert_eq!(selected.get_at_height(&state, &alice, 2).unwrap(), Some(10));
+    assert!(selected.get_at_height(&state, &alice, 4).is_err());
+    assert_eq!(selected.get(&state, &alice).unwrap(), Some(50));
+
+    every.remove(&mut state, &alice, 6).unwrap();
+    assert_eq!(every.get_at_height(&state, &alice, 6).unwrap(), Some(50));
+    assert_eq!(every.get_at_height(&state, &alice, 7).unwrap(), None);
+    // history doesn't show up in the map
+    assert!(every
+        .range(&state, DecodeMode::FailFast)
+        .unwrap()
+        .items
+        .is_empty());
+}
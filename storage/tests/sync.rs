@@ -0,0 +1,70 @@
+use std::{env::temp_dir, fs, time::Duration};
+use storage::state::{ChunkSet, ChunkTracker};
+
+#[test]
+fn chunk_tracker_rarest_first() {
+    let mut tracker = ChunkTracker::new(b"snapshot".to_vec(), 4);
+    tracker.set_peer("a", ChunkSet::full(4));
+    // chunk 3 is only held by a, chunks 0..3 by both peers
+    let mut b = ChunkSet::new(4);
+    (0..3).for_each(|c| b.insert(c));
+    tracker.set_peer("b", b);
+
+    assert_eq!(tracker.request(&"a", 2), vec![3, 0]);
+    // requests in flight to another peer aren't handed out twice
+    assert_eq!(tracker.request(&"b", 8), vec![1, 2]);
+    assert!(tracker.request(&"a", 2).is_empty());
+
+    assert!(tracker.complete(3).unwrap());
+    assert!(!tracker.complete(3).unwrap());
+    tracker.fail(0);
+    // b is gone, its chunks go back to a
+    assert_eq!(tracker.remove_peer(&"b"), vec![1, 2]);
+    assert_eq!(tracker.request(&"a", 3), vec![0, 1, 2]);
+    assert_eq!(tracker.expire(Duration::from_secs(60)), vec![]);
+    assert_eq!(tracker.expire(Duration::ZERO).len(), 3);
+    assert_eq!(tracker.missing(), 3);
+    assert!(tracker.complete(9).is_err());
+}
+
+#[test]
+fn chunk_set_from_bytes() {
+    let set = ChunkSet::from_bytes(&[0b1000_0001, 0b0000_0100], 11).unwrap();
+    assert!(set.contains(0) && set.contains(7) && set.contains(10));
+    assert_eq!(set.count(), 3);
+    assert_eq!(ChunkSet::from_bytes(set.as_bytes(), 11).unwrap(), set);
+
+    // bits past the last chunk, or a bitmap of another length
+    assert!(ChunkSet::from_bytes(&[0, 0b0000_1000], 11).is_err());
+    assert!(ChunkSet::from_bytes(&[0, 0], 17).is_err());
+    assert!(ChunkSet::from_bytes(&[0xFF, 0xFF], 16).is_ok());
+}
+
+#[test]
+fn chunk_tracker_resume() {
+    let path = temp_dir().join(format!("sync_{}.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut tracker = ChunkTracker::<u32>::open(&path, b"snapshot".to_vec(), 10).unwrap();
+    tracker.complete(1).unwrap();
+    tracker.complete(7).unwrap();
+    drop(tracker);
+
+    // a restart resumes the same snapshot
+    let mut tracker = ChunkTracker::<u32>::open(&path, b"snapshot".to_vec(), 10).unwrap();
+    assert_eq!(tracker.missing(), 8);
+    assert!(tracker.done().contains(7));
+    tracker.peer_has(1, 7);
+    tracker.peer_has(1, 8);
+    assert_eq!(tracker.request(&1, 4), vec![8]);
+
+    // progress of another snapshot is discarded
+    let tracker = ChunkTracker::<u32>::open(&path, b"other".to_vec(), 10).unwrap();
+    assert_eq!(tracker.missing(), 10);
+    drop(tracker);
+
+    let tracker = ChunkTracker::<u32>::open(&path, b"snapshot".to_vec(), 10).unwrap();
+    assert_eq!(tracker.missing(), 8);
+    tracker.finish().unwrap();
+    assert!(!path.exists());
+}
@@ -0,0 +1,89 @@
+use mem_db::MemoryDB;
+use std::{thread, time::Duration};
+use storage::{
+    db::{IterOrder, MerkleDB, ScanControl},
+    merge::MergeOp,
+    tracked::{IterLimits, TrackedDB},
+};
+
+fn gen_db(limits: IterLimits) -> TrackedDB<MemoryDB> {
+    let mut db = TrackedDB::new(MemoryDB::new(), limits);
+    db.put_batch(vec![
+        (b"k1".to_vec(), Some(b"v1".to_vec())),
+        (b"k2".to_vec(), Some(b"v2".to_vec())),
+    ])
+    .unwrap();
+    db
+}
+
+#[test]
+fn tracked_counts_iterators() {
+    let db = gen_db(IterLimits {
+        max_open: Some(2),
+        ..Default::default()
+    });
+    {
+        let a = db.try_iter(b"k", b"l", IterOrder::Asc).unwrap();
+        let b = db.iter(b"k", b"l", IterOrder::Desc);
+        assert_eq!(a.count(), 2);
+        assert_eq!(db.stats().open, 1);
+        assert!(db.try_iter(b"k", b"l", IterOrder::Asc).is_ok());
+        let _c = db.iter(b"k", b"l", IterOrder::Asc);
+        // the limit is reached
+        assert!(db.try_iter(b"k", b"l", IterOrder::Asc).is_err());
+        drop(b);
+    }
+    let stats = db.stats();
+    assert_eq!(stats.open, 0);
+    assert_eq!(stats.peak, 2);
+    assert_eq!(stats.opened, 4);
+    assert_eq!(stats.over_limit, 1);
+}
+
+#[test]
+fn tracked_forwards_scans_and_writes() {
+    let mut db = gen_db(IterLimits::default());
+    let mut keys = vec![];
+    let visited = db.scan_apply(b"k", b"l", IterOrder::Asc, &mut |k, _| {
+        keys.push(k.to_vec());
+        ScanControl::Continue
+    });
+    assert_eq!(visited, 2);
+    assert_eq!(keys, vec![b"k1".to_vec(), b"k2".to_vec()]);
+
+    db.merge(b"n", &5i64.to_be_bytes(), MergeOp::CounterAdd)
+        .unwrap();
+    db.delete_range(b"k2", b"l").unwrap();
+    assert_eq!(
+        db.inner().get(b"n").unwrap(),
+        Some(5i64.to_be_bytes().to_vec())
+    );
+    assert_eq!(db.inner().get(b"k1").unwrap(), Some(b"v1".to_vec()));
+    assert_eq!(db.inner().get(b"k2").unwrap(), None);
+
+    // the scan and the range delete are tracked while they run
+    let stats = db.stats();
+    assert_eq!((stats.open, stats.opened), (0, 2));
+}
+
+#[test]
+fn tracked_detects_leaks() {
+    let mut db = gen_db(IterLimits {
+        max_age: Some(Duration::from_millis(10)),
+        ..Default::default()
+    });
+
+    let iter = db.iter(b"k", b"l", IterOrder::Asc);
+    assert!(db.leaked().is_empty());
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(db.leaked().len(), 1);
+    drop(iter);
+    assert!(db.leaked().is_empty());
+
+    // a forgotten iterator is never unregistered and outlives the next commit
+    std::mem::forget(db.iter(b"k", b"l", IterOrder::Asc));
+    db.commit(vec![], false).unwrap();
+    let leaked = db.leaked();
+    assert_eq!(leaked.len(), 1);
+    assert_eq!(leaked[0].commits_outlived, 1);
+}
@@ -1,36 +1,69 @@
-use fin_db::FinDB;
+use fin_db::{FinDB, FinDBOpts};
 use ruc::*;
 use std::env::temp_dir;
-use std::ops::{Deref, DerefMut};
+use std::ops::{Bound, Deref, DerefMut};
 use std::path::Path;
 use std::time::SystemTime;
-use storage::db::{DbIter, IterOrder, KVBatch, KValue, MerkleDB};
+use storage::{
+    db::{
+        DbIter, DbTryIter, IterOpts, IterOrder, KVBatch, KVEntryRef, KValue, MerkleDB, ScanControl,
+        ValueLogOpts,
+    },
+    leaks::{track_temp_db, TempDbTicket},
+    merge::MergeOp,
+    proof::{MultiProof, Proof},
+};
 
 /// Wraps a Findora db instance and deletes it from disk it once it goes out of scope.
 pub struct TempFinDB {
     inner: Option<FinDB>,
+    // recorded while leak tracking is enabled, see `storage::leaks`
+    ticket: Option<TempDbTicket>,
 }
 
 impl TempFinDB {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<TempFinDB> {
-        let inner = Some(FinDB::open(path)?);
-        Ok(TempFinDB { inner })
+        let inner = Some(FinDB::open(&path)?);
+        Ok(TempFinDB::tracked(inner, path.as_ref()))
+    }
+
+    /// Opens a `TempFinDB` keeping large values in a value log
+    pub fn open_with_value_log<P: AsRef<Path>>(path: P, opts: &ValueLogOpts) -> Result<TempFinDB> {
+        let inner = Some(FinDB::open_with_value_log(&path, opts)?);
+        Ok(TempFinDB::tracked(inner, path.as_ref()))
+    }
+
+    /// Opens a `TempFinDB` with the given options, e.g. a shared block cache
+    pub fn open_with_opts<P: AsRef<Path>>(path: P, opts: &FinDBOpts) -> Result<TempFinDB> {
+        let inner = Some(FinDB::open_with_opts(&path, opts)?);
+        Ok(TempFinDB::tracked(inner, path.as_ref()))
     }
 
     /// Opens a `TempFinDB` at an autogenerated, temporary file path.
     pub fn new() -> Result<TempFinDB> {
         let time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
+            .c(d!())?
             .as_nanos();
         let mut path = temp_dir();
         path.push(format!("temp-findb–{}", time));
         TempFinDB::open(path)
     }
 
+    fn tracked(inner: Option<FinDB>, path: &Path) -> TempFinDB {
+        TempFinDB {
+            inner,
+            ticket: track_temp_db("TempFinDB", path),
+        }
+    }
+
     /// Closes db and deletes all data from disk.
     fn destroy(&mut self) -> Result<()> {
-        self.inner.take().unwrap().destroy()
+        self.inner.take().c(d!("db already destroyed"))?.destroy()?;
+        if let Some(ticket) = self.ticket.take() {
+            ticket.release();
+        }
+        Ok(())
     }
 }
 
@@ -47,20 +80,110 @@ impl MerkleDB for TempFinDB {
         self.deref().get_aux(key)
     }
 
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        self.deref().get_into(key, buf)
+    }
+
     fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
         self.deref_mut().put_batch(kvs)
     }
 
+    fn put_batch_ref(&mut self, kvs: &[KVEntryRef<'_>]) -> Result<()> {
+        self.deref_mut().put_batch_ref(kvs)
+    }
+
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        self.deref_mut().delete_range(lower, upper)
+    }
+
+    fn merge(&mut self, key: &[u8], patch: &[u8], op: MergeOp) -> Result<()> {
+        self.deref_mut().merge(key, patch, op)
+    }
+
     fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
         self.deref().iter(lower, upper, order)
     }
 
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbIter<'_> {
+        self.deref().iter_with_opts(lower, upper, order, opts)
+    }
+
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        self.deref().iter_bounds(lower, upper, order)
+    }
+
+    fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        self.deref().scan_apply(lower, upper, order, f)
+    }
+
     fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
         self.deref().iter_aux(lower, upper, order)
     }
+
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.deref().try_iter(lower, upper, order)
+    }
+
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.deref().try_iter_aux(lower, upper, order)
+    }
     fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_>{
         self.deref().db_all_iterator(order)
     }
+
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        self.deref().db_all_iterator_aux(order)
+    }
+
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.deref().try_db_all_iterator(order)
+    }
+
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.deref().try_db_all_iterator_aux(order)
+    }
+
+    fn has_proofs(&self) -> bool {
+        self.deref().has_proofs()
+    }
+
+    fn prove_keys(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        self.deref().prove_keys(keys)
+    }
+
+    fn prove_range(&self, lower: &[u8], upper: &[u8]) -> Result<Vec<u8>> {
+        self.deref().prove_range(lower, upper)
+    }
+
+    fn prove(&self, key: &[u8]) -> Result<Proof> {
+        self.deref().prove(key)
+    }
+
+    fn prove_batch(&self, keys: &[Vec<u8>]) -> Result<MultiProof> {
+        self.deref().prove_batch(keys)
+    }
+
+    fn verify_entry(&self, kv_pair: &(Box<[u8]>, Box<[u8]>)) -> Result<()> {
+        self.deref().verify_entry(kv_pair)
+    }
+
     fn commit(&mut self, aux: KVBatch, flush: bool) -> Result<()> {
         self.deref_mut().commit(aux, flush)
     }
@@ -93,16 +216,29 @@ impl DerefMut for TempFinDB {
 
 impl Drop for TempFinDB {
     fn drop(&mut self) {
-        self.destroy().expect("failed to delete db");
+        // a db left on disk must not abort the process
+        if let Err(e) = self.destroy() {
+            println!("failed to delete db: {}", e);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::TempFinDB;
-    use fmerk::tree::Tree;
-    use std::thread;
-    use storage::db::{IterOrder, MerkleDB};
+    use crate::cf_options;
+    use fin_db::{
+        BlockCache, FinDB, FinDBOpts, MerkVerifier, SecondaryFinDB, MERK_CF_AUX, MERK_CF_INTERNAL,
+        MERK_ROOT_KEY,
+    };
+    use fmerk::{rocksdb, tree::Tree};
+    use std::{ffi::OsStr, fs, path::Path, thread};
+    use storage::{
+        config::{OpenWithConfig, StorageConfig},
+        db::{CfOpts, Compression, IterOrder, MerkleDB, ValueLogOpts},
+        layout::DataLayout,
+        state::ChainState,
+    };
 
     #[test]
     fn db_put_n_get() {
@@ -333,6 +469,61 @@ mod tests {
         assert_eq!(expected_aux, actual_aux);
     }
 
+    #[test]
+    fn db_all_iterator_order() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut fdb = TempFinDB::open(path).expect("failed to open db");
+
+        fdb.put_batch(vec![
+            (b"\x00".to_vec(), Some(b"v00".to_vec())),
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (vec![0xff; 33], Some(b"vff".to_vec())),
+        ])
+        .unwrap();
+        fdb.commit(
+            vec![
+                (b"k11".to_vec(), Some(b"v11".to_vec())),
+                (b"k21".to_vec(), Some(b"v21".to_vec())),
+            ],
+            true,
+        )
+        .unwrap();
+
+        // every key, below and above the usual bounds
+        let keys = fdb
+            .db_all_iterator(IterOrder::Asc)
+            .map(|kv| fdb.decode_kv(kv).0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![b"\x00".to_vec(), b"k10".to_vec(), vec![0xff; 33]]
+        );
+        let keys = fdb
+            .db_all_iterator(IterOrder::Desc)
+            .map(|kv| fdb.decode_kv(kv).0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![vec![0xff; 33], b"k10".to_vec(), b"\x00".to_vec()]
+        );
+
+        // the aux holds internal keys of the db too
+        let aux_keys = |order| {
+            fdb.db_all_iterator_aux(order)
+                .map(|(k, _)| k.to_vec())
+                .filter(|k| k.starts_with(b"k"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            aux_keys(IterOrder::Asc),
+            vec![b"k11".to_vec(), b"k21".to_vec()]
+        );
+        assert_eq!(
+            aux_keys(IterOrder::Desc),
+            vec![b"k21".to_vec(), b"k11".to_vec()]
+        );
+    }
+
     #[test]
     fn db_snapshot() {
         let path = thread::current().name().unwrap().to_owned();
@@ -400,4 +591,400 @@ mod tests {
             .collect::<Vec<_>>();
         assert_eq!(expected_aux, actual_aux);
     }
+
+    #[test]
+    fn db_prove() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut fdb = TempFinDB::open(path).expect("failed to open db");
+        fdb.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+        ])
+        .unwrap();
+        fdb.commit(vec![], true).unwrap();
+        let root = fdb.root_hash();
+
+        let proof = fdb.prove(b"k10").unwrap();
+        assert_eq!(proof.value, Some(b"v10".to_vec()));
+        assert!(proof.verify(&root, &MerkVerifier).is_ok());
+        let absent = fdb.prove(b"k15").unwrap();
+        assert_eq!(absent.value, None);
+        assert!(absent.verify(&root, &MerkVerifier).is_ok());
+
+        // a proof doesn't hold for another value or root
+        let mut forged = proof.clone();
+        forged.value = Some(b"v11".to_vec());
+        assert!(forged.verify(&root, &MerkVerifier).is_err());
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v11".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![], true).unwrap();
+        assert!(proof.verify(&fdb.root_hash(), &MerkVerifier).is_err());
+    }
+
+    #[test]
+    fn db_prove_batch() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut fdb = TempFinDB::open(path).expect("failed to open db");
+        let batch = (0..50u32)
+            .map(|i| (format!("k{:03}", i * 2).into_bytes(), Some(vec![i as u8])))
+            .collect();
+        fdb.put_batch(batch).unwrap();
+        fdb.commit(vec![], true).unwrap();
+        let root = fdb.root_hash();
+
+        let keys = vec![
+            b"k010".to_vec(),
+            b"k001".to_vec(),
+            b"k098".to_vec(),
+            b"k010".to_vec(),
+            b"k999".to_vec(),
+        ];
+        let proof = fdb.prove_batch(&keys).unwrap();
+        assert_eq!(
+            proof.values,
+            vec![
+                (b"k001".to_vec(), None),
+                (b"k010".to_vec(), Some(vec![5])),
+                (b"k098".to_vec(), Some(vec![49])),
+                (b"k999".to_vec(), None),
+            ]
+        );
+        assert!(proof.verify(&root, &MerkVerifier).is_ok());
+
+        // claiming a present key is absent fails
+        let mut forged = proof.clone();
+        forged.values[1].1 = None;
+        assert!(forged.verify(&root, &MerkVerifier).is_err());
+        assert!(proof.verify(&[0; 32], &MerkVerifier).is_err());
+    }
+
+    #[test]
+    fn db_value_log() {
+        let path = thread::current().name().unwrap().to_owned();
+        let opts = ValueLogOpts::default()
+            .with_min_value_size(16)
+            .with_segment_size(256);
+        let mut fdb = TempFinDB::open_with_value_log(path.clone(), &opts).unwrap();
+        let mut plain = TempFinDB::open(format!("{}_plain", path)).unwrap();
+        let large = |n: u8| vec![n; 100];
+
+        let batch = vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(large(20))),
+            (b"k30".to_vec(), Some(large(30))),
+        ];
+        for db in [&mut fdb, &mut plain] {
+            db.put_batch(batch.clone()).unwrap();
+            db.commit(vec![], true).unwrap();
+        }
+        // large values went to blob files
+        let blobs = fs::read_dir(format!("{}/main", path))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some(OsStr::new("blob")))
+            .count();
+        assert!(blobs > 0);
+
+        // the tree, its root and its proofs don't depend on where values are stored
+        assert_eq!(fdb.root_hash(), plain.root_hash());
+        assert_eq!(fdb.get(b"k20").unwrap(), Some(large(20)));
+        let proof = fdb.prove(b"k20").unwrap();
+        assert_eq!(proof.value, Some(large(20)));
+        assert!(proof.verify(&plain.root_hash(), &MerkVerifier).is_ok());
+        let actual = fdb
+            .iter(b"k10", b"k40", IterOrder::Asc)
+            .map(|kv| fdb.decode_kv(kv))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actual,
+            vec![
+                (b"k10".to_vec(), b"v10".to_vec()),
+                (b"k20".to_vec(), large(20)),
+                (b"k30".to_vec(), large(30)),
+            ]
+        );
+        for kv in fdb.db_all_iterator(IterOrder::Asc) {
+            fdb.verify_entry(&kv).unwrap();
+        }
+
+        // a db opened without the option still reads its blob files
+        let root = fdb.root_hash();
+        let path_cp = format!("{}_cp", path);
+        fdb.snapshot(path_cp.clone()).unwrap();
+        let fdb_cp = TempFinDB::open(path_cp).unwrap();
+        assert_eq!(fdb_cp.get(b"k20").unwrap(), Some(large(20)));
+        assert_eq!(fdb_cp.root_hash(), root);
+    }
+
+    #[test]
+    fn db_shared_block_cache() {
+        let path = thread::current().name().unwrap().to_owned();
+        // writes a db and reopens it, its tree is then read from disk
+        let reopened = |open: &dyn Fn() -> FinDB| {
+            let mut fdb = open();
+            let batch = (0..100u32)
+                .map(|n| (n.to_be_bytes().to_vec(), Some(vec![n as u8; 100])))
+                .collect::<Vec<_>>();
+            fdb.put_batch(batch).unwrap();
+            fdb.commit(vec![], true).unwrap();
+            drop(fdb);
+            open()
+        };
+        // reads every key of each db, the usage of the cache grows with each of them
+        let read_all = |dbs: Vec<FinDB>, cache: &BlockCache| {
+            let mut usage = cache.usage();
+            for fdb in dbs {
+                for n in 0..100u32 {
+                    let value = fdb.get(&n.to_be_bytes()).unwrap();
+                    assert_eq!(value, Some(vec![n as u8; 100]));
+                }
+                assert!(cache.usage() > usage);
+                usage = cache.usage();
+                fdb.destroy().unwrap();
+            }
+        };
+
+        let cache = BlockCache::new(8 << 20).unwrap();
+        let opts = FinDBOpts::default().with_block_cache(&cache);
+        let dbs = ["a", "b"]
+            .iter()
+            .map(|db| {
+                let db_path = format!("{}_{}", path, db);
+                reopened(&|| FinDB::open_with_opts(&db_path, &opts).unwrap())
+            })
+            .collect();
+        read_all(dbs, &cache);
+        assert!(cache.usage() <= 8 << 20);
+
+        // dbs configured with the same cache name share it
+        let name = format!("{}_cache", path);
+        let mut cfg = StorageConfig::default();
+        cfg.rocksdb.block_cache_size = Some(8 << 20);
+        cfg.rocksdb.shared_block_cache = Some(name.clone());
+        let dbs = ["c", "d"]
+            .iter()
+            .map(|db| {
+                let mut cfg = cfg.clone();
+                cfg.path = format!("{}_{}", path, db).into();
+                reopened(&|| FinDB::open_with_config(&cfg).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let cache = BlockCache::named(&name, 1).unwrap();
+        read_all(dbs, &cache);
+    }
+
+    #[test]
+    fn db_tuned_opts() {
+        let path = thread::current().name().unwrap().to_owned();
+        let data = CfOpts::default()
+            .with_compression(Compression::Lz4)
+            .with_write_buffer_size(4 << 20)
+            .with_bloom_bits_per_key(10);
+        let opts = FinDBOpts::default()
+            .with_block_cache_size(8 << 20)
+            .with_data_cf(data);
+
+        let mut fdb = TempFinDB::open_with_opts(&path, &opts).unwrap();
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+        assert_eq!(fdb.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(fdb.get_aux(b"height").unwrap(), Some(b"1".to_vec()));
+
+        // the tree nodes are in the default column family
+        let applied = cf_options(&Path::new(&path).join("main"), "default");
+        assert!(applied.contains(&"compression=kLZ4Compression".to_owned()));
+        assert!(applied.contains(&format!("write_buffer_size={}", 4 << 20)));
+        let filter = applied.iter().find(|l| l.starts_with("filter_policy="));
+        assert!(filter.is_some_and(|l| !l.ends_with("nullptr")));
+    }
+
+    #[test]
+    fn db_try_iter() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut db = TempFinDB::open(path).expect("failed to open db");
+        db.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k30".to_vec(), Some(b"v30".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+
+        // same pairs as the infallible iterators
+        for order in [IterOrder::Asc, IterOrder::Desc] {
+            let pairs = db
+                .try_iter(b"k", b"k30", order)
+                .collect::<ruc::Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(pairs.len(), 2);
+            assert_eq!(pairs, db.iter(b"k", b"k30", order).collect::<Vec<_>>());
+        }
+        let aux = db
+            .try_iter_aux(b"h", b"i", IterOrder::Asc)
+            .collect::<ruc::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            aux,
+            db.iter_aux(b"h", b"i", IterOrder::Asc).collect::<Vec<_>>()
+        );
+        assert_eq!(aux.len(), 1);
+    }
+
+    #[test]
+    fn db_secondary_catch_up() {
+        let path = thread::current().name().unwrap().to_owned();
+        let secondary_path = format!("{}_secondary", path);
+        let mut fdb = TempFinDB::open(&path).unwrap();
+        fdb.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        fdb.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+
+        let mut secondary = SecondaryFinDB::open(&path, &secondary_path).unwrap();
+        assert_eq!(secondary.root_hash(), fdb.root_hash());
+        assert_eq!(secondary.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(secondary.get_aux(b"height").unwrap(), Some(b"1".to_vec()));
+        assert!(secondary.put_batch(vec![]).is_err());
+        assert!(secondary.commit(vec![], false).is_err());
+
+        // the view stays the same until it catches up
+        fdb.put_batch(vec![
+            (b"k10".to_vec(), None),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+        ])
+        .unwrap();
+        fdb.commit(vec![(b"height".to_vec(), Some(b"2".to_vec()))], false)
+            .unwrap();
+        assert_eq!(secondary.get(b"k20").unwrap(), None);
+        assert_ne!(secondary.root_hash(), fdb.root_hash());
+
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(secondary.root_hash(), fdb.root_hash());
+        assert_eq!(secondary.get(b"k10").unwrap(), None);
+        assert_eq!(secondary.get_aux(b"height").unwrap(), Some(b"2".to_vec()));
+        let pairs = secondary
+            .iter(b"k", b"l", IterOrder::Asc)
+            .map(|kv| secondary.decode_kv(kv))
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, vec![(b"k20".to_vec(), b"v20".to_vec())]);
+        let pairs = secondary
+            .try_iter(b"k", b"l", IterOrder::Asc)
+            .collect::<ruc::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(secondary.try_root_hash().unwrap(), fdb.root_hash());
+
+        drop(secondary);
+        fs::remove_dir_all(secondary_path).unwrap();
+    }
+
+    #[test]
+    fn db_secondary_malformed_node() {
+        let path = thread::current().name().unwrap().to_owned();
+        let secondary_path = format!("{}_secondary", path);
+        // a fmerk db whose root node can't be decoded
+        let main_dir = DataLayout::open(&path).unwrap().main_dir();
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let db = rocksdb::DB::open_cf(&opts, main_dir, [MERK_CF_AUX, MERK_CF_INTERNAL]).unwrap();
+        let internal = db.cf_handle(MERK_CF_INTERNAL).unwrap();
+        db.put_cf(internal, MERK_ROOT_KEY, b"k10").unwrap();
+        db.put(b"k10", b"malformed").unwrap();
+        drop(db);
+
+        // the errors are returned instead of a null root or a panic
+        let secondary = SecondaryFinDB::open(&path, &secondary_path).unwrap();
+        assert!(secondary.try_root_hash().is_err());
+        assert!(secondary.get(b"k10").is_err());
+        let mut pairs = secondary.try_iter(b"k", b"l", IterOrder::Asc);
+        assert!(pairs.next().unwrap().is_err());
+        assert!(pairs.next().is_none());
+        assert_eq!(secondary.iter(b"k", b"l", IterOrder::Asc).count(), 0);
+
+        drop(pairs);
+        drop(secondary);
+        fs::remove_dir_all(secondary_path).unwrap();
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn db_collect_garbage() {
+        let path = thread::current().name().unwrap().to_owned();
+        let fdb = FinDB::open(&path).unwrap();
+        let mut chain = ChainState::new(fdb, "test".to_string(), 2);
+        for height in 1..=4u64 {
+            let value = height.to_be_bytes().to_vec();
+            let batch = (0..50u32)
+                .map(|i| (format!("k{:03}", i).into_bytes(), Some(value.clone())))
+                .collect();
+            chain.commit(batch, height, true).unwrap();
+        }
+        let root = chain.root_hash();
+        drop(chain);
+
+        // a node left by an interrupted write, no root reaches it
+        let main_dir = DataLayout::open(&path).unwrap().main_dir();
+        let db = rocksdb::DB::open_cf(
+            &rocksdb::Options::default(),
+            main_dir,
+            [MERK_CF_AUX, MERK_CF_INTERNAL],
+        )
+        .unwrap();
+        let node = db.get(b"k001").unwrap().unwrap();
+        db.put(b"k001x", node).unwrap();
+        drop(db);
+
+        let report = FinDB::collect_garbage(&path, 1).unwrap();
+        assert_eq!(report.roots, 1);
+        assert_eq!(report.reachable, 50);
+        assert_eq!(report.deleted, 1);
+        let report = FinDB::collect_garbage(&path, 1).unwrap();
+        assert_eq!(report.deleted, 0);
+
+        // the tree is left as it was
+        let fdb = FinDB::open(&path).unwrap();
+        assert_eq!(fdb.root_hash(), root);
+        assert_eq!(fdb.get(b"k001").unwrap(), Some(4u64.to_be_bytes().to_vec()));
+        assert_eq!(fdb.get(b"k001x").unwrap(), None);
+        assert_eq!(fdb.iter(b"k", b"l", IterOrder::Asc).count(), 50);
+        fdb.destroy().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "test-hooks")]
+    fn db_stall_hook() {
+        use fin_db::{StallHook, StallOp};
+        use std::time::{Duration, Instant};
+
+        let path = thread::current().name().unwrap().to_owned();
+        let mut fdb = FinDB::open(&path).unwrap();
+        let hook = StallHook::new();
+        fdb.set_stall_hook(hook.clone());
+
+        hook.set_delay(StallOp::Write, Duration::from_millis(20));
+        let start = Instant::now();
+        fdb.put_batch(vec![(b"k1".to_vec(), Some(b"v1".to_vec()))])
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        hook.set_delay(StallOp::Write, Duration::ZERO);
+
+        // a stalled commit goes on once released
+        hook.stall(StallOp::Compaction);
+        let writer = thread::spawn(move || {
+            fdb.commit(vec![], true).unwrap();
+            fdb
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        hook.release(StallOp::Compaction);
+        let fdb = writer.join().unwrap();
+
+        assert_eq!(fdb.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(hook.waited(StallOp::Write), 1);
+        assert_eq!(hook.waited(StallOp::Compaction), 1);
+        fdb.destroy().unwrap();
+    }
 }
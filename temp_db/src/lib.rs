@@ -3,3 +3,38 @@ mod rocks;
 
 pub use fin::TempFinDB;
 pub use rocks::TempRocksDB;
+
+/// Options rocksdb applied to the column family `cf` of the db in `dir`, the lines of its
+/// sections in the newest OPTIONS file
+#[cfg(test)]
+fn cf_options(dir: &std::path::Path, cf: &str) -> Vec<String> {
+    let newest = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("OPTIONS-"))
+        .max()
+        .unwrap();
+    let sections = [
+        format!("[CFOptions \"{}\"]", cf),
+        format!("[TableOptions/BlockBasedTable \"{}\"]", cf),
+    ];
+    let mut in_section = false;
+    let mut lines = vec![];
+    for line in std::fs::read_to_string(dir.join(newest)).unwrap().lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_section = sections.iter().any(|section| section == line);
+        } else if in_section && !line.is_empty() {
+            lines.push(line.replace(' ', ""));
+        }
+    }
+    lines
+}
+
+/// `storage::prelude` plus the temporary backends, so tests need a single import
+#[cfg(feature = "test-utils")]
+pub mod prelude {
+    pub use crate::{TempFinDB, TempRocksDB};
+    pub use mem_db::MemoryDB;
+    pub use storage::prelude::*;
+}
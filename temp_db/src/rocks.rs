@@ -1,36 +1,55 @@
 use fin_db::RocksDB;
 use ruc::*;
 use std::env::temp_dir;
-use std::ops::{Deref, DerefMut};
+use std::ops::{Bound, Deref, DerefMut};
 use std::path::Path;
 use std::time::SystemTime;
-use storage::db::{DbIter, IterOrder, KVBatch, KValue, MerkleDB};
+use storage::{
+    db::{
+        DbIter, DbTryIter, IterOpts, IterOrder, KVBatch, KVEntryRef, KValue, MerkleDB, ScanControl,
+    },
+    leaks::{track_temp_db, TempDbTicket},
+    merge::MergeOp,
+};
 
 /// Wraps a RocksDB instance and deletes it from disk it once it goes out of scope.
 pub struct TempRocksDB {
     inner: Option<RocksDB>,
+    // recorded while leak tracking is enabled, see `storage::leaks`
+    ticket: Option<TempDbTicket>,
 }
 
 impl TempRocksDB {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<TempRocksDB> {
-        let inner = Some(RocksDB::open(path)?);
-        Ok(TempRocksDB { inner })
+        let inner = Some(RocksDB::open(&path)?);
+        Ok(TempRocksDB::tracked(inner, path.as_ref()))
     }
 
     /// Opens a `TempRocksDB` at an autogenerated, temporary file path.
     pub fn new() -> Result<TempRocksDB> {
         let time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
+            .c(d!())?
             .as_nanos();
         let mut path = temp_dir();
         path.push(format!("temp-rocksdb–{}", time));
         TempRocksDB::open(path)
     }
 
+    fn tracked(inner: Option<RocksDB>, path: &Path) -> TempRocksDB {
+        TempRocksDB {
+            inner,
+            ticket: track_temp_db("TempRocksDB", path),
+        }
+    }
+
     /// Closes db and deletes all data from disk.
     fn destroy(&mut self) -> Result<()> {
-        self.inner.take().unwrap().destroy()
+        self.inner.take().c(d!("db already destroyed"))?.destroy()?;
+        if let Some(ticket) = self.ticket.take() {
+            ticket.release();
+        }
+        Ok(())
     }
 }
 
@@ -47,20 +66,90 @@ impl MerkleDB for TempRocksDB {
         self.deref().get(key)
     }
 
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        self.deref().get_into(key, buf)
+    }
+
     fn put_batch(&mut self, kvs: KVBatch) -> Result<()> {
         self.deref_mut().put_batch(kvs)
     }
 
+    fn put_batch_ref(&mut self, kvs: &[KVEntryRef<'_>]) -> Result<()> {
+        self.deref_mut().put_batch_ref(kvs)
+    }
+
+    fn delete_range(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        self.deref_mut().delete_range(lower, upper)
+    }
+
+    fn merge(&mut self, key: &[u8], patch: &[u8], op: MergeOp) -> Result<()> {
+        self.deref_mut().merge(key, patch, op)
+    }
+
     fn iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
         self.deref().iter(lower, upper, order)
     }
 
+    fn iter_with_opts(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        opts: &IterOpts,
+    ) -> DbIter<'_> {
+        self.deref().iter_with_opts(lower, upper, order, opts)
+    }
+
+    fn iter_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        order: IterOrder,
+    ) -> DbIter<'_> {
+        self.deref().iter_bounds(lower, upper, order)
+    }
+
+    fn scan_apply(
+        &self,
+        lower: &[u8],
+        upper: &[u8],
+        order: IterOrder,
+        f: &mut dyn FnMut(&[u8], &[u8]) -> ScanControl,
+    ) -> u64 {
+        self.deref().scan_apply(lower, upper, order, f)
+    }
+
     fn iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbIter<'_> {
         self.deref().iter(lower, upper, order)
     }
+
+    fn try_iter(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.deref().try_iter(lower, upper, order)
+    }
+
+    fn try_iter_aux(&self, lower: &[u8], upper: &[u8], order: IterOrder) -> DbTryIter<'_> {
+        self.deref().try_iter_aux(lower, upper, order)
+    }
     fn db_all_iterator(&self, order: IterOrder) -> DbIter<'_>{
         self.deref().db_all_iterator(order)
     }
+
+    fn db_all_iterator_aux(&self, order: IterOrder) -> DbIter<'_> {
+        self.deref().db_all_iterator_aux(order)
+    }
+
+    fn try_db_all_iterator(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.deref().try_db_all_iterator(order)
+    }
+
+    fn try_db_all_iterator_aux(&self, order: IterOrder) -> DbTryIter<'_> {
+        self.deref().try_db_all_iterator_aux(order)
+    }
+
+    fn compact_range(&self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        self.deref().compact_range(lower, upper)
+    }
+
     fn commit(&mut self, kvs: KVBatch, flush: bool) -> Result<()> {
         self.deref_mut().commit(kvs, flush)
     }
@@ -93,15 +182,21 @@ impl DerefMut for TempRocksDB {
 
 impl Drop for TempRocksDB {
     fn drop(&mut self) {
-        self.destroy().expect("failed to delete db");
+        // a db left on disk must not abort the process
+        if let Err(e) = self.destroy() {
+            println!("failed to delete db: {}", e);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::TempRocksDB;
-    use std::thread;
-    use storage::db::{IterOrder, MerkleDB};
+    use crate::cf_options;
+    use fin_db::{FinDBOpts, RocksDB};
+    use fmerk::rocksdb;
+    use std::{path::Path, thread};
+    use storage::db::{CfOpts, Compression, IterOrder, MerkleDB, ValueLogOpts};
 
     #[test]
     fn db_put_n_get() {
@@ -266,4 +361,88 @@ mod tests {
             .collect::<Vec<_>>();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn db_try_iter() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut db = TempRocksDB::open(path).expect("failed to open db");
+        db.put_batch(vec![
+            (b"k10".to_vec(), Some(b"v10".to_vec())),
+            (b"k20".to_vec(), Some(b"v20".to_vec())),
+            (b"k30".to_vec(), Some(b"v30".to_vec())),
+        ])
+        .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+
+        // same pairs as the infallible iterators
+        for order in [IterOrder::Asc, IterOrder::Desc] {
+            let pairs = db
+                .try_iter(b"k", b"k30", order)
+                .collect::<ruc::Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(pairs.len(), 2);
+            assert_eq!(pairs, db.iter(b"k", b"k30", order).collect::<Vec<_>>());
+        }
+        let aux = db
+            .try_iter_aux(b"h", b"i", IterOrder::Asc)
+            .collect::<ruc::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            aux,
+            db.iter_aux(b"h", b"i", IterOrder::Asc).collect::<Vec<_>>()
+        );
+        assert_eq!(aux.len(), 1);
+    }
+
+    #[test]
+    fn db_tuned_cfs() {
+        let path = thread::current().name().unwrap().to_owned();
+        let data = CfOpts::default()
+            .with_compression(Compression::Lz4)
+            .with_write_buffer_size(1 << 20);
+        let opts = FinDBOpts::default()
+            .with_block_cache_size(8 << 20)
+            .with_data_cf(data);
+        let mut db = RocksDB::open_with_opts(&path, &opts).unwrap();
+        db.put_batch(vec![(b"k10".to_vec(), Some(b"v10".to_vec()))])
+            .unwrap();
+        db.commit(vec![(b"height".to_vec(), Some(b"1".to_vec()))], true)
+            .unwrap();
+        drop(db);
+
+        // the aux data is read back from the tuned column family of the state
+        let dir = Path::new(&path).join("main");
+        let state = cf_options(&dir, "state");
+        assert!(state.contains(&"compression=kLZ4Compression".to_owned()));
+        assert!(state.contains(&format!("write_buffer_size={}", 1 << 20)));
+        let raw = rocksdb::DB::open_cf_for_read_only(
+            &rocksdb::Options::default(),
+            &dir,
+            ["state"],
+            false,
+        )
+        .unwrap();
+        let state_cf = raw.cf_handle("state").unwrap();
+        assert_eq!(
+            raw.get_cf(state_cf, b"height").unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(raw.get(b"height").unwrap(), None);
+        drop(raw);
+        let db = RocksDB::open_with_opts(&path, &opts).unwrap();
+        assert_eq!(db.get(b"k10").unwrap(), Some(b"v10".to_vec()));
+        assert_eq!(db.get_aux(b"height").unwrap(), Some(b"1".to_vec()));
+        db.destroy().unwrap();
+
+        // large values of the state go to blob files
+        let opts =
+            FinDBOpts::default().with_value_log(ValueLogOpts::default().with_min_value_size(16));
+        let mut db = RocksDB::open_with_opts(format!("{}_vlog", path), &opts).unwrap();
+        db.put_batch(vec![(b"k10".to_vec(), Some(vec![1; 100]))])
+            .unwrap();
+        db.commit(vec![], true).unwrap();
+        assert_eq!(db.get(b"k10").unwrap(), Some(vec![1; 100]));
+        db.destroy().unwrap();
+    }
 }